@@ -1,11 +1,17 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use aws_sdk_s3::Client;
+use bottomless::progress::RestoreProgress;
+use bottomless::storage::{CloudStorage, ObjectStorage, S3Storage, StorageBackend};
 use chrono::NaiveDateTime;
 use clap::{Parser, Subcommand};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 mod replicator_extras;
 use crate::replicator_extras::detect_db;
-use replicator_extras::Replicator;
+use replicator_extras::{CopyTarget, Replicator};
 
 #[derive(Debug, Parser)]
 #[command(name = "bottomless-cli")]
@@ -51,9 +57,14 @@ enum Commands {
         #[clap(
             long,
             short,
-            long_help = "Print detailed information on each generation"
+            long_help = "Print detailed information on each generation.\nThis downloads the objects of each generation to verify their checksums."
         )]
         verbose: bool,
+        #[clap(
+            long,
+            long_help = "Don't download the objects of the listed generations to verify their checksums"
+        )]
+        skip_verify: bool,
     },
     #[clap(about = "Restore the database")]
     Restore {
@@ -70,6 +81,33 @@ enum Commands {
             long_help = "UTC timestamp which is an upper bound for the transactions to be restored."
         )]
         utc_time: Option<NaiveDateTime>,
+        #[clap(
+            long,
+            long_help = "Fail the restore when a WAL frame batch doesn't match its manifest checksum.\nBy default the restore stops with a warning at the last valid frame preceding it.\nA snapshot which doesn't match its manifest checksum always fails the restore."
+        )]
+        strict_verify: bool,
+        #[clap(
+            long,
+            conflicts_with = "strict_verify",
+            long_help = "Restore without verifying the snapshot and WAL frame batches against the manifest checksums.\nMeant for emergencies, when a generation is known to be damaged."
+        )]
+        skip_verify: bool,
+        #[clap(
+            long,
+            long_help = "File containing the key used to encrypt the backup, either as 32 raw bytes or 64 hex digits.\nRequired to restore encrypted generations."
+        )]
+        encryption_key_file: Option<std::path::PathBuf>,
+        #[clap(
+            long,
+            long_help = "Directory to restore the database into, instead of the path of the detected database.\nThe directory is created if it doesn't exist."
+        )]
+        to: Option<PathBuf>,
+        #[clap(
+            long,
+            requires = "to",
+            long_help = "Overwrite the database in the directory passed with --to, if there is one."
+        )]
+        force: bool,
     },
     #[clap(about = "Remove given generation from remote storage")]
     Rm {
@@ -84,6 +122,82 @@ enum Commands {
         #[clap(long, short)]
         verbose: bool,
     },
+    #[clap(about = "Copy generations to another namespace and/or bucket")]
+    Copy {
+        #[clap(
+            long,
+            alias = "from-namespace",
+            long_help = "Namespace to copy the generations from. It takes the place of --namespace."
+        )]
+        source_namespace: String,
+        #[clap(
+            long,
+            alias = "to-namespace",
+            long_help = "Namespace to copy the generations to"
+        )]
+        dest_namespace: String,
+        #[clap(
+            long,
+            long_help = "Bucket to copy the generations to.\nDefaults to the source bucket."
+        )]
+        dest_bucket: Option<String>,
+        #[clap(
+            long,
+            long_help = "Endpoint of the destination bucket. Defaults to the source endpoint.\nObjects are copied server-side, so the destination must be able to read the source bucket.\nGoogle Cloud Storage and Azure Blob Storage can only copy within the same bucket."
+        )]
+        dest_endpoint: Option<String>,
+        #[clap(
+            long,
+            short,
+            long_help = "Generation to copy, along with the generations it depends on.\nSkip this parameter to copy all generations."
+        )]
+        generation: Option<uuid::Uuid>,
+        #[clap(long, long_help = "Only print the objects that would be copied")]
+        dry_run: bool,
+        #[clap(
+            long,
+            long_help = "Overwrite the generations that already exist in the destination.\nBy default, nothing is copied if any of them does."
+        )]
+        force: bool,
+    },
+}
+
+/// Opens a bucket of the storage selected by `endpoint`: `gs://bucket` and `azblob://container`
+/// endpoints open Google Cloud Storage and Azure Blob Storage, any other endpoint is an S3 one.
+/// An explicit `bucket` takes precedence over the one in the endpoint.
+async fn open_storage(
+    endpoint: Option<String>,
+    bucket: Option<String>,
+) -> Result<Arc<dyn ObjectStorage>> {
+    let backend = match std::env::var("LIBSQL_BOTTOMLESS_BACKEND") {
+        Ok(backend) => StorageBackend::parse(&backend)?,
+        Err(_) => StorageBackend::default(),
+    };
+    let (backend, endpoint, url_bucket) =
+        match endpoint.as_deref().and_then(StorageBackend::from_url) {
+            Some((backend, url_bucket)) => (backend, None, Some(url_bucket)),
+            None => (backend, endpoint, None),
+        };
+    let bucket = bucket
+        .or(url_bucket)
+        .unwrap_or_else(|| "bottomless".to_string());
+    let storage: Arc<dyn ObjectStorage> = match backend {
+        StorageBackend::S3 => {
+            let mut loader = aws_config::from_env();
+            if let Some(endpoint) = endpoint {
+                loader = loader.endpoint_url(endpoint);
+            }
+            let client = Client::from_conf(
+                aws_sdk_s3::config::Builder::from(&loader.load().await)
+                    .force_path_style(true)
+                    .build(),
+            );
+            Arc::new(S3Storage::new(client, bucket))
+        }
+        StorageBackend::Gcs => Arc::new(CloudStorage::gcs(&bucket)?),
+        StorageBackend::Azure => Arc::new(CloudStorage::azure(&bucket)?),
+    };
+    Ok(storage)
 }
 
 async fn run() -> Result<()> {
@@ -101,22 +215,22 @@ async fn run() -> Result<()> {
     } else {
         options.bucket = std::env::var("LIBSQL_BOTTOMLESS_BUCKET").ok();
     }
-    let namespace = options.namespace.as_deref().unwrap_or("ns-default");
+    let namespace = match &options.command {
+        Commands::Copy {
+            source_namespace, ..
+        } => source_namespace.clone(),
+        _ => options
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "ns-default".to_string()),
+    };
+    let namespace = namespace.as_str();
     std::env::set_var("LIBSQL_BOTTOMLESS_DATABASE_ID", namespace);
     let database = match options.database.clone() {
         Some(db) => db,
         None => {
-            let client = Client::from_conf({
-                let mut loader = aws_config::from_env();
-                if let Some(endpoint) = options.endpoint.clone() {
-                    loader = loader.endpoint_url(endpoint);
-                }
-                aws_sdk_s3::config::Builder::from(&loader.load().await)
-                    .force_path_style(true)
-                    .build()
-            });
-            let bucket = options.bucket.as_deref().unwrap_or("bottomless");
-            match detect_db(&client, bucket, namespace).await {
+            let storage = open_storage(options.endpoint.clone(), options.bucket.clone()).await?;
+            match detect_db(storage.as_ref(), namespace).await {
                 Some(db) => db,
                 None => {
                     println!("Could not autodetect the database. Please pass it explicitly with -d option");
@@ -125,9 +239,30 @@ async fn run() -> Result<()> {
             }
         }
     };
-    let database = database + "/dbs/" + namespace.strip_prefix("ns-").unwrap() + "/data";
+    let database = namespace_db_path(&database, namespace);
     tracing::info!("Database: '{}' (namespace: {})", database, namespace);
 
+    if let Commands::Restore {
+        strict_verify: true,
+        ..
+    } = options.command
+    {
+        std::env::set_var("LIBSQL_BOTTOMLESS_STRICT_VERIFY", "true");
+    }
+    if let Commands::Restore {
+        skip_verify: true, ..
+    } = options.command
+    {
+        std::env::set_var("LIBSQL_BOTTOMLESS_SKIP_VERIFY", "true");
+    }
+    if let Commands::Restore {
+        encryption_key_file: Some(fpath),
+        ..
+    } = &options.command
+    {
+        std::env::set_var("LIBSQL_BOTTOMLESS_ENCRYPTION_KEY_FILE", fpath);
+    }
+
     let mut client = Replicator::new(database.clone()).await?;
 
     match options.command {
@@ -137,20 +272,65 @@ async fn run() -> Result<()> {
             older_than,
             newer_than,
             verbose,
+            skip_verify,
         } => match generation {
-            Some(gen) => client.list_generation(gen).await?,
+            Some(gen) => client.list_generation(gen, !skip_verify).await?,
             None => {
                 client
-                    .list_generations(limit, older_than, newer_than, verbose)
+                    .list_generations(limit, older_than, newer_than, verbose, !skip_verify)
                     .await?
             }
         },
         Commands::Restore {
             generation,
             utc_time,
+            to,
+            force,
+            ..
         } => {
-            tokio::fs::create_dir_all(&database).await?;
-            client.restore(generation, utc_time).await?;
+            let db_path = match to {
+                Some(dir) => {
+                    let db_path = dir.join("data");
+                    prepare_restore_target(&db_path, force).await?;
+                    // the name of the database in the bucket is derived from its original path,
+                    // so only the local path is overridden
+                    client.db_path = db_path.to_string_lossy().into_owned();
+                    db_path
+                }
+                None => PathBuf::from(&database),
+            };
+            if let Some(parent) = db_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            let generation = match generation {
+                Some(generation) => generation,
+                None => match client.latest_generation_before(utc_time.as_ref()).await {
+                    Some(generation) => generation,
+                    None => {
+                        println!("No generation found, nothing to restore");
+                        return Ok(());
+                    }
+                },
+            };
+            let progress = client.restore_progress();
+            let progress_line = std::io::stderr()
+                .is_terminal()
+                .then(|| tokio::spawn(render_progress(progress.clone())));
+            let restored = client.restore(Some(generation), utc_time).await;
+            if let Some(progress_line) = progress_line {
+                progress_line.abort();
+                eprintln!("\r\x1b[2KRestoring: {progress}");
+            }
+            restored?;
+
+            let size = tokio::fs::metadata(&db_path).await?.len();
+            println!(
+                "Restored generation {} into {} ({} bytes)",
+                generation,
+                db_path.display(),
+                size
+            );
         }
         Commands::Rm {
             generation,
@@ -164,10 +344,80 @@ async fn run() -> Result<()> {
                 "rm command cannot be run without parameters; see -h or --help for details"
             ),
         },
+        Commands::Copy {
+            ref dest_namespace,
+            dest_bucket,
+            dest_endpoint,
+            generation,
+            dry_run,
+            force,
+            ..
+        } => {
+            let dest_bucket = dest_bucket.unwrap_or_else(|| client.storage.bucket().to_string());
+            let target = CopyTarget {
+                storage: open_storage(dest_endpoint.or(options.endpoint), Some(dest_bucket))
+                    .await?,
+                // the database name is prefixed with the namespace
+                db_name: format!("{}{}", dest_namespace, &client.db_name[namespace.len()..]),
+                force,
+            };
+            if let Err(e) = target.storage.ensure_bucket(false).await {
+                bail!(
+                    "destination bucket {} is not accessible: {}",
+                    target.storage.bucket(),
+                    e
+                );
+            }
+            client.copy(&target, generation, dry_run).await?;
+        }
     };
     Ok(())
 }
 
+/// Returns the path of the database file of `namespace` in the sqld data directory `database`.
+fn namespace_db_path(database: &str, namespace: &str) -> String {
+    format!(
+        "{}/dbs/{}/data",
+        database,
+        namespace.strip_prefix("ns-").unwrap()
+    )
+}
+
+/// Renders the progress of a restore on a single line of stderr, until the task is aborted.
+async fn render_progress(progress: Arc<RestoreProgress>) {
+    let mut ticker = tokio::time::interval(Duration::from_millis(250));
+    loop {
+        ticker.tick().await;
+        eprint!("\r\x1b[2KRestoring: {progress}");
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Makes sure that a database can be restored into `db_path`, removing the existing database
+/// (with its WAL files) only when `force` is set.
+async fn prepare_restore_target(db_path: &Path, force: bool) -> Result<()> {
+    if !tokio::fs::try_exists(db_path).await? {
+        return Ok(());
+    }
+    if !force {
+        bail!(
+            "database {} already exists, pass --force to overwrite it",
+            db_path.display()
+        );
+    }
+
+    tokio::fs::remove_file(db_path).await?;
+    for suffix in ["-wal", "-shm"] {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(suffix);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     if let Err(e) = run().await {
@@ -1,9 +1,31 @@
-use anyhow::Result;
-use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::types::ObjectAttributes;
-use aws_sdk_s3::Client;
-use aws_smithy_types::date_time::Format;
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use anyhow::{bail, Result};
+use bottomless::replicator::{ChecksumStatus, CompressionKind, UNCOMPRESSED_SIZE_METADATA};
+use bottomless::storage::ObjectStorage;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// Size of the header preceding each page in a WAL frame.
+const WAL_FRAME_HEADER_SIZE: u64 = 24;
+
+/// Location where `bottomless-cli copy` puts the copied generations.
+pub(crate) struct CopyTarget {
+    pub storage: Arc<dyn ObjectStorage>,
+    /// Name of the database in the destination, which prefixes the keys of its generations.
+    pub db_name: String,
+    /// Whether generations that already exist in the destination are overwritten.
+    pub force: bool,
+}
+
+impl CopyTarget {
+    /// Returns whether the destination already holds objects of `generation`.
+    async fn has_generation(&self, generation: &uuid::Uuid) -> Result<bool> {
+        let prefix = format!("{}-{}/", self.db_name, generation);
+        let response = self.storage.list(&prefix, false, None, Some(1)).await?;
+        Ok(!response.objects.is_empty())
+    }
+}
 
 pub(crate) struct Replicator {
     inner: bottomless::replicator::Replicator,
@@ -32,18 +54,11 @@ fn uuid_to_datetime(uuid: &uuid::Uuid) -> chrono::NaiveDateTime {
     chrono::NaiveDateTime::from_timestamp_millis((seconds * 1000) as i64).unwrap()
 }
 
-pub(crate) async fn detect_db(client: &Client, bucket: &str, namespace: &str) -> Option<String> {
+pub(crate) async fn detect_db(storage: &dyn ObjectStorage, namespace: &str) -> Option<String> {
     let namespace = namespace.to_owned() + ":";
-    let response = client
-        .list_objects()
-        .bucket(bucket)
-        .set_delimiter(Some("/".to_string()))
-        .prefix(namespace.clone())
-        .send()
-        .await
-        .ok()?;
-
-    let prefix = response.common_prefixes()?.first()?.prefix()?;
+    let response = storage.list(&namespace, true, None, None).await.ok()?;
+
+    let prefix = response.common_prefixes.first()?;
     // 38 is the length of the uuid part
     if let Some('-') = prefix.chars().nth(prefix.len().saturating_sub(38)) {
         let ns_db = &prefix[..prefix.len().saturating_sub(38)];
@@ -60,32 +75,107 @@ impl Replicator {
     }
 
     pub(crate) async fn print_snapshot_summary(&self, generation: &uuid::Uuid) -> Result<()> {
-        match self
-            .client
-            .get_object_attributes()
-            .bucket(&self.bucket)
-            .key(format!("{}-{}/db.gz", self.db_name, generation))
-            .object_attributes(ObjectAttributes::ObjectSize)
-            .send()
-            .await
-        {
-            Ok(attrs) => {
-                println!("\tmain database snapshot:");
-                println!("\t\tobject size:   {}", attrs.object_size());
-                println!(
-                    "\t\tlast modified: {}",
-                    attrs
-                        .last_modified()
-                        .map(|s| s.fmt(Format::DateTime).unwrap_or_else(|e| e.to_string()))
-                        .as_deref()
-                        .unwrap_or("never")
-                );
-            }
-            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => {
-                println!("\tno main database snapshot file found")
+        for (key, compression) in self.snapshot_keys(generation) {
+            match self.storage.head(&key).await {
+                Ok(Some(head)) => {
+                    let uncompressed_size = head
+                        .metadata
+                        .get(UNCOMPRESSED_SIZE_METADATA)
+                        .map(String::as_str)
+                        .unwrap_or("unknown");
+                    println!("\tmain database snapshot:");
+                    println!("\t\tcompression:       {}", compression);
+                    println!("\t\tobject size:       {}", head.size);
+                    println!("\t\tuncompressed size: {}", uncompressed_size);
+                    println!(
+                        "\t\tlast modified:     {}",
+                        head.last_modified
+                            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+                            .as_deref()
+                            .unwrap_or("never")
+                    );
+                    return Ok(());
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("\tfailed to fetch main database snapshot info: {e}");
+                    return Ok(());
+                }
             }
-            Err(e) => println!("\tfailed to fetch main database snapshot info: {e}"),
+        }
+        println!("\tno main database snapshot file found");
+        Ok(())
+    }
+
+    pub(crate) async fn print_encryption_summary(&self, generation: &uuid::Uuid) -> Result<()> {
+        let encrypted = match self.is_generation_encrypted(generation).await? {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "unknown",
         };
+        println!("\tencrypted:            {encrypted}");
+        Ok(())
+    }
+
+    pub(crate) async fn print_checksum_summary(&self, generation: &uuid::Uuid) -> Result<()> {
+        let status = match self.verify_generation(generation).await? {
+            ChecksumStatus::Verified => "verified".to_string(),
+            ChecksumStatus::Unknown => "unknown".to_string(),
+            ChecksumStatus::Mismatch(key) => format!("MISMATCH in {key}"),
+        };
+        println!("\tchecksums:            {status}");
+        Ok(())
+    }
+
+    /// Prints the total size of WAL frame batches of the generation, per compression kind.
+    /// Batches hold whole frames, so their uncompressed size is derived from the frame range.
+    pub(crate) async fn print_wal_summary(
+        &self,
+        generation: &uuid::Uuid,
+        page_size: Option<u32>,
+    ) -> Result<()> {
+        // compression -> (batch count, compressed size, uncompressed size)
+        let mut summary: BTreeMap<CompressionKind, (u64, u64, u64)> = BTreeMap::new();
+        let prefix = format!("{}-{}/", &self.db_name, generation);
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            for obj in &response.objects {
+                let Some((first_frame_no, last_frame_no, _, compression)) =
+                    bottomless::replicator::Replicator::parse_frame_range(&obj.key)
+                else {
+                    continue;
+                };
+                let frames = (last_frame_no - first_frame_no + 1) as u64;
+                let entry = summary.entry(compression).or_default();
+                entry.0 += 1;
+                entry.1 += obj.size;
+                entry.2 += frames * (page_size.unwrap_or_default() as u64 + WAL_FRAME_HEADER_SIZE);
+            }
+
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                break;
+            }
+        }
+
+        if summary.is_empty() {
+            println!("\tno WAL frame batches found");
+            return Ok(());
+        }
+        for (compression, (batches, compressed_size, uncompressed_size)) in summary {
+            println!("\tWAL frame batches ({compression}):");
+            println!("\t\tbatch count:       {batches}");
+            println!("\t\tobject size:       {compressed_size}");
+            if page_size.is_some() {
+                println!("\t\tuncompressed size: {uncompressed_size}");
+            } else {
+                println!("\t\tuncompressed size: unknown");
+            }
+        }
         Ok(())
     }
 
@@ -95,64 +185,58 @@ impl Replicator {
         older_than: Option<chrono::NaiveDate>,
         newer_than: Option<chrono::NaiveDate>,
         verbose: bool,
+        verify: bool,
     ) -> Result<()> {
         let mut next_marker = None;
         let mut limit = limit.unwrap_or(u64::MAX);
         loop {
-            let mut list_request = self
-                .client
-                .list_objects()
-                .bucket(&self.bucket)
-                .set_delimiter(Some("/".to_string()))
-                .prefix(&self.db_name);
-
-            if let Some(marker) = next_marker {
-                list_request = list_request.marker(marker)
-            }
-
             if verbose {
                 println!("Database {}:", self.db_name);
             }
 
-            let response = list_request.send().await?;
-            let prefixes = match response.common_prefixes() {
-                Some(prefixes) => prefixes,
-                None => {
-                    println!("No generations found");
-                    return Ok(());
+            let response = self
+                .storage
+                .list(&self.db_name, true, next_marker.as_deref(), None)
+                .await?;
+            if response.common_prefixes.is_empty() {
+                println!("No generations found");
+                return Ok(());
+            }
+
+            for prefix in &response.common_prefixes {
+                let prefix = &prefix[self.db_name.len() + 1..prefix.len() - 1];
+                let uuid = uuid::Uuid::try_parse(prefix)?;
+                let datetime = uuid_to_datetime(&uuid);
+                if datetime.date() < newer_than.unwrap_or(chrono::NaiveDate::MIN) {
+                    continue;
+                }
+                if datetime.date() > older_than.unwrap_or(chrono::NaiveDate::MAX) {
+                    continue;
                 }
-            };
-
-            for prefix in prefixes {
-                if let Some(prefix) = &prefix.prefix {
-                    let prefix = &prefix[self.db_name.len() + 1..prefix.len() - 1];
-                    let uuid = uuid::Uuid::try_parse(prefix)?;
-                    let datetime = uuid_to_datetime(&uuid);
-                    if datetime.date() < newer_than.unwrap_or(chrono::NaiveDate::MIN) {
-                        continue;
+                println!("{} (created: {})", uuid, datetime.and_utc().to_rfc3339());
+                if verbose {
+                    let counter = self.get_remote_change_counter(&uuid).await?;
+                    let consistent_frame = self.get_last_consistent_frame(&uuid).await?;
+                    let m = self.get_metadata(&uuid).await?;
+                    let parent = self.get_dependency(&uuid).await?;
+                    println!("\tcreated at (UTC):     {datetime}");
+                    println!("\tchange counter:       {counter:?}");
+                    println!("\tconsistent WAL frame: {consistent_frame}");
+                    if let Some((page_size, crc)) = m {
+                        println!("\tpage size:            {}", page_size);
+                        println!("\tWAL frame checksum:   {:x}", crc);
                     }
-                    if datetime.date() > older_than.unwrap_or(chrono::NaiveDate::MAX) {
-                        continue;
+                    if let Some(prev_gen) = parent {
+                        println!("\tprevious generation:  {}", prev_gen);
                     }
-                    println!("{} (created: {})", uuid, datetime.and_utc().to_rfc3339());
-                    if verbose {
-                        let counter = self.get_remote_change_counter(&uuid).await?;
-                        let consistent_frame = self.get_last_consistent_frame(&uuid).await?;
-                        let m = self.get_metadata(&uuid).await?;
-                        let parent = self.get_dependency(&uuid).await?;
-                        println!("\tcreated at (UTC):     {datetime}");
-                        println!("\tchange counter:       {counter:?}");
-                        println!("\tconsistent WAL frame: {consistent_frame}");
-                        if let Some((page_size, crc)) = m {
-                            println!("\tpage size:            {}", page_size);
-                            println!("\tWAL frame checksum:   {:x}", crc);
-                        }
-                        if let Some(prev_gen) = parent {
-                            println!("\tprevious generation:  {}", prev_gen);
-                        }
-                        self.print_snapshot_summary(&uuid).await?;
-                        println!()
+                    self.print_encryption_summary(&uuid).await?;
+                    if verify {
+                        self.print_checksum_summary(&uuid).await?;
                     }
+                    self.print_snapshot_summary(&uuid).await?;
+                    self.print_wal_summary(&uuid, m.map(|(page_size, _)| page_size))
+                        .await?;
+                    println!()
                 }
                 limit -= 1;
                 if limit == 0 {
@@ -160,7 +244,7 @@ impl Replicator {
                 }
             }
 
-            next_marker = response.next_marker().map(|s| s.to_owned());
+            next_marker = response.next_marker;
             if next_marker.is_none() {
                 return Ok(());
             }
@@ -184,46 +268,30 @@ impl Replicator {
     }
 
     pub(crate) async fn remove(&self, generation: uuid::Uuid, verbose: bool) -> Result<()> {
+        let prefix = format!("{}-{}/", &self.db_name, generation);
         let mut removed = 0;
         let mut next_marker = None;
         loop {
-            let mut list_request = self
-                .client
-                .list_objects()
-                .bucket(&self.bucket)
-                .prefix(format!("{}-{}/", &self.db_name, generation));
-
-            if let Some(marker) = next_marker {
-                list_request = list_request.marker(marker)
-            }
-
-            let response = list_request.send().await?;
-            let objs = match response.contents() {
-                Some(prefixes) => prefixes,
-                None => {
-                    if verbose {
-                        println!("No objects found")
-                    }
-                    return Ok(());
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            if response.objects.is_empty() {
+                if verbose {
+                    println!("No objects found")
                 }
-            };
+                return Ok(());
+            }
 
-            for obj in objs {
-                if let Some(key) = obj.key() {
-                    if verbose {
-                        println!("Removing {key}")
-                    }
-                    self.client
-                        .delete_object()
-                        .bucket(&self.bucket)
-                        .key(key)
-                        .send()
-                        .await?;
-                    removed += 1;
+            for obj in &response.objects {
+                if verbose {
+                    println!("Removing {}", obj.key)
                 }
+                self.storage.delete(&obj.key).await?;
+                removed += 1;
             }
 
-            next_marker = response.next_marker().map(|s| s.to_owned());
+            next_marker = response.next_marker;
             if next_marker.is_none() {
                 if verbose {
                     println!("Removed {removed} snapshot generations");
@@ -233,18 +301,165 @@ impl Replicator {
         }
     }
 
-    pub(crate) async fn list_generation(&self, generation: uuid::Uuid) -> Result<()> {
-        self.client
-            .list_objects()
-            .bucket(&self.bucket)
-            .prefix(format!("{}-{}/", &self.db_name, generation))
-            .max_keys(1)
-            .send()
-            .await?
-            .contents()
-            .ok_or_else(|| {
-                anyhow::anyhow!("Generation {} not found for {}", generation, &self.db_name)
-            })?;
+    /// Returns all the generations of the database.
+    async fn all_generations(&self) -> Result<Vec<uuid::Uuid>> {
+        let prefix = format!("{}-", &self.db_name);
+        let mut generations = Vec::new();
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, true, next_marker.as_deref(), None)
+                .await?;
+            for prefix in &response.common_prefixes {
+                let prefix = &prefix[self.db_name.len() + 1..prefix.len() - 1];
+                generations.push(uuid::Uuid::try_parse(prefix)?);
+            }
+
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                return Ok(generations);
+            }
+        }
+    }
+
+    /// Copies generations to another database name and/or bucket, with server-side copies.
+    /// If `generation` is passed, only this generation and the generations it depends on are
+    /// copied, otherwise all the generations of the database are. Generations keep their ids, so
+    /// the dependencies between them still hold in the destination.
+    pub(crate) async fn copy(
+        &self,
+        target: &CopyTarget,
+        generation: Option<uuid::Uuid>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let generations = match generation {
+            Some(generation) => {
+                let mut chain = vec![generation];
+                let mut current = generation;
+                while let Some(parent) = self.get_dependency(&current).await? {
+                    if chain.contains(&parent) {
+                        break;
+                    }
+                    chain.push(parent);
+                    current = parent;
+                }
+                chain
+            }
+            None => self.all_generations().await?,
+        };
+        if generations.is_empty() {
+            println!("No generations found for {}", self.db_name);
+            return Ok(());
+        }
+
+        // nothing is copied if any generation conflicts, rather than leaving a partial copy behind
+        if !target.force {
+            for generation in &generations {
+                if target.has_generation(generation).await? {
+                    bail!(
+                        "generation {} already exists in {}/{}, pass --force to overwrite it",
+                        generation,
+                        target.storage.bucket(),
+                        target.db_name
+                    );
+                }
+            }
+        }
+
+        let mut copied = BTreeSet::new();
+        let (mut objects, mut bytes) = (0u64, 0u64);
+        for generation in generations {
+            if !copied.insert(generation) {
+                continue;
+            }
+            println!("Generation {generation}:");
+            let (generation_objects, generation_bytes) =
+                self.copy_generation(target, &generation, dry_run).await?;
+            if generation_objects == 0 {
+                println!("	no objects found");
+            }
+            objects += generation_objects;
+            bytes += generation_bytes;
+        }
+
+        let verb = if dry_run { "Would copy" } else { "Copied" };
+        println!(
+            "{verb} {objects} objects ({bytes} bytes) of {} generations to {}/{}",
+            copied.len(),
+            target.storage.bucket(),
+            target.db_name
+        );
+        Ok(())
+    }
+
+    /// Copies all the objects of a generation, returning their count and total size. The objects
+    /// are listed first, so that the progress of the copy can be shown on a terminal.
+    async fn copy_generation(
+        &self,
+        target: &CopyTarget,
+        generation: &uuid::Uuid,
+        dry_run: bool,
+    ) -> Result<(u64, u64)> {
+        let prefix = format!("{}-{}/", &self.db_name, generation);
+        let dest_bucket = target.storage.bucket();
+        let mut objects = Vec::new();
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            objects.extend(response.objects);
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                break;
+            }
+        }
+        let bytes = objects.iter().map(|obj| obj.size).sum::<u64>();
+
+        // the progress bar is hidden when stderr is not a terminal
+        let progress = if dry_run {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(bytes)
+        };
+        progress.set_style(ProgressStyle::with_template(
+            "{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)",
+        )?);
+        for obj in &objects {
+            let key = &obj.key;
+            let dest_key = format!("{}{}", target.db_name, &key[self.db_name.len()..]);
+            if dry_run {
+                println!(
+                    "\twould copy {key} -> {dest_bucket}/{dest_key} ({} bytes)",
+                    obj.size
+                );
+            } else {
+                target
+                    .storage
+                    .copy(self.storage.bucket(), key, &dest_key, obj.size)
+                    .await?;
+                progress.suspend(|| {
+                    println!(
+                        "\tcopied {key} -> {dest_bucket}/{dest_key} ({} bytes)",
+                        obj.size
+                    )
+                });
+                progress.inc(obj.size);
+            }
+        }
+        progress.finish_and_clear();
+
+        Ok((objects.len() as u64, bytes))
+    }
+
+    pub(crate) async fn list_generation(&self, generation: uuid::Uuid, verify: bool) -> Result<()> {
+        let prefix = format!("{}-{}/", &self.db_name, generation);
+        let response = self.storage.list(&prefix, false, None, Some(1)).await?;
+        if response.objects.is_empty() {
+            bail!("Generation {} not found for {}", generation, &self.db_name);
+        }
 
         let counter = self.get_remote_change_counter(&generation).await?;
         let consistent_frame = self.get_last_consistent_frame(&generation).await?;
@@ -261,7 +476,13 @@ impl Replicator {
         if let Some(prev_gen) = dep {
             println!("\tprevious generation:  {}", prev_gen);
         }
+        self.print_encryption_summary(&generation).await?;
+        if verify {
+            self.print_checksum_summary(&generation).await?;
+        }
         self.print_snapshot_summary(&generation).await?;
+        self.print_wal_summary(&generation, meta.map(|(page_size, _)| page_size))
+            .await?;
         Ok(())
     }
 }
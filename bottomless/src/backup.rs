@@ -1,27 +1,48 @@
-use crate::replicator::CompressionKind;
+use crate::encryption::{self, EncryptionKey};
+use crate::replicator::{compression_level, CompressionKind};
 use crate::wal::WalFileReader;
 use anyhow::{anyhow, bail, Result};
 use arc_swap::ArcSwapOption;
+use sha2::{Digest, Sha256};
 use std::ops::Range;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::Sender;
 use tokio::time::Instant;
 use uuid::Uuid;
 
+/// Suffix of the per-generation objects listing the SHA-256 digests of the uploaded frame batches
+/// and snapshot. Each flush uploads the digests of its own batches as a `{first-frame-no}.manifest.sha256`
+/// segment, so that the manifest is never rewritten, and is extended across restarts.
+pub(crate) const MANIFEST_FILE: &str = "manifest.sha256";
+/// Manifest segment listing the SHA-256 digest of the snapshot of the generation.
+pub(crate) const SNAPSHOT_MANIFEST_FILE: &str = "snapshot.manifest.sha256";
+/// Name of the per-generation object mapping last frames of committed transactions to their
+/// commit timestamps. Each entry is a big-endian `u32` frame number followed by a big-endian
+/// `i64` UTC timestamp in milliseconds.
+pub(crate) const FRAMES_INDEX_FILE: &str = "frames.index";
+pub(crate) const FRAMES_INDEX_ENTRY_SIZE: usize = 12;
+
+/// Commits which were not backed up yet, as pairs of (last frame number, UTC timestamp in millis).
+pub(crate) type PendingCommits = Arc<Mutex<Vec<(u32, i64)>>>;
+
 #[derive(Debug)]
 pub(crate) struct WalCopier {
     wal: Option<WalFileReader>,
     outbox: Sender<String>,
     use_compression: CompressionKind,
+    compression_level: Option<u32>,
+    encryption_key: Option<EncryptionKey>,
     max_frames_per_batch: usize,
     wal_path: String,
     bucket: String,
     db_name: Arc<str>,
     generation: Arc<ArcSwapOption<Uuid>>,
+    pending_commits: PendingCommits,
 }
 
 impl WalCopier {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         bucket: String,
         db_name: Arc<str>,
@@ -29,7 +50,10 @@ impl WalCopier {
         db_path: &str,
         max_frames_per_batch: usize,
         use_compression: CompressionKind,
+        compression_level: Option<u32>,
+        encryption_key: Option<EncryptionKey>,
         outbox: Sender<String>,
+        pending_commits: PendingCommits,
     ) -> Self {
         WalCopier {
             wal: None,
@@ -40,6 +64,9 @@ impl WalCopier {
             outbox,
             max_frames_per_batch,
             use_compression,
+            compression_level,
+            encryption_key,
+            pending_commits,
         }
     }
 
@@ -89,6 +116,8 @@ impl WalCopier {
         }
         tracing::trace!("Flushing {} frames locally.", frames.len());
 
+        // entries of the manifest segment of the flushed batches, in `sha256sum` format
+        let mut manifest = Vec::new();
         for start in frames.clone().step_by(self.max_frames_per_batch) {
             let period_start = Instant::now();
             let timestamp = chrono::Utc::now().timestamp() as u64;
@@ -112,10 +141,21 @@ impl WalCopier {
                     out.shutdown().await?;
                 }
                 CompressionKind::Gzip => {
-                    let mut gzip = async_compression::tokio::write::GzipEncoder::new(&mut out);
+                    let mut gzip = async_compression::tokio::write::GzipEncoder::with_quality(
+                        &mut out,
+                        compression_level(self.compression_level),
+                    );
                     wal.copy_frames(&mut gzip, len).await?;
                     gzip.shutdown().await?;
                 }
+                CompressionKind::Zstd => {
+                    let mut zstd = async_compression::tokio::write::ZstdEncoder::with_quality(
+                        &mut out,
+                        compression_level(self.compression_level),
+                    );
+                    wal.copy_frames(&mut zstd, len).await?;
+                    zstd.shutdown().await?;
+                }
             }
             if tracing::enabled!(tracing::Level::DEBUG) {
                 let elapsed = Instant::now() - period_start;
@@ -123,6 +163,13 @@ impl WalCopier {
                 tracing::debug!("written {} bytes to {} in {:?}", file_len, fdesc, elapsed);
             }
             drop(out);
+            if let Some(key) = &self.encryption_key {
+                encryption::encrypt_file(key, &format!("{}/{}", self.bucket, fdesc)).await?;
+            }
+            let digest =
+                Sha256::digest(tokio::fs::read(&format!("{}/{}", self.bucket, fdesc)).await?);
+            let fname = &fdesc[(fdesc.rfind('/').unwrap() + 1)..];
+            manifest.push(format!("{:x}  {}\n", digest, fname));
             if self.outbox.send(fdesc).await.is_err() {
                 tracing::warn!(
                     "WAL local cloning ended prematurely. Last cloned frame no.: {}",
@@ -131,6 +178,54 @@ impl WalCopier {
                 return Ok(end - 1);
             }
         }
+        let segment = format!("{:012}.{}", frames.start, MANIFEST_FILE);
+        tokio::fs::write(format!("{}/{}", dir, segment), manifest.concat()).await?;
+        let msg = format!("{}-{}/{}", self.db_name, generation, segment);
+        if self.outbox.send(msg).await.is_err() {
+            tracing::warn!("Couldn't request upload of {}/{}", dir, segment);
+        }
+        self.append_frames_index(&dir, &generation, &frames).await?;
         Ok(frames.end - 1)
     }
+
+    /// Appends commit timestamps of flushed `frames` to the local frames index file of the
+    /// generation, which is then uploaded as a whole. The local file is never truncated, so that
+    /// the uploaded object always contains the full index of the generation.
+    async fn append_frames_index(
+        &mut self,
+        dir: &str,
+        generation: &Uuid,
+        frames: &Range<u32>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        {
+            let mut pending = self.pending_commits.lock().unwrap();
+            pending.retain(|&(frame_no, timestamp)| {
+                if frame_no >= frames.end {
+                    return true;
+                }
+                if frame_no >= frames.start {
+                    buf.extend_from_slice(&frame_no.to_be_bytes());
+                    buf.extend_from_slice(&timestamp.to_be_bytes());
+                }
+                false
+            });
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let index_path = format!("{}/{}", dir, FRAMES_INDEX_FILE);
+        let mut index = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)
+            .await?;
+        index.write_all(&buf).await?;
+        index.flush().await?;
+        let msg = format!("{}-{}/{}", self.db_name, generation, FRAMES_INDEX_FILE);
+        if self.outbox.send(msg).await.is_err() {
+            tracing::warn!("Couldn't request upload of {}", index_path);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,269 @@
+//! Client-side encryption of the objects uploaded to S3.
+//!
+//! Encrypted objects start with [ENCRYPTION_MAGIC], followed by a random nonce prefix and the
+//! content encrypted with XChaCha20-Poly1305 in chunks of [CHUNK_SIZE] bytes, using the STREAM
+//! construction, so that large snapshots never have to be held in memory. Objects which don't
+//! start with the magic are not encrypted, which lets generations backed up before encryption was
+//! enabled be restored.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use rand::RngCore;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+/// Header of every encrypted object.
+pub const ENCRYPTION_MAGIC: &[u8; 8] = b"BTMLSEC1";
+/// Size of a plaintext chunk. Every chunk is followed by its authentication tag.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+/// XChaCha20 nonce is 24 bytes, 5 of which are used by the STREAM construction.
+const NONCE_PREFIX_SIZE: usize = 19;
+
+/// A 256-bit key used to encrypt backups.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+impl EncryptionKey {
+    /// Parses a key from a string of 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = hex::decode(hex.trim()).context("encryption key is not a valid hex string")?;
+        let key = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            anyhow!("encryption key must be 32 bytes long, got {}", bytes.len())
+        })?;
+        Ok(EncryptionKey(key))
+    }
+
+    /// Reads a key from a file, which contains either the raw 32 bytes of the key, or its hex
+    /// representation.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("failed to read encryption key file {}", path.display()))?;
+        match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(key) => Ok(EncryptionKey(key)),
+            Err(_) => Self::from_hex(
+                std::str::from_utf8(&bytes)
+                    .with_context(|| format!("invalid encryption key file {}", path.display()))?,
+            ),
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(GenericArray::from_slice(&self.0))
+    }
+}
+
+/// Returns the key needed to decrypt an encrypted object.
+fn required_key(key: Option<&EncryptionKey>) -> Result<&EncryptionKey> {
+    key.ok_or_else(|| anyhow!("backup is encrypted, an encryption key is required to restore it"))
+}
+
+/// Reads into `buf` until it's full or the reader is exhausted, returning the number of bytes read.
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Encrypts the content of `reader` into `writer`, including the header.
+pub(crate) async fn encrypt<R, W>(key: &EncryptionKey, mut reader: R, mut writer: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut nonce = [0u8; NONCE_PREFIX_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut encryptor = EncryptorBE32::from_aead(key.cipher(), GenericArray::from_slice(&nonce));
+    writer.write_all(ENCRYPTION_MAGIC).await?;
+    writer.write_all(&nonce).await?;
+
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut len = read_full(&mut reader, &mut chunk).await?;
+    loop {
+        // the last chunk is encrypted differently, so we need to look ahead to recognize it
+        let next_len = if len == CHUNK_SIZE {
+            read_full(&mut reader, &mut next).await?
+        } else {
+            0
+        };
+        if next_len == 0 {
+            let encrypted = encryptor
+                .encrypt_last(&chunk[..len])
+                .map_err(|_| anyhow!("failed to encrypt backup"))?;
+            writer.write_all(&encrypted).await?;
+            break;
+        }
+        let encrypted = encryptor
+            .encrypt_next(&chunk[..len])
+            .map_err(|_| anyhow!("failed to encrypt backup"))?;
+        writer.write_all(&encrypted).await?;
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Decrypts the content of `reader`, positioned right after the header, into `writer`.
+async fn decrypt<R, W>(key: &EncryptionKey, mut reader: R, mut writer: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut nonce = [0u8; NONCE_PREFIX_SIZE];
+    if read_full(&mut reader, &mut nonce).await? != NONCE_PREFIX_SIZE {
+        bail!("encrypted backup object is truncated");
+    }
+    let mut decryptor = DecryptorBE32::from_aead(key.cipher(), GenericArray::from_slice(&nonce));
+
+    let mut chunk = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut len = read_full(&mut reader, &mut chunk).await?;
+    loop {
+        let next_len = if len == chunk.len() {
+            read_full(&mut reader, &mut next).await?
+        } else {
+            0
+        };
+        if next_len == 0 {
+            let decrypted = decryptor.decrypt_last(&chunk[..len]).map_err(|_| {
+                anyhow!("failed to decrypt backup: invalid encryption key or corrupted object")
+            })?;
+            writer.write_all(&decrypted).await?;
+            break;
+        }
+        let decrypted = decryptor.decrypt_next(&chunk[..len]).map_err(|_| {
+            anyhow!("failed to decrypt backup: invalid encryption key or corrupted object")
+        })?;
+        writer.write_all(&decrypted).await?;
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Checks if an object starting with `header` is encrypted.
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(ENCRYPTION_MAGIC)
+}
+
+/// Encrypts the file at `path`, replacing its content.
+pub(crate) async fn encrypt_file(key: &EncryptionKey, path: &str) -> Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let src = tokio::fs::File::open(path).await?;
+    let dst = tokio::fs::File::create(&tmp_path).await?;
+    encrypt(key, src, dst).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Returns the content of an object, decrypted if it's encrypted.
+pub(crate) async fn maybe_decrypt_bytes(
+    key: Option<&EncryptionKey>,
+    data: bytes::Bytes,
+) -> Result<bytes::Bytes> {
+    if !is_encrypted(&data) {
+        return Ok(data);
+    }
+    let key = required_key(key)?;
+    let mut decrypted = Vec::with_capacity(data.len());
+    decrypt(key, &data[ENCRYPTION_MAGIC.len()..], &mut decrypted).await?;
+    Ok(decrypted.into())
+}
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Wraps the reader of an object, so that it returns the decrypted content if the object is
+/// encrypted. Decryption is done by a background task, whose result must be checked once the
+/// reader is exhausted and dropped, since a failed decryption just ends the stream early.
+pub(crate) async fn maybe_decrypt_reader<R>(
+    key: Option<&EncryptionKey>,
+    mut reader: R,
+) -> Result<(BoxedReader, Option<JoinHandle<Result<()>>>)>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let mut header = vec![0u8; ENCRYPTION_MAGIC.len()];
+    let len = read_full(&mut reader, &mut header).await?;
+    header.truncate(len);
+    if !is_encrypted(&header) {
+        return Ok((Box::new(std::io::Cursor::new(header).chain(reader)), None));
+    }
+    let key = required_key(key)?.clone();
+    let (rx, tx) = tokio::io::duplex(CHUNK_SIZE);
+    let decryption = tokio::spawn(async move { decrypt(&key, reader, tx).await });
+    Ok((Box::new(rx), Some(decryption)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn encryption_roundtrip() {
+        let key = EncryptionKey([7; 32]);
+        for len in [0, 1, CHUNK_SIZE, 3 * CHUNK_SIZE + 17] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut encrypted = Vec::new();
+            encrypt(&key, data.as_slice(), &mut encrypted)
+                .await
+                .unwrap();
+            assert!(is_encrypted(&encrypted));
+
+            let decrypted = maybe_decrypt_bytes(Some(&key), encrypted.clone().into())
+                .await
+                .unwrap();
+            assert_eq!(decrypted.as_ref(), data.as_slice());
+
+            let (mut reader, decryption) =
+                maybe_decrypt_reader(Some(&key), std::io::Cursor::new(encrypted))
+                    .await
+                    .unwrap();
+            let mut streamed = Vec::new();
+            reader.read_to_end(&mut streamed).await.unwrap();
+            decryption.unwrap().await.unwrap().unwrap();
+            assert_eq!(streamed, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn decryption_requires_valid_key() {
+        let key = EncryptionKey([7; 32]);
+        let mut encrypted = Vec::new();
+        encrypt(&key, b"hello".as_slice(), &mut encrypted)
+            .await
+            .unwrap();
+
+        let err = maybe_decrypt_bytes(None, encrypted.clone().into())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("backup is encrypted"));
+        assert!(
+            maybe_decrypt_bytes(Some(&EncryptionKey([8; 32])), encrypted.into())
+                .await
+                .is_err()
+        );
+
+        let plain = maybe_decrypt_bytes(None, bytes::Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(plain.as_ref(), b"hello");
+    }
+}
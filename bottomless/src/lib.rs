@@ -5,8 +5,13 @@
 mod ffi;
 
 mod backup;
+pub mod encryption;
+pub mod progress;
 mod read;
 pub mod replicator;
+pub mod retention;
+pub mod status;
+pub mod storage;
 mod transaction_cache;
 pub mod uuid_utils;
 mod wal;
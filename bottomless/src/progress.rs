@@ -0,0 +1,135 @@
+//! Progress reporting of database restores.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Interval at which the progress of a restore is logged.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Progress of the restore of a database, updated while it runs. The total number of bytes grows
+/// as the objects to download are discovered.
+#[derive(Debug, Default)]
+pub struct RestoreProgress {
+    downloaded_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    frames_applied: AtomicU64,
+    /// Upload time of the frame batch holding the last applied transaction, in seconds since
+    /// the epoch, or 0 if no transaction was applied.
+    last_applied_batch_time: AtomicU64,
+}
+
+impl RestoreProgress {
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_applied(&self) -> u64 {
+        self.frames_applied.load(Ordering::Relaxed)
+    }
+
+    /// Time at which the last transaction applied from the WAL was uploaded, with the precision
+    /// of the frame batch timestamps. `None` if the restore only used a snapshot.
+    pub fn last_applied_at(&self) -> Option<DateTime<Utc>> {
+        match self.last_applied_batch_time.load(Ordering::Relaxed) {
+            0 => None,
+            secs => NaiveDateTime::from_timestamp_opt(secs as i64, 0).map(|t| t.and_utc()),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.downloaded_bytes.store(0, Ordering::Relaxed);
+        self.total_bytes.store(0, Ordering::Relaxed);
+        self.frames_applied.store(0, Ordering::Relaxed);
+        self.last_applied_batch_time.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_total(&self, bytes: u64) {
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_frame(&self) {
+        self.frames_applied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_last_applied_batch_time(&self, timestamp: u64) {
+        self.last_applied_batch_time
+            .store(timestamp, Ordering::Relaxed);
+    }
+
+    /// Logs the progress at a steady interval, until the returned guard is dropped.
+    pub(crate) fn log_periodically(self: &Arc<Self>, db_name: &str) -> ProgressLogger {
+        let progress = self.clone();
+        let db_name = db_name.to_string();
+        ProgressLogger(tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + LOG_INTERVAL, LOG_INTERVAL);
+            loop {
+                ticker.tick().await;
+                tracing::info!("Restoring '{}': {}", db_name, progress);
+            }
+        }))
+    }
+}
+
+impl fmt::Display for RestoreProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let downloaded = self.downloaded_bytes();
+        let total = self.total_bytes();
+        write!(f, "downloaded {downloaded}/{total} bytes")?;
+        if let Some(percent) = (downloaded.min(total) * 100).checked_div(total) {
+            write!(f, " ({percent}%)")?;
+        }
+        write!(f, ", applied {} frames", self.frames_applied())
+    }
+}
+
+/// Stops logging the progress of a restore when dropped.
+pub(crate) struct ProgressLogger(JoinHandle<()>);
+
+impl Drop for ProgressLogger {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_progress() {
+        let progress = RestoreProgress::default();
+        assert_eq!(
+            progress.to_string(),
+            "downloaded 0/0 bytes, applied 0 frames"
+        );
+        progress.add_total(200);
+        progress.add_downloaded(50);
+        progress.add_frame();
+        assert_eq!(
+            progress.to_string(),
+            "downloaded 50/200 bytes (25%), applied 1 frames"
+        );
+
+        assert_eq!(progress.last_applied_at(), None);
+        progress.set_last_applied_batch_time(1_700_000_000);
+        assert_eq!(
+            progress.last_applied_at().unwrap().timestamp(),
+            1_700_000_000
+        );
+        progress.reset();
+        assert_eq!(progress.last_applied_at(), None);
+    }
+}
@@ -1,12 +1,11 @@
 use crate::replicator::CompressionKind;
 use crate::wal::WalFrameHeader;
 use anyhow::Result;
-use async_compression::tokio::bufread::GzipDecoder;
-use aws_sdk_s3::primitives::ByteStream;
-use std::io::ErrorKind;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use bytes::Bytes;
+use std::io::{Cursor, ErrorKind};
 use std::pin::Pin;
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
-use tokio_util::io::StreamReader;
 
 type AsyncByteReader = dyn AsyncRead + Send + Sync;
 
@@ -18,12 +17,12 @@ pub(crate) struct BatchReader {
 impl BatchReader {
     pub fn new(
         init_frame_no: u32,
-        content: ByteStream,
+        content: Bytes,
         page_size: usize,
         use_compression: CompressionKind,
     ) -> Self {
         let reader =
-            BufReader::with_capacity(page_size + WalFrameHeader::SIZE, StreamReader::new(content));
+            BufReader::with_capacity(page_size + WalFrameHeader::SIZE, Cursor::new(content));
         BatchReader {
             next_frame_no: init_frame_no,
             reader: match use_compression {
@@ -32,6 +31,10 @@ impl BatchReader {
                     let gzip = GzipDecoder::new(reader);
                     Box::pin(gzip)
                 }
+                CompressionKind::Zstd => {
+                    let zstd = ZstdDecoder::new(reader);
+                    Box::pin(zstd)
+                }
             },
         }
     }
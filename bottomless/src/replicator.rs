@@ -1,26 +1,32 @@
-use crate::backup::WalCopier;
+use crate::backup::{
+    PendingCommits, WalCopier, FRAMES_INDEX_ENTRY_SIZE, FRAMES_INDEX_FILE, MANIFEST_FILE,
+    SNAPSHOT_MANIFEST_FILE,
+};
+use crate::encryption::{self, EncryptionKey};
+use crate::progress::RestoreProgress;
 use crate::read::BatchReader;
+use crate::retention::{RetentionPolicy, RetentionTask};
+use crate::status::ReplicatorStatus;
+use crate::storage::{CloudStorage, ListPage, ObjectStorage, S3Storage, StorageBackend};
 use crate::transaction_cache::TransactionPageCache;
 use crate::uuid_utils::decode_unix_timestamp;
 use crate::wal::WalFileReader;
 use anyhow::{anyhow, bail};
 use arc_swap::ArcSwapOption;
-use async_compression::tokio::write::GzipEncoder;
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use async_compression::Level;
 use aws_sdk_s3::config::{Credentials, Region};
-use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::operation::get_object::builders::GetObjectFluentBuilder;
-use aws_sdk_s3::operation::get_object::GetObjectError;
-use aws_sdk_s3::operation::list_objects::builders::ListObjectsFluentBuilder;
-use aws_sdk_s3::operation::list_objects::ListObjectsOutput;
-use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::{Client, Config};
 use bytes::{Buf, Bytes};
 use chrono::{NaiveDateTime, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
 use std::io::SeekFrom;
 use std::ops::Deref;
 use std::path::Path;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
@@ -35,11 +41,19 @@ use uuid::{NoContext, Uuid};
 /// consecutive generations has to have a snapshot included.
 const MAX_RESTORE_STACK_DEPTH: usize = 100;
 
+/// Size of the ranges in which snapshots are downloaded. A restore interrupted while downloading
+/// a snapshot resumes from the last downloaded range.
+const SNAPSHOT_DOWNLOAD_RANGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Name of the object metadata entry with the size of the main database file before it was
+/// compressed into a snapshot.
+pub const UNCOMPRESSED_SIZE_METADATA: &str = "uncompressed-size";
+
 pub type Result<T> = anyhow::Result<T>;
 
 #[derive(Debug)]
 pub struct Replicator {
-    pub client: Client,
+    pub storage: Arc<dyn ObjectStorage>,
 
     /// Frame number, incremented whenever a new frame is written from SQLite.
     next_frame_no: Arc<AtomicU32>,
@@ -52,19 +66,26 @@ pub struct Replicator {
     flush_trigger: Sender<()>,
     snapshot_waiter: Receiver<Result<Option<Uuid>>>,
     snapshot_notifier: Arc<Sender<Result<Option<Uuid>>>>,
+    pending_commits: PendingCommits,
 
     pub page_size: usize,
     restore_transaction_page_swap_after: u32,
     restore_transaction_cache_fpath: Arc<str>,
     generation: Arc<ArcSwapOption<Uuid>>,
     verify_crc: bool,
+    strict_verify: bool,
+    skip_verify: bool,
     pub bucket: String,
     pub db_path: String,
     pub db_name: String,
 
     use_compression: CompressionKind,
+    compression_level: Option<u32>,
+    encryption_key: Option<EncryptionKey>,
     max_frames_per_batch: usize,
     s3_upload_max_parallelism: usize,
+    restore_progress: Arc<RestoreProgress>,
+    status: Arc<Mutex<ReplicatorStatus>>,
     _join_set: JoinSet<()>,
 }
 
@@ -74,6 +95,18 @@ pub struct FetchedResults {
     pub next_marker: Option<String>,
 }
 
+/// Result of [Replicator::verify_generation].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// All the frame batches and the snapshot of the generation match their digests in the
+    /// manifest of the generation.
+    Verified,
+    /// Some objects have no entry in the manifest, and the other ones match theirs.
+    Unknown,
+    /// The object with the given key doesn't match its digest in the manifest.
+    Mismatch(String),
+}
+
 #[derive(Debug)]
 pub enum RestoreAction {
     SnapshotMainDbFile,
@@ -86,8 +119,26 @@ pub struct Options {
     /// If `true` when restoring, frames checksums will be verified prior their pages being flushed
     /// into the main database file.
     pub verify_crc: bool,
-    /// Kind of compression algorithm used on the WAL frames to be sent to S3.
+    /// If `true` when restoring, a frame batch which doesn't match its SHA-256 digest from
+    /// the generation's manifest fails the restore. Otherwise the restore stops with a warning at
+    /// the last valid frame preceding it. A snapshot which doesn't match its digest fails the
+    /// restore either way, as nothing can be restored without it.
+    pub strict_verify: bool,
+    /// If `true` when restoring, the downloaded objects are not verified against the manifest at
+    /// all. Meant as an escape hatch, to recover what's possible from a damaged generation.
+    pub skip_verify: bool,
+    /// Kind of compression algorithm used on the WAL frames and snapshots to be sent to S3.
+    /// Restore picks the decompressor from the object key, so it doesn't depend on this setting.
     pub use_compression: CompressionKind,
+    /// Compression level passed to the encoder. If not set, the codec's default level is used.
+    pub compression_level: Option<u32>,
+    /// Key used to encrypt the WAL frames and snapshots before they are sent to S3, and to
+    /// decrypt them when restoring. Objects which were not encrypted are restored without it.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Kind of storage the generations are backed up to. Credentials of Google Cloud Storage and
+    /// Azure Blob Storage are taken from their standard environment variables, while S3 uses
+    /// [Options::access_key_id], [Options::secret_access_key] and [Options::region].
+    pub backend: StorageBackend,
     pub aws_endpoint: Option<String>,
     pub access_key_id: Option<String>,
     pub secret_access_key: Option<String>,
@@ -96,6 +147,10 @@ pub struct Options {
     /// Bucket directory name where all S3 objects are backed up. General schema is:
     /// - `{db-name}-{uuid-v7}` subdirectories:
     ///   - `.meta` file with database page size and initial WAL checksum.
+    ///   - `{first-frame-no}.manifest.sha256` files with SHA-256 digests of the frame batch files
+    ///     uploaded by each flush, and `snapshot.manifest.sha256` with the digest of the snapshot.
+    ///   - `frames.index` file with commit timestamps of frames, used for point-in-time restore.
+    ///   - `db.{compression-kind}` file with the snapshot of the main database file.
     ///   - Series of files `{first-frame-no}-{last-frame-no}.{compression-kind}` containing
     ///     the batches of frames from which the restore will be made.
     pub bucket_name: String,
@@ -113,6 +168,10 @@ pub struct Options {
     /// When recovering a transaction, when its page cache needs to be swapped onto local file,
     /// this field contains a path for a file to be used.
     pub restore_transaction_cache_fpath: String,
+    /// Generations falling outside of this policy are periodically deleted.
+    pub retention: RetentionPolicy,
+    /// Interval between two enforcements of the retention policy.
+    pub retention_check_interval: Duration,
 }
 
 impl Options {
@@ -146,6 +205,28 @@ impl Options {
         Ok(conf)
     }
 
+    /// Opens the bucket (or container) where the generations are backed up.
+    pub async fn storage(&self) -> Result<Arc<dyn ObjectStorage>> {
+        let storage: Arc<dyn ObjectStorage> = match self.backend {
+            StorageBackend::S3 => {
+                let client = Client::from_conf(self.client_config().await?);
+                Arc::new(S3Storage::new(client, self.bucket_name.clone()))
+            }
+            StorageBackend::Gcs => Arc::new(CloudStorage::gcs(&self.bucket_name)?),
+            StorageBackend::Azure => Arc::new(CloudStorage::azure(&self.bucket_name)?),
+        };
+        Ok(storage)
+    }
+
+    /// Endpoint of the storage, as reported in the [ReplicatorStatus].
+    pub fn endpoint(&self) -> Option<String> {
+        match self.backend {
+            StorageBackend::S3 => self.aws_endpoint.clone(),
+            StorageBackend::Gcs => Some(format!("gs://{}", self.bucket_name)),
+            StorageBackend::Azure => Some(format!("azblob://{}", self.bucket_name)),
+        }
+    }
+
     pub fn from_env() -> Result<Self> {
         let mut options = Self::default();
         if let Ok(key) = std::env::var("LIBSQL_BOTTOMLESS_ENDPOINT") {
@@ -154,6 +235,28 @@ impl Options {
         if let Ok(bucket_name) = std::env::var("LIBSQL_BOTTOMLESS_BUCKET") {
             options.bucket_name = bucket_name;
         }
+        if let Ok(backend) = std::env::var("LIBSQL_BOTTOMLESS_BACKEND") {
+            match StorageBackend::parse(&backend) {
+                Ok(backend) => options.backend = backend,
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_BACKEND environment variable: {}",
+                    e
+                ),
+            }
+        }
+        // `gs://bucket` and `azblob://container` endpoints select the backend, and the bucket
+        // unless it's set explicitly
+        if let Some((backend, bucket_name)) = options
+            .aws_endpoint
+            .as_deref()
+            .and_then(StorageBackend::from_url)
+        {
+            options.backend = backend;
+            if std::env::var("LIBSQL_BOTTOMLESS_BUCKET").is_err() {
+                options.bucket_name = bucket_name;
+            }
+            options.aws_endpoint = None;
+        }
         if let Ok(seconds) = std::env::var("LIBSQL_BOTTOMLESS_BATCH_INTERVAL_SECS") {
             if let Ok(seconds) = seconds.parse::<u64>() {
                 options.max_batch_interval = Duration::from_secs(seconds);
@@ -209,6 +312,32 @@ impl Options {
                 ),
             }
         }
+        if let Ok(level) = std::env::var("LIBSQL_BOTTOMLESS_COMPRESSION_LEVEL") {
+            match level.parse::<u32>() {
+                Ok(level) => options.compression_level = Some(level),
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_COMPRESSION_LEVEL environment variable: {}",
+                    e
+                ),
+            }
+        }
+        if let Ok(key) = std::env::var("LIBSQL_BOTTOMLESS_ENCRYPTION_KEY") {
+            match EncryptionKey::from_hex(&key) {
+                Ok(key) => options.encryption_key = Some(key),
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_ENCRYPTION_KEY environment variable: {}",
+                    e
+                ),
+            }
+        } else if let Ok(fpath) = std::env::var("LIBSQL_BOTTOMLESS_ENCRYPTION_KEY_FILE") {
+            match EncryptionKey::from_file(&fpath) {
+                Ok(key) => options.encryption_key = Some(key),
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_ENCRYPTION_KEY_FILE environment variable: {}",
+                    e
+                ),
+            }
+        }
         if let Ok(verify) = std::env::var("LIBSQL_BOTTOMLESS_VERIFY_CRC") {
             match verify.to_lowercase().as_ref() {
                 "yes" | "true" | "1" | "y" | "t" => options.verify_crc = true,
@@ -219,6 +348,57 @@ impl Options {
                 ),
             }
         }
+        if let Ok(days) = std::env::var("LIBSQL_BOTTOMLESS_RETENTION_DAYS") {
+            match days.parse::<u32>() {
+                Ok(days) => options.retention.retention_days = Some(days),
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_RETENTION_DAYS environment variable: {}",
+                    e
+                ),
+            }
+        }
+        if let Ok(count) = std::env::var("LIBSQL_BOTTOMLESS_MAX_GENERATIONS") {
+            match count.parse::<usize>() {
+                Ok(count) if count > 0 => options.retention.max_generations = Some(count),
+                Ok(_) => bail!("LIBSQL_BOTTOMLESS_MAX_GENERATIONS must be greater than 0"),
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_MAX_GENERATIONS environment variable: {}",
+                    e
+                ),
+            }
+        }
+        if let Ok(seconds) = std::env::var("LIBSQL_BOTTOMLESS_RETENTION_CHECK_INTERVAL_SECS") {
+            match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => {
+                    options.retention_check_interval = Duration::from_secs(seconds)
+                }
+                Ok(_) => bail!("LIBSQL_BOTTOMLESS_RETENTION_CHECK_INTERVAL_SECS must be greater than 0"),
+                Err(e) => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_RETENTION_CHECK_INTERVAL_SECS environment variable: {}",
+                    e
+                ),
+            }
+        }
+        if let Ok(verify) = std::env::var("LIBSQL_BOTTOMLESS_STRICT_VERIFY") {
+            match verify.to_lowercase().as_ref() {
+                "yes" | "true" | "1" | "y" | "t" => options.strict_verify = true,
+                "no" | "false" | "0" | "n" | "f" => options.strict_verify = false,
+                other => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_STRICT_VERIFY environment variable: {}",
+                    other
+                ),
+            }
+        }
+        if let Ok(skip) = std::env::var("LIBSQL_BOTTOMLESS_SKIP_VERIFY") {
+            match skip.to_lowercase().as_ref() {
+                "yes" | "true" | "1" | "y" | "t" => options.skip_verify = true,
+                "no" | "false" | "0" | "n" | "f" => options.skip_verify = false,
+                other => bail!(
+                    "Invalid LIBSQL_BOTTOMLESS_SKIP_VERIFY environment variable: {}",
+                    other
+                ),
+            }
+        }
         Ok(options)
     }
 }
@@ -229,7 +409,12 @@ impl Default for Options {
         Options {
             create_bucket_if_not_exists: true,
             verify_crc: true,
+            strict_verify: false,
+            skip_verify: false,
             use_compression: CompressionKind::Gzip,
+            compression_level: None,
+            encryption_key: None,
+            backend: StorageBackend::default(),
             max_batch_interval: Duration::from_secs(15),
             max_frames_per_batch: 500, // basically half of the default SQLite checkpoint size
             s3_upload_max_parallelism: 32,
@@ -241,6 +426,8 @@ impl Default for Options {
             region: None,
             restore_transaction_cache_fpath: ".bottomless.restore".to_string(),
             bucket_name: "bottomless".to_string(),
+            retention: RetentionPolicy::default(),
+            retention_check_interval: Duration::from_secs(60 * 60),
         }
     }
 }
@@ -253,27 +440,26 @@ impl Replicator {
     }
 
     pub async fn with_options<S: Into<String>>(db_path: S, options: Options) -> Result<Self> {
-        let config = options.client_config().await?;
-        let client = Client::from_conf(config);
+        let storage = options.storage().await?;
+        Self::with_storage(db_path, options, storage).await
+    }
+
+    /// Creates a replicator backing up to `storage`, instead of the storage of `options`.
+    async fn with_storage<S: Into<String>>(
+        db_path: S,
+        options: Options,
+        storage: Arc<dyn ObjectStorage>,
+    ) -> Result<Self> {
         let bucket = options.bucket_name.clone();
         let generation = Arc::new(ArcSwapOption::default());
+        let status = Arc::new(Mutex::new(ReplicatorStatus::new(
+            bucket.clone(),
+            options.endpoint(),
+        )));
 
-        match client.head_bucket().bucket(&bucket).send().await {
-            Ok(_) => tracing::info!("Bucket {} exists and is accessible", bucket),
-            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => {
-                if options.create_bucket_if_not_exists {
-                    tracing::info!("Bucket {} not found, recreating", bucket);
-                    client.create_bucket().bucket(&bucket).send().await?;
-                } else {
-                    tracing::error!("Bucket {} does not exist", bucket);
-                    return Err(SdkError::ServiceError(err).into());
-                }
-            }
-            Err(e) => {
-                tracing::error!("Bucket checking error: {}", e);
-                return Err(e.into());
-            }
-        }
+        storage
+            .ensure_bucket(options.create_bucket_if_not_exists)
+            .await?;
 
         let db_path = db_path.into();
         let db_name = {
@@ -295,6 +481,7 @@ impl Replicator {
         let mut _join_set = JoinSet::new();
 
         let (frames_outbox, mut frames_inbox) = tokio::sync::mpsc::channel(64);
+        let pending_commits = PendingCommits::default();
         let _local_backup = {
             let mut copier = WalCopier::new(
                 bucket.clone(),
@@ -303,7 +490,10 @@ impl Replicator {
                 &db_path,
                 options.max_frames_per_batch,
                 options.use_compression,
+                options.compression_level,
+                options.encryption_key.clone(),
                 frames_outbox,
+                pending_commits.clone(),
             );
             let next_frame_no = next_frame_no.clone();
             let last_sent_frame_no = last_sent_frame_no.clone();
@@ -340,9 +530,10 @@ impl Replicator {
         };
 
         let _s3_upload = {
-            let client = client.clone();
+            let storage = storage.clone();
             let bucket = options.bucket_name.clone();
             let max_parallelism = options.s3_upload_max_parallelism;
+            let status = status.clone();
             _join_set.spawn(async move {
                 let sem = Arc::new(tokio::sync::Semaphore::new(max_parallelism));
                 let mut join_set = JoinSet::new();
@@ -351,22 +542,33 @@ impl Replicator {
                     let start = Instant::now();
                     let sem = sem.clone();
                     let permit = sem.acquire_owned().await.unwrap();
-                    let client = client.clone();
+                    let storage = storage.clone();
                     let bucket = bucket.clone();
+                    let status = status.clone();
                     join_set.spawn(async move {
                         let fpath = format!("{}/{}", bucket, fdesc);
-                        let body = ByteStream::from_path(&fpath).await.unwrap();
-                        if let Err(e) = client
-                            .put_object()
-                            .bucket(bucket)
-                            .key(fdesc)
-                            .body(body)
-                            .send()
-                            .await
-                        {
+                        // the file may have been removed since its upload was requested
+                        let file = match File::open(&fpath).await {
+                            Ok(file) => file,
+                            Err(e) => {
+                                tracing::warn!("Couldn't read {} for upload: {}", fpath, e);
+                                return;
+                            }
+                        };
+                        if let Err(e) = storage.put_file(&fdesc, file, &[]).await {
                             tracing::error!("Failed to send {} to S3: {}", fpath, e);
                         } else {
-                            tokio::fs::remove_file(&fpath).await.unwrap();
+                            // frames index is only ever appended to, and reuploaded as a whole
+                            if !fpath.ends_with(FRAMES_INDEX_FILE) {
+                                let _ = tokio::fs::remove_file(&fpath).await;
+                            }
+                            if let Some((_, last_frame_no, _, _)) =
+                                Replicator::parse_frame_range(&fdesc)
+                            {
+                                if let Some((dir, _)) = fdesc.split_once('/') {
+                                    status.lock().unwrap().record_upload(dir, last_frame_no);
+                                }
+                            }
                             let elapsed = Instant::now() - start;
                             tracing::debug!("Uploaded to S3: {} in {:?}", fpath, elapsed);
                         }
@@ -375,9 +577,24 @@ impl Replicator {
                 }
             })
         };
+        if options.retention.is_enabled() {
+            tracing::info!(
+                "Enforcing retention policy of '{}': {:?}",
+                db_name,
+                options.retention
+            );
+            let task = RetentionTask {
+                storage: storage.clone(),
+                db_name: db_name.clone(),
+                generation: generation.clone(),
+                policy: options.retention,
+            };
+            _join_set.spawn(task.run(options.retention_check_interval));
+        }
+
         let (snapshot_notifier, snapshot_waiter) = channel(Ok(None));
         Ok(Self {
-            client,
+            storage,
             bucket,
             page_size: Self::UNSET_PAGE_SIZE,
             generation,
@@ -386,15 +603,22 @@ impl Replicator {
             flush_trigger,
             last_committed_frame_no,
             verify_crc: options.verify_crc,
+            strict_verify: options.strict_verify,
+            skip_verify: options.skip_verify,
             db_path,
             db_name,
             snapshot_waiter,
             snapshot_notifier: Arc::new(snapshot_notifier),
+            pending_commits,
             restore_transaction_page_swap_after: options.restore_transaction_page_swap_after,
             restore_transaction_cache_fpath: options.restore_transaction_cache_fpath.into(),
             use_compression: options.use_compression,
+            compression_level: options.compression_level,
+            encryption_key: options.encryption_key,
             max_frames_per_batch: options.max_frames_per_batch,
             s3_upload_max_parallelism: options.s3_upload_max_parallelism,
+            restore_progress: Arc::default(),
+            status,
             _join_set,
         })
     }
@@ -411,18 +635,34 @@ impl Replicator {
         self.last_sent_frame_no.load(Ordering::Acquire)
     }
 
+    /// Returns the replication status, kept up to date while frames are replicated.
+    pub fn status(&self) -> Arc<Mutex<ReplicatorStatus>> {
+        self.status.clone()
+    }
+
     pub async fn wait_until_snapshotted(&mut self, generation: Uuid) -> Result<()> {
-        let res = self
-            .snapshot_waiter
-            .wait_for(|result| match result {
-                Ok(Some(gen)) => *gen == generation,
-                Ok(None) => false,
-                Err(_) => true,
-            })
-            .await?;
-        match res.deref() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!("Failed snapshot generation {}: {}", generation, e)),
+        self.snapshot_uploaded(generation).await
+    }
+
+    /// Returns a future resolving once the snapshot of `generation` is uploaded. Unlike
+    /// [Replicator::wait_until_snapshotted], the replicator isn't borrowed while waiting.
+    pub fn snapshot_uploaded(
+        &self,
+        generation: Uuid,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let mut snapshot_waiter = self.snapshot_waiter.clone();
+        async move {
+            let res = snapshot_waiter
+                .wait_for(|result| match result {
+                    Ok(Some(gen)) => *gen == generation,
+                    Ok(None) => false,
+                    Err(_) => true,
+                })
+                .await?;
+            match res.deref() {
+                Ok(_) => Ok(()),
+                Err(e) => Err(anyhow!("Failed snapshot generation {}: {}", generation, e)),
+            }
         }
     }
 
@@ -474,21 +714,12 @@ impl Replicator {
         Ok(())
     }
 
-    // Gets an object from the current bucket
-    fn get_object(&self, key: String) -> GetObjectFluentBuilder {
-        self.client.get_object().bucket(&self.bucket).key(key)
-    }
-
-    // Lists objects from the current bucket
-    fn list_objects(&self) -> ListObjectsFluentBuilder {
-        self.client.list_objects().bucket(&self.bucket)
-    }
-
     fn reset_frames(&mut self, frame_no: u32) {
         let last_sent = self.last_sent_frame_no();
         self.next_frame_no.store(frame_no + 1, Ordering::Release);
         self.last_sent_frame_no
             .store(last_sent.min(frame_no), Ordering::Release);
+        self.status.lock().unwrap().reset_frames(frame_no);
     }
 
     // Generates a new generation UUID v7, which contains a timestamp and is binary-sortable.
@@ -534,6 +765,7 @@ impl Replicator {
     // is reused in this session.
     pub fn set_generation(&mut self, generation: Uuid) -> Option<Uuid> {
         let prev_generation = self.generation.swap(Some(Arc::new(generation)));
+        self.status.lock().unwrap().set_generation(generation);
         self.reset_frames(0);
         if let Some(prev) = prev_generation.as_deref() {
             tracing::debug!("Generation changed from {} -> {}", prev, generation);
@@ -557,16 +789,10 @@ impl Replicator {
     /// extra undesired latency and this method may be called during SQLite checkpoint.
     fn store_dependency(&self, prev: Uuid, curr: Uuid) {
         let key = format!("{}-{}/.dep", self.db_name, curr);
-        let request =
-            self.client
-                .put_object()
-                .bucket(&self.bucket)
-                .key(key)
-                .body(ByteStream::from(Bytes::copy_from_slice(
-                    prev.into_bytes().as_slice(),
-                )));
+        let body = Bytes::copy_from_slice(prev.into_bytes().as_slice());
+        let storage = self.storage.clone();
         tokio::spawn(async move {
-            if let Err(e) = request.send().await {
+            if let Err(e) = storage.put(&key, body).await {
                 tracing::error!(
                     "Failed to store dependency between generations {} -> {}: {}",
                     prev,
@@ -584,26 +810,7 @@ impl Replicator {
     }
 
     pub async fn get_dependency(&self, generation: &Uuid) -> Result<Option<Uuid>> {
-        let key = format!("{}-{}/.dep", self.db_name, generation);
-        let resp = self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await;
-        match resp {
-            Ok(out) => {
-                let bytes = out.body.collect().await?.into_bytes();
-                let prev_generation = Uuid::from_bytes(bytes.as_ref().try_into()?);
-                Ok(Some(prev_generation))
-            }
-            Err(SdkError::ServiceError(se)) => match se.into_err() {
-                GetObjectError::NoSuchKey(_) => Ok(None),
-                e => Err(e.into()),
-            },
-            Err(e) => Err(e.into()),
-        }
+        fetch_dependency(self.storage.as_ref(), &self.db_name, generation).await
     }
 
     // Returns the current last valid frame in the replicated log
@@ -631,6 +838,11 @@ impl Replicator {
         let prev = self.next_frame_no.fetch_add(frame_count, Ordering::SeqCst);
         let last_sent = self.last_sent_frame_no();
         let most_recent = prev + frame_count - 1;
+        self.status.lock().unwrap().last_known_frame = most_recent;
+        self.pending_commits
+            .lock()
+            .unwrap()
+            .push((most_recent, Utc::now().timestamp_millis()));
         if most_recent - last_sent >= self.max_frames_per_batch as u32 {
             self.request_flush();
         }
@@ -667,30 +879,56 @@ impl Replicator {
         }
     }
 
-    // Returns the compressed database file path and its change counter, extracted
-    // from the header of page1 at offset 24..27 (as per SQLite documentation).
+    // Returns the compressed database file, ready to be uploaded as a snapshot.
     pub async fn maybe_compress_main_db_file(
         mut reader: File,
         compression: CompressionKind,
-    ) -> Result<ByteStream> {
+        level: Option<u32>,
+        encryption_key: Option<&EncryptionKey>,
+    ) -> Result<File> {
         reader.seek(SeekFrom::Start(0)).await?;
-        match compression {
-            CompressionKind::None => Ok(ByteStream::read_from().file(reader).build().await?),
-            CompressionKind::Gzip => {
-                let compressed_file = OpenOptions::new()
+        let fpath = format!("db.{}", compression);
+        let compressed_file = match (compression, encryption_key) {
+            (CompressionKind::None, None) => return Ok(reader),
+            (CompressionKind::None, Some(key)) => {
+                let encrypted_file = File::create(&fpath).await?;
+                encryption::encrypt(key, reader, encrypted_file).await?;
+                tracing::trace!("Encrypted database file into {}", fpath);
+                return Ok(File::open(&fpath).await?);
+            }
+            (CompressionKind::Gzip | CompressionKind::Zstd, _) => {
+                OpenOptions::new()
                     .create(true)
                     .write(true)
                     .read(true)
                     .truncate(true)
-                    .open("db.gz")
-                    .await?;
-                let mut writer = GzipEncoder::new(compressed_file);
+                    .open(&fpath)
+                    .await?
+            }
+        };
+        let size = match compression {
+            CompressionKind::Gzip => {
+                let mut writer =
+                    GzipEncoder::with_quality(compressed_file, compression_level(level));
                 let size = tokio::io::copy(&mut reader, &mut writer).await?;
-                tracing::trace!("Compressed database file ({} bytes) into db.gz", size);
                 writer.shutdown().await?;
-                Ok(ByteStream::from_path("db.gz").await?)
+                size
             }
+            CompressionKind::Zstd => {
+                let mut writer =
+                    ZstdEncoder::with_quality(compressed_file, compression_level(level));
+                let size = tokio::io::copy(&mut reader, &mut writer).await?;
+                writer.shutdown().await?;
+                size
+            }
+            CompressionKind::None => unreachable!(),
+        };
+        tracing::trace!("Compressed database file ({} bytes) into {}", size, fpath);
+        if let Some(key) = encryption_key {
+            encryption::encrypt_file(key, &fpath).await?;
+            tracing::trace!("Encrypted {}", fpath);
         }
+        Ok(File::open(&fpath).await?)
     }
     // Replicates local WAL pages to S3, if local WAL is present.
     // This function is called under the assumption that if local WAL
@@ -761,13 +999,14 @@ impl Replicator {
             self.wait_until_snapshotted(prev).await?;
         }
 
-        let client = self.client.clone();
+        let storage = self.storage.clone();
         let mut db_file = File::open(&self.db_path).await?;
         let change_counter = Self::read_change_counter(&mut db_file).await?;
-        let snapshot_req = client.put_object().bucket(self.bucket.clone()).key(format!(
+        let db_size = db_file.metadata().await?.len();
+        let snapshot_key = format!(
             "{}-{}/db.{}",
             self.db_name, generation, self.use_compression
-        ));
+        );
 
         /* FIXME: we can't rely on the change counter in WAL mode:
          ** "In WAL mode, changes to the database are detected using the wal-index and
@@ -776,19 +1015,23 @@ impl Replicator {
          ** Instead, we need to consult WAL checksums.
          */
         let change_counter_key = format!("{}-{}/.changecounter", self.db_name, generation);
-        let change_counter_req = self
-            .client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(change_counter_key)
-            .body(ByteStream::from(Bytes::copy_from_slice(
-                change_counter.as_ref(),
-            )));
+        let snapshot_manifest_key =
+            format!("{}-{}/{}", self.db_name, generation, SNAPSHOT_MANIFEST_FILE);
+        let change_counter = Bytes::copy_from_slice(change_counter.as_ref());
         let snapshot_notifier = self.snapshot_notifier.clone();
         let compression = self.use_compression;
+        let compression_level = self.compression_level;
+        let encryption_key = self.encryption_key.clone();
         let handle = tokio::spawn(async move {
             let start = Instant::now();
-            let body = match Self::maybe_compress_main_db_file(db_file, compression).await {
+            let mut file = match Self::maybe_compress_main_db_file(
+                db_file,
+                compression,
+                compression_level,
+                encryption_key.as_ref(),
+            )
+            .await
+            {
                 Ok(file) => file,
                 Err(e) => {
                     tracing::error!(
@@ -800,24 +1043,47 @@ impl Replicator {
                     return;
                 }
             };
-            let mut result = snapshot_req.body(body).send().await;
-            if let Err(e) = result {
+            let checksum = match file_sha256(&mut file).await {
+                Ok(checksum) => checksum,
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to compute the checksum of the snapshot (generation {}): {}",
+                        generation,
+                        e
+                    );
+                    let _ = snapshot_notifier.send(Err(e));
+                    return;
+                }
+            };
+            let metadata = [(UNCOMPRESSED_SIZE_METADATA, db_size.to_string())];
+            if let Err(e) = storage.put_file(&snapshot_key, file, &metadata).await {
                 tracing::error!(
                     "Failed to upload snapshot for generation {}: {:?}",
                     generation,
                     e
                 );
-                let _ = snapshot_notifier.send(Err(e.into()));
+                let _ = snapshot_notifier.send(Err(e));
+                return;
+            }
+            // the digest is listed in the manifest of the generation, like those of frame batches
+            let fname = &snapshot_key[(snapshot_key.rfind('/').unwrap() + 1)..];
+            let manifest = Bytes::from(format!("{}  {}\n", checksum, fname));
+            if let Err(e) = storage.put(&snapshot_manifest_key, manifest).await {
+                tracing::error!(
+                    "Failed to upload snapshot manifest for generation {}: {:?}",
+                    generation,
+                    e
+                );
+                let _ = snapshot_notifier.send(Err(e));
                 return;
             }
-            result = change_counter_req.send().await;
-            if let Err(e) = result {
+            if let Err(e) = storage.put(&change_counter_key, change_counter).await {
                 tracing::error!(
                     "Failed to upload change counter for generation {}: {:?}",
                     generation,
                     e
                 );
-                let _ = snapshot_notifier.send(Err(e.into()));
+                let _ = snapshot_notifier.send(Err(e));
                 return;
             }
             let _ = snapshot_notifier.send(Ok(Some(generation)));
@@ -843,22 +1109,20 @@ impl Replicator {
         let prefix = format!("{}-", self.db_name);
         let threshold = timestamp.map(|ts| ts.timestamp() as u64);
         loop {
-            let mut request = self.list_objects().prefix(prefix.clone());
-            if threshold.is_none() {
-                request = request.max_keys(1);
-            }
-            if let Some(marker) = next_marker.take() {
-                request = request.marker(marker);
-            }
-            let response = request.send().await.ok()?;
-            let objs = response.contents()?;
+            let max_keys = threshold.is_none().then_some(1);
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.take().as_deref(), max_keys)
+                .await
+                .ok()?;
+            let objs = response.objects;
             if objs.is_empty() {
                 break;
             }
             let mut last_key = None;
             let mut last_gen = None;
-            for obj in objs {
-                let key = obj.key();
+            for obj in objs.iter() {
+                let key = Some(obj.key.as_str());
                 last_key = key;
                 if let Some(key) = last_key {
                     let key = match key.find('/') {
@@ -909,16 +1173,9 @@ impl Replicator {
     // Tries to fetch the remote database change counter from given generation
     pub async fn get_remote_change_counter(&self, generation: &Uuid) -> Result<[u8; 4]> {
         let mut remote_change_counter = [0u8; 4];
-        if let Ok(response) = self
-            .get_object(format!("{}-{}/.changecounter", self.db_name, generation))
-            .send()
-            .await
-        {
-            response
-                .body
-                .collect()
-                .await?
-                .copy_to_slice(&mut remote_change_counter)
+        let key = format!("{}-{}/.changecounter", self.db_name, generation);
+        if let Ok(Some(mut body)) = self.storage.get(&key).await {
+            body.copy_to_slice(&mut remote_change_counter)
         }
         Ok(remote_change_counter)
     }
@@ -940,7 +1197,7 @@ impl Replicator {
 
     // Parses the frame and page number from given key.
     // Format: <db-name>-<generation>/<first-frame-no>-<last-frame-no>-<timestamp>.<compression-kind>
-    fn parse_frame_range(key: &str) -> Option<(u32, u32, u64, CompressionKind)> {
+    pub fn parse_frame_range(key: &str) -> Option<(u32, u32, u64, CompressionKind)> {
         let frame_delim = key.rfind('/')?;
         let frame_suffix = &key[(frame_delim + 1)..];
         let timestamp_delim = frame_suffix.rfind('-')?;
@@ -991,15 +1248,17 @@ impl Replicator {
 
         // at this point we know, we should do a full restore
 
-        tokio::fs::rename(&self.db_path, format!("{}.bottomless.backup", self.db_path))
-            .await
-            .ok(); // Best effort
-        let _ = self.remove_wal_files().await; // best effort, WAL files may not exists
+        self.restore_progress.reset();
+        let _progress_logger = self.restore_progress.log_periodically(&self.db_name);
+        // the database is restored next to the database file, and moved in place only once it's
+        // complete, so that an interrupted restore never leaves a partial database behind
+        let partial_path = format!("{}.bottomless.partial", self.db_path);
         let mut db = tokio::fs::OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
-            .open(&self.db_path)
+            .truncate(true)
+            .open(&partial_path)
             .await?;
 
         let mut restore_stack = Vec::new();
@@ -1062,9 +1321,23 @@ impl Replicator {
             };
         }
 
+        db.sync_all().await?;
         db.shutdown().await?;
+        drop(db);
+
+        let backup_path = format!("{}.bottomless.backup", self.db_path);
+        let _ = tokio::fs::remove_file(&backup_path).await;
+        tokio::fs::hard_link(&self.db_path, &backup_path).await.ok(); // Best effort
+        let _ = self.remove_wal_files().await; // best effort, WAL files may not exists
+        tokio::fs::rename(&partial_path, &self.db_path).await?;
+        self.remove_snapshot_download().await;
+
         let elapsed = Instant::now() - start_ts;
-        tracing::info!("Finished database restoration in {:?}", elapsed);
+        tracing::info!(
+            "Finished database restoration in {:?}: {}",
+            elapsed,
+            self.restore_progress
+        );
 
         if applied_wal_frame {
             tracing::info!("WAL file has been applied onto database file in generation {}. Requesting snapshot.", generation);
@@ -1135,23 +1408,71 @@ impl Replicator {
         }
     }
 
+    /// Returns keys under which the snapshot of a given generation may be stored, together with
+    /// the compression used for each of them. The snapshot could have been made with a different
+    /// compression than the current one, so the current one is only tried first.
+    pub fn snapshot_keys(&self, generation: &Uuid) -> Vec<(String, CompressionKind)> {
+        let mut kinds = vec![self.use_compression];
+        kinds.extend(
+            CompressionKind::ALL
+                .into_iter()
+                .filter(|&kind| kind != self.use_compression),
+        );
+        let mut keys = Vec::with_capacity(kinds.len() + 1);
+        for kind in kinds {
+            keys.push((format!("{}-{}/db.{}", self.db_name, generation, kind), kind));
+            if kind == CompressionKind::None {
+                // older versions stored uncompressed snapshots as `db.db`
+                keys.push((format!("{}-{}/db.db", self.db_name, generation), kind));
+            }
+        }
+        keys
+    }
+
     async fn restore_from_snapshot(&mut self, generation: &Uuid, db: &mut File) -> Result<bool> {
-        let main_db_path = match self.use_compression {
-            CompressionKind::None => format!("{}-{}/db.db", self.db_name, generation),
-            CompressionKind::Gzip => format!("{}-{}/db.gz", self.db_name, generation),
-        };
+        let mut snapshot = None;
+        for (key, compression) in self.snapshot_keys(generation) {
+            if let Ok(Some(head)) = self.storage.head(&key).await {
+                snapshot = Some((key, head, compression));
+                break;
+            }
+        }
 
-        if let Ok(db_file) = self.get_object(main_db_path).send().await {
-            let mut body_reader = db_file.body.into_async_read();
-            let db_size = match self.use_compression {
-                CompressionKind::None => tokio::io::copy(&mut body_reader, db).await?,
+        if let Some((key, head, compression)) = snapshot {
+            let mut db_file = self
+                .download_snapshot(&key, head.size, head.etag.as_deref().unwrap_or_default())
+                .await?;
+            if !self.skip_verify {
+                let manifest = self.get_manifest(generation).await?;
+                let digest = file_sha256(&mut db_file).await?;
+                if let Err(e) = verify_digest(&manifest, &key, &digest) {
+                    // don't resume from a corrupted download on the next attempt
+                    self.remove_snapshot_download().await;
+                    return Err(e);
+                }
+            }
+            let (mut body_reader, decryption) =
+                encryption::maybe_decrypt_reader(self.encryption_key.as_ref(), db_file).await?;
+            let db_size = match compression {
+                CompressionKind::None => tokio::io::copy(&mut body_reader, db).await,
                 CompressionKind::Gzip => {
                     let mut decompress_reader = async_compression::tokio::bufread::GzipDecoder::new(
                         tokio::io::BufReader::new(body_reader),
                     );
-                    tokio::io::copy(&mut decompress_reader, db).await?
+                    tokio::io::copy(&mut decompress_reader, db).await
+                }
+                CompressionKind::Zstd => {
+                    let mut decompress_reader = async_compression::tokio::bufread::ZstdDecoder::new(
+                        tokio::io::BufReader::new(body_reader),
+                    );
+                    tokio::io::copy(&mut decompress_reader, db).await
                 }
             };
+            // a failed decryption surfaces as a truncated stream, so report it first
+            if let Some(decryption) = decryption {
+                decryption.await??;
+            }
+            let db_size = db_size?;
             db.flush().await?;
 
             let page_size = Self::read_page_size(db).await?;
@@ -1163,6 +1484,83 @@ impl Replicator {
         }
     }
 
+    /// Downloads the snapshot stored under `key` into a file next to the database. The progress
+    /// of the download is recorded after every range, so that a restore interrupted by a crash
+    /// resumes the download of the same object instead of starting over.
+    async fn download_snapshot(&self, key: &str, size: u64, etag: &str) -> Result<File> {
+        let (download_path, progress_path) = self.snapshot_download_paths();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(&download_path)
+            .await?;
+
+        let object = format!("{key}\n{etag}\n");
+        let recorded = tokio::fs::read_to_string(&progress_path)
+            .await
+            .unwrap_or_default();
+        let mut offset = match recorded
+            .strip_prefix(&object)
+            .and_then(|downloaded| downloaded.trim().parse::<u64>().ok())
+        {
+            Some(downloaded) if downloaded <= size => downloaded,
+            _ => 0,
+        };
+        if offset > 0 {
+            tracing::info!(
+                "Resuming the download of {} from byte {}/{}",
+                key,
+                offset,
+                size
+            );
+        }
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        self.restore_progress.add_total(size);
+        self.restore_progress.add_downloaded(offset);
+
+        while offset < size {
+            let end = (offset + SNAPSHOT_DOWNLOAD_RANGE_SIZE).min(size);
+            let data = self
+                .storage
+                .get_range(key, offset..end, (!etag.is_empty()).then_some(etag))
+                .await?;
+            if data.len() as u64 != end - offset {
+                bail!(
+                    "Downloaded {} bytes of {} at offset {}, expected {}",
+                    data.len(),
+                    key,
+                    offset,
+                    end - offset
+                );
+            }
+            file.write_all(&data).await?;
+            file.sync_data().await?;
+            offset = end;
+            tokio::fs::write(&progress_path, format!("{object}{offset}\n")).await?;
+            self.restore_progress.add_downloaded(data.len() as u64);
+        }
+
+        file.seek(SeekFrom::Start(0)).await?;
+        Ok(file)
+    }
+
+    fn snapshot_download_paths(&self) -> (String, String) {
+        (
+            format!("{}.bottomless.snapshot", self.db_path),
+            format!("{}.bottomless.snapshot.progress", self.db_path),
+        )
+    }
+
+    async fn remove_snapshot_download(&self) {
+        let (download_path, progress_path) = self.snapshot_download_paths();
+        // best effort, the download is discarded on the next restore anyway
+        let _ = tokio::fs::remove_file(progress_path).await;
+        let _ = tokio::fs::remove_file(download_path).await;
+    }
+
     async fn restore_wal(
         &self,
         generation: &Uuid,
@@ -1179,31 +1577,58 @@ impl Replicator {
             unsafe { v.set_len(page_size) };
             v
         };
+        let manifest = if self.skip_verify {
+            HashMap::new()
+        } else {
+            self.get_manifest(generation).await?
+        };
+        // last frame committed before the requested point in time. If it's not known, restore
+        // stops at the first batch of frames that was uploaded after the requested time.
+        let watermark = match utc_time {
+            Some(threshold) => {
+                let threshold = threshold.and_utc().timestamp_millis();
+                let index = self.get_frames_index(generation).await?;
+                if index.iter().any(|&(_, timestamp)| timestamp > threshold) {
+                    let watermark = index
+                        .iter()
+                        .filter(|&&(_, timestamp)| timestamp <= threshold)
+                        .map(|&(frame_no, _)| frame_no)
+                        .max()
+                        .unwrap_or(0);
+                    tracing::debug!("Restoring generation {generation} up to frame {watermark}");
+                    Some(watermark)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
         let mut next_marker = None;
         let mut applied_wal_frame = false;
         'restore_wal: loop {
-            let mut list_request = self.list_objects().prefix(&prefix);
-            if let Some(marker) = next_marker {
-                list_request = list_request.marker(marker);
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            let objs = &response.objects;
+            if objs.is_empty() {
+                tracing::debug!("No objects found in generation {}", generation);
+                break;
             }
-            let response = list_request.send().await?;
-            let objs = match response.contents() {
-                Some(objs) => objs,
-                None => {
-                    tracing::debug!("No objects found in generation {}", generation);
-                    break;
-                }
-            };
             let mut pending_pages = TransactionPageCache::new(
                 self.restore_transaction_page_swap_after,
                 page_size as u32,
                 self.restore_transaction_cache_fpath.clone(),
             );
             let mut last_received_frame_no = 0;
+            let listed_bytes: u64 = objs
+                .iter()
+                .filter(|obj| Self::parse_frame_range(&obj.key).is_some())
+                .map(|obj| obj.size)
+                .sum();
+            self.restore_progress.add_total(listed_bytes);
             for obj in objs {
-                let key = obj
-                    .key()
-                    .ok_or_else(|| anyhow::anyhow!("Failed to get key for an object"))?;
+                let key = obj.key.as_str();
                 tracing::debug!("Loading {}", key);
 
                 let (first_frame_no, last_frame_no, timestamp, compression_kind) =
@@ -1211,10 +1636,14 @@ impl Replicator {
                         Some(result) => result,
                         None => {
                             if !key.ends_with(".gz")
+                                && !key.ends_with(".zst")
+                                && !key.ends_with(".raw")
                                 && !key.ends_with(".db")
                                 && !key.ends_with(".meta")
                                 && !key.ends_with(".dep")
                                 && !key.ends_with(".changecounter")
+                                && !key.ends_with(MANIFEST_FILE)
+                                && !key.ends_with(FRAMES_INDEX_FILE)
                             {
                                 tracing::warn!("Failed to parse frame/page from key {}", key);
                             }
@@ -1233,7 +1662,12 @@ impl Replicator {
                         break;
                     }
                 }
-                if let Some(threshold) = utc_time.as_ref() {
+                if let Some(watermark) = watermark {
+                    if first_frame_no > watermark {
+                        tracing::info!("Frame batch {} contains only frames committed after the requested point in time. Stopping recovery.", key);
+                        break 'restore_wal;
+                    }
+                } else if let Some(threshold) = utc_time.as_ref() {
                     match NaiveDateTime::from_timestamp_opt(timestamp as i64, 0) {
                         Some(timestamp) => {
                             if &timestamp > threshold {
@@ -1247,12 +1681,32 @@ impl Replicator {
                         }
                     }
                 }
-                let frame = self.get_object(key.into()).send().await?;
+                let body = self
+                    .storage
+                    .get(key)
+                    .await?
+                    .ok_or_else(|| anyhow!("Frame batch {} not found", key))?;
+                if !self.skip_verify {
+                    let digest = format!("{:x}", Sha256::digest(&body));
+                    if let Err(e) = verify_digest(&manifest, key, &digest) {
+                        if self.strict_verify {
+                            return Err(e);
+                        }
+                        // the following batches can't be applied without this one
+                        tracing::warn!("{}. Stopping the restoration process", e);
+                        break 'restore_wal;
+                    }
+                }
+                self.restore_progress.add_downloaded(body.len() as u64);
+                let body =
+                    encryption::maybe_decrypt_bytes(self.encryption_key.as_ref(), body).await?;
                 let mut frameno = first_frame_no;
-                let mut reader =
-                    BatchReader::new(frameno, frame.body, self.page_size, compression_kind);
+                let mut reader = BatchReader::new(frameno, body, self.page_size, compression_kind);
 
                 while let Some(frame) = reader.next_frame_header().await? {
+                    if watermark.map(|w| frameno > w).unwrap_or(false) {
+                        break 'restore_wal;
+                    }
                     let pgno = frame.pgno();
                     let page_size = self.page_size;
                     reader.next_page(&mut page_buf).await?;
@@ -1260,6 +1714,7 @@ impl Replicator {
                         checksum = frame.verify(checksum, &page_buf)?;
                     }
                     pending_pages.insert(pgno, &page_buf).await?;
+                    self.restore_progress.add_frame();
                     if frame.is_committed() {
                         let pending_pages = std::mem::replace(
                             &mut pending_pages,
@@ -1271,18 +1726,16 @@ impl Replicator {
                         );
                         pending_pages.flush(db).await?;
                         applied_wal_frame = true;
+                        self.restore_progress.set_last_applied_batch_time(timestamp);
                     }
                     frameno += 1;
                     last_received_frame_no += 1;
                 }
                 db.flush().await?;
             }
-            next_marker = response
-                .is_truncated()
-                .then(|| objs.last().map(|elem| elem.key().unwrap().to_string()))
-                .flatten();
+            next_marker = response.next_marker.clone();
             if next_marker.is_none() {
-                tracing::trace!("Restored DB from S3 backup using generation {}", generation);
+                tracing::trace!("Restored DB from backup using generation {}", generation);
                 break;
             }
         }
@@ -1296,6 +1749,11 @@ impl Replicator {
         Ok(())
     }
 
+    /// Progress of the restore currently running, or of the last one.
+    pub fn restore_progress(&self) -> Arc<RestoreProgress> {
+        self.restore_progress.clone()
+    }
+
     /// Restores the database state from newest remote generation
     /// On success, returns the RestoreAction, and whether the database was recovered from backup.
     pub async fn restore(
@@ -1323,29 +1781,25 @@ impl Replicator {
         let mut marker: Option<String> = None;
         let mut last_frame = 0;
         while {
-            let mut list_objects = self.list_objects().prefix(&prefix);
-            if let Some(marker) = marker.take() {
-                list_objects = list_objects.marker(marker);
-            }
-            let response = list_objects.send().await?;
+            let response = self
+                .storage
+                .list(&prefix, false, marker.take().as_deref(), None)
+                .await?;
             marker = Self::try_get_last_frame_no(response, &mut last_frame);
             marker.is_some()
         } {}
         Ok(last_frame)
     }
 
-    fn try_get_last_frame_no(response: ListObjectsOutput, frame_no: &mut u32) -> Option<String> {
-        let objs = response.contents()?;
+    fn try_get_last_frame_no(response: ListPage, frame_no: &mut u32) -> Option<String> {
         let mut last_key = None;
-        for obj in objs.iter() {
-            last_key = Some(obj.key()?);
-            if let Some(key) = last_key {
-                if let Some((_, last_frame_no, _, _)) = Self::parse_frame_range(key) {
-                    *frame_no = last_frame_no;
-                }
+        for obj in response.objects {
+            if let Some((_, last_frame_no, _, _)) = Self::parse_frame_range(&obj.key) {
+                *frame_no = last_frame_no;
             }
+            last_key = Some(obj.key);
         }
-        last_key.map(String::from)
+        last_key
     }
 
     async fn upload_remaining_files(&self, generation: &Uuid) -> Result<()> {
@@ -1359,19 +1813,11 @@ impl Replicator {
                 if let Some(key) = Self::fpath_to_key(&fpath, &prefix) {
                     tracing::trace!("Requesting upload of the remaining backup file: {}", key);
                     let permit = sem.clone().acquire_owned().await?;
-                    let bucket = self.bucket.clone();
                     let key = key.to_string();
-                    let client = self.client.clone();
+                    let storage = self.storage.clone();
                     tokio::spawn(async move {
-                        let body = ByteStream::from_path(&fpath).await.unwrap();
-                        if let Err(e) = client
-                            .put_object()
-                            .bucket(bucket)
-                            .key(key.clone())
-                            .body(body)
-                            .send()
-                            .await
-                        {
+                        let file = File::open(&fpath).await.unwrap();
+                        if let Err(e) = storage.put_file(&key, file, &[]).await {
                             tracing::error!("Failed to send {} to S3: {}", key, e);
                         } else {
                             tokio::fs::remove_file(&fpath).await.unwrap();
@@ -1396,10 +1842,13 @@ impl Replicator {
         let str = fpath.to_str()?;
         if str.ends_with(".db")
             | str.ends_with(".gz")
+            | str.ends_with(".zst")
             | str.ends_with(".raw")
             | str.ends_with(".meta")
             | str.ends_with(".dep")
             | str.ends_with(".changecounter")
+            | str.ends_with(MANIFEST_FILE)
+            | str.ends_with(FRAMES_INDEX_FILE)
         {
             let idx = str.rfind(dir)?;
             return Some(&str[idx..]);
@@ -1419,28 +1868,13 @@ impl Replicator {
         let mut body = Vec::with_capacity(12);
         body.extend_from_slice(page_size.to_be_bytes().as_slice());
         body.extend_from_slice(crc.to_be_bytes().as_slice());
-        let _ = self
-            .client
-            .put_object()
-            .bucket(self.bucket.clone())
-            .key(key)
-            .body(ByteStream::from(body))
-            .send()
-            .await?;
+        self.storage.put(&key, body.into()).await?;
         Ok(())
     }
 
     pub async fn get_metadata(&self, generation: &Uuid) -> Result<Option<(u32, u64)>> {
         let key = format!("{}-{}/.meta", self.db_name, generation);
-        if let Ok(obj) = self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await
-        {
-            let mut data = obj.body.collect().await?;
+        if let Ok(Some(mut data)) = self.storage.get(&key).await {
             let page_size = data.get_u32();
             let crc = data.get_u64();
             Ok(Some((page_size, crc)))
@@ -1449,6 +1883,155 @@ impl Replicator {
         }
     }
 
+    /// Checks if the snapshot of a given generation, or its first frame batch if it has no
+    /// snapshot, is encrypted. Returns `None` if the generation contains neither.
+    pub async fn is_generation_encrypted(&self, generation: &Uuid) -> Result<Option<bool>> {
+        let range = 0..encryption::ENCRYPTION_MAGIC.len() as u64;
+        for (key, _) in self.snapshot_keys(generation) {
+            if let Ok(header) = self.storage.get_range(&key, range.clone(), None).await {
+                return Ok(Some(encryption::is_encrypted(&header)));
+            }
+        }
+        let prefix = format!("{}-{}/", self.db_name, generation);
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            for obj in &response.objects {
+                if Self::parse_frame_range(&obj.key).is_some() {
+                    let header = self
+                        .storage
+                        .get_range(&obj.key, range.clone(), None)
+                        .await?;
+                    return Ok(Some(encryption::is_encrypted(&header)));
+                }
+            }
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns SHA-256 digests of the frame batch files and of the snapshot, keyed by their file
+    /// names, as stored in the `manifest.sha256` segments of a given generation. Generations
+    /// backed up without a manifest return an empty map.
+    pub async fn get_manifest(&self, generation: &Uuid) -> Result<HashMap<String, String>> {
+        let prefix = format!("{}-{}/", self.db_name, generation);
+        let mut manifest = HashMap::new();
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            for obj in response.objects {
+                if !obj.key.ends_with(MANIFEST_FILE) {
+                    continue;
+                }
+                let Some(data) = self.storage.get(&obj.key).await? else {
+                    continue;
+                };
+                for line in String::from_utf8_lossy(&data).lines() {
+                    match line.split_once("  ") {
+                        Some((digest, fname)) => {
+                            manifest.insert(fname.to_string(), digest.to_string());
+                        }
+                        None => tracing::warn!("Malformed manifest entry: {}", line),
+                    }
+                }
+            }
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                break;
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Downloads the snapshot and the frame batches of a generation, and verifies them against
+    /// the manifest of the generation.
+    pub async fn verify_generation(&self, generation: &Uuid) -> Result<ChecksumStatus> {
+        let manifest = self.get_manifest(generation).await?;
+        let mut unknown = false;
+        let mut check = |key: String, digest: String| {
+            let fname = &key[(key.rfind('/').unwrap() + 1)..];
+            match manifest.get(fname) {
+                Some(expected) if *expected != digest => Some(ChecksumStatus::Mismatch(key)),
+                Some(_) => None,
+                None => {
+                    unknown = true;
+                    None
+                }
+            }
+        };
+        for (key, _) in self.snapshot_keys(generation) {
+            if let Some(head) = self.storage.head(&key).await? {
+                // snapshots are hashed range by range, instead of being held in memory
+                let mut hasher = Sha256::new();
+                let mut offset = 0;
+                while offset < head.size {
+                    let end = (offset + SNAPSHOT_DOWNLOAD_RANGE_SIZE).min(head.size);
+                    let range = offset..end;
+                    hasher.update(self.storage.get_range(&key, range, None).await?);
+                    offset = end;
+                }
+                if let Some(mismatch) = check(key, format!("{:x}", hasher.finalize())) {
+                    return Ok(mismatch);
+                }
+                break;
+            }
+        }
+
+        let prefix = format!("{}-{}/", self.db_name, generation);
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, false, next_marker.as_deref(), None)
+                .await?;
+            for obj in response.objects {
+                if Self::parse_frame_range(&obj.key).is_none() {
+                    continue;
+                }
+                let Some(data) = self.storage.get(&obj.key).await? else {
+                    continue;
+                };
+                if let Some(mismatch) = check(obj.key, format!("{:x}", Sha256::digest(&data))) {
+                    return Ok(mismatch);
+                }
+            }
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                break;
+            }
+        }
+
+        if unknown {
+            Ok(ChecksumStatus::Unknown)
+        } else {
+            Ok(ChecksumStatus::Verified)
+        }
+    }
+
+    /// Returns pairs of (last frame number, UTC commit timestamp in millis) of transactions
+    /// committed in a given generation, as stored in its `frames.index` object.
+    pub async fn get_frames_index(&self, generation: &Uuid) -> Result<Vec<(u32, i64)>> {
+        let key = format!("{}-{}/{}", self.db_name, generation, FRAMES_INDEX_FILE);
+        let mut index = Vec::new();
+        if let Ok(Some(data)) = self.storage.get(&key).await {
+            // a trailing partial entry may be present if the object was uploaded while appended to
+            for mut entry in data.chunks_exact(FRAMES_INDEX_ENTRY_SIZE) {
+                let frame_no = entry.get_u32();
+                let timestamp = entry.get_i64();
+                index.push((frame_no, timestamp));
+            }
+        }
+        Ok(index)
+    }
+
     /// Marks current replicator database as deleted, invalidating all generations.
     pub async fn delete_all(&self, older_than: Option<NaiveDateTime>) -> Result<DeleteAll> {
         tracing::info!(
@@ -1457,67 +2040,42 @@ impl Replicator {
         );
         let key = format!("{}.tombstone", self.db_name);
         let threshold = older_than.unwrap_or(NaiveDateTime::MAX);
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .body(ByteStream::from(
-                threshold.timestamp().to_be_bytes().to_vec(),
-            ))
-            .send()
-            .await?;
-        let delete_task = DeleteAll::new(
-            self.client.clone(),
-            self.bucket.clone(),
-            self.db_name.clone(),
-            threshold,
-        );
+        let body = Bytes::copy_from_slice(&threshold.timestamp().to_be_bytes());
+        self.storage.put(&key, body).await?;
+        let delete_task = DeleteAll::new(self.storage.clone(), self.db_name.clone(), threshold);
         Ok(delete_task)
     }
 
     /// Checks if current replicator database has been marked as deleted.
     pub async fn get_tombstone(&self) -> Result<Option<NaiveDateTime>> {
         let key = format!("{}.tombstone", self.db_name);
-        let resp = self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await;
-        match resp {
-            Ok(out) => {
+        match self.storage.get(&key).await? {
+            Some(mut body) => {
                 let mut buf = [0u8; 8];
-                out.body.collect().await?.copy_to_slice(&mut buf);
+                body.copy_to_slice(&mut buf);
                 let timestamp = i64::from_be_bytes(buf);
                 let tombstone = NaiveDateTime::from_timestamp_opt(timestamp, 0);
                 Ok(tombstone)
             }
-            Err(SdkError::ServiceError(se)) => match se.into_err() {
-                GetObjectError::NoSuchKey(_) => Ok(None),
-                e => Err(e.into()),
-            },
-            Err(e) => Err(e.into()),
+            None => Ok(None),
         }
     }
 }
 
 /// This structure is returned by [Replicator::delete_all] after tombstoning (soft deletion) has
 /// been confirmed. It may be called using [DeleteAll::commit] to trigger a follow up procedure that
-/// performs hard deletion of corresponding S3 objects.
+/// performs hard deletion of corresponding objects.
 #[derive(Debug)]
 pub struct DeleteAll {
-    client: Client,
-    bucket: String,
+    storage: Arc<dyn ObjectStorage>,
     db_name: String,
     threshold: NaiveDateTime,
 }
 
 impl DeleteAll {
-    fn new(client: Client, bucket: String, db_name: String, threshold: NaiveDateTime) -> Self {
+    fn new(storage: Arc<dyn ObjectStorage>, db_name: String, threshold: NaiveDateTime) -> Self {
         DeleteAll {
-            client,
-            bucket,
+            storage,
             db_name,
             threshold,
         }
@@ -1533,42 +2091,29 @@ impl DeleteAll {
         let mut next_marker = None;
         let mut removed_count = 0;
         loop {
-            let mut list_request = self
-                .client
-                .list_objects()
-                .bucket(&self.bucket)
-                .set_delimiter(Some("/".to_string()))
-                .prefix(&self.db_name);
-
-            if let Some(marker) = next_marker {
-                list_request = list_request.marker(marker)
+            let response = self
+                .storage
+                .list(&self.db_name, true, next_marker.as_deref(), None)
+                .await?;
+            if response.common_prefixes.is_empty() {
+                tracing::debug!("no generations found to delete");
+                return Ok(0);
             }
 
-            let response = list_request.send().await?;
-            let prefixes = match response.common_prefixes() {
-                Some(prefixes) => prefixes,
-                None => {
-                    tracing::debug!("no generations found to delete");
-                    return Ok(0);
-                }
-            };
-
-            for prefix in prefixes {
-                if let Some(prefix) = &prefix.prefix {
-                    let prefix = &prefix[self.db_name.len() + 1..prefix.len() - 1];
-                    let uuid = Uuid::try_parse(prefix)?;
-                    if let Some(datetime) = Replicator::generation_to_timestamp(&uuid) {
-                        if datetime.to_unix().0 >= self.threshold.timestamp() as u64 {
-                            continue;
-                        }
-                        tracing::debug!("Removing generation {}", uuid);
-                        self.remove(uuid).await?;
-                        removed_count += 1;
+            for prefix in &response.common_prefixes {
+                let prefix = &prefix[self.db_name.len() + 1..prefix.len() - 1];
+                let uuid = Uuid::try_parse(prefix)?;
+                if let Some(datetime) = Replicator::generation_to_timestamp(&uuid) {
+                    if datetime.to_unix().0 >= self.threshold.timestamp() as u64 {
+                        continue;
                     }
+                    tracing::debug!("Removing generation {}", uuid);
+                    self.remove(uuid).await?;
+                    removed_count += 1;
                 }
             }
 
-            next_marker = response.next_marker().map(|s| s.to_owned());
+            next_marker = response.next_marker;
             if next_marker.is_none() {
                 break;
             }
@@ -1580,59 +2125,101 @@ impl DeleteAll {
 
     pub async fn remove_tombstone(&self) -> Result<()> {
         let key = format!("{}.tombstone", self.db_name);
-        self.client
-            .delete_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await?;
+        self.storage.delete(&key).await?;
         Ok(())
     }
 
     async fn remove(&self, generation: Uuid) -> Result<()> {
-        let mut removed = 0;
-        let mut next_marker = None;
-        loop {
-            let mut list_request = self
-                .client
-                .list_objects()
-                .bucket(&self.bucket)
-                .prefix(format!("{}-{}/", &self.db_name, generation));
+        remove_generation(self.storage.as_ref(), &self.db_name, &generation).await?;
+        Ok(())
+    }
+}
 
-            if let Some(marker) = next_marker {
-                list_request = list_request.marker(marker)
-            }
+/// Returns the parent of a generation, which it was started from.
+pub(crate) async fn fetch_dependency(
+    storage: &dyn ObjectStorage,
+    db_name: &str,
+    generation: &Uuid,
+) -> Result<Option<Uuid>> {
+    let key = format!("{}-{}/.dep", db_name, generation);
+    match storage.get(&key).await? {
+        Some(bytes) => {
+            let prev_generation = Uuid::from_bytes(bytes.as_ref().try_into()?);
+            Ok(Some(prev_generation))
+        }
+        None => Ok(None),
+    }
+}
 
-            let response = list_request.send().await?;
-            let objs = match response.contents() {
-                Some(prefixes) => prefixes,
-                None => {
-                    return Ok(());
-                }
-            };
+/// Deletes all the objects of a generation, returning their total size.
+pub(crate) async fn remove_generation(
+    storage: &dyn ObjectStorage,
+    db_name: &str,
+    generation: &Uuid,
+) -> Result<u64> {
+    let prefix = format!("{}-{}/", db_name, generation);
+    let mut removed = 0;
+    let mut removed_bytes = 0;
+    let mut next_marker = None;
+    loop {
+        let response = storage
+            .list(&prefix, false, next_marker.as_deref(), None)
+            .await?;
+        if response.objects.is_empty() {
+            return Ok(removed_bytes);
+        }
 
-            for obj in objs {
-                if let Some(key) = obj.key() {
-                    tracing::trace!("Removing {}", key);
-                    self.client
-                        .delete_object()
-                        .bucket(&self.bucket)
-                        .key(key)
-                        .send()
-                        .await?;
-                    removed += 1;
-                }
-            }
+        for obj in &response.objects {
+            tracing::trace!("Removing {}", obj.key);
+            storage.delete(&obj.key).await?;
+            removed += 1;
+            removed_bytes += obj.size;
+        }
 
-            next_marker = response.next_marker().map(|s| s.to_owned());
-            if next_marker.is_none() {
-                tracing::trace!("Removed {} snapshot generations", removed);
-                return Ok(());
-            }
+        next_marker = response.next_marker;
+        if next_marker.is_none() {
+            tracing::trace!("Removed {} objects of generation {}", removed, generation);
+            return Ok(removed_bytes);
         }
     }
 }
 
+/// Checks the SHA-256 `digest` of the object `key` against the manifest of its generation.
+/// Objects without an entry in the manifest, uploaded by older versions, are not verified.
+fn verify_digest(manifest: &HashMap<String, String>, key: &str, digest: &str) -> Result<()> {
+    let fname = &key[(key.rfind('/').unwrap() + 1)..];
+    match manifest.get(fname) {
+        Some(expected) if expected != digest => bail!(
+            "{} failed integrity verification: expected SHA-256 {}, got {}",
+            key,
+            expected,
+            digest
+        ),
+        Some(_) => {}
+        None if !manifest.is_empty() => {
+            tracing::debug!("No manifest entry for {}, skipping verification", key)
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Returns the hex-encoded SHA-256 digest of the content of `file`, rewinding it afterwards.
+async fn file_sha256(file: &mut File) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    file.seek(SeekFrom::Start(0)).await?;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 pub struct Context {
     pub replicator: Replicator,
     pub runtime: tokio::runtime::Runtime,
@@ -1643,23 +2230,121 @@ pub enum CompressionKind {
     #[default]
     None,
     Gzip,
+    Zstd,
 }
 
 impl CompressionKind {
+    pub const ALL: [CompressionKind; 3] = [
+        CompressionKind::None,
+        CompressionKind::Gzip,
+        CompressionKind::Zstd,
+    ];
+
     pub fn parse(kind: &str) -> std::result::Result<Self, &str> {
         match kind {
             "gz" | "gzip" => Ok(CompressionKind::Gzip),
-            "raw" | "" => Ok(CompressionKind::None),
+            "zst" | "zstd" => Ok(CompressionKind::Zstd),
+            "raw" | "none" | "" => Ok(CompressionKind::None),
             other => Err(other),
         }
     }
 }
 
+/// Maps an optional compression level from [Options] onto the level of the encoder.
+pub(crate) fn compression_level(level: Option<u32>) -> Level {
+    match level {
+        Some(level) => Level::Precise(level),
+        None => Level::Default,
+    }
+}
+
 impl std::fmt::Display for CompressionKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CompressionKind::None => write!(f, "raw"),
             CompressionKind::Gzip => write!(f, "gz"),
+            CompressionKind::Zstd => write!(f, "zst"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_replicator() -> Replicator {
+        let options = Options {
+            bucket_name: "test".to_string(),
+            ..Options::default()
+        };
+        let storage = Arc::new(CloudStorage::in_memory());
+        Replicator::with_storage("test/data", options, storage)
+            .await
+            .unwrap()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    #[test]
+    fn digest_verified_against_manifest() {
+        let manifest = HashMap::from([("db.gz".to_string(), sha256(b"snapshot"))]);
+        assert!(verify_digest(&manifest, "ns-gen/db.gz", &sha256(b"snapshot")).is_ok());
+        let err = verify_digest(&manifest, "ns-gen/db.gz", &sha256(b"corrupt")).unwrap_err();
+        assert!(err.to_string().contains("ns-gen/db.gz"));
+        // objects missing from the manifest were uploaded by older versions
+        assert!(verify_digest(&manifest, "ns-gen/db.zst", &sha256(b"snapshot")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn generation_verified_against_manifest() {
+        let replicator = test_replicator().await;
+        let storage = replicator.storage.clone();
+        let generation = Uuid::new_v4();
+        let prefix = format!("{}-{}", replicator.db_name, generation);
+        let batch = "000000000001-000000000002-1700000000.raw";
+        storage
+            .put(&format!("{prefix}/db.gz"), Bytes::from_static(b"snapshot"))
+            .await
+            .unwrap();
+        storage
+            .put(&format!("{prefix}/{batch}"), Bytes::from_static(b"frames"))
+            .await
+            .unwrap();
+        assert_eq!(
+            replicator.verify_generation(&generation).await.unwrap(),
+            ChecksumStatus::Unknown
+        );
+
+        let snapshot_manifest = format!("{}  db.gz\n", sha256(b"snapshot"));
+        storage
+            .put(
+                &format!("{prefix}/{SNAPSHOT_MANIFEST_FILE}"),
+                snapshot_manifest.into(),
+            )
+            .await
+            .unwrap();
+        let manifest = format!("{}  {batch}\n", sha256(b"frames"));
+        storage
+            .put(
+                &format!("{prefix}/000000000001.{MANIFEST_FILE}"),
+                manifest.into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            replicator.verify_generation(&generation).await.unwrap(),
+            ChecksumStatus::Verified
+        );
+
+        storage
+            .put(&format!("{prefix}/{batch}"), Bytes::from_static(b"corrupt"))
+            .await
+            .unwrap();
+        assert_eq!(
+            replicator.verify_generation(&generation).await.unwrap(),
+            ChecksumStatus::Mismatch(format!("{prefix}/{batch}"))
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Retention of the generations backed up to the object storage.
+//!
+//! When a [RetentionPolicy] is configured, the replicator periodically deletes the generations
+//! which fall outside of it. Generations needed to restore the retained ones are always kept:
+//! a generation without a snapshot is restored on top of its parent generations, up to the first
+//! one holding a snapshot.
+
+use crate::replicator::{fetch_dependency, remove_generation, Replicator};
+use crate::storage::ObjectStorage;
+use anyhow::Result;
+use arc_swap::ArcSwapOption;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Policy deciding which generations are kept. A generation falls outside the policy if it's
+/// older than `retention_days`, or if it's not among the `max_generations` newest ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub retention_days: Option<u32>,
+    pub max_generations: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.retention_days.is_some() || self.max_generations.is_some()
+    }
+
+    /// Checks if a generation is within the policy, given its position among the generations
+    /// of the database, from the newest one (0), and its age.
+    fn retains(&self, rank: usize, age: Duration) -> bool {
+        let too_many = matches!(self.max_generations, Some(max) if rank >= max);
+        let too_old = matches!(
+            self.retention_days,
+            Some(days) if age >= Duration::from_secs(days as u64 * SECONDS_PER_DAY)
+        );
+        !too_many && !too_old
+    }
+}
+
+/// Background task deleting the generations of a database that fall outside of its policy.
+pub(crate) struct RetentionTask {
+    pub storage: Arc<dyn ObjectStorage>,
+    pub db_name: String,
+    /// Generation currently written to by the replicator.
+    pub generation: Arc<ArcSwapOption<Uuid>>,
+    pub policy: RetentionPolicy,
+}
+
+impl RetentionTask {
+    pub async fn run(self, interval: Duration) {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticker.tick().await;
+            // failures, e.g. a missing permission to delete objects, must not stop replication,
+            // so they are only reported and the policy is enforced again on the next tick
+            if let Err(e) = self.enforce().await {
+                tracing::error!(
+                    "Failed to enforce the retention policy of '{}': {}",
+                    self.db_name,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn enforce(&self) -> Result<()> {
+        // until the replicator picks its generation, we can't tell which ones it depends on
+        let Some(current) = self.generation.load_full() else {
+            return Ok(());
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let generations = self.generations().await?;
+
+        let mut retained: HashSet<Uuid> = generations
+            .iter()
+            .enumerate()
+            .filter(|(rank, (_, created))| {
+                self.policy
+                    .retains(*rank, now.saturating_sub(Duration::from_secs(*created)))
+            })
+            .map(|(_, (generation, _))| *generation)
+            .collect();
+        retained.insert(*current);
+
+        let mut pending: Vec<Uuid> = retained.iter().copied().collect();
+        let mut visited = HashSet::new();
+        while let Some(generation) = pending.pop() {
+            if !visited.insert(generation) || self.has_snapshot(&generation).await? {
+                continue;
+            }
+            let parent =
+                fetch_dependency(self.storage.as_ref(), &self.db_name, &generation).await?;
+            if let Some(parent) = parent {
+                retained.insert(parent);
+                pending.push(parent);
+            }
+        }
+
+        for (generation, _) in generations {
+            if retained.contains(&generation) {
+                continue;
+            }
+            match remove_generation(self.storage.as_ref(), &self.db_name, &generation).await {
+                Ok(freed) => tracing::info!(
+                    "Removed generation {} of '{}' outside of the retention policy (~{} bytes freed)",
+                    generation,
+                    self.db_name,
+                    freed
+                ),
+                Err(e) => tracing::error!(
+                    "Failed to remove generation {} of '{}' outside of the retention policy: {}",
+                    generation,
+                    self.db_name,
+                    e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the generations of the database with their creation time in seconds since the
+    /// unix epoch, from the newest one.
+    async fn generations(&self) -> Result<Vec<(Uuid, u64)>> {
+        let mut generations = Vec::new();
+        let prefix = format!("{}-", self.db_name);
+        let mut next_marker = None;
+        loop {
+            let response = self
+                .storage
+                .list(&prefix, true, next_marker.as_deref(), None)
+                .await?;
+            for prefix in &response.common_prefixes {
+                let prefix = &prefix[self.db_name.len() + 1..prefix.len() - 1];
+                let Ok(generation) = Uuid::try_parse(prefix) else {
+                    continue;
+                };
+                if let Some(ts) = Replicator::generation_to_timestamp(&generation) {
+                    generations.push((generation, ts.to_unix().0));
+                }
+            }
+
+            next_marker = response.next_marker;
+            if next_marker.is_none() {
+                break;
+            }
+        }
+        generations.sort_by_key(|(_, created)| std::cmp::Reverse(*created));
+        Ok(generations)
+    }
+
+    async fn has_snapshot(&self, generation: &Uuid) -> Result<bool> {
+        let prefix = format!("{}-{}/db.", self.db_name, generation);
+        let response = self.storage.list(&prefix, false, None, Some(1)).await?;
+        Ok(!response.objects.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_policy() {
+        let day = Duration::from_secs(SECONDS_PER_DAY);
+
+        let disabled = RetentionPolicy::default();
+        assert!(!disabled.is_enabled());
+        assert!(disabled.retains(1000, day * 1000));
+
+        let by_count = RetentionPolicy {
+            retention_days: None,
+            max_generations: Some(2),
+        };
+        assert!(by_count.retains(1, day * 1000));
+        assert!(!by_count.retains(2, Duration::ZERO));
+
+        let by_age = RetentionPolicy {
+            retention_days: Some(7),
+            max_generations: None,
+        };
+        assert!(by_age.retains(1000, day * 6));
+        assert!(!by_age.retains(0, day * 7));
+
+        let both = RetentionPolicy {
+            retention_days: Some(7),
+            max_generations: Some(2),
+        };
+        assert!(both.retains(1, day));
+        assert!(!both.retains(1, day * 8));
+        assert!(!both.retains(2, day));
+    }
+}
@@ -0,0 +1,87 @@
+//! Status of the replication of a database to the bottomless storage.
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Status of a [crate::replicator::Replicator], updated as the frames of the current generation
+/// are written and uploaded.
+#[derive(Clone, Debug, Default)]
+pub struct ReplicatorStatus {
+    pub bucket: String,
+    /// Endpoint of the storage. Not set for S3 when the default AWS endpoint is used.
+    pub endpoint: Option<String>,
+    pub generation: Option<Uuid>,
+    /// Last frame of the current generation written by the database.
+    pub last_known_frame: u32,
+    /// Last frame of the current generation uploaded to the storage.
+    pub last_uploaded_frame: u32,
+    /// Time at which the last batch of frames was uploaded.
+    pub last_upload_at: Option<DateTime<Utc>>,
+}
+
+impl ReplicatorStatus {
+    pub(crate) fn new(bucket: String, endpoint: Option<String>) -> Self {
+        Self {
+            bucket,
+            endpoint,
+            ..Default::default()
+        }
+    }
+
+    /// Number of frames written by the database which are not uploaded yet.
+    pub fn upload_lag_frames(&self) -> u32 {
+        self.last_known_frame
+            .saturating_sub(self.last_uploaded_frame)
+    }
+
+    pub(crate) fn set_generation(&mut self, generation: Uuid) {
+        if self.generation != Some(generation) {
+            self.generation = Some(generation);
+            self.last_uploaded_frame = 0;
+        }
+    }
+
+    pub(crate) fn reset_frames(&mut self, frame_no: u32) {
+        self.last_known_frame = frame_no;
+        self.last_uploaded_frame = self.last_uploaded_frame.min(frame_no);
+    }
+
+    /// Records the upload of the frames up to `last_frame_no`, in the generation of the
+    /// directory `dir`. Uploads of previous generations are ignored.
+    pub(crate) fn record_upload(&mut self, dir: &str, last_frame_no: u32) {
+        let generation = dir
+            .get(dir.len().saturating_sub(36)..)
+            .and_then(|gen| Uuid::try_parse(gen).ok());
+        if generation.is_some() && generation == self.generation {
+            self.last_uploaded_frame = self.last_uploaded_frame.max(last_frame_no);
+            self.last_upload_at = Some(Utc::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_lag() {
+        let generation = Uuid::from_u128(1);
+        let mut status = ReplicatorStatus::new("bucket".into(), None);
+        status.set_generation(generation);
+        status.reset_frames(10);
+        assert_eq!(status.upload_lag_frames(), 10);
+
+        status.record_upload(&format!("ns-:default-{generation}"), 6);
+        assert_eq!(status.last_uploaded_frame, 6);
+        assert_eq!(status.upload_lag_frames(), 4);
+        assert!(status.last_upload_at.is_some());
+
+        // late uploads of a previous generation don't count
+        status.record_upload(&format!("ns-:default-{}", Uuid::from_u128(2)), 10);
+        assert_eq!(status.upload_lag_frames(), 4);
+
+        status.reset_frames(3);
+        assert_eq!(status.last_uploaded_frame, 3);
+        assert_eq!(status.upload_lag_frames(), 0);
+    }
+}
@@ -0,0 +1,347 @@
+use super::{ListPage, ObjectHead, ObjectInfo, ObjectStorage, StorageBackend};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use object_store::path::Path;
+use object_store::{
+    Attribute, Attributes, GetOptions, GetRange, ObjectStore, PutMultipartOpts, PutOptions,
+    WriteMultipart,
+};
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Files larger than this are uploaded with a multipart upload, instead of being read into memory.
+const MAX_SINGLE_PUT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of parts of a multipart upload which are uploaded concurrently.
+const MULTIPART_CONCURRENCY: usize = 4;
+
+/// Google Cloud Storage or Azure Blob Storage, accessed with the `object_store` crate.
+///
+/// Credentials are taken from each cloud's standard environment variables (`GOOGLE_*` and
+/// `AZURE_*`), falling back to the metadata endpoint of the instance the process runs on.
+#[derive(Clone)]
+pub struct CloudStorage {
+    store: Arc<dyn ObjectStore>,
+    bucket: String,
+    backend: StorageBackend,
+}
+
+impl CloudStorage {
+    /// Opens a Google Cloud Storage bucket.
+    pub fn gcs(bucket: &str) -> Result<Self> {
+        let store = object_store::gcp::GoogleCloudStorageBuilder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+        Ok(Self {
+            store: Arc::new(store),
+            bucket: bucket.to_string(),
+            backend: StorageBackend::Gcs,
+        })
+    }
+
+    /// Opens an Azure Blob Storage container.
+    pub fn azure(container: &str) -> Result<Self> {
+        let store = object_store::azure::MicrosoftAzureBuilder::from_env()
+            .with_container_name(container)
+            .build()?;
+        Ok(Self {
+            store: Arc::new(store),
+            bucket: container.to_string(),
+            backend: StorageBackend::Azure,
+        })
+    }
+
+    /// A bucket held in memory, for tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self {
+            store: Arc::new(object_store::memory::InMemory::new()),
+            bucket: "test".to_string(),
+            backend: StorageBackend::Gcs,
+        }
+    }
+
+    async fn list_all(&self, dir: Option<&Path>, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        Ok(self
+            .store
+            .list(dir)
+            .try_filter_map(|meta| async move {
+                let key = meta.location.to_string();
+                Ok(key.starts_with(prefix).then_some(ObjectInfo {
+                    key,
+                    size: meta.size as u64,
+                }))
+            })
+            .try_collect()
+            .await?)
+    }
+}
+
+impl fmt::Debug for CloudStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloudStorage")
+            .field("backend", &self.backend)
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+fn is_not_found(e: &object_store::Error) -> bool {
+    matches!(e, object_store::Error::NotFound { .. })
+}
+
+#[async_trait::async_trait]
+impl ObjectStorage for CloudStorage {
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    async fn ensure_bucket(&self, create: bool) -> Result<()> {
+        match self.store.list_with_delimiter(None).await {
+            Ok(_) => {
+                tracing::info!("Bucket {} exists and is accessible", self.bucket);
+                Ok(())
+            }
+            Err(e) => {
+                if create {
+                    tracing::error!(
+                        "Bucket {} is not accessible, and creating it is not supported on {}",
+                        self.bucket,
+                        self.backend
+                    );
+                } else {
+                    tracing::error!("Bucket checking error: {}", e);
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn put(&self, key: &str, body: Bytes) -> Result<()> {
+        self.store.put(&Path::from(key), body.into()).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, mut file: File, metadata: &[(&str, String)]) -> Result<()> {
+        let location = Path::from(key);
+        let attributes = metadata
+            .iter()
+            .map(|(name, value)| {
+                (
+                    Attribute::Metadata(name.to_string().into()),
+                    value.clone().into(),
+                )
+            })
+            .collect::<Attributes>();
+        let size = file.metadata().await?.len();
+        if size <= MAX_SINGLE_PUT_SIZE {
+            let mut body = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut body).await?;
+            let options = PutOptions {
+                attributes,
+                ..Default::default()
+            };
+            self.store
+                .put_opts(&location, Bytes::from(body).into(), options)
+                .await?;
+            return Ok(());
+        }
+        let options = PutMultipartOpts {
+            attributes,
+            ..Default::default()
+        };
+        let upload = self.store.put_multipart_opts(&location, options).await?;
+        let mut writer = WriteMultipart::new(upload);
+        let uploaded = async {
+            let mut buf = vec![0; 1024 * 1024];
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                writer.wait_for_capacity(MULTIPART_CONCURRENCY).await?;
+                writer.write(&buf[..n]);
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        match uploaded {
+            Ok(()) => writer.finish().await?,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(e).with_context(|| format!("failed to upload {key}"));
+            }
+        };
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match self.store.get(&Path::from(key)).await {
+            Ok(result) => Ok(Some(result.bytes().await?)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+        if_match: Option<&str>,
+    ) -> Result<Bytes> {
+        let options = GetOptions {
+            if_match: if_match.map(str::to_owned),
+            range: Some(GetRange::Bounded(range.start as usize..range.end as usize)),
+            ..Default::default()
+        };
+        let result = self.store.get_opts(&Path::from(key), options).await?;
+        Ok(result.bytes().await?)
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectHead>> {
+        let options = GetOptions {
+            head: true,
+            ..Default::default()
+        };
+        match self.store.get_opts(&Path::from(key), options).await {
+            Ok(result) => Ok(Some(ObjectHead {
+                size: result.meta.size as u64,
+                etag: result.meta.e_tag,
+                last_modified: Some(result.meta.last_modified),
+                metadata: result
+                    .attributes
+                    .iter()
+                    .filter_map(|(attribute, value)| match attribute {
+                        Attribute::Metadata(name) => {
+                            Some((name.to_string(), value.as_ref().to_string()))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+            })),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(
+        &self,
+        prefix: &str,
+        delimiter: bool,
+        marker: Option<&str>,
+        max_keys: Option<usize>,
+    ) -> Result<ListPage> {
+        // `object_store` lists directories, while S3 lists keys starting with any string, so the
+        // directory holding the prefix is listed and its entries filtered
+        let dir = prefix.rsplit_once('/').map(|(dir, _)| Path::from(dir));
+        let (mut objects, mut common_prefixes) = if delimiter {
+            let listed = self.store.list_with_delimiter(dir.as_ref()).await?;
+            let objects = listed
+                .objects
+                .into_iter()
+                .map(|meta| ObjectInfo {
+                    key: meta.location.to_string(),
+                    size: meta.size as u64,
+                })
+                .filter(|obj| obj.key.starts_with(prefix))
+                .collect();
+            let common_prefixes = listed
+                .common_prefixes
+                .into_iter()
+                .map(|p| format!("{p}/"))
+                .filter(|p| p.starts_with(prefix))
+                .collect();
+            (objects, common_prefixes)
+        } else {
+            (self.list_all(dir.as_ref(), prefix).await?, Vec::new())
+        };
+
+        if let Some(marker) = marker {
+            objects.retain(|obj| obj.key.as_str() > marker);
+            common_prefixes.retain(|p| p.as_str() > marker);
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        common_prefixes.sort();
+
+        let mut next_marker = None;
+        if let Some(max_keys) = max_keys {
+            if objects.len() + common_prefixes.len() > max_keys {
+                // keep the first `max_keys` entries of both lists, merged in key order
+                let mut keys: Vec<&str> = objects
+                    .iter()
+                    .map(|obj| obj.key.as_str())
+                    .chain(common_prefixes.iter().map(String::as_str))
+                    .collect();
+                keys.sort_unstable();
+                let last = keys[max_keys.max(1) - 1].to_string();
+                objects.retain(|obj| obj.key <= last);
+                common_prefixes.retain(|p| *p <= last);
+                next_marker = Some(last);
+            }
+        }
+
+        Ok(ListPage {
+            objects,
+            common_prefixes,
+            next_marker,
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match self.store.delete(&Path::from(key)).await {
+            Ok(()) => Ok(()),
+            // S3 doesn't fail deleting missing objects, and neither should other backends
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn copy(&self, source_bucket: &str, from: &str, to: &str, _size: u64) -> Result<()> {
+        if source_bucket != self.bucket {
+            bail!(
+                "copying from another bucket is not supported on {}: {} -> {}",
+                self.backend,
+                source_bucket,
+                self.bucket
+            );
+        }
+        self.store.copy(&Path::from(from), &Path::from(to)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn put_file_stores_metadata() {
+        let storage = CloudStorage::in_memory();
+        let path = std::env::temp_dir().join(format!("bottomless-cloud-{}", std::process::id()));
+        tokio::fs::write(&path, b"snapshot").await.unwrap();
+
+        let file = File::open(&path).await.unwrap();
+        storage
+            .put_file(
+                "ns/db.gz",
+                file,
+                &[("uncompressed-size", "4096".to_string())],
+            )
+            .await
+            .unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        let head = storage.head("ns/db.gz").await.unwrap().unwrap();
+        assert_eq!(head.size, 8);
+        assert_eq!(
+            head.metadata,
+            HashMap::from([("uncompressed-size".to_string(), "4096".to_string())])
+        );
+        assert!(storage.head("ns/missing").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,158 @@
+//! Object storage backends where the generations are backed up.
+//!
+//! The replicator only needs a handful of operations from the storage, which [ObjectStorage]
+//! abstracts over. S3-compatible storage is accessed with the AWS SDK, while Google Cloud Storage
+//! and Azure Blob Storage are accessed with the `object_store` crate.
+
+mod cloud;
+mod s3;
+
+pub use cloud::CloudStorage;
+pub use s3::S3Storage;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use tokio::fs::File;
+
+/// An object listed by [ObjectStorage::list].
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Metadata of an object, returned by [ObjectStorage::head].
+#[derive(Clone, Debug, Default)]
+pub struct ObjectHead {
+    pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// User-defined metadata of the object.
+    pub metadata: HashMap<String, String>,
+}
+
+/// A page of the results of [ObjectStorage::list], sorted by key.
+#[derive(Clone, Debug, Default)]
+pub struct ListPage {
+    pub objects: Vec<ObjectInfo>,
+    /// Prefixes of the keys listed, up to the first `/` following the listed prefix. Only
+    /// returned when listing with a delimiter.
+    pub common_prefixes: Vec<String>,
+    /// Marker from which the next page is listed, if there are more results.
+    pub next_marker: Option<String>,
+}
+
+/// Operations used by bottomless on the bucket (or container) holding the backups.
+#[async_trait::async_trait]
+pub trait ObjectStorage: fmt::Debug + Send + Sync + 'static {
+    /// Name of the bucket or container the objects are stored in.
+    fn bucket(&self) -> &str;
+
+    /// Checks that the bucket is accessible, creating it if it doesn't exist and `create` is set.
+    async fn ensure_bucket(&self, create: bool) -> Result<()>;
+
+    async fn put(&self, key: &str, body: Bytes) -> Result<()>;
+
+    /// Uploads the content of `file`, with the given user-defined metadata.
+    async fn put_file(&self, key: &str, file: File, metadata: &[(&str, String)]) -> Result<()>;
+
+    /// Downloads an object, returning `None` if it doesn't exist.
+    async fn get(&self, key: &str) -> Result<Option<Bytes>>;
+
+    /// Downloads a range of an object. If `if_match` is set, the download fails unless the etag
+    /// of the object is still the same.
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+        if_match: Option<&str>,
+    ) -> Result<Bytes>;
+
+    /// Returns the metadata of an object, or `None` if it doesn't exist.
+    async fn head(&self, key: &str) -> Result<Option<ObjectHead>>;
+
+    /// Lists the objects whose key starts with `prefix`, from the first one following `marker`.
+    /// With `delimiter`, keys containing a `/` after the prefix are grouped into common prefixes
+    /// instead. At most `max_keys` objects are returned, if set.
+    async fn list(
+        &self,
+        prefix: &str,
+        delimiter: bool,
+        marker: Option<&str>,
+        max_keys: Option<usize>,
+    ) -> Result<ListPage>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Copies the object `from` of the bucket `source_bucket` into this bucket, as `to`.
+    async fn copy(&self, source_bucket: &str, from: &str, to: &str, size: u64) -> Result<()>;
+}
+
+/// Kind of storage the generations are backed up to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// S3-compatible storage.
+    #[default]
+    S3,
+    /// Google Cloud Storage.
+    Gcs,
+    /// Azure Blob Storage.
+    Azure,
+}
+
+impl StorageBackend {
+    pub fn parse(backend: &str) -> Result<Self> {
+        match backend.to_lowercase().as_str() {
+            "s3" => Ok(Self::S3),
+            "gcs" | "gs" => Ok(Self::Gcs),
+            "azure" | "azblob" => Ok(Self::Azure),
+            other => bail!("unknown storage backend: {other}"),
+        }
+    }
+
+    /// Parses the backend and bucket from an endpoint URL such as `gs://bucket` or
+    /// `azblob://container`. Returns `None` for other endpoints, which are S3 endpoints.
+    pub fn from_url(endpoint: &str) -> Option<(Self, String)> {
+        let (scheme, bucket) = endpoint.split_once("://")?;
+        let backend = match scheme {
+            "gs" => Self::Gcs,
+            "azblob" => Self::Azure,
+            _ => return None,
+        };
+        Some((backend, bucket.trim_end_matches('/').to_string()))
+    }
+}
+
+impl fmt::Display for StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageBackend::S3 => write!(f, "s3"),
+            StorageBackend::Gcs => write!(f, "gcs"),
+            StorageBackend::Azure => write!(f, "azure"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_from_url() {
+        assert_eq!(
+            StorageBackend::from_url("gs://backups/"),
+            Some((StorageBackend::Gcs, "backups".to_string()))
+        );
+        assert_eq!(
+            StorageBackend::from_url("azblob://backups"),
+            Some((StorageBackend::Azure, "backups".to_string()))
+        );
+        assert_eq!(StorageBackend::from_url("http://localhost:9000"), None);
+        assert_eq!(StorageBackend::parse("GCS").unwrap(), StorageBackend::Gcs);
+        assert!(StorageBackend::parse("ftp").is_err());
+    }
+}
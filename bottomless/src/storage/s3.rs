@@ -0,0 +1,325 @@
+use super::{ListPage, ObjectHead, ObjectInfo, ObjectStorage};
+use anyhow::Result;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::ops::Range;
+use std::time::SystemTime;
+use tokio::fs::File;
+
+/// Largest object that can be copied with a single CopyObject request.
+const MAX_SINGLE_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// Size of the parts of objects copied with a multipart upload.
+const COPY_PART_SIZE: u64 = 512 * 1024 * 1024;
+
+/// S3-compatible storage, accessed with the AWS SDK.
+#[derive(Debug, Clone)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    pub fn new(client: Client, bucket: String) -> Self {
+        Self { client, bucket }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    async fn copy_multipart(
+        &self,
+        source: &str,
+        from_bucket: &str,
+        from: &str,
+        to: &str,
+        size: u64,
+    ) -> Result<()> {
+        // unlike CopyObject, a multipart upload doesn't carry over the metadata of the source
+        let head = self
+            .client
+            .head_object()
+            .bucket(from_bucket)
+            .key(from)
+            .send()
+            .await?;
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(to)
+            .set_metadata(head.metadata().cloned())
+            .set_content_type(head.content_type().map(str::to_owned))
+            .send()
+            .await?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| anyhow::anyhow!("no upload id returned for {to}"))?;
+
+        let mut parts = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            let part_number = parts.len() as i32 + 1;
+            let part = self
+                .client
+                .upload_part_copy()
+                .bucket(&self.bucket)
+                .key(to)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .copy_source(source)
+                .copy_source_range(format!("bytes={start}-{end}"))
+                .send()
+                .await;
+            let part = match part {
+                Ok(part) => part,
+                Err(e) => {
+                    let _ = self
+                        .client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(to)
+                        .upload_id(upload_id)
+                        .send()
+                        .await;
+                    return Err(e.into());
+                }
+            };
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(
+                        part.copy_part_result()
+                            .and_then(|r| r.e_tag())
+                            .map(str::to_owned),
+                    )
+                    .part_number(part_number)
+                    .build(),
+            );
+            start = end + 1;
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(to)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Encodes `bucket/key` to be used as the source of a copy request.
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut encoded = String::with_capacity(bucket.len() + key.len() + 1);
+    for byte in format!("{bucket}/{key}").bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[async_trait::async_trait]
+impl ObjectStorage for S3Storage {
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    async fn ensure_bucket(&self, create: bool) -> Result<()> {
+        match self.client.head_bucket().bucket(&self.bucket).send().await {
+            Ok(_) => tracing::info!("Bucket {} exists and is accessible", self.bucket),
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => {
+                if create {
+                    tracing::info!("Bucket {} not found, recreating", self.bucket);
+                    self.client
+                        .create_bucket()
+                        .bucket(&self.bucket)
+                        .send()
+                        .await?;
+                } else {
+                    tracing::error!("Bucket {} does not exist", self.bucket);
+                    return Err(SdkError::ServiceError(err).into());
+                }
+            }
+            Err(e) => {
+                tracing::error!("Bucket checking error: {}", e);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, body: Bytes) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(body))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, file: File, metadata: &[(&str, String)]) -> Result<()> {
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::read_from().file(file).build().await?);
+        for (name, value) in metadata {
+            request = request.metadata(*name, value);
+        }
+        request.send().await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(out) => Ok(Some(out.body.collect().await?.into_bytes())),
+            Err(SdkError::ServiceError(se)) if se.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+        if_match: Option<&str>,
+    ) -> Result<Bytes> {
+        let out = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", range.start, range.end - 1))
+            .set_if_match(if_match.map(str::to_owned))
+            .send()
+            .await?;
+        Ok(out.body.collect().await?.into_bytes())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectHead>> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(head) => Ok(Some(ObjectHead {
+                size: head.content_length().max(0) as u64,
+                etag: head.e_tag().map(str::to_owned),
+                last_modified: head
+                    .last_modified()
+                    .and_then(|t| SystemTime::try_from(*t).ok())
+                    .map(DateTime::<Utc>::from),
+                metadata: head.metadata().cloned().unwrap_or_default(),
+            })),
+            Err(SdkError::ServiceError(se)) if se.err().is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(
+        &self,
+        prefix: &str,
+        delimiter: bool,
+        marker: Option<&str>,
+        max_keys: Option<usize>,
+    ) -> Result<ListPage> {
+        let response = self
+            .client
+            .list_objects()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .set_delimiter(delimiter.then(|| "/".to_string()))
+            .set_marker(marker.map(str::to_owned))
+            .set_max_keys(max_keys.map(|max| max.min(i32::MAX as usize) as i32))
+            .send()
+            .await?;
+        let objects: Vec<_> = response
+            .contents()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|obj| {
+                Some(ObjectInfo {
+                    key: obj.key()?.to_string(),
+                    size: obj.size().max(0) as u64,
+                })
+            })
+            .collect();
+        let common_prefixes: Vec<_> = response
+            .common_prefixes()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|prefix| Some(prefix.prefix()?.to_string()))
+            .collect();
+        // S3 only returns the next marker when listing with a delimiter, otherwise the listing
+        // continues from the last key
+        let next_marker = if response.is_truncated() {
+            response.next_marker().map(str::to_owned).or_else(|| {
+                let last_object = objects.last().map(|obj| &obj.key);
+                last_object.max(common_prefixes.last()).cloned()
+            })
+        } else {
+            None
+        };
+        Ok(ListPage {
+            objects,
+            common_prefixes,
+            next_marker,
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn copy(&self, source_bucket: &str, from: &str, to: &str, size: u64) -> Result<()> {
+        let source = copy_source(source_bucket, from);
+        if size > MAX_SINGLE_COPY_SIZE {
+            // objects too large for CopyObject are copied part by part
+            return self
+                .copy_multipart(&source, source_bucket, from, to, size)
+                .await;
+        }
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(to)
+            .copy_source(source)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
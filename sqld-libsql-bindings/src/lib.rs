@@ -62,6 +62,33 @@ impl<'a> Connection<'a> {
         );
 
         let conn_str = format!("file:{}?_journal_mode=WAL", path.display());
+        Self::open_uri::<W>(conn_str, flags, hook_ctx, auto_checkpoint)
+    }
+
+    /// Opens an in-memory database, shared by all the connections opened with the same path in
+    /// this process. The database is dropped with the last connection to it.
+    pub fn open_in_memory<W: WalHook>(
+        path: impl AsRef<std::path::Path>,
+        flags: rusqlite::OpenFlags,
+        _wal_hook: &'static WalMethodsHook<W>,
+        hook_ctx: &'a mut W::Context,
+    ) -> Result<Self, rusqlite::Error> {
+        let path = path.as_ref().join("data");
+        tracing::trace!(
+            "Opening a connection to in-memory database {}",
+            path.display()
+        );
+
+        let conn_str = format!("file:{}?mode=memory&cache=shared", path.display());
+        Self::open_uri::<W>(conn_str, flags, hook_ctx, 0)
+    }
+
+    fn open_uri<W: WalHook>(
+        conn_str: String,
+        flags: rusqlite::OpenFlags,
+        hook_ctx: &'a mut W::Context,
+        auto_checkpoint: u32,
+    ) -> Result<Self, rusqlite::Error> {
         let filename = CString::new(conn_str).unwrap();
         let mut db: *mut rusqlite::ffi::sqlite3 = std::ptr::null_mut();
 
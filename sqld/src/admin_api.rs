@@ -1,39 +1,94 @@
 use anyhow::Context as _;
-use axum::extract::{Path, State};
+use axum::extract::{FromRequest, Path, Query, RawBody, State};
+use axum::response::IntoResponse;
 use axum::routing::delete;
 use axum::Json;
-use chrono::NaiveDateTime;
-use futures::TryStreamExt;
-use serde::Deserialize;
+use bottomless::replicator::Replicator;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use hyper_tungstenite::tungstenite::{Error as WsError, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio_util::io::ReaderStream;
 use url::Url;
 use uuid::Uuid;
 
-use crate::connection::config::{DatabaseConfig, DatabaseConfigStore};
+use crate::auth::{Auth, Authenticated, Authorized, RevokedJwts};
+use crate::connection::config::{check_page_size, DatabaseConfig, DatabaseConfigStore};
+use crate::connection::dump::loader::{LoadDumpOptions, LoadDumpStats};
+use crate::connection::dump::s3::export_dump_to_s3;
+use crate::connection::schema::Schema;
+use crate::connection::{Connection, MakeConnection};
+use crate::database::Database;
 use crate::error::LoadDumpError;
+use crate::heap_limit::{self, MemoryStatus};
+use crate::http::dump::dump_reader;
+use crate::http::response_headers::parse_response_headers;
 use crate::namespace::{DumpStream, MakeNamespace, NamespaceStore, RestoreOption};
+use crate::query_analysis::Statement;
+use crate::query_result_builder::{QueryResultBuilder, StepResult, StepResultsBuilder};
+use crate::rpc::tls::TlsReload;
+use crate::stats::{SchemaEvent, SlowQuery, Stats};
+use crate::DEFAULT_NAMESPACE_NAME;
 
 struct AppState<M: MakeNamespace> {
+    /// Authentication of the user API, which the namespaces uploaded by other primaries require.
+    auth: Arc<Auth>,
     db_config_store: Arc<DatabaseConfigStore>,
     namespaces: NamespaceStore<M>,
+    tls_reload: Option<Arc<dyn TlsReload>>,
+    bottomless_replication: Option<bottomless::replicator::Options>,
+    revoked_jwts: Arc<RevokedJwts>,
+    stats: Stats,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_admin_api<M, A>(
     acceptor: A,
+    auth: Arc<Auth>,
     db_config_store: Arc<DatabaseConfigStore>,
     namespaces: NamespaceStore<M>,
+    tls_reload: Option<Arc<dyn TlsReload>>,
+    bottomless_replication: Option<bottomless::replicator::Options>,
+    revoked_jwts: Arc<RevokedJwts>,
+    stats: Stats,
 ) -> anyhow::Result<()>
 where
     A: crate::net::Accept,
     M: MakeNamespace,
 {
+    let router = admin_router(AppState {
+        auth,
+        db_config_store,
+        namespaces,
+        tls_reload,
+        bottomless_replication,
+        revoked_jwts,
+        stats,
+    });
+
+    hyper::server::Server::builder(acceptor)
+        .serve(router.into_make_service())
+        .await
+        .context("Could not bind admin HTTP API server")?;
+    Ok(())
+}
+
+fn admin_router<M: MakeNamespace>(state: AppState<M>) -> axum::Router {
     use axum::routing::{get, post};
-    let router = axum::Router::new()
+    axum::Router::new()
         .route("/", get(handle_get_index))
         .route("/v1/config", get(handle_get_config))
+        .route("/v1/status", get(handle_get_status))
+        .route("/v1/bottomless/status", get(handle_get_bottomless_status))
         .route("/v1/block", post(handle_post_block))
+        .route("/v1/tls/reload", post(handle_reload_tls))
+        .route("/v1/auth/revoke", post(handle_revoke_jwt))
+        .route("/v1/auth/revoke/:jti", delete(handle_unrevoke_jwt))
+        .route("/v1/auth/revoked", get(handle_get_revoked_jwts))
         .route(
             "/v1/namespaces/:namespace/fork/:to",
             post(handle_fork_namespace),
@@ -44,19 +99,54 @@ where
         )
         .route(
             "/v1/namespaces/:namespace/restore",
-            post(handle_restore_namespace),
+            post(handle_restore_namespace).delete(handle_abort_restore_namespace),
+        )
+        .route(
+            "/v1/namespaces/:namespace/migrate",
+            post(handle_migrate_namespace),
+        )
+        .route(
+            "/v1/namespaces/:namespace/load",
+            post(handle_load_namespace),
+        )
+        .route(
+            "/v1/namespaces/:namespace/config",
+            get(handle_get_namespace_config).patch(handle_patch_namespace_config),
+        )
+        .route(
+            "/v1/namespaces/:namespace/schema",
+            get(handle_get_namespace_schema),
+        )
+        .route(
+            "/v1/namespaces/:namespace/integrity-check",
+            get(handle_integrity_check_namespace),
+        )
+        .route(
+            "/v1/namespaces/:namespace/vacuum",
+            post(handle_vacuum_namespace),
+        )
+        .route(
+            "/v1/namespaces/:namespace/backup",
+            post(handle_backup_namespace),
+        )
+        .route(
+            "/v1/namespaces/:namespace/stats",
+            get(handle_get_namespace_stats),
+        )
+        .route(
+            "/v1/namespaces/:namespace/memory",
+            get(handle_get_namespace_memory),
+        )
+        .route(
+            "/v1/namespaces/:namespace/slow-queries",
+            get(handle_get_namespace_slow_queries),
+        )
+        .route(
+            "/v1/namespaces/:namespace/schema-events",
+            get(handle_namespace_schema_events),
         )
         .route("/v1/namespaces/:namespace", delete(handle_delete_namespace))
-        .with_state(Arc::new(AppState {
-            db_config_store,
-            namespaces,
-        }));
-
-    hyper::server::Server::builder(acceptor)
-        .serve(router.into_make_service())
-        .await
-        .context("Could not bind admin HTTP API server")?;
-    Ok(())
+        .with_state(Arc::new(state))
 }
 
 async fn handle_get_index() -> &'static str {
@@ -69,6 +159,99 @@ async fn handle_get_config<M: MakeNamespace>(
     Json(app_state.db_config_store.get())
 }
 
+#[derive(Debug, Serialize)]
+struct StatusResp {
+    /// Present only if bottomless replication is enabled.
+    bottomless: Option<BottomlessStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct BottomlessStatus {
+    bucket: String,
+    retention_days: Option<u32>,
+    max_generations: Option<usize>,
+    retention_check_interval_s: Option<u64>,
+}
+
+async fn handle_get_status<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+) -> Json<StatusResp> {
+    let bottomless = app_state
+        .bottomless_replication
+        .as_ref()
+        .map(|options| BottomlessStatus {
+            bucket: options.bucket_name.clone(),
+            retention_days: options.retention.retention_days,
+            max_generations: options.retention.max_generations,
+            retention_check_interval_s: options
+                .retention
+                .is_enabled()
+                .then_some(options.retention_check_interval.as_secs()),
+        });
+    Json(StatusResp { bottomless })
+}
+
+#[derive(Debug, Deserialize)]
+struct BottomlessStatusQuery {
+    #[serde(default)]
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BottomlessReplicationResp {
+    enabled: bool,
+    #[serde(flatten)]
+    status: Option<BottomlessReplicationStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct BottomlessReplicationStatus {
+    bucket: String,
+    endpoint: Option<String>,
+    current_generation: Option<Uuid>,
+    last_uploaded_frame: u32,
+    last_upload_at: Option<DateTime<Utc>>,
+    upload_lag_frames: u32,
+}
+
+/// Reports the progress of the bottomless replication of a namespace, the default one unless
+/// the `namespace` query parameter is set.
+async fn handle_get_bottomless_status<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Query(query): Query<BottomlessStatusQuery>,
+) -> crate::Result<Json<BottomlessReplicationResp>> {
+    if app_state.bottomless_replication.is_none() {
+        return Ok(Json(BottomlessReplicationResp {
+            enabled: false,
+            status: None,
+        }));
+    }
+
+    let namespace = query
+        .namespace
+        .unwrap_or_else(|| DEFAULT_NAMESPACE_NAME.to_string());
+    let status = app_state
+        .namespaces
+        .with(namespace.into(), |ns| ns.bottomless_status.clone())
+        .await?;
+    let status = status.map(|status| {
+        let status = status.lock().unwrap();
+        BottomlessReplicationStatus {
+            bucket: status.bucket.clone(),
+            endpoint: status.endpoint.clone(),
+            current_generation: status.generation,
+            last_uploaded_frame: status.last_uploaded_frame,
+            last_upload_at: status.last_upload_at,
+            upload_lag_frames: status.upload_lag_frames(),
+        }
+    });
+
+    Ok(Json(BottomlessReplicationResp {
+        enabled: status.is_some(),
+        status,
+    }))
+}
+
 #[derive(Debug, Deserialize)]
 struct BlockReq {
     block_reads: bool,
@@ -77,9 +260,75 @@ struct BlockReq {
     block_reason: Option<String>,
 }
 
+/// Partial update of the config of a namespace. Fields that can't be updated are rejected, and
+/// the request fails with 422 Unprocessable Entity.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchNamespaceConfigReq {
+    #[serde(default)]
+    block_reads: Option<bool>,
+    #[serde(default)]
+    block_writes: Option<bool>,
+    #[serde(default)]
+    block_reason: Option<String>,
+    /// New size quota of the namespace in bytes, or `null` to fall back to the server quota.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    max_db_size: Option<Option<u64>>,
+    /// New maximum number of rows returned by a statement, or `null` to fall back to the limit of
+    /// the server.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    max_rows: Option<Option<u64>>,
+    /// New origins allowed to make cross-origin requests to the namespace, or `null` to allow any
+    /// origin.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    cors_origins: Option<Option<Vec<String>>>,
+    /// New soft heap limit of the namespace in MiB, or `null` to lift it.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    soft_heap_limit_mb: Option<Option<usize>>,
+    /// New hard heap limit of the namespace in MiB, or `null` to lift it.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    hard_heap_limit_mb: Option<Option<usize>>,
+    /// Whether connections to the namespace may attach the namespaces of the allowlist.
+    #[serde(default)]
+    allow_attach: Option<bool>,
+    /// New namespaces that connections to the namespace may attach read-only.
+    #[serde(default)]
+    attach_allowlist: Option<Vec<String>>,
+    /// New pragmas that connections to the namespace may execute, or `null` to allow the
+    /// read-only pragmas.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pragma_allowlist: Option<Option<Vec<String>>>,
+    /// New slow query threshold of the namespace in microseconds, or `null` to stop logging slow
+    /// queries.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    slow_query_threshold_us: Option<Option<u64>>,
+    /// New headers added to the HTTP responses of the namespace, replacing the previous ones.
+    #[serde(default)]
+    response_headers: Option<HashMap<String, String>>,
+    /// New page size of the database in bytes, or `null` to use the default page size. It only
+    /// applies to a database that has no tables yet.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    page_size: Option<Option<u32>>,
+}
+
+/// Deserializes a field that is present, even if `null`, to `Some`, to tell it apart from an
+/// absent field.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
 #[derive(Debug, Deserialize)]
 struct CreateNamespaceReq {
     dump_url: Option<Url>,
+    /// DDL script executed in a single transaction once the namespace is created.
+    init_sql: Option<String>,
+    /// Create the database in memory, for disposable data.
+    #[serde(default)]
+    in_memory: bool,
 }
 
 async fn handle_post_block<M: MakeNamespace>(
@@ -100,17 +349,384 @@ async fn handle_post_block<M: MakeNamespace>(
     }
 }
 
+async fn handle_get_namespace_config<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<Json<Arc<DatabaseConfig>>> {
+    let store = app_state
+        .namespaces
+        .with(namespace.into(), |ns| ns.config_store.clone())
+        .await?;
+    Ok(Json(store.get()))
+}
+
+#[derive(Debug, Serialize)]
+struct NamespaceStatsResp {
+    /// Unix timestamp of the last write to the namespace, in milliseconds, if it was ever written.
+    last_write_ms: Option<u64>,
+}
+
+/// Returns the stats of a namespace. The namespace is not loaded, so that the stats of idle
+/// namespaces can be polled without waking them up.
+async fn handle_get_namespace_stats<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+) -> Json<NamespaceStatsResp> {
+    Json(NamespaceStatsResp {
+        last_write_ms: app_state.stats.namespace_last_write_ms(&namespace),
+    })
+}
+
+#[derive(Serialize)]
+struct NamespaceMemoryResp {
+    /// Memory allocated by SQLite for the namespace, in bytes, 0 if it is not tracked.
+    heap_used: u64,
+    /// Heap limits of the namespace, in bytes.
+    soft_heap_limit: Option<u64>,
+    hard_heap_limit: Option<u64>,
+    /// Number of open connections to the namespace.
+    connections: usize,
+    /// Memory used by all the connections.
+    total: MemoryStatus,
+    /// Largest memory used by a single connection, for each kind of memory.
+    max: MemoryStatus,
+}
+
+/// Samples the memory used by the open connections to a namespace, as reported by
+/// `sqlite3_db_status` when each connection last executed a request, along with the heap usage and
+/// limits of the namespace.
+async fn handle_get_namespace_memory<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<Json<NamespaceMemoryResp>> {
+    // fails if the namespace doesn't exist
+    app_state
+        .namespaces
+        .with(namespace.clone().into(), |_| ())
+        .await?;
+
+    let heap = heap_limit::namespace_heap(&namespace);
+    let (soft_heap_limit, hard_heap_limit) = heap.limits();
+    let connections = heap.connections_memory();
+    let mut total = MemoryStatus::default();
+    let mut max = MemoryStatus::default();
+    for status in &connections {
+        total.cache_used += status.cache_used;
+        total.schema_used += status.schema_used;
+        total.stmt_used += status.stmt_used;
+        max.cache_used = max.cache_used.max(status.cache_used);
+        max.schema_used = max.schema_used.max(status.schema_used);
+        max.stmt_used = max.stmt_used.max(status.stmt_used);
+    }
+
+    Ok(Json(NamespaceMemoryResp {
+        heap_used: heap.used(),
+        soft_heap_limit,
+        hard_heap_limit,
+        connections: connections.len(),
+        total,
+        max,
+    }))
+}
+
+/// Returns the last slow queries of a namespace, oldest first. They are kept in memory only, so
+/// they are lost when the server restarts.
+async fn handle_get_namespace_slow_queries<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<Json<Vec<SlowQuery>>> {
+    // fails if the namespace doesn't exist
+    app_state
+        .namespaces
+        .with(namespace.clone().into(), |_| ())
+        .await?;
+
+    Ok(Json(app_state.stats.slow_queries(&namespace).list()))
+}
+
+/// Upgrades to a WebSocket on which a JSON message is sent for every DDL statement that completes
+/// successfully in a namespace, e.g. `{"event": "schema_change", "schema_version": 3,
+/// "statement": "ALTER TABLE ..."}`. Only the statements executed by this instance are reported:
+/// the writes of a replica are executed by its primary.
+async fn handle_namespace_schema_events<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+    mut req: hyper::Request<hyper::Body>,
+) -> crate::Result<axum::response::Response> {
+    // fails if the namespace doesn't exist
+    app_state
+        .namespaces
+        .with(namespace.clone().into(), |_| ())
+        .await?;
+
+    if !hyper_tungstenite::is_upgrade_request(&req) {
+        return Ok((
+            hyper::StatusCode::BAD_REQUEST,
+            "schema events are sent over a WebSocket",
+        )
+            .into_response());
+    }
+    let (resp, websocket) = match hyper_tungstenite::upgrade(&mut req, None) {
+        Ok(upgrade) => upgrade,
+        Err(e) => return Ok((hyper::StatusCode::BAD_REQUEST, e.to_string()).into_response()),
+    };
+
+    let events = app_state.stats.schema_events(&namespace).subscribe();
+    tokio::spawn(async move {
+        let res = match websocket.await {
+            Ok(websocket) => send_schema_events(websocket, events).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            tracing::debug!("schema events WebSocket of namespace `{namespace}` closed: {e}");
+        }
+    });
+
+    Ok(resp.into_response())
+}
+
+async fn send_schema_events<S>(
+    mut websocket: S,
+    mut events: broadcast::Receiver<SchemaEvent>,
+) -> anyhow::Result<()>
+where
+    S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin,
+{
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let msg = serde_json::to_string(&event)?;
+                    websocket.send(Message::Text(msg)).await?;
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("schema events subscriber is too slow, missed {missed} events");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // the messages of the client are ignored, but they must be read to notice that the
+            // WebSocket was closed
+            msg = websocket.next() => match msg {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_patch_namespace_config<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+    Json(req): Json<PatchNamespaceConfigReq>,
+) -> crate::Result<Json<Arc<DatabaseConfig>>> {
+    let store = app_state
+        .namespaces
+        .with(namespace.clone().into(), |ns| ns.config_store.clone())
+        .await?;
+    let mut config = (*store.get()).clone();
+    if let Some(block_reads) = req.block_reads {
+        config.block_reads = block_reads;
+    }
+    if let Some(block_writes) = req.block_writes {
+        config.block_writes = block_writes;
+    }
+    if req.block_reason.is_some() {
+        config.block_reason = req.block_reason;
+    } else if !config.block_reads && !config.block_writes {
+        config.block_reason = None;
+    }
+    if let Some(max_db_size) = req.max_db_size {
+        config.max_db_size = max_db_size;
+    }
+    if let Some(max_rows) = req.max_rows {
+        config.max_rows = max_rows;
+    }
+    if let Some(cors_origins) = req.cors_origins {
+        if let Some(origin) = cors_origins
+            .iter()
+            .flatten()
+            .find(|origin| axum::http::HeaderValue::from_str(origin).is_err())
+        {
+            return Err(crate::Error::InvalidCorsOrigin(origin.clone()));
+        }
+        config.cors_origins = cors_origins;
+    }
+    if let Some(soft_heap_limit_mb) = req.soft_heap_limit_mb {
+        config.soft_heap_limit_mb = soft_heap_limit_mb;
+    }
+    if let Some(hard_heap_limit_mb) = req.hard_heap_limit_mb {
+        config.hard_heap_limit_mb = hard_heap_limit_mb;
+    }
+    if let Some(allow_attach) = req.allow_attach {
+        config.allow_attach = allow_attach;
+    }
+    if let Some(attach_allowlist) = req.attach_allowlist {
+        config.attach_allowlist = attach_allowlist;
+    }
+    if let Some(pragma_allowlist) = req.pragma_allowlist {
+        config.pragma_allowlist = pragma_allowlist;
+    }
+    if let Some(slow_query_threshold_us) = req.slow_query_threshold_us {
+        config.slow_query_threshold_us = slow_query_threshold_us;
+    }
+    if let Some(response_headers) = req.response_headers {
+        parse_response_headers(&response_headers)?;
+        config.response_headers = response_headers;
+    }
+    if let Some(page_size) = req.page_size {
+        if let Some(page_size) = page_size {
+            check_page_size(page_size)?;
+        }
+        config.page_size = page_size;
+    }
+
+    // the connections and the CORS middleware read the config from the store on each request,
+    // so the new config applies without reopening the namespace
+    store.store(config)?;
+    tracing::info!(
+        "updated config of namespace `{namespace}`: {:?}",
+        store.get()
+    );
+
+    Ok(Json(store.get()))
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeJwtReq {
+    jti: String,
+}
+
+/// Revokes the JWTs with the given ID (`jti` claim), until the revocation is lifted.
+async fn handle_revoke_jwt<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Json(req): Json<RevokeJwtReq>,
+) -> crate::Result<()> {
+    if app_state.revoked_jwts.revoke(req.jti.clone()).await? {
+        tracing::info!("revoked JWT `{}`", req.jti);
+    }
+    Ok(())
+}
+
+async fn handle_unrevoke_jwt<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(jti): Path<String>,
+) -> crate::Result<()> {
+    if !app_state.revoked_jwts.unrevoke(&jti).await? {
+        return Err(crate::Error::JwtNotRevoked(jti));
+    }
+    tracing::info!("lifted revocation of JWT `{jti}`");
+    Ok(())
+}
+
+async fn handle_get_revoked_jwts<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+) -> Json<Vec<String>> {
+    Json(app_state.revoked_jwts.list())
+}
+
+async fn handle_reload_tls<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+) -> (axum::http::StatusCode, String) {
+    let Some(ref tls_reload) = app_state.tls_reload else {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            "gRPC TLS is not enabled".into(),
+        );
+    };
+
+    match tls_reload.reload() {
+        Ok(()) => {
+            tracing::info!("reloaded gRPC TLS config");
+            (axum::http::StatusCode::OK, "OK".into())
+        }
+        Err(err) => {
+            tracing::warn!("Could not reload gRPC TLS config, keeping the current one: {err:#}");
+            (
+                axum::http::StatusCode::BAD_REQUEST,
+                format!("Failed to reload TLS config: {err:#}"),
+            )
+        }
+    }
+}
+
 async fn handle_create_namespace<M: MakeNamespace>(
     State(app_state): State<Arc<AppState<M>>>,
     Path(namespace): Path<String>,
     Json(req): Json<CreateNamespaceReq>,
 ) -> crate::Result<()> {
+    // the init script may only contain DDL statements, and is validated before anything is
+    // created
+    let mut init_stmts = Vec::new();
+    for stmt in Statement::parse(req.init_sql.as_deref().unwrap_or_default()) {
+        let stmt = stmt.map_err(|e| crate::Error::FailedToParse(e.to_string()))?;
+        if !stmt.is_ddl {
+            return Err(crate::Error::InitSqlNotDdl(stmt.stmt));
+        }
+        init_stmts.push(stmt);
+    }
+
     let dump = match req.dump_url {
+        Some(_) if req.in_memory => return Err(crate::Error::ConflictingRestoreParameters),
         Some(ref url) => RestoreOption::Dump(dump_stream_from_url(url).await?),
+        None if req.in_memory => RestoreOption::InMemory,
         None => RestoreOption::Latest,
     };
 
-    app_state.namespaces.create(namespace.into(), dump).await?;
+    app_state
+        .namespaces
+        .create(namespace.clone().into(), dump)
+        .await?;
+
+    if !init_stmts.is_empty() {
+        if let Err(e) = run_init_sql(&app_state.namespaces, &namespace, init_stmts).await {
+            tracing::warn!("init_sql of namespace `{namespace}` failed, destroying it: {e}");
+            app_state.namespaces.destroy(namespace.into()).await?;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes the `init_sql` statements in a single transaction, which is rolled back if any of
+/// them fails.
+async fn run_init_sql<M: MakeNamespace>(
+    namespaces: &NamespaceStore<M>,
+    namespace: &str,
+    stmts: Vec<Statement>,
+) -> crate::Result<()> {
+    let connection_maker = namespaces
+        .with(namespace.to_owned().into(), |ns| ns.db.connection_maker())
+        .await?;
+    let conn = connection_maker.create().await?;
+
+    let query = |stmt| crate::query::Query {
+        stmt,
+        params: crate::query::Params::empty(),
+        want_rows: false,
+        max_memory_bytes: None,
+        max_rows: None,
+    };
+    let mut batch = vec![query(Statement::parse("BEGIN").next().unwrap()?)];
+    batch.extend(stmts.into_iter().map(query));
+    batch.push(query(Statement::parse("COMMIT").next().unwrap()?));
+
+    let (builder, _) = conn
+        .execute_batch_or_rollback(
+            batch,
+            Authenticated::Authorized(Authorized::FullAccess),
+            StepResultsBuilder::default(),
+        )
+        .await?;
+    for result in builder.into_ret() {
+        if let StepResult::Err(e) = result {
+            return Err(crate::Error::QueryError(e.to_string()));
+        }
+    }
+
     Ok(())
 }
 
@@ -122,6 +738,54 @@ async fn handle_fork_namespace<M: MakeNamespace>(
     Ok(())
 }
 
+#[derive(Debug, Deserialize)]
+struct MigrateNamespaceReq {
+    target_primary: Url,
+    /// `Authorization` header with which the database is uploaded to the target primary.
+    #[serde(default)]
+    target_auth: Option<String>,
+}
+
+async fn handle_migrate_namespace<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+    Json(req): Json<MigrateNamespaceReq>,
+) -> crate::Result<()> {
+    app_state
+        .namespaces
+        .migrate(namespace.into(), req.target_primary, req.target_auth)
+        .await?;
+    Ok(())
+}
+
+/// Creates a namespace from a SQL dump streamed in the request body. This is the endpoint used
+/// by a primary migrating one of its namespaces to this instance, which must be authorized with
+/// full access by the user API of this instance.
+async fn handle_load_namespace<M: MakeNamespace>(
+    State(app_state): State<Arc<AppState<M>>>,
+    Path(namespace): Path<String>,
+    headers: hyper::HeaderMap,
+    RawBody(body): RawBody,
+) -> crate::Result<()> {
+    match app_state
+        .auth
+        .authenticate_http(headers.get(hyper::header::AUTHORIZATION))?
+    {
+        Authenticated::Authorized(Authorized::FullAccess) => (),
+        _ => {
+            return Err(crate::error::Error::NotAuthorized(
+                "uploading a namespace requires full access".into(),
+            ))
+        }
+    }
+    let dump = body.map_err(|e| std::io::Error::new(ErrorKind::Other, e));
+    app_state
+        .namespaces
+        .create(namespace.into(), RestoreOption::Dump(Box::new(dump)))
+        .await?;
+    Ok(())
+}
+
 async fn dump_stream_from_url(url: &Url) -> Result<DumpStream, LoadDumpError> {
     match url.scheme() {
         "http" => {
@@ -164,26 +828,557 @@ async fn handle_delete_namespace<F: MakeNamespace>(
     Ok(())
 }
 
+#[derive(Debug, Serialize)]
+struct IntegrityCheckResp {
+    ok: bool,
+    /// Lines reported by `PRAGMA integrity_check`.
+    result: Vec<String>,
+}
+
+/// Checks the integrity of the database of a namespace. On a replica, the local copy of the
+/// database is checked.
+async fn handle_integrity_check_namespace<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<Json<IntegrityCheckResp>> {
+    let connection_maker = app_state
+        .namespaces
+        .with(namespace.clone().into(), |ns| ns.db.connection_maker())
+        .await?;
+    let conn = connection_maker.create().await?;
+    let result = conn.integrity_check().await?;
+    let ok = result == ["ok"];
+    if !ok {
+        tracing::warn!("integrity check of namespace `{namespace}` failed: {result:?}");
+    }
+
+    Ok(Json(IntegrityCheckResp { ok, result }))
+}
+
+async fn handle_get_namespace_schema<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<Schema> {
+    let connection_maker = app_state
+        .namespaces
+        .with(namespace.into(), |ns| ns.db.connection_maker())
+        .await?;
+    let conn = connection_maker.create().await?;
+    conn.schema(Authenticated::Authorized(Authorized::FullAccess))
+        .await
+}
+
+/// Vacuums the database of a namespace. This fails if a write is in progress on the namespace, and
+/// blocks the writes until it is done.
+async fn handle_vacuum_namespace<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<()> {
+    let connection_maker = app_state
+        .namespaces
+        .with(namespace.clone().into(), |ns| ns.db.connection_maker())
+        .await?;
+    let conn = connection_maker.create().await?;
+    conn.vacuum(Authenticated::Authorized(Authorized::FullAccess))
+        .await?;
+    tracing::info!("vacuumed namespace `{namespace}`");
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupQuery {
+    /// Whether to respond once the backup is uploaded, defaults to `true`.
+    #[serde(default)]
+    wait: Option<bool>,
+    /// Whether to upload a SQL dump of the namespace instead of starting a new generation.
+    #[serde(default)]
+    dump: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct BackupResp {
+    generation: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+struct DumpBackupResp {
+    key: String,
+}
+
+/// Backs up a namespace to bottomless right away. Unless `wait=false` is passed, it responds once
+/// the backup is uploaded, with the generation it can be restored from. Otherwise, the backup
+/// runs in the background and the request is answered with `202 Accepted`.
+///
+/// With `dump=true`, a SQL dump of the namespace is streamed to the bottomless bucket instead,
+/// and the response holds the key of the dump object, even with `wait=false`.
+async fn handle_backup_namespace<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+    Query(query): Query<BackupQuery>,
+) -> crate::Result<axum::response::Response> {
+    let (connection_maker, replicator) = app_state
+        .namespaces
+        .with(namespace.clone().into(), |ns| {
+            (ns.db.connection_maker(), ns.bottomless_replicator.clone())
+        })
+        .await?;
+    let (Some(replicator), Some(options)) = (replicator, app_state.bottomless_replication.clone())
+    else {
+        return Err(crate::Error::BottomlessNotEnabled(namespace));
+    };
+
+    if query.dump {
+        let key = dump_key(&replicator.lock().unwrap().db_name);
+        let backup = dump_namespace(connection_maker, replicator, options, key.clone());
+        if !query.wait.unwrap_or(true) {
+            let resp = DumpBackupResp { key: key.clone() };
+            tokio::spawn(async move {
+                match backup.await {
+                    Ok(()) => tracing::info!("dumped namespace `{namespace}` to `{key}`"),
+                    Err(e) => tracing::error!("failed to dump namespace `{namespace}`: {e}"),
+                }
+            });
+            return Ok((axum::http::StatusCode::ACCEPTED, Json(resp)).into_response());
+        }
+
+        backup.await?;
+        tracing::info!("dumped namespace `{namespace}` to `{key}`");
+
+        return Ok(Json(DumpBackupResp { key }).into_response());
+    }
+
+    if !query.wait.unwrap_or(true) {
+        tokio::spawn(async move {
+            match backup_namespace(connection_maker, replicator).await {
+                Ok(generation) => {
+                    tracing::info!("backed up namespace `{namespace}` to generation {generation}")
+                }
+                Err(e) => tracing::error!("failed to back up namespace `{namespace}`: {e}"),
+            }
+        });
+        return Ok(axum::http::StatusCode::ACCEPTED.into_response());
+    }
+
+    let generation = backup_namespace(connection_maker, replicator).await?;
+    tracing::info!("backed up namespace `{namespace}` to generation {generation}");
+
+    Ok(Json(BackupResp { generation }).into_response())
+}
+
+/// Key of a new dump of the database `db_name` in the bottomless bucket. Dumps are kept apart
+/// from the generations, which are listed by the prefix of the database name.
+fn dump_key(db_name: &str) -> String {
+    format!(
+        "dumps/{db_name}/{}.sql",
+        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+    )
+}
+
+/// Uploads a SQL dump of a namespace to the object `key` of the bottomless bucket, without
+/// buffering it on disk. The namespace is checkpointed first, for the dump to include all the
+/// committed writes.
+async fn dump_namespace<C: Connection>(
+    connection_maker: Arc<dyn MakeConnection<Connection = C>>,
+    replicator: Arc<std::sync::Mutex<Replicator>>,
+    options: bottomless::replicator::Options,
+    key: String,
+) -> crate::Result<()> {
+    let conn = connection_maker.create().await?;
+    conn.checkpoint().await?;
+    drop(conn);
+
+    let db_path = replicator.lock().unwrap().db_path.clone();
+    tokio::task::spawn_blocking(move || {
+        let conn = rusqlite::Connection::open(db_path)?;
+        export_dump_to_s3(conn, &options, &key)
+    })
+    .await
+    .context("dump task panicked")??;
+
+    Ok(())
+}
+
+/// Finalizes the current bottomless generation of a namespace. The checkpoint waits for the
+/// pending WAL frames to be uploaded, and starts a new generation from a snapshot of the main
+/// database file. Returns the new generation, once its snapshot is uploaded.
+async fn backup_namespace<C: Connection>(
+    connection_maker: Arc<dyn MakeConnection<Connection = C>>,
+    replicator: Arc<std::sync::Mutex<Replicator>>,
+) -> crate::Result<Uuid> {
+    let conn = connection_maker.create().await?;
+    let prev_generation = replicator.lock().unwrap().generation().ok();
+    conn.checkpoint().await?;
+
+    let (generation, snapshot_uploaded) = {
+        let replicator = replicator.lock().unwrap();
+        let generation = replicator.generation()?;
+        // a checkpoint which couldn't complete because of readers doesn't start a new generation
+        if Some(generation) == prev_generation && replicator.last_known_frame() > 0 {
+            return Err(crate::Error::BackupBusy);
+        }
+        (generation, replicator.snapshot_uploaded(generation))
+    };
+    snapshot_uploaded.await?;
+
+    Ok(generation)
+}
+
 #[derive(Debug, Deserialize)]
 struct RestoreReq {
     generation: Option<Uuid>,
+    /// Point in time to restore to, in UTC.
     timestamp: Option<NaiveDateTime>,
 }
 
+#[derive(Serialize)]
+struct RestoreResp {
+    generation: Uuid,
+    last_applied_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreDumpQuery {
+    /// Number of statements executed in each transaction.
+    #[serde(default = "default_restore_batch_size")]
+    batch_size: u64,
+    /// Load the dump even if the database already has tables.
+    #[serde(default)]
+    allow_non_empty: bool,
+}
+
+fn default_restore_batch_size() -> u64 {
+    10_000
+}
+
+/// Restores a namespace from its bottomless backups, to a generation or to a point in time, or to
+/// the latest backup if neither is passed, as told by the JSON body of the request.
+///
+/// Otherwise, the body is a SQL dump which is loaded into the namespace (see
+/// [`restore_namespace_from_dump`]).
 async fn handle_restore_namespace<F: MakeNamespace>(
     State(app_state): State<Arc<AppState<F>>>,
     Path(namespace): Path<String>,
-    Json(req): Json<RestoreReq>,
-) -> crate::Result<()> {
-    let restore_option = match (req.generation, req.timestamp) {
-        (None, None) => RestoreOption::Latest,
-        (Some(generation), None) => RestoreOption::Generation(generation),
-        (None, Some(timestamp)) => RestoreOption::PointInTime(timestamp),
-        (Some(_), Some(_)) => return Err(crate::Error::ConflictingRestoreParameters),
+    Query(query): Query<RestoreDumpQuery>,
+    req: hyper::Request<hyper::Body>,
+) -> crate::Result<axum::response::Response> {
+    let is_json = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/json"));
+    if !is_json {
+        let stats = restore_namespace_from_dump(app_state, namespace, query, req).await?;
+        return Ok(Json(stats).into_response());
+    }
+
+    let req = match Json::<RestoreReq>::from_request(req, &()).await {
+        Ok(Json(req)) => req,
+        Err(rejection) => return Ok(rejection.into_response()),
     };
-    app_state
+    if req.generation.is_some() && req.timestamp.is_some() {
+        return Err(crate::Error::ConflictingRestoreParameters);
+    }
+    let restored = app_state
         .namespaces
-        .reset(namespace.into(), restore_option)
+        .restore(namespace.into(), req.generation, req.timestamp)
         .await?;
-    Ok(())
+    Ok(Json(RestoreResp {
+        generation: restored.generation,
+        last_applied_at: restored.last_applied_at,
+    })
+    .into_response())
+}
+
+/// Loads the SQL dump streamed in the request body into a namespace, in transactions of
+/// `?batch_size=` statements, 10,000 by default (see [`load_dump_in_batches`]). The dump can be
+/// compressed with gzip, as told by the `Content-Encoding` header.
+///
+/// If the load is interrupted, the statements of the last transaction are rolled back, and the load
+/// is resumed with a request streaming the rest of the dump, from the offset of the end of the
+/// last committed transaction, as told by a `Range: bytes=<offset>-` header. The offsets count the
+/// bytes of the uncompressed dump. A request without a `Range` header is refused with `409
+/// Conflict` while a load is to be resumed, and the error holds the offset to resume from. The
+/// interrupted load can also be abandoned with `DELETE` (see [`handle_abort_restore_namespace`]).
+///
+/// [`load_dump_in_batches`]: crate::connection::dump::loader::load_dump_in_batches
+async fn restore_namespace_from_dump<F: MakeNamespace>(
+    app_state: Arc<AppState<F>>,
+    namespace: String,
+    query: RestoreDumpQuery,
+    req: hyper::Request<hyper::Body>,
+) -> crate::Result<LoadDumpStats> {
+    let (parts, body) = req.into_parts();
+    let resume_offset = parts
+        .headers
+        .get(hyper::header::RANGE)
+        .map(parse_resume_offset)
+        .transpose()?;
+    let dump = dump_reader(&parts.headers, body)?;
+
+    let connection_maker = app_state
+        .namespaces
+        .with(namespace.clone().into(), |ns| ns.db.connection_maker())
+        .await?;
+    let conn = connection_maker.create().await?;
+    let options = LoadDumpOptions {
+        allow_non_empty: query.allow_non_empty,
+        batch_size: Some(query.batch_size),
+        resume_offset,
+        abort: false,
+    };
+    let stats = conn
+        .load_dump(
+            dump,
+            options,
+            Authenticated::Authorized(Authorized::FullAccess),
+        )
+        .await?;
+    tracing::info!("restored namespace `{namespace}` from a dump");
+
+    Ok(stats)
+}
+
+/// Abandons the interrupted load of a SQL dump into a namespace, so that another one can be
+/// started. The statements loaded so far are kept, and the response holds the progress of the
+/// load.
+async fn handle_abort_restore_namespace<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> crate::Result<Json<LoadDumpStats>> {
+    let connection_maker = app_state
+        .namespaces
+        .with(namespace.clone().into(), |ns| ns.db.connection_maker())
+        .await?;
+    let conn = connection_maker.create().await?;
+    let options = LoadDumpOptions {
+        abort: true,
+        ..Default::default()
+    };
+    let progress = conn
+        .load_dump(
+            Box::new(std::io::empty()),
+            options,
+            Authenticated::Authorized(Authorized::FullAccess),
+        )
+        .await?;
+    tracing::info!("aborted the restore of namespace `{namespace}` from a dump");
+
+    Ok(Json(progress))
+}
+
+/// Parses a `Range: bytes=<offset>-` header, the only range with which a load can be resumed.
+fn parse_resume_offset(range: &hyper::header::HeaderValue) -> crate::Result<u64> {
+    range
+        .to_str()
+        .ok()
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+        .and_then(|range| range.strip_suffix('-'))
+        .and_then(|offset| offset.parse().ok())
+        .ok_or_else(|| LoadDumpError::InvalidRange.into())
+}
+
+#[cfg(test)]
+mod test {
+    use hyper_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    use crate::error::Error;
+    use crate::namespace::MigrateError;
+    use crate::test::namespaces::{execute, has_row_value, primary_namespaces};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn migrate_namespace() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let target = primary_namespaces(target_dir.path());
+        let router = admin_router(AppState {
+            auth: Arc::new(Auth {
+                http_basic: Some("dXNlcjpwYXNz".into()),
+                ..Auth::default()
+            }),
+            db_config_store: Arc::new(DatabaseConfigStore::new_test()),
+            namespaces: target.clone(),
+            tls_reload: None,
+            bottomless_replication: None,
+            revoked_jwts: Default::default(),
+            stats: Stats::new(target_dir.path()).unwrap(),
+        });
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_url: Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = primary_namespaces(source_dir.path());
+        source
+            .create("foo".into(), RestoreOption::Latest)
+            .await
+            .unwrap();
+        execute(&source, "CREATE TABLE t (x)").await.unwrap();
+        execute(&source, "INSERT INTO t VALUES (42)").await.unwrap();
+
+        // the upload must be authorized by the target, and the source stays writable when it's not
+        let res = source.migrate("foo".into(), target_url.clone(), None).await;
+        assert!(matches!(
+            res,
+            Err(Error::Migrate(MigrateError::Rejected(
+                hyper::StatusCode::UNAUTHORIZED
+            )))
+        ));
+        execute(&source, "INSERT INTO t VALUES (43)").await.unwrap();
+
+        source
+            .migrate(
+                "foo".into(),
+                target_url.clone(),
+                Some("Basic dXNlcjpwYXNz".into()),
+            )
+            .await
+            .unwrap();
+        let calls = execute(&target, "SELECT x FROM t").await.unwrap();
+        assert!(has_row_value(&calls, 42) && has_row_value(&calls, 43));
+
+        // the source keeps serving reads, and redirects the writes to the target
+        let calls = execute(&source, "SELECT x FROM t").await.unwrap();
+        assert!(has_row_value(&calls, 42));
+        assert!(matches!(
+            execute(&source, "INSERT INTO t VALUES (44)").await,
+            Err(Error::NamespaceMigrated(_, url)) if url == target_url
+        ));
+        assert!(matches!(
+            source.migrate("foo".into(), target_url, None).await,
+            Err(Error::Migrate(MigrateError::AlreadyMigrated(..)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn restore_namespace_from_dump_in_batches() {
+        use tower::ServiceExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let namespaces = primary_namespaces(tmp.path());
+        namespaces
+            .create("foo".into(), RestoreOption::Latest)
+            .await
+            .unwrap();
+        let router = admin_router(AppState {
+            auth: Arc::new(Auth {
+                disabled: true,
+                ..Auth::default()
+            }),
+            db_config_store: Arc::new(DatabaseConfigStore::new_test()),
+            namespaces: namespaces.clone(),
+            tls_reload: None,
+            bottomless_replication: None,
+            revoked_jwts: Default::default(),
+            stats: Stats::new(tmp.path()).unwrap(),
+        });
+        let request = |method: &str, range: Option<u64>, body: hyper::Body| {
+            let mut req = hyper::Request::builder()
+                .method(method)
+                .uri("/v1/namespaces/foo/restore?batch_size=2")
+                .header("content-type", "application/sql");
+            if let Some(offset) = range {
+                req = req.header("range", format!("bytes={offset}-"));
+            }
+            let req = req.body(body).unwrap();
+            let router = router.clone();
+            async move {
+                let resp = router.oneshot(req).await.unwrap();
+                let status = resp.status();
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, body)
+            }
+        };
+        let interrupted = |dump: &'static str| {
+            hyper::Body::wrap_stream(futures::stream::iter([
+                Ok(bytes::Bytes::from(dump)),
+                Err(std::io::Error::from(ErrorKind::ConnectionReset)),
+            ]))
+        };
+
+        let dump = "CREATE TABLE t (x);
+INSERT INTO t VALUES (1);
+INSERT INTO t VALUES (2);
+INSERT INTO t VALUES (3);
+";
+        let offset = dump.find("INSERT INTO t VALUES (2)").unwrap();
+        let (status, _) = request("POST", None, interrupted(&dump[..offset + 5])).await;
+        assert!(status.is_server_error());
+
+        // the load is resumed from the end of the first batch
+        let (status, body) = request("POST", None, dump.into()).await;
+        assert_eq!(status, hyper::StatusCode::CONFLICT);
+        assert_eq!(body["offset"], offset);
+        let (status, body) = request("POST", Some(offset as u64 - 1), "".into()).await;
+        assert_eq!(status, hyper::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(body["offset"], offset);
+        let (status, body) = request("POST", Some(offset as u64), dump[offset..].into()).await;
+        assert_eq!(status, hyper::StatusCode::OK, "{body}");
+        assert_eq!(body["statements"], 4);
+        assert_eq!(body["bytes"], dump.len());
+        let calls = execute(&namespaces, "SELECT x FROM t").await.unwrap();
+        assert!((1..=3).all(|x| has_row_value(&calls, x)));
+
+        let (status, _) = request("DELETE", None, hyper::Body::empty()).await;
+        assert_eq!(status, hyper::StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // an interrupted load can be abandoned, keeping the committed statements
+        let dump = "CREATE TABLE u (x);\nINSERT INTO u VALUES (1);\nINSERT INTO u VALUES (2);\n";
+        let req =
+            hyper::Request::post("/v1/namespaces/foo/restore?batch_size=2&allow_non_empty=true")
+                .header("content-type", "application/sql")
+                .body(interrupted(&dump[..dump.len() - 10]))
+                .unwrap();
+        let resp = router.clone().oneshot(req).await.unwrap();
+        assert!(resp.status().is_server_error());
+        let (status, body) = request("DELETE", None, hyper::Body::empty()).await;
+        assert_eq!(status, hyper::StatusCode::OK);
+        assert_eq!(body["statements"], 2);
+        let calls = execute(&namespaces, "SELECT count(*) FROM u")
+            .await
+            .unwrap();
+        assert!(has_row_value(&calls, 1));
+        let (status, _) = request("POST", None, dump.into()).await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn schema_events_are_sent_over_websocket() {
+        let (server, client) = tokio::io::duplex(4096);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        let (sender, events) = broadcast::channel(16);
+        let task = tokio::spawn(send_schema_events(server, events));
+
+        sender
+            .send(SchemaEvent::SchemaChange {
+                schema_version: 3,
+                statement: "CREATE TABLE t (x)".into(),
+            })
+            .unwrap();
+        let msg = client.next().await.unwrap().unwrap();
+        let event: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "event": "schema_change",
+                "schema_version": 3,
+                "statement": "CREATE TABLE t (x)",
+            })
+        );
+
+        // the events stop once the client closes the WebSocket
+        client.close(None).await.unwrap();
+        task.await.unwrap().unwrap();
+    }
 }
@@ -1,5 +1,12 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
 use anyhow::{bail, Context as _, Result};
 use axum::http::HeaderValue;
+use chrono::{DateTime, Utc};
 use tonic::Status;
 
 static GRPC_AUTH_HEADER: &str = "x-authorization";
@@ -14,6 +21,157 @@ pub struct Auth {
     pub http_basic: Option<String>,
     /// If `Some`, we accept all JWTs signed by this key.
     pub jwt_key: Option<jsonwebtoken::DecodingKey>,
+    /// JWTs that are rejected even if they are signed by `jwt_key`.
+    pub revoked_jwts: Arc<RevokedJwts>,
+    /// If `Some`, the outcome of every authentication attempt is recorded in this log.
+    pub audit_log: Option<AuditLog>,
+}
+
+/// IDs (`jti` claim) of the JWTs revoked before their expiry, persisted to a JSON file so that
+/// they stay revoked across restarts.
+#[derive(Default)]
+pub struct RevokedJwts {
+    path: Option<PathBuf>,
+    jtis: RwLock<HashSet<String>>,
+    /// Serializes the updates, so that the file is written in the order of the updates.
+    update_lock: tokio::sync::Mutex<()>,
+}
+
+impl RevokedJwts {
+    /// Loads the revoked JWTs from the file at `path`, if it exists.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let jtis = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Could not parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            jtis: RwLock::new(jtis),
+            update_lock: Default::default(),
+        })
+    }
+
+    pub fn contains(&self, jti: &str) -> bool {
+        self.jtis.read().unwrap().contains(jti)
+    }
+
+    /// Returns the revoked JWT IDs, sorted.
+    pub fn list(&self) -> Vec<String> {
+        let mut jtis: Vec<_> = self.jtis.read().unwrap().iter().cloned().collect();
+        jtis.sort_unstable();
+        jtis
+    }
+
+    /// Revokes the JWTs with ID `jti`. Returns false if they were already revoked.
+    pub async fn revoke(&self, jti: String) -> Result<bool> {
+        self.update(|jtis| jtis.insert(jti)).await
+    }
+
+    /// Lifts the revocation of the JWTs with ID `jti`. Returns false if they were not revoked.
+    pub async fn unrevoke(&self, jti: &str) -> Result<bool> {
+        self.update(|jtis| jtis.remove(jti)).await
+    }
+
+    async fn update(&self, f: impl FnOnce(&mut HashSet<String>) -> bool) -> Result<bool> {
+        let _guard = self.update_lock.lock().await;
+        let (changed, data) = {
+            let mut jtis = self.jtis.write().unwrap();
+            let changed = f(&mut jtis);
+            let mut sorted: Vec<_> = jtis.iter().collect();
+            sorted.sort_unstable();
+            (changed, serde_json::to_vec(&sorted)?)
+        };
+
+        if let (true, Some(path)) = (changed, &self.path) {
+            // write to a temporary file first, so that the file is never left half-written
+            let tmp_path = path.with_extension("json.tmp");
+            tokio::fs::write(&tmp_path, data)
+                .await
+                .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+            tokio::fs::rename(&tmp_path, path)
+                .await
+                .with_context(|| format!("Could not write {}", path.display()))?;
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Audit log of the authentication attempts, recorded as JSON lines.
+///
+/// Each record holds the `timestamp` of the attempt, the `api` through which the client
+/// authenticated, the `identity` of the client, whether the attempt was a `success`, and the
+/// `error` code of the failed attempts. The identity is `basic` for the HTTP basic credentials, the
+/// `sub` claim of a JWT (or `jwt` if it has none), and `anonymous` when no credentials were
+/// accepted. Nothing is recorded when authentication is disabled.
+pub enum AuditLog {
+    /// Appends the records to a file.
+    File(Mutex<File>),
+    /// Emits the records as events of the `sqld::auth_audit` tracing target.
+    Tracing,
+}
+
+/// API through which a client authenticated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthApi {
+    Http,
+    Hrana,
+    Grpc,
+}
+
+#[derive(serde::Serialize)]
+struct AuditRecord<'a> {
+    timestamp: DateTime<Utc>,
+    api: AuthApi,
+    identity: &'a str,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `dest`, which is either `tracing`, or the path of a file to which
+    /// the records are appended.
+    pub fn open(dest: &str) -> Result<Self> {
+        if dest == "tracing" {
+            return Ok(Self::Tracing);
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dest)
+            .with_context(|| format!("Could not open auth audit log {dest}"))?;
+        Ok(Self::File(Mutex::new(file)))
+    }
+
+    fn record(&self, api: AuthApi, outcome: &Result<(Authenticated, String), AuthError>) {
+        let record = AuditRecord {
+            timestamp: Utc::now(),
+            api,
+            identity: match outcome {
+                Ok((_, identity)) => identity,
+                Err(_) => "anonymous",
+            },
+            success: outcome.is_ok(),
+            error: outcome.as_ref().err().map(AuthError::code),
+        };
+        let line = serde_json::to_string(&record).expect("audit record is always serializable");
+
+        match self {
+            Self::File(file) => {
+                // a failure to record an attempt must not fail the attempt itself
+                if let Err(e) = writeln!(file.lock().unwrap(), "{line}") {
+                    tracing::error!("could not write to the auth audit log: {e}");
+                }
+            }
+            Self::Tracing => tracing::info!(target: "sqld::auth_audit", "{line}"),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -38,12 +196,14 @@ pub enum AuthError {
     JwtExpired,
     #[error("The JWT is immature (not valid yet)")]
     JwtImmature,
+    #[error("The JWT has been revoked")]
+    Revoked,
     #[error("Authentication failed")]
     Other,
 }
 
 #[non_exhaustive]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Authorized {
     FullAccess,
     ReadOnly,
@@ -51,7 +211,7 @@ pub enum Authorized {
 
 /// A witness that the user has been authenticated.
 #[non_exhaustive]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Authenticated {
     Anonymous,
     Authorized(Authorized),
@@ -66,6 +226,55 @@ impl Auth {
             return Ok(Authenticated::Authorized(Authorized::FullAccess));
         }
 
+        let outcome = self.check_http_auth_header(auth_header);
+        self.audit(AuthApi::Http, outcome)
+    }
+
+    pub fn authenticate_grpc<T>(&self, req: &tonic::Request<T>) -> Result<Authenticated, Status> {
+        if self.disabled {
+            return Ok(Authenticated::Authorized(Authorized::FullAccess));
+        }
+
+        let metadata = req.metadata();
+
+        let auth = metadata
+            .get(GRPC_AUTH_HEADER)
+            .map(|v| v.to_bytes().expect("Auth should always be ASCII"))
+            .map(|v| HeaderValue::from_maybe_shared(v).expect("Should already be valid header"));
+
+        let outcome = self.check_http_auth_header(auth.as_ref());
+        self.audit(AuthApi::Grpc, outcome).map_err(Into::into)
+    }
+
+    pub fn authenticate_jwt(&self, jwt: Option<&str>) -> Result<Authenticated, AuthError> {
+        if self.disabled {
+            return Ok(Authenticated::Authorized(Authorized::FullAccess));
+        }
+
+        let outcome = match jwt {
+            Some(jwt) => self.validate_jwt(jwt),
+            None => Err(AuthError::JwtMissing),
+        };
+        self.audit(AuthApi::Hrana, outcome)
+    }
+
+    /// Records the outcome of an authentication attempt in the audit log, if there is one.
+    fn audit(
+        &self,
+        api: AuthApi,
+        outcome: Result<(Authenticated, String), AuthError>,
+    ) -> Result<Authenticated, AuthError> {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(api, &outcome);
+        }
+        outcome.map(|(authenticated, _)| authenticated)
+    }
+
+    /// Checks the `Authorization` header, returning the identity of the client with the result.
+    fn check_http_auth_header(
+        &self,
+        auth_header: Option<&hyper::header::HeaderValue>,
+    ) -> Result<(Authenticated, String), AuthError> {
         let Some(auth_header) = auth_header else {
             return Err(AuthError::HttpAuthHeaderMissing)
         };
@@ -80,7 +289,10 @@ impl Auth {
                 let actual_value = actual_value.trim_end_matches('=');
                 let expected_value = expected_value.trim_end_matches('=');
                 if actual_value == expected_value {
-                    Ok(Authenticated::Authorized(Authorized::FullAccess))
+                    Ok((
+                        Authenticated::Authorized(Authorized::FullAccess),
+                        "basic".into(),
+                    ))
                 } else {
                     Err(AuthError::BasicRejected)
                 }
@@ -89,34 +301,11 @@ impl Auth {
         }
     }
 
-    pub fn authenticate_grpc<T>(&self, req: &tonic::Request<T>) -> Result<Authenticated, Status> {
-        let metadata = req.metadata();
-
-        let auth = metadata
-            .get(GRPC_AUTH_HEADER)
-            .map(|v| v.to_bytes().expect("Auth should always be ASCII"))
-            .map(|v| HeaderValue::from_maybe_shared(v).expect("Should already be valid header"));
-
-        self.authenticate_http(auth.as_ref()).map_err(Into::into)
-    }
-
-    pub fn authenticate_jwt(&self, jwt: Option<&str>) -> Result<Authenticated, AuthError> {
-        if self.disabled {
-            return Ok(Authenticated::Authorized(Authorized::FullAccess));
-        }
-
-        let Some(jwt) = jwt else {
-            return Err(AuthError::JwtMissing)
-        };
-
-        self.validate_jwt(jwt)
-    }
-
-    fn validate_jwt(&self, jwt: &str) -> Result<Authenticated, AuthError> {
+    fn validate_jwt(&self, jwt: &str) -> Result<(Authenticated, String), AuthError> {
         let Some(jwt_key) = self.jwt_key.as_ref() else {
             return Err(AuthError::JwtNotAllowed)
         };
-        validate_jwt(jwt_key, jwt)
+        validate_jwt(jwt_key, jwt, &self.revoked_jwts)
     }
 }
 
@@ -188,7 +377,8 @@ fn parse_http_auth_header(
 fn validate_jwt(
     jwt_key: &jsonwebtoken::DecodingKey,
     jwt: &str,
-) -> Result<Authenticated, AuthError> {
+    revoked_jwts: &RevokedJwts,
+) -> Result<(Authenticated, String), AuthError> {
     use jsonwebtoken::errors::ErrorKind;
 
     let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::EdDSA);
@@ -197,13 +387,20 @@ fn validate_jwt(
     match jsonwebtoken::decode::<serde_json::Value>(jwt, jwt_key, &validation).map(|t| t.claims) {
         Ok(serde_json::Value::Object(claims)) => {
             tracing::trace!("Claims: {claims:#?}");
-            Ok(match claims.get("a").and_then(|s| s.as_str()) {
+            if let Some(jti) = claims.get("jti").and_then(|s| s.as_str()) {
+                if revoked_jwts.contains(jti) {
+                    return Err(AuthError::Revoked);
+                }
+            }
+            let authenticated = match claims.get("a").and_then(|s| s.as_str()) {
                 Some("ro") => Authenticated::Authorized(Authorized::ReadOnly),
                 Some("rw") => Authenticated::Authorized(Authorized::FullAccess),
                 Some(_) => Authenticated::Anonymous,
                 // Backward compatibility - no access claim means full access
                 None => Authenticated::Authorized(Authorized::FullAccess),
-            })
+            };
+            let identity = claims.get("sub").and_then(|s| s.as_str()).unwrap_or("jwt");
+            Ok((authenticated, identity.into()))
         }
         Ok(_) => Err(AuthError::JwtInvalid),
         Err(error) => Err(match error.kind() {
@@ -263,6 +460,7 @@ impl AuthError {
             Self::JwtInvalid => "AUTH_JWT_INVALID",
             Self::JwtExpired => "AUTH_JWT_EXPIRED",
             Self::JwtImmature => "AUTH_JWT_IMMATURE",
+            Self::Revoked => "AUTH_JWT_REVOKED",
             Self::Other => "AUTH_FAILED",
         }
     }
@@ -369,4 +567,64 @@ mod tests {
         assert_ok!(auth.authenticate_jwt(Some(VALID_JWT)));
         assert_err!(auth.authenticate_jwt(Some(&VALID_JWT[..80])));
     }
+
+    #[test]
+    fn test_audit_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("audit.log");
+        let auth = Auth {
+            http_basic: parse_http_basic_auth_arg("basic:d29qdGVrOnRoZWJlYXI=").unwrap(),
+            jwt_key: Some(parse_jwt_key(VALID_JWT_KEY).unwrap()),
+            audit_log: Some(AuditLog::open(path.to_str().unwrap()).unwrap()),
+            ..Auth::default()
+        };
+        assert_ok!(authenticate_http(&auth, "Basic d29qdGVrOnRoZWJlYXI="));
+        assert_err!(authenticate_http(&auth, "Basic d29qdGVrOnRoZWZveA=="));
+        assert_ok!(auth.authenticate_jwt(Some(VALID_JWT)));
+        assert_err!(auth.authenticate_jwt(None));
+
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let outcomes: Vec<_> = records
+            .iter()
+            .map(|r| {
+                assert!(r["timestamp"].is_string());
+                (
+                    r["api"].as_str().unwrap(),
+                    r["identity"].as_str().unwrap(),
+                    r["success"].as_bool().unwrap(),
+                    r["error"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("http", "basic", true, None),
+                ("http", "anonymous", false, Some("AUTH_BASIC_REJECTED")),
+                ("hrana", "jwt", true, None),
+                ("hrana", "anonymous", false, Some("AUTH_JWT_MISSING")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoked_jwts_persisted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("revocations.json");
+        let revoked_jwts = RevokedJwts::load(path.clone()).unwrap();
+        assert!(revoked_jwts.revoke("b".into()).await.unwrap());
+        assert!(revoked_jwts.revoke("a".into()).await.unwrap());
+        assert!(!revoked_jwts.revoke("a".into()).await.unwrap());
+        assert!(revoked_jwts.unrevoke("b").await.unwrap());
+        assert!(!revoked_jwts.unrevoke("c").await.unwrap());
+
+        let revoked_jwts = RevokedJwts::load(path).unwrap();
+        assert_eq!(revoked_jwts.list(), ["a"]);
+        assert!(revoked_jwts.contains("a"));
+        assert!(!revoked_jwts.contains("b"));
+    }
 }
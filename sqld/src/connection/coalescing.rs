@@ -0,0 +1,580 @@
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
+use std::sync::Arc;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use tokio::sync::{broadcast, watch};
+
+use crate::auth::Authenticated;
+use crate::error::Error;
+use crate::query::{Params, Value};
+use crate::query_analysis::{State, StmtKind};
+use crate::query_result_builder::{
+    QueryBuilderConfig, QueryResultBuilder, RecordedCall, StepRecorder,
+};
+use crate::replication::FrameNo;
+use crate::Result;
+
+use super::dump::loader::{LoadDumpOptions, LoadDumpStats};
+use super::program::{Cond, DescribeResult, Program, Step};
+use super::schema::Schema;
+use super::{Connection, MakeConnection};
+
+/// Results of an in-flight program, sent to the programs coalesced with it. `None` tells them to
+/// execute the program on their own connection instead.
+type SharedResults = Option<Arc<Vec<RecordedCall>>>;
+
+type InFlight = DashMap<ProgramKey, broadcast::Sender<SharedResults>>;
+
+/// Identifies the programs which have the same results: the same steps, executed with the same
+/// authentication after the same commit. Programs are only coalesced when their keys are equal,
+/// not merely when their hashes collide.
+#[derive(Clone)]
+struct ProgramKey {
+    auth: Authenticated,
+    committed_frame_no: FrameNo,
+    steps: Arc<Vec<Step>>,
+}
+
+impl ProgramKey {
+    fn new(pgm: &Program, auth: Authenticated, committed_frame_no: FrameNo) -> Self {
+        Self {
+            auth,
+            committed_frame_no,
+            steps: pgm.steps.clone(),
+        }
+    }
+}
+
+impl Hash for ProgramKey {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        self.auth.hash(hasher);
+        self.committed_frame_no.hash(hasher);
+        self.steps.len().hash(hasher);
+        for step in self.steps.iter() {
+            step.query.stmt.stmt.hash(hasher);
+            step.query.want_rows.hash(hasher);
+            step.query.max_memory_bytes.hash(hasher);
+            step.query.max_rows.hash(hasher);
+            hash_params(&step.query.params, hasher);
+            match &step.cond {
+                Some(cond) => {
+                    1u8.hash(hasher);
+                    hash_cond(cond, hasher);
+                }
+                None => 0u8.hash(hasher),
+            }
+        }
+    }
+}
+
+impl PartialEq for ProgramKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.auth == other.auth
+            && self.committed_frame_no == other.committed_frame_no
+            && self.steps.len() == other.steps.len()
+            && self
+                .steps
+                .iter()
+                .zip(other.steps.iter())
+                .all(|(a, b)| steps_eq(a, b))
+    }
+}
+
+impl Eq for ProgramKey {}
+
+/// Wraps the connections of a database to coalesce the identical read programs executed
+/// concurrently: while a read program is in flight, the connections executing the same program
+/// with the same authentication wait for its results, instead of executing it again.
+///
+/// Programs are identified by a [ProgramKey], which includes the last frame committed to the
+/// database when they are executed, so that a program only shares the results of a program that
+/// started after the last commit it could have observed.
+pub struct CoalescingMakeConnection<F> {
+    inner: F,
+    builder_config: QueryBuilderConfig,
+    /// Last frame committed to the database, or `None` if programs are not coalesced.
+    committed_frame_no: Option<watch::Receiver<FrameNo>>,
+    in_flight: Arc<InFlight>,
+}
+
+impl<F> CoalescingMakeConnection<F> {
+    /// `builder_config` is the config that the connections of `inner` initialize the result
+    /// builders with, for the results replayed to the coalesced programs.
+    pub fn new(
+        inner: F,
+        builder_config: QueryBuilderConfig,
+        committed_frame_no: Option<watch::Receiver<FrameNo>>,
+    ) -> Self {
+        Self {
+            inner,
+            builder_config,
+            committed_frame_no,
+            in_flight: Default::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: MakeConnection> MakeConnection for CoalescingMakeConnection<F> {
+    type Connection = CoalescingConnection<F::Connection>;
+
+    async fn create(&self) -> Result<Self::Connection, Error> {
+        Ok(CoalescingConnection {
+            inner: self.inner.create().await?,
+            builder_config: self.builder_config,
+            committed_frame_no: self.committed_frame_no.clone(),
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+pub struct CoalescingConnection<C> {
+    inner: C,
+    builder_config: QueryBuilderConfig,
+    committed_frame_no: Option<watch::Receiver<FrameNo>>,
+    in_flight: Arc<InFlight>,
+}
+
+/// Removes a program from the in-flight programs once it is done, or if its execution is
+/// cancelled.
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    key: ProgramKey,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.remove(&self.key);
+    }
+}
+
+impl<C: Connection> CoalescingConnection<C> {
+    /// Replays the recorded results of a program in `builder`, from a blocking context, like the
+    /// results of a program executed by a connection.
+    async fn replay<B: QueryResultBuilder>(
+        &self,
+        mut builder: B,
+        calls: Vec<RecordedCall>,
+    ) -> Result<B> {
+        let config = self.builder_config;
+        tokio::task::spawn_blocking(move || {
+            builder.init(&config)?;
+            StepRecorder::replay(calls, &mut builder)?;
+            builder.finish()?;
+            Ok(builder)
+        })
+        .await
+        .map_err(|e| Error::Internal(format!("failed to replay coalesced results: {e}")))?
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: Connection> Connection for CoalescingConnection<C> {
+    async fn execute_program<B: QueryResultBuilder>(
+        &self,
+        pgm: Program,
+        auth: Authenticated,
+        builder: B,
+    ) -> Result<(B, State)> {
+        let Some(committed_frame_no) = self.committed_frame_no.as_ref() else {
+            return self.inner.execute_program(pgm, auth, builder).await;
+        };
+        // a transaction reads from its own snapshot of the database
+        if !is_coalescable(&pgm) || !self.inner.is_autocommit().await? {
+            return self.inner.execute_program(pgm, auth, builder).await;
+        }
+
+        let key = ProgramKey::new(&pgm, auth, *committed_frame_no.borrow());
+        let sender = match self.in_flight.entry(key.clone()) {
+            Entry::Occupied(e) => {
+                let mut receiver = e.get().subscribe();
+                drop(e);
+                if let Ok(Some(calls)) = receiver.recv().await {
+                    let calls = calls.iter().filter_map(RecordedCall::try_clone).collect();
+                    let builder = self.replay(builder, calls).await?;
+                    return Ok((builder, State::Init));
+                }
+                // the in-flight program failed or was cancelled
+                return self.inner.execute_program(pgm, auth, builder).await;
+            }
+            Entry::Vacant(e) => {
+                let (sender, _) = broadcast::channel(1);
+                e.insert(sender.clone());
+                sender
+            }
+        };
+
+        let guard = InFlightGuard {
+            in_flight: &self.in_flight,
+            key,
+        };
+        let res = self
+            .inner
+            .execute_program(pgm, auth, StepRecorder::default())
+            .await;
+        drop(guard);
+
+        let (calls, state) = match res {
+            Ok((recorder, state)) => (recorder.into_ret(), state),
+            Err(e) => {
+                let _ = sender.send(None);
+                return Err(e);
+            }
+        };
+
+        // step errors can't be cloned, so the coalesced programs execute the program themselves
+        if calls
+            .iter()
+            .any(|call| matches!(call, RecordedCall::StepError(_)))
+        {
+            let _ = sender.send(None);
+            let builder = self.replay(builder, calls).await?;
+            return Ok((builder, state));
+        }
+
+        let calls = Arc::new(calls);
+        let _ = sender.send(Some(calls.clone()));
+        let calls = Arc::try_unwrap(calls)
+            .unwrap_or_else(|calls| calls.iter().filter_map(RecordedCall::try_clone).collect());
+        let builder = self.replay(builder, calls).await?;
+
+        Ok((builder, state))
+    }
+
+    async fn describe(
+        &self,
+        sql: String,
+        auth: Authenticated,
+        query_plan: bool,
+    ) -> Result<DescribeResult> {
+        self.inner.describe(sql, auth, query_plan).await
+    }
+
+    async fn is_autocommit(&self) -> Result<bool> {
+        self.inner.is_autocommit().await
+    }
+
+    async fn checkpoint(&self) -> Result<()> {
+        self.inner.checkpoint().await
+    }
+
+    async fn vacuum(&self, auth: Authenticated) -> Result<()> {
+        self.inner.vacuum(auth).await
+    }
+
+    async fn integrity_check(&self) -> Result<Vec<String>> {
+        self.inner.integrity_check().await
+    }
+
+    async fn schema(&self, auth: Authenticated) -> Result<Schema> {
+        self.inner.schema(auth).await
+    }
+
+    async fn load_dump(
+        &self,
+        dump: Box<dyn BufRead + Send>,
+        options: LoadDumpOptions,
+        auth: Authenticated,
+    ) -> Result<LoadDumpStats> {
+        self.inner.load_dump(dump, options, auth).await
+    }
+}
+
+/// Fragments of the SQL of the statements whose results depend on the time of their execution
+/// (`CURRENT_TIMESTAMP`, `date('now')`), on chance (`random()`), or on the connection executing
+/// them (`last_insert_rowid()`, `changes()`). Matching the text of the statements errs on the side
+/// of not coalescing.
+const VOLATILE_SQL: &[&str] = &["random", "current_", "now", "last_insert_rowid", "changes"];
+
+/// Only the programs made of reads can be coalesced: they leave the connection in autocommit
+/// mode, and have the same results on any connection, unless they call a volatile function.
+fn is_coalescable(pgm: &Program) -> bool {
+    !pgm.dry_run
+        && pgm.steps.iter().all(|step| {
+            step.query.stmt.kind == StmtKind::Read
+                && !is_volatile(&step.query.stmt.stmt)
+                && !has_volatile_param(&step.query.params)
+        })
+}
+
+fn is_volatile(sql: &str) -> bool {
+    let sql = sql.to_ascii_lowercase();
+    VOLATILE_SQL.iter().any(|fragment| sql.contains(fragment))
+}
+
+/// The date and time functions read the current time from a `'now'` parameter too.
+fn has_volatile_param(params: &Params) -> bool {
+    let is_now = |value: &Value| matches!(value, Value::Text(s) if is_volatile(s));
+    match params {
+        Params::Positional(values) => values.iter().any(is_now),
+        Params::Named(values) => values.values().any(is_now),
+    }
+}
+
+fn steps_eq(a: &Step, b: &Step) -> bool {
+    a.query.stmt.stmt == b.query.stmt.stmt
+        && a.query.want_rows == b.query.want_rows
+        && a.query.max_memory_bytes == b.query.max_memory_bytes
+        && a.query.max_rows == b.query.max_rows
+        && params_eq(&a.query.params, &b.query.params)
+        && match (&a.cond, &b.cond) {
+            (Some(a), Some(b)) => cond_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+}
+
+fn params_eq(a: &Params, b: &Params) -> bool {
+    match (a, b) {
+        (Params::Positional(a), Params::Positional(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| value_eq(a, b))
+        }
+        (Params::Named(a), Params::Named(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(name, a)| b.get(name).map_or(false, |b| value_eq(a, b)))
+        }
+        _ => false,
+    }
+}
+
+/// Reals are compared by their bits, like they are hashed.
+fn value_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Null, Value::Null) => true,
+        (Value::Integer(a), Value::Integer(b)) => a == b,
+        (Value::Real(a), Value::Real(b)) => a.to_bits() == b.to_bits(),
+        (Value::Text(a), Value::Text(b)) => a == b,
+        (Value::Blob(a), Value::Blob(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn cond_eq(a: &Cond, b: &Cond) -> bool {
+    match (a, b) {
+        (Cond::Ok { step: a }, Cond::Ok { step: b })
+        | (Cond::Err { step: a }, Cond::Err { step: b }) => a == b,
+        (Cond::Not { cond: a }, Cond::Not { cond: b }) => cond_eq(a, b),
+        (Cond::Or { conds: a }, Cond::Or { conds: b })
+        | (Cond::And { conds: a }, Cond::And { conds: b }) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| cond_eq(a, b))
+        }
+        (Cond::IsAutocommit, Cond::IsAutocommit) => true,
+        _ => false,
+    }
+}
+
+fn hash_params(params: &Params, hasher: &mut impl Hasher) {
+    match params {
+        Params::Positional(values) => {
+            0u8.hash(hasher);
+            values.len().hash(hasher);
+            for value in values {
+                hash_value(value, hasher);
+            }
+        }
+        Params::Named(values) => {
+            1u8.hash(hasher);
+            values.len().hash(hasher);
+            let mut values: Vec<_> = values.iter().collect();
+            values.sort_unstable_by_key(|(name, _)| *name);
+            for (name, value) in values {
+                name.hash(hasher);
+                hash_value(value, hasher);
+            }
+        }
+    }
+}
+
+fn hash_value(value: &Value, hasher: &mut impl Hasher) {
+    std::mem::discriminant(value).hash(hasher);
+    match value {
+        Value::Null => (),
+        Value::Integer(i) => i.hash(hasher),
+        Value::Real(x) => x.to_bits().hash(hasher),
+        Value::Text(s) => s.hash(hasher),
+        Value::Blob(b) => b.hash(hasher),
+    }
+}
+
+fn hash_cond(cond: &Cond, hasher: &mut impl Hasher) {
+    std::mem::discriminant(cond).hash(hasher);
+    match cond {
+        Cond::Ok { step } | Cond::Err { step } => step.hash(hasher),
+        Cond::Not { cond } => hash_cond(cond, hasher),
+        Cond::Or { conds } | Cond::And { conds } => {
+            conds.len().hash(hasher);
+            for cond in conds {
+                hash_cond(cond, hasher);
+            }
+        }
+        Cond::IsAutocommit => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::auth::Authorized;
+
+    use super::*;
+
+    /// A connection answering every program with a single row, after a delay.
+    struct SlowDb {
+        executed: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Connection for SlowDb {
+        async fn execute_program<B: QueryResultBuilder>(
+            &self,
+            _pgm: Program,
+            _auth: Authenticated,
+            mut builder: B,
+        ) -> Result<(B, State)> {
+            self.executed.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            builder.init(&QueryBuilderConfig::default())?;
+            builder.begin_step()?;
+            builder.cols_description([("x", None)])?;
+            builder.begin_rows()?;
+            builder.begin_row()?;
+            builder.add_row_value(rusqlite::types::ValueRef::Integer(42))?;
+            builder.finish_row()?;
+            builder.finish_rows()?;
+            builder.finish_step(0, None)?;
+            builder.finish()?;
+            Ok((builder, State::Init))
+        }
+
+        async fn describe(
+            &self,
+            _sql: String,
+            _auth: Authenticated,
+            _query_plan: bool,
+        ) -> Result<DescribeResult> {
+            unreachable!()
+        }
+
+        async fn is_autocommit(&self) -> Result<bool> {
+            Ok(true)
+        }
+
+        async fn checkpoint(&self) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn vacuum(&self, _auth: Authenticated) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn integrity_check(&self) -> Result<Vec<String>> {
+            unreachable!()
+        }
+
+        async fn schema(&self, _auth: Authenticated) -> Result<Schema> {
+            unreachable!()
+        }
+
+        async fn load_dump(
+            &self,
+            _dump: Box<dyn BufRead + Send>,
+            _options: LoadDumpOptions,
+            _auth: Authenticated,
+        ) -> Result<LoadDumpStats> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce_concurrent_reads() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let (_frame_no_sender, frame_no) = watch::channel(0);
+        let factory = CoalescingMakeConnection::new(
+            {
+                let executed = executed.clone();
+                move || {
+                    let executed = executed.clone();
+                    async move { Ok(SlowDb { executed }) }
+                }
+            },
+            QueryBuilderConfig::default(),
+            Some(frame_no),
+        );
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        let execute = |sql: &'static str| {
+            let factory = &factory;
+            async move {
+                let conn = factory.create().await.unwrap();
+                let (rows, _) = conn
+                    .execute_program(Program::seq(&[sql]), auth, StepRecorder::default())
+                    .await
+                    .unwrap();
+                rows.into_ret()
+            }
+        };
+
+        let results = futures::future::join_all([
+            execute("SELECT x FROM t"),
+            execute("SELECT x FROM t"),
+            execute("SELECT x FROM t"),
+        ])
+        .await;
+        assert_eq!(executed.load(Ordering::SeqCst), 1);
+        for calls in results {
+            assert!(matches!(
+                calls.as_slice(),
+                [
+                    RecordedCall::BeginStep,
+                    RecordedCall::ColsDescription(_),
+                    RecordedCall::BeginRows,
+                    RecordedCall::BeginRow,
+                    RecordedCall::AddRowValue(rusqlite::types::Value::Integer(42)),
+                    RecordedCall::FinishRow,
+                    RecordedCall::FinishRows,
+                    RecordedCall::FinishStep { .. },
+                ]
+            ));
+        }
+
+        futures::future::join(execute("SELECT x FROM t"), execute("SELECT y FROM t")).await;
+        assert_eq!(executed.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn program_key_includes_commits_and_auth() {
+        let pgm = Program::seq(&["SELECT x FROM t"]);
+        let full_access = Authenticated::Authorized(Authorized::FullAccess);
+        let key = ProgramKey::new(&pgm, full_access, 0);
+        assert!(key == ProgramKey::new(&pgm, full_access, 0));
+        assert!(key != ProgramKey::new(&pgm, full_access, 1));
+        assert!(key != ProgramKey::new(&pgm, Authenticated::Anonymous, 0));
+        assert!(key != ProgramKey::new(&Program::seq(&["SELECT y FROM t"]), full_access, 0));
+    }
+
+    #[test]
+    fn volatile_programs_are_not_coalesced() {
+        assert!(is_coalescable(&Program::seq(&["SELECT x FROM t"])));
+        for sql in [
+            "SELECT random()",
+            "SELECT randomblob(16)",
+            "SELECT CURRENT_TIMESTAMP",
+            "SELECT datetime('now')",
+            "SELECT last_insert_rowid()",
+            "SELECT changes()",
+            "SELECT total_changes()",
+        ] {
+            assert!(!is_coalescable(&Program::seq(&[sql])), "{sql}");
+        }
+
+        let mut pgm = Program::seq(&["SELECT date(?)"]);
+        Arc::make_mut(&mut pgm.steps)[0].query.params =
+            Params::Positional(vec![Value::Text("now".into())]);
+        assert!(!is_coalescable(&pgm));
+    }
+}
@@ -1,16 +1,31 @@
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{fs, io};
 
 use crate::error::Error;
+use crate::replication::WAL_PAGE_SIZE;
 use crate::Result;
 
+#[derive(Debug)]
 pub struct DatabaseConfigStore {
     config_path: PathBuf,
     tmp_config_path: PathBuf,
     config: Mutex<Arc<DatabaseConfig>>,
+    /// Server-wide store, whose blocks apply on top of the blocks of a namespace.
+    parent: Option<Arc<DatabaseConfigStore>>,
+    defaults: DefaultLimits,
+}
+
+/// Limits of the server, which apply to the namespaces that don't set their own.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultLimits {
+    /// Size quota of the databases, in bytes.
+    pub max_db_size: Option<u64>,
+    /// Maximum number of rows returned by a statement.
+    pub max_rows: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -22,6 +37,66 @@ pub struct DatabaseConfig {
     /// The reason why operations are blocked. This will be included in [`Error::Blocked`].
     #[serde(default)]
     pub block_reason: Option<String>,
+    /// Size quota of the database, in bytes. Writes are rejected while the database exceeds it.
+    /// Defaults to the quota of the server when not set.
+    #[serde(default)]
+    pub max_db_size: Option<u64>,
+    /// Maximum number of rows returned by a statement executed on the namespace, except by
+    /// cursors. Defaults to the limit of the server when not set.
+    #[serde(default)]
+    pub max_rows: Option<u64>,
+    /// Origins allowed to make cross-origin HTTP requests to the namespace, or `*` to allow any
+    /// origin. Any origin is allowed when not set.
+    #[serde(default)]
+    pub cors_origins: Option<Vec<String>>,
+    /// Whether the database lives in memory only. Set when the namespace is created: its data is
+    /// lost when the namespace is unloaded, and is not backed up to bottomless.
+    #[serde(default)]
+    pub in_memory: bool,
+    /// Soft heap limit of the connections to the namespace, in MiB. Connections release the memory
+    /// they can spare while their namespace is over it.
+    #[serde(default)]
+    pub soft_heap_limit_mb: Option<usize>,
+    /// Hard heap limit of the connections to the namespace, in MiB. Statements fail with an
+    /// out-of-memory error rather than going over it.
+    #[serde(default)]
+    pub hard_heap_limit_mb: Option<usize>,
+    /// Whether the connections to the namespace may `ATTACH` and `DETACH` databases. Only the
+    /// namespaces of `attach_allowlist` can be attached.
+    #[serde(default)]
+    pub allow_attach: bool,
+    /// Namespaces whose database the connections to the namespace may `ATTACH`, read-only.
+    #[serde(default)]
+    pub attach_allowlist: Vec<String>,
+    /// Pragmas that the connections to the namespace may execute, on top of the pragmas that are
+    /// always denied. An entry `name` allows reading the pragma, and `name=` allows setting it
+    /// too. The read-only pragmas are allowed when not set.
+    #[serde(default)]
+    pub pragma_allowlist: Option<Vec<String>>,
+    /// Duration, in microseconds, over which a program executed on the namespace is logged as a
+    /// slow query. Slow queries are not logged when not set.
+    #[serde(default)]
+    pub slow_query_threshold_us: Option<u64>,
+    /// Headers added to every HTTP response of the namespace, such as `X-Frame-Options`. Only
+    /// security headers, `Cache-Control` and custom `X-` headers can be set.
+    #[serde(default)]
+    pub response_headers: HashMap<String, String>,
+    /// Primary to which the namespace was migrated. Writes to the namespace are redirected to it,
+    /// while reads are still served from the local copy until the namespace is deleted.
+    #[serde(default)]
+    pub migrated_to: Option<url::Url>,
+    /// Page size of the database, in bytes, applied when the database is created. Only the page
+    /// size of the replication log, [`WAL_PAGE_SIZE`], is supported.
+    #[serde(default)]
+    pub page_size: Option<u32>,
+}
+
+/// Checks that databases may use pages of `page_size` bytes.
+pub fn check_page_size(page_size: u32) -> Result<()> {
+    if page_size != WAL_PAGE_SIZE as u32 {
+        return Err(Error::UnsupportedPageSize(page_size));
+    }
+    Ok(())
 }
 
 impl DatabaseConfigStore {
@@ -39,15 +114,34 @@ impl DatabaseConfigStore {
             config_path,
             tmp_config_path,
             config: Mutex::new(Arc::new(config)),
+            parent: None,
+            defaults: DefaultLimits::default(),
         })
     }
 
+    /// Loads the config of a namespace stored in `db_path`, which is combined with the
+    /// server-wide config of `parent`, and defaults to its limits.
+    pub fn load_with_parent(db_path: &Path, parent: Arc<DatabaseConfigStore>) -> Result<Self> {
+        let mut this = Self::load(db_path)?;
+        this.defaults = parent.defaults;
+        this.parent = Some(parent);
+        Ok(this)
+    }
+
+    /// Sets the limits in effect when the config doesn't set its own.
+    pub fn with_defaults(mut self, defaults: DefaultLimits) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
     #[cfg(test)]
     pub fn new_test() -> Self {
         Self {
             config_path: "".into(),
             tmp_config_path: "".into(),
             config: Mutex::new(Arc::new(DatabaseConfig::default())),
+            parent: None,
+            defaults: DefaultLimits::default(),
         }
     }
 
@@ -55,6 +149,38 @@ impl DatabaseConfigStore {
         self.config.lock().clone()
     }
 
+    /// Returns the config in effect, where operations blocked by the parent store are blocked
+    /// too, and the limits that are not set are the default ones.
+    pub fn effective(&self) -> Arc<DatabaseConfig> {
+        let mut config = self.get();
+        let max_db_size = config.max_db_size.or(self.defaults.max_db_size);
+        let max_rows = config.max_rows.or(self.defaults.max_rows);
+        if max_db_size != config.max_db_size || max_rows != config.max_rows {
+            config = Arc::new(DatabaseConfig {
+                max_db_size,
+                max_rows,
+                ..(*config).clone()
+            });
+        }
+        let Some(parent) = self.parent.as_ref().map(|p| p.effective()) else {
+            return config;
+        };
+        if !parent.block_reads && !parent.block_writes {
+            return config;
+        }
+        let block_reason = if config.block_reads || config.block_writes {
+            config.block_reason.clone()
+        } else {
+            None
+        };
+        Arc::new(DatabaseConfig {
+            block_reads: config.block_reads || parent.block_reads,
+            block_writes: config.block_writes || parent.block_writes,
+            block_reason: block_reason.or_else(|| parent.block_reason.clone()),
+            ..(*config).clone()
+        })
+    }
+
     pub fn store(&self, config: DatabaseConfig) -> Result<()> {
         let data = serde_json::to_vec_pretty(&config)?;
         fs::write(&self.tmp_config_path, data)?;
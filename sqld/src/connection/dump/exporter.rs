@@ -7,9 +7,20 @@ use anyhow::bail;
 use rusqlite::types::ValueRef;
 use rusqlite::OptionalExtension;
 
+/// Restricts what [`export_dump`] exports.
+#[derive(Debug, Clone, Default)]
+pub struct DumpOptions {
+    /// Only export the schema, without the rows of the tables.
+    pub schema_only: bool,
+    /// Only export these tables, with their indexes and triggers. All the tables are exported if
+    /// empty.
+    pub tables: Vec<String>,
+}
+
 struct DumpState<W: Write> {
     /// true if db is in writable_schema mode
     writable_schema: bool,
+    schema_only: bool,
     writer: W,
 }
 
@@ -20,20 +31,26 @@ impl<W: Write> DumpState<W> {
         &mut self,
         txn: &rusqlite::Connection,
         stmt: &str,
+        params: impl rusqlite::Params,
     ) -> anyhow::Result<()> {
         let mut stmt = txn.prepare(stmt)?;
-        let mut rows = stmt.query(())?;
+        let mut rows = stmt.query(params)?;
         while let Some(row) = rows.next()? {
             let ValueRef::Text(table) = row.get_ref(0)? else { bail!("invalid schema table") };
             let ValueRef::Text(ty) = row.get_ref(1)? else { bail!("invalid schema table") };
             let ValueRef::Text(sql) = row.get_ref(2)? else { bail!("invalid schema table") };
 
+            // the internal tables only hold data
+            if self.schema_only && table.starts_with(b"sqlite_") {
+                continue;
+            }
+
             if table == b"sqlite_sequence" {
                 writeln!(self.writer, "DELETE FROM sqlite_sequence;")?;
             } else if table.starts_with(b"sqlite_stat") {
                 writeln!(self.writer, "ANALYZE sqlite_schema;")?;
             } else if table.starts_with(b"sqlite_") {
-                return Ok(());
+                continue;
             } else if sql.starts_with(b"CREATE VIRTUAL TABLE") {
                 if !self.writable_schema {
                     writeln!(self.writer, "PRAGMA writable_schema=ON;")?;
@@ -48,7 +65,7 @@ impl<W: Write> DumpState<W> {
                     table_str,
                     std::str::from_utf8(sql)?
                 )?;
-                return Ok(());
+                continue;
             } else {
                 if sql.starts_with(b"CREATE TABLE") {
                     self.writer.write_all(b"CREATE TABLE IF NOT EXISTS ")?;
@@ -59,7 +76,7 @@ impl<W: Write> DumpState<W> {
                 writeln!(self.writer, ";")?;
             }
 
-            if ty == b"table" {
+            if ty == b"table" && !self.schema_only {
                 let table_str = std::str::from_utf8(table)?;
                 let (row_id_col, colss) = self.list_table_columns(txn, table_str)?;
                 let mut insert = String::new();
@@ -115,10 +132,15 @@ impl<W: Write> DumpState<W> {
         Ok(())
     }
 
-    fn run_table_dump_query(&mut self, txn: &rusqlite::Connection, q: &str) -> anyhow::Result<()> {
+    fn run_table_dump_query(
+        &mut self,
+        txn: &rusqlite::Connection,
+        q: &str,
+        params: impl rusqlite::Params,
+    ) -> anyhow::Result<()> {
         let mut stmt = txn.prepare(q)?;
         let col_count = stmt.column_count();
-        let mut rows = stmt.query(())?;
+        let mut rows = stmt.query(params)?;
         while let Some(row) = rows.next()? {
             let ValueRef::Text(sql) = row.get_ref(0)? else { bail!("the first row in a table dump query should be of type text") };
             self.writer.write_all(sql)?;
@@ -420,14 +442,32 @@ fn find_unused_str(haystack: &str, needle1: &str, needle2: &str) -> String {
     }
 }
 
-pub fn export_dump(mut db: rusqlite::Connection, writer: impl Write) -> anyhow::Result<()> {
+/// Exports a SQL dump of the database.
+///
+/// The dump is read from a single transaction, so that it is a consistent point-in-time view of a
+/// database that is written to concurrently. In WAL mode, the snapshot of the transaction is taken
+/// by its first read, and it doesn't block writers, but checkpoints can't move the frames written
+/// after the snapshot to the database file until the dump is over: TRUNCATE checkpoints fail with
+/// `SQLITE_BUSY` in the meantime, and the WAL keeps growing.
+pub fn export_dump(
+    mut db: rusqlite::Connection,
+    writer: impl Write,
+    options: &DumpOptions,
+) -> anyhow::Result<()> {
     let mut txn = db.transaction()?;
     txn.execute("PRAGMA writable_schema=ON", ())?;
     let savepoint = txn.savepoint_with_name("dump")?;
     let mut state = DumpState {
         writable_schema: false,
+        schema_only: options.schema_only,
         writer,
     };
+    // the tables are passed as a JSON array, NULL selecting all the tables
+    let tables = if options.tables.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(&options.tables)?)
+    };
 
     writeln!(state.writer, "PRAGMA foreign_keys=OFF;")?;
     writeln!(state.writer, "BEGIN TRANSACTION;")?;
@@ -440,13 +480,15 @@ pub fn export_dump(mut db: rusqlite::Connection, writer: impl Write) -> anyhow::
     let q = "SELECT name, type, sql FROM sqlite_schema AS o 
 WHERE type=='table' 
 AND sql NOT NULL 
+AND (?1 IS NULL OR tbl_name IN (SELECT value FROM json_each(?1))) 
 ORDER BY tbl_name='sqlite_sequence', rowid";
-    state.run_schema_dump_query(&savepoint, q)?;
+    state.run_schema_dump_query(&savepoint, q, [&tables])?;
 
     let q = "SELECT sql FROM sqlite_schema AS o 
 WHERE sql NOT NULL 
-AND type IN ('index','trigger','view')";
-    state.run_table_dump_query(&savepoint, q)?;
+AND type IN ('index','trigger','view') 
+AND (?1 IS NULL OR tbl_name IN (SELECT value FROM json_each(?1)))";
+    state.run_table_dump_query(&savepoint, q, [&tables])?;
 
     if state.writable_schema {
         writeln!(state.writer, "PRAGMA writable_schema=OFF;")?;
@@ -487,4 +529,142 @@ mod test {
         assert_eq!("X'68656c6c6f0a'", Blob(b"hello\n").to_string());
         assert_eq!("X''", Blob(b"").to_string());
     }
+
+    #[test]
+    fn dump_is_consistent_with_concurrent_writes() {
+        /// Writer that slows down the dump, so that the writes happen while it is exported.
+        struct SlowWriter(Vec<u8>);
+
+        impl Write for SlowWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                std::thread::sleep(std::time::Duration::from_micros(50));
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        conn.execute_batch(
+            "CREATE TABLE a (x INTEGER);
+            CREATE TABLE b (x INTEGER);",
+        )
+        .unwrap();
+        for i in 0..500 {
+            conn.execute_batch(&format!(
+                "INSERT INTO a VALUES ({i}); INSERT INTO b VALUES ({i});"
+            ))
+            .unwrap();
+        }
+
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let inserts = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut i = 500;
+                while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                    conn.execute_batch(&format!(
+                        "BEGIN; INSERT INTO a VALUES ({i}); INSERT INTO b VALUES ({i}); COMMIT;"
+                    ))
+                    .unwrap();
+                    i += 1;
+                }
+                i
+            }
+        });
+
+        let mut writer = SlowWriter(Vec::new());
+        export_dump(
+            rusqlite::Connection::open(&path).unwrap(),
+            &mut writer,
+            &DumpOptions::default(),
+        )
+        .unwrap();
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        let inserted = inserts.join().unwrap();
+        assert!(inserted > 500);
+
+        let restored = rusqlite::Connection::open_in_memory().unwrap();
+        restored
+            .execute_batch(std::str::from_utf8(&writer.0).unwrap())
+            .unwrap();
+        let count = |table: &str| -> i64 {
+            restored
+                .query_row(&format!("SELECT count(*) FROM {table}"), (), |r| r.get(0))
+                .unwrap()
+        };
+        assert!(count("a") >= 500);
+        assert_eq!(count("a"), count("b"));
+        let missing: i64 = restored
+            .query_row(
+                "SELECT count(*) FROM a WHERE x NOT IN (SELECT x FROM b)",
+                (),
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(missing, 0);
+    }
+
+    #[test]
+    fn dump_filtered_tables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                r#"CREATE TABLE a (x INTEGER);
+                CREATE INDEX a_x ON a (x);
+                CREATE TABLE "b c" (x INTEGER PRIMARY KEY AUTOINCREMENT, y TEXT);
+                CREATE TRIGGER b_c_trigger AFTER INSERT ON "b c" BEGIN INSERT INTO a VALUES (NEW.x); END;
+                CREATE TABLE d (x INTEGER);
+                INSERT INTO "b c" (y) VALUES ('hello');
+                INSERT INTO d VALUES (42);"#,
+            )
+            .unwrap();
+        let dump = |options: DumpOptions| {
+            let mut out = Vec::new();
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            export_dump(conn, &mut out, &options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let full = dump(DumpOptions::default());
+        assert!(full.contains("CREATE TABLE IF NOT EXISTS d"));
+        assert!(full.contains("INSERT INTO d VALUES(42);"));
+        assert!(full.contains("DELETE FROM sqlite_sequence;"));
+
+        let filtered = dump(DumpOptions {
+            schema_only: false,
+            tables: vec!["b c".into(), "a".into()],
+        });
+        assert!(filtered.contains("CREATE INDEX a_x"));
+        assert!(filtered.contains("CREATE TRIGGER b_c_trigger"));
+        assert!(filtered.contains(r#"INSERT INTO "b c" VALUES(1,'hello');"#));
+        assert!(filtered.contains("INSERT INTO a VALUES(1);"));
+        assert!(!filtered.contains("CREATE TABLE IF NOT EXISTS d"));
+        assert!(!filtered.contains("42"));
+        assert!(!filtered.contains("sqlite_sequence"));
+
+        let schema = dump(DumpOptions {
+            schema_only: true,
+            tables: Vec::new(),
+        });
+        assert!(schema.contains("CREATE TABLE IF NOT EXISTS d"));
+        assert!(schema.contains("CREATE TRIGGER b_c_trigger"));
+        assert!(!schema.contains("VALUES("));
+        assert!(!schema.contains("sqlite_sequence"));
+
+        // the dumps can be loaded back
+        for dump in [full, filtered, schema] {
+            rusqlite::Connection::open_in_memory()
+                .unwrap()
+                .execute_batch(&dump)
+                .unwrap();
+        }
+    }
 }
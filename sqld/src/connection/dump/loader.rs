@@ -0,0 +1,508 @@
+//! Loads SQL dumps, such as the ones of [`super::exporter::export_dump`], into a database.
+use std::ffi::CString;
+use std::fmt::Display;
+use std::io::BufRead;
+
+use rusqlite::ffi::sqlite3_complete;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::connection::pragma::check_pragma;
+use crate::error::{Error, LoadDumpError};
+use crate::query_analysis::{Statement, StmtKind};
+
+/// Number of statements executed between two progress logs.
+const PROGRESS_LOG_INTERVAL: u64 = 10_000;
+
+/// Name of the table holding the progress of a load in batches which did not complete. It's
+/// written in the transaction of every batch, so that it always matches the committed statements,
+/// and dropped in the transaction of the last one.
+pub const LOAD_PROGRESS_TABLE: &str = "libsql_dump_load_progress";
+
+/// Options of the load of a dump.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadDumpOptions {
+    /// Load the dump even if the database already has tables.
+    pub allow_non_empty: bool,
+    /// If `Some`, the dump is loaded in transactions of this many statements, and the load can be
+    /// resumed after the last committed transaction if it is interrupted (see
+    /// [`load_dump_in_batches`]). Otherwise the dump is loaded in a single transaction.
+    pub batch_size: Option<u64>,
+    /// Offset in the dump at which the dump is read from, to resume an interrupted load in
+    /// batches.
+    pub resume_offset: Option<u64>,
+    /// Abandon the interrupted load in batches instead of loading a dump (see
+    /// [`abort_dump_load`]).
+    pub abort: bool,
+}
+
+/// Outcome of the load of a dump.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LoadDumpStats {
+    /// Number of bytes read from the dump.
+    pub bytes: u64,
+    /// Number of lines read from the dump.
+    pub lines: u64,
+    /// Number of statements executed.
+    pub statements: u64,
+    /// Number of rows inserted, updated or deleted by the statements.
+    pub rows_changed: u64,
+}
+
+/// Executes the statements of the dump read from `reader` in a single transaction, which is
+/// rolled back if any of them fails. The transaction statements of the dump are skipped.
+///
+/// The load is refused if the database already has tables, unless `allow_non_empty` is set.
+pub fn load_dump(
+    conn: &rusqlite::Connection,
+    reader: impl BufRead,
+    allow_non_empty: bool,
+) -> crate::Result<LoadDumpStats> {
+    if !conn.is_autocommit() {
+        return Err(Error::QueryError(
+            "cannot load a dump inside a transaction".into(),
+        ));
+    }
+
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    let res = check_empty(conn, allow_non_empty)
+        .and_then(|()| execute_dump(conn, reader, LoadDumpStats::default(), |_| Ok(())))
+        .and_then(|stats| {
+            conn.execute_batch("COMMIT")?;
+            Ok(stats)
+        });
+    if res.is_err() && !conn.is_autocommit() {
+        conn.execute_batch("ROLLBACK")?;
+    }
+
+    res
+}
+
+/// Executes the statements of the dump read from `reader` in transactions of `batch_size`
+/// statements. The transaction statements of the dump are skipped.
+///
+/// Each transaction saves the progress of the load in the [`LOAD_PROGRESS_TABLE`] table before it
+/// commits. If the load is interrupted, by a failing statement or by an error reading the dump,
+/// only the last transaction is rolled back, and the load is resumed by passing the offset of the
+/// progress as `resume_offset`, with `reader` reading the dump from that offset. The table is
+/// dropped by the transaction loading the end of the dump.
+///
+/// The load is refused if the database already has tables, unless `allow_non_empty` is set, and
+/// a new load is refused while the progress of a previous one is saved.
+pub fn load_dump_in_batches(
+    conn: &rusqlite::Connection,
+    reader: impl BufRead,
+    allow_non_empty: bool,
+    batch_size: u64,
+    resume_offset: Option<u64>,
+) -> crate::Result<LoadDumpStats> {
+    if !conn.is_autocommit() {
+        return Err(Error::QueryError(
+            "cannot load a dump inside a transaction".into(),
+        ));
+    }
+
+    // the progress is read in the first transaction, so that two loads can't both start
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    let res = read_progress(conn)
+        .and_then(|progress| match (resume_offset, progress) {
+            (None, None) => {
+                check_empty(conn, allow_non_empty)?;
+                Ok(LoadDumpStats::default())
+            }
+            (None, Some(progress)) => Err(LoadDumpError::LoadInProgress(progress.bytes).into()),
+            (Some(_), None) => Err(LoadDumpError::NoLoadInProgress.into()),
+            (Some(offset), Some(progress)) if offset != progress.bytes => {
+                Err(LoadDumpError::InvalidResumeOffset(progress.bytes).into())
+            }
+            (Some(_), Some(progress)) => Ok(progress),
+        })
+        .and_then(|mut committed| {
+            execute_dump(conn, reader, committed, |stats| {
+                if stats.statements - committed.statements >= batch_size.max(1) {
+                    write_progress(conn, stats)?;
+                    conn.execute_batch("COMMIT")?;
+                    committed = *stats;
+                    conn.execute_batch("BEGIN IMMEDIATE")?;
+                }
+                Ok(())
+            })
+        })
+        .and_then(|stats| {
+            conn.execute_batch(&format!("DROP TABLE IF EXISTS {LOAD_PROGRESS_TABLE}"))?;
+            conn.execute_batch("COMMIT")?;
+            Ok(stats)
+        });
+    if res.is_err() && !conn.is_autocommit() {
+        conn.execute_batch("ROLLBACK")?;
+    }
+
+    res
+}
+
+/// Abandons the interrupted load in batches, and returns its progress. The statements it committed
+/// are kept, but the load can't be resumed anymore.
+pub fn abort_dump_load(conn: &rusqlite::Connection) -> crate::Result<LoadDumpStats> {
+    if !conn.is_autocommit() {
+        return Err(Error::QueryError(
+            "cannot abort the load of a dump inside a transaction".into(),
+        ));
+    }
+
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    let res = read_progress(conn).and_then(|progress| {
+        let progress = progress.ok_or(LoadDumpError::NoLoadInProgress)?;
+        conn.execute_batch(&format!("DROP TABLE {LOAD_PROGRESS_TABLE}"))?;
+        conn.execute_batch("COMMIT")?;
+        Ok(progress)
+    });
+    if res.is_err() && !conn.is_autocommit() {
+        conn.execute_batch("ROLLBACK")?;
+    }
+
+    res
+}
+
+fn check_empty(conn: &rusqlite::Connection, allow_non_empty: bool) -> crate::Result<()> {
+    if !allow_non_empty {
+        let tables: u64 = conn.query_row(
+            "SELECT count(*) FROM sqlite_schema
+            WHERE name NOT LIKE 'sqlite_%' AND name != 'libsql_wasm_func_table' AND name != ?",
+            [LOAD_PROGRESS_TABLE],
+            |row| row.get(0),
+        )?;
+        if tables > 0 {
+            return Err(LoadDumpError::NonEmptyDb.into());
+        }
+    }
+
+    Ok(())
+}
+
+fn read_progress(conn: &rusqlite::Connection) -> crate::Result<Option<LoadDumpStats>> {
+    let exists: bool = conn.query_row(
+        "SELECT count(*) > 0 FROM sqlite_schema WHERE type = 'table' AND name = ?",
+        [LOAD_PROGRESS_TABLE],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let progress = conn
+        .query_row(
+            &format!("SELECT bytes, lines, statements, rows_changed FROM {LOAD_PROGRESS_TABLE}"),
+            (),
+            |row| {
+                Ok(LoadDumpStats {
+                    bytes: row.get(0)?,
+                    lines: row.get(1)?,
+                    statements: row.get(2)?,
+                    rows_changed: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(progress)
+}
+
+fn write_progress(conn: &rusqlite::Connection, stats: &LoadDumpStats) -> crate::Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {LOAD_PROGRESS_TABLE} (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            bytes INTEGER NOT NULL,
+            lines INTEGER NOT NULL,
+            statements INTEGER NOT NULL,
+            rows_changed INTEGER NOT NULL
+        )"
+    ))?;
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO {LOAD_PROGRESS_TABLE} VALUES (0, ?, ?, ?, ?)"),
+        (
+            stats.bytes,
+            stats.lines,
+            stats.statements,
+            stats.rows_changed,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Executes the statements of the dump, continuing from `stats`. `after_statements` is called
+/// after each group of complete statements, which is where a transaction may be committed.
+fn execute_dump(
+    conn: &rusqlite::Connection,
+    mut reader: impl BufRead,
+    mut stats: LoadDumpStats,
+    mut after_statements: impl FnMut(&LoadDumpStats) -> crate::Result<()>,
+) -> crate::Result<LoadDumpStats> {
+    let mut line = String::new();
+    let mut sql = String::new();
+    // line on which the statements in `sql` start
+    let mut sql_line = 0;
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+        stats.bytes += n as u64;
+        stats.lines += 1;
+
+        if sql.is_empty() {
+            let frag = line.trim_start();
+            if frag.is_empty() || frag.starts_with("--") {
+                continue;
+            }
+            sql_line = stats.lines;
+        }
+        sql.push_str(&line);
+
+        // a statement ends with a semicolon, but a semicolon may also be part of a statement, in
+        // the body of a trigger for example
+        if line.trim_end().ends_with(';') && is_complete(&sql) {
+            execute_statements(conn, &sql, sql_line, &mut stats)?;
+            sql.clear();
+            after_statements(&stats)?;
+        }
+    }
+
+    if !sql.trim().is_empty() {
+        return Err(statement_error(
+            sql_line,
+            "incomplete statement at the end of the dump",
+        ));
+    }
+
+    Ok(stats)
+}
+
+fn execute_statements(
+    conn: &rusqlite::Connection,
+    sql: &str,
+    line: u64,
+    stats: &mut LoadDumpStats,
+) -> crate::Result<()> {
+    for stmt in Statement::parse(sql) {
+        let stmt = stmt.map_err(|e| statement_error(line, e))?;
+        // the dump is already executed in a transaction
+        if matches!(stmt.kind, StmtKind::TxnBegin | StmtKind::TxnEnd) {
+            continue;
+        }
+        if matches!(stmt.kind, StmtKind::Attach | StmtKind::Detach) {
+            return Err(statement_error(
+                line,
+                "databases can't be attached while loading a dump",
+            ));
+        }
+        // dumps only need the pragmas allowed by default, such as `foreign_keys`
+        if let Some(pragma) = &stmt.pragma {
+            check_pragma(pragma, None).map_err(|e| statement_error(line, e))?;
+        }
+
+        let changed = conn.execute(&stmt.stmt, ()).map_err(|e| match e {
+            // the statement is valid, but the database reached its quota: the caller reports it
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::DiskFull,
+                    ..
+                },
+                _,
+            ) => e.into(),
+            e => statement_error(line, e),
+        })?;
+        if stmt.is_iud {
+            stats.rows_changed += changed as u64;
+        }
+
+        stats.statements += 1;
+        if stats.statements % PROGRESS_LOG_INTERVAL == 0 {
+            tracing::debug!(
+                "loaded {} statements of the dump, up to line {line}",
+                stats.statements
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn is_complete(sql: &str) -> bool {
+    // a nul byte can't be part of a statement, the error is reported when it is executed
+    let Ok(sql) = CString::new(sql) else {
+        return true;
+    };
+    unsafe { sqlite3_complete(sql.as_ptr()) != 0 }
+}
+
+fn statement_error(line: u64, error: impl Display) -> Error {
+    LoadDumpError::InvalidStatement {
+        line,
+        error: error.to_string(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tables(conn: &rusqlite::Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_schema WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        stmt.query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn load_dump_in_transaction() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let dump = "PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+-- a comment
+CREATE TABLE t (x TEXT);
+INSERT INTO t VALUES ('a;b'), ('c');
+CREATE TRIGGER tr AFTER INSERT ON t BEGIN
+    INSERT INTO t2 VALUES (new.x);
+END;
+CREATE TABLE t2 (x TEXT); INSERT INTO t VALUES ('d');
+COMMIT;
+";
+        let stats = load_dump(&conn, dump.as_bytes(), false).unwrap();
+        assert_eq!(stats.lines, 10);
+        assert_eq!(stats.statements, 6);
+        assert_eq!(stats.rows_changed, 3);
+        assert_eq!(tables(&conn), ["t", "t2"]);
+        let rows: u64 = conn
+            .query_row("SELECT count(*) FROM t2", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+
+        // the database is not empty anymore
+        let err = load_dump(&conn, "CREATE TABLE t3 (x);".as_bytes(), false).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::NonEmptyDb)
+        ));
+        load_dump(&conn, "CREATE TABLE t3 (x);".as_bytes(), true).unwrap();
+        assert_eq!(tables(&conn), ["t", "t2", "t3"]);
+    }
+
+    struct Interrupted;
+
+    impl std::io::Read for Interrupted {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    #[test]
+    fn load_dump_in_batches_is_resumable() {
+        use std::io::Read as _;
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let dump = "BEGIN TRANSACTION;
+CREATE TABLE t (x);
+INSERT INTO t VALUES (1);
+INSERT INTO t VALUES (2);
+INSERT INTO t VALUES (3);
+INSERT INTO t VALUES (4);
+COMMIT;
+";
+        let rows = |conn: &rusqlite::Connection| -> u64 {
+            conn.query_row("SELECT count(*) FROM t", (), |row| row.get(0))
+                .unwrap()
+        };
+        let load = |reader: &mut dyn BufRead, resume_offset| {
+            load_dump_in_batches(&conn, reader, false, 2, resume_offset)
+        };
+
+        // the load is interrupted in the middle of the sixth line
+        let cut = dump.find("(4)").unwrap();
+        let mut reader = std::io::BufReader::new(dump[..cut].as_bytes().chain(Interrupted));
+        assert!(load(&mut reader, None).is_err());
+        assert!(conn.is_autocommit());
+        assert_eq!(rows(&conn), 3);
+        let offset = dump.find("INSERT INTO t VALUES (4)").unwrap() as u64;
+        // the progress was committed with the statements
+        assert_eq!(read_progress(&conn).unwrap().unwrap().bytes, offset);
+
+        // the load has to be resumed from the end of the last transaction
+        let err = load(&mut "".as_bytes(), None).unwrap_err();
+        assert!(
+            matches!(err, Error::LoadDumpError(LoadDumpError::LoadInProgress(o)) if o == offset)
+        );
+        let err = load(&mut "".as_bytes(), Some(offset - 1)).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::InvalidResumeOffset(o)) if o == offset
+        ));
+
+        let stats = load(&mut dump[offset as usize..].as_bytes(), Some(offset)).unwrap();
+        assert_eq!(stats.bytes, dump.len() as u64);
+        assert_eq!(stats.lines, 7);
+        assert_eq!(stats.statements, 5);
+        assert_eq!(stats.rows_changed, 4);
+        assert_eq!(rows(&conn), 4);
+        assert_eq!(tables(&conn), ["t"]);
+
+        let err = load(&mut "".as_bytes(), Some(offset)).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::NoLoadInProgress)
+        ));
+    }
+
+    #[test]
+    fn abort_load_dump_in_batches() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let dump = "CREATE TABLE t (x);\nINSERT INTO t VALUES (1);\nINSERT INTO u VALUES (2);\n";
+
+        // the first batch is committed, and the second one fails
+        assert!(load_dump_in_batches(&conn, dump.as_bytes(), false, 2, None).is_err());
+        let progress = abort_dump_load(&conn).unwrap();
+        assert_eq!(progress.lines, 2);
+        assert_eq!(progress.statements, 2);
+
+        let err = abort_dump_load(&conn).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::NoLoadInProgress)
+        ));
+        // the committed statements are kept
+        assert_eq!(tables(&conn), ["t"]);
+        let err = load_dump_in_batches(&conn, dump.as_bytes(), false, 2, None).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::NonEmptyDb)
+        ));
+    }
+
+    #[test]
+    fn malformed_dump_is_rolled_back() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let dump = "CREATE TABLE t (x);
+
+INSERT INTO t VALUES (1);
+INSERT INTO t
+VALUES (2, 3);
+";
+        let err = load_dump(&conn, dump.as_bytes(), false).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::InvalidStatement { line: 4, .. })
+        ));
+        assert!(conn.is_autocommit());
+        assert!(tables(&conn).is_empty());
+
+        let err = load_dump(&conn, "CREATE TABLE t (x);\nCREATE".as_bytes(), false).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LoadDumpError(LoadDumpError::InvalidStatement { line: 2, .. })
+        ));
+        assert!(tables(&conn).is_empty());
+    }
+}
@@ -1 +1,4 @@
 pub mod exporter;
+pub mod loader;
+pub mod s3;
+pub mod table;
@@ -0,0 +1,156 @@
+//! Export of SQL dumps straight to S3, without buffering the whole dump locally.
+use std::io::Write;
+
+use anyhow::{bail, Context as _};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use bottomless::storage::StorageBackend;
+use tokio::runtime::Handle;
+
+use super::exporter::{export_dump, DumpOptions};
+
+/// Size of the parts of the multipart upload. S3 requires every part but the last one to be at
+/// least 5MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Exports a SQL dump of the database to the object `key` of the bottomless bucket, with a
+/// multipart upload. At most one part of the dump is held in memory.
+///
+/// This blocks on the upload, so it must be called from a blocking thread of a tokio runtime,
+/// e.g. with `tokio::task::spawn_blocking`.
+pub fn export_dump_to_s3(
+    conn: rusqlite::Connection,
+    opts: &bottomless::replicator::Options,
+    key: &str,
+) -> anyhow::Result<()> {
+    if opts.backend != StorageBackend::S3 {
+        bail!("dumps can only be exported to S3, not {:?}", opts.backend);
+    }
+    let handle = Handle::current();
+    let client = Client::from_conf(handle.block_on(opts.client_config())?);
+    let mut writer = MultipartWriter::new(handle, client, opts.bucket_name.clone(), key)?;
+    export_dump(conn, &mut writer, &DumpOptions::default())?;
+    writer.finish()
+}
+
+/// Uploads what is written to it as an S3 object, in parts of [PART_SIZE] bytes. The upload is
+/// aborted if the writer is dropped before it is finished with [MultipartWriter::finish].
+struct MultipartWriter {
+    handle: Handle,
+    client: Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    buffer: Vec<u8>,
+    parts: Vec<CompletedPart>,
+    finished: bool,
+}
+
+impl MultipartWriter {
+    fn new(handle: Handle, client: Client, bucket: String, key: &str) -> anyhow::Result<Self> {
+        let upload = handle.block_on(
+            client
+                .create_multipart_upload()
+                .bucket(&bucket)
+                .key(key)
+                .content_type("application/sql")
+                .send(),
+        )?;
+        let upload_id = upload
+            .upload_id()
+            .with_context(|| format!("no upload id returned for {key}"))?
+            .to_owned();
+        Ok(Self {
+            handle,
+            client,
+            bucket,
+            key: key.to_owned(),
+            upload_id,
+            buffer: Vec::with_capacity(PART_SIZE),
+            parts: Vec::new(),
+            finished: false,
+        })
+    }
+
+    fn upload_part(&mut self) -> anyhow::Result<()> {
+        let part_number = self.parts.len() as i32 + 1;
+        let body = std::mem::replace(&mut self.buffer, Vec::with_capacity(PART_SIZE));
+        let part = self.handle.block_on(
+            self.client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(&self.upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(body))
+                .send(),
+        )?;
+        self.parts.push(
+            CompletedPart::builder()
+                .set_e_tag(part.e_tag().map(str::to_owned))
+                .part_number(part_number)
+                .build(),
+        );
+        Ok(())
+    }
+
+    /// Uploads the last part, and completes the upload.
+    fn finish(mut self) -> anyhow::Result<()> {
+        // an upload needs at least one part, even if it's empty
+        if !self.buffer.is_empty() || self.parts.is_empty() {
+            self.upload_part()?;
+        }
+        self.handle.block_on(
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(&self.upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(std::mem::take(&mut self.parts)))
+                        .build(),
+                )
+                .send(),
+        )?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Write for MultipartWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(PART_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == PART_SIZE {
+            self.upload_part()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+        Ok(len)
+    }
+
+    /// Parts are only uploaded once they are full, so this does nothing.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MultipartWriter {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // the parts which were already uploaded are billed until the upload is aborted
+        let abort = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .send();
+        if let Err(e) = self.handle.block_on(abort) {
+            tracing::warn!("failed to abort the upload of dump `{}`: {e}", self.key);
+        }
+    }
+}
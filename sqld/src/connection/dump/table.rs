@@ -0,0 +1,162 @@
+//! Export of the rows of a single table, as CSV or JSON lines.
+use std::io::Write;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use rusqlite::types::ValueRef;
+use rusqlite::OptionalExtension;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TableExportFormat {
+    /// RFC 4180 CSV, with a header row holding the column names.
+    #[default]
+    Csv,
+    /// One JSON object per row, keyed by column name.
+    Jsonl,
+}
+
+impl TableExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TableExportFormat::Csv => "text/csv; charset=utf-8",
+            TableExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+}
+
+/// Checks that `table` is an existing, non-internal table of the database. The name is then safe
+/// to quote into a query.
+pub fn table_exists(db: &rusqlite::Connection, table: &str) -> rusqlite::Result<bool> {
+    if table.starts_with("sqlite_") {
+        return Ok(false);
+    }
+    Ok(db
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?",
+            [table],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Writes all the rows of `table`, in rowid order for regular tables. Text is written as is,
+/// blobs are encoded in base64, and NULLs are written as empty CSV fields or JSON `null`.
+pub fn export_table(
+    mut db: rusqlite::Connection,
+    table: &str,
+    format: TableExportFormat,
+    mut writer: impl Write,
+) -> anyhow::Result<()> {
+    // read all the rows from the same snapshot of the database
+    let txn = db.transaction()?;
+    let mut stmt = txn.prepare(&format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")))?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+    if format == TableExportFormat::Csv {
+        write_csv_record(&mut writer, columns.iter().map(|c| c.as_str()))?;
+    }
+
+    let mut rows = stmt.query(())?;
+    let mut fields = Vec::with_capacity(columns.len());
+    while let Some(row) = rows.next()? {
+        match format {
+            TableExportFormat::Csv => {
+                fields.clear();
+                for i in 0..columns.len() {
+                    fields.push(match row.get_ref(i)? {
+                        ValueRef::Null => String::new(),
+                        ValueRef::Integer(i) => i.to_string(),
+                        ValueRef::Real(f) => f.to_string(),
+                        ValueRef::Text(s) => String::from_utf8_lossy(s).into_owned(),
+                        ValueRef::Blob(b) => BASE64_STANDARD.encode(b),
+                    });
+                }
+                write_csv_record(&mut writer, fields.iter().map(|f| f.as_str()))?;
+            }
+            TableExportFormat::Jsonl => {
+                let mut object = serde_json::Map::with_capacity(columns.len());
+                for (i, column) in columns.iter().enumerate() {
+                    let value = match row.get_ref(i)? {
+                        ValueRef::Null => serde_json::Value::Null,
+                        ValueRef::Integer(i) => i.into(),
+                        ValueRef::Real(f) => f.into(),
+                        ValueRef::Text(s) => String::from_utf8_lossy(s).into(),
+                        ValueRef::Blob(b) => BASE64_STANDARD.encode(b).into(),
+                    };
+                    object.insert(column.clone(), value);
+                }
+                serde_json::to_writer(&mut writer, &object)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_csv_record<'a>(
+    writer: &mut impl Write,
+    fields: impl Iterator<Item = &'a str>,
+) -> std::io::Result<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        write_csv_field(writer, field)?;
+    }
+    writer.write_all(b"\r\n")
+}
+
+/// Writes a CSV field, quoted if it contains a separator, a quote or a line break.
+pub fn write_csv_field(writer: &mut impl Write, field: &str) -> std::io::Result<()> {
+    if field.contains([',', '"', '\r', '\n']) {
+        write!(writer, "\"{}\"", field.replace('"', "\"\""))
+    } else {
+        writer.write_all(field.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn export(format: TableExportFormat) -> String {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            r#"CREATE TABLE "my ""table""" (id INTEGER PRIMARY KEY, name TEXT, score REAL, data BLOB);
+            INSERT INTO "my ""table""" VALUES (1, 'plain', 1.5, x'68656c6c6f');
+            INSERT INTO "my ""table""" VALUES (2, 'with, "quotes"
+and newline', NULL, NULL);"#,
+        )
+        .unwrap();
+        assert!(table_exists(&db, "my \"table\"").unwrap());
+        assert!(!table_exists(&db, "my table").unwrap());
+        assert!(!table_exists(&db, "sqlite_master").unwrap());
+
+        let mut out = Vec::new();
+        export_table(db, "my \"table\"", format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn export_csv() {
+        assert_eq!(
+            export(TableExportFormat::Csv),
+            "id,name,score,data\r\n1,plain,1.5,aGVsbG8=\r\n2,\"with, \"\"quotes\"\"\nand newline\",,\r\n"
+        );
+    }
+
+    #[test]
+    fn export_jsonl() {
+        assert_eq!(
+            export(TableExportFormat::Jsonl),
+            "{\"id\":1,\"name\":\"plain\",\"score\":1.5,\"data\":\"aGVsbG8=\"}\n\
+             {\"id\":2,\"name\":\"with, \\\"quotes\\\"\\nand newline\",\"score\":null,\"data\":null}\n"
+        );
+    }
+}
@@ -1,39 +1,63 @@
+use std::cell::Cell;
+use std::ffi::{c_int, c_void};
+use std::io::BufRead;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crossbeam::channel::RecvTimeoutError;
+use futures::future::BoxFuture;
 use rusqlite::{ErrorCode, OpenFlags, StatementStatus};
 use sqld_libsql_bindings::wal_hook::WalMethodsHook;
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot, OwnedSemaphorePermit, Semaphore};
 use tracing::warn;
 
 use crate::auth::{Authenticated, Authorized};
+use crate::config::TrustedExtension;
 use crate::error::Error;
+use crate::heap_limit::{self, ConnectionMemory, MemoryStatus, NamespaceHeap};
 use crate::libsql::wal_hook::WalHook;
-use crate::query::Query;
-use crate::query_analysis::{State, StmtKind};
-use crate::query_result_builder::{QueryBuilderConfig, QueryResultBuilder};
-use crate::stats::Stats;
+use crate::query::{Params, Query, Value};
+use crate::query_analysis::{Attach, State, Statement, StmtKind};
+use crate::query_result_builder::{
+    CountRows, LimitRows, QueryBuilderConfig, QueryResultBuilder, RecordedCall, StepRecorder,
+};
+use crate::stats::{SchemaEvent, SlowQueries, SlowQuery, StatementCounts, Stats};
 use crate::Result;
 
-use super::config::DatabaseConfigStore;
-use super::program::{Cond, DescribeCol, DescribeParam, DescribeResponse, DescribeResult};
+use super::config::{check_page_size, DatabaseConfig, DatabaseConfigStore};
+use super::dump::loader::{
+    abort_dump_load, load_dump, load_dump_in_batches, LoadDumpOptions, LoadDumpStats,
+};
+use super::pragma;
+use super::program::{
+    Cond, DescribeCol, DescribeParam, DescribeResponse, DescribeResult, QueryPlanStep,
+    DRY_RUN_SAVEPOINT,
+};
+use super::reaper::{self, IdleConnections};
+use super::schema::Schema;
 use super::{MakeConnection, Program, Step, TXN_TIMEOUT};
 
+/// Maximum length of the SQL text of a logged slow query, in characters.
+const MAX_SLOW_QUERY_SQL_LEN: usize = 512;
+
 /// Internal message used to communicate between the database thread and the `LibSqlDb` handle.
 type ExecCallback = Box<dyn FnOnce(Result<&mut Connection>) -> anyhow::Result<()> + Send + 'static>;
 
 pub struct LibSqlDbFactory<W: WalHook + 'static> {
     db_path: PathBuf,
     hook: &'static WalMethodsHook<W>,
-    ctx_builder: Box<dyn Fn() -> W::Context + Sync + Send + 'static>,
+    ctx_builder: Arc<dyn Fn() -> W::Context + Sync + Send + 'static>,
     stats: Stats,
+    statement_counts: Arc<StatementCounts>,
     config_store: Arc<DatabaseConfigStore>,
-    extensions: Arc<[PathBuf]>,
-    max_response_size: u64,
-    max_total_response_size: u64,
-    auto_checkpoint: u32,
+    extensions: Arc<[TrustedExtension]>,
+    builder_config: QueryBuilderConfig,
+    /// Memory used by the created connections, which is held to the heap limits of the namespace.
+    heap: Arc<NamespaceHeap>,
+    /// Connections executing the parallel steps of the programs of the created connections.
+    readers: Arc<ReaderPool>,
     /// In wal mode, closing the last database takes time, and causes other databases creation to
     /// return sqlite busy. To mitigate that, we hold on to one connection
     _db: Option<LibSqlConnection>,
@@ -50,25 +74,64 @@ where
         hook: &'static WalMethodsHook<W>,
         ctx_builder: F,
         stats: Stats,
+        statement_counts: Arc<StatementCounts>,
         config_store: Arc<DatabaseConfigStore>,
-        extensions: Arc<[PathBuf]>,
+        extensions: Arc<[TrustedExtension]>,
         max_response_size: u64,
         max_total_response_size: u64,
+        statement_cache_size: usize,
+        max_query_params: usize,
         auto_checkpoint: u32,
+        heap: Arc<NamespaceHeap>,
     ) -> Result<Self>
     where
         F: Fn() -> W::Context + Sync + Send + 'static,
     {
+        let ctx_builder: Arc<dyn Fn() -> W::Context + Sync + Send> = Arc::new(ctx_builder);
+        let builder_config = QueryBuilderConfig {
+            max_size: Some(max_response_size),
+            max_total_size: Some(max_total_response_size),
+            auto_checkpoint,
+            statement_cache_size,
+            max_query_params: Some(max_query_params),
+            max_rows: None,
+        };
+        let readers = Arc::new(ReaderPool::new({
+            let db_path = db_path.clone();
+            let ctx_builder = ctx_builder.clone();
+            let stats = stats.clone();
+            let statement_counts = statement_counts.clone();
+            let config_store = config_store.clone();
+            let extensions = extensions.clone();
+            let heap = heap.clone();
+            move || {
+                Box::pin(LibSqlConnection::new(
+                    db_path.clone(),
+                    extensions.clone(),
+                    hook,
+                    ctx_builder(),
+                    stats.clone(),
+                    statement_counts.clone(),
+                    config_store.clone(),
+                    builder_config,
+                    Some(heap.clone()),
+                    None,
+                ))
+            }
+        }));
+        reaper::register(Arc::downgrade(&readers) as Weak<dyn IdleConnections>);
+
         let mut this = Self {
             db_path,
             hook,
-            ctx_builder: Box::new(ctx_builder),
+            ctx_builder,
             stats,
+            statement_counts,
             config_store,
             extensions,
-            max_response_size,
-            max_total_response_size,
-            auto_checkpoint,
+            builder_config,
+            heap,
+            readers,
             _db: None,
         };
 
@@ -107,6 +170,12 @@ where
         }
     }
 
+    /// Config that the result builders of the programs of the created connections are
+    /// initialized with.
+    pub fn builder_config(&self) -> QueryBuilderConfig {
+        self.builder_config
+    }
+
     async fn create_database(&self) -> Result<LibSqlConnection> {
         LibSqlConnection::new(
             self.db_path.clone(),
@@ -114,12 +183,11 @@ where
             self.hook,
             (self.ctx_builder)(),
             self.stats.clone(),
+            self.statement_counts.clone(),
             self.config_store.clone(),
-            QueryBuilderConfig {
-                max_size: Some(self.max_response_size),
-                max_total_size: Some(self.max_total_response_size),
-                auto_checkpoint: self.auto_checkpoint,
-            },
+            self.builder_config,
+            Some(self.heap.clone()),
+            Some(self.readers.clone()),
         )
         .await
     }
@@ -153,24 +221,32 @@ pub fn open_db<'a, W>(
 where
     W: WalHook,
 {
-    let flags = flags.unwrap_or(
-        OpenFlags::SQLITE_OPEN_READ_WRITE
-            | OpenFlags::SQLITE_OPEN_CREATE
-            | OpenFlags::SQLITE_OPEN_URI
-            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
-    );
+    let flags = flags.unwrap_or_else(default_open_flags);
     sqld_libsql_bindings::Connection::open(path, flags, wal_methods, hook_ctx, auto_checkpoint)
 }
 
+fn default_open_flags() -> OpenFlags {
+    OpenFlags::SQLITE_OPEN_READ_WRITE
+        | OpenFlags::SQLITE_OPEN_CREATE
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX
+}
+
 impl LibSqlConnection {
+    /// Opens a connection to the database. Its memory is charged to `heap`, if passed, and
+    /// parallel programs execute their independent steps on connections from `readers`, if passed.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new<W>(
         path: impl AsRef<Path> + Send + 'static,
-        extensions: Arc<[PathBuf]>,
+        extensions: Arc<[TrustedExtension]>,
         wal_hook: &'static WalMethodsHook<W>,
         hook_ctx: W::Context,
         stats: Stats,
+        statement_counts: Arc<StatementCounts>,
         config_store: Arc<DatabaseConfigStore>,
         builder_config: QueryBuilderConfig,
+        heap: Option<Arc<NamespaceHeap>>,
+        readers: Option<Arc<ReaderPool>>,
     ) -> crate::Result<Self>
     where
         W: WalHook,
@@ -180,6 +256,8 @@ impl LibSqlConnection {
         let (init_sender, init_receiver) = oneshot::channel();
 
         tokio::task::spawn_blocking(move || {
+            // the connection is dropped before the guard, so its memory is released from the heap
+            let _heap_guard = heap.clone().map(heap_limit::enter);
             let mut ctx = hook_ctx;
             let mut connection = match Connection::new(
                 path.as_ref(),
@@ -187,8 +265,11 @@ impl LibSqlConnection {
                 wal_hook,
                 &mut ctx,
                 stats,
+                statement_counts,
                 config_store,
                 builder_config,
+                heap,
+                readers,
             ) {
                 Ok(conn) => {
                     let Ok(_) = init_sender.send(Ok(())) else { return };
@@ -229,6 +310,7 @@ impl LibSqlConnection {
                     tracing::warn!("Database connection closed unexpectedly");
                     return;
                 };
+                connection.record_memory_status();
             }
         });
 
@@ -238,63 +320,361 @@ impl LibSqlConnection {
     }
 }
 
+/// Maximum number of steps of a program executed at once, and number of connections opened by a
+/// [`ReaderPool`].
+const MAX_PARALLEL_STEPS: usize = 8;
+
+type MakeReader = dyn Fn() -> BoxFuture<'static, Result<LibSqlConnection>> + Send + Sync + 'static;
+
+/// Connections to a database, shared by the connections to the database to execute the
+/// independent read steps of their programs in parallel, see [`Program::parallel_groups`].
+///
+/// The readers are not throttled with the connections of the namespace, so the pool opens at most
+/// [`MAX_PARALLEL_STEPS`] of them, whether they are idle or in use.
+pub struct ReaderPool {
+    make_reader: Box<MakeReader>,
+    /// Permits of the open readers.
+    permits: Arc<Semaphore>,
+    /// Idle connections, least recently used first, with the time at which they were put back.
+    idle: parking_lot::Mutex<Vec<(Reader, Instant)>>,
+}
+
+/// Connection of a [`ReaderPool`], which holds a permit of the pool until it is closed.
+struct Reader {
+    conn: LibSqlConnection,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ReaderPool {
+    fn new(
+        make_reader: impl Fn() -> BoxFuture<'static, Result<LibSqlConnection>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            make_reader: Box::new(make_reader),
+            permits: Arc::new(Semaphore::new(MAX_PARALLEL_STEPS)),
+            idle: Default::default(),
+        }
+    }
+
+    /// Takes an idle connection, or opens a new one, unless the pool already opened as many
+    /// connections as it may. Must be called from a blocking task.
+    fn get(&self) -> Result<Option<Reader>> {
+        if let Some((reader, _)) = self.idle.lock().pop() {
+            return Ok(Some(reader));
+        }
+        let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+            return Ok(None);
+        };
+        let conn = tokio::runtime::Handle::current().block_on((self.make_reader)())?;
+        Ok(Some(Reader {
+            conn,
+            _permit: permit,
+        }))
+    }
+
+    fn put(&self, reader: Reader) {
+        self.idle.lock().push((reader, Instant::now()));
+    }
+}
+
+impl IdleConnections for ReaderPool {
+    fn idle_count(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    fn least_recently_used(&self) -> Option<Instant> {
+        self.idle.lock().first().map(|(_, last_used)| *last_used)
+    }
+
+    fn close_least_recently_used(&self) -> bool {
+        // the reader is closed outside of the lock
+        let reader = {
+            let mut idle = self.idle.lock();
+            (!idle.is_empty()).then(|| idle.remove(0))
+        };
+        reader.is_some()
+    }
+}
+
+/// Sets the page size of a new database, before its first table is created. The page size of a
+/// database with tables only changes with a `VACUUM`, so a different page size is rejected.
+fn apply_page_size(conn: &rusqlite::Connection, page_size: u32) -> Result<()> {
+    check_page_size(page_size)?;
+    let is_empty = conn.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM sqlite_master)",
+        (),
+        |row| row.get::<_, bool>(0),
+    )?;
+    if is_empty {
+        conn.execute_batch(&format!("PRAGMA page_size = {page_size}"))?;
+        return Ok(());
+    }
+
+    let current = conn.query_row("PRAGMA page_size", (), |row| row.get::<_, u32>(0))?;
+    if current != page_size {
+        return Err(Error::PageSizeChange {
+            current,
+            requested: page_size,
+        });
+    }
+
+    Ok(())
+}
+
 struct Connection<'a> {
+    /// Directory of the namespace, next to the directories of the namespaces it can attach.
+    db_path: PathBuf,
     timeout_deadline: Option<Instant>,
     conn: sqld_libsql_bindings::Connection<'a>,
     timed_out: bool,
     stats: Stats,
+    statement_counts: Arc<StatementCounts>,
+    /// Last slow queries of the namespace.
+    slow_queries: Arc<SlowQueries>,
+    /// Subscribers to the schema changes of the namespace.
+    schema_events: broadcast::Sender<SchemaEvent>,
+    /// DDL statements of the ongoing transaction, whose events are sent once it commits.
+    pending_schema_changes: Vec<String>,
+    /// Set by the rollback hook of `conn` when a transaction is rolled back. It is declared after
+    /// `conn`, so that it outlives the connection, which may roll back when it is closed.
+    rolled_back: Box<AtomicBool>,
     config_store: Arc<DatabaseConfigStore>,
     builder_config: QueryBuilderConfig,
+    /// `max_page_count` of the connection when it was opened, restored when the database has no
+    /// quota.
+    default_max_page_count: u64,
+    /// Current `max_page_count` of the connection, which enforces the quota of the database.
+    max_page_count: u64,
+    heap: Option<Arc<NamespaceHeap>>,
+    /// Memory status of the connection, recorded in the heap of the namespace.
+    memory: Option<ConnectionMemory>,
+    readers: Option<Arc<ReaderPool>>,
 }
 
 impl<'a> Connection<'a> {
+    #[allow(clippy::too_many_arguments)]
     fn new<W: WalHook>(
         path: &Path,
-        extensions: Arc<[PathBuf]>,
+        extensions: Arc<[TrustedExtension]>,
         wal_methods: &'static WalMethodsHook<W>,
         hook_ctx: &'a mut W::Context,
         stats: Stats,
+        statement_counts: Arc<StatementCounts>,
         config_store: Arc<DatabaseConfigStore>,
         builder_config: QueryBuilderConfig,
+        heap: Option<Arc<NamespaceHeap>>,
+        readers: Option<Arc<ReaderPool>>,
     ) -> Result<Self> {
-        let this = Self {
-            conn: open_db(
+        if let Some(heap) = &heap {
+            let config = config_store.get();
+            heap.set_limits(config.soft_heap_limit_mb, config.hard_heap_limit_mb);
+        }
+        let conn = if config_store.get().in_memory {
+            sqld_libsql_bindings::Connection::open_in_memory(
+                path,
+                default_open_flags(),
+                wal_methods,
+                hook_ctx,
+            )?
+        } else {
+            open_db(
                 path,
                 wal_methods,
                 hook_ctx,
                 None,
                 builder_config.auto_checkpoint,
-            )?,
+            )?
+        };
+        if let Some(page_size) = config_store.get().page_size {
+            apply_page_size(&conn, page_size)?;
+        }
+        let max_page_count =
+            conn.query_row("PRAGMA max_page_count", (), |row| row.get::<_, i64>(0))? as u64;
+        // the directory of the namespace is named after it
+        let namespace = path.file_name().unwrap_or_default().to_string_lossy();
+        let this = Self {
+            db_path: path.to_owned(),
+            conn,
             timeout_deadline: None,
             timed_out: false,
+            slow_queries: stats.slow_queries(&namespace),
+            schema_events: stats.schema_events(&namespace),
+            pending_schema_changes: Vec::new(),
+            rolled_back: Box::default(),
             stats,
+            statement_counts,
             config_store,
             builder_config,
+            default_max_page_count: max_page_count,
+            max_page_count,
+            memory: heap.as_ref().map(NamespaceHeap::register_connection),
+            heap,
+            readers,
         };
+        this.watch_rollbacks();
+        this.conn
+            .set_prepared_statement_cache_capacity(builder_config.statement_cache_size);
 
         for ext in extensions.iter() {
+            // the file may have been replaced since the extensions were validated at startup
+            if let Err(e) = ext.verify() {
+                tracing::error!("refusing to load extension {}: {e}", ext.path.display());
+                Err(e)?;
+            }
             unsafe {
                 let _guard = rusqlite::LoadExtensionGuard::new(&this.conn).unwrap();
-                if let Err(e) = this.conn.load_extension(ext, None) {
-                    tracing::error!("failed to load extension: {}", ext.display());
+                if let Err(e) = this.conn.load_extension(&ext.path, None) {
+                    tracing::error!("failed to load extension: {}", ext.path.display());
                     Err(e)?;
                 }
-                tracing::debug!("Loaded extension {}", ext.display());
             }
+            tracing::info!(
+                "Loaded extension {} with verified digest {}",
+                ext.path.display(),
+                ext.digest()
+            );
         }
 
         Ok(this)
     }
 
-    fn run<B: QueryResultBuilder>(&mut self, pgm: Program, mut builder: B) -> Result<B> {
+    fn run<B: QueryResultBuilder>(&mut self, pgm: Program, builder: B) -> Result<B> {
+        let mut builder = LimitRows::new(builder, pgm.max_rows());
+        let Some(threshold_us) = self.config_store.get().slow_query_threshold_us else {
+            self.run_program(&pgm, &mut builder)?;
+            return Ok(builder.into_inner());
+        };
+
+        let start = Instant::now();
+        let mut builder = CountRows::new(builder);
+        let res = self.run_program(&pgm, &mut builder);
+        let duration_us = start.elapsed().as_micros() as u64;
+        if duration_us > threshold_us {
+            self.record_slow_query(&pgm, duration_us, builder.rows());
+        }
+        res?;
+
+        Ok(builder.into_inner().into_inner())
+    }
+
+    /// Logs a program that took longer than the slow query threshold of the namespace, and keeps
+    /// it with the last slow queries of the namespace.
+    fn record_slow_query(&self, pgm: &Program, duration_us: u64, rows: u64) {
+        let sql = pgm
+            .steps()
+            .iter()
+            .map(|step| step.query.stmt.stmt.as_str())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let sql = match sql.char_indices().nth(MAX_SLOW_QUERY_SQL_LEN) {
+            Some((end, _)) => sql[..end].to_string(),
+            None => sql,
+        };
+        let namespace = self
+            .db_path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        warn!(
+            "slow query on namespace `{namespace}` took {duration_us}us and returned {rows} rows: {sql}"
+        );
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.slow_queries.record(SlowQuery {
+            timestamp_ms,
+            sql,
+            duration_us,
+            rows,
+        });
+    }
+
+    fn run_program<B: QueryResultBuilder>(&mut self, pgm: &Program, builder: &mut B) -> Result<()> {
+        // the config is read once, so that a program is either blocked as a whole, or not at all
+        let mut config = self.config_store.effective();
+        if config.block_reads || (config.block_writes && !pgm.is_read_only()) {
+            return Err(Error::Blocked(config.block_reason.clone()));
+        }
+        if let Some(target) = config.migrated_to.as_ref().filter(|_| !pgm.is_read_only()) {
+            let namespace = self
+                .db_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            return Err(Error::NamespaceMigrated(namespace, target.clone()));
+        }
+        // the steps see no row limit if their rows are streamed by a cursor
+        if pgm.incremental && config.max_rows.is_some() {
+            config = Arc::new(DatabaseConfig {
+                max_rows: None,
+                ..(*config).clone()
+            });
+        }
+        // the limits may have been updated since the connection was created
+        if let Some(heap) = &self.heap {
+            heap.set_limits(config.soft_heap_limit_mb, config.hard_heap_limit_mb);
+        }
+        self.release_memory_over_soft_limit();
+        let max_db_size = config.max_db_size.filter(|_| !pgm.is_read_only());
+        if !pgm.is_read_only() {
+            self.set_max_page_count(max_db_size)?;
+        }
+        // once over its quota, the database only accepts the writes that free space
+        if let Some(max_db_size) = max_db_size.filter(|_| pgm.may_grow_db()) {
+            if self.db_size()? >= max_db_size {
+                return Err(Error::DatabaseFull(max_db_size));
+            }
+        }
+
         let mut results = Vec::with_capacity(pgm.steps.len());
 
-        builder.init(&self.builder_config)?;
+        // the rows are limited by the builder, which the connection wraps in `LimitRows`
+        builder.init(&QueryBuilderConfig {
+            max_rows: config.max_rows,
+            ..self.builder_config
+        })?;
         let is_autocommit_before = self.conn.is_autocommit();
 
-        for step in pgm.steps() {
-            let res = self.execute_step(step, &results, &mut builder)?;
+        let readers = self.readers.clone();
+        let mut parallel_groups = match readers {
+            Some(_) => pgm.parallel_groups(),
+            None => Vec::new(),
+        }
+        .into_iter()
+        .peekable();
+        let steps = pgm.steps();
+        let mut i = 0;
+        while i < steps.len() {
+            if let Some(group) = parallel_groups.next_if(|group| group.start == i) {
+                // the other connections can't see the changes of an ongoing transaction
+                if let Some(readers) = readers.as_deref().filter(|_| self.conn.is_autocommit()) {
+                    let res = self.execute_parallel_steps(
+                        readers,
+                        &steps[group.clone()],
+                        &config,
+                        builder,
+                    )?;
+                    results.extend(res);
+                    i = group.end;
+                    continue;
+                }
+            }
+
+            let res = match self.execute_step(&steps[i], &results, &config, builder) {
+                Ok(res) => res,
+                Err(e) => {
+                    if pgm.dry_run {
+                        self.abort_dry_run();
+                    }
+                    return Err(e);
+                }
+            };
+            // a dry run never changes the schema, it's rolled back
+            if !pgm.dry_run {
+                self.track_schema_changes(&steps[i], res);
+            }
             results.push(res);
+            i += 1;
         }
 
         // A transaction is still open, set up a timeout
@@ -302,15 +682,203 @@ impl<'a> Connection<'a> {
             self.timeout_deadline = Some(Instant::now() + TXN_TIMEOUT)
         }
 
+        // the writes fail rather than grow the database past its quota, but a database that was
+        // over its quota when it was set stays so until enough space is freed
+        if let Some(max_db_size) = max_db_size {
+            let db_size = self.db_size()?;
+            if db_size >= max_db_size {
+                tracing::warn!(
+                    "database size of {db_size} bytes reached its quota of {max_db_size} bytes, further writes will be rejected"
+                );
+            }
+        }
+
+        self.release_memory_over_soft_limit();
+
         builder.finish()?;
 
-        Ok(builder)
+        Ok(())
+    }
+
+    /// Memory used by the connection, as reported by `sqlite3_db_status`.
+    fn status(&self) -> MemoryStatus {
+        let db_status = |op| {
+            let mut current = 0;
+            let mut highwater = 0;
+            unsafe {
+                rusqlite::ffi::sqlite3_db_status(
+                    self.conn.handle(),
+                    op,
+                    &mut current,
+                    &mut highwater,
+                    0,
+                );
+            }
+            current.max(0) as u64
+        };
+        MemoryStatus {
+            cache_used: db_status(rusqlite::ffi::SQLITE_DBSTATUS_CACHE_USED),
+            schema_used: db_status(rusqlite::ffi::SQLITE_DBSTATUS_SCHEMA_USED),
+            stmt_used: db_status(rusqlite::ffi::SQLITE_DBSTATUS_STMT_USED),
+        }
+    }
+
+    /// Records the memory status of the connection in the heap of the namespace, for it to be
+    /// sampled without waiting for the connection.
+    fn record_memory_status(&self) {
+        if let Some(memory) = &self.memory {
+            memory.record(self.status());
+        }
+    }
+
+    /// Releases the memory that the connection can spare, such as its page cache, if its
+    /// namespace is over its soft heap limit.
+    fn release_memory_over_soft_limit(&self) {
+        if self
+            .heap
+            .as_ref()
+            .map_or(false, |heap| heap.is_over_soft_limit())
+        {
+            unsafe {
+                rusqlite::ffi::sqlite3_db_release_memory(self.conn.handle());
+            }
+        }
+    }
+
+    /// Executes unconditional read steps concurrently, each on a connection from `readers`, and
+    /// adds their results to `builder` in the order of the steps. The steps for which the pool has
+    /// no connection to spare are executed on this connection.
+    fn execute_parallel_steps(
+        &mut self,
+        readers: &ReaderPool,
+        steps: &[Step],
+        config: &DatabaseConfig,
+        builder: &mut impl QueryResultBuilder,
+    ) -> Result<Vec<bool>> {
+        let mut results = Vec::with_capacity(steps.len());
+        for chunk in steps.chunks(MAX_PARALLEL_STEPS) {
+            let mut pending = Vec::with_capacity(chunk.len());
+            for step in chunk {
+                let (resp, receiver) = oneshot::channel();
+                let Some(reader) = readers.get()? else {
+                    let _ = resp.send(self.record_step(step, config));
+                    pending.push((None, receiver));
+                    continue;
+                };
+                let step = step.clone();
+                let config = config.clone();
+                let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+                    let res = maybe_conn.and_then(|c| c.record_step(&step, &config));
+
+                    if resp.send(res).is_err() {
+                        anyhow::bail!("connection closed");
+                    }
+
+                    Ok(())
+                });
+                reader
+                    .conn
+                    .sender
+                    .send(cb)
+                    .map_err(|_| Error::Internal("reader connection closed".into()))?;
+                pending.push((Some(reader), receiver));
+            }
+
+            for (reader, receiver) in pending {
+                let (enabled, calls) = receiver.blocking_recv()??;
+                if let Some(reader) = reader {
+                    readers.put(reader);
+                }
+                StepRecorder::replay(calls, builder)?;
+                results.push(enabled);
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Executes a step of a parallel group, whose results are recorded to be added to the builder
+    /// of the program in order.
+    fn record_step(
+        &mut self,
+        step: &Step,
+        config: &DatabaseConfig,
+    ) -> Result<(bool, Vec<RecordedCall>)> {
+        // the rows are limited before they are recorded, rather than when they are replayed
+        let mut recorder = LimitRows::new(StepRecorder::default(), vec![step.query.max_rows]);
+        recorder.init(&QueryBuilderConfig {
+            max_rows: config.max_rows,
+            ..self.builder_config
+        })?;
+        let enabled = self.execute_step(step, &[], config, &mut recorder)?;
+        Ok((enabled, recorder.into_ret()))
+    }
+
+    /// Tracks the schema changes of an executed step, to send an event for each DDL step that
+    /// completed successfully, if anyone is listening to the schema events of the namespace. The
+    /// events are sent once the changes are committed: the DDL steps of a transaction are held
+    /// back until it commits, and dropped if it rolls back.
+    fn track_schema_changes(&mut self, step: &Step, executed: bool) {
+        if self.rolled_back.swap(false, Ordering::Relaxed) {
+            self.pending_schema_changes.clear();
+        }
+        if executed && step.query.stmt.is_ddl && self.schema_events.receiver_count() > 0 {
+            self.pending_schema_changes
+                .push(step.query.stmt.stmt.clone());
+        }
+        if self.pending_schema_changes.is_empty() || !self.conn.is_autocommit() {
+            return;
+        }
+
+        let schema_version = match self
+            .conn
+            .query_row("PRAGMA schema_version", (), |row| row.get(0))
+        {
+            Ok(version) => version,
+            Err(e) => {
+                tracing::warn!("failed to read the schema version for schema events: {e}");
+                self.pending_schema_changes.clear();
+                return;
+            }
+        };
+        for statement in self.pending_schema_changes.drain(..) {
+            // fails only if every subscriber went away in the meantime
+            let _ = self.schema_events.send(SchemaEvent::SchemaChange {
+                schema_version,
+                statement,
+            });
+        }
+    }
+
+    /// Installs the rollback hook of the connection, which sets `rolled_back`.
+    fn watch_rollbacks(&self) {
+        unsafe extern "C" fn on_rollback(rolled_back: *mut c_void) {
+            (*(rolled_back as *const AtomicBool)).store(true, Ordering::Relaxed);
+        }
+
+        unsafe {
+            rusqlite::ffi::sqlite3_rollback_hook(
+                self.conn.handle(),
+                Some(on_rollback),
+                &*self.rolled_back as *const AtomicBool as *mut c_void,
+            );
+        }
+    }
+
+    /// Rolls back the changes of a dry-run program whose execution was interrupted before it
+    /// reached its rollback steps.
+    fn abort_dry_run(&mut self) {
+        let sql = format!("ROLLBACK TO {DRY_RUN_SAVEPOINT}; RELEASE {DRY_RUN_SAVEPOINT};");
+        if let Err(e) = self.conn.execute_batch(&sql) {
+            tracing::warn!("failed to roll back interrupted dry run: {e}");
+        }
     }
 
     fn execute_step(
         &mut self,
         step: &Step,
         results: &[bool],
+        config: &DatabaseConfig,
         builder: &mut impl QueryResultBuilder,
     ) -> Result<bool> {
         builder.begin_step()?;
@@ -327,7 +895,7 @@ impl<'a> Connection<'a> {
         };
 
         let (affected_row_count, last_insert_rowid) = if enabled {
-            match self.execute_query(&step.query, builder) {
+            match self.execute_query(&step.query, config, builder) {
                 // builder error interupt the execution of query. we should exit immediately.
                 Err(e @ Error::BuilderError(_)) => return Err(e),
                 Err(e) => {
@@ -349,21 +917,85 @@ impl<'a> Connection<'a> {
     fn execute_query(
         &self,
         query: &Query,
+        config: &DatabaseConfig,
         builder: &mut impl QueryResultBuilder,
     ) -> Result<(u64, Option<i64>)> {
         tracing::trace!("executing query: {}", query.stmt.stmt);
 
-        let config = self.config_store.get();
         let blocked = match query.stmt.kind {
-            StmtKind::Read | StmtKind::TxnBegin | StmtKind::Other => config.block_reads,
+            StmtKind::Read
+            | StmtKind::TxnBegin
+            | StmtKind::Savepoint
+            | StmtKind::Attach
+            | StmtKind::Detach
+            | StmtKind::Other => config.block_reads,
             StmtKind::Write => config.block_reads || config.block_writes,
-            StmtKind::TxnEnd => false,
+            StmtKind::TxnEnd | StmtKind::Release | StmtKind::RollbackTo => false,
         };
         if blocked {
             return Err(Error::Blocked(config.block_reason.clone()));
         }
 
-        let mut stmt = self.conn.prepare(&query.stmt.stmt)?;
+        if let Some(limit) = self.builder_config.max_query_params {
+            let count = query.params.len();
+            if count > limit {
+                return Err(Error::TooManyQueryParams(count, limit));
+            }
+        }
+
+        self.statement_counts.record(&query.stmt);
+
+        if matches!(query.stmt.kind, StmtKind::Attach | StmtKind::Detach) && !config.allow_attach {
+            return Err(Error::AttachDisabled);
+        }
+        if let Some(pragma) = &query.stmt.pragma {
+            pragma::check_pragma(pragma, config.pragma_allowlist.as_deref())?;
+        }
+        let attach_query;
+        let query = match &query.stmt.attach {
+            Some(attach) => {
+                attach_query = self.attach_query(query, attach, config)?;
+                &attach_query
+            }
+            None => query,
+        };
+
+        let memory_limit = query
+            .max_memory_bytes
+            .map(|max_bytes| MemoryLimit::install(&self.conn, max_bytes));
+        let res = match (self.run_query(query, builder), config.max_db_size) {
+            // `max_page_count` is set from the quota, writes past it fail with SQLITE_FULL
+            (
+                Err(Error::RusqliteError(rusqlite::Error::SqliteFailure(e, _))),
+                Some(max_db_size),
+            ) if e.code == ErrorCode::DiskFull => Err(Error::DatabaseFull(max_db_size)),
+            (res, _) => res,
+        };
+        if res.is_ok() && query.stmt.kind == StmtKind::Write {
+            self.statement_counts.record_write();
+        }
+        match memory_limit {
+            Some(limit) if res.is_err() && limit.is_exceeded() => {
+                Err(Error::MemoryLimitExceeded(limit.max_bytes()))
+            }
+            _ => res,
+        }
+    }
+
+    fn run_query(
+        &self,
+        query: &Query,
+        builder: &mut impl QueryResultBuilder,
+    ) -> Result<(u64, Option<i64>)> {
+        let mut cached;
+        let mut uncached;
+        let stmt: &mut rusqlite::Statement = if self.builder_config.statement_cache_size > 0 {
+            cached = self.conn.prepare_cached(&query.stmt.stmt)?;
+            &mut cached
+        } else {
+            uncached = self.conn.prepare(&query.stmt.stmt)?;
+            &mut uncached
+        };
 
         let cols = stmt.columns();
         let cols_count = cols.len();
@@ -372,12 +1004,18 @@ impl<'a> Connection<'a> {
 
         query
             .params
-            .bind(&mut stmt)
+            .bind(stmt)
             .map_err(Error::LibSqlInvalidQueryParams)?;
 
         let mut qresult = stmt.raw_query();
         builder.begin_rows()?;
         while let Some(row) = qresult.next()? {
+            // the statement must still run to completion, but the client doesn't care about the
+            // rows, so we don't spend time and response size on them
+            if !query.want_rows {
+                continue;
+            }
+
             builder.begin_row()?;
             for i in 0..cols_count {
                 let val = row.get_ref(i)?;
@@ -396,32 +1034,193 @@ impl<'a> Connection<'a> {
         };
 
         // sqlite3_last_insert_rowid() only makes sense for INSERTs into a rowid table. we can't detect
-        // a rowid table, but at least we can detect an INSERT
-        let last_insert_rowid = match query.stmt.is_insert {
+        // a rowid table, but at least we can detect an INSERT. it is not reset by an INSERT that
+        // didn't insert any row (e.g. INSERT OR IGNORE), so we would report the rowid of a previous
+        // INSERT in that case.
+        let last_insert_rowid = match query.stmt.is_insert && affected_row_count > 0 {
             true => Some(self.conn.last_insert_rowid()),
             false => None,
         };
 
         drop(qresult);
 
-        self.update_stats(&stmt);
+        self.update_stats(stmt);
 
         Ok((affected_row_count, last_insert_rowid))
     }
 
+    /// Returns the query attaching the database of a namespace read-only, if the namespace is in
+    /// the attach allowlist. The database is attached through its path, rather than the name
+    /// given to `ATTACH`, so that only the databases of namespaces can be attached.
+    fn attach_query(
+        &self,
+        query: &Query,
+        attach: &Attach,
+        config: &DatabaseConfig,
+    ) -> Result<Query> {
+        if !config.attach_allowlist.contains(&attach.namespace) {
+            return Err(Error::AttachNotAllowed(attach.namespace.clone()));
+        }
+        let Some(dbs_path) = self.db_path.parent() else {
+            return Err(Error::AttachNotAllowed(attach.namespace.clone()));
+        };
+        let path = dbs_path.join(&attach.namespace).join("data");
+        let path = path.to_string_lossy();
+        // escape the characters that have a meaning in a URI
+        let path = path
+            .replace('%', "%25")
+            .replace('?', "%3f")
+            .replace('#', "%23");
+
+        Ok(Query {
+            stmt: Statement {
+                stmt: "ATTACH DATABASE ? AS ?".into(),
+                ..query.stmt.clone()
+            },
+            params: Params::new_positional(vec![
+                Value::Text(format!("file:{path}?mode=ro")),
+                Value::Text(attach.alias.clone()),
+            ]),
+            want_rows: false,
+            max_memory_bytes: query.max_memory_bytes,
+            max_rows: query.max_rows,
+        })
+    }
+
     fn rollback(&self) {
         let _ = self.conn.execute("ROLLBACK", ());
     }
 
+    /// Size of the database in bytes, including the pages written by the ongoing transaction. The
+    /// free pages are not counted, as they are reused by the next writes.
+    fn db_size(&self) -> Result<u64> {
+        let size = self.conn.query_row(
+            "SELECT (page_count - freelist_count) * page_size \
+                FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+            (),
+            |row| row.get::<_, i64>(0),
+        )?;
+        Ok(size as u64)
+    }
+
+    /// Sets the `max_page_count` of the connection from the quota of the database, so that SQLite
+    /// fails the writes that would grow the database past it. SQLite doesn't lower it below the
+    /// current size of the database, so it's set again once the database shrinks.
+    fn set_max_page_count(&mut self, max_db_size: Option<u64>) -> Result<()> {
+        let max_page_count = match max_db_size {
+            Some(max_db_size) => {
+                let page_size = self
+                    .conn
+                    .query_row("PRAGMA page_size", (), |row| row.get::<_, i64>(0))?;
+                (max_db_size / page_size as u64).max(1)
+            }
+            None => self.default_max_page_count,
+        };
+        if max_page_count != self.max_page_count {
+            self.max_page_count = self.conn.query_row(
+                &format!("PRAGMA max_page_count = {max_page_count}"),
+                (),
+                |row| row.get::<_, i64>(0),
+            )? as u64;
+        }
+        Ok(())
+    }
+
     fn checkpoint(&self) -> Result<()> {
         self.conn
             .query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))?;
         Ok(())
     }
 
+    fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let lines = stmt
+            .query_map((), |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(lines)
+    }
+
+    fn schema(&self) -> Result<Schema> {
+        let config = self.config_store.effective();
+        if config.block_reads {
+            return Err(Error::Blocked(config.block_reason.clone()));
+        }
+        Ok(Schema::load(&self.conn)?)
+    }
+
+    fn vacuum(&self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            return Err(Error::QueryError(
+                "cannot vacuum the database inside a transaction".into(),
+            ));
+        }
+
+        // no busy handler is installed, so this fails right away if another connection holds the
+        // write lock, instead of waiting for it
+        match self.conn.execute("VACUUM", ()) {
+            Ok(_) => Ok(()),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+            {
+                Err(Error::VacuumConflict)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn load_dump(&mut self, dump: impl BufRead, options: LoadDumpOptions) -> Result<LoadDumpStats> {
+        let config = self.config_store.effective();
+        if config.block_reads || config.block_writes {
+            return Err(Error::Blocked(config.block_reason.clone()));
+        }
+        if options.abort {
+            let progress = abort_dump_load(&self.conn)?;
+            tracing::info!(
+                "aborted the load of a dump after {} statements",
+                progress.statements
+            );
+            return Ok(progress);
+        }
+
+        // the dump is held to the quota like the writes of the programs
+        self.set_max_page_count(config.max_db_size)?;
+        if let Some(max_db_size) = config.max_db_size {
+            if self.db_size()? >= max_db_size {
+                return Err(Error::DatabaseFull(max_db_size));
+            }
+        }
+
+        let res = match options.batch_size {
+            Some(batch_size) => load_dump_in_batches(
+                &self.conn,
+                dump,
+                options.allow_non_empty,
+                batch_size,
+                options.resume_offset,
+            ),
+            None => load_dump(&self.conn, dump, options.allow_non_empty),
+        };
+        let stats = match (res, config.max_db_size) {
+            (
+                Err(Error::RusqliteError(rusqlite::Error::SqliteFailure(e, _))),
+                Some(max_db_size),
+            ) if e.code == ErrorCode::DiskFull => return Err(Error::DatabaseFull(max_db_size)),
+            (res, _) => res?,
+        };
+        tracing::info!(
+            "loaded dump of {} lines: {} statements executed, {} rows changed",
+            stats.lines,
+            stats.statements,
+            stats.rows_changed
+        );
+
+        Ok(stats)
+    }
+
     fn update_stats(&self, stmt: &rusqlite::Statement) {
-        let rows_read = stmt.get_status(StatementStatus::RowsRead);
-        let rows_written = stmt.get_status(StatementStatus::RowsWritten);
+        // the counters are reset, as a cached statement keeps them across executions
+        let rows_read = stmt.reset_status(StatementStatus::RowsRead);
+        let rows_written = stmt.reset_status(StatementStatus::RowsWritten);
         let rows_read = if rows_read == 0 && rows_written == 0 {
             1
         } else {
@@ -431,7 +1230,7 @@ impl<'a> Connection<'a> {
         self.stats.inc_rows_written(rows_written as u64);
     }
 
-    fn describe(&self, sql: &str) -> DescribeResult {
+    fn describe(&self, sql: &str, query_plan: bool) -> DescribeResult {
         let stmt = self.conn.prepare(sql)?;
 
         let params = (1..=stmt.parameter_count())
@@ -453,14 +1252,36 @@ impl<'a> Connection<'a> {
 
         let is_explain = stmt.is_explain() != 0;
         let is_readonly = stmt.readonly();
+        let query_plan = if query_plan && !is_explain {
+            self.query_plan(sql)?
+        } else {
+            Vec::new()
+        };
         Ok(DescribeResponse {
             params,
             cols,
             is_explain,
             is_readonly,
+            query_plan,
         })
     }
 
+    /// Runs `EXPLAIN QUERY PLAN` for the statement. The statement is only compiled, not executed,
+    /// so its parameters are left unbound.
+    fn query_plan(&self, sql: &str) -> Result<Vec<QueryPlanStep>> {
+        let mut stmt = self.conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+        let mut rows = stmt.raw_query();
+        let mut steps = Vec::new();
+        while let Some(row) = rows.next()? {
+            steps.push(QueryPlanStep {
+                id: row.get(0)?,
+                parent: row.get(1)?,
+                detail: row.get(3)?,
+            });
+        }
+        Ok(steps)
+    }
+
     fn is_autocommit(&self) -> bool {
         self.conn.is_autocommit()
     }
@@ -495,8 +1316,18 @@ fn check_program_auth(auth: Authenticated, pgm: &Program) -> Result<()> {
                     "anonymous access not allowed".to_string(),
                 ));
             }
-            (StmtKind::Read, Authenticated::Authorized(_)) => (),
-            (StmtKind::TxnBegin, _) | (StmtKind::TxnEnd, _) => (),
+            (
+                StmtKind::Read | StmtKind::Attach | StmtKind::Detach,
+                Authenticated::Authorized(_),
+            ) => (),
+            (
+                StmtKind::TxnBegin
+                | StmtKind::TxnEnd
+                | StmtKind::Savepoint
+                | StmtKind::Release
+                | StmtKind::RollbackTo,
+                _,
+            ) => (),
             (_, Authenticated::Authorized(Authorized::FullAccess)) => (),
             _ => {
                 return Err(Error::NotAuthorized(format!(
@@ -552,11 +1383,16 @@ impl super::Connection for LibSqlConnection {
         Ok(receiver.await??)
     }
 
-    async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult> {
+    async fn describe(
+        &self,
+        sql: String,
+        auth: Authenticated,
+        query_plan: bool,
+    ) -> Result<DescribeResult> {
         check_describe_auth(auth)?;
         let (resp, receiver) = oneshot::channel();
         let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
-            let res = maybe_conn.and_then(|c| c.describe(&sql));
+            let res = maybe_conn.and_then(|c| c.describe(&sql, query_plan));
 
             if resp.send(res).is_err() {
                 anyhow::bail!("connection closed");
@@ -597,25 +1433,197 @@ impl super::Connection for LibSqlConnection {
         let _: Result<_, _> = self.sender.send(cb);
         receiver.await?
     }
+
+    async fn vacuum(&self, auth: Authenticated) -> Result<()> {
+        if !matches!(auth, Authenticated::Authorized(Authorized::FullAccess)) {
+            return Err(Error::NotAuthorized(
+                "vacuum requires full access".to_string(),
+            ));
+        }
+
+        let (resp, receiver) = oneshot::channel();
+        let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            let res = maybe_conn.and_then(|c| c.vacuum());
+            if resp.send(res).is_err() {
+                anyhow::bail!("connection closed");
+            }
+            Ok(())
+        });
+
+        let _: Result<_, _> = self.sender.send(cb);
+        receiver.await?
+    }
+
+    async fn integrity_check(&self) -> Result<Vec<String>> {
+        let (resp, receiver) = oneshot::channel();
+        let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            let res = maybe_conn.and_then(|c| c.integrity_check());
+            if resp.send(res).is_err() {
+                anyhow::bail!("connection closed");
+            }
+            Ok(())
+        });
+
+        let _: Result<_, _> = self.sender.send(cb);
+        receiver.await?
+    }
+
+    async fn schema(&self, auth: Authenticated) -> Result<Schema> {
+        check_describe_auth(auth)?;
+        let (resp, receiver) = oneshot::channel();
+        let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            let res = maybe_conn.and_then(|c| c.schema());
+            if resp.send(res).is_err() {
+                anyhow::bail!("connection closed");
+            }
+            Ok(())
+        });
+
+        let _: Result<_, _> = self.sender.send(cb);
+        receiver.await?
+    }
+
+    async fn load_dump(
+        &self,
+        dump: Box<dyn BufRead + Send>,
+        options: LoadDumpOptions,
+        auth: Authenticated,
+    ) -> Result<LoadDumpStats> {
+        if !matches!(auth, Authenticated::Authorized(Authorized::FullAccess)) {
+            return Err(Error::NotAuthorized(
+                "loading a dump requires full access".to_string(),
+            ));
+        }
+
+        let (resp, receiver) = oneshot::channel();
+        let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            let res = maybe_conn.and_then(|c| c.load_dump(dump, options));
+            if resp.send(res).is_err() {
+                anyhow::bail!("connection closed");
+            }
+            Ok(())
+        });
+
+        let _: Result<_, _> = self.sender.send(cb);
+        receiver.await?
+    }
+}
+
+/// Number of virtual machine instructions between two checks of the memory limit of a query.
+const MEMORY_LIMIT_CHECK_INTERVAL: c_int = 1000;
+
+/// Interrupts the statements running on a connection once the memory used by SQLite grew by more
+/// than a limit since it was installed. The progress handler is removed when this is dropped.
+///
+/// SQLite only counts the memory of the whole process, so the memory allocated by the queries
+/// running concurrently on other connections counts towards the limit too.
+struct MemoryLimit<'a> {
+    conn: &'a rusqlite::Connection,
+    state: Box<MemoryLimitState>,
+}
+
+struct MemoryLimitState {
+    max_bytes: u64,
+    /// Memory used by SQLite when the query started.
+    baseline: u64,
+    exceeded: Cell<bool>,
+}
+
+impl<'a> MemoryLimit<'a> {
+    fn install(conn: &'a rusqlite::Connection, max_bytes: u64) -> Self {
+        let state = Box::new(MemoryLimitState {
+            max_bytes,
+            baseline: memory_used(),
+            exceeded: Cell::new(false),
+        });
+
+        unsafe extern "C" fn check_memory(state: *mut c_void) -> c_int {
+            let state = &*(state as *const MemoryLimitState);
+            if memory_used().saturating_sub(state.baseline) > state.max_bytes {
+                state.exceeded.set(true);
+                1
+            } else {
+                0
+            }
+        }
+
+        unsafe {
+            rusqlite::ffi::sqlite3_progress_handler(
+                conn.handle(),
+                MEMORY_LIMIT_CHECK_INTERVAL,
+                Some(check_memory),
+                &*state as *const MemoryLimitState as *mut c_void,
+            );
+        }
+
+        Self { conn, state }
+    }
+
+    fn max_bytes(&self) -> u64 {
+        self.state.max_bytes
+    }
+
+    fn is_exceeded(&self) -> bool {
+        self.state.exceeded.get()
+    }
+}
+
+fn memory_used() -> u64 {
+    unsafe { rusqlite::ffi::sqlite3_memory_used().max(0) as u64 }
+}
+
+impl Drop for MemoryLimit<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            rusqlite::ffi::sqlite3_progress_handler(
+                self.conn.handle(),
+                0,
+                None,
+                std::ptr::null_mut(),
+            );
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use itertools::Itertools;
 
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::connection::config::DefaultLimits;
+    use crate::connection::Connection as _;
+    use crate::query::{Params, Value};
     use crate::query_result_builder::{test::test_driver, IgnoreResult};
 
     use super::*;
 
     fn setup_test_conn(ctx: &mut ()) -> Connection {
+        let conn = sqld_libsql_bindings::Connection::test(ctx);
+        let max_page_count = conn
+            .query_row("PRAGMA max_page_count", (), |row| row.get::<_, i64>(0))
+            .unwrap() as u64;
         let mut conn = Connection {
+            db_path: PathBuf::new(),
             timeout_deadline: None,
-            conn: sqld_libsql_bindings::Connection::test(ctx),
+            conn,
             timed_out: false,
             stats: Stats::default(),
+            statement_counts: Default::default(),
+            slow_queries: Default::default(),
+            schema_events: broadcast::channel(16).0,
+            pending_schema_changes: Vec::new(),
+            rolled_back: Box::default(),
             config_store: Arc::new(DatabaseConfigStore::new_test()),
             builder_config: QueryBuilderConfig::default(),
+            default_max_page_count: max_page_count,
+            max_page_count,
+            heap: None,
+            memory: None,
+            readers: None,
         };
+        conn.watch_rollbacks();
 
         let stmts = std::iter::once("create table test (x)")
             .chain(std::iter::repeat("insert into test values ('hello world')").take(100))
@@ -625,6 +1633,909 @@ mod test {
         conn
     }
 
+    /// Opens a connection to the database in `path`, without heap limit.
+    async fn open_test_conn(
+        path: PathBuf,
+        extensions: Arc<[TrustedExtension]>,
+        config_store: Arc<DatabaseConfigStore>,
+        readers: Option<Arc<ReaderPool>>,
+    ) -> crate::Result<LibSqlConnection> {
+        LibSqlConnection::new(
+            path,
+            extensions,
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Default::default(),
+            config_store,
+            QueryBuilderConfig::default(),
+            None,
+            readers,
+        )
+        .await
+    }
+
+    #[test]
+    fn test_memory_status() {
+        let ctx = &mut ();
+        let conn = setup_test_conn(ctx);
+        let status = conn.status();
+        assert!(status.cache_used > 0);
+        assert!(status.schema_used > 0);
+    }
+
+    #[test]
+    fn test_dry_run_does_not_persist() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let pgm = Program::seq(&["insert into test values ('dry')", "delete from test"])
+            .into_dry_run()
+            .unwrap();
+        conn.run(pgm, IgnoreResult).unwrap();
+
+        let count: i64 = conn
+            .conn
+            .query_row("select count(*) from test", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 100);
+        assert!(conn.is_autocommit());
+    }
+
+    #[test]
+    fn test_dry_run_rejects_transactions() {
+        assert!(
+            Program::seq(&["begin", "insert into test values (1)", "commit"])
+                .into_dry_run()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_query_params_limit() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let mut query = Program::seq(&["select ?, ?, ?"]).steps[0].query.clone();
+        query.params = Params::Positional(vec![Value::Integer(1); 3]);
+
+        conn.builder_config.max_query_params = Some(2);
+        let res = conn.execute_query(&query, &DatabaseConfig::default(), &mut IgnoreResult);
+        assert!(matches!(res, Err(Error::TooManyQueryParams(3, 2))));
+
+        conn.builder_config.max_query_params = Some(3);
+        conn.execute_query(&query, &DatabaseConfig::default(), &mut IgnoreResult)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_step_without_rows() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let mut pgm = Program::seq(&[
+            "insert into test values ('skipped') returning x",
+            "select x from test where x = 'skipped'",
+        ]);
+        Arc::make_mut(&mut pgm.steps)[0].query.want_rows = false;
+
+        let calls = conn.run(pgm, StepRecorder::default()).unwrap().into_ret();
+        let steps = calls
+            .split(|call| matches!(call, RecordedCall::BeginStep))
+            .skip(1)
+            .map(|calls| {
+                calls
+                    .iter()
+                    .filter(|call| matches!(call, RecordedCall::BeginRow))
+                    .count()
+            })
+            .collect_vec();
+        // the insert ran without returning its row, which the next step selects
+        assert_eq!(steps, [0, 1]);
+    }
+
+    /// Runs a program of a single step, whose query is limited to `max_rows`, and returns the
+    /// number of rows passed to the builder and the error of the step, if any.
+    fn run_with_row_limit(
+        conn: &mut Connection,
+        mut pgm: Program,
+        max_rows: Option<u64>,
+    ) -> (usize, Option<Error>) {
+        Arc::make_mut(&mut pgm.steps)[0].query.max_rows = max_rows;
+        let calls = conn.run(pgm, StepRecorder::default()).unwrap().into_ret();
+        let rows = calls
+            .iter()
+            .filter(|call| matches!(call, RecordedCall::BeginRow))
+            .count();
+        let error = calls.into_iter().find_map(|call| match call {
+            RecordedCall::StepError(e) => Some(e),
+            _ => None,
+        });
+        (rows, error)
+    }
+
+    #[test]
+    fn test_query_row_limit() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let pgm = Program::seq(&[
+            "with recursive c(x) as (select 1 union all select x + 1 from c limit 10) \
+            select x from c",
+        ]);
+
+        // the rows past the limit don't reach the builder
+        let (rows, error) = run_with_row_limit(&mut conn, pgm.clone(), Some(5));
+        assert_eq!(rows, 5);
+        assert!(matches!(error, Some(Error::RowLimitExceeded(5))));
+
+        let (rows, error) = run_with_row_limit(&mut conn, pgm, Some(10));
+        assert_eq!(rows, 10);
+        assert!(error.is_none());
+    }
+
+    /// Returns a config store without config, which defaults to the limits of the server.
+    fn default_limits(limits: DefaultLimits) -> Arc<DatabaseConfigStore> {
+        Arc::new(DatabaseConfigStore::new_test().with_defaults(limits))
+    }
+
+    #[test]
+    fn test_row_limit_of_server_and_namespace() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        conn.config_store = default_limits(DefaultLimits {
+            max_rows: Some(50),
+            ..Default::default()
+        });
+        let pgm = Program::seq(&["select * from test"]);
+
+        // the test table holds 100 rows
+        let (_, error) = run_with_row_limit(&mut conn, pgm.clone(), None);
+        assert!(matches!(error, Some(Error::RowLimitExceeded(50))));
+        let mut incremental = pgm.clone();
+        incremental.incremental = true;
+        let (rows, error) = run_with_row_limit(&mut conn, incremental, None);
+        assert_eq!(rows, 100);
+        assert!(error.is_none());
+
+        // the limit of the query applies when lower
+        let (_, error) = run_with_row_limit(&mut conn, pgm.clone(), Some(10));
+        assert!(matches!(error, Some(Error::RowLimitExceeded(10))));
+        let (_, error) = run_with_row_limit(&mut conn, pgm, Some(1000));
+        assert!(matches!(error, Some(Error::RowLimitExceeded(50))));
+    }
+
+    #[test]
+    fn test_pragma_policy() {
+        let ctx = &mut ();
+        let conn = setup_test_conn(ctx);
+        let query = Program::seq(&["pragma user_version = 7"]).steps[0]
+            .query
+            .clone();
+
+        let res = conn.execute_query(&query, &DatabaseConfig::default(), &mut IgnoreResult);
+        assert!(matches!(res, Err(Error::PragmaNotAllowed(name)) if name == "user_version"));
+
+        let config = DatabaseConfig {
+            pragma_allowlist: Some(vec!["user_version=".into()]),
+            ..Default::default()
+        };
+        conn.execute_query(&query, &config, &mut IgnoreResult)
+            .unwrap();
+        let version: i64 = conn
+            .conn
+            .query_row("pragma user_version", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 7);
+    }
+
+    #[test]
+    fn test_apply_page_size() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(matches!(
+            apply_page_size(&conn, 8192),
+            Err(Error::UnsupportedPageSize(8192))
+        ));
+        apply_page_size(&conn, 4096).unwrap();
+        conn.execute("create table test (x)", ()).unwrap();
+        // the database already has this page size
+        apply_page_size(&conn, 4096).unwrap();
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("pragma page_size = 8192; create table test (x)")
+            .unwrap();
+        assert!(matches!(
+            apply_page_size(&conn, 4096),
+            Err(Error::PageSizeChange {
+                current: 8192,
+                requested: 4096
+            })
+        ));
+    }
+
+    #[test]
+    fn test_query_memory_limit() {
+        let ctx = &mut ();
+        let conn = setup_test_conn(ctx);
+        let sql = "with recursive c(x) as (select 1 union all select x + 1 from c limit 100000) \
+            select x from c order by x desc";
+        let mut query = Program::seq(&[sql]).steps[0].query.clone();
+
+        query.max_memory_bytes = Some(1);
+        let res = conn.execute_query(&query, &DatabaseConfig::default(), &mut IgnoreResult);
+        assert!(matches!(res, Err(Error::MemoryLimitExceeded(1))));
+
+        // the handler is removed after the query, so that it doesn't affect the next ones
+        query.max_memory_bytes = None;
+        conn.execute_query(&query, &DatabaseConfig::default(), &mut IgnoreResult)
+            .unwrap();
+
+        // only the memory allocated by the query counts, not the memory SQLite used before it
+        let limit = 16 * 1024;
+        assert!(memory_used() > limit);
+        let mut query = Program::seq(&["select 1"]).steps[0].query.clone();
+        query.max_memory_bytes = Some(limit);
+        conn.execute_query(&query, &DatabaseConfig::default(), &mut IgnoreResult)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_statement_counts() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        conn.run(
+            Program::seq(&[
+                "begin",
+                "create index test_x on test (x)",
+                "insert into test values (1)",
+                "select * from test",
+                "commit",
+            ]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        // the setup created the table and inserted 100 rows
+        assert_eq!(
+            conn.statement_counts.by_class(),
+            [
+                ("read", 1),
+                ("write", 101),
+                ("ddl", 2),
+                ("transaction_control", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_write() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let last_write = conn.statement_counts.last_write_ms().unwrap();
+
+        conn.run(Program::seq(&["select * from test"]), IgnoreResult)
+            .unwrap();
+        assert_eq!(conn.statement_counts.last_write_ms(), Some(last_write));
+
+        // a failed write doesn't count
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        conn.run(
+            Program::seq(&["insert into missing values (1)"]),
+            IgnoreResult,
+        )
+        .unwrap();
+        assert_eq!(conn.statement_counts.last_write_ms(), Some(last_write));
+
+        conn.run(Program::seq(&["insert into test values (1)"]), IgnoreResult)
+            .unwrap();
+        assert!(conn.statement_counts.last_write_ms().unwrap() > last_write);
+    }
+
+    #[test]
+    fn test_slow_queries() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let tmp = tempfile::tempdir().unwrap();
+        conn.config_store = Arc::new(DatabaseConfigStore::load(tmp.path()).unwrap());
+
+        // no query is slow while the threshold is not set
+        conn.run(Program::seq(&["select * from test"]), IgnoreResult)
+            .unwrap();
+        assert!(conn.slow_queries.list().is_empty());
+
+        conn.config_store
+            .store(DatabaseConfig {
+                slow_query_threshold_us: Some(0),
+                ..Default::default()
+            })
+            .unwrap();
+        let long_sql = format!("select * from test where x != '{}'", "a".repeat(1000));
+        conn.run(
+            Program::seq(&["select * from test", &long_sql]),
+            IgnoreResult,
+        )
+        .unwrap();
+        let slow_queries = conn.slow_queries.list();
+        assert_eq!(slow_queries.len(), 1);
+        assert_eq!(slow_queries[0].rows, 200);
+        assert_eq!(slow_queries[0].sql.chars().count(), MAX_SLOW_QUERY_SQL_LEN);
+        assert!(slow_queries[0]
+            .sql
+            .starts_with("select * from test; select"));
+
+        // only the last queries are kept
+        for _ in 0..crate::stats::MAX_SLOW_QUERIES {
+            conn.run(Program::seq(&["select 1"]), IgnoreResult).unwrap();
+        }
+        let slow_queries = conn.slow_queries.list();
+        assert_eq!(slow_queries.len(), crate::stats::MAX_SLOW_QUERIES);
+        assert!(slow_queries.iter().all(|q| q.sql == "select 1"));
+    }
+
+    #[test]
+    fn test_schema_events() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let mut events = conn.schema_events.subscribe();
+
+        conn.run(
+            Program::seq(&[
+                "insert into test values ('a')",
+                "create table other (y)",
+                "create index idx on missing (z)",
+            ]),
+            IgnoreResult,
+        )
+        .unwrap();
+        let schema_version: i64 = conn
+            .conn
+            .query_row("PRAGMA schema_version", (), |row| row.get(0))
+            .unwrap();
+        // only the DDL statements that succeeded are reported
+        match events.try_recv().unwrap() {
+            SchemaEvent::SchemaChange {
+                schema_version: version,
+                statement,
+            } => {
+                assert_eq!(version, schema_version);
+                assert_eq!(statement, "create table other (y)");
+            }
+        }
+        assert!(events.try_recv().is_err());
+
+        let pgm = Program::seq(&["alter table other add column z"])
+            .into_dry_run()
+            .unwrap();
+        conn.run(pgm, IgnoreResult).unwrap();
+        assert!(events.try_recv().is_err());
+
+        // the changes of a transaction are reported once it commits, and never if it rolls back
+        let statement = |events: &mut broadcast::Receiver<SchemaEvent>| match events.try_recv() {
+            Ok(SchemaEvent::SchemaChange { statement, .. }) => Some(statement),
+            Err(_) => None,
+        };
+        conn.run(
+            Program::seq(&["begin", "create table t1 (x)"]),
+            IgnoreResult,
+        )
+        .unwrap();
+        assert_eq!(statement(&mut events), None);
+        conn.run(Program::seq(&["rollback"]), IgnoreResult).unwrap();
+        assert_eq!(statement(&mut events), None);
+
+        conn.run(
+            Program::seq(&["begin", "create table t2 (x)"]),
+            IgnoreResult,
+        )
+        .unwrap();
+        conn.run(
+            Program::seq(&["create table t3 (x)", "commit"]),
+            IgnoreResult,
+        )
+        .unwrap();
+        assert_eq!(
+            statement(&mut events).as_deref(),
+            Some("create table t2 (x)")
+        );
+        assert_eq!(
+            statement(&mut events).as_deref(),
+            Some("create table t3 (x)")
+        );
+        assert_eq!(statement(&mut events), None);
+    }
+
+    #[test]
+    fn test_cached_statements_stats() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        fn rows_read(conn: &mut Connection) -> u64 {
+            let before = conn.stats.rows_read();
+            conn.run(Program::seq(&["select * from test"]), IgnoreResult)
+                .unwrap();
+            conn.stats.rows_read() - before
+        }
+        let uncached = rows_read(&mut conn);
+
+        conn.builder_config.statement_cache_size = 16;
+        conn.conn.set_prepared_statement_cache_capacity(16);
+        // the rows read by an execution are not counted again by the next ones
+        assert_eq!(rows_read(&mut conn), uncached);
+        assert_eq!(rows_read(&mut conn), uncached);
+    }
+
+    #[test]
+    fn test_max_db_size() {
+        fn step_error(conn: &mut Connection, sql: &str) -> Result<Option<Error>> {
+            let calls = conn
+                .run(Program::seq(&[sql]), StepRecorder::default())?
+                .into_ret();
+            Ok(calls.into_iter().find_map(|call| match call {
+                RecordedCall::StepError(e) => Some(e),
+                _ => None,
+            }))
+        }
+
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let size = conn.db_size().unwrap();
+        let max_db_size = size + 150_000;
+        conn.config_store = default_limits(DefaultLimits {
+            max_db_size: Some(max_db_size),
+            ..Default::default()
+        });
+
+        let write = "insert into test values (zeroblob(100000))";
+        assert!(step_error(&mut conn, write).unwrap().is_none());
+        assert!(conn.db_size().unwrap() > size);
+        // the write that would grow the database past its quota fails
+        assert!(matches!(
+            step_error(&mut conn, write).unwrap(),
+            Some(Error::DatabaseFull(_))
+        ));
+        assert!(conn.db_size().unwrap() <= max_db_size);
+        assert!(step_error(&mut conn, "select * from test")
+            .unwrap()
+            .is_none());
+
+        // a database over its quota refuses the writes, except those which free space
+        conn.config_store = default_limits(DefaultLimits {
+            max_db_size: Some(size + 50_000),
+            ..Default::default()
+        });
+        assert!(matches!(
+            step_error(&mut conn, write),
+            Err(Error::DatabaseFull(_))
+        ));
+        assert!(step_error(&mut conn, "delete from test").unwrap().is_none());
+        assert!(step_error(&mut conn, "insert into test values (1)")
+            .unwrap()
+            .is_none());
+
+        conn.config_store = default_limits(DefaultLimits {
+            max_db_size: Some(1),
+            ..Default::default()
+        });
+        assert!(matches!(
+            step_error(&mut conn, "create table other (x)"),
+            Err(Error::DatabaseFull(_))
+        ));
+        assert!(step_error(&mut conn, "drop table test").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_load_dump_max_db_size() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        let max_db_size = conn.db_size().unwrap() + 150_000;
+        conn.config_store = default_limits(DefaultLimits {
+            max_db_size: Some(max_db_size),
+            ..Default::default()
+        });
+
+        // the database is under its quota when the load starts, but the dump grows it past it
+        let dump = "create table big (x);\n".to_owned()
+            + &"insert into big values (zeroblob(100000));\n".repeat(5);
+        let options = LoadDumpOptions {
+            allow_non_empty: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            conn.load_dump(dump.as_bytes(), options),
+            Err(Error::DatabaseFull(_))
+        ));
+        // the load was rolled back
+        assert!(conn.db_size().unwrap() <= max_db_size);
+        let tables: i64 = conn
+            .conn
+            .query_row(
+                "select count(*) from sqlite_schema where name = 'big'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let ctx = &mut ();
+        let conn = setup_test_conn(ctx);
+        assert_eq!(conn.integrity_check().unwrap(), vec!["ok".to_string()]);
+    }
+
+    #[test]
+    fn test_schema() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        conn.run(
+            Program::seq(&[
+                "create table users (id integer primary key, name text not null default 'anon')",
+                "create index users_name on users (name)",
+                "create view names as select name from users",
+                "create trigger users_insert after insert on users begin select 1; end",
+            ]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        let schema = conn.schema().unwrap();
+        let tables: Vec<_> = schema.tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(tables, ["test", "users"]);
+        let users = &schema.tables[1];
+        assert_eq!(users.columns.len(), 2);
+        assert!(users.columns[0].primary_key);
+        assert_eq!(users.columns[0].decl_type, "INTEGER");
+        assert!(users.columns[1].not_null);
+        assert_eq!(users.columns[1].default.as_deref(), Some("'anon'"));
+        assert_eq!(users.indexes[0].name, "users_name");
+        assert_eq!(users.indexes[0].columns, [Some("name".to_string())]);
+        assert_eq!(users.triggers[0].name, "users_insert");
+        assert_eq!(schema.views[0].name, "names");
+        assert!(schema.version > 0);
+    }
+
+    #[test]
+    fn test_describe_query_plan() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        conn.run(
+            Program::seq(&["create table users (id integer primary key, name text)"]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        let sql = "select name from users where id = ?";
+        assert!(conn.describe(sql, false).unwrap().query_plan.is_empty());
+        let plan = conn.describe(sql, true).unwrap().query_plan;
+        assert_eq!(plan.len(), 1);
+        assert!(plan[0].detail.contains("users USING INTEGER PRIMARY KEY"));
+
+        // the plan of an EXPLAIN statement is not explained
+        let explain = conn.describe(&format!("explain {sql}"), true).unwrap();
+        assert!(explain.is_explain);
+        assert!(explain.query_plan.is_empty());
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let ctx = &mut ();
+        let mut conn = setup_test_conn(ctx);
+        conn.run(Program::seq(&["delete from test"]), IgnoreResult)
+            .unwrap();
+
+        conn.run(Program::seq(&["begin"]), IgnoreResult).unwrap();
+        assert!(matches!(conn.vacuum(), Err(Error::QueryError(_))));
+        conn.run(Program::seq(&["commit"]), IgnoreResult).unwrap();
+
+        conn.vacuum().unwrap();
+        let freelist_count: i64 = conn
+            .conn
+            .query_row("pragma freelist_count", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(freelist_count, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parallel_steps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let readers = Arc::new(ReaderPool::new({
+            let path = tmp.path().to_owned();
+            move || {
+                let config_store = Arc::new(DatabaseConfigStore::new_test());
+                Box::pin(open_test_conn(
+                    path.clone(),
+                    Arc::new([]),
+                    config_store,
+                    None,
+                ))
+            }
+        }));
+        let conn = open_test_conn(
+            tmp.path().to_owned(),
+            Arc::new([]),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Some(readers.clone()),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        conn.execute_program(
+            Program::seq(&["create table t (x)", "insert into t values (1), (2)"]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        let mut pgm = Program::seq(&[
+            "select x from t where x = 1",
+            "select x from t where x = 2",
+            "select count(*) from t",
+            "insert into t values (3)",
+            "select count(*) from t",
+        ]);
+        pgm.parallel = true;
+        let (recorder, _) = conn
+            .execute_program(pgm, auth, StepRecorder::default())
+            .await
+            .unwrap();
+        let values: Vec<_> = recorder
+            .into_ret()
+            .into_iter()
+            .filter_map(|call| match call {
+                RecordedCall::AddRowValue(v) => Some(v),
+                _ => None,
+            })
+            .collect();
+        use rusqlite::types::Value::Integer;
+        assert_eq!(values, [Integer(1), Integer(2), Integer(2), Integer(3)]);
+        // the readers went back to the pool
+        assert!(!readers.idle.lock().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reader_pool_is_bounded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opened = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let readers = Arc::new(ReaderPool::new({
+            let path = tmp.path().to_owned();
+            let opened = opened.clone();
+            move || {
+                opened.fetch_add(1, Ordering::Relaxed);
+                let config_store = Arc::new(DatabaseConfigStore::new_test());
+                Box::pin(open_test_conn(
+                    path.clone(),
+                    Arc::new([]),
+                    config_store,
+                    None,
+                ))
+            }
+        }));
+        let conn = open_test_conn(
+            tmp.path().to_owned(),
+            Arc::new([]),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Some(readers.clone()),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        conn.execute_program(
+            Program::seq(&["create table t (x)", "insert into t values (1), (2)"]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+        let select_parallel = || async {
+            let mut pgm =
+                Program::seq(&["select x from t where x = 1", "select x from t where x = 2"]);
+            pgm.parallel = true;
+            let (recorder, _) = conn
+                .execute_program(pgm, auth, StepRecorder::default())
+                .await
+                .unwrap();
+            recorder
+                .into_ret()
+                .into_iter()
+                .filter_map(|call| match call {
+                    RecordedCall::AddRowValue(v) => Some(v),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        use rusqlite::types::Value::Integer;
+
+        let taken = tokio::task::spawn_blocking({
+            let readers = readers.clone();
+            move || {
+                let taken = (0..MAX_PARALLEL_STEPS)
+                    .map(|_| readers.get().unwrap().unwrap())
+                    .collect::<Vec<_>>();
+                assert!(readers.get().unwrap().is_none());
+                taken
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(opened.load(Ordering::Relaxed), MAX_PARALLEL_STEPS);
+
+        // the steps run on the connection of the program while every reader is in use
+        assert_eq!(select_parallel().await, [Integer(1), Integer(2)]);
+        assert_eq!(opened.load(Ordering::Relaxed), MAX_PARALLEL_STEPS);
+
+        // the readers that are put back are reused
+        for reader in taken {
+            readers.put(reader);
+        }
+        assert_eq!(select_parallel().await, [Integer(1), Integer(2)]);
+        assert_eq!(opened.load(Ordering::Relaxed), MAX_PARALLEL_STEPS);
+        assert_eq!(readers.idle.lock().len(), MAX_PARALLEL_STEPS);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_db() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_store = Arc::new(DatabaseConfigStore::load(tmp.path()).unwrap());
+        config_store
+            .store(DatabaseConfig {
+                in_memory: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let open = || {
+            open_test_conn(
+                tmp.path().to_owned(),
+                Arc::new([]),
+                config_store.clone(),
+                None,
+            )
+        };
+        let count = |conn: LibSqlConnection| async move {
+            let (recorder, _) = conn
+                .execute_program(
+                    Program::seq(&["select count(*) from t"]),
+                    Authenticated::Authorized(Authorized::FullAccess),
+                    StepRecorder::default(),
+                )
+                .await
+                .unwrap();
+            recorder.into_ret().into_iter().find_map(|call| match call {
+                RecordedCall::AddRowValue(v) => Some(v),
+                RecordedCall::StepError(e) => panic!("{e}"),
+                _ => None,
+            })
+        };
+
+        let conn = open().await.unwrap();
+        conn.execute_program(
+            Program::seq(&["create table t (x)", "insert into t values (1), (2)"]),
+            Authenticated::Authorized(Authorized::FullAccess),
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+        // the connections share the database, which is not written to disk
+        assert_eq!(
+            count(open().await.unwrap()).await,
+            Some(rusqlite::types::Value::Integer(2))
+        );
+        assert!(!tmp.path().join("data").exists());
+    }
+
+    #[tokio::test]
+    async fn test_attach_allowed_namespace() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dbs = tmp.path().join("dbs");
+        for ns in ["primary", "other", "forbidden"] {
+            std::fs::create_dir_all(dbs.join(ns)).unwrap();
+        }
+        let open =
+            |ns: &str, config_store| open_test_conn(dbs.join(ns), Arc::new([]), config_store, None);
+        let execute = |conn: LibSqlConnection, sql: &'static str| async move {
+            let (recorder, _) = conn
+                .execute_program(
+                    Program::seq(&[sql]),
+                    Authenticated::Authorized(Authorized::FullAccess),
+                    StepRecorder::default(),
+                )
+                .await
+                .unwrap();
+            recorder.into_ret().into_iter().find_map(|call| match call {
+                RecordedCall::AddRowValue(v) => Some(Ok(v)),
+                RecordedCall::StepError(e) => Some(Err(e)),
+                _ => None,
+            })
+        };
+
+        for ns in ["other", "forbidden"] {
+            let conn = open(ns, Arc::new(DatabaseConfigStore::new_test()))
+                .await
+                .unwrap();
+            conn.execute_program(
+                Program::seq(&["create table t (x)", "insert into t values (42)"]),
+                Authenticated::Authorized(Authorized::FullAccess),
+                IgnoreResult,
+            )
+            .await
+            .unwrap();
+        }
+
+        let config_store = Arc::new(DatabaseConfigStore::load(&dbs.join("primary")).unwrap());
+        config_store
+            .store(DatabaseConfig {
+                attach_allowlist: vec!["other".into()],
+                ..Default::default()
+            })
+            .unwrap();
+        let conn = open("primary", config_store.clone()).await.unwrap();
+
+        // attaching is disabled until it's allowed, even for the namespaces of the allowlist
+        assert!(matches!(
+            execute(conn.clone(), "attach 'other' as o").await,
+            Some(Err(Error::AttachDisabled))
+        ));
+        config_store
+            .store(DatabaseConfig {
+                allow_attach: true,
+                attach_allowlist: vec!["other".into()],
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert!(execute(conn.clone(), "attach 'other' as o").await.is_none());
+        assert_eq!(
+            execute(conn.clone(), "select x from o.t")
+                .await
+                .unwrap()
+                .unwrap(),
+            rusqlite::types::Value::Integer(42)
+        );
+        // the attached database is read-only
+        assert!(matches!(
+            execute(conn.clone(), "insert into o.t values (1)").await,
+            Some(Err(Error::RusqliteError(_)))
+        ));
+        assert!(execute(conn.clone(), "detach o").await.is_none());
+
+        assert!(matches!(
+            execute(conn.clone(), "attach 'forbidden' as f").await,
+            Some(Err(Error::AttachNotAllowed(ns))) if ns == "forbidden"
+        ));
+        // namespaces outside the allowlist are rejected the same way whether they exist or not
+        assert!(matches!(
+            execute(conn.clone(), "attach 'missing' as m").await,
+            Some(Err(Error::AttachNotAllowed(ns))) if ns == "missing"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_modified_extension_is_not_loaded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ext_dir = tmp.path().join("extensions");
+        std::fs::create_dir(&ext_dir).unwrap();
+        std::fs::write(ext_dir.join("ext.so"), "extension").unwrap();
+        std::fs::write(
+            ext_dir.join("trusted.lst"),
+            format!("{}  ext.so\n", sha256::digest("extension")),
+        )
+        .unwrap();
+        let db_config = crate::config::DbConfig {
+            extensions_path: Some(ext_dir.clone().into()),
+            ..crate::config::DbConfig::new_test()
+        };
+        let extensions = db_config.validate_extensions().unwrap();
+
+        std::fs::write(ext_dir.join("ext.so"), "swapped extension").unwrap();
+        let res = open_test_conn(
+            tmp.path().to_owned(),
+            extensions,
+            Arc::new(DatabaseConfigStore::new_test()),
+            None,
+        )
+        .await;
+        let Err(e) = res else {
+            panic!("the modified extension was loaded")
+        };
+        assert!(e.to_string().contains("sha256 differs"), "{e}");
+    }
+
     #[test]
     fn test_libsql_conn_builder_driver() {
         test_driver(1000, |b| {
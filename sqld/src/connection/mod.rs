@@ -1,23 +1,33 @@
+use std::io::BufRead;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::Future;
+use metrics::histogram;
 use tokio::{sync::Semaphore, time::timeout};
 
 use crate::auth::Authenticated;
 use crate::error::Error;
+use crate::heap_limit::{self, NamespaceHeap};
 use crate::query::{Params, Query};
 use crate::query_analysis::{State, Statement};
 use crate::query_result_builder::{IgnoreResult, QueryResultBuilder};
 use crate::Result;
 
+use self::dump::loader::{LoadDumpOptions, LoadDumpStats};
 use self::program::{Cond, DescribeResult, Program, Step};
+use self::schema::Schema;
 
+pub mod coalescing;
 pub mod config;
 pub mod dump;
 pub mod libsql;
+pub mod pragma;
 pub mod program;
+pub mod reaper;
+pub mod schema;
+pub mod warm;
 pub mod write_proxy;
 
 const TXN_TIMEOUT: Duration = Duration::from_secs(5);
@@ -32,6 +42,26 @@ pub trait Connection: Send + Sync + 'static {
         reponse_builder: B,
     ) -> Result<(B, State)>;
 
+    /// Executes a query program inside a savepoint that is always rolled back, so that none of its
+    /// changes persist. The builder receives the results of the program steps only.
+    async fn execute_program_dry_run<B: QueryResultBuilder>(
+        &self,
+        pgm: Program,
+        auth: Authenticated,
+        response_builder: B,
+    ) -> Result<(B, State)> {
+        let steps_len = pgm.steps.len();
+        let pgm = pgm.into_dry_run().ok_or_else(|| {
+            Error::QueryError("transaction statements are not allowed in a dry run".into())
+        })?;
+
+        // skip the savepoint step, and ignore the rollback steps
+        let builder = response_builder.take(steps_len).skip(1);
+        let (builder, state) = self.execute_program(pgm, auth, builder).await?;
+
+        Ok((builder.into_inner().into_inner(), state))
+    }
+
     /// Execute all the queries in the batch sequentially.
     /// If an query in the batch fails, the remaining queries are ignores, and the batch current
     /// transaction (if any) is rolledback.
@@ -51,6 +81,8 @@ pub trait Connection: Send + Sync + 'static {
                     stmt: Statement::parse("ROLLBACK").next().unwrap().unwrap(),
                     params: Params::empty(),
                     want_rows: false,
+                    max_memory_bytes: None,
+                    max_rows: None,
                 },
                 cond: Some(Cond::Not {
                     cond: Box::new(Cond::Ok {
@@ -88,6 +120,8 @@ pub trait Connection: Send + Sync + 'static {
                 stmt: Statement::parse("ROLLBACK").next().unwrap().unwrap(),
                 params: Params::empty(),
                 want_rows: false,
+                max_memory_bytes: None,
+                max_rows: None,
             }],
             auth,
             IgnoreResult,
@@ -97,14 +131,48 @@ pub trait Connection: Send + Sync + 'static {
         Ok(())
     }
 
-    /// Parse the SQL statement and return information about it.
-    async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult>;
+    /// Parse the SQL statement and return information about it, including its query plan if
+    /// `query_plan` is set.
+    async fn describe(
+        &self,
+        sql: String,
+        auth: Authenticated,
+        query_plan: bool,
+    ) -> Result<DescribeResult>;
 
     /// Check whether the connection is in autocommit mode.
     async fn is_autocommit(&self) -> Result<bool>;
 
     /// Calls for database checkpoint (if supported).
     async fn checkpoint(&self) -> Result<()>;
+
+    /// Rebuilds the database file to reclaim its free pages, with `VACUUM`. This requires full
+    /// access.
+    ///
+    /// The vacuum holds the write lock of the database until it completes, so it can't run
+    /// concurrently with other writes: it fails with [`Error::VacuumConflict`] if another write
+    /// transaction is open, and other writes can't start until it is done. It can't be run from
+    /// inside a transaction either.
+    async fn vacuum(&self, auth: Authenticated) -> Result<()>;
+
+    /// Runs `PRAGMA integrity_check` against the local copy of the database, returning the lines
+    /// it reported, which are just `ok` if no problem was found.
+    async fn integrity_check(&self) -> Result<Vec<String>>;
+
+    /// Describes the schema of the local copy of the database.
+    async fn schema(&self, auth: Authenticated) -> Result<Schema>;
+
+    /// Executes the SQL dump read from `dump` in a single transaction, see
+    /// [`dump::loader::load_dump`], or in batches if `options` has a batch size, see
+    /// [`dump::loader::load_dump_in_batches`]. This requires full access.
+    ///
+    /// The dump is read from the thread of the connection, so reading it may block.
+    async fn load_dump(
+        &self,
+        dump: Box<dyn BufRead + Send>,
+        options: LoadDumpOptions,
+        auth: Authenticated,
+    ) -> Result<LoadDumpStats>;
 }
 
 fn make_batch_program(batch: Vec<Query>) -> Vec<Step> {
@@ -135,11 +203,18 @@ pub trait MakeConnection: Send + Sync + 'static {
         conccurency: usize,
         timeout: Option<Duration>,
         max_total_response_size: u64,
+        namespace: String,
     ) -> MakeThrottledConnection<Self>
     where
         Self: Sized,
     {
-        MakeThrottledConnection::new(conccurency, self, timeout, max_total_response_size)
+        MakeThrottledConnection::new(
+            conccurency,
+            self,
+            timeout,
+            max_total_response_size,
+            namespace,
+        )
     }
 }
 
@@ -166,6 +241,11 @@ pub struct MakeThrottledConnection<F> {
     // will result in reducing concurrency to prevent out-of-memory errors.
     max_total_response_size: u64,
     waiters: AtomicUsize,
+    /// Name of the namespace of the connections, labelling their creation metrics.
+    namespace: String,
+    /// Heap of the namespace. Its usage also reduces concurrency, so that a namespace close to its
+    /// soft heap limit doesn't push itself over it with more connections.
+    heap: Arc<NamespaceHeap>,
 }
 
 impl<F> MakeThrottledConnection<F> {
@@ -174,6 +254,7 @@ impl<F> MakeThrottledConnection<F> {
         connection_maker: F,
         timeout: Option<Duration>,
         max_total_response_size: u64,
+        namespace: String,
     ) -> Self {
         Self {
             semaphore: Arc::new(Semaphore::new(conccurency)),
@@ -181,15 +262,19 @@ impl<F> MakeThrottledConnection<F> {
             timeout,
             max_total_response_size,
             waiters: AtomicUsize::new(0),
+            heap: heap_limit::namespace_heap(&namespace),
+            namespace,
         }
     }
 
     // How many units should be acquired from the semaphore,
     // depending on current memory pressure.
+    // The pressure comes from the responses of all namespaces, or from the heap of this namespace
+    // nearing its soft limit, and the highest of the two wins.
     fn units_to_take(&self) -> u32 {
         let total_response_size = crate::query_result_builder::TOTAL_RESPONSE_SIZE
             .load(std::sync::atomic::Ordering::Relaxed) as u64;
-        if total_response_size * 2 > self.max_total_response_size {
+        let response_units = if total_response_size * 2 > self.max_total_response_size {
             tracing::trace!("High memory pressure, reducing concurrency");
             16
         } else if total_response_size * 4 > self.max_total_response_size {
@@ -197,7 +282,25 @@ impl<F> MakeThrottledConnection<F> {
             4
         } else {
             1
+        };
+        let heap_units = heap_units_to_take(self.heap.soft_limit_usage());
+        if heap_units > 1 {
+            tracing::trace!(
+                "Namespace `{}` is close to its soft heap limit, reducing concurrency",
+                self.namespace
+            );
         }
+        response_units.max(heap_units)
+    }
+}
+
+/// How many units should be acquired from the semaphore of a namespace, depending on the fraction
+/// of its soft heap limit which is in use.
+fn heap_units_to_take(soft_limit_usage: Option<f64>) -> u32 {
+    match soft_limit_usage {
+        Some(usage) if usage > 1.0 => 16,
+        Some(usage) if usage > 0.75 => 4,
+        _ => 1,
     }
 }
 
@@ -233,6 +336,7 @@ impl<F: MakeConnection> MakeConnection for MakeThrottledConnection<F> {
         if waiters_guard.waiters.load(Ordering::Relaxed) >= 128 {
             return Err(Error::TooManyRequests);
         }
+        let wait_start = Instant::now();
         let fut = self.semaphore.clone().acquire_many_owned(units);
         let mut permit = match self.timeout {
             Some(t) => timeout(t, fut).await.map_err(|_| Error::DbCreateTimeout)?,
@@ -251,8 +355,19 @@ impl<F: MakeConnection> MakeConnection for MakeThrottledConnection<F> {
             .expect("semaphore closed");
             permit.merge(mem_permit);
         }
+        histogram!(
+            "connection_create_wait_seconds",
+            wait_start.elapsed(),
+            "namespace" => self.namespace.clone()
+        );
 
+        let create_start = Instant::now();
         let inner = self.connection_maker.create().await?;
+        histogram!(
+            "connection_create_seconds",
+            create_start.elapsed(),
+            "namespace" => self.namespace.clone()
+        );
         Ok(TrackedConnection { permit, inner })
     }
 }
@@ -276,8 +391,13 @@ impl<DB: Connection> Connection for TrackedConnection<DB> {
     }
 
     #[inline]
-    async fn describe(&self, sql: String, auth: Authenticated) -> crate::Result<DescribeResult> {
-        self.inner.describe(sql, auth).await
+    async fn describe(
+        &self,
+        sql: String,
+        auth: Authenticated,
+        query_plan: bool,
+    ) -> crate::Result<DescribeResult> {
+        self.inner.describe(sql, auth, query_plan).await
     }
 
     #[inline]
@@ -289,6 +409,31 @@ impl<DB: Connection> Connection for TrackedConnection<DB> {
     async fn checkpoint(&self) -> Result<()> {
         self.inner.checkpoint().await
     }
+
+    #[inline]
+    async fn vacuum(&self, auth: Authenticated) -> Result<()> {
+        self.inner.vacuum(auth).await
+    }
+
+    #[inline]
+    async fn integrity_check(&self) -> Result<Vec<String>> {
+        self.inner.integrity_check().await
+    }
+
+    #[inline]
+    async fn schema(&self, auth: Authenticated) -> Result<Schema> {
+        self.inner.schema(auth).await
+    }
+
+    #[inline]
+    async fn load_dump(
+        &self,
+        dump: Box<dyn BufRead + Send>,
+        options: LoadDumpOptions,
+        auth: Authenticated,
+    ) -> Result<LoadDumpStats> {
+        self.inner.load_dump(dump, options, auth).await
+    }
 }
 
 #[cfg(test)]
@@ -312,6 +457,7 @@ mod test {
             &self,
             _sql: String,
             _auth: Authenticated,
+            _query_plan: bool,
         ) -> crate::Result<DescribeResult> {
             unreachable!()
         }
@@ -323,12 +469,37 @@ mod test {
         async fn checkpoint(&self) -> Result<()> {
             unreachable!()
         }
+
+        async fn vacuum(&self, _auth: Authenticated) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn integrity_check(&self) -> Result<Vec<String>> {
+            unreachable!()
+        }
+
+        async fn schema(&self, _auth: Authenticated) -> Result<Schema> {
+            unreachable!()
+        }
+
+        async fn load_dump(
+            &self,
+            _dump: Box<dyn BufRead + Send>,
+            _options: LoadDumpOptions,
+            _auth: Authenticated,
+        ) -> Result<LoadDumpStats> {
+            unreachable!()
+        }
     }
 
     #[tokio::test]
     async fn throttle_db_creation() {
-        let factory =
-            (|| async { Ok(DummyDb) }).throttled(10, Some(Duration::from_millis(100)), u64::MAX);
+        let factory = (|| async { Ok(DummyDb) }).throttled(
+            10,
+            Some(Duration::from_millis(100)),
+            u64::MAX,
+            "test".into(),
+        );
 
         let mut conns = Vec::with_capacity(10);
         for _ in 0..10 {
@@ -341,4 +512,12 @@ mod test {
 
         assert!(factory.create().await.is_ok());
     }
+
+    #[test]
+    fn heap_usage_reduces_concurrency() {
+        assert_eq!(heap_units_to_take(None), 1);
+        assert_eq!(heap_units_to_take(Some(0.5)), 1);
+        assert_eq!(heap_units_to_take(Some(0.8)), 4);
+        assert_eq!(heap_units_to_take(Some(1.2)), 16);
+    }
 }
@@ -0,0 +1,140 @@
+//! Policy deciding which `PRAGMA` statements the connections to a namespace may execute.
+//!
+//! Some pragmas change assumptions that the replication logger and the WAL make about the
+//! database, or lift its quota, and are always rejected. The other pragmas must be allowed by the
+//! `pragma_allowlist` of the config of the namespace, or by [DEFAULT_PRAGMA_ALLOWLIST] when it is
+//! not set. An entry `name` of the allowlist allows reading the pragma, and an entry `name=`
+//! allows setting it too.
+
+use crate::error::Error;
+use crate::query_analysis::Pragma;
+use crate::Result;
+
+/// Pragmas which are rejected on every namespace, whether they are read or set.
+const DENIED_PRAGMAS: &[&str] = &[
+    "data_store_directory",
+    "hard_heap_limit",
+    "journal_mode",
+    "journal_size_limit",
+    "locking_mode",
+    "max_page_count",
+    "soft_heap_limit",
+    "synchronous",
+    "temp_store_directory",
+    "wal_autocheckpoint",
+    "wal_checkpoint",
+    "writable_schema",
+];
+
+/// Pragmas allowed on the namespaces whose config has no `pragma_allowlist`: the pragmas which
+/// only read the schema or the state of the database, and `foreign_keys`, which is set by dumps
+/// and by most ORMs.
+pub const DEFAULT_PRAGMA_ALLOWLIST: &[&str] = &[
+    "application_id",
+    "collation_list",
+    "compile_options",
+    "data_version",
+    "database_list",
+    "encoding",
+    "foreign_key_check",
+    "foreign_key_list",
+    "foreign_keys",
+    "foreign_keys=",
+    "freelist_count",
+    "function_list",
+    "index_info",
+    "index_list",
+    "index_xinfo",
+    "integrity_check",
+    "module_list",
+    "page_count",
+    "page_size",
+    "pragma_list",
+    "quick_check",
+    "schema_version",
+    "table_info",
+    "table_list",
+    "table_xinfo",
+    "user_version",
+];
+
+/// Checks that `pragma` is allowed by `allowlist`, or by [DEFAULT_PRAGMA_ALLOWLIST] when it is
+/// `None`.
+pub fn check_pragma(pragma: &Pragma, allowlist: Option<&[String]>) -> Result<()> {
+    let name = pragma.name.as_str();
+    let allowed = |entry: &str| match entry.strip_suffix('=') {
+        Some(entry) => entry.eq_ignore_ascii_case(name),
+        None => !pragma.sets_value && entry.eq_ignore_ascii_case(name),
+    };
+    let allowed = !DENIED_PRAGMAS.contains(&name)
+        && match allowlist {
+            Some(allowlist) => allowlist.iter().any(|entry| allowed(entry)),
+            None => DEFAULT_PRAGMA_ALLOWLIST.iter().any(|entry| allowed(entry)),
+        };
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::PragmaNotAllowed(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::query_analysis::Statement;
+
+    use super::*;
+
+    fn check(sql: &str, allowlist: Option<&[String]>) -> Result<()> {
+        let stmt = Statement::parse(sql).next().unwrap().unwrap();
+        check_pragma(stmt.pragma.as_ref().unwrap(), allowlist)
+    }
+
+    #[test]
+    fn default_pragma_policy() {
+        for sql in [
+            "PRAGMA table_info(t)",
+            "PRAGMA main.table_info('t')",
+            "PRAGMA TABLE_LIST",
+            "PRAGMA user_version",
+            "PRAGMA \"user_version\"",
+            "PRAGMA foreign_keys = ON",
+        ] {
+            assert!(check(sql, None).is_ok(), "{sql}");
+        }
+
+        for sql in [
+            "PRAGMA user_version = 5",
+            "PRAGMA user_version(5)",
+            "PRAGMA main.user_version = 5",
+            "PRAGMA cache_size = 100",
+            "PRAGMA case_sensitive_like = ON",
+            "PRAGMA journal_mode",
+            "PRAGMA journal_mode = DELETE",
+            "PRAGMA main.synchronous = OFF",
+            "PRAGMA locking_mode = EXCLUSIVE",
+            "PRAGMA wal_checkpoint(TRUNCATE)",
+            "PRAGMA writable_schema = ON",
+            "PRAGMA unknown_pragma",
+        ] {
+            assert!(
+                matches!(check(sql, None), Err(Error::PragmaNotAllowed(_))),
+                "{sql}"
+            );
+        }
+    }
+
+    #[test]
+    fn pragma_allowlist() {
+        let allowlist = ["table_info".to_string(), "user_version=".to_string()];
+        let allowlist = Some(&allowlist[..]);
+        assert!(check("PRAGMA table_info(t)", allowlist).is_ok());
+        assert!(check("PRAGMA user_version", allowlist).is_ok());
+        assert!(check("PRAGMA user_version = 5", allowlist).is_ok());
+        // the allowlist replaces the default one
+        assert!(check("PRAGMA schema_version", allowlist).is_err());
+
+        // denied pragmas can't be allowed
+        let allowlist = ["journal_mode=".to_string()];
+        assert!(check("PRAGMA journal_mode = DELETE", Some(&allowlist)).is_err());
+    }
+}
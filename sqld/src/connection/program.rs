@@ -1,27 +1,125 @@
+use std::ops::Range;
 use std::sync::Arc;
 
-use crate::query::Query;
+use crate::query::{Params, Query};
+use crate::query_analysis::{Statement, StmtKind};
+
+/// Name of the savepoint that a dry-run program is wrapped in.
+pub const DRY_RUN_SAVEPOINT: &str = "sqld_dry_run";
 
 #[derive(Debug, Clone)]
 pub struct Program {
     pub steps: Arc<Vec<Step>>,
+    /// Whether the steps are wrapped in the [`DRY_RUN_SAVEPOINT`], see [`Program::into_dry_run`].
+    pub dry_run: bool,
+    /// Whether the independent steps may be executed concurrently, on other connections to the
+    /// database, see [`Program::parallel_groups`].
+    pub parallel: bool,
+    /// Whether the rows of the steps are streamed to the client as they are produced, by a
+    /// cursor, rather than buffered in the response. The row limits of the server and of the
+    /// namespace don't apply to them.
+    pub incremental: bool,
 }
 
 impl Program {
     pub fn new(steps: Vec<Step>) -> Self {
         Self {
             steps: Arc::new(steps),
+            dry_run: false,
+            parallel: false,
+            incremental: false,
+        }
+    }
+
+    /// Wraps the program in a savepoint that is rolled back after the last step, whatever the
+    /// outcome of the steps, so that the program can be validated without persisting any change.
+    ///
+    /// The returned program has an extra step before and two extra steps after the original
+    /// ones. Since the steps run in a transaction, `IsAutocommit` conditions never hold.
+    ///
+    /// Returns `None` if the program contains transaction statements, which could release the
+    /// savepoint before it is rolled back.
+    pub fn into_dry_run(self) -> Option<Self> {
+        if self.dry_run {
+            return Some(self);
+        }
+        if self
+            .steps
+            .iter()
+            .any(|s| matches!(s.query.stmt.kind, StmtKind::TxnBegin | StmtKind::TxnEnd))
+        {
+            return None;
+        }
+
+        let mut steps = Vec::with_capacity(self.steps.len() + 3);
+        steps.push(Step::savepoint(format!("SAVEPOINT {DRY_RUN_SAVEPOINT}")));
+        steps.extend(self.steps.iter().map(|step| Step {
+            cond: step.cond.as_ref().map(|cond| cond.shifted(1)),
+            query: step.query.clone(),
+        }));
+        steps.push(Step::savepoint(format!("ROLLBACK TO {DRY_RUN_SAVEPOINT}")));
+        steps.push(Step::savepoint(format!("RELEASE {DRY_RUN_SAVEPOINT}")));
+
+        // the steps must run in the savepoint, on the connection that holds it
+        Some(Self {
+            steps: Arc::new(steps),
+            dry_run: true,
+            parallel: false,
+            incremental: self.incremental,
+        })
+    }
+
+    /// Returns the ranges of consecutive steps that can be executed concurrently if the program
+    /// is `parallel`, each holding at least two steps.
+    ///
+    /// These steps are the unconditional reads: they don't depend on the outcome of other steps,
+    /// and since none of them writes, they can't observe each other, whatever the tables they
+    /// access. The other steps separate the groups, so that the reads that follow a write still
+    /// see its effects.
+    pub fn parallel_groups(&self) -> Vec<Range<usize>> {
+        if !self.parallel || self.dry_run {
+            return Vec::new();
         }
+
+        let mut groups = Vec::new();
+        let mut start = 0;
+        for (i, step) in self.steps.iter().enumerate() {
+            if !step.is_independent_read() {
+                if i - start > 1 {
+                    groups.push(start..i);
+                }
+                start = i + 1;
+            }
+        }
+        if self.steps.len().saturating_sub(start) > 1 {
+            groups.push(start..self.steps.len());
+        }
+
+        groups
     }
 
     pub fn is_read_only(&self) -> bool {
         self.steps.iter().all(|s| s.query.stmt.is_read_only())
     }
 
+    /// Whether the program may grow the database, which is refused once it's over its quota.
+    /// Deleting rows and dropping tables is always allowed, so that the database can be brought
+    /// back under its quota.
+    pub fn may_grow_db(&self) -> bool {
+        self.steps
+            .iter()
+            .any(|s| !s.query.stmt.is_read_only() && !s.query.stmt.frees_space)
+    }
+
     pub fn steps(&self) -> &[Step] {
         self.steps.as_slice()
     }
 
+    /// Row limits of the queries of the steps, in order.
+    pub fn max_rows(&self) -> Vec<Option<u64>> {
+        self.steps.iter().map(|step| step.query.max_rows).collect()
+    }
+
     #[cfg(test)]
     pub fn seq(stmts: &[&str]) -> Self {
         use crate::{query::Params, query_analysis::Statement};
@@ -34,6 +132,8 @@ impl Program {
                     stmt: Statement::parse(stmt).next().unwrap().unwrap(),
                     params: Params::empty(),
                     want_rows: true,
+                    max_memory_bytes: None,
+                    max_rows: None,
                 },
             };
 
@@ -50,6 +150,38 @@ pub struct Step {
     pub query: Query,
 }
 
+impl Step {
+    fn is_independent_read(&self) -> bool {
+        self.cond.is_none() && self.query.stmt.kind == StmtKind::Read
+    }
+
+    /// An unconditional step managing the dry-run savepoint. These statements leave the
+    /// transaction state unchanged once balanced, so they are built as read statements that can
+    /// be executed on replicas.
+    fn savepoint(stmt: String) -> Self {
+        Self {
+            cond: None,
+            query: Query {
+                stmt: Statement {
+                    stmt,
+                    kind: StmtKind::Read,
+                    is_iud: false,
+                    is_insert: false,
+                    is_ddl: false,
+                    frees_space: false,
+                    attach: None,
+                    savepoint: None,
+                    pragma: None,
+                },
+                params: Params::empty(),
+                want_rows: false,
+                max_memory_bytes: None,
+                max_rows: None,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Cond {
     Ok { step: usize },
@@ -60,6 +192,30 @@ pub enum Cond {
     IsAutocommit,
 }
 
+impl Cond {
+    /// Returns the condition with the referred step indexes increased by `offset`.
+    fn shifted(&self, offset: usize) -> Self {
+        match self {
+            Self::Ok { step } => Self::Ok {
+                step: step + offset,
+            },
+            Self::Err { step } => Self::Err {
+                step: step + offset,
+            },
+            Self::Not { cond } => Self::Not {
+                cond: Box::new(cond.shifted(offset)),
+            },
+            Self::Or { conds } => Self::Or {
+                conds: conds.iter().map(|c| c.shifted(offset)).collect(),
+            },
+            Self::And { conds } => Self::And {
+                conds: conds.iter().map(|c| c.shifted(offset)).collect(),
+            },
+            Self::IsAutocommit => Self::IsAutocommit,
+        }
+    }
+}
+
 pub type DescribeResult = crate::Result<DescribeResponse>;
 
 #[derive(Debug, Clone)]
@@ -68,6 +224,8 @@ pub struct DescribeResponse {
     pub cols: Vec<DescribeCol>,
     pub is_explain: bool,
     pub is_readonly: bool,
+    /// Output of `EXPLAIN QUERY PLAN` for the statement, if it was requested.
+    pub query_plan: Vec<QueryPlanStep>,
 }
 
 #[derive(Debug, Clone)]
@@ -80,3 +238,61 @@ pub struct DescribeCol {
     pub name: String,
     pub decltype: Option<String>,
 }
+
+/// A row of the output of `EXPLAIN QUERY PLAN`.
+#[derive(Debug, Clone)]
+pub struct QueryPlanStep {
+    pub id: i32,
+    pub parent: i32,
+    pub detail: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parallel(stmts: &[&str]) -> Program {
+        Program {
+            parallel: true,
+            ..Program::seq(stmts)
+        }
+    }
+
+    #[test]
+    fn parallel_groups() {
+        let pgm = parallel(&[
+            "select 1",
+            "select 2",
+            "insert into t values (1)",
+            "select 3",
+            "update t set x = 2",
+            "select 4",
+            "select 5",
+            "select 6",
+        ]);
+        assert_eq!(pgm.parallel_groups(), [0..2, 5..8]);
+
+        let mut pgm = parallel(&["select 1", "select 2", "select 3"]);
+        let mut steps = (*pgm.steps).clone();
+        steps[1].cond = Some(Cond::Ok { step: 0 });
+        pgm.steps = Arc::new(steps);
+        assert!(pgm.parallel_groups().is_empty());
+
+        // whether the reads run in a transaction is only known when the program is executed
+        let pgm = parallel(&["begin", "select 1", "select 2", "commit"]);
+        let groups = pgm.parallel_groups();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0], 1..3);
+
+        let pgm = parallel(&["select 1", "select 2"]);
+        assert!(pgm
+            .clone()
+            .into_dry_run()
+            .unwrap()
+            .parallel_groups()
+            .is_empty());
+        assert!(Program::seq(&["select 1", "select 2"])
+            .parallel_groups()
+            .is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! Reaper of idle connections.
+//!
+//! The connections opened ahead of time by [WarmMakeConnection], and the readers kept by the
+//! connections for their parallel steps, stay open while they are idle, and hold on to their page
+//! cache. When SQLite uses more than a fraction of the process-wide soft heap limit, the reaper
+//! closes the least recently used of these idle connections to give their memory back. The
+//! connections handed out to clients are never closed by the reaper.
+//!
+//! [WarmMakeConnection]: super::warm::WarmMakeConnection
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use sqld_libsql_bindings::ffi::{sqlite3_status64, SQLITE_STATUS_MEMORY_USED};
+
+/// A pool of idle connections which the reaper may close.
+pub trait IdleConnections: Send + Sync {
+    /// Number of idle connections in the pool.
+    fn idle_count(&self) -> usize;
+    /// Time at which the least recently used idle connection of the pool was last used.
+    fn least_recently_used(&self) -> Option<Instant>;
+    /// Closes the least recently used idle connection of the pool, returning whether there was
+    /// one.
+    fn close_least_recently_used(&self) -> bool;
+}
+
+/// Pools of idle connections. The pools are dropped with their namespace, so only weak
+/// references are kept, and pruned as pools are registered and on every check of the reaper.
+static POOLS: Lazy<Mutex<Vec<Weak<dyn IdleConnections>>>> = Lazy::new(Default::default);
+
+/// Whether the reaper runs. Pools are not tracked otherwise.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Registers a pool whose idle connections the reaper may close. This is a no-op if the reaper
+/// doesn't run.
+pub fn register(pool: Weak<dyn IdleConnections>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut pools = POOLS.lock();
+    pools.retain(|pool| pool.strong_count() > 0);
+    pools.push(pool);
+}
+
+/// Checks the memory used by SQLite every `interval`, and closes idle connections while it is
+/// over `threshold` bytes.
+///
+/// The memory of a closed connection is only released once its thread is done with it, so each
+/// check closes the least recently used half of the idle connections, and leaves the rest to the
+/// next checks if the memory is still over the threshold then.
+///
+/// The pools are tracked as soon as this is called, so that the pools registered before the
+/// returned future is first polled are not missed.
+pub fn run(interval: Duration, threshold: u64) -> impl Future<Output = anyhow::Result<()>> {
+    ENABLED.store(true, Ordering::Relaxed);
+    async move {
+        tracing::info!(
+            "closing idle connections when SQLite uses more than {threshold} bytes, checked every {interval:?}"
+        );
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let pools = {
+                let mut pools = POOLS.lock();
+                pools.retain(|pool| pool.strong_count() > 0);
+                pools.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+            };
+            let used = memory_used();
+            if used <= threshold {
+                continue;
+            }
+
+            let idle = pools.iter().map(|pool| pool.idle_count()).sum::<usize>();
+            let closed = close_least_recently_used(&pools, (idle + 1) / 2);
+            if closed > 0 {
+                tracing::info!(
+                    "SQLite uses {used} bytes, over the threshold of {threshold} bytes: reclaimed {closed} idle connections"
+                );
+            }
+        }
+    }
+}
+
+/// Closes up to `count` idle connections across `pools`, least recently used first, and returns
+/// the number of closed connections.
+fn close_least_recently_used(pools: &[Arc<dyn IdleConnections>], count: usize) -> usize {
+    let mut closed = 0;
+    while closed < count {
+        let Some(pool) = pools
+            .iter()
+            .filter_map(|pool| Some((pool.least_recently_used()?, pool)))
+            .min_by_key(|(last_used, _)| *last_used)
+            .map(|(_, pool)| pool)
+        else {
+            break;
+        };
+        if pool.close_least_recently_used() {
+            closed += 1;
+        }
+    }
+    closed
+}
+
+/// Memory currently used by SQLite, in bytes.
+fn memory_used() -> u64 {
+    let mut current = 0;
+    let mut highwater = 0;
+    unsafe {
+        sqlite3_status64(SQLITE_STATUS_MEMORY_USED, &mut current, &mut highwater, 0);
+    }
+    current.max(0) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    struct FakePool(Mutex<VecDeque<Instant>>);
+
+    impl IdleConnections for FakePool {
+        fn idle_count(&self) -> usize {
+            self.0.lock().len()
+        }
+
+        fn least_recently_used(&self) -> Option<Instant> {
+            self.0.lock().front().copied()
+        }
+
+        fn close_least_recently_used(&self) -> bool {
+            self.0.lock().pop_front().is_some()
+        }
+    }
+
+    #[test]
+    fn closes_least_recently_used_connections_first() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let first = Arc::new(FakePool(Mutex::new([at(1), at(4), at(5)].into())));
+        let second = Arc::new(FakePool(Mutex::new([at(2), at(3)].into())));
+        let pools: Vec<Arc<dyn IdleConnections>> = vec![first.clone(), second.clone()];
+
+        assert_eq!(close_least_recently_used(&pools, 3), 3);
+        assert_eq!(*first.0.lock(), [at(4), at(5)]);
+        assert!(second.0.lock().is_empty());
+
+        // there are fewer idle connections left than requested
+        assert_eq!(close_least_recently_used(&pools, 3), 2);
+        assert!(first.0.lock().is_empty());
+    }
+
+    #[test]
+    fn pools_are_only_tracked_while_the_reaper_runs() {
+        let registered =
+            |pool: &Weak<dyn IdleConnections>| POOLS.lock().iter().any(|p| Weak::ptr_eq(p, pool));
+
+        let first: Arc<dyn IdleConnections> = Arc::new(FakePool(Default::default()));
+        let first_weak = Arc::downgrade(&first);
+        register(first_weak.clone());
+        assert!(!registered(&first_weak));
+
+        // `run` tracks the pools before it is polled
+        drop(run(Duration::from_secs(1), 0));
+        register(first_weak.clone());
+        assert!(registered(&first_weak));
+
+        // the dropped pools are pruned as new pools are registered
+        let second: Arc<dyn IdleConnections> = Arc::new(FakePool(Default::default()));
+        let second_weak = Arc::downgrade(&second);
+        drop(first);
+        register(second_weak.clone());
+        assert!(!registered(&first_weak));
+        assert!(registered(&second_weak));
+    }
+}
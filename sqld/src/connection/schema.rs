@@ -0,0 +1,147 @@
+//! Introspection of the schema of a database.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Tables and views of a database, excluding the internal `sqlite_*` tables.
+#[derive(Debug, Default, Serialize)]
+pub struct Schema {
+    pub tables: Vec<Table>,
+    pub views: Vec<View>,
+    /// Schema cookie of the database, incremented by SQLite whenever the schema changes.
+    #[serde(skip)]
+    pub version: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Table {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub indexes: Vec<Index>,
+    pub triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub decl_type: String,
+    pub not_null: bool,
+    /// Default value of the column, as an SQL expression.
+    pub default: Option<String>,
+    pub primary_key: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Index {
+    pub name: String,
+    /// Indexed columns, or `None` for an expression.
+    pub columns: Vec<Option<String>>,
+    pub sql: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Trigger {
+    pub name: String,
+    pub sql: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct View {
+    pub name: String,
+    pub sql: Option<String>,
+    pub triggers: Vec<Trigger>,
+}
+
+impl Schema {
+    /// Reads the schema of the database. The columns of tables and indexes are read with the
+    /// table-valued pragma functions, rather than parsed from the `CREATE` statements, so that
+    /// they are reported just as SQLite understands them.
+    pub fn load(conn: &rusqlite::Connection) -> rusqlite::Result<Self> {
+        let version = conn.query_row("PRAGMA schema_version", (), |row| row.get(0))?;
+
+        let mut tables = BTreeMap::new();
+        let mut views = BTreeMap::new();
+        let mut indexes = Vec::new();
+        let mut triggers = Vec::new();
+        let mut stmt = conn.prepare(
+            "SELECT type, name, tbl_name, sql FROM sqlite_master
+             WHERE name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            let kind: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            let table: String = row.get(2)?;
+            let sql: Option<String> = row.get(3)?;
+            match kind.as_str() {
+                "table" => {
+                    tables.insert(
+                        name.clone(),
+                        Table {
+                            name,
+                            columns: Vec::new(),
+                            indexes: Vec::new(),
+                            triggers: Vec::new(),
+                        },
+                    );
+                }
+                "view" => {
+                    views.insert(
+                        name.clone(),
+                        View {
+                            name,
+                            sql,
+                            triggers: Vec::new(),
+                        },
+                    );
+                }
+                "index" => indexes.push((table, name, sql)),
+                "trigger" => triggers.push((table, Trigger { name, sql })),
+                _ => (),
+            }
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?) ORDER BY cid",
+        )?;
+        for table in tables.values_mut() {
+            table.columns = stmt
+                .query_map([&table.name], |row| {
+                    Ok(Column {
+                        name: row.get(0)?,
+                        decl_type: row.get(1)?,
+                        not_null: row.get(2)?,
+                        default: row.get(3)?,
+                        primary_key: row.get::<_, i64>(4)? != 0,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+        }
+
+        let mut stmt = conn.prepare("SELECT name FROM pragma_index_info(?) ORDER BY seqno")?;
+        for (table, name, sql) in indexes {
+            let columns = stmt
+                .query_map([&name], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            if let Some(table) = tables.get_mut(&table) {
+                table.indexes.push(Index { name, columns, sql });
+            }
+        }
+
+        for (table, trigger) in triggers {
+            if let Some(table) = tables.get_mut(&table) {
+                table.triggers.push(trigger);
+            } else if let Some(view) = views.get_mut(&table) {
+                view.triggers.push(trigger);
+            }
+        }
+
+        Ok(Self {
+            tables: tables.into_values().collect(),
+            views: views.into_values().collect(),
+            version,
+        })
+    }
+}
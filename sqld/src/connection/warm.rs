@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+use crate::error::Error;
+
+use super::reaper::{self, IdleConnections};
+use super::MakeConnection;
+
+/// Wraps a connection maker to keep a pool of connections opened ahead of time, so that a request
+/// arriving after the database was idle doesn't wait for a connection to be opened. [create]
+/// hands out a pooled connection if there is one, and refills the pool in the background;
+/// otherwise it opens a connection on demand, like the wrapped maker.
+///
+/// The pooled connections are not counted by the throttling of [MakeThrottledConnection] until
+/// they are handed out, so a namespace may hold `size` more open connections than its concurrency
+/// limit. They may be closed by the [reaper] under memory pressure, and are only replaced at the
+/// next [create].
+///
+/// Every open connection pins a blocking thread, so the pools of all the namespaces hold at most
+/// [set_max_pooled_connections] connections together. A pool that can't get a connection under
+/// that limit stops refilling until its next [create].
+///
+/// [create]: MakeConnection::create
+/// [MakeThrottledConnection]: super::MakeThrottledConnection
+pub struct WarmMakeConnection<F: MakeConnection> {
+    inner: Arc<Pool<F>>,
+}
+
+struct Pool<F: MakeConnection> {
+    connection_maker: F,
+    /// Pooled connections, oldest first, with the time at which they were opened, and their slot
+    /// under the limit of pooled connections.
+    connections: Mutex<VecDeque<(F::Connection, Instant, PoolSlot)>>,
+    size: usize,
+    /// Whether a task is refilling the pool.
+    refilling: AtomicBool,
+    limit: &'static PoolLimit,
+}
+
+/// Limit of the connections pooled by all the pools.
+struct PoolLimit {
+    pooled: AtomicUsize,
+    max: AtomicUsize,
+}
+
+static POOL_LIMIT: PoolLimit = PoolLimit {
+    pooled: AtomicUsize::new(0),
+    max: AtomicUsize::new(DEFAULT_MAX_POOLED_CONNECTIONS),
+};
+
+const DEFAULT_MAX_POOLED_CONNECTIONS: usize = 128;
+
+/// Sets the maximum number of connections pooled by all the pools of the process together.
+pub fn set_max_pooled_connections(max: usize) {
+    POOL_LIMIT.max.store(max, Ordering::Relaxed);
+}
+
+impl PoolLimit {
+    /// Takes a slot for a pooled connection, unless the limit is reached.
+    fn acquire(&'static self) -> Option<PoolSlot> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.pooled
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pooled| {
+                (pooled < max).then_some(pooled + 1)
+            })
+            .ok()
+            .map(|_| PoolSlot { limit: self })
+    }
+}
+
+/// Slot of a pooled connection under the [PoolLimit], released when the connection leaves the
+/// pool.
+struct PoolSlot {
+    limit: &'static PoolLimit,
+}
+
+impl Drop for PoolSlot {
+    fn drop(&mut self) {
+        self.limit.pooled.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<F: MakeConnection> WarmMakeConnection<F> {
+    /// Creates a pool of `size` connections, which is filled in the background. A pool of size 0
+    /// opens every connection on demand. This must be called from a tokio runtime.
+    pub fn new(connection_maker: F, size: usize) -> Self {
+        Self::with_limit(connection_maker, size, &POOL_LIMIT)
+    }
+
+    fn with_limit(connection_maker: F, size: usize, limit: &'static PoolLimit) -> Self {
+        let inner = Arc::new(Pool {
+            connection_maker,
+            connections: Mutex::new(VecDeque::with_capacity(size)),
+            size,
+            refilling: AtomicBool::new(false),
+            limit,
+        });
+        if size > 0 {
+            reaper::register(Arc::downgrade(&inner) as Weak<dyn IdleConnections>);
+        }
+        refill(&inner);
+        Self { inner }
+    }
+
+    /// Number of connections waiting in the pool.
+    #[cfg(test)]
+    fn pooled(&self) -> usize {
+        self.inner.connections.lock().len()
+    }
+}
+
+/// Spawns a task opening connections until the pool is full, unless one is already running. The
+/// task only holds a weak reference to the pool, so that it stops once the pool is dropped.
+fn refill<F: MakeConnection>(pool: &Arc<Pool<F>>) {
+    if pool.connections.lock().len() >= pool.size || pool.refilling.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let pool = Arc::downgrade(pool);
+    tokio::spawn(async move {
+        while let Some(pool) = Weak::upgrade(&pool) {
+            if pool.connections.lock().len() >= pool.size {
+                pool.refilling.store(false, Ordering::Release);
+                // a connection may have been handed out before the flag was cleared, in which
+                // case no other task was spawned to replace it
+                if pool.connections.lock().len() < pool.size
+                    && !pool.refilling.swap(true, Ordering::AcqRel)
+                {
+                    continue;
+                }
+                return;
+            }
+            let Some(slot) = pool.limit.acquire() else {
+                tracing::debug!("the connection pools are full, not refilling");
+                pool.refilling.store(false, Ordering::Release);
+                return;
+            };
+            match pool.connection_maker.create().await {
+                Ok(conn) => pool
+                    .connections
+                    .lock()
+                    .push_back((conn, Instant::now(), slot)),
+                Err(e) => {
+                    // the connections are opened on demand until the next refill
+                    tracing::warn!("failed to open a connection for the pool: {e}");
+                    pool.refilling.store(false, Ordering::Release);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[async_trait::async_trait]
+impl<F: MakeConnection> MakeConnection for WarmMakeConnection<F> {
+    type Connection = F::Connection;
+
+    async fn create(&self) -> Result<Self::Connection, Error> {
+        let pooled = self.inner.connections.lock().pop_front();
+        refill(&self.inner);
+        match pooled {
+            Some((conn, _, _slot)) => Ok(conn),
+            None => self.inner.connection_maker.create().await,
+        }
+    }
+}
+
+impl<F: MakeConnection> IdleConnections for Pool<F> {
+    fn idle_count(&self) -> usize {
+        self.connections.lock().len()
+    }
+
+    fn least_recently_used(&self) -> Option<Instant> {
+        self.connections
+            .lock()
+            .front()
+            .map(|(_, opened, _)| *opened)
+    }
+
+    fn close_least_recently_used(&self) -> bool {
+        // the connection is closed outside of the lock
+        let conn = self.connections.lock().pop_front();
+        conn.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::connection::config::DatabaseConfigStore;
+    use crate::connection::libsql::LibSqlConnection;
+    use crate::query_result_builder::QueryBuilderConfig;
+    use crate::stats::Stats;
+
+    /// Returns a connection maker whose opens each wait for a permit of `opens`.
+    fn gated_connection_maker(
+        path: &std::path::Path,
+        opens: Arc<Semaphore>,
+    ) -> impl MakeConnection<Connection = LibSqlConnection> {
+        let path = path.to_owned();
+        move || {
+            let opens = opens.clone();
+            let path = path.clone();
+            async move {
+                opens.acquire().await.unwrap().forget();
+                LibSqlConnection::new(
+                    path,
+                    Arc::new([]),
+                    &TRANSPARENT_METHODS,
+                    (),
+                    Stats::default(),
+                    Default::default(),
+                    Arc::new(DatabaseConfigStore::new_test()),
+                    QueryBuilderConfig::default(),
+                    None,
+                    None,
+                )
+                .await
+            }
+        }
+    }
+
+    fn new_limit(max: usize) -> &'static PoolLimit {
+        Box::leak(Box::new(PoolLimit {
+            pooled: AtomicUsize::new(0),
+            max: AtomicUsize::new(max),
+        }))
+    }
+
+    async fn wait_pooled<F: MakeConnection>(maker: &WarmMakeConnection<F>, count: usize) {
+        while maker.pooled() < count || maker.inner.refilling.load(Ordering::Acquire) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn warm_connection_is_handed_out_without_opening() {
+        let tmp = tempfile::tempdir().unwrap();
+        // every open waits for a permit, and only the pool gets some
+        let opens = Arc::new(Semaphore::new(2));
+        let maker = WarmMakeConnection::with_limit(
+            gated_connection_maker(tmp.path(), opens.clone()),
+            2,
+            new_limit(16),
+        );
+        wait_pooled(&maker, 2).await;
+
+        // no open can complete anymore, so these connections come from the pool
+        for _ in 0..2 {
+            maker
+                .create()
+                .now_or_never()
+                .expect("a warm connection was not handed out right away")
+                .unwrap();
+        }
+
+        // the pool is empty, so the next connection is opened on demand
+        let mut create = maker.create();
+        assert!((&mut create).now_or_never().is_none());
+
+        // one permit for the connection, two for refilling the pool
+        opens.add_permits(3);
+        create.await.unwrap();
+        wait_pooled(&maker, 2).await;
+    }
+
+    #[tokio::test]
+    async fn pools_are_limited_together() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opens = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+        let limit = new_limit(3);
+        let first = WarmMakeConnection::with_limit(
+            gated_connection_maker(tmp.path(), opens.clone()),
+            2,
+            limit,
+        );
+        wait_pooled(&first, 2).await;
+        let second = WarmMakeConnection::with_limit(
+            gated_connection_maker(tmp.path(), opens.clone()),
+            2,
+            limit,
+        );
+        wait_pooled(&second, 1).await;
+        assert_eq!(second.pooled(), 1);
+        assert_eq!(limit.pooled.load(Ordering::Acquire), 3);
+
+        // handing out a connection frees its slot, which the pool takes back to refill
+        second.create().await.unwrap();
+        wait_pooled(&second, 1).await;
+        assert_eq!(second.pooled(), 1);
+        assert_eq!(limit.pooled.load(Ordering::Acquire), 3);
+
+        // the slots of a dropped pool are freed
+        drop(first);
+        assert_eq!(limit.pooled.load(Ordering::Acquire), 1);
+        second.create().await.unwrap();
+        wait_pooled(&second, 2).await;
+        assert_eq!(limit.pooled.load(Ordering::Acquire), 2);
+    }
+}
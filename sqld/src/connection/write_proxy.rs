@@ -1,3 +1,5 @@
+use std::future::Future;
+use std::io::BufRead;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -12,23 +14,27 @@ use tonic::Request;
 use uuid::Uuid;
 
 use crate::auth::Authenticated;
-use crate::error::Error;
+use crate::config::{TrustedExtension, WriteProxyRetryConfig};
+use crate::error::{Error, LoadDumpError};
+use crate::heap_limit;
 use crate::query::Value;
 use crate::query_analysis::State;
 use crate::query_result_builder::{
-    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+    Column, LimitRows, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
 use crate::replication::FrameNo;
 use crate::rpc::proxy::rpc::proxy_client::ProxyClient;
 use crate::rpc::proxy::rpc::query_result::RowResult;
-use crate::rpc::proxy::rpc::{DisconnectMessage, ExecuteResults};
-use crate::rpc::NAMESPACE_METADATA_KEY;
+use crate::rpc::proxy::rpc::{DisconnectMessage, ExecuteResults, VacuumReq};
+use crate::rpc::{explain_message_size_error, migrated_to, NAMESPACE_METADATA_KEY};
 use crate::stats::Stats;
 use crate::{Result, DEFAULT_AUTO_CHECKPOINT};
 
 use super::config::DatabaseConfigStore;
+use super::dump::loader::{LoadDumpOptions, LoadDumpStats};
 use super::libsql::LibSqlConnection;
 use super::program::DescribeResult;
+use super::schema::Schema;
 use super::Connection;
 use super::{MakeConnection, Program};
 
@@ -36,12 +42,15 @@ use super::{MakeConnection, Program};
 pub struct MakeWriteProxyConnection {
     client: ProxyClient<Channel>,
     db_path: PathBuf,
-    extensions: Arc<[PathBuf]>,
+    extensions: Arc<[TrustedExtension]>,
     stats: Stats,
     config_store: Arc<DatabaseConfigStore>,
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
     max_response_size: u64,
     max_total_response_size: u64,
+    statement_cache_size: usize,
+    max_query_params: usize,
+    retry: WriteProxyRetryConfig,
     namespace: Bytes,
 }
 
@@ -49,17 +58,23 @@ impl MakeWriteProxyConnection {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         db_path: PathBuf,
-        extensions: Arc<[PathBuf]>,
+        extensions: Arc<[TrustedExtension]>,
         channel: Channel,
         uri: tonic::transport::Uri,
+        max_message_size: usize,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
         max_response_size: u64,
         max_total_response_size: u64,
+        statement_cache_size: usize,
+        max_query_params: usize,
+        retry: WriteProxyRetryConfig,
         namespace: Bytes,
     ) -> Self {
-        let client = ProxyClient::with_origin(channel, uri);
+        let client = ProxyClient::with_origin(channel, uri)
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
         Self {
             client,
             db_path,
@@ -69,6 +84,9 @@ impl MakeWriteProxyConnection {
             applied_frame_no_receiver,
             max_response_size,
             max_total_response_size,
+            statement_cache_size,
+            max_query_params,
+            retry,
             namespace,
         }
     }
@@ -89,7 +107,11 @@ impl MakeConnection for MakeWriteProxyConnection {
                 max_size: Some(self.max_response_size),
                 max_total_size: Some(self.max_total_response_size),
                 auto_checkpoint: DEFAULT_AUTO_CHECKPOINT,
+                statement_cache_size: self.statement_cache_size,
+                max_query_params: Some(self.max_query_params),
+                max_rows: None,
             },
+            self.retry,
             self.namespace.clone(),
         )
         .await?;
@@ -110,11 +132,72 @@ pub struct WriteProxyConnection {
     /// Notifier from the repliator of the currently applied frameno
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
     builder_config: QueryBuilderConfig,
+    /// Config of the namespace, whose row limit applies to the rows returned by the primary.
+    config_store: Arc<DatabaseConfigStore>,
     stats: Stats,
+    /// Retry policy of the writes sent to the primary
+    retry: WriteProxyRetryConfig,
     /// bytes representing the namespace name
     namespace: Bytes,
 }
 
+/// Returns whether a write that failed with `status` can be retried, because it provably never
+/// reached the primary: the connection to the primary could not be established. A write that timed
+/// out, or whose connection broke after it was sent, may have been applied by the primary, and
+/// retrying it could apply it twice.
+fn is_unsent(status: &tonic::Status) -> bool {
+    if status.code() != tonic::Code::Unavailable {
+        return false;
+    }
+
+    let mut source = std::error::Error::source(status);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            if err.is_connect() {
+                return true;
+            }
+        }
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if err.kind() == std::io::ErrorKind::ConnectionRefused {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+/// Sends a write to the primary with `send`, retrying it with a backoff as long as it fails
+/// without reaching the primary, up to the retries of `policy`. `on_retry` is called before every
+/// retry.
+async fn send_with_retries<T, F, Fut>(
+    policy: WriteProxyRetryConfig,
+    mut send: F,
+    mut on_retry: impl FnMut(),
+) -> Result<T, tonic::Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, tonic::Status>>,
+{
+    let mut backoff = policy.initial_backoff;
+    let mut retries = 0;
+    loop {
+        match send().await {
+            Err(e) if retries < policy.max_retries && is_unsent(&e) => {
+                tracing::warn!(
+                    "write could not be sent to the primary, retrying in {backoff:?}: {e}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+                on_retry();
+            }
+            result => return result,
+        }
+    }
+}
+
 fn execute_results_to_builder<B: QueryResultBuilder>(
     execute_result: ExecuteResults,
     mut builder: B,
@@ -131,7 +214,7 @@ fn execute_results_to_builder<B: QueryResultBuilder>(
                 }))?;
 
                 builder.begin_rows()?;
-                for row in rows.rows {
+                let res = rows.rows.into_iter().try_for_each(|row| {
                     builder.begin_row()?;
                     for value in row.values {
                         let value: Value = bincode::deserialize(&value.data)
@@ -139,19 +222,32 @@ fn execute_results_to_builder<B: QueryResultBuilder>(
                             .map_err(QueryResultBuilderError::from_any)?;
                         builder.add_row_value(ValueRef::from(&value))?;
                     }
-                    builder.finish_row()?;
+                    builder.finish_row()
+                });
+                match res {
+                    Ok(()) => {
+                        builder.finish_rows()?;
+                        builder.finish_step(rows.affected_row_count, rows.last_insert_rowid)?;
+                    }
+                    // the step fails as it would on a local connection, and the program goes on
+                    Err(QueryResultBuilderError::RowLimitExceeded(limit)) => {
+                        builder.step_error(Error::RowLimitExceeded(limit))?;
+                        builder.finish_step(0, None)?;
+                    }
+                    Err(e) => return Err(e.into()),
                 }
-
-                builder.finish_rows()?;
-
-                builder.finish_step(rows.affected_row_count, rows.last_insert_rowid)?;
             }
             Some(RowResult::Error(err)) => {
                 builder.begin_step()?;
                 builder.step_error(Error::RpcQueryError(err))?;
                 builder.finish_step(0, None)?;
             }
-            None => (),
+            // older primaries don't report skipped steps, but a result we can't make sense of
+            // must still take the place of its step
+            Some(RowResult::Skipped(_)) | None => {
+                builder.begin_step()?;
+                builder.finish_step(0, None)?;
+            }
         }
     }
 
@@ -165,21 +261,26 @@ impl WriteProxyConnection {
     async fn new(
         write_proxy: ProxyClient<Channel>,
         db_path: PathBuf,
-        extensions: Arc<[PathBuf]>,
+        extensions: Arc<[TrustedExtension]>,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
         builder_config: QueryBuilderConfig,
+        retry: WriteProxyRetryConfig,
         namespace: Bytes,
     ) -> Result<Self> {
+        let namespace_name = String::from_utf8_lossy(&namespace);
         let read_conn = LibSqlConnection::new(
             db_path,
             extensions,
             &TRANSPARENT_METHODS,
             (),
             stats.clone(),
-            config_store,
+            stats.statement_counts(&namespace_name),
+            config_store.clone(),
             builder_config,
+            Some(heap_limit::namespace_heap(&namespace_name)),
+            None,
         )
         .await?;
 
@@ -191,7 +292,9 @@ impl WriteProxyConnection {
             last_write_frame_no: PMutex::new(FrameNo::MAX),
             applied_frame_no_receiver,
             builder_config,
+            config_store,
             stats,
+            retry,
             namespace,
         })
     }
@@ -204,34 +307,60 @@ impl WriteProxyConnection {
         builder: B,
     ) -> Result<(B, State)> {
         self.stats.inc_write_requests_delegated();
-        let mut client = self.write_proxy.clone();
-
-        let mut req = Request::new(crate::rpc::proxy::rpc::ProgramReq {
-            client_id: self.client_id.to_string(),
-            pgm: Some(pgm.into()),
-        });
-
-        let namespace = BinaryMetadataValue::from_bytes(&self.namespace[..]);
-        req.metadata_mut()
-            .insert_bin(NAMESPACE_METADATA_KEY, namespace);
-        auth.upgrade_grpc_request(&mut req);
-
-        match client.execute(req).await {
+        let client = self.write_proxy.clone();
+        // the primary enforces the limits of the queries, but not the limit of this namespace
+        let builder = LimitRows::new(builder, pgm.max_rows());
+        let builder_config = QueryBuilderConfig {
+            max_rows: self.config_store.effective().max_rows,
+            ..self.builder_config
+        };
+        let pgm: crate::rpc::proxy::rpc::Program = pgm.into();
+        // Retrying is only safe outside of a transaction: the primary drops the transaction of
+        // this client when it goes away, so retrying the rest of it would run it in autocommit.
+        let retry = match *state {
+            State::Init => self.retry,
+            _ => WriteProxyRetryConfig {
+                max_retries: 0,
+                ..self.retry
+            },
+        };
+
+        let send = || {
+            let mut req = Request::new(crate::rpc::proxy::rpc::ProgramReq {
+                client_id: self.client_id.to_string(),
+                pgm: Some(pgm.clone()),
+                report_skipped: true,
+            });
+
+            let namespace = BinaryMetadataValue::from_bytes(&self.namespace[..]);
+            req.metadata_mut()
+                .insert_bin(NAMESPACE_METADATA_KEY, namespace);
+            auth.upgrade_grpc_request(&mut req);
+
+            let mut client = client.clone();
+            async move { client.execute(req).await }
+        };
+        let result = send_with_retries(retry, send, || self.stats.inc_retried_writes()).await;
+
+        match result {
             Ok(r) => {
                 let execute_result = r.into_inner();
                 *state = execute_result.state().into();
                 let current_frame_no = execute_result.current_frame_no;
-                let builder =
-                    execute_results_to_builder(execute_result, builder, &self.builder_config)?;
+                let builder = execute_results_to_builder(execute_result, builder, &builder_config)?;
                 self.update_last_write_frame_no(current_frame_no);
 
-                Ok((builder, *state))
+                Ok((builder.into_inner(), *state))
             }
             Err(e) => {
                 // Set state to invalid, so next call is sent to remote, and we have a chance
                 // to recover state.
                 *state = State::Invalid;
-                Err(Error::RpcQueryExecutionError(e))
+                if let Some(target) = migrated_to(&e) {
+                    let namespace = String::from_utf8_lossy(&self.namespace).into_owned();
+                    return Err(Error::NamespaceMigrated(namespace, target));
+                }
+                Err(Error::RpcQueryExecutionError(explain_message_size_error(e)))
             }
         }
     }
@@ -295,9 +424,14 @@ impl Connection for WriteProxyConnection {
         }
     }
 
-    async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult> {
+    async fn describe(
+        &self,
+        sql: String,
+        auth: Authenticated,
+        query_plan: bool,
+    ) -> Result<DescribeResult> {
         self.wait_replication_sync().await?;
-        self.read_conn.describe(sql, auth).await
+        self.read_conn.describe(sql, auth, query_plan).await
     }
 
     async fn is_autocommit(&self) -> Result<bool> {
@@ -312,6 +446,43 @@ impl Connection for WriteProxyConnection {
         self.wait_replication_sync().await?;
         self.read_conn.checkpoint().await
     }
+
+    async fn integrity_check(&self) -> Result<Vec<String>> {
+        // this checks the replica copy of the database, and not the primary
+        self.read_conn.integrity_check().await
+    }
+
+    async fn schema(&self, auth: Authenticated) -> Result<Schema> {
+        self.read_conn.schema(auth).await
+    }
+
+    async fn vacuum(&self, auth: Authenticated) -> Result<()> {
+        // the replica database is a copy of the primary's, so the vacuum must happen there
+        let mut client = self.write_proxy.clone();
+        let mut req = Request::new(VacuumReq {});
+        let namespace = BinaryMetadataValue::from_bytes(&self.namespace[..]);
+        req.metadata_mut()
+            .insert_bin(NAMESPACE_METADATA_KEY, namespace);
+        auth.upgrade_grpc_request(&mut req);
+
+        let resp = client
+            .vacuum(req)
+            .await
+            .map_err(Error::RpcQueryExecutionError)?;
+        self.update_last_write_frame_no(resp.into_inner().current_frame_no);
+
+        Ok(())
+    }
+
+    async fn load_dump(
+        &self,
+        _dump: Box<dyn BufRead + Send>,
+        _options: LoadDumpOptions,
+        _auth: Authenticated,
+    ) -> Result<LoadDumpStats> {
+        // the dump is streamed, and there is no RPC to stream it to the primary
+        Err(LoadDumpError::ReplicaLoadDump.into())
+    }
 }
 
 impl Drop for WriteProxyConnection {
@@ -347,6 +518,156 @@ pub mod test {
         Ok(v.into())
     }
 
+    #[tokio::test]
+    async fn only_unsent_writes_are_retried() {
+        let policy = WriteProxyRetryConfig {
+            max_retries: 3,
+            initial_backoff: std::time::Duration::from_millis(1),
+        };
+        async fn attempts(
+            policy: WriteProxyRetryConfig,
+            send: impl Fn() -> Result<(), tonic::Status>,
+        ) -> u32 {
+            let mut sent = 0;
+            let mut retried = 0;
+            let res = send_with_retries(
+                policy,
+                || {
+                    sent += 1;
+                    std::future::ready(send())
+                },
+                || retried += 1,
+            )
+            .await;
+            assert!(res.is_err());
+            assert_eq!(retried, sent - 1);
+            sent
+        }
+
+        // the primary may have applied the write before failing or timing out
+        let unavailable = || Err(tonic::Status::unavailable(""));
+        assert_eq!(attempts(policy, unavailable).await, 1);
+        let deadline_exceeded = || Err(tonic::Status::deadline_exceeded(""));
+        assert_eq!(attempts(policy, deadline_exceeded).await, 1);
+        let internal = || Err(tonic::Status::internal(""));
+        assert_eq!(attempts(policy, internal).await, 1);
+
+        // the connection to the primary is refused, since nothing listens on the port once the
+        // listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect_lazy();
+        let client = ProxyClient::new(channel);
+        let status = client
+            .clone()
+            .execute(crate::rpc::proxy::rpc::ProgramReq::default())
+            .await
+            .unwrap_err();
+        assert!(is_unsent(&status), "{status:?}");
+
+        let mut sent = 0;
+        let res = send_with_retries(
+            policy,
+            || {
+                sent += 1;
+                let mut client = client.clone();
+                async move {
+                    client
+                        .execute(crate::rpc::proxy::rpc::ProgramReq::default())
+                        .await
+                }
+            },
+            || (),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(sent, 4);
+    }
+
+    #[test]
+    fn skipped_steps_keep_their_place() {
+        use crate::query_result_builder::{StepResult, StepResultsBuilder};
+        use crate::rpc::proxy::rpc::{QueryResult, ResultRows, Skipped};
+
+        let results = vec![
+            QueryResult {
+                row_result: Some(RowResult::Row(ResultRows::default())),
+            },
+            QueryResult {
+                row_result: Some(RowResult::Skipped(Skipped {})),
+            },
+            QueryResult { row_result: None },
+            QueryResult {
+                row_result: Some(RowResult::Row(ResultRows::default())),
+            },
+        ];
+        let res = ExecuteResults {
+            results,
+            ..Default::default()
+        };
+        let builder = execute_results_to_builder(
+            res,
+            StepResultsBuilder::default(),
+            &QueryBuilderConfig::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            builder.into_ret()[..],
+            [
+                StepResult::Ok,
+                StepResult::Skipped,
+                StepResult::Skipped,
+                StepResult::Ok
+            ]
+        ));
+    }
+
+    #[test]
+    fn row_limit_applies_to_proxied_rows() {
+        use crate::query_result_builder::{StepResult, StepResultsBuilder};
+        use crate::rpc::proxy::rpc::{self, QueryResult, ResultRows};
+
+        let rows = |count| {
+            let value = rpc::Value {
+                data: bincode::serialize(&Value::Integer(1)).unwrap(),
+            };
+            QueryResult {
+                row_result: Some(RowResult::Row(ResultRows {
+                    rows: vec![
+                        rpc::Row {
+                            values: vec![value]
+                        };
+                        count
+                    ],
+                    ..Default::default()
+                })),
+            }
+        };
+        let res = ExecuteResults {
+            results: vec![rows(4), rows(4), rows(5)],
+            ..Default::default()
+        };
+        // the lowest of the limits of the query and of the namespace applies
+        let builder = LimitRows::new(StepResultsBuilder::default(), vec![Some(2), Some(10), None]);
+        let config = QueryBuilderConfig {
+            max_rows: Some(4),
+            ..Default::default()
+        };
+        let builder = execute_results_to_builder(res, builder, &config).unwrap();
+        assert!(matches!(
+            builder.into_ret()[..],
+            [
+                StepResult::Err(Error::RowLimitExceeded(2)),
+                StepResult::Ok,
+                StepResult::Err(Error::RowLimitExceeded(4))
+            ]
+        ));
+    }
+
     /// In this test, we generate random ExecuteResults, and ensures that the `execute_results_to_builder` drives the builder FSM correctly.
     #[test]
     fn test_execute_results_to_builder() {
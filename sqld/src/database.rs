@@ -1,11 +1,12 @@
 use std::sync::Arc;
 
+use crate::connection::coalescing::CoalescingConnection;
 use crate::connection::libsql::LibSqlConnection;
 use crate::connection::write_proxy::WriteProxyConnection;
 use crate::connection::{Connection, MakeConnection, TrackedConnection};
 use crate::replication::ReplicationLogger;
 
-pub trait Database: Sync + Send + 'static {
+pub trait Database: Clone + Sync + Send + 'static {
     /// The connection type of the database
     type Connection: Connection;
 
@@ -13,6 +14,7 @@ pub trait Database: Sync + Send + 'static {
     fn shutdown(&self);
 }
 
+#[derive(Clone)]
 pub struct ReplicaDatabase {
     pub connection_maker:
         Arc<dyn MakeConnection<Connection = TrackedConnection<WriteProxyConnection>>>,
@@ -28,13 +30,16 @@ impl Database for ReplicaDatabase {
     fn shutdown(&self) {}
 }
 
+#[derive(Clone)]
 pub struct PrimaryDatabase {
     pub logger: Arc<ReplicationLogger>,
-    pub connection_maker: Arc<dyn MakeConnection<Connection = TrackedConnection<LibSqlConnection>>>,
+    pub connection_maker: Arc<
+        dyn MakeConnection<Connection = TrackedConnection<CoalescingConnection<LibSqlConnection>>>,
+    >,
 }
 
 impl Database for PrimaryDatabase {
-    type Connection = TrackedConnection<LibSqlConnection>;
+    type Connection = TrackedConnection<CoalescingConnection<LibSqlConnection>>;
 
     fn connection_maker(&self) -> Arc<dyn MakeConnection<Connection = Self::Connection>> {
         self.connection_maker.clone()
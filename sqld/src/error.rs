@@ -3,7 +3,9 @@ use hyper::StatusCode;
 use tonic::metadata::errors::InvalidMetadataValueBytes;
 
 use crate::{
-    auth::AuthError, namespace::ForkError, query_result_builder::QueryResultBuilderError,
+    auth::AuthError,
+    namespace::{ForkError, MigrateError, RestoreError},
+    query_result_builder::QueryResultBuilderError,
     replication::replica::error::ReplicationError,
 };
 
@@ -39,7 +41,7 @@ pub enum Error {
     #[error("Timed out while openning database connection")]
     DbCreateTimeout,
     #[error(transparent)]
-    BuilderError(#[from] QueryResultBuilderError),
+    BuilderError(QueryResultBuilderError),
     #[error("Operation was blocked{}", .0.as_ref().map(|msg| format!(": {}", msg)).unwrap_or_default())]
     Blocked(Option<String>),
     #[error(transparent)]
@@ -79,6 +81,51 @@ pub enum Error {
     ConflictingRestoreParameters,
     #[error("failed to fork database: {0}")]
     Fork(#[from] ForkError),
+    #[error("failed to migrate database: {0}")]
+    Migrate(#[from] MigrateError),
+    #[error("failed to restore database: {0}")]
+    Restore(#[from] RestoreError),
+    #[error("Namespace `{0}` was migrated to `{1}`")]
+    NamespaceMigrated(String, url::Url),
+    #[error("Cursor `{0}` doesn't exist or has expired")]
+    CursorNotFound(uuid::Uuid),
+    #[error("Query exceeded the memory limit of {0} bytes")]
+    MemoryLimitExceeded(u64),
+    #[error("Query returned more than {0} rows, add a LIMIT clause or paginate the results")]
+    RowLimitExceeded(u64),
+    #[error("Query has {0} parameters, more than the limit of {1}")]
+    TooManyQueryParams(usize, usize),
+    #[error("Cannot vacuum the database while another write is in progress")]
+    VacuumConflict,
+    #[error("Bottomless replication is not enabled for namespace `{0}`")]
+    BottomlessNotEnabled(String),
+    #[error("Cannot back up the database while it is in use, retry later")]
+    BackupBusy,
+    #[error("init_sql may only contain DDL statements, found: `{0}`")]
+    InitSqlNotDdl(String),
+    #[error("JWT `{0}` is not revoked")]
+    JwtNotRevoked(String),
+    #[error("Table `{0}` doesn't exist")]
+    TableNotFound(String),
+    #[error("Database exceeds its size quota of {0} bytes, writes are rejected")]
+    DatabaseFull(u64),
+    #[error("Invalid CORS origin: `{0}`")]
+    InvalidCorsOrigin(String),
+    #[error("Namespace `{0}` is not allowed to be attached")]
+    AttachNotAllowed(String),
+    #[error("ATTACH and DETACH are not allowed on this namespace")]
+    AttachDisabled,
+    #[error("PRAGMA `{0}` is not allowed on this namespace")]
+    PragmaNotAllowed(String),
+    #[error("Invalid or disallowed response header `{0}`")]
+    InvalidResponseHeader(String),
+    #[error(
+        "Page size of {0} bytes is not supported, the replication log only holds pages of {} bytes",
+        crate::replication::WAL_PAGE_SIZE
+    )]
+    UnsupportedPageSize(u32),
+    #[error("Cannot change the page size of a non-empty database from {current} to {requested} bytes, it only applies after a VACUUM")]
+    PageSizeChange { current: u32, requested: u32 },
 }
 
 trait ResponseError: std::error::Error {
@@ -129,6 +176,27 @@ impl IntoResponse for Error {
             LoadDumpExistingDb => self.format_err(StatusCode::BAD_REQUEST),
             ConflictingRestoreParameters => self.format_err(StatusCode::BAD_REQUEST),
             Fork(e) => e.into_response(),
+            Migrate(e) => e.into_response(),
+            Restore(e) => e.into_response(),
+            NamespaceMigrated(_, _) => self.format_err(StatusCode::MISDIRECTED_REQUEST),
+            CursorNotFound(_) => self.format_err(StatusCode::NOT_FOUND),
+            MemoryLimitExceeded(_) => self.format_err(StatusCode::BAD_REQUEST),
+            RowLimitExceeded(_) => self.format_err(StatusCode::BAD_REQUEST),
+            TooManyQueryParams(_, _) => self.format_err(StatusCode::BAD_REQUEST),
+            VacuumConflict => self.format_err(StatusCode::CONFLICT),
+            BottomlessNotEnabled(_) => self.format_err(StatusCode::CONFLICT),
+            BackupBusy => self.format_err(StatusCode::SERVICE_UNAVAILABLE),
+            InitSqlNotDdl(_) => self.format_err(StatusCode::BAD_REQUEST),
+            JwtNotRevoked(_) => self.format_err(StatusCode::NOT_FOUND),
+            TableNotFound(_) => self.format_err(StatusCode::NOT_FOUND),
+            DatabaseFull(_) => self.format_err(StatusCode::INSUFFICIENT_STORAGE),
+            InvalidCorsOrigin(_) => self.format_err(StatusCode::BAD_REQUEST),
+            AttachNotAllowed(_) => self.format_err(StatusCode::FORBIDDEN),
+            AttachDisabled => self.format_err(StatusCode::FORBIDDEN),
+            PragmaNotAllowed(_) => self.format_err(StatusCode::FORBIDDEN),
+            InvalidResponseHeader(_) => self.format_err(StatusCode::BAD_REQUEST),
+            UnsupportedPageSize(_) => self.format_err(StatusCode::BAD_REQUEST),
+            PageSizeChange { .. } => self.format_err(StatusCode::BAD_REQUEST),
         }
     }
 }
@@ -141,6 +209,16 @@ impl From<tokio::sync::oneshot::error::RecvError> for Error {
     }
 }
 
+impl From<QueryResultBuilderError> for Error {
+    fn from(other: QueryResultBuilderError) -> Self {
+        match other {
+            // the step fails, but the program goes on
+            QueryResultBuilderError::RowLimitExceeded(limit) => Self::RowLimitExceeded(limit),
+            other => Self::BuilderError(other),
+        }
+    }
+}
+
 impl From<bincode::Error> for Error {
     fn from(other: bincode::Error) -> Self {
         Self::Internal(other.to_string())
@@ -165,10 +243,34 @@ pub enum LoadDumpError {
     Fetch(#[from] hyper::Error),
     #[error("unsupported dump url scheme `{0}`, supported schemes are: `http`, `file`")]
     UnsupportedUrlScheme(String),
+    #[error(
+        "unsupported dump content encoding `{0}`, supported encodings are: `identity`, `gzip`"
+    )]
+    UnsupportedContentEncoding(String),
+    #[error("the database is not empty, pass `allow_non_empty=true` to load the dump anyway")]
+    NonEmptyDb,
+    #[error("invalid statement at line {line} of the dump: {error}")]
+    InvalidStatement { line: u64, error: String },
+    #[error("the load of a dump is in progress, resume it from offset {0} with a `Range: bytes={0}-` header")]
+    LoadInProgress(u64),
+    #[error("the load of the dump can only be resumed from offset {0}")]
+    InvalidResumeOffset(u64),
+    #[error("there is no load of a dump to resume")]
+    NoLoadInProgress,
+    #[error("invalid `Range` header, expected `bytes=<offset>-`")]
+    InvalidRange,
 }
 
 impl ResponseError for LoadDumpError {}
 
+impl LoadDumpError {
+    fn format_offset_err(&self, status: StatusCode, offset: u64) -> axum::response::Response {
+        let json = serde_json::json!({ "error": self.to_string(), "offset": offset });
+        tracing::error!("HTTP API: {}, {}", status, json);
+        (status, axum::Json(json)).into_response()
+    }
+}
+
 impl IntoResponse for LoadDumpError {
     fn into_response(self) -> axum::response::Response {
         use LoadDumpError::*;
@@ -180,7 +282,22 @@ impl IntoResponse for LoadDumpError {
             | InvalidDumpUrl
             | DumpFileDoesntExist
             | UnsupportedUrlScheme(_)
+            | UnsupportedContentEncoding(_)
+            | NonEmptyDb
+            | InvalidRange
             | DumpFilePathNotAbsolute => self.format_err(StatusCode::BAD_REQUEST),
+            NoLoadInProgress => self.format_err(StatusCode::RANGE_NOT_SATISFIABLE),
+            // the offset is reported on its own, for clients to resume the load from it
+            LoadInProgress(offset) => self.format_offset_err(StatusCode::CONFLICT, *offset),
+            InvalidResumeOffset(offset) => {
+                self.format_offset_err(StatusCode::RANGE_NOT_SATISFIABLE, *offset)
+            }
+            // the line is reported on its own, for clients to point at the failing statement
+            InvalidStatement { line, .. } => {
+                let json = serde_json::json!({ "error": self.to_string(), "line": line });
+                tracing::error!("HTTP API: {}, {}", StatusCode::BAD_REQUEST, json);
+                (StatusCode::BAD_REQUEST, axum::Json(json)).into_response()
+            }
         }
     }
 }
@@ -194,7 +311,43 @@ impl IntoResponse for ForkError {
             | ForkError::Io(_)
             | ForkError::LogRead(_)
             | ForkError::CreateNamespace(_) => self.format_err(StatusCode::INTERNAL_SERVER_ERROR),
-            ForkError::ForkReplica => self.format_err(StatusCode::BAD_REQUEST),
+            ForkError::ForkReplica | ForkError::ForkInMemory => {
+                self.format_err(StatusCode::BAD_REQUEST)
+            }
+        }
+    }
+}
+
+impl ResponseError for MigrateError {}
+
+impl IntoResponse for MigrateError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            MigrateError::Internal(_)
+            | MigrateError::Io(_)
+            | MigrateError::Checkpoint(_)
+            | MigrateError::Upload(_)
+            | MigrateError::Config(_) => self.format_err(StatusCode::INTERNAL_SERVER_ERROR),
+            MigrateError::Rejected(_) => self.format_err(StatusCode::BAD_GATEWAY),
+            MigrateError::InvalidTarget(_)
+            | MigrateError::AlreadyMigrated(_, _)
+            | MigrateError::MigrateReplica => self.format_err(StatusCode::BAD_REQUEST),
+            MigrateError::InProgress(_) => self.format_err(StatusCode::CONFLICT),
+        }
+    }
+}
+
+impl ResponseError for RestoreError {}
+
+impl IntoResponse for RestoreError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            RestoreError::Internal(_)
+            | RestoreError::Io(_)
+            | RestoreError::Bottomless(_)
+            | RestoreError::OpenRestored(_) => self.format_err(StatusCode::INTERNAL_SERVER_ERROR),
+            RestoreError::NoGeneration(_) => self.format_err(StatusCode::NOT_FOUND),
+            RestoreError::InProgress(_) => self.format_err(StatusCode::CONFLICT),
         }
     }
 }
@@ -97,7 +97,7 @@ where
 #[derive(Debug, Clone)]
 pub struct H2c<S> {
     s: S,
-    connect_info: TcpConnectInfo,
+    connect_info: Option<TcpConnectInfo>,
 }
 
 impl<S> Service<Request<Body>> for H2c<S>
@@ -124,7 +124,9 @@ where
         let connect_info = self.connect_info.clone();
 
         Box::pin(async move {
-            req.extensions_mut().insert(connect_info.clone());
+            if let Some(connect_info) = connect_info.clone() {
+                req.extensions_mut().insert(connect_info);
+            }
 
             // Check if this request is a `h2c` upgrade, if it is not pass
             // the request to the inner service, which in our case is the
@@ -153,7 +155,9 @@ where
                     .serve_connection(
                         upgraded_io,
                         tower::service_fn(move |mut r: hyper::Request<hyper::Body>| {
-                            r.extensions_mut().insert(connect_info.clone());
+                            if let Some(connect_info) = connect_info.clone() {
+                                r.extensions_mut().insert(connect_info);
+                            }
                             svc.call(r)
                         }),
                     )
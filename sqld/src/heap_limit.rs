@@ -0,0 +1,417 @@
+//! Heap limits of namespaces.
+//!
+//! SQLite's soft and hard heap limits apply to the whole process, so a namespace using a lot of
+//! memory can starve the others. To limit each namespace on its own, we register a memory
+//! allocator wrapping the default SQLite allocator, which charges every allocation to the
+//! [NamespaceHeap] of the connection running on the current thread, see [enter]. Each allocation
+//! is prefixed with a header pointing to the heap it was charged to, so that it's released from
+//! the same heap when it's freed, whichever thread frees it. Every allocation also holds a
+//! reference to its heap, so that the heap of a namespace is freed once the namespace is dropped
+//! and all the memory charged to it is released.
+//!
+//! An allocation that would take a namespace over its hard limit fails, and SQLite reports
+//! `SQLITE_NOMEM` for the statement. Connections release the memory they can spare, such as their
+//! page cache, when their namespace is over its soft limit.
+//!
+//! Connections to a namespace close to its soft limit are also throttled: each new connection
+//! takes more units of the concurrency semaphore of the namespace, like under memory pressure from
+//! the responses, see [NamespaceHeap::soft_limit_usage]. The process-wide limits set with
+//! `--soft-heap-limit-mb` and `--hard-heap-limit-mb` still apply on top of the namespace limits.
+//!
+//! The connections also record how their memory is used, as reported by `sqlite3_db_status`, in
+//! the heap of their namespace, see [NamespaceHeap::connections_memory].
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Once, Weak};
+
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sqld_libsql_bindings::ffi::{
+    sqlite3_config, sqlite3_mem_methods, SQLITE_CONFIG_GETMALLOC, SQLITE_CONFIG_MALLOC, SQLITE_OK,
+};
+
+/// Size of the header of every allocation. It holds a pointer to the heap the allocation is
+/// charged to, and is padded to keep the 8-byte alignment guaranteed by SQLite.
+const HEADER_SIZE: usize = 16;
+
+const NO_LIMIT: u64 = u64::MAX;
+
+/// Heaps of the namespaces, by namespace name. A heap removes itself from the map when it's
+/// dropped. A namespace that is loaded again while memory is still
+/// charged to its previous heap gets that heap back.
+static HEAPS: Lazy<Mutex<HashMap<String, Weak<NamespaceHeap>>>> = Lazy::new(Default::default);
+
+/// The default SQLite allocator, which the registered allocator wraps.
+static UNDERLYING: OnceCell<Underlying> = OnceCell::new();
+
+struct Underlying(sqlite3_mem_methods);
+
+// the methods are thread safe, and the app data is only passed back to them
+unsafe impl Send for Underlying {}
+unsafe impl Sync for Underlying {}
+
+/// Whether the allocator was registered, and heap limits are enforced.
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Heap charged with the allocations of the current thread.
+    static CURRENT: Cell<*const NamespaceHeap> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Memory used by the connections to a namespace, and its limits.
+#[derive(Debug)]
+pub struct NamespaceHeap {
+    namespace: String,
+    used: AtomicU64,
+    soft_limit: AtomicU64,
+    hard_limit: AtomicU64,
+    /// Memory status of the open connections, by connection id.
+    connections: Mutex<HashMap<u64, Arc<Mutex<MemoryStatus>>>>,
+    next_connection_id: AtomicU64,
+}
+
+impl NamespaceHeap {
+    fn new(namespace: String) -> Self {
+        Self {
+            namespace,
+            used: AtomicU64::new(0),
+            soft_limit: AtomicU64::new(NO_LIMIT),
+            hard_limit: AtomicU64::new(NO_LIMIT),
+            connections: Default::default(),
+            next_connection_id: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Memory used by a connection, in bytes, as reported by `sqlite3_db_status`.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct MemoryStatus {
+    /// Memory used by the page cache (`SQLITE_DBSTATUS_CACHE_USED`).
+    pub cache_used: u64,
+    /// Memory used by the schema (`SQLITE_DBSTATUS_SCHEMA_USED`).
+    pub schema_used: u64,
+    /// Memory used by the prepared statements (`SQLITE_DBSTATUS_STMT_USED`).
+    pub stmt_used: u64,
+}
+
+/// Memory status of a connection, registered in the heap of its namespace until it's dropped.
+pub struct ConnectionMemory {
+    heap: Arc<NamespaceHeap>,
+    id: u64,
+    status: Arc<Mutex<MemoryStatus>>,
+}
+
+impl ConnectionMemory {
+    pub fn record(&self, status: MemoryStatus) {
+        *self.status.lock() = status;
+    }
+}
+
+impl Drop for ConnectionMemory {
+    fn drop(&mut self) {
+        self.heap.connections.lock().remove(&self.id);
+    }
+}
+
+impl NamespaceHeap {
+    /// Number of bytes allocated by the connections to the namespace.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Sets the soft and hard limits of the heap, in MiB, or lifts them if `None`.
+    pub fn set_limits(&self, soft_limit_mb: Option<usize>, hard_limit_mb: Option<usize>) {
+        let to_bytes = |mb: Option<usize>| mb.map_or(NO_LIMIT, |mb| mb as u64 * 1024 * 1024);
+        self.soft_limit
+            .store(to_bytes(soft_limit_mb), Ordering::Relaxed);
+        self.hard_limit
+            .store(to_bytes(hard_limit_mb), Ordering::Relaxed);
+    }
+
+    /// Whether more memory than the soft limit is in use, and connections should release the
+    /// memory they can spare. Always false if the limits are not enforced.
+    pub fn is_over_soft_limit(&self) -> bool {
+        REGISTERED.load(Ordering::Relaxed) && self.used() > self.soft_limit.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of the soft limit which is in use, e.g. 0.5 when half of it is used, or `None` if
+    /// there is no soft limit or the limits are not enforced.
+    pub fn soft_limit_usage(&self) -> Option<f64> {
+        let soft_limit = self.soft_limit.load(Ordering::Relaxed);
+        if !REGISTERED.load(Ordering::Relaxed) || soft_limit == NO_LIMIT {
+            return None;
+        }
+        Some(self.used() as f64 / soft_limit.max(1) as f64)
+    }
+
+    /// Soft and hard limits of the heap, in bytes, `None` if there is no limit.
+    pub fn limits(&self) -> (Option<u64>, Option<u64>) {
+        let limit =
+            |limit: &AtomicU64| Some(limit.load(Ordering::Relaxed)).filter(|&l| l != NO_LIMIT);
+        (limit(&self.soft_limit), limit(&self.hard_limit))
+    }
+
+    /// Registers a connection to the namespace, which records its memory status in the returned
+    /// handle.
+    pub fn register_connection(self: &Arc<Self>) -> ConnectionMemory {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let status = Arc::<Mutex<MemoryStatus>>::default();
+        self.connections.lock().insert(id, status.clone());
+        ConnectionMemory {
+            heap: self.clone(),
+            id,
+            status,
+        }
+    }
+
+    /// Memory status of the open connections to the namespace, as last recorded by each of them.
+    pub fn connections_memory(&self) -> Vec<MemoryStatus> {
+        self.connections
+            .lock()
+            .values()
+            .map(|status| *status.lock())
+            .collect()
+    }
+
+    /// Whether `size` more bytes can be allocated without going over the hard limit. Concurrent
+    /// allocations are checked independently, so they can overshoot the limit by their own size.
+    fn admits(&self, size: u64) -> bool {
+        self.used().saturating_add(size) <= self.hard_limit.load(Ordering::Relaxed)
+    }
+
+    fn charge(&self, size: u64) {
+        self.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn release(&self, size: u64) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+impl Drop for NamespaceHeap {
+    fn drop(&mut self) {
+        let mut heaps = HEAPS.lock();
+        // the namespace may already have a new heap, if it was loaded again
+        if heaps
+            .get(&self.namespace)
+            .map_or(false, |heap| heap.strong_count() == 0)
+        {
+            heaps.remove(&self.namespace);
+        }
+    }
+}
+
+/// Returns the heap of a namespace, shared by all the connections to it. It lives as long as it's
+/// referenced, or memory is charged to it.
+pub fn namespace_heap(namespace: &str) -> Arc<NamespaceHeap> {
+    let mut heaps = HEAPS.lock();
+    if let Some(heap) = heaps.get(namespace).and_then(Weak::upgrade) {
+        return heap;
+    }
+    let heap = Arc::new(NamespaceHeap::new(namespace.to_string()));
+    heaps.insert(namespace.to_string(), Arc::downgrade(&heap));
+    heap
+}
+
+/// Charges the allocations made by the current thread to `heap`, until the returned guard is
+/// dropped.
+pub fn enter(heap: Arc<NamespaceHeap>) -> HeapGuard {
+    let previous = CURRENT.with(|current| current.replace(Arc::as_ptr(&heap)));
+    HeapGuard {
+        previous,
+        _heap: heap,
+    }
+}
+
+pub struct HeapGuard {
+    previous: *const NamespaceHeap,
+    /// Keeps the heap alive while it's the current heap of the thread.
+    _heap: Arc<NamespaceHeap>,
+}
+
+impl Drop for HeapGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+/// Registers the allocator enforcing the heap limits of namespaces. It must be called before
+/// SQLite is initialized, otherwise the limits are not enforced.
+pub fn register() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| unsafe {
+        let mut underlying: sqlite3_mem_methods = std::mem::zeroed();
+        let rc = sqlite3_config(
+            SQLITE_CONFIG_GETMALLOC,
+            &mut underlying as *mut sqlite3_mem_methods,
+        );
+        if rc != SQLITE_OK {
+            tracing::warn!("could not get the SQLite allocator ({rc}), namespace heap limits will not be enforced");
+            return;
+        }
+        let _ = UNDERLYING.set(Underlying(underlying));
+
+        let methods = sqlite3_mem_methods {
+            xMalloc: Some(x_malloc),
+            xFree: Some(x_free),
+            xRealloc: Some(x_realloc),
+            xSize: Some(x_size),
+            xRoundup: Some(x_roundup),
+            ..underlying
+        };
+        // SQLite copies the methods
+        let rc = sqlite3_config(
+            SQLITE_CONFIG_MALLOC,
+            &methods as *const sqlite3_mem_methods,
+        );
+        if rc != SQLITE_OK {
+            tracing::warn!("could not register the SQLite allocator ({rc}), namespace heap limits will not be enforced");
+            return;
+        }
+        REGISTERED.store(true, Ordering::Relaxed);
+    });
+}
+
+fn underlying() -> &'static sqlite3_mem_methods {
+    &UNDERLYING
+        .get()
+        .expect("allocator used before it was registered")
+        .0
+}
+
+/// Size of the allocation at `base`, header included.
+unsafe fn allocation_size(base: *mut c_void) -> u64 {
+    underlying().xSize.unwrap()(base) as u64
+}
+
+unsafe extern "C" fn x_malloc(size: c_int) -> *mut c_void {
+    let heap = CURRENT.with(|current| current.get());
+    let full_size = size as usize + HEADER_SIZE;
+    if !heap.is_null() && !(*heap).admits(full_size as u64) {
+        return std::ptr::null_mut();
+    }
+    let base = underlying().xMalloc.unwrap()(full_size as c_int);
+    if base.is_null() {
+        return base;
+    }
+    (base as *mut *const NamespaceHeap).write(heap);
+    if !heap.is_null() {
+        // the allocation holds a reference to the heap until it's freed
+        Arc::increment_strong_count(heap);
+        (*heap).charge(allocation_size(base));
+    }
+    base.add(HEADER_SIZE)
+}
+
+unsafe extern "C" fn x_free(ptr: *mut c_void) {
+    let base = ptr.sub(HEADER_SIZE);
+    let heap = (base as *const *const NamespaceHeap).read();
+    let size = allocation_size(base);
+    underlying().xFree.unwrap()(base);
+    if !heap.is_null() {
+        (*heap).release(size);
+        Arc::decrement_strong_count(heap);
+    }
+}
+
+/// Reallocations are charged to the heap of the original allocation.
+unsafe extern "C" fn x_realloc(ptr: *mut c_void, size: c_int) -> *mut c_void {
+    let base = ptr.sub(HEADER_SIZE);
+    let heap = (base as *const *const NamespaceHeap).read();
+    let old_size = allocation_size(base);
+    let full_size = size as usize + HEADER_SIZE;
+    if !heap.is_null()
+        && full_size as u64 > old_size
+        && !(*heap).admits(full_size as u64 - old_size)
+    {
+        return std::ptr::null_mut();
+    }
+    let base = underlying().xRealloc.unwrap()(base, full_size as c_int);
+    if base.is_null() {
+        return base;
+    }
+    if !heap.is_null() {
+        (*heap).release(old_size);
+        (*heap).charge(allocation_size(base));
+    }
+    base.add(HEADER_SIZE)
+}
+
+unsafe extern "C" fn x_size(ptr: *mut c_void) -> c_int {
+    if ptr.is_null() {
+        return 0;
+    }
+    (allocation_size(ptr.sub(HEADER_SIZE)) as usize - HEADER_SIZE) as c_int
+}
+
+unsafe extern "C" fn x_roundup(size: c_int) -> c_int {
+    underlying().xRoundup.unwrap()(size + HEADER_SIZE as c_int) - HEADER_SIZE as c_int
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hard_limit_admits_allocations_below_it() {
+        let heap = NamespaceHeap::new("heap-limit-test-admits".to_string());
+        assert!(heap.admits(u64::MAX));
+
+        heap.set_limits(None, Some(1));
+        heap.charge(1024 * 1024 - 10);
+        assert!(heap.admits(10));
+        assert!(!heap.admits(11));
+
+        heap.release(1024);
+        assert!(heap.admits(1034));
+
+        heap.set_limits(None, None);
+        assert!(heap.admits(u64::MAX));
+    }
+
+    #[test]
+    fn connections_record_their_memory() {
+        let heap = namespace_heap("heap-limit-test-connections");
+        let conn = heap.register_connection();
+        conn.record(MemoryStatus {
+            cache_used: 1,
+            schema_used: 2,
+            stmt_used: 3,
+        });
+        let other = heap.register_connection();
+        let mut memory = heap.connections_memory();
+        memory.sort_by_key(|status| status.cache_used);
+        assert_eq!(memory.len(), 2);
+        assert_eq!(memory[0].cache_used, 0);
+        assert_eq!(memory[1].stmt_used, 3);
+
+        drop(other);
+        drop(conn);
+        assert!(heap.connections_memory().is_empty());
+    }
+
+    #[test]
+    fn namespaces_have_their_own_heap() {
+        let a = namespace_heap("heap-limit-test-a");
+        let b = namespace_heap("heap-limit-test-b");
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert!(Arc::ptr_eq(&a, &namespace_heap("heap-limit-test-a")));
+    }
+
+    #[test]
+    fn heaps_are_evicted_when_dropped() {
+        let heap = namespace_heap("heap-limit-test-evicted");
+        heap.set_limits(Some(1), None);
+        assert!(HEAPS.lock().contains_key("heap-limit-test-evicted"));
+
+        drop(heap);
+        assert!(!HEAPS.lock().contains_key("heap-limit-test-evicted"));
+        // the namespace gets a fresh heap when it's loaded again
+        let heap = namespace_heap("heap-limit-test-evicted");
+        assert_eq!(heap.limits(), (None, None));
+    }
+}
@@ -1,28 +1,120 @@
+use std::collections::BTreeMap;
 use std::time::Duration;
+
+use serde::Serialize;
 use tokio::time::sleep;
 
+use crate::config::HeartbeatAuth;
 use crate::http::stats::StatsResponse;
 use crate::stats::Stats;
 
+/// Delay before the first retry of a failed heartbeat. It's doubled on each consecutive failure,
+/// up to the regular heartbeat period.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// Number of consecutive failures after which the heartbeat is reported as failing.
+const MAX_SILENT_FAILURES: u32 = 10;
+
+#[derive(Serialize)]
+struct HeartbeatBody {
+    #[serde(flatten)]
+    stats: StatsResponse,
+    /// Unix timestamps of the last write to each namespace, in milliseconds. Namespaces that
+    /// were never written are omitted.
+    namespace_last_write_ms: BTreeMap<String, u64>,
+}
+
 pub async fn server_heartbeat(
     url: String,
-    auth: Option<String>,
+    auth: Option<HeartbeatAuth>,
     update_period: Duration,
     stats: Stats,
 ) {
     let client = reqwest::Client::new();
+    let mut failures: u32 = 0;
+    let mut next_delay = update_period;
     loop {
-        sleep(update_period).await;
-        let body = StatsResponse::from(&stats);
+        sleep(next_delay).await;
+        let body = HeartbeatBody {
+            stats: StatsResponse::from(&stats),
+            namespace_last_write_ms: stats.all_last_writes_ms(),
+        };
         let request = client.post(&url);
-        let request = if let Some(ref auth) = auth {
-            request.header("Authorization", auth.clone())
-        } else {
-            request
+        let request = match auth {
+            Some(ref auth) => match auth.get().await {
+                Ok(auth) => request.header("Authorization", auth),
+                Err(err) => {
+                    tracing::warn!("Error reading heartbeat auth, skipping heartbeat: {err:#}");
+                    next_delay = update_period;
+                    continue;
+                }
+            },
+            None => request,
         };
         let request = request.json(&body);
-        if let Err(err) = request.send().await {
-            tracing::warn!("Error sending heartbeat: {}", err);
+        match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => {
+                if failures > 0 {
+                    tracing::info!("Heartbeat recovered after {} failed attempts", failures);
+                }
+                failures = 0;
+                next_delay = update_period;
+            }
+            Err(err) => {
+                failures = failures.saturating_add(1);
+                let (delay, report) = retry_after_failure(failures, update_period);
+                next_delay = delay;
+                tracing::warn!(
+                    "Error sending heartbeat ({} consecutive failures), retrying in {:?}: {}",
+                    failures,
+                    next_delay,
+                    err
+                );
+                if report {
+                    tracing::error!(
+                        "Heartbeat to {} failed {} times in a row, the endpoint may be unavailable",
+                        url,
+                        failures
+                    );
+                }
+            }
         }
     }
 }
+
+/// Returns the delay before retrying after the `failures`-th consecutive failed heartbeat, and
+/// whether the failure should be reported as an error, which happens once per outage.
+fn retry_after_failure(failures: u32, update_period: Duration) -> (Duration, bool) {
+    let delay = INITIAL_RETRY_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(update_period);
+    (delay, failures == MAX_SILENT_FAILURES)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_update_period() {
+        let update_period = Duration::from_secs(30);
+        let delays: Vec<_> = (1..=7)
+            .map(|failures| retry_after_failure(failures, update_period).0)
+            .collect();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 16, 30, 30].map(Duration::from_secs).to_vec()
+        );
+        assert_eq!(
+            retry_after_failure(u32::MAX, update_period).0,
+            update_period
+        );
+    }
+
+    #[test]
+    fn failing_heartbeat_is_reported_once() {
+        let reported: Vec<_> = (1..=100)
+            .filter(|&failures| retry_after_failure(failures, Duration::from_secs(30)).1)
+            .collect();
+        assert_eq!(reported, [MAX_SILENT_FAILURES]);
+    }
+}
@@ -99,7 +99,9 @@ pub fn proto_batch_to_program(
         steps.push(step);
     }
 
-    Ok(Program::new(steps))
+    let mut pgm = Program::new(steps);
+    pgm.parallel = batch.parallel.unwrap_or(false);
+    Ok(pgm)
 }
 
 pub async fn execute_batch(
@@ -133,12 +135,17 @@ pub fn proto_sequence_to_program(sql: &str) -> Result<Program> {
                 stmt,
                 params: Params::empty(),
                 want_rows: false,
+                max_memory_bytes: None,
+                max_rows: None,
             };
             Step { cond, query }
         })
         .collect();
     Ok(Program {
         steps: Arc::new(steps),
+        dry_run: false,
+        parallel: false,
+        incremental: false,
     })
 }
 
@@ -199,3 +206,167 @@ impl BatchError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::connection::config::DatabaseConfigStore;
+    use crate::connection::libsql::LibSqlConnection;
+    use crate::query_result_builder::QueryBuilderConfig;
+    use crate::stats::Stats;
+
+    /// A batch which opens a transaction, unless one is already open, and writes in it.
+    fn begin_if_autocommit() -> proto::Batch {
+        serde_json::from_value(serde_json::json!({
+            "steps": [
+                { "stmt": { "sql": "BEGIN" }, "condition": { "type": "is_autocommit" } },
+                { "stmt": { "sql": "CREATE TABLE IF NOT EXISTS t (x)" } },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn is_autocommit_cond_requires_hrana3() {
+        let err = proto_batch_to_program(&begin_if_autocommit(), &HashMap::new(), Version::Hrana2)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::NotSupported { .. })
+        ));
+    }
+
+    async fn test_conn(path: &std::path::Path) -> LibSqlConnection {
+        LibSqlConnection::new(
+            path.to_owned(),
+            Arc::new([]),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Default::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn begin_only_in_autocommit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = test_conn(tmp.path()).await;
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let execute = || async {
+            let pgm =
+                proto_batch_to_program(&begin_if_autocommit(), &HashMap::new(), Version::Hrana3)
+                    .unwrap();
+            execute_batch(&conn, auth, pgm).await.unwrap()
+        };
+
+        let res = execute().await;
+        assert!(res.step_results[0].is_some());
+        assert!(res.step_errors.iter().all(Option::is_none));
+        assert!(!conn.is_autocommit().await.unwrap());
+
+        // the transaction is still open, so the BEGIN is skipped rather than failing
+        let res = execute().await;
+        assert!(res.step_results[0].is_none());
+        assert!(res.step_results[1].is_some());
+        assert!(res.step_errors.iter().all(Option::is_none));
+        assert!(!conn.is_autocommit().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn skipped_steps_are_not_executed_steps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = test_conn(tmp.path()).await;
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let batch: proto::Batch = serde_json::from_value(serde_json::json!({
+            "steps": [
+                { "stmt": { "sql": "CREATE TABLE t (x)" } },
+                { "stmt": { "sql": "INSERT INTO t VALUES (1)" } },
+                { "stmt": { "sql": "INSERT INTO missing VALUES (2)" } },
+                {
+                    "stmt": { "sql": "INSERT INTO t VALUES (3)" },
+                    "condition": { "type": "ok", "step": 2 },
+                },
+                {
+                    "stmt": { "sql": "INSERT INTO t VALUES (4)" },
+                    "condition": { "type": "error", "step": 2 },
+                },
+                {
+                    "stmt": { "sql": "INSERT INTO t VALUES (5)" },
+                    "condition": { "type": "ok", "step": 3 },
+                },
+                { "stmt": { "sql": "DELETE FROM t WHERE x = 0" } },
+            ],
+        }))
+        .unwrap();
+        let pgm = proto_batch_to_program(&batch, &HashMap::new(), Version::Hrana3).unwrap();
+        let res = execute_batch(&conn, auth, pgm).await.unwrap();
+
+        let executed = |i: usize| {
+            assert!(res.step_errors[i].is_none());
+            res.step_results[i].as_ref().unwrap()
+        };
+        let skipped = |i: usize| res.step_results[i].is_none() && res.step_errors[i].is_none();
+
+        // the rowid is the one of the step, not the last one of the batch
+        assert_eq!(executed(1).affected_row_count, 1);
+        assert_eq!(executed(1).last_insert_rowid, Some(1));
+        assert!(res.step_results[2].is_none());
+        assert!(res.step_errors[2].is_some());
+        assert!(skipped(3));
+        assert_eq!(executed(4).affected_row_count, 1);
+        assert_eq!(executed(4).last_insert_rowid, Some(2));
+        assert!(skipped(5));
+        // a step which ran but changed nothing still has a result
+        assert_eq!(executed(6).affected_row_count, 0);
+        assert_eq!(executed(6).last_insert_rowid, None);
+    }
+
+    #[tokio::test]
+    async fn unreleased_savepoint_is_rolled_back_on_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = test_conn(tmp.path()).await;
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let batch: proto::Batch = serde_json::from_value(serde_json::json!({
+            "steps": [
+                { "stmt": { "sql": "CREATE TABLE t (x)" } },
+                { "stmt": { "sql": "SAVEPOINT sp" } },
+                { "stmt": { "sql": "INSERT INTO t VALUES (1)" } },
+                { "stmt": { "sql": "INSERT INTO missing VALUES (2)" } },
+                {
+                    "stmt": { "sql": "RELEASE sp" },
+                    "condition": { "type": "ok", "step": 3 },
+                },
+                {
+                    "stmt": { "sql": "ROLLBACK" },
+                    "condition": { "type": "not", "cond": { "type": "ok", "step": 3 } },
+                },
+            ],
+        }))
+        .unwrap();
+        let pgm = proto_batch_to_program(&batch, &HashMap::new(), Version::Hrana3).unwrap();
+        let res = execute_batch(&conn, auth, pgm).await.unwrap();
+
+        // the savepoint opened a transaction, which the rollback closed
+        assert!(res.step_errors[3].is_some());
+        assert!(res.step_results[4].is_none());
+        assert!(res.step_results[5].is_some());
+        assert!(conn.is_autocommit().await.unwrap());
+
+        let pgm = Program::seq(&["SELECT count(*) FROM t"]);
+        let res = execute_batch(&conn, auth, pgm).await.unwrap();
+        let rows = &res.step_results[0].as_ref().unwrap().rows;
+        assert!(matches!(
+            rows[0].values[0],
+            proto::Value::Integer { value: 0 }
+        ));
+    }
+}
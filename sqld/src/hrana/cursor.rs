@@ -8,6 +8,8 @@ use tokio::sync::{mpsc, oneshot};
 use crate::auth::Authenticated;
 use crate::connection::program::Program;
 use crate::connection::Connection;
+use crate::query::{Params, Query};
+use crate::query_analysis::{Statement, StmtKind};
 use crate::query_result_builder::{
     Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
@@ -18,6 +20,11 @@ use super::{batch, proto, stmt};
 pub struct CursorHandle<C> {
     open_tx: Option<oneshot::Sender<OpenReq<C>>>,
     entry_rx: mpsc::Receiver<Result<SizedEntry>>,
+    /// Number of rows that the next fetches must skip.
+    skip_rows: u64,
+    /// Entry received by [CursorHandle::is_done], which the next fetch returns. `None` if there
+    /// is no such entry, and `Some(None)` if the cursor is known to be done.
+    peeked: Option<Option<Result<SizedEntry>>>,
 }
 
 #[derive(Debug)]
@@ -44,20 +51,70 @@ impl<C> CursorHandle<C> {
         Self {
             open_tx: Some(open_tx),
             entry_rx,
+            skip_rows: 0,
+            peeked: None,
         }
     }
 
-    pub fn open(&mut self, db: Arc<C>, auth: Authenticated, pgm: Program) {
+    pub fn open(&mut self, db: Arc<C>, auth: Authenticated, mut pgm: Program) {
+        // the rows are sent as they are fetched, so they are not limited like buffered rows
+        pgm.incremental = true;
         let open_tx = self.open_tx.take().unwrap();
         let _: Result<_, _> = open_tx.send(OpenReq { db, auth, pgm });
     }
 
+    /// Skips the next `count` rows, whichever steps they belong to: the next fetches consume them
+    /// from the cursor, but don't return them. The other entries are returned as usual.
+    pub fn skip(&mut self, count: u64) {
+        self.skip_rows += count;
+    }
+
     pub async fn fetch(&mut self) -> Result<Option<SizedEntry>> {
-        self.entry_rx.recv().await.transpose()
+        if let Some(entry) = self.peeked.take() {
+            return entry.transpose();
+        }
+        loop {
+            match self.entry_rx.recv().await.transpose()? {
+                Some(entry) if self.skips(&entry) => continue,
+                entry => return Ok(entry),
+            }
+        }
     }
 
     pub fn poll_fetch(&mut self, cx: &mut task::Context) -> task::Poll<Option<Result<SizedEntry>>> {
-        self.entry_rx.poll_recv(cx)
+        if let Some(entry) = self.peeked.take() {
+            return task::Poll::Ready(entry);
+        }
+        loop {
+            match task::ready!(self.entry_rx.poll_recv(cx)) {
+                Some(Ok(entry)) if self.skips(&entry) => continue,
+                entry => return task::Poll::Ready(entry),
+            }
+        }
+    }
+
+    /// Returns whether the cursor is known to have no more entries, without consuming the next
+    /// entry. This doesn't wait for the cursor: it returns `false` if the cursor hasn't produced
+    /// its next entry, or hasn't ended, yet.
+    pub fn is_done(&mut self) -> bool {
+        while self.peeked.is_none() {
+            match self.entry_rx.try_recv() {
+                Ok(Ok(entry)) if self.skips(&entry) => continue,
+                Ok(entry) => self.peeked = Some(Some(entry)),
+                Err(mpsc::error::TryRecvError::Empty) => return false,
+                Err(mpsc::error::TryRecvError::Disconnected) => self.peeked = Some(None),
+            }
+        }
+        matches!(self.peeked, Some(None))
+    }
+
+    /// Whether the entry is a row to skip, which is then counted as skipped.
+    fn skips(&mut self, entry: &SizedEntry) -> bool {
+        if self.skip_rows == 0 || !matches!(entry.entry, proto::CursorEntry::Row { .. }) {
+            return false;
+        }
+        self.skip_rows -= 1;
+        true
     }
 }
 
@@ -69,10 +126,12 @@ async fn run_cursor<C: Connection>(
         return
     };
 
+    let total_rows = count_rows(&*open_req.db, open_req.auth, &open_req.pgm).await;
     let result_builder = CursorResultBuilder {
         entry_tx: entry_tx.clone(),
         step_i: 0,
         step_state: StepState::default(),
+        total_rows,
     };
 
     if let Err(err) = open_req
@@ -93,10 +152,48 @@ async fn run_cursor<C: Connection>(
     }
 }
 
+/// Counts the rows returned by the leading read steps of the program, by step, when SQLite can
+/// count them cheaply (see [Statement::count_rows_query]). The following steps are not counted,
+/// because the steps before them may change the rows. The counts are only hints: outside of a
+/// transaction, the rows may change between the count and the step.
+///
+/// [Statement::count_rows_query]: crate::query_analysis::Statement::count_rows_query
+async fn count_rows<C: Connection>(db: &C, auth: Authenticated, pgm: &Program) -> Vec<Option<u64>> {
+    let mut counts = Vec::new();
+    for step in pgm.steps() {
+        if step.query.stmt.kind != StmtKind::Read {
+            break;
+        }
+        let count = match step.query.stmt.count_rows_query() {
+            Some(sql) => count_rows_of(db, auth, &sql).await,
+            None => None,
+        };
+        counts.push(count);
+    }
+    counts
+}
+
+async fn count_rows_of<C: Connection>(db: &C, auth: Authenticated, sql: &str) -> Option<u64> {
+    let query = Query {
+        stmt: Statement::parse(sql).next()?.ok()?,
+        params: Params::empty(),
+        want_rows: true,
+        max_memory_bytes: None,
+        max_rows: None,
+    };
+    let result = stmt::execute_stmt(db, auth, query).await.ok()?;
+    match result.rows.first()?.values.first()? {
+        proto::Value::Integer { value } => u64::try_from(*value).ok(),
+        _ => None,
+    }
+}
+
 struct CursorResultBuilder {
     entry_tx: mpsc::Sender<Result<SizedEntry>>,
     step_i: u32,
     step_state: StepState,
+    /// Number of rows returned by the steps, by step, if they were counted.
+    total_rows: Vec<Option<u64>>,
 }
 
 #[derive(Debug, Default)]
@@ -193,6 +290,7 @@ impl QueryResultBuilder for CursorResultBuilder {
             entry: proto::CursorEntry::StepBegin(proto::StepBeginEntry {
                 step: self.step_i,
                 cols,
+                total_rows: self.total_rows.get(self.step_i as usize).copied().flatten(),
             }),
             size: cols_size,
         }));
@@ -245,3 +343,84 @@ impl QueryResultBuilder for CursorResultBuilder {
 
     fn into_ret(self) {}
 }
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::connection::config::DatabaseConfigStore;
+    use crate::connection::libsql::LibSqlConnection;
+    use crate::query_result_builder::IgnoreResult;
+    use crate::stats::Stats;
+
+    #[tokio::test]
+    async fn skip_rows_and_count_them() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlConnection::new(
+            tmp.path().to_owned(),
+            Arc::new([]),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Default::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let db = Arc::new(db);
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        db.execute_program(
+            Program::seq(&[
+                "CREATE TABLE t (x)",
+                "INSERT INTO t VALUES (1), (2), (3), (4), (5)",
+            ]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        let mut join_set = tokio::task::JoinSet::new();
+        let mut cursor = CursorHandle::spawn(&mut join_set);
+        cursor.open(
+            db,
+            auth,
+            Program::seq(&["SELECT * FROM t", "SELECT x FROM t WHERE x > 3"]),
+        );
+        cursor.skip(6);
+
+        let mut entries = Vec::new();
+        while let Some(entry) = cursor.fetch().await.unwrap() {
+            entries.push(entry.entry);
+        }
+
+        let rows = |entries: &[proto::CursorEntry]| -> Vec<i64> {
+            entries
+                .iter()
+                .filter_map(|entry| match entry {
+                    proto::CursorEntry::Row { row } => match row.values[..] {
+                        [proto::Value::Integer { value }] => Some(value),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect()
+        };
+        // the skipped rows span both steps, whose entries are still returned
+        assert_eq!(rows(&entries), [5]);
+        let begins: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                proto::CursorEntry::StepBegin(begin) => Some((begin.step, begin.total_rows)),
+                _ => None,
+            })
+            .collect();
+        // only the rows of the whole table are counted
+        assert_eq!(begins, [(0, Some(5)), (1, None)]);
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{Context, Result};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures::stream::Stream;
 use parking_lot::Mutex;
 use serde::{de::DeserializeOwned, Serialize};
@@ -16,8 +16,13 @@ mod request;
 mod stream;
 
 pub struct Server<C> {
+    /// Base URL returned to the clients for the next requests of their streams. Streams are local
+    /// to this server, so when it's unset, the `base_url` of the responses is `null`, which tells
+    /// clients to keep using the URL of their previous request.
     self_url: Option<String>,
     baton_key: [u8; 32],
+    /// Maximum size of the body of a request, in bytes.
+    max_request_size: u64,
     stream_state: Mutex<stream::ServerStreamState<C>>,
 }
 
@@ -28,10 +33,11 @@ pub enum Endpoint {
 }
 
 impl<C: Connection> Server<C> {
-    pub fn new(self_url: Option<String>) -> Self {
+    pub fn new(self_url: Option<String>, max_request_size: u64) -> Self {
         Self {
             self_url,
             baton_key: rand::random(),
+            max_request_size,
             stream_state: Mutex::new(stream::ServerStreamState::new()),
         }
     }
@@ -105,7 +111,8 @@ async fn handle_pipeline<C: Connection>(
     version: Version,
     encoding: Encoding,
 ) -> Result<hyper::Response<hyper::Body>> {
-    let req_body: proto::PipelineReqBody = read_decode_request(req, encoding).await?;
+    let req_body: proto::PipelineReqBody =
+        read_decode_request(req, encoding, server.max_request_size).await?;
     let mut stream_guard =
         stream::acquire(server, connection_maker, req_body.baton.as_deref()).await?;
 
@@ -132,7 +139,8 @@ async fn handle_cursor<C: Connection>(
     version: Version,
     encoding: Encoding,
 ) -> Result<hyper::Response<hyper::Body>> {
-    let req_body: proto::CursorReqBody = read_decode_request(req, encoding).await?;
+    let req_body: proto::CursorReqBody =
+        read_decode_request(req, encoding, server.max_request_size).await?;
     let stream_guard = stream::acquire(server, connection_maker, req_body.baton.as_deref()).await?;
 
     let mut join_set = tokio::task::JoinSet::new();
@@ -154,7 +162,7 @@ async fn handle_cursor<C: Connection>(
     });
     let content_type = match encoding {
         Encoding::Json => "text/plain",
-        Encoding::Protobuf => "application/octet-stream",
+        Encoding::Protobuf | Encoding::MessagePack => "application/octet-stream",
     };
 
     Ok(hyper::Response::builder()
@@ -214,6 +222,10 @@ fn encode_stream_item<T: Serialize + prost::Message>(item: &T, encoding: Encodin
         Encoding::Protobuf => {
             data = <T as prost::Message>::encode_length_delimited_to_vec(item);
         }
+        // MessagePack values are self-delimiting
+        Encoding::MessagePack => {
+            data = rmp_serde::to_vec_named(item).unwrap();
+        }
     }
     Bytes::from(data)
 }
@@ -221,10 +233,9 @@ fn encode_stream_item<T: Serialize + prost::Message>(item: &T, encoding: Encodin
 async fn read_decode_request<T: DeserializeOwned + prost::Message + Default>(
     req: hyper::Request<hyper::Body>,
     encoding: Encoding,
+    max_size: u64,
 ) -> Result<T> {
-    let req_body = hyper::body::to_bytes(req.into_body())
-        .await
-        .context("Could not read request body")?;
+    let req_body = read_body(req.into_body(), max_size).await?;
     match encoding {
         Encoding::Json => serde_json::from_slice(&req_body)
             .map_err(|err| ProtocolError::JsonDeserialize { source: err })
@@ -232,11 +243,40 @@ async fn read_decode_request<T: DeserializeOwned + prost::Message + Default>(
         Encoding::Protobuf => <T as prost::Message>::decode(req_body)
             .map_err(|err| ProtocolError::ProtobufDecode { source: err })
             .context("Could not decode Protobuf request body"),
+        Encoding::MessagePack => rmp_serde::from_slice(&req_body)
+            .map_err(|err| ProtocolError::MessagePackDecode { source: err })
+            .context("Could not decode MessagePack request body"),
+    }
+}
+
+/// Reads the whole body of a request, failing with [ProtocolError::RequestTooLarge] as soon as it
+/// is larger than `max_size`, without buffering the rest of it.
+async fn read_body(mut body: hyper::Body, max_size: u64) -> Result<Bytes> {
+    let too_large = || anyhow::anyhow!(ProtocolError::RequestTooLarge { max_size });
+    if hyper::body::HttpBody::size_hint(&body)
+        .exact()
+        .map_or(false, |size| size > max_size)
+    {
+        return Err(too_large());
+    }
+
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+        let chunk = chunk.context("Could not read request body")?;
+        if (buf.len() + chunk.len()) as u64 > max_size {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk);
     }
+    Ok(buf.freeze())
 }
 
 fn protocol_error_response(err: ProtocolError) -> hyper::Response<hyper::Body> {
-    text_response(hyper::StatusCode::BAD_REQUEST, err.to_string())
+    let status = match err {
+        ProtocolError::RequestTooLarge { .. } => hyper::StatusCode::PAYLOAD_TOO_LARGE,
+        _ => hyper::StatusCode::BAD_REQUEST,
+    };
+    text_response(status, err.to_string())
 }
 
 fn stream_error_response(
@@ -264,6 +304,10 @@ fn encode_response<T: Serialize + prost::Message>(
             <T as prost::Message>::encode_to_vec(resp_body),
             "application/x-protobuf",
         ),
+        Encoding::MessagePack => (
+            rmp_serde::to_vec_named(resp_body).unwrap(),
+            "application/msgpack",
+        ),
     };
     hyper::Response::builder()
         .status(status)
@@ -279,3 +323,241 @@ fn text_response(status: hyper::StatusCode, resp_body: String) -> hyper::Respons
         .body(hyper::Body::from(resp_body))
         .unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use serde_json::{json, Value};
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::connection::config::DatabaseConfigStore;
+    use crate::connection::libsql::LibSqlConnection;
+    use crate::query_result_builder::QueryBuilderConfig;
+    use crate::stats::Stats;
+
+    use super::*;
+
+    async fn pipeline<C: Connection>(
+        server: &Server<C>,
+        connection_maker: Arc<dyn MakeConnection<Connection = C>>,
+        body: Value,
+    ) -> (hyper::StatusCode, Value) {
+        let req = hyper::Request::post("/v3/pipeline")
+            .body(hyper::Body::from(body.to_string()))
+            .unwrap();
+        let resp = server
+            .handle_request(
+                connection_maker,
+                Authenticated::Authorized(Authorized::FullAccess),
+                req,
+                Endpoint::Pipeline,
+                Version::Hrana3,
+                Encoding::Json,
+            )
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn execute(sql: &str) -> Value {
+        json!({ "type": "execute", "stmt": { "sql": sql } })
+    }
+
+    #[tokio::test]
+    async fn transaction_without_self_url() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().to_owned();
+        let connection_maker: Arc<dyn MakeConnection<Connection = LibSqlConnection>> =
+            Arc::new(move || {
+                let path: PathBuf = path.clone();
+                async move {
+                    LibSqlConnection::new(
+                        path,
+                        Arc::new([]),
+                        &TRANSPARENT_METHODS,
+                        (),
+                        Stats::default(),
+                        Default::default(),
+                        Arc::new(DatabaseConfigStore::new_test()),
+                        QueryBuilderConfig::default(),
+                        None,
+                        None,
+                    )
+                    .await
+                }
+            });
+        let server = Server::new(None, u64::MAX);
+
+        let (_, resp) = pipeline(
+            &server,
+            connection_maker.clone(),
+            json!({
+                "baton": null,
+                "requests": [
+                    execute("BEGIN"),
+                    execute("CREATE TABLE t (x)"),
+                    execute("INSERT INTO t VALUES (1)"),
+                ],
+            }),
+        )
+        .await;
+        // without a self URL, the client sends the next requests of the stream to the same URL
+        assert_eq!(resp["base_url"], Value::Null);
+        let baton = resp["baton"].as_str().unwrap().to_owned();
+        assert!(resp["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|res| res["type"] == "ok"));
+
+        let (_, resp) = pipeline(
+            &server,
+            connection_maker.clone(),
+            json!({
+                "baton": baton,
+                "requests": [
+                    execute("SELECT count(*) FROM t"),
+                    execute("COMMIT"),
+                    { "type": "close" },
+                ],
+            }),
+        )
+        .await;
+        assert_eq!(resp["results"][0]["type"], "ok", "{resp}");
+        assert_eq!(
+            resp["results"][0]["response"]["result"]["rows"][0][0]["value"],
+            "1"
+        );
+        assert_eq!(resp["results"][1]["type"], "ok", "{resp}");
+        assert_eq!(resp["baton"], Value::Null);
+
+        // batons are local to the server which issued them
+        let (_, resp) = pipeline(
+            &server,
+            connection_maker.clone(),
+            json!({ "baton": null, "requests": [execute("BEGIN")] }),
+        )
+        .await;
+        let baton = resp["baton"].as_str().unwrap().to_owned();
+        let (status, _) = pipeline(
+            &Server::new(None, u64::MAX),
+            connection_maker,
+            json!({ "baton": baton, "requests": [execute("COMMIT")] }),
+        )
+        .await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn write_results_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().to_owned();
+        let connection_maker: Arc<dyn MakeConnection<Connection = LibSqlConnection>> =
+            Arc::new(move || {
+                let path: PathBuf = path.clone();
+                async move {
+                    LibSqlConnection::new(
+                        path,
+                        Arc::new([]),
+                        &TRANSPARENT_METHODS,
+                        (),
+                        Stats::default(),
+                        Default::default(),
+                        Arc::new(DatabaseConfigStore::new_test()),
+                        QueryBuilderConfig::default(),
+                        None,
+                        None,
+                    )
+                    .await
+                }
+            });
+        let server = Server::new(None, u64::MAX);
+
+        let (_, resp) = pipeline(
+            &server,
+            connection_maker,
+            json!({
+                "baton": null,
+                "requests": [
+                    execute("CREATE TABLE t (id INTEGER PRIMARY KEY, x UNIQUE)"),
+                    execute("INSERT INTO t (x) VALUES ('a'), ('b')"),
+                    execute("UPDATE t SET x = 'c' WHERE id = 42"),
+                    execute("INSERT OR IGNORE INTO t (x) VALUES ('a')"),
+                    {
+                        "type": "batch",
+                        "batch": { "steps": [
+                            { "stmt": { "sql": "UPDATE t SET x = 'd' WHERE id = 1" } },
+                            { "stmt": { "sql": "INSERT OR IGNORE INTO t (x) VALUES ('b')" } },
+                            { "stmt": { "sql": "INSERT INTO t (x) VALUES ('e')" } },
+                        ] },
+                    },
+                    { "type": "close" },
+                ],
+            }),
+        )
+        .await;
+
+        let result = |i: usize| &resp["results"][i]["response"]["result"];
+        let expected = [
+            (0, Value::Null),
+            (2, json!("2")),
+            (0, Value::Null),
+            (0, Value::Null),
+        ];
+        for (i, (affected_row_count, last_insert_rowid)) in expected.into_iter().enumerate() {
+            assert_eq!(resp["results"][i]["type"], "ok", "{resp}");
+            assert_eq!(
+                result(i)["affected_row_count"],
+                affected_row_count,
+                "{resp}"
+            );
+            assert_eq!(result(i)["last_insert_rowid"], last_insert_rowid, "{resp}");
+        }
+
+        let step_results = &result(4)["step_results"];
+        let expected = [(1, Value::Null), (0, Value::Null), (1, json!("3"))];
+        for (i, (affected_row_count, last_insert_rowid)) in expected.into_iter().enumerate() {
+            assert_eq!(
+                step_results[i]["affected_row_count"], affected_row_count,
+                "{resp}"
+            );
+            assert_eq!(
+                step_results[i]["last_insert_rowid"], last_insert_rowid,
+                "{resp}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_too_large_request() {
+        let body = read_body(hyper::Body::from("x".repeat(64)), 64)
+            .await
+            .unwrap();
+        assert_eq!(body.len(), 64);
+
+        let is_too_large = |err: anyhow::Error| {
+            matches!(
+                err.downcast_ref::<ProtocolError>(),
+                Some(ProtocolError::RequestTooLarge { max_size: 64 })
+            )
+        };
+        let err = read_body(hyper::Body::from("x".repeat(65)), 64)
+            .await
+            .unwrap_err();
+        assert!(is_too_large(err));
+
+        // the size of a streamed body is not known in advance, so it's checked as it's read
+        let chunks = futures::stream::iter((0..3).map(|_| Ok::<_, std::io::Error>("x".repeat(30))));
+        let err = read_body(hyper::Body::wrap_stream(chunks), 64)
+            .await
+            .unwrap_err();
+        assert!(is_too_large(err));
+
+        let resp = protocol_error_response(ProtocolError::RequestTooLarge { max_size: 64 });
+        assert_eq!(resp.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -89,6 +89,11 @@ pub struct CloseStreamResp {}
 pub struct ExecuteStreamReq {
     #[prost(message, required, tag = "1")]
     pub stmt: Stmt,
+    /// Maximum number of rows returned by the statement, which only lowers the limit of the
+    /// server. Since Hrana 3.
+    #[serde(default)]
+    #[prost(uint64, optional, tag = "2")]
+    pub max_rows: Option<u64>,
 }
 
 #[derive(Serialize, prost::Message)]
@@ -130,6 +135,9 @@ pub struct DescribeStreamReq {
     #[serde(default)]
     #[prost(int32, optional, tag = "2")]
     pub sql_id: Option<i32>,
+    #[serde(default)]
+    #[prost(bool, optional, tag = "3")]
+    pub query_plan: Option<bool>,
 }
 
 #[derive(Serialize, prost::Message)]
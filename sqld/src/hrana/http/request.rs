@@ -60,10 +60,17 @@ async fn try_handle<D: Connection>(
             proto::StreamResponse::Close(proto::CloseStreamResp {})
         }
         proto::StreamRequest::Execute(req) => {
+            if req.max_rows.is_some() {
+                ensure_version!(
+                    Version::Hrana3,
+                    "The `max_rows` field of the `execute` request"
+                );
+            }
             let db = stream_guard.get_db()?;
             let sqls = stream_guard.sqls();
-            let query =
+            let mut query =
                 stmt::proto_stmt_to_query(&req.stmt, sqls, version).map_err(catch_stmt_error)?;
+            query.max_rows = req.max_rows;
             let result = stmt::execute_stmt(db, auth, query)
                 .await
                 .map_err(catch_stmt_error)?;
@@ -93,7 +100,8 @@ async fn try_handle<D: Connection>(
             let db = stream_guard.get_db()?;
             let sqls = stream_guard.sqls();
             let sql = stmt::proto_sql_to_sql(req.sql.as_deref(), req.sql_id, sqls, version)?;
-            let result = stmt::describe_stmt(db, auth, sql.into())
+            let query_plan = req.query_plan.unwrap_or(false);
+            let result = stmt::describe_stmt(db, auth, sql.into(), query_plan)
                 .await
                 .map_err(catch_stmt_error)?;
             proto::StreamResponse::Describe(proto::DescribeStreamResp { result })
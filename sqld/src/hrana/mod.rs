@@ -20,6 +20,8 @@ pub enum Version {
 pub enum Encoding {
     Json,
     Protobuf,
+    /// MessagePack, with the same structure as JSON.
+    MessagePack,
 }
 
 /// An unrecoverable protocol error that should close the WebSocket or HTTP stream. A correct
@@ -30,6 +32,8 @@ pub enum ProtocolError {
     JsonDeserialize { source: serde_json::Error },
     #[error("Could not decode client message from Protobuf: {source}")]
     ProtobufDecode { source: prost::DecodeError },
+    #[error("Could not decode client message from MessagePack: {source}")]
+    MessagePackDecode { source: rmp_serde::decode::Error },
     #[error("Received a binary WebSocket message, which is not supported in this encoding")]
     BinaryWebSocketMessage,
     #[error("Received a text WebSocket message, which is not supported in this encoding")]
@@ -76,6 +80,8 @@ pub enum ProtocolError {
 
     #[error("{0}")]
     ResponseTooLarge(String),
+    #[error("Request is larger than the maximum of {max_size} bytes")]
+    RequestTooLarge { max_size: u64 },
 
     #[error("BatchCond type not recognized")]
     NoneBatchCond,
@@ -89,6 +95,11 @@ pub enum ProtocolError {
     NoneStreamRequest,
 }
 
+impl Version {
+    /// The latest version of the protocol supported by the server.
+    pub const LATEST: Version = Version::Hrana3;
+}
+
 impl fmt::Display for Version {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -29,6 +29,12 @@ pub struct Stmt {
     #[serde(default)]
     #[prost(bool, optional, tag = "5")]
     pub want_rows: Option<bool>,
+    #[serde(default)]
+    #[prost(bool, optional, tag = "6")]
+    pub strict_args: Option<bool>,
+    #[serde(default)]
+    #[prost(uint64, optional, tag = "7")]
+    pub max_memory_bytes: Option<u64>,
 }
 
 #[derive(Deserialize, prost::Message)]
@@ -71,6 +77,9 @@ pub struct Row {
 pub struct Batch {
     #[prost(message, repeated, tag = "1")]
     pub steps: Vec<BatchStep>,
+    #[serde(default)]
+    #[prost(bool, optional, tag = "2")]
+    pub parallel: Option<bool>,
 }
 
 #[derive(Deserialize, prost::Message)]
@@ -137,6 +146,11 @@ pub struct StepBeginEntry {
     pub step: u32,
     #[prost(message, repeated, tag = "2")]
     pub cols: Vec<Col>,
+    /// Number of rows returned by the step, if the server could count them cheaply. This is only
+    /// a hint, the rows may change before they are read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[prost(uint64, optional, tag = "3")]
+    pub total_rows: Option<u64>,
 }
 
 #[derive(Serialize, prost::Message)]
@@ -165,6 +179,9 @@ pub struct DescribeResult {
     pub is_explain: bool,
     #[prost(bool, tag = "4")]
     pub is_readonly: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[prost(message, repeated, tag = "5")]
+    pub query_plan: Vec<DescribeQueryPlanStep>,
 }
 
 #[derive(Serialize, prost::Message)]
@@ -181,6 +198,16 @@ pub struct DescribeCol {
     pub decltype: Option<String>,
 }
 
+#[derive(Serialize, prost::Message)]
+pub struct DescribeQueryPlanStep {
+    #[prost(int32, tag = "1")]
+    pub id: i32,
+    #[prost(int32, tag = "2")]
+    pub parent: i32,
+    #[prost(string, tag = "3")]
+    pub detail: String,
+}
+
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Value {
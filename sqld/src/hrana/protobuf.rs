@@ -333,3 +333,172 @@ mod arc_str {
         key_len(tag) + encoded_len_varint(value.len() as u64) + value.len()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use prost::Message as _;
+
+    use super::*;
+    use crate::hrana::proto::{Col, Error, Row, StmtResult};
+
+    /// Mirror of `BatchResult` as described by the protobuf schema, used to decode what we encode.
+    #[derive(prost::Message)]
+    struct DecodedBatchResult {
+        #[prost(map = "uint32, message", tag = "1")]
+        step_results: HashMap<u32, StmtResult>,
+        #[prost(map = "uint32, message", tag = "2")]
+        step_errors: HashMap<u32, Error>,
+    }
+
+    /// Mirror of `BatchCond` as described by the protobuf schema, used to encode what we decode.
+    #[derive(prost::Message)]
+    struct EncodedBatchCond {
+        #[prost(oneof = "EncodedCond", tags = "1, 2, 4, 5, 6")]
+        cond: Option<EncodedCond>,
+    }
+
+    #[derive(prost::Oneof)]
+    enum EncodedCond {
+        #[prost(uint32, tag = "1")]
+        Ok(u32),
+        #[prost(uint32, tag = "2")]
+        Error(u32),
+        #[prost(message, tag = "4")]
+        And(EncodedCondList),
+        #[prost(message, tag = "5")]
+        Or(EncodedCondList),
+        #[prost(message, tag = "6")]
+        IsAutocommit(Empty),
+    }
+
+    #[derive(prost::Message)]
+    struct EncodedCondList {
+        #[prost(message, repeated, tag = "1")]
+        conds: Vec<EncodedBatchCond>,
+    }
+
+    #[derive(prost::Message)]
+    struct Empty {}
+
+    fn cond(cond: EncodedCond) -> EncodedBatchCond {
+        EncodedBatchCond { cond: Some(cond) }
+    }
+
+    #[test]
+    fn value_round_trip() {
+        let values = [
+            Value::Null,
+            Value::Integer { value: 0 },
+            Value::Integer { value: i64::MIN },
+            Value::Integer { value: i64::MAX },
+            Value::Float { value: -1.5 },
+            Value::Text { value: "".into() },
+            Value::Text {
+                value: "ahoj světe".into(),
+            },
+            Value::Blob {
+                value: Bytes::new(),
+            },
+            Value::Blob {
+                value: Bytes::from_static(&[0, 1, 0xfe, 0xff]),
+            },
+        ];
+        for value in values {
+            let encoded = value.encode_to_vec();
+            assert_eq!(encoded.len(), value.encoded_len());
+            let decoded = Value::decode(&encoded[..]).unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{value:?}"));
+        }
+    }
+
+    #[test]
+    fn null_is_distinct_from_missing_value() {
+        // NULL is an empty message in field 1, while a missing value has no field at all
+        assert_eq!(Value::Null.encode_to_vec(), b"\x0a\x00");
+        assert!(Value::None.encode_to_vec().is_empty());
+        assert!(matches!(
+            Value::decode(&b"\x0a\x00"[..]).unwrap(),
+            Value::Null
+        ));
+        assert!(matches!(Value::decode(&b""[..]).unwrap(), Value::None));
+    }
+
+    #[test]
+    fn encode_batch_result_as_map() {
+        let result = BatchResult {
+            step_results: vec![
+                Some(StmtResult {
+                    cols: vec![Col {
+                        name: Some("x".into()),
+                        decltype: None,
+                    }],
+                    rows: vec![
+                        Row {
+                            values: vec![Value::Null],
+                        },
+                        Row {
+                            values: vec![Value::Blob {
+                                value: Bytes::from_static(b"\0blob"),
+                            }],
+                        },
+                    ],
+                    affected_row_count: 0,
+                    last_insert_rowid: None,
+                }),
+                None,
+                None,
+            ],
+            step_errors: vec![
+                None,
+                Some(Error {
+                    message: "no such table: t".into(),
+                    code: "SQLITE_ERROR".into(),
+                }),
+                None,
+            ],
+        };
+        let encoded = result.encode_to_vec();
+        assert_eq!(encoded.len(), result.encoded_len());
+
+        let decoded = DecodedBatchResult::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.step_results.len(), 1);
+        assert_eq!(decoded.step_errors.len(), 1);
+        assert_eq!(decoded.step_errors[&1].code, "SQLITE_ERROR");
+
+        let rows = &decoded.step_results[&0].rows;
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows[0].values[..], [Value::Null]));
+        assert!(
+            matches!(&rows[1].values[..], [Value::Blob { value }] if value[..] == b"\0blob"[..])
+        );
+    }
+
+    #[test]
+    fn decode_batch_cond() {
+        let encoded = cond(EncodedCond::And(EncodedCondList {
+            conds: vec![
+                cond(EncodedCond::Ok(0)),
+                cond(EncodedCond::Or(EncodedCondList {
+                    conds: vec![
+                        cond(EncodedCond::Error(1)),
+                        cond(EncodedCond::IsAutocommit(Empty {})),
+                    ],
+                })),
+            ],
+        }))
+        .encode_to_vec();
+
+        let BatchCond::And(BatchCondList { conds }) = BatchCond::decode(&encoded[..]).unwrap()
+        else {
+            panic!("expected an `and` condition")
+        };
+        assert!(matches!(conds[0], BatchCond::Ok { step: 0 }));
+        let BatchCond::Or(BatchCondList { conds }) = &conds[1] else {
+            panic!("expected an `or` condition")
+        };
+        assert!(matches!(conds[0], BatchCond::Error { step: 1 }));
+        assert!(matches!(conds[1], BatchCond::IsAutocommit {}));
+    }
+}
@@ -25,6 +25,15 @@ pub enum StmtError {
     ArgsInvalid { source: anyhow::Error },
     #[error("Specifying both positional and named arguments is not supported")]
     ArgsBothPositionalAndNamed,
+    #[error("Statement has {count} arguments, more than the limit of {limit}")]
+    ArgsTooMany { count: usize, limit: usize },
+    #[error("Argument `{name}` does not match any parameter of the statement")]
+    UnknownParameter { name: String },
+    #[error("Statement has {expected} parameters, but {provided} arguments were provided")]
+    ArgsKindMismatch {
+        provided: &'static str,
+        expected: &'static str,
+    },
 
     #[error("Transaction timed out")]
     TransactionTimeout,
@@ -46,6 +55,18 @@ pub enum StmtError {
     Blocked { reason: Option<String> },
     #[error("Response is too large")]
     ResponseTooLarge,
+    #[error("Query exceeded the memory limit of {limit} bytes")]
+    MemoryLimitExceeded { limit: u64 },
+    #[error("Query returned more than {limit} rows, add a LIMIT clause or paginate the results")]
+    RowLimitExceeded { limit: u64 },
+    #[error("Database exceeds its size quota of {limit} bytes, writes are rejected")]
+    DatabaseFull { limit: u64 },
+    #[error("Namespace `{namespace}` is not allowed to be attached")]
+    AttachNotAllowed { namespace: String },
+    #[error("ATTACH and DETACH are not allowed on this namespace")]
+    AttachDisabled,
+    #[error("PRAGMA `{pragma}` is not allowed on this namespace")]
+    PragmaNotAllowed { pragma: String },
     #[error("error executing a request on the primary: {0}")]
     Proxy(String),
 }
@@ -67,8 +88,9 @@ pub async fn describe_stmt(
     db: &impl Connection,
     auth: Authenticated,
     sql: String,
+    query_plan: bool,
 ) -> Result<proto::DescribeResult> {
-    match db.describe(sql, auth).await? {
+    match db.describe(sql, auth, query_plan).await? {
         Ok(describe_response) => Ok(proto_describe_result_from_describe_response(
             describe_response,
         )),
@@ -117,14 +139,62 @@ pub fn proto_stmt_to_query(
         bail!(StmtError::ArgsBothPositionalAndNamed)
     };
 
+    if proto_stmt.strict_args.unwrap_or(false) {
+        check_args_strict(&stmt, proto_stmt)?;
+    }
+
     let want_rows = proto_stmt.want_rows.unwrap_or(true);
     Ok(Query {
         stmt,
         params,
         want_rows,
+        max_memory_bytes: proto_stmt.max_memory_bytes,
+        max_rows: None,
     })
 }
 
+/// Checks that the arguments of the statement are all used: SQLite silently ignores named
+/// arguments that don't match any parameter, which hides bugs in clients.
+fn check_args_strict(stmt: &Statement, proto_stmt: &proto::Stmt) -> Result<()> {
+    let params = stmt.parameters();
+    if params.is_empty() {
+        if let Some(arg) = proto_stmt.named_args.first() {
+            bail!(StmtError::UnknownParameter {
+                name: arg.name.clone()
+            })
+        }
+        return Ok(());
+    }
+
+    let is_named = |param: &&str| !param.starts_with('?');
+    if !proto_stmt.args.is_empty() && params.iter().all(is_named) {
+        bail!(StmtError::ArgsKindMismatch {
+            provided: "positional",
+            expected: "named",
+        })
+    }
+    if !proto_stmt.named_args.is_empty() && !params.iter().any(is_named) {
+        bail!(StmtError::ArgsKindMismatch {
+            provided: "named",
+            expected: "positional",
+        })
+    }
+
+    for arg in &proto_stmt.named_args {
+        // like when binding, the name may be given without its prefix
+        let is_used = params
+            .iter()
+            .any(|param| *param == arg.name || (is_named(param) && param[1..] == arg.name));
+        if !is_used {
+            bail!(StmtError::UnknownParameter {
+                name: arg.name.clone()
+            })
+        }
+    }
+
+    Ok(())
+}
+
 pub fn proto_sql_to_sql<'s>(
     proto_sql: Option<&'s str>,
     proto_sql_id: Option<i32>,
@@ -193,6 +263,15 @@ fn proto_describe_result_from_describe_response(
             .collect(),
         is_explain: response.is_explain,
         is_readonly: response.is_readonly,
+        query_plan: response
+            .query_plan
+            .into_iter()
+            .map(|step| proto::DescribeQueryPlanStep {
+                id: step.id,
+                parent: step.parent,
+                detail: step.detail,
+            })
+            .collect(),
     }
 }
 
@@ -212,6 +291,13 @@ pub fn stmt_error_from_sqld_error(sqld_error: SqldError) -> Result<StmtError, Sq
             StmtError::ResponseTooLarge
         }
         SqldError::Blocked(reason) => StmtError::Blocked { reason },
+        SqldError::MemoryLimitExceeded(limit) => StmtError::MemoryLimitExceeded { limit },
+        SqldError::RowLimitExceeded(limit) => StmtError::RowLimitExceeded { limit },
+        SqldError::DatabaseFull(limit) => StmtError::DatabaseFull { limit },
+        SqldError::AttachNotAllowed(namespace) => StmtError::AttachNotAllowed { namespace },
+        SqldError::AttachDisabled => StmtError::AttachDisabled,
+        SqldError::PragmaNotAllowed(pragma) => StmtError::PragmaNotAllowed { pragma },
+        SqldError::TooManyQueryParams(count, limit) => StmtError::ArgsTooMany { count, limit },
         SqldError::RpcQueryError(e) => StmtError::Proxy(e.message),
         SqldError::RusqliteError(rusqlite_error) => match rusqlite_error {
             rusqlite::Error::SqliteFailure(sqlite_error, Some(message)) => StmtError::SqliteError {
@@ -253,12 +339,20 @@ impl StmtError {
             Self::SqlManyStmts => "SQL_MANY_STATEMENTS",
             Self::ArgsInvalid { .. } => "ARGS_INVALID",
             Self::ArgsBothPositionalAndNamed => "ARGS_BOTH_POSITIONAL_AND_NAMED",
+            Self::ArgsTooMany { .. } => "ARGS_TOO_MANY",
+            Self::UnknownParameter { .. } => "ARGS_UNKNOWN_PARAMETER",
+            Self::ArgsKindMismatch { .. } => "ARGS_KIND_MISMATCH",
             Self::TransactionTimeout => "TRANSACTION_TIMEOUT",
             Self::TransactionBusy => "TRANSACTION_BUSY",
             Self::SqliteError { source, .. } => sqlite_error_code(source.code),
             Self::SqlInputError { .. } => "SQL_INPUT_ERROR",
             Self::Blocked { .. } => "BLOCKED",
             Self::ResponseTooLarge => "RESPONSE_TOO_LARGE",
+            Self::MemoryLimitExceeded { .. } => "MEMORY_LIMIT_EXCEEDED",
+            Self::RowLimitExceeded { .. } => "ROW_LIMIT_EXCEEDED",
+            Self::DatabaseFull { .. } => "DATABASE_FULL",
+            Self::AttachNotAllowed { .. } | Self::AttachDisabled => "ATTACH_NOT_ALLOWED",
+            Self::PragmaNotAllowed { .. } => "PRAGMA_NOT_ALLOWED",
             Self::Proxy(_) => "PROXY_ERROR",
         }
     }
@@ -3,11 +3,13 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use anyhow::{bail, Context as _, Result};
 use bytes::Bytes;
 use futures::stream::FuturesUnordered;
 use futures::{ready, FutureExt as _, StreamExt as _};
+use metrics::{decrement_gauge, increment_gauge};
 use tokio::sync::oneshot;
 use tokio_tungstenite::tungstenite;
 use tungstenite::protocol::frame::coding::CloseCode;
@@ -37,6 +39,8 @@ struct Conn<F: MakeNamespace> {
     /// Future responses to requests that we have received but are evaluating asynchronously.
     responses: FuturesUnordered<ResponseFuture>,
     connection_maker: Arc<dyn MakeConnection<Connection = <F::Database as Database>::Connection>>,
+    /// Number of pings sent since the client last sent a message.
+    missed_pongs: u32,
 }
 
 /// A `Future` that stores a handle to a future response to request which is being evaluated
@@ -62,6 +66,7 @@ pub(super) async fn handle_tcp<F: MakeNamespace>(
         socket,
         server.disable_default_namespace,
         server.disable_namespaces,
+        server.max_request_size,
     )
     .await
     .context("Could not perform the WebSocket handshake on TCP connection")?;
@@ -82,6 +87,7 @@ pub(super) async fn handle_upgrade<F: MakeNamespace>(
         upgrade,
         server.disable_default_namespace,
         server.disable_namespaces,
+        server.max_request_size,
     )
     .await
     .context("Could not perform the WebSocket handshake on HTTP connection")?;
@@ -111,14 +117,42 @@ async fn handle_ws<F: MakeNamespace>(
         join_set: tokio::task::JoinSet::new(),
         responses: FuturesUnordered::new(),
         connection_maker,
+        missed_pongs: 0,
     };
+    let _session_guard = SessionGauge::new();
+
+    let res = run_conn(&mut conn).await;
+    shutdown(&mut conn).await;
+    res
+}
+
+async fn run_conn<F: MakeNamespace>(conn: &mut Conn<F>) -> Result<()> {
+    let mut expire_interval = conn.server.stream_idle_timeout.map(|idle_timeout| {
+        let period = (idle_timeout / 4).max(Duration::from_secs(1));
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+
+    let mut ping_interval = conn.server.ping_interval.map(|period| {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
 
     loop {
         tokio::select! {
             Some(client_msg_res) = conn.ws.recv() => {
-                let client_msg = client_msg_res
-                    .context("Could not receive a WebSocket message")?;
-                match handle_msg(&mut conn, client_msg).await {
+                let msg_res = match client_msg_res {
+                    Ok(client_msg) => {
+                        // any message, not only a pong, shows that the client is still there
+                        conn.missed_pongs = 0;
+                        handle_msg(conn, client_msg).await
+                    }
+                    Err(tungstenite::Error::Capacity(_)) => Err(anyhow::anyhow!(
+                        ProtocolError::RequestTooLarge { max_size: conn.server.max_request_size }
+                    )),
+                    Err(err) => return Err(err).context("Could not receive a WebSocket message"),
+                };
+                match msg_res {
                     Ok(true) => continue,
                     Ok(false) => break,
                     Err(err) => {
@@ -130,11 +164,11 @@ async fn handle_ws<F: MakeNamespace>(
                                     proto_err,
                                 );
                                 let close_code = protocol_error_to_close_code(&proto_err);
-                                close(&mut conn, close_code, proto_err.to_string()).await;
+                                close(conn, close_code, proto_err.to_string()).await;
                                 return Ok(())
                             }
                             Err(err) => {
-                                close(&mut conn, CloseCode::Error, "Internal server error".into()).await;
+                                close(conn, CloseCode::Error, "Internal server error".into()).await;
                                 return Err(err);
                             }
                         }
@@ -146,7 +180,34 @@ async fn handle_ws<F: MakeNamespace>(
             },
             Some(response_res) = conn.responses.next() => {
                 let response_msg = response_res?;
-                send_msg(&mut conn, &response_msg).await?;
+                send_msg(conn, &response_msg).await?;
+            },
+            _ = async { expire_interval.as_mut().unwrap().tick().await }, if expire_interval.is_some() => {
+                if let (Some(session), Some(idle_timeout)) = (conn.session.as_mut(), conn.server.stream_idle_timeout) {
+                    session::expire_idle_streams(session, idle_timeout);
+                }
+                // expiring streams is not activity, so it must not kick the idle shutdown
+                continue
+            },
+            _ = async { ping_interval.as_mut().unwrap().tick().await }, if ping_interval.is_some() => {
+                if conn.missed_pongs >= conn.server.max_missed_pongs {
+                    tracing::info!(
+                        "Connection #{} missed {} pongs, closing it",
+                        conn.conn_id,
+                        conn.missed_pongs,
+                    );
+                    conn.ws_closed = true;
+                    return Ok(())
+                }
+                conn.missed_pongs += 1;
+                let period = conn.server.ping_interval.unwrap();
+                // a client which is gone may stop reading, and leave the send blocked
+                match tokio::time::timeout(period, conn.ws.send(tungstenite::Message::Ping(Vec::new()))).await {
+                    Ok(res) => res.context("Could not send ping to the WebSocket")?,
+                    Err(_) => conn.missed_pongs = conn.server.max_missed_pongs,
+                }
+                // pings are not activity, so they must not kick the idle shutdown
+                continue
             },
             else => break,
         }
@@ -156,15 +217,40 @@ async fn handle_ws<F: MakeNamespace>(
         }
     }
 
-    close(
-        &mut conn,
-        CloseCode::Normal,
-        "Thank you for using sqld".into(),
-    )
-    .await;
+    close(conn, CloseCode::Normal, "Thank you for using sqld".into()).await;
     Ok(())
 }
 
+/// Releases the resources of a connection whose client is gone, whether it closed the WebSocket,
+/// stopped answering pings or the connection failed. The streams and cursors are closed, and the
+/// tasks running them are aborted, cancelling their pending jobs, so that their database
+/// connections are returned before this function returns.
+async fn shutdown<F: MakeNamespace>(conn: &mut Conn<F>) {
+    if let Some(session) = conn.session.as_mut() {
+        session::close_all_streams(session);
+    }
+    // nobody is waiting for these responses anymore
+    conn.responses.clear();
+    conn.join_set.shutdown().await;
+}
+
+/// Counts the live Hrana WebSocket sessions in the `hrana_ws_sessions` gauge, for as long as it
+/// is held.
+struct SessionGauge;
+
+impl SessionGauge {
+    fn new() -> Self {
+        increment_gauge!("hrana_ws_sessions", 1.0);
+        Self
+    }
+}
+
+impl Drop for SessionGauge {
+    fn drop(&mut self) {
+        decrement_gauge!("hrana_ws_sessions", 1.0);
+    }
+}
+
 async fn handle_msg<F: MakeNamespace>(
     conn: &mut Conn<F>,
     client_msg: tungstenite::Message,
@@ -180,12 +266,15 @@ async fn handle_msg<F: MakeNamespace>(
             handle_client_msg(conn, client_msg).await
         }
         tungstenite::Message::Binary(client_msg) => {
-            if conn.encoding != Encoding::Protobuf {
-                bail!(ProtocolError::BinaryWebSocketMessage)
-            }
-
-            let client_msg = <proto::ClientMsg as prost::Message>::decode(client_msg.as_slice())
-                .map_err(|err| ProtocolError::ProtobufDecode { source: err })?;
+            let client_msg: proto::ClientMsg = match conn.encoding {
+                Encoding::Json => bail!(ProtocolError::BinaryWebSocketMessage),
+                Encoding::Protobuf => {
+                    <proto::ClientMsg as prost::Message>::decode(client_msg.as_slice())
+                        .map_err(|err| ProtocolError::ProtobufDecode { source: err })?
+                }
+                Encoding::MessagePack => rmp_serde::from_slice(&client_msg)
+                    .map_err(|err| ProtocolError::MessagePackDecode { source: err })?,
+            };
             handle_client_msg(conn, client_msg).await
         }
         tungstenite::Message::Ping(ping_data) => {
@@ -221,6 +310,7 @@ async fn handle_hello_msg<F: MakeNamespace>(
     conn: &mut Conn<F>,
     jwt: Option<String>,
 ) -> Result<bool> {
+    let is_initial = conn.session.is_none();
     let hello_res = match conn.session.as_mut() {
         None => session::handle_initial_hello(&conn.server, conn.version, jwt)
             .map(|session| conn.session = Some(session)),
@@ -229,7 +319,13 @@ async fn handle_hello_msg<F: MakeNamespace>(
 
     match hello_res {
         Ok(_) => {
-            send_msg(conn, &proto::ServerMsg::HelloOk(proto::HelloOkMsg {})).await?;
+            let limits = (is_initial && conn.version >= Version::Hrana3)
+                .then(|| session::limits(&conn.server));
+            send_msg(
+                conn,
+                &proto::ServerMsg::HelloOk(proto::HelloOkMsg { limits }),
+            )
+            .await?;
             Ok(true)
         }
         Err(err) => match downcast_error(err) {
@@ -330,6 +426,12 @@ async fn send_msg<F: MakeNamespace>(conn: &mut Conn<F>, msg: &proto::ServerMsg)
             let msg = <proto::ServerMsg as prost::Message>::encode_to_vec(msg);
             tungstenite::Message::Binary(msg)
         }
+        Encoding::MessagePack => {
+            // structs are encoded as maps, like in JSON, and not as arrays
+            let msg =
+                rmp_serde::to_vec_named(&msg).context("Could not serialize response message")?;
+            tungstenite::Message::Binary(msg)
+        }
     };
     conn.ws
         .send(msg)
@@ -370,8 +472,126 @@ fn protocol_error_to_close_code(err: &ProtocolError) -> CloseCode {
     match err {
         ProtocolError::JsonDeserialize { .. } => CloseCode::Invalid,
         ProtocolError::ProtobufDecode { .. } => CloseCode::Invalid,
+        ProtocolError::MessagePackDecode { .. } => CloseCode::Invalid,
         ProtocolError::BinaryWebSocketMessage => CloseCode::Unsupported,
         ProtocolError::TextWebSocketMessage => CloseCode::Unsupported,
+        ProtocolError::RequestTooLarge { .. } => CloseCode::Size,
         _ => CloseCode::Policy,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::sync::atomic::AtomicU64;
+
+    use futures::StreamExt as _;
+    use tokio::net::UnixStream;
+    use tokio::task::JoinHandle;
+    use tokio::time::Instant;
+    use tokio_tungstenite::WebSocketStream;
+    use tungstenite::protocol::Role;
+
+    use super::*;
+    use crate::auth::Auth;
+    use crate::namespace::{NamespaceStore, PrimaryNamespaceConfig, PrimaryNamespaceMaker};
+    use crate::net::AddrStream;
+    use crate::stats::Stats;
+    use crate::DEFAULT_NAMESPACE_NAME;
+
+    const PING_INTERVAL: Duration = Duration::from_secs(10);
+    const MAX_MISSED_PONGS: u32 = 2;
+
+    /// Returns a server pinging its connections, with the default namespace already loaded.
+    async fn test_server(path: &Path) -> Arc<Server<PrimaryNamespaceMaker>> {
+        let stats = Stats::new(path).unwrap();
+        let config = PrimaryNamespaceConfig::new_test(path, stats);
+        let namespaces = NamespaceStore::new(PrimaryNamespaceMaker::new(config), true);
+        namespaces
+            .with(DEFAULT_NAMESPACE_NAME.into(), |_| ())
+            .await
+            .unwrap();
+        Arc::new(Server {
+            namespaces,
+            auth: Arc::new(Auth {
+                disabled: true,
+                ..Auth::default()
+            }),
+            idle_kicker: None,
+            max_response_size: 1024 * 1024,
+            max_fetch_cursor_size: 1024 * 1024,
+            stream_idle_timeout: None,
+            max_streams_per_session: 16,
+            max_sql_count: 16,
+            max_sql_bytes: 1024 * 1024,
+            ping_interval: Some(PING_INTERVAL),
+            max_missed_pongs: MAX_MISSED_PONGS,
+            max_request_size: 1024 * 1024,
+            next_conn_id: AtomicU64::new(0),
+            disable_default_namespace: false,
+            disable_namespaces: true,
+        })
+    }
+
+    /// Opens a connection to `server`, returning the task running it and the client end.
+    async fn connect(
+        server: &Arc<Server<PrimaryNamespaceMaker>>,
+    ) -> (JoinHandle<Result<()>>, WebSocketStream<UnixStream>) {
+        let (server_end, client_end) = UnixStream::pair().unwrap();
+        let socket: Box<dyn crate::net::Conn> = Box::new(AddrStream::from_unix(server_end));
+        let ws = WebSocket::Tcp(WebSocketStream::from_raw_socket(socket, Role::Server, None).await);
+        let client = WebSocketStream::from_raw_socket(client_end, Role::Client, None).await;
+        let task = tokio::spawn(handle_ws(
+            server.clone(),
+            ws,
+            Version::Hrana2,
+            Encoding::Json,
+            0,
+            DEFAULT_NAMESPACE_NAME.into(),
+        ));
+        (task, client)
+    }
+
+    #[tokio::test]
+    async fn connection_closed_after_missed_pongs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let server = test_server(tmp.path()).await;
+        tokio::time::pause();
+        let start = Instant::now();
+        // the client never reads, so it never answers the pings
+        let (task, mut client) = connect(&server).await;
+
+        task.await.unwrap().unwrap();
+        let elapsed = start.elapsed();
+        let expected = PING_INTERVAL * (MAX_MISSED_PONGS + 1);
+        assert!(
+            elapsed >= expected && elapsed < expected + PING_INTERVAL / 2,
+            "closed after {elapsed:?}, expected {expected:?}"
+        );
+
+        let mut pings = 0;
+        while let Some(Ok(msg)) = client.next().await {
+            if msg.is_ping() {
+                pings += 1;
+            }
+        }
+        assert_eq!(pings, MAX_MISSED_PONGS);
+    }
+
+    #[tokio::test]
+    async fn connection_kept_while_pongs_answer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let server = test_server(tmp.path()).await;
+        tokio::time::pause();
+        let (task, mut client) = connect(&server).await;
+        // reading the pings answers them
+        let client = tokio::spawn(async move { while let Some(Ok(_)) = client.next().await {} });
+
+        tokio::time::sleep(PING_INTERVAL * (MAX_MISSED_PONGS + 1) * 4).await;
+        assert!(!task.is_finished());
+
+        // the connection ends once the client is gone
+        client.abort();
+        assert!(task.await.is_ok());
+    }
+}
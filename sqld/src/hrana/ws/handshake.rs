@@ -21,6 +21,7 @@ enum Subproto {
     Hrana2,
     Hrana3,
     Hrana3Protobuf,
+    Hrana3MessagePack,
 }
 
 pub struct Output {
@@ -34,6 +35,7 @@ pub async fn handshake_tcp(
     socket: Box<dyn Conn>,
     disable_default_ns: bool,
     disable_namespaces: bool,
+    max_message_size: u64,
 ) -> Result<Output> {
     let mut subproto = None;
     let mut namespace = None;
@@ -58,7 +60,7 @@ pub async fn handshake_tcp(
         }
     };
 
-    let ws_config = Some(get_ws_config());
+    let ws_config = Some(get_ws_config(max_message_size));
     let stream =
         tokio_tungstenite::accept_hdr_async_with_config(socket, callback, ws_config).await?;
 
@@ -75,11 +77,12 @@ pub async fn handshake_upgrade(
     upgrade: Upgrade,
     disable_default_ns: bool,
     disable_namespaces: bool,
+    max_message_size: u64,
 ) -> Result<Output> {
     let mut req = upgrade.request;
 
     let namespace = namespace_from_headers(req.headers(), disable_default_ns, disable_namespaces)?;
-    let ws_config = Some(get_ws_config());
+    let ws_config = Some(get_ws_config(max_message_size));
     let (mut resp, stream_fut_subproto_res) = match hyper_tungstenite::upgrade(&mut req, ws_config)
     {
         Ok((mut resp, stream_fut)) => match negotiate_subproto(req.headers(), resp.headers_mut()) {
@@ -141,6 +144,7 @@ fn negotiate_subproto(
 
         let server_subprotos = [
             Subproto::Hrana3Protobuf,
+            Subproto::Hrana3MessagePack,
             Subproto::Hrana3,
             Subproto::Hrana2,
             Subproto::Hrana1,
@@ -185,9 +189,13 @@ fn select_subproto(client_subprotos: &[&str], server_subprotos: &[Subproto]) ->
     None
 }
 
-fn get_ws_config() -> tungstenite::protocol::WebSocketConfig {
+fn get_ws_config(max_message_size: u64) -> tungstenite::protocol::WebSocketConfig {
+    let max_message_size = usize::try_from(max_message_size).unwrap_or(usize::MAX);
     tungstenite::protocol::WebSocketConfig {
         max_send_queue: Some(1 << 20),
+        // a message is buffered whole before it's decoded, so its size must be bounded
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
         ..Default::default()
     }
 }
@@ -215,6 +223,7 @@ impl Subproto {
             Self::Hrana2 => "hrana2",
             Self::Hrana3 => "hrana3",
             Self::Hrana3Protobuf => "hrana3-protobuf",
+            Self::Hrana3MessagePack => "hrana3-msgpack",
         }
     }
 
@@ -224,6 +233,39 @@ impl Subproto {
             Self::Hrana2 => (Version::Hrana2, Encoding::Json),
             Self::Hrana3 => (Version::Hrana3, Encoding::Json),
             Self::Hrana3Protobuf => (Version::Hrana3, Encoding::Protobuf),
+            Self::Hrana3MessagePack => (Version::Hrana3, Encoding::MessagePack),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn negotiate(client_subprotos: &str) -> (Version, Encoding, http::HeaderMap) {
+        let mut req_headers = http::HeaderMap::new();
+        req_headers.insert(
+            "sec-websocket-protocol",
+            http::HeaderValue::from_str(client_subprotos).unwrap(),
+        );
+        let mut resp_headers = http::HeaderMap::new();
+        let (version, encoding) = negotiate_subproto(&req_headers, &mut resp_headers)
+            .unwrap()
+            .version_encoding();
+        (version, encoding, resp_headers)
+    }
+
+    #[test]
+    fn negotiate_msgpack() {
+        let (version, encoding, resp_headers) = negotiate("hrana2, hrana3-msgpack, hrana3");
+        assert_eq!(version, Version::Hrana3);
+        assert_eq!(encoding, Encoding::MessagePack);
+        assert_eq!(resp_headers["sec-websocket-protocol"], "hrana3-msgpack");
+
+        let (_, encoding, _) = negotiate("hrana3, hrana3-msgpack, hrana3-protobuf");
+        assert_eq!(encoding, Encoding::Protobuf);
+
+        let (_, encoding, _) = negotiate("hrana3");
+        assert_eq!(encoding, Encoding::Json);
+    }
+}
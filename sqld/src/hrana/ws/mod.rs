@@ -2,6 +2,7 @@ use std::future::poll_fn;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use enclose::enclose;
@@ -26,6 +27,18 @@ struct Server<F: MakeNamespace> {
     auth: Arc<Auth>,
     idle_kicker: Option<IdleKicker>,
     max_response_size: u64,
+    /// Maximum total size of the entries returned by a `fetch_cursor` request, in bytes.
+    max_fetch_cursor_size: u64,
+    stream_idle_timeout: Option<Duration>,
+    max_streams_per_session: usize,
+    max_sql_count: usize,
+    max_sql_bytes: u64,
+    /// Interval at which the connections are pinged, if they are.
+    ping_interval: Option<Duration>,
+    /// Number of consecutive unanswered pings after which a connection is closed.
+    max_missed_pongs: u32,
+    /// Maximum size of a message received from a client, in bytes.
+    max_request_size: u64,
     next_conn_id: AtomicU64,
     disable_default_namespace: bool,
     disable_namespaces: bool,
@@ -47,6 +60,14 @@ pub async fn serve<F: MakeNamespace>(
     auth: Arc<Auth>,
     idle_kicker: Option<IdleKicker>,
     max_response_size: u64,
+    max_fetch_cursor_size: u64,
+    stream_idle_timeout: Option<Duration>,
+    max_streams_per_session: usize,
+    max_sql_count: usize,
+    max_sql_bytes: u64,
+    ping_interval: Option<Duration>,
+    max_missed_pongs: u32,
+    max_request_size: u64,
     mut accept_rx: mpsc::Receiver<Accept>,
     mut upgrade_rx: mpsc::Receiver<Upgrade>,
     namespaces: NamespaceStore<F>,
@@ -57,6 +78,14 @@ pub async fn serve<F: MakeNamespace>(
         auth,
         idle_kicker,
         max_response_size,
+        max_fetch_cursor_size,
+        stream_idle_timeout,
+        max_streams_per_session,
+        max_sql_count,
+        max_sql_bytes,
+        ping_interval,
+        max_missed_pongs,
+        max_request_size,
         next_conn_id: AtomicU64::new(0),
         namespaces,
         disable_default_namespace,
@@ -112,7 +141,7 @@ where
     while let Some(maybe_conn) = poll_fn(|cx| acceptor.as_mut().poll_accept(cx)).await {
         match maybe_conn {
             Ok(conn) => {
-                let Some(peer_addr) = conn.connect_info().remote_addr() else {
+                let Some(peer_addr) = conn.connect_info().and_then(|info| info.remote_addr()) else {
                     tracing::error!("connection missing remote addr");
                     continue;
                 };
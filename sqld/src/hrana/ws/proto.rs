@@ -23,7 +23,7 @@ pub struct HelloMsg {
 pub struct RequestMsg {
     #[prost(int32, tag = "1")]
     pub request_id: i32,
-    #[prost(oneof = "Request", tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13")]
+    #[prost(oneof = "Request", tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14")]
     pub request: Option<Request>,
 }
 
@@ -37,7 +37,12 @@ pub enum ServerMsg {
 }
 
 #[derive(Serialize, prost::Message)]
-pub struct HelloOkMsg {}
+pub struct HelloOkMsg {
+    /// Limits of the server, sent in response to the initial hello since Hrana 3.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[prost(message, optional, tag = "1")]
+    pub limits: Option<Limits>,
+}
 
 #[derive(Serialize, prost::Message)]
 pub struct HelloErrorMsg {
@@ -49,7 +54,10 @@ pub struct HelloErrorMsg {
 pub struct ResponseOkMsg {
     #[prost(int32, tag = "1")]
     pub request_id: i32,
-    #[prost(oneof = "Response", tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13")]
+    #[prost(
+        oneof = "Response",
+        tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14"
+    )]
     pub response: Option<Response>,
 }
 
@@ -88,6 +96,8 @@ pub enum Request {
     CloseSql(CloseSqlReq),
     #[prost(message, tag = "13")]
     GetAutocommit(GetAutocommitReq),
+    #[prost(message, tag = "14")]
+    GetLimits(GetLimitsReq),
 }
 
 #[derive(Serialize, prost::Oneof)]
@@ -117,6 +127,8 @@ pub enum Response {
     CloseSql(CloseSqlResp),
     #[prost(message, tag = "13")]
     GetAutocommit(GetAutocommitResp),
+    #[prost(message, tag = "14")]
+    GetLimits(GetLimitsResp),
 }
 
 #[derive(Deserialize, prost::Message)]
@@ -143,6 +155,11 @@ pub struct ExecuteReq {
     pub stream_id: i32,
     #[prost(message, required, tag = "2")]
     pub stmt: Stmt,
+    /// Maximum number of rows returned by the statement, which only lowers the limit of the
+    /// server. Since Hrana 3.
+    #[serde(default)]
+    #[prost(uint64, optional, tag = "3")]
+    pub max_rows: Option<u64>,
 }
 
 #[derive(Serialize, prost::Message)]
@@ -193,6 +210,11 @@ pub struct FetchCursorReq {
     pub cursor_id: i32,
     #[prost(uint32, tag = "2")]
     pub max_count: u32,
+    /// Number of rows to skip before the returned entries. The skipped rows are read from the
+    /// database, but not returned.
+    #[serde(default)]
+    #[prost(uint32, tag = "3")]
+    pub skip: u32,
 }
 
 #[derive(Serialize, prost::Message)]
@@ -228,6 +250,9 @@ pub struct DescribeReq {
     #[serde(default)]
     #[prost(int32, optional, tag = "3")]
     pub sql_id: Option<i32>,
+    #[serde(default)]
+    #[prost(bool, optional, tag = "4")]
+    pub query_plan: Option<bool>,
 }
 
 #[derive(Serialize, prost::Message)]
@@ -267,3 +292,68 @@ pub struct GetAutocommitResp {
     #[prost(bool, required, tag = "1")]
     pub is_autocommit: bool,
 }
+
+#[derive(Deserialize, prost::Message)]
+pub struct GetLimitsReq {}
+
+#[derive(Serialize, prost::Message)]
+pub struct GetLimitsResp {
+    #[prost(message, required, tag = "1")]
+    pub limits: Limits,
+}
+
+#[derive(Serialize, prost::Message)]
+pub struct Limits {
+    /// Maximum number of SQL texts that can be stored with `store_sql`.
+    #[prost(uint32, tag = "1")]
+    pub max_sql_count: u32,
+    /// Maximum size of a response, in bytes.
+    #[prost(uint64, tag = "2")]
+    pub max_response_size: u64,
+    /// Maximum number of streams that can be open at the same time in a session.
+    #[prost(uint32, tag = "3")]
+    pub max_streams_per_session: u32,
+    /// The latest protocol version supported by the server (such as `hrana3`).
+    #[prost(string, tag = "4")]
+    pub version: String,
+    /// Maximum total size of the SQL texts that can be stored with `store_sql`, in bytes.
+    #[prost(uint64, tag = "5")]
+    pub max_sql_bytes: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_msgpack_client_msg() {
+        let msg = serde_json::json!({
+            "type": "request",
+            "request_id": 1,
+            "request": {
+                "type": "execute",
+                "stream_id": 2,
+                "stmt": {
+                    "sql": "SELECT ?, ?",
+                    "args": [
+                        { "type": "integer", "value": "42" },
+                        { "type": "blob", "base64": "AQID" },
+                    ],
+                },
+            },
+        });
+        let msg = rmp_serde::to_vec_named(&msg).unwrap();
+
+        let ClientMsg::Request(RequestMsg {
+            request_id: 1,
+            request: Some(Request::Execute(req)),
+        }) = rmp_serde::from_slice(&msg).unwrap()
+        else {
+            panic!("unexpected message")
+        };
+        assert_eq!(req.stream_id, 2);
+        assert_eq!(req.stmt.sql.as_deref(), Some("SELECT ?, ?"));
+        assert!(matches!(req.stmt.args[0], Value::Integer { value: 42 }));
+        assert!(matches!(&req.stmt.args[1], Value::Blob { value } if value[..] == [1, 2, 3]));
+    }
+}
@@ -98,3 +98,383 @@ impl prost::Message for ServerMsg {
         panic!("ServerMsg can only be encoded, not decoded")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use prost::Message as _;
+
+    use crate::hrana::ws::proto::*;
+
+    /// Mirror of `ClientMsg` as described by the protobuf schema, used to encode what we decode.
+    #[derive(prost::Message)]
+    struct EncodedClientMsg {
+        #[prost(oneof = "EncodedClient", tags = "1, 2")]
+        msg: Option<EncodedClient>,
+    }
+
+    #[derive(prost::Oneof)]
+    enum EncodedClient {
+        #[prost(message, tag = "1")]
+        Hello(HelloMsg),
+        #[prost(message, tag = "2")]
+        Request(RequestMsg),
+    }
+
+    /// Mirror of `ServerMsg` as described by the protobuf schema, used to decode what we encode.
+    #[derive(prost::Message)]
+    struct DecodedServerMsg {
+        #[prost(oneof = "DecodedServer", tags = "1, 2, 3, 4")]
+        msg: Option<DecodedServer>,
+    }
+
+    #[derive(prost::Oneof)]
+    enum DecodedServer {
+        #[prost(message, tag = "1")]
+        HelloOk(HelloOkMsg),
+        #[prost(message, tag = "2")]
+        HelloError(HelloErrorMsg),
+        #[prost(message, tag = "3")]
+        ResponseOk(DecodedResponseOkMsg),
+        #[prost(message, tag = "4")]
+        ResponseError(ResponseErrorMsg),
+    }
+
+    #[derive(prost::Message)]
+    struct DecodedResponseOkMsg {
+        #[prost(int32, tag = "1")]
+        request_id: i32,
+        #[prost(oneof = "DecodedResponse", tags = "4, 8")]
+        response: Option<DecodedResponse>,
+    }
+
+    #[derive(prost::Oneof)]
+    enum DecodedResponse {
+        #[prost(message, tag = "4")]
+        Execute(ExecuteResp),
+        #[prost(message, tag = "8")]
+        FetchCursor(DecodedFetchCursorResp),
+    }
+
+    #[derive(prost::Message)]
+    struct DecodedFetchCursorResp {
+        #[prost(message, repeated, tag = "1")]
+        entries: Vec<DecodedCursorEntry>,
+        #[prost(bool, tag = "2")]
+        done: bool,
+    }
+
+    #[derive(prost::Message)]
+    struct DecodedCursorEntry {
+        #[prost(oneof = "DecodedEntry", tags = "1, 2, 3, 4, 5")]
+        entry: Option<DecodedEntry>,
+    }
+
+    #[derive(prost::Oneof)]
+    enum DecodedEntry {
+        #[prost(message, tag = "1")]
+        StepBegin(StepBeginEntry),
+        #[prost(message, tag = "2")]
+        StepEnd(StepEndEntry),
+        #[prost(message, tag = "3")]
+        StepError(StepErrorEntry),
+        #[prost(message, tag = "4")]
+        Row(Row),
+        #[prost(message, tag = "5")]
+        Error(Error),
+    }
+
+    fn decode_client_msg(msg: EncodedClient) -> ClientMsg {
+        let encoded = EncodedClientMsg { msg: Some(msg) }.encode_to_vec();
+        ClientMsg::decode(&encoded[..]).unwrap()
+    }
+
+    fn decode_server_msg(msg: &ServerMsg) -> DecodedServer {
+        let encoded = msg.encode_to_vec();
+        assert_eq!(encoded.len(), msg.encoded_len());
+        DecodedServerMsg::decode(&encoded[..]).unwrap().msg.unwrap()
+    }
+
+    fn blob(value: &'static [u8]) -> Value {
+        Value::Blob {
+            value: Bytes::from_static(value),
+        }
+    }
+
+    fn sql_error() -> Error {
+        Error {
+            message: "no such table: t".into(),
+            code: "SQLITE_ERROR".into(),
+        }
+    }
+
+    #[test]
+    fn decode_hello() {
+        let msg = decode_client_msg(EncodedClient::Hello(HelloMsg {
+            jwt: Some("token".into()),
+        }));
+        assert!(matches!(msg, ClientMsg::Hello(HelloMsg { jwt: Some(jwt) }) if jwt == "token"));
+
+        let msg = decode_client_msg(EncodedClient::Hello(HelloMsg { jwt: None }));
+        assert!(matches!(msg, ClientMsg::Hello(HelloMsg { jwt: None })));
+    }
+
+    #[test]
+    fn decode_execute_request() {
+        let msg = decode_client_msg(EncodedClient::Request(RequestMsg {
+            request_id: 3,
+            request: Some(Request::Execute(ExecuteReq {
+                stream_id: 1,
+                stmt: Stmt {
+                    sql: Some("INSERT INTO t VALUES (?, ?, ?)".into()),
+                    args: vec![Value::Null, blob(b"\0\xff"), Value::Float { value: 0.5 }],
+                    named_args: vec![NamedArg {
+                        name: ":x".into(),
+                        value: Value::Text { value: "".into() },
+                    }],
+                    ..Default::default()
+                },
+                max_rows: Some(100),
+            })),
+        }));
+
+        let ClientMsg::Request(RequestMsg {
+            request_id: 3,
+            request: Some(Request::Execute(req)),
+        }) = msg
+        else {
+            panic!("unexpected message")
+        };
+        assert_eq!(req.stream_id, 1);
+        assert_eq!(req.max_rows, Some(100));
+        assert_eq!(
+            req.stmt.sql.as_deref(),
+            Some("INSERT INTO t VALUES (?, ?, ?)")
+        );
+        assert!(req.stmt.sql_id.is_none());
+        assert!(matches!(
+            &req.stmt.args[..],
+            [Value::Null, Value::Blob { value }, Value::Float { value: f }]
+                if value[..] == b"\0\xff"[..] && *f == 0.5
+        ));
+        assert!(matches!(
+            &req.stmt.named_args[..],
+            [NamedArg { name, value: Value::Text { value } }] if name == ":x" && value.is_empty()
+        ));
+    }
+
+    #[test]
+    fn decode_cursor_requests() {
+        let msg = decode_client_msg(EncodedClient::Request(RequestMsg {
+            request_id: 4,
+            request: Some(Request::OpenCursor(OpenCursorReq {
+                stream_id: 1,
+                cursor_id: 2,
+                batch: Batch {
+                    steps: vec![BatchStep {
+                        condition: None,
+                        stmt: Stmt {
+                            sql_id: Some(5),
+                            args: vec![Value::Null],
+                            ..Default::default()
+                        },
+                    }],
+                    parallel: None,
+                },
+            })),
+        }));
+        let ClientMsg::Request(RequestMsg {
+            request: Some(Request::OpenCursor(req)),
+            ..
+        }) = msg
+        else {
+            panic!("unexpected message")
+        };
+        assert_eq!((req.stream_id, req.cursor_id), (1, 2));
+        assert!(req.batch.steps[0].condition.is_none());
+        assert_eq!(req.batch.steps[0].stmt.sql_id, Some(5));
+        assert!(matches!(req.batch.steps[0].stmt.args[..], [Value::Null]));
+
+        let msg = decode_client_msg(EncodedClient::Request(RequestMsg {
+            request_id: 5,
+            request: Some(Request::FetchCursor(FetchCursorReq {
+                cursor_id: 2,
+                max_count: 100,
+                skip: 20,
+            })),
+        }));
+        assert!(matches!(
+            msg,
+            ClientMsg::Request(RequestMsg {
+                request_id: 5,
+                request: Some(Request::FetchCursor(FetchCursorReq {
+                    cursor_id: 2,
+                    max_count: 100,
+                    skip: 20,
+                })),
+            })
+        ));
+    }
+
+    #[test]
+    fn encode_hello_responses() {
+        let msg = ServerMsg::HelloOk(HelloOkMsg {
+            limits: Some(Limits {
+                max_sql_count: 150,
+                max_response_size: 1 << 20,
+                max_streams_per_session: 8,
+                version: "hrana3".into(),
+                max_sql_bytes: 1 << 16,
+            }),
+        });
+        let DecodedServer::HelloOk(HelloOkMsg { limits: Some(limits) }) = decode_server_msg(&msg)
+        else {
+            panic!("unexpected message")
+        };
+        assert_eq!(limits.max_sql_count, 150);
+        assert_eq!(limits.version, "hrana3");
+
+        let msg = ServerMsg::HelloError(HelloErrorMsg { error: sql_error() });
+        assert!(matches!(
+            decode_server_msg(&msg),
+            DecodedServer::HelloError(HelloErrorMsg { error }) if error.code == "SQLITE_ERROR"
+        ));
+    }
+
+    #[test]
+    fn encode_execute_response() {
+        let msg = ServerMsg::ResponseOk(ResponseOkMsg {
+            request_id: 7,
+            response: Some(Response::Execute(ExecuteResp {
+                result: StmtResult {
+                    cols: vec![Col {
+                        name: Some("a".into()),
+                        decltype: Some("BLOB".into()),
+                    }],
+                    rows: vec![
+                        Row {
+                            values: vec![blob(b"")],
+                        },
+                        Row {
+                            values: vec![Value::Null],
+                        },
+                    ],
+                    affected_row_count: 0,
+                    last_insert_rowid: Some(-1),
+                },
+            })),
+        });
+        let DecodedServer::ResponseOk(DecodedResponseOkMsg {
+            request_id: 7,
+            response: Some(DecodedResponse::Execute(resp)),
+        }) = decode_server_msg(&msg)
+        else {
+            panic!("unexpected message")
+        };
+        let result = resp.result;
+        assert_eq!(result.cols[0].decltype.as_deref(), Some("BLOB"));
+        assert!(matches!(&result.rows[0].values[..], [Value::Blob { value }] if value.is_empty()));
+        assert!(matches!(result.rows[1].values[..], [Value::Null]));
+        assert_eq!(result.last_insert_rowid, Some(-1));
+
+        let msg = ServerMsg::ResponseError(ResponseErrorMsg {
+            request_id: 8,
+            error: sql_error(),
+        });
+        assert!(matches!(
+            decode_server_msg(&msg),
+            DecodedServer::ResponseError(ResponseErrorMsg { request_id: 8, error })
+                if error.message == "no such table: t"
+        ));
+    }
+
+    #[test]
+    fn encode_fetch_cursor_response() {
+        let msg = ServerMsg::ResponseOk(ResponseOkMsg {
+            request_id: 9,
+            response: Some(Response::FetchCursor(FetchCursorResp {
+                entries: vec![
+                    CursorEntry::StepBegin(StepBeginEntry {
+                        step: 0,
+                        cols: vec![
+                            Col {
+                                name: Some("a".into()),
+                                decltype: None,
+                            },
+                            Col {
+                                name: None,
+                                decltype: None,
+                            },
+                        ],
+                        total_rows: Some(2),
+                    }),
+                    CursorEntry::Row {
+                        row: Row {
+                            values: vec![Value::Null, blob(b"\0\x01\0")],
+                        },
+                    },
+                    CursorEntry::Row {
+                        row: Row {
+                            values: vec![blob(b""), Value::Null],
+                        },
+                    },
+                    CursorEntry::StepEnd(StepEndEntry {
+                        affected_row_count: 0,
+                        last_insert_rowid: None,
+                    }),
+                    CursorEntry::StepError(StepErrorEntry {
+                        step: 1,
+                        error: sql_error(),
+                    }),
+                    CursorEntry::Error { error: sql_error() },
+                ],
+                done: true,
+            })),
+        });
+        let DecodedServer::ResponseOk(DecodedResponseOkMsg {
+            request_id: 9,
+            response: Some(DecodedResponse::FetchCursor(resp)),
+        }) = decode_server_msg(&msg)
+        else {
+            panic!("unexpected message")
+        };
+        assert!(resp.done);
+
+        let entries: Vec<_> = resp.entries.into_iter().map(|e| e.entry.unwrap()).collect();
+        assert_eq!(entries.len(), 6);
+        assert!(matches!(
+            &entries[0],
+            DecodedEntry::StepBegin(StepBeginEntry { step: 0, cols, total_rows: Some(2) })
+                if cols.len() == 2 && cols[0].name.as_deref() == Some("a") && cols[1].name.is_none()
+        ));
+        assert!(matches!(
+            &entries[1],
+            DecodedEntry::Row(Row { values }) if matches!(
+                &values[..],
+                [Value::Null, Value::Blob { value }] if value[..] == b"\0\x01\0"[..]
+            )
+        ));
+        assert!(matches!(
+            &entries[2],
+            DecodedEntry::Row(Row { values }) if matches!(
+                &values[..],
+                [Value::Blob { value }, Value::Null] if value.is_empty()
+            )
+        ));
+        assert!(matches!(
+            entries[3],
+            DecodedEntry::StepEnd(StepEndEntry {
+                affected_row_count: 0,
+                last_insert_rowid: None,
+            })
+        ));
+        assert!(matches!(
+            &entries[4],
+            DecodedEntry::StepError(StepErrorEntry { step: 1, error }) if error.code == "SQLITE_ERROR"
+        ));
+        assert!(matches!(
+            &entries[5],
+            DecodedEntry::Error(error) if error.code == "SQLITE_ERROR"
+        ));
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail, Context as _, Result};
 use futures::future::BoxFuture;
@@ -17,13 +19,25 @@ pub struct Session<D> {
     authenticated: Authenticated,
     version: Version,
     streams: HashMap<i32, StreamHandle<D>>,
+    /// Streams that were closed due to inactivity, mapped to the cursor that was open on them (if
+    /// any). They are kept until the client closes them, so that we can report why they are gone.
+    expired_streams: HashMap<i32, Option<i32>>,
     sqls: HashMap<i32, String>,
+    /// Total size of the SQL texts in `sqls`, in bytes.
+    sqls_size: u64,
     cursors: HashMap<i32, i32>,
+    /// Cursors of the expired streams, mapped to their stream. They are kept until the client
+    /// closes them or their stream.
+    expired_cursors: HashMap<i32, i32>,
 }
 
 struct StreamHandle<D> {
     job_tx: mpsc::Sender<StreamJob<D>>,
     cursor_id: Option<i32>,
+    /// The last time a request was submitted to the stream, or the stream was found busy.
+    last_activity: Instant,
+    /// Number of jobs that were submitted to the stream and are not finished yet.
+    pending_jobs: Arc<AtomicUsize>,
 }
 
 /// An arbitrary job that is executed on a [`Stream`].
@@ -54,10 +68,27 @@ pub enum ResponseError {
     Auth { source: AuthError },
     #[error("Stream {stream_id} has failed to open")]
     StreamNotOpen { stream_id: i32 },
+    #[error("Stream {stream_id} was closed due to inactivity")]
+    StreamExpired { stream_id: i32 },
     #[error("Cursor {cursor_id} has failed to open")]
     CursorNotOpen { cursor_id: i32 },
-    #[error("The server already stores {count} SQL texts, it cannot store more")]
-    SqlTooMany { count: usize },
+    #[error(
+        "The server already stores the maximum of {count} SQL texts, it cannot store more until \
+        some are closed (stored SQL text ids: {sql_ids:?})"
+    )]
+    SqlTooMany { count: usize, sql_ids: Vec<i32> },
+    #[error(
+        "Storing an SQL text of {size} bytes would exceed the maximum of {limit} bytes of stored \
+        SQL texts, {stored} bytes are already stored (stored SQL text ids: {sql_ids:?})"
+    )]
+    SqlTooLarge {
+        size: u64,
+        stored: u64,
+        limit: u64,
+        sql_ids: Vec<i32>,
+    },
+    #[error("The session already has {count} open streams, it cannot open more")]
+    StreamTooMany { count: usize },
     #[error(transparent)]
     Stmt(stmt::StmtError),
     #[error(transparent)]
@@ -78,8 +109,11 @@ pub(super) fn handle_initial_hello<F: MakeNamespace>(
         authenticated,
         version,
         streams: HashMap::new(),
+        expired_streams: HashMap::new(),
         sqls: HashMap::new(),
+        sqls_size: 0,
         cursors: HashMap::new(),
+        expired_cursors: HashMap::new(),
     })
 }
 
@@ -102,6 +136,21 @@ pub(super) fn handle_repeated_hello<F: MakeNamespace>(
     Ok(())
 }
 
+/// Returns the limits of the server, so that clients can configure themselves without running into
+/// them.
+pub(super) fn limits<F: MakeNamespace>(server: &Server<F>) -> proto::Limits {
+    proto::Limits {
+        max_sql_count: server.max_sql_count.try_into().unwrap_or(u32::MAX),
+        max_response_size: server.max_response_size,
+        max_streams_per_session: server
+            .max_streams_per_session
+            .try_into()
+            .unwrap_or(u32::MAX),
+        version: Version::LATEST.to_string(),
+        max_sql_bytes: server.max_sql_bytes,
+    }
+}
+
 pub(super) async fn handle_request<F: MakeNamespace>(
     server: &Server<F>,
     session: &mut Session<<F::Database as Database>::Connection>,
@@ -143,7 +192,15 @@ pub(super) async fn handle_request<F: MakeNamespace>(
     macro_rules! get_stream_mut {
         ($stream_id:expr) => {
             match session.streams.get_mut(&$stream_id) {
-                Some(stream_hdn) => stream_hdn,
+                Some(stream_hdn) => {
+                    stream_hdn.last_activity = Instant::now();
+                    stream_hdn
+                }
+                None if session.expired_streams.contains_key(&$stream_id) => {
+                    bail!(ResponseError::StreamExpired {
+                        stream_id: $stream_id
+                    })
+                }
                 None => bail!(ProtocolError::StreamNotFound {
                     stream_id: $stream_id
                 }),
@@ -176,9 +233,16 @@ pub(super) async fn handle_request<F: MakeNamespace>(
     match req {
         proto::Request::OpenStream(req) => {
             let stream_id = req.stream_id;
-            if session.streams.contains_key(&stream_id) {
+            if session.streams.contains_key(&stream_id)
+                || session.expired_streams.contains_key(&stream_id)
+            {
                 bail!(ProtocolError::StreamExists { stream_id })
             }
+            if session.streams.len() >= server.max_streams_per_session {
+                bail!(ResponseError::StreamTooMany {
+                    count: session.streams.len(),
+                })
+            }
 
             let mut stream_hnd = stream_spawn(
                 join_set,
@@ -200,6 +264,15 @@ pub(super) async fn handle_request<F: MakeNamespace>(
         }
         proto::Request::CloseStream(req) => {
             let stream_id = req.stream_id;
+            if let Some(cursor_id) = session.expired_streams.remove(&stream_id) {
+                // the stream is already gone, the client just acknowledges that
+                if let Some(cursor_id) = cursor_id {
+                    session.expired_cursors.remove(&cursor_id);
+                }
+                respond!(proto::Response::CloseStream(proto::CloseStreamResp {}));
+                return Ok(resp_rx);
+            }
+
             let Some(mut stream_hnd) = session.streams.remove(&stream_id) else {
                 bail!(ProtocolError::StreamNotFound { stream_id })
             };
@@ -216,8 +289,15 @@ pub(super) async fn handle_request<F: MakeNamespace>(
             let stream_id = req.stream_id;
             let stream_hnd = get_stream_mut!(stream_id);
 
-            let query = stmt::proto_stmt_to_query(&req.stmt, &session.sqls, session.version)
+            if req.max_rows.is_some() {
+                ensure_version!(
+                    Version::Hrana3,
+                    "The `max_rows` field of the `execute` request"
+                );
+            }
+            let mut query = stmt::proto_stmt_to_query(&req.stmt, &session.sqls, session.version)
                 .map_err(catch_stmt_error)?;
+            query.max_rows = req.max_rows;
             let auth = session.authenticated;
 
             stream_respond!(stream_hnd, async move |stream| {
@@ -279,11 +359,12 @@ pub(super) async fn handle_request<F: MakeNamespace>(
                 session.version,
             )?
             .into();
+            let query_plan = req.query_plan.unwrap_or(false);
             let auth = session.authenticated;
 
             stream_respond!(stream_hnd, async move |stream| {
                 let db = get_stream_db!(stream, stream_id);
-                let result = stmt::describe_stmt(&**db, auth, sql)
+                let result = stmt::describe_stmt(&**db, auth, sql, query_plan)
                     .await
                     .map_err(catch_stmt_error)?;
                 Ok(proto::Response::Describe(proto::DescribeResp { result }))
@@ -291,21 +372,20 @@ pub(super) async fn handle_request<F: MakeNamespace>(
         }
         proto::Request::StoreSql(req) => {
             ensure_version!(Version::Hrana2, "The `store_sql` request");
-            let sql_id = req.sql_id;
-            if session.sqls.contains_key(&sql_id) {
-                bail!(ProtocolError::SqlExists { sql_id })
-            } else if session.sqls.len() >= MAX_SQL_COUNT {
-                bail!(ResponseError::SqlTooMany {
-                    count: session.sqls.len()
-                })
-            }
-
-            session.sqls.insert(sql_id, req.sql);
+            store_sql(
+                session,
+                req.sql_id,
+                req.sql,
+                server.max_sql_count,
+                server.max_sql_bytes,
+            )?;
             respond!(proto::Response::StoreSql(proto::StoreSqlResp {}));
         }
         proto::Request::CloseSql(req) => {
             ensure_version!(Version::Hrana2, "The `close_sql` request");
-            session.sqls.remove(&req.sql_id);
+            if let Some(sql) = session.sqls.remove(&req.sql_id) {
+                session.sqls_size -= sql.len() as u64;
+            }
             respond!(proto::Response::CloseSql(proto::CloseSqlResp {}));
         }
         proto::Request::OpenCursor(req) => {
@@ -318,7 +398,9 @@ pub(super) async fn handle_request<F: MakeNamespace>(
             }
 
             let cursor_id = req.cursor_id;
-            if session.cursors.contains_key(&cursor_id) {
+            if session.cursors.contains_key(&cursor_id)
+                || session.expired_cursors.contains_key(&cursor_id)
+            {
                 bail!(ProtocolError::CursorExists { cursor_id })
             }
 
@@ -340,6 +422,14 @@ pub(super) async fn handle_request<F: MakeNamespace>(
             ensure_version!(Version::Hrana3, "The `close_cursor` request");
 
             let cursor_id = req.cursor_id;
+            if let Some(stream_id) = session.expired_cursors.remove(&cursor_id) {
+                // the cursor is already gone with its stream, the client just acknowledges that
+                if let Some(expired_cursor_id) = session.expired_streams.get_mut(&stream_id) {
+                    *expired_cursor_id = None;
+                }
+                respond!(proto::Response::CloseCursor(proto::CloseCursorResp {}));
+                return Ok(resp_rx);
+            }
             let Some(stream_id) = session.cursors.remove(&cursor_id) else {
                 bail!(ProtocolError::CursorNotFound { cursor_id })
             };
@@ -357,6 +447,9 @@ pub(super) async fn handle_request<F: MakeNamespace>(
             ensure_version!(Version::Hrana3, "The `fetch_cursor` request");
 
             let cursor_id = req.cursor_id;
+            if let Some(&stream_id) = session.expired_cursors.get(&cursor_id) {
+                bail!(ResponseError::StreamExpired { stream_id })
+            }
             let Some(&stream_id) = session.cursors.get(&cursor_id) else {
                 bail!(ProtocolError::CursorNotFound { cursor_id })
             };
@@ -365,28 +458,22 @@ pub(super) async fn handle_request<F: MakeNamespace>(
             assert_eq!(stream_hnd.cursor_id, Some(cursor_id));
 
             let max_count = req.max_count as usize;
-            let max_total_size = server.max_response_size / 8;
+            let max_total_size = server.max_fetch_cursor_size;
+            let skip = req.skip;
             stream_respond!(stream_hnd, async move |stream| {
                 let cursor_hnd = get_stream_cursor_hnd!(stream, cursor_id);
-
-                let mut entries = Vec::new();
-                let mut total_size = 0;
-                let mut done = false;
-                while entries.len() < max_count && total_size < max_total_size {
-                    let Some(sized_entry) = cursor_hnd.fetch().await? else {
-                        done = true;
-                        break
-                    };
-                    entries.push(sized_entry.entry);
-                    total_size += sized_entry.size;
-                }
-
-                Ok(proto::Response::FetchCursor(proto::FetchCursorResp {
-                    entries,
-                    done,
-                }))
+                // the skipped rows are not returned, so they don't count towards the limits
+                cursor_hnd.skip(skip.into());
+                let resp = fetch_cursor(cursor_hnd, max_count, max_total_size).await?;
+                Ok(proto::Response::FetchCursor(resp))
             });
         }
+        proto::Request::GetLimits(_req) => {
+            ensure_version!(Version::Hrana3, "The `get_limits` request");
+            respond!(proto::Response::GetLimits(proto::GetLimitsResp {
+                limits: limits(server),
+            }));
+        }
         proto::Request::GetAutocommit(req) => {
             ensure_version!(Version::Hrana3, "The `get_autocommit` request");
             let stream_id = req.stream_id;
@@ -404,23 +491,99 @@ pub(super) async fn handle_request<F: MakeNamespace>(
     Ok(resp_rx)
 }
 
-const MAX_SQL_COUNT: usize = 150;
+/// Fetches up to `max_count` entries from the cursor, stopping early once they reach
+/// `max_total_size` bytes. The response is `done` if the cursor is known to have no more entries,
+/// even if the limits were reached, so that the client usually doesn't have to fetch again to find
+/// out. This doesn't wait for the next entry of the cursor.
+async fn fetch_cursor<C>(
+    cursor_hnd: &mut cursor::CursorHandle<C>,
+    max_count: usize,
+    max_total_size: u64,
+) -> Result<proto::FetchCursorResp> {
+    let mut entries = Vec::new();
+    let mut total_size = 0;
+    while entries.len() < max_count && total_size < max_total_size {
+        let Some(sized_entry) = cursor_hnd.fetch().await? else {
+            return Ok(proto::FetchCursorResp {
+                entries,
+                done: true,
+            });
+        };
+        entries.push(sized_entry.entry);
+        total_size += sized_entry.size;
+    }
+    let done = cursor_hnd.is_done();
+    Ok(proto::FetchCursorResp { entries, done })
+}
+
+/// Closes the streams that have not been used for `idle_timeout`, dropping their database
+/// connections, which rolls back their open transactions. Subsequent requests on these streams,
+/// or on their cursors, fail with [`ResponseError::StreamExpired`].
+pub(super) fn expire_idle_streams<D>(session: &mut Session<D>, idle_timeout: Duration) {
+    let now = Instant::now();
+    let expired_streams = &mut session.expired_streams;
+    let cursors = &mut session.cursors;
+    let expired_cursors = &mut session.expired_cursors;
+    session.streams.retain(|&stream_id, stream_hnd| {
+        if stream_hnd.pending_jobs.load(Ordering::Acquire) > 0 {
+            // a long running request is not inactivity
+            stream_hnd.last_activity = now;
+            return true;
+        }
+
+        if now.duration_since(stream_hnd.last_activity) < idle_timeout {
+            return true;
+        }
+
+        tracing::debug!("closing stream {stream_id} due to inactivity");
+        expired_streams.insert(stream_id, stream_hnd.cursor_id);
+        if let Some(cursor_id) = stream_hnd.cursor_id {
+            cursors.remove(&cursor_id);
+            expired_cursors.insert(cursor_id, stream_id);
+        }
+        false
+    });
+}
+
+/// Closes all streams of the session and their cursors, because the client is gone. Unlike
+/// [`expire_idle_streams`], nothing is kept to answer later requests, as there will be none.
+///
+/// This only drops the handles: the tasks of the streams and cursors must be aborted with the join
+/// set in which they were spawned, so that the jobs running on them are cancelled and the database
+/// connections are released right away.
+pub(super) fn close_all_streams<D>(session: &mut Session<D>) {
+    let count = session.streams.len();
+    if count > 0 {
+        tracing::debug!("closing {count} streams of a disconnected session");
+    }
+    session.streams.clear();
+    session.expired_streams.clear();
+    session.cursors.clear();
+    session.expired_cursors.clear();
+}
 
 fn stream_spawn<D: Connection>(
     join_set: &mut tokio::task::JoinSet<()>,
     stream: Stream<D>,
 ) -> StreamHandle<D> {
     let (job_tx, mut job_rx) = mpsc::channel::<StreamJob<D>>(8);
-    join_set.spawn(async move {
-        let mut stream = stream;
-        while let Some(job) = job_rx.recv().await {
-            let res = (job.f)(&mut stream).await;
-            let _: Result<_, _> = job.resp_tx.send(res);
+    let pending_jobs = Arc::new(AtomicUsize::new(0));
+    join_set.spawn({
+        let pending_jobs = pending_jobs.clone();
+        async move {
+            let mut stream = stream;
+            while let Some(job) = job_rx.recv().await {
+                let res = (job.f)(&mut stream).await;
+                pending_jobs.fetch_sub(1, Ordering::AcqRel);
+                let _: Result<_, _> = job.resp_tx.send(res);
+            }
         }
     });
     StreamHandle {
         job_tx,
         cursor_id: None,
+        last_activity: Instant::now(),
+        pending_jobs,
     }
 }
 
@@ -436,7 +599,48 @@ async fn stream_respond<F, D>(
         f: Box::new(f),
         resp_tx,
     };
-    let _: Result<_, _> = stream_hnd.job_tx.send(job).await;
+    stream_hnd.pending_jobs.fetch_add(1, Ordering::AcqRel);
+    if stream_hnd.job_tx.send(job).await.is_err() {
+        stream_hnd.pending_jobs.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Stores the SQL text `sql` in the session, unless the session would then store more than
+/// `max_count` SQL texts, or more than `max_bytes` bytes of them.
+fn store_sql<D>(
+    session: &mut Session<D>,
+    sql_id: i32,
+    sql: String,
+    max_count: usize,
+    max_bytes: u64,
+) -> Result<()> {
+    let size = sql.len() as u64;
+    if session.sqls.contains_key(&sql_id) {
+        bail!(ProtocolError::SqlExists { sql_id })
+    } else if session.sqls.len() >= max_count {
+        bail!(ResponseError::SqlTooMany {
+            count: max_count,
+            sql_ids: stored_sql_ids(session),
+        })
+    } else if session.sqls_size + size > max_bytes {
+        bail!(ResponseError::SqlTooLarge {
+            size,
+            stored: session.sqls_size,
+            limit: max_bytes,
+            sql_ids: stored_sql_ids(session),
+        })
+    }
+
+    session.sqls.insert(sql_id, sql);
+    session.sqls_size += size;
+    Ok(())
+}
+
+/// Returns the ids of the SQL texts stored in the session, in ascending order.
+fn stored_sql_ids<D>(session: &Session<D>) -> Vec<i32> {
+    let mut sql_ids: Vec<_> = session.sqls.keys().copied().collect();
+    sql_ids.sort_unstable();
+    sql_ids
 }
 
 fn catch_stmt_error(err: anyhow::Error) -> anyhow::Error {
@@ -458,10 +662,223 @@ impl ResponseError {
         match self {
             Self::Auth { source } => source.code(),
             Self::SqlTooMany { .. } => "SQL_STORE_TOO_MANY",
+            Self::SqlTooLarge { .. } => "SQL_STORE_TOO_LARGE",
+            Self::StreamTooMany { .. } => "STREAM_TOO_MANY",
             Self::StreamNotOpen { .. } => "STREAM_NOT_OPEN",
+            Self::StreamExpired { .. } => "STREAM_EXPIRED",
             Self::CursorNotOpen { .. } => "CURSOR_NOT_OPEN",
             Self::Stmt(err) => err.code(),
             Self::Batch(err) => err.code(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::connection::config::DatabaseConfigStore;
+    use crate::connection::libsql::LibSqlConnection;
+    use crate::connection::program::Program;
+    use crate::query_result_builder::{IgnoreResult, QueryBuilderConfig};
+    use crate::stats::Stats;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn expire_idle_stream_and_its_cursor() {
+        let mut join_set = tokio::task::JoinSet::new();
+        let mut spawn = || {
+            stream_spawn::<LibSqlConnection>(
+                &mut join_set,
+                Stream {
+                    db: None,
+                    cursor_hnd: None,
+                },
+            )
+        };
+        let mut session = Session {
+            authenticated: Authenticated::Anonymous,
+            version: Version::Hrana3,
+            streams: HashMap::new(),
+            expired_streams: HashMap::new(),
+            sqls: HashMap::new(),
+            sqls_size: 0,
+            cursors: HashMap::new(),
+            expired_cursors: HashMap::new(),
+        };
+        let mut idle = spawn();
+        idle.cursor_id = Some(10);
+        idle.last_activity -= Duration::from_secs(10);
+        session.streams.insert(1, idle);
+        session.cursors.insert(10, 1);
+        let mut busy = spawn();
+        busy.last_activity -= Duration::from_secs(10);
+        busy.pending_jobs.fetch_add(1, Ordering::AcqRel);
+        session.streams.insert(2, busy);
+        session.streams.insert(3, spawn());
+
+        expire_idle_streams(&mut session, Duration::from_secs(5));
+
+        let mut streams: Vec<_> = session.streams.keys().copied().collect();
+        streams.sort();
+        assert_eq!(streams, [2, 3]);
+        assert_eq!(session.expired_streams, HashMap::from([(1, Some(10))]));
+        assert!(session.cursors.is_empty());
+        assert_eq!(session.expired_cursors, HashMap::from([(10, 1)]));
+    }
+
+    #[test]
+    fn store_sql_within_limits() {
+        let mut session = Session::<LibSqlConnection> {
+            authenticated: Authenticated::Anonymous,
+            version: Version::Hrana3,
+            streams: HashMap::new(),
+            expired_streams: HashMap::new(),
+            sqls: HashMap::new(),
+            sqls_size: 0,
+            cursors: HashMap::new(),
+            expired_cursors: HashMap::new(),
+        };
+        let code = |err: anyhow::Error| err.downcast::<ResponseError>().unwrap().code();
+
+        store_sql(&mut session, 2, "SELECT 1".into(), 3, 20).unwrap();
+        store_sql(&mut session, 1, "SELECT 2".into(), 3, 20).unwrap();
+        assert_eq!(session.sqls_size, 16);
+
+        let err = store_sql(&mut session, 3, "SELECT 3".into(), 3, 20).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ResponseError>(),
+            Some(ResponseError::SqlTooLarge { size: 8, stored: 16, limit: 20, sql_ids })
+                if sql_ids == &[1, 2]
+        ));
+        assert_eq!(code(err), "SQL_STORE_TOO_LARGE");
+        assert_eq!(session.sqls.len(), 2);
+
+        store_sql(&mut session, 3, "SELECT".into(), 3, 30).unwrap();
+        let err = store_sql(&mut session, 4, "".into(), 3, 30).unwrap_err();
+        assert_eq!(code(err), "SQL_STORE_TOO_MANY");
+
+        let err = store_sql(&mut session, 3, "".into(), 4, 30).unwrap_err();
+        assert!(err.downcast_ref::<ProtocolError>().is_some());
+        assert_eq!(session.sqls_size, 22);
+    }
+
+    #[tokio::test]
+    async fn close_all_streams_releases_connections() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlConnection::new(
+            tmp.path().to_owned(),
+            Arc::new([]),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Default::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let db = Arc::new(db);
+        let weak_db = Arc::downgrade(&db);
+
+        let mut join_set = tokio::task::JoinSet::new();
+        let mut stream_hnd = stream_spawn(
+            &mut join_set,
+            Stream {
+                db: Some(db),
+                cursor_hnd: None,
+            },
+        );
+        // a job which never finishes by itself, like a query that the client stopped waiting for
+        let (resp_tx, resp_rx) = oneshot::channel();
+        stream_respond(&mut stream_hnd, resp_tx, |_stream| {
+            Box::pin(std::future::pending())
+        })
+        .await;
+        stream_hnd.cursor_id = Some(10);
+
+        let mut session = Session {
+            authenticated: Authenticated::Anonymous,
+            version: Version::Hrana3,
+            streams: HashMap::from([(1, stream_hnd)]),
+            expired_streams: HashMap::from([(2, None)]),
+            sqls: HashMap::new(),
+            sqls_size: 0,
+            cursors: HashMap::from([(10, 1)]),
+            expired_cursors: HashMap::new(),
+        };
+
+        close_all_streams(&mut session);
+        assert!(session.streams.is_empty());
+        assert!(session.expired_streams.is_empty());
+        assert!(session.cursors.is_empty());
+
+        join_set.shutdown().await;
+        assert!(weak_db.upgrade().is_none());
+        // the job was cancelled without a response
+        assert!(resp_rx.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_cursor_is_done_when_cursor_ended() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlConnection::new(
+            tmp.path().to_owned(),
+            Arc::new([]),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Default::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let db = Arc::new(db);
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        db.execute_program(
+            Program::seq(&["CREATE TABLE t (x)", "INSERT INTO t VALUES (1), (2), (3)"]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        let mut join_set = tokio::task::JoinSet::new();
+        let mut cursor_hnd = cursor::CursorHandle::spawn(&mut join_set);
+        // the cursor hasn't produced anything yet, which doesn't block the check
+        assert!(!cursor_hnd.is_done());
+        cursor_hnd.open(db, auth, Program::seq(&["SELECT x FROM t"]));
+
+        // the cursor returns a step begin, 3 rows and a step end
+        let resp = fetch_cursor(&mut cursor_hnd, 2, u64::MAX).await.unwrap();
+        assert_eq!(resp.entries.len(), 2);
+        assert!(!resp.done);
+        let resp = fetch_cursor(&mut cursor_hnd, 3, u64::MAX).await.unwrap();
+        assert_eq!(resp.entries.len(), 3);
+        assert!(matches!(
+            resp.entries.last(),
+            Some(proto::CursorEntry::StepEnd(_))
+        ));
+
+        // once the cursor has ended, the last batch is known to be the last one
+        join_set.join_next().await.unwrap().unwrap();
+        assert!(cursor_hnd.is_done());
+
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            fetch_cursor(&mut cursor_hnd, 3, u64::MAX),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(resp.entries.is_empty());
+        assert!(resp.done);
+    }
+}
@@ -0,0 +1,267 @@
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use hyper::{header, StatusCode};
+use rusqlite::types::ValueRef;
+use serde::Deserialize;
+
+use crate::auth::Authenticated;
+use crate::connection::Connection;
+use crate::error::Error;
+use crate::query::{Params, Query};
+use crate::query_analysis::Statement;
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+
+use super::db_factory::MakeConnectionExtractor;
+use super::parse_queries;
+use super::result_builder::JsonHttpPayloadBuilder;
+use super::types::QueryObject;
+
+#[derive(Debug, Deserialize)]
+struct BatchReq {
+    statements: Vec<QueryObject>,
+    #[serde(default)]
+    mode: BatchMode,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BatchMode {
+    /// The statements are executed in a `BEGIN DEFERRED` transaction, so that the batch is
+    /// applied as a whole, or not at all.
+    Deferred,
+    /// The statements are executed as with [`Connection::execute_batch_or_rollback`]: the batch
+    /// stops at the first failed statement, and rolls back the transaction it opened, if any.
+    #[default]
+    Rollback,
+}
+
+/// Executes a batch of statements sent to `POST /batch`, with the body:
+///
+/// `{ "statements": [<statement>, ...], "mode": "deferred" | "rollback" }`
+///
+/// where the statements are the same as for the queries of `POST /`. The response is the array of
+/// the results of the statements, or, if one failed, its error and its index in the batch. Like the
+/// other requests, the body is limited to `--max-request-size`.
+pub(super) async fn handle_batch<C: Connection>(
+    auth: Authenticated,
+    MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<C>,
+    body: Bytes,
+) -> crate::Result<axum::response::Response> {
+    let req: BatchReq =
+        serde_json::from_slice(&body).map_err(|e| Error::FailedToParse(e.to_string()))?;
+    let batch = parse_queries(req.statements)?;
+    let batch_len = batch.len();
+    let (batch, skip) = match req.mode {
+        BatchMode::Rollback => (batch, 0),
+        BatchMode::Deferred => {
+            let mut steps = Vec::with_capacity(batch_len + 2);
+            steps.push(txn_query("BEGIN DEFERRED"));
+            steps.extend(batch);
+            steps.push(txn_query("COMMIT"));
+            (steps, 1)
+        }
+    };
+
+    let db = connection_maker.create().await?;
+    let builder = FirstFailure::new(JsonHttpPayloadBuilder::new().take(batch_len).skip(skip));
+    let (builder, _) = db.execute_batch_or_rollback(batch, auth, builder).await?;
+
+    match builder.failure {
+        // the index of a failed commit is past the last statement
+        Some((index, error)) => {
+            let json = serde_json::json!({ "error": error, "index": index.saturating_sub(skip) });
+            Ok((StatusCode::BAD_REQUEST, axum::Json(json)).into_response())
+        }
+        None => Ok((
+            [(header::CONTENT_TYPE, "application/json")],
+            builder.inner.into_ret(),
+        )
+            .into_response()),
+    }
+}
+
+fn txn_query(sql: &str) -> Query {
+    Query {
+        stmt: Statement::parse(sql).next().unwrap().unwrap(),
+        params: Params::empty(),
+        want_rows: false,
+        max_memory_bytes: None,
+        max_rows: None,
+    }
+}
+
+/// A builder that wraps another builder, and records the index and the error of the first failed
+/// step.
+struct FirstFailure<B> {
+    inner: B,
+    step: usize,
+    failure: Option<(usize, String)>,
+}
+
+impl<B> FirstFailure<B> {
+    fn new(inner: B) -> Self {
+        Self {
+            inner,
+            step: 0,
+            failure: None,
+        }
+    }
+}
+
+impl<B: QueryResultBuilder> QueryResultBuilder for FirstFailure<B> {
+    type Ret = B::Ret;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        self.step = 0;
+        self.failure = None;
+        self.inner.init(config)
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_step()
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.step += 1;
+        self.inner
+            .finish_step(affected_row_count, last_insert_rowid)
+    }
+
+    fn step_error(&mut self, error: crate::error::Error) -> Result<(), QueryResultBuilderError> {
+        if self.failure.is_none() {
+            self.failure = Some((self.step, error.to_string()));
+        }
+        self.inner.step_error(error)
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.inner.cols_description(cols)
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_rows()
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_row()
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.inner.add_row_value(v)
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_row()
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_rows()
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish()
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.inner.into_ret()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Request};
+    use tower::ServiceExt;
+
+    use crate::http::test::test_router;
+
+    use super::*;
+
+    #[test]
+    fn first_failure_index() {
+        // steps of a deferred batch of 3 statements, whose second statement fails
+        let mut builder = FirstFailure::new(JsonHttpPayloadBuilder::new().take(3).skip(1));
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+        for step in 0..5 {
+            builder.begin_step().unwrap();
+            if step == 2 {
+                builder
+                    .step_error(Error::QueryError("no such table: t".into()))
+                    .unwrap();
+            }
+            builder.finish_step(0, None).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let (index, error) = builder.failure.clone().unwrap();
+        assert_eq!(index - 1, 1);
+        assert!(error.contains("no such table"));
+
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&builder.into_ret()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results[1]["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn batch_modes() {
+        let (router, _state, _tmp) = test_router();
+        let batch = |body: serde_json::Value| {
+            let req = Request::post("/batch")
+                .header("host", "foo.sqld")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let router = router.clone();
+            async move {
+                let resp = router.oneshot(req).await.unwrap();
+                let status = resp.status();
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, body)
+            }
+        };
+        let statements = serde_json::json!([
+            "CREATE TABLE t (x)",
+            { "q": "INSERT INTO t VALUES (?)", "params": [1] },
+            { "q": "INSERT INTO u VALUES (:x)", "params": { ":x": 2 } },
+        ]);
+
+        // a deferred batch is applied as a whole, or not at all
+        let (status, body) = batch(serde_json::json!({
+            "statements": statements,
+            "mode": "deferred",
+        }))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["index"], 2, "{body}");
+        let (status, body) = batch(serde_json::json!({ "statements": ["SELECT * FROM t"] })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["index"], 0, "{body}");
+
+        // a batch in the default mode keeps the statements before the failed one
+        let (status, body) = batch(serde_json::json!({ "statements": statements })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["index"], 2, "{body}");
+        let (status, body) = batch(serde_json::json!({
+            "statements": ["SELECT count(*) FROM t", "SELECT x FROM t"],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 2, "{body}");
+
+        // Hrana over HTTP v1 batches have their own route
+        let (status, _) = batch(serde_json::json!({
+            "batch": { "steps": [{ "stmt": { "sql": "SELECT 1" } }] },
+        }))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,165 @@
+use std::convert::Infallible;
+
+use axum::extract::State as AxumState;
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::http::HeaderValue;
+use hyper::{Body, Request};
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowMethods, AllowOrigin, Any, CorsLayer};
+
+use crate::namespace::MakeNamespace;
+
+use super::db_factory::NamespaceConfig;
+use super::AppState;
+
+/// Middleware applying the CORS policy of the namespace of the request, set by the
+/// `cors_origins` of its config. Requests to a namespace without `cors_origins`, or whose
+/// namespace can't be resolved or is not loaded, are allowed from any origin.
+pub(super) async fn handle_cors<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let config = match req.extensions().get::<NamespaceConfig>() {
+        Some(config) => config.clone(),
+        None => NamespaceConfig::lookup(&state, req.headers()).await,
+    };
+    let origins = config.0.and_then(|config| config.cors_origins.clone());
+
+    // The CORS service calls its inner service at most once: preflight requests are answered
+    // without reaching the router.
+    let mut next = Some(next);
+    let inner = tower::service_fn(move |req| {
+        let next = next.take().expect("inner service called more than once");
+        async move { Ok::<_, Infallible>(next.run(req).await) }
+    });
+    match cors_layer(origins.as_deref())
+        .layer(inner)
+        .oneshot(req)
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => match e {},
+    }
+}
+
+fn cors_layer(origins: Option<&[String]>) -> CorsLayer {
+    let allow_origin = match origins {
+        Some(origins) if !origins.iter().any(|origin| origin == "*") => AllowOrigin::list(
+            origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        ),
+        _ => AllowOrigin::any(),
+    };
+
+    CorsLayer::new()
+        .allow_methods(AllowMethods::any())
+        .allow_headers(Any)
+        .allow_origin(allow_origin)
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN};
+    use tower::service_fn;
+
+    use crate::connection::config::DatabaseConfig;
+    use crate::http::test::test_router;
+    use crate::namespace::RestoreOption;
+
+    use super::*;
+
+    async fn allowed_origin(origins: Option<&[String]>, origin: &str) -> Option<HeaderValue> {
+        let svc = cors_layer(origins).layer(service_fn(|_req: Request<Body>| async {
+            Ok::<_, Infallible>(hyper::Response::new(Body::empty()))
+        }));
+        let req = Request::get("/")
+            .header(ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap();
+        let resp = svc.oneshot(req).await.unwrap();
+        resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).cloned()
+    }
+
+    #[tokio::test]
+    async fn namespace_cors_origins() {
+        assert_eq!(
+            allowed_origin(None, "https://example.com").await.unwrap(),
+            "*"
+        );
+
+        let origins = ["https://example.com".to_string()];
+        assert_eq!(
+            allowed_origin(Some(&origins), "https://example.com")
+                .await
+                .unwrap(),
+            "https://example.com"
+        );
+        assert!(allowed_origin(Some(&origins), "https://evil.com")
+            .await
+            .is_none());
+
+        let origins = ["https://example.com".to_string(), "*".to_string()];
+        assert_eq!(
+            allowed_origin(Some(&origins), "https://evil.com")
+                .await
+                .unwrap(),
+            "*"
+        );
+    }
+
+    #[tokio::test]
+    async fn namespace_cors_origins_through_router() {
+        let (router, state, tmp) = test_router();
+        state
+            .namespaces
+            .create("foo".into(), RestoreOption::Latest)
+            .await
+            .unwrap();
+        state
+            .namespaces
+            .with("foo".into(), |ns| {
+                ns.config_store.store(DatabaseConfig {
+                    cors_origins: Some(vec!["https://example.com".into()]),
+                    ..DatabaseConfig::default()
+                })
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let preflight = |host: &str, origin: &str| {
+            Request::options("/v2/pipeline")
+                .header("host", host)
+                .header(ORIGIN, origin)
+                .header("access-control-request-method", "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let allowed_origin =
+            |resp: Response| resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).cloned();
+
+        let resp = router
+            .clone()
+            .oneshot(preflight("foo.sqld", "https://example.com"))
+            .await
+            .unwrap();
+        assert_eq!(allowed_origin(resp).unwrap(), "https://example.com");
+        let resp = router
+            .clone()
+            .oneshot(preflight("foo.sqld", "https://evil.com"))
+            .await
+            .unwrap();
+        assert!(allowed_origin(resp).is_none());
+
+        // a namespace which is not loaded is allowed from any origin, and is not created
+        let resp = router
+            .oneshot(preflight("bar.sqld", "https://evil.com"))
+            .await
+            .unwrap();
+        assert_eq!(allowed_origin(resp).unwrap(), "*");
+        assert!(!tmp.path().join("dbs").join("bar").exists());
+    }
+}
@@ -0,0 +1,467 @@
+//! Server-side cursors, that let clients of the HTTP API page through result sets that are too
+//! large to be sent in a single response.
+//!
+//! Opening a cursor starts executing the statement; rows are produced by the connection and sent
+//! through a bounded channel, so that the query is paused until the client fetches more rows. A
+//! cursor can only be fetched by the client that opened it, on the same namespace.
+
+use std::collections::HashMap;
+use std::mem::take;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, Query as AxumQuery, State as AxumState};
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use hyper::HeaderMap;
+use parking_lot::Mutex;
+use rusqlite::types::ValueRef;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::auth::Authenticated;
+use crate::connection::program::{Program, Step};
+use crate::connection::Connection;
+use crate::error::Error;
+use crate::namespace::MakeNamespace;
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+
+use super::db_factory::{namespace_from_headers, MakeConnectionExtractor};
+use super::result_builder::HttpJsonValueSerializer;
+use super::types::QueryObject;
+use super::{parse_queries, AppState, Json};
+
+/// Number of rows that are buffered ahead of the client.
+const CURSOR_BUFFER_ROWS: usize = 128;
+const DEFAULT_FETCH_COUNT: usize = 100;
+const MAX_FETCH_COUNT: usize = 10_000;
+
+enum CursorEvent {
+    Columns(Vec<String>),
+    Row(Vec<serde_json::Value>),
+    Error(Error),
+}
+
+struct Cursor {
+    events_rx: mpsc::Receiver<CursorEvent>,
+    /// Error that was received after some rows, and is returned by the next fetch.
+    pending_error: Option<Error>,
+}
+
+/// The client that opened a cursor.
+#[derive(Debug, PartialEq, Eq)]
+struct CursorOwner {
+    namespace: Bytes,
+    auth: Authenticated,
+    /// Digest of the `Authorization` header, which tells apart the clients with the same access.
+    credentials: Option<[u8; 32]>,
+}
+
+impl CursorOwner {
+    fn new<F: MakeNamespace>(
+        state: &AppState<F>,
+        headers: &HeaderMap,
+        auth: Authenticated,
+    ) -> crate::Result<Self> {
+        let namespace = namespace_from_headers(
+            headers,
+            state.disable_default_namespace,
+            state.disable_namespaces,
+        )?;
+        let credentials = headers
+            .get(hyper::header::AUTHORIZATION)
+            .map(|value| Sha256::digest(value.as_bytes()).into());
+        Ok(Self {
+            namespace,
+            auth,
+            credentials,
+        })
+    }
+}
+
+struct CursorEntry {
+    cursor: Arc<tokio::sync::Mutex<Cursor>>,
+    owner: CursorOwner,
+    last_access: Instant,
+}
+
+/// The open cursors of the HTTP API.
+pub struct CursorStore {
+    cursors: Mutex<HashMap<Uuid, CursorEntry>>,
+    idle_timeout: Duration,
+}
+
+impl CursorStore {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            cursors: Mutex::new(HashMap::new()),
+            idle_timeout,
+        }
+    }
+
+    fn insert(&self, cursor: Cursor, owner: CursorOwner) -> Uuid {
+        let cursor_id = Uuid::new_v4();
+        self.cursors.lock().insert(
+            cursor_id,
+            CursorEntry {
+                cursor: Arc::new(tokio::sync::Mutex::new(cursor)),
+                owner,
+                last_access: Instant::now(),
+            },
+        );
+        cursor_id
+    }
+
+    /// Returns the cursor, if it was opened by `owner`. The cursors of other clients are not
+    /// found, so that their IDs can't be probed.
+    fn get(
+        &self,
+        cursor_id: &Uuid,
+        owner: &CursorOwner,
+    ) -> Option<Arc<tokio::sync::Mutex<Cursor>>> {
+        let mut cursors = self.cursors.lock();
+        let entry = cursors
+            .get_mut(cursor_id)
+            .filter(|entry| entry.owner == *owner)?;
+        entry.last_access = Instant::now();
+        Some(entry.cursor.clone())
+    }
+
+    fn touch(&self, cursor_id: &Uuid) {
+        if let Some(entry) = self.cursors.lock().get_mut(cursor_id) {
+            entry.last_access = Instant::now();
+        }
+    }
+
+    fn remove(&self, cursor_id: &Uuid) {
+        self.cursors.lock().remove(cursor_id);
+    }
+
+    /// Periodically closes the cursors that were not used for the idle timeout. Dropping a cursor
+    /// aborts the statement that feeds it.
+    pub async fn run_expire(&self) {
+        let period = (self.idle_timeout / 2).max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            self.cursors.lock().retain(|cursor_id, entry| {
+                // a cursor that is being fetched from is not idle
+                let busy = entry.cursor.try_lock().is_err();
+                let keep = busy || now.duration_since(entry.last_access) < self.idle_timeout;
+                if !keep {
+                    tracing::debug!("closing cursor {cursor_id} due to inactivity");
+                }
+                keep
+            });
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OpenCursorReq {
+    statement: QueryObject,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenCursorResp {
+    cursor_id: Uuid,
+    columns: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FetchCursorParams {
+    count: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct FetchCursorResp {
+    rows: Vec<Vec<serde_json::Value>>,
+    done: bool,
+}
+
+pub(super) async fn handle_open_cursor<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    auth: Authenticated,
+    headers: HeaderMap,
+    MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<
+        <F::Database as crate::database::Database>::Connection,
+    >,
+    Json(req): Json<OpenCursorReq>,
+) -> crate::Result<axum::response::Response> {
+    let query = parse_queries(vec![req.statement])?
+        .pop()
+        .expect("one query was parsed");
+    // rows are pushed to the cursor from a blocking context, which is only guaranteed for
+    // statements that run against the local database.
+    if !query.stmt.is_read_only() {
+        return Err(Error::QueryError(
+            "cursors only support read-only statements".into(),
+        ));
+    }
+    let pgm = Program::new(vec![Step { cond: None, query }]);
+    let owner = CursorOwner::new(&state, &headers, auth)?;
+
+    let db = connection_maker.create().await?;
+    let (events_tx, mut events_rx) = mpsc::channel(CURSOR_BUFFER_ROWS);
+    tokio::spawn(async move {
+        let builder = CursorResultBuilder {
+            events_tx: events_tx.clone(),
+            row: Vec::new(),
+        };
+        if let Err(e) = db.execute_program(pgm, auth, builder).await {
+            let _: Result<_, _> = events_tx.send(CursorEvent::Error(e)).await;
+        }
+    });
+
+    let columns = match events_rx.recv().await {
+        Some(CursorEvent::Columns(columns)) => columns,
+        Some(CursorEvent::Error(e)) => return Err(e),
+        Some(CursorEvent::Row(_)) => unreachable!("rows are sent after the columns"),
+        // the statement did not describe any column
+        None => Vec::new(),
+    };
+
+    let cursor_id = state.cursors.insert(
+        Cursor {
+            events_rx,
+            pending_error: None,
+        },
+        owner,
+    );
+    let resp = OpenCursorResp { cursor_id, columns };
+
+    Ok(axum::Json(resp).into_response())
+}
+
+pub(super) async fn handle_fetch_cursor<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    auth: Authenticated,
+    headers: HeaderMap,
+    Path(cursor_id): Path<Uuid>,
+    AxumQuery(params): AxumQuery<FetchCursorParams>,
+) -> crate::Result<axum::response::Response> {
+    let owner = CursorOwner::new(&state, &headers, auth)?;
+    let Some(cursor) = state.cursors.get(&cursor_id, &owner) else {
+        return Err(Error::CursorNotFound(cursor_id));
+    };
+    let count = params
+        .count
+        .unwrap_or(DEFAULT_FETCH_COUNT)
+        .clamp(1, MAX_FETCH_COUNT);
+
+    let mut cursor = cursor.lock().await;
+    if let Some(e) = cursor.pending_error.take() {
+        state.cursors.remove(&cursor_id);
+        return Err(e);
+    }
+
+    let mut rows = Vec::new();
+    let mut done = false;
+    while rows.len() < count {
+        match cursor.events_rx.recv().await {
+            Some(CursorEvent::Row(row)) => rows.push(row),
+            Some(CursorEvent::Columns(_)) => (),
+            Some(CursorEvent::Error(e)) if rows.is_empty() => {
+                state.cursors.remove(&cursor_id);
+                return Err(e);
+            }
+            Some(CursorEvent::Error(e)) => {
+                cursor.pending_error = Some(e);
+                break;
+            }
+            None => {
+                done = true;
+                break;
+            }
+        }
+    }
+
+    if done {
+        state.cursors.remove(&cursor_id);
+    } else {
+        state.cursors.touch(&cursor_id);
+    }
+
+    Ok(axum::Json(FetchCursorResp { rows, done }).into_response())
+}
+
+/// Sends the rows of the statement to the cursor. The query runs on the blocking thread of the
+/// connection, and is paused while the channel is full.
+struct CursorResultBuilder {
+    events_tx: mpsc::Sender<CursorEvent>,
+    row: Vec<serde_json::Value>,
+}
+
+impl CursorResultBuilder {
+    /// Waits for room in the channel. The connection calls the builder from its own thread,
+    /// outside of the runtime, where the send is driven to completion.
+    fn send(&self, event: CursorEvent) -> Result<(), QueryResultBuilderError> {
+        futures::executor::block_on(self.events_tx.send(event))
+            .map_err(|_| QueryResultBuilderError::Internal(anyhow::anyhow!("cursor was closed")))
+    }
+}
+
+impl QueryResultBuilder for CursorResultBuilder {
+    type Ret = ();
+
+    fn init(&mut self, _config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        _affected_row_count: u64,
+        _last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        self.send(CursorEvent::Error(error))
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        let columns = cols.into_iter().map(|c| c.into().name.to_owned()).collect();
+        self.send(CursorEvent::Columns(columns))
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        let value = serde_json::to_value(HttpJsonValueSerializer(&v))
+            .map_err(|e| QueryResultBuilderError::Internal(e.into()))?;
+        self.row.push(value);
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        let row = take(&mut self.row);
+        self.send(CursorEvent::Row(row))
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn into_ret(self) -> Self::Ret {}
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use crate::http::test::test_router;
+
+    async fn send(
+        router: &axum::Router,
+        req: hyper::http::request::Builder,
+        body: Body,
+    ) -> (StatusCode, serde_json::Value) {
+        let resp = router
+            .clone()
+            .oneshot(req.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn fetch(host: &str, cursor_id: &str, count: usize) -> hyper::http::request::Builder {
+        Request::get(format!("/v1/cursors/{cursor_id}/fetch?count={count}")).header("host", host)
+    }
+
+    #[tokio::test]
+    async fn fetch_rows_from_cursor() {
+        let (router, _state, _tmp) = test_router();
+        let statement =
+            "with recursive c(x) as (select 1 union all select x + 1 from c limit 250) \
+            select x from c";
+        let open = Request::post("/v1/cursors")
+            .header("host", "foo.sqld")
+            .header("content-type", "application/json");
+        let (status, body) = send(
+            &router,
+            open,
+            Body::from(serde_json::json!({ "statement": statement }).to_string()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["columns"], serde_json::json!(["x"]));
+        let cursor_id = body["cursor_id"].as_str().unwrap().to_owned();
+
+        let (status, body) = send(&router, fetch("foo.sqld", &cursor_id, 100), Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["rows"].as_array().unwrap().len(), 100);
+        assert_eq!(body["rows"][0], serde_json::json!([1]));
+        assert_eq!(body["done"], false);
+
+        let (status, body) = send(&router, fetch("foo.sqld", &cursor_id, 200), Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["rows"].as_array().unwrap().len(), 150);
+        assert_eq!(body["rows"][149], serde_json::json!([250]));
+        assert_eq!(body["done"], true);
+
+        // the cursor is closed once it's done
+        let (status, _) = send(&router, fetch("foo.sqld", &cursor_id, 100), Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn cursor_is_bound_to_its_owner() {
+        let (router, _state, _tmp) = test_router();
+        let open = Request::post("/v1/cursors")
+            .header("host", "foo.sqld")
+            .header("authorization", "Basic b3duZXI6c2VjcmV0")
+            .header("content-type", "application/json");
+        let (status, body) = send(
+            &router,
+            open,
+            Body::from(serde_json::json!({ "statement": "select 1" }).to_string()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let cursor_id = body["cursor_id"].as_str().unwrap().to_owned();
+
+        // another namespace, or other credentials, don't find the cursor
+        let req =
+            fetch("bar.sqld", &cursor_id, 10).header("authorization", "Basic b3duZXI6c2VjcmV0");
+        let (status, _) = send(&router, req, Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let req =
+            fetch("foo.sqld", &cursor_id, 10).header("authorization", "Basic b3RoZXI6c2VjcmV0");
+        let (status, _) = send(&router, req, Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&router, fetch("foo.sqld", &cursor_id, 10), Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let req =
+            fetch("foo.sqld", &cursor_id, 10).header("authorization", "Basic b3duZXI6c2VjcmV0");
+        let (status, body) = send(&router, req, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["rows"], serde_json::json!([[1]]));
+    }
+}
@@ -5,6 +5,7 @@ use bytes::Bytes;
 use hyper::http::request::Parts;
 use hyper::HeaderMap;
 
+use crate::connection::config::DatabaseConfig;
 use crate::connection::MakeConnection;
 use crate::database::Database;
 use crate::error::Error;
@@ -41,6 +42,32 @@ where
     }
 }
 
+/// Config of the namespace of a request, looked up once by the outermost namespace middleware and
+/// shared with the inner ones through the extensions of the request. It's `None` when the
+/// namespace can't be resolved, or is not loaded.
+#[derive(Clone)]
+pub(super) struct NamespaceConfig(pub Option<Arc<DatabaseConfig>>);
+
+impl NamespaceConfig {
+    /// Looks up the config of the namespace of the request. The request is not authenticated yet,
+    /// so the namespace is never loaded nor created.
+    pub(super) async fn lookup<F: MakeNamespace>(state: &AppState<F>, headers: &HeaderMap) -> Self {
+        let Ok(ns) = namespace_from_headers(
+            headers,
+            state.disable_default_namespace,
+            state.disable_namespaces,
+        ) else {
+            return Self(None);
+        };
+        Self(
+            state
+                .namespaces
+                .with_loaded(&ns, |ns| ns.config_store.effective())
+                .await,
+        )
+    }
+}
+
 pub fn namespace_from_headers(
     headers: &HeaderMap,
     disable_default_namespace: bool,
@@ -2,16 +2,26 @@ use std::future::Future;
 use std::pin::Pin;
 use std::task;
 
-use axum::extract::State as AxumState;
-use futures::StreamExt;
-use hyper::HeaderMap;
+use std::io::BufRead;
+
+use axum::extract::{BodyStream, Path, Query, RawQuery, State as AxumState};
+use axum::response::IntoResponse;
+use futures::{StreamExt, TryStreamExt};
+use hyper::{header, HeaderMap};
 use pin_project_lite::pin_project;
+use serde::Deserialize;
 
-use crate::connection::dump::exporter::export_dump;
-use crate::error::Error;
+use crate::auth::Authenticated;
+use crate::connection::dump::exporter::{export_dump, DumpOptions};
+use crate::connection::dump::loader::{LoadDumpOptions, LoadDumpStats};
+use crate::connection::dump::table::{export_table, table_exists, TableExportFormat};
+use crate::connection::Connection;
+use crate::database::Database;
+use crate::error::{Error, LoadDumpError};
 use crate::namespace::MakeNamespace;
+use crate::DEFAULT_NAMESPACE_NAME;
 
-use super::db_factory::namespace_from_headers;
+use super::db_factory::{namespace_from_headers, MakeConnectionExtractor};
 use super::AppState;
 
 pin_project! {
@@ -67,17 +77,54 @@ where
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub(super) struct DumpQuery {
+    /// Checkpoint the database before the dump.
+    #[serde(default)]
+    checkpoint: bool,
+    /// Only dump the schema, without the rows.
+    #[serde(default)]
+    schema_only: bool,
+}
+
+/// Streams a SQL dump of the database, taken from a consistent snapshot of the database (see
+/// [`export_dump`]). Pass `?checkpoint=true` to checkpoint the database first, so that the dump
+/// reads the database file rather than a long WAL. It doesn't change the contents of the dump,
+/// which sees every transaction committed before it starts either way.
+///
+/// The dump can be restricted to the schema with `?schema_only=true`, and to some tables with
+/// `?table=<name>`, repeated for each table. The dump is compressed by the compression layer of
+/// the HTTP server, according to the `Accept-Encoding` header.
 pub(super) async fn handle_dump<F: MakeNamespace>(
     AxumState(state): AxumState<AppState<F>>,
     headers: HeaderMap,
+    Query(DumpQuery {
+        checkpoint,
+        schema_only,
+    }): Query<DumpQuery>,
+    RawQuery(query): RawQuery,
 ) -> Result<axum::body::StreamBody<impl futures::Stream<Item = Result<bytes::Bytes, Error>>>, Error>
 {
+    // `Query` can't deserialize repeated parameters
+    let tables: Vec<String> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "table")
+        .map(|(_, table)| table.into_owned())
+        .collect();
+
     let namespace = namespace_from_headers(
         &headers,
         state.disable_default_namespace,
         state.disable_namespaces,
     )?;
 
+    if checkpoint {
+        let connection_maker = state
+            .namespaces
+            .with(namespace.clone(), |ns| ns.db.connection_maker())
+            .await?;
+        connection_maker.create().await?.checkpoint().await?;
+    }
+
     let db_path = state
         .path
         .join("dbs")
@@ -85,12 +132,21 @@ pub(super) async fn handle_dump<F: MakeNamespace>(
         .join("data");
 
     let connection = rusqlite::Connection::open(db_path)?;
+    for table in &tables {
+        if !table_exists(&connection, table)? {
+            return Err(Error::TableNotFound(table.clone()));
+        }
+    }
 
     let (reader, writer) = tokio::io::duplex(8 * 1024);
 
+    let options = DumpOptions {
+        schema_only,
+        tables,
+    };
     let join_handle = tokio::task::spawn_blocking(move || {
         let writer = tokio_util::io::SyncIoBridge::new(writer);
-        export_dump(connection, writer).map_err(Into::into)
+        export_dump(connection, writer, &options).map_err(Into::into)
     });
 
     let stream = tokio_util::io::ReaderStream::new(reader);
@@ -104,3 +160,124 @@ pub(super) async fn handle_dump<F: MakeNamespace>(
 
     Ok(stream)
 }
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ExportQuery {
+    table: String,
+    #[serde(default)]
+    format: TableExportFormat,
+}
+
+pub(super) async fn handle_export<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    auth: Authenticated,
+    Path(namespace): Path<String>,
+    Query(ExportQuery { table, format }): Query<ExportQuery>,
+) -> Result<impl IntoResponse, Error> {
+    if let Authenticated::Anonymous = auth {
+        return Err(Error::NotAuthorized(
+            "exporting a table requires authentication".into(),
+        ));
+    }
+
+    if (state.disable_namespaces && namespace != DEFAULT_NAMESPACE_NAME)
+        || namespace.is_empty()
+        || namespace.contains('/')
+        || namespace == "."
+        || namespace == ".."
+    {
+        return Err(Error::InvalidNamespace);
+    }
+
+    let db_path = state.path.join("dbs").join(&namespace).join("data");
+    // opening a missing database would create it
+    if !tokio::fs::try_exists(&db_path).await.unwrap_or(false) {
+        return Err(Error::NamespaceDoesntExist(namespace));
+    }
+
+    let connection = rusqlite::Connection::open(db_path)?;
+    // the table name is quoted into the export query, so it must name an existing table
+    if !table_exists(&connection, &table)? {
+        return Err(Error::QueryError(format!("no such table: {table}")));
+    }
+
+    let (reader, writer) = tokio::io::duplex(8 * 1024);
+
+    let join_handle = tokio::task::spawn_blocking(move || {
+        let writer = tokio_util::io::SyncIoBridge::new(writer);
+        export_table(connection, &table, format, writer).map_err(Into::into)
+    });
+
+    let stream = tokio_util::io::ReaderStream::new(reader);
+
+    let stream = DumpStream {
+        stream: stream.fuse(),
+        join_handle: Some(join_handle),
+    };
+
+    Ok((
+        [(header::CONTENT_TYPE, format.content_type())],
+        axum::body::StreamBody::new(stream),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct LoadQuery {
+    /// Load the dump even if the database already has tables.
+    #[serde(default)]
+    allow_non_empty: bool,
+}
+
+/// Loads the SQL dump streamed in the request body into the database, in a single transaction
+/// (see [`Connection::load_dump`]). The load is refused if the database already has tables,
+/// unless `?allow_non_empty=true` is passed.
+///
+/// The dump can be compressed with gzip, as told by the `Content-Encoding` header. If a statement
+/// fails, nothing is loaded, and the line of the statement is returned with the error.
+pub(super) async fn handle_load<C: Connection>(
+    auth: Authenticated,
+    MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<C>,
+    headers: HeaderMap,
+    Query(LoadQuery { allow_non_empty }): Query<LoadQuery>,
+    body: BodyStream,
+) -> Result<axum::Json<LoadDumpStats>, Error> {
+    let dump = dump_reader(&headers, body)?;
+    let options = LoadDumpOptions {
+        allow_non_empty,
+        ..Default::default()
+    };
+    let db = connection_maker.create().await?;
+    let stats = db.load_dump(dump, options, auth).await?;
+
+    Ok(axum::Json(stats))
+}
+
+/// Reads the SQL dump streamed in `body`, decompressing it as told by the `Content-Encoding`
+/// header. The dump is meant to be read from the blocking thread of a connection.
+pub fn dump_reader<S, E>(headers: &HeaderMap, body: S) -> Result<Box<dyn BufRead + Send>, Error>
+where
+    S: futures::Stream<Item = Result<bytes::Bytes, E>> + Send + Unpin + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let encoding = headers.get(header::CONTENT_ENCODING).map(|value| {
+        value
+            .to_str()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    });
+
+    let body = body.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+    let reader = tokio_util::io::SyncIoBridge::new(tokio_util::io::StreamReader::new(body));
+    let dump: Box<dyn BufRead + Send> = match encoding.as_deref() {
+        None | Some("identity") => Box::new(std::io::BufReader::new(reader)),
+        Some("gzip") => Box::new(std::io::BufReader::new(flate2::read::GzDecoder::new(
+            reader,
+        ))),
+        Some(encoding) => {
+            return Err(LoadDumpError::UnsupportedContentEncoding(encoding.to_owned()).into())
+        }
+    };
+
+    Ok(dump)
+}
@@ -0,0 +1,398 @@
+//! Export of the result set of a single statement as CSV or JSON lines.
+//!
+//! The rows are encoded by the connection as they are produced, and sent to the client in chunks
+//! through a bounded channel, so that the result set is never held in memory in full.
+
+use std::io::Write;
+use std::mem::take;
+
+use axum::response::IntoResponse;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use futures::StreamExt;
+use hyper::header;
+use rusqlite::types::ValueRef;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::auth::Authenticated;
+use crate::connection::dump::table::{write_csv_field, TableExportFormat};
+use crate::connection::program::{Program, Step};
+use crate::connection::Connection;
+use crate::error::Error;
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+
+use super::db_factory::MakeConnectionExtractor;
+use super::types::QueryObject;
+use super::{parse_queries, Json};
+
+/// Size above which the encoded rows are sent to the client.
+const EXPORT_CHUNK_SIZE: usize = 8 * 1024;
+/// Number of chunks that are buffered ahead of the client.
+const EXPORT_BUFFER_CHUNKS: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportReq {
+    stmt: QueryObject,
+    #[serde(default)]
+    format: TableExportFormat,
+    /// Maximum number of rows to export.
+    max_rows: Option<u64>,
+    /// Maximum size of the exported data, in bytes. The export stops before the row that would
+    /// exceed it.
+    max_bytes: Option<u64>,
+}
+
+/// Error returned by the builder to stop the statement once a limit of the export is reached.
+#[derive(Debug, thiserror::Error)]
+#[error("export limit reached")]
+struct ExportLimitReached;
+
+/// Streams the rows of a read-only statement as CSV (with a header row) or JSON lines. Blobs are
+/// encoded in hex in CSV, and in base64 in JSON.
+///
+/// The `max_response_size` of the server does not apply, since the rows are not buffered; the
+/// export can be limited with `max_rows` and `max_bytes` instead, in which case it is cut at a
+/// row boundary.
+pub(super) async fn handle_export_query<C: Connection>(
+    auth: Authenticated,
+    MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<C>,
+    Json(req): Json<ExportReq>,
+) -> crate::Result<axum::response::Response> {
+    let query = parse_queries(vec![req.stmt])?
+        .pop()
+        .expect("one query was parsed");
+    // rows are sent from a blocking context, which is only guaranteed for statements that run
+    // against the local database.
+    if !query.stmt.is_read_only() {
+        return Err(Error::QueryError(
+            "exports only support read-only statements".into(),
+        ));
+    }
+    let pgm = Program::new(vec![Step { cond: None, query }]);
+
+    let db = connection_maker.create().await?;
+    let (chunks_tx, mut chunks_rx) = mpsc::channel(EXPORT_BUFFER_CHUNKS);
+    tokio::spawn(async move {
+        let builder =
+            ExportResultBuilder::new(req.format, chunks_tx.clone(), req.max_rows, req.max_bytes);
+        match db.execute_program(pgm, auth, builder).await {
+            Err(Error::BuilderError(QueryResultBuilderError::Internal(e)))
+                if e.is::<ExportLimitReached>() => {}
+            Err(e) => {
+                let _: Result<_, _> = chunks_tx.send(Err(e)).await;
+            }
+            Ok(_) => (),
+        }
+    });
+
+    // errors that happen before the statement produces anything are reported with the status of
+    // the response, later ones abort the response.
+    let first = match chunks_rx.recv().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(e)) => return Err(e),
+        None => Bytes::new(),
+    };
+    let stream =
+        futures::stream::once(async move { Ok(first) }).chain(ReceiverStream::new(chunks_rx));
+
+    Ok((
+        [(header::CONTENT_TYPE, req.format.content_type())],
+        axum::body::StreamBody::new(stream),
+    )
+        .into_response())
+}
+
+/// Encodes the rows of the statement, and sends them to the client in chunks. The query runs on
+/// the blocking thread of the connection, and is paused while the channel is full.
+struct ExportResultBuilder {
+    format: TableExportFormat,
+    chunks_tx: mpsc::Sender<crate::Result<Bytes>>,
+    columns: Vec<String>,
+    /// Encoded rows that were not sent yet.
+    buffer: Vec<u8>,
+    /// The current row, encoded as CSV.
+    csv_row: Vec<u8>,
+    /// The current row, as a JSON object.
+    json_row: serde_json::Map<String, serde_json::Value>,
+    value_count: usize,
+    row_count: u64,
+    byte_count: u64,
+    max_rows: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+impl ExportResultBuilder {
+    fn new(
+        format: TableExportFormat,
+        chunks_tx: mpsc::Sender<crate::Result<Bytes>>,
+        max_rows: Option<u64>,
+        max_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            format,
+            chunks_tx,
+            columns: Vec::new(),
+            buffer: Vec::new(),
+            csv_row: Vec::new(),
+            json_row: serde_json::Map::new(),
+            value_count: 0,
+            row_count: 0,
+            byte_count: 0,
+            max_rows,
+            max_bytes,
+        }
+    }
+
+    fn send(&self, chunk: crate::Result<Bytes>) -> Result<(), QueryResultBuilderError> {
+        self.chunks_tx
+            .blocking_send(chunk)
+            .map_err(|_| QueryResultBuilderError::Internal(anyhow::anyhow!("export was closed")))
+    }
+
+    fn flush(&mut self) -> Result<(), QueryResultBuilderError> {
+        let chunk = Bytes::from(take(&mut self.buffer));
+        self.send(Ok(chunk))
+    }
+
+    /// Sends the rows exported so far, and stops the statement.
+    fn stop(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.flush()?;
+        Err(QueryResultBuilderError::Internal(ExportLimitReached.into()))
+    }
+}
+
+impl QueryResultBuilder for ExportResultBuilder {
+    type Ret = ();
+
+    fn init(&mut self, _config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        _affected_row_count: u64,
+        _last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        if !self.buffer.is_empty() {
+            self.flush()?;
+        }
+        self.send(Err(error))
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.columns = cols.into_iter().map(|c| c.into().name.to_owned()).collect();
+        if self.format == TableExportFormat::Csv {
+            for (i, column) in self.columns.iter().enumerate() {
+                if i > 0 {
+                    self.buffer.push(b',');
+                }
+                write_csv_field(&mut self.buffer, column)
+                    .map_err(|e| QueryResultBuilderError::Internal(e.into()))?;
+            }
+            self.buffer.extend_from_slice(b"\r\n");
+        }
+        // the first chunk tells the handler that the statement is running, even if empty
+        self.flush()
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.max_rows.is_some_and(|max| self.row_count >= max) {
+            return self.stop();
+        }
+        self.value_count = 0;
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        match self.format {
+            TableExportFormat::Csv => {
+                if self.value_count > 0 {
+                    self.csv_row.push(b',');
+                }
+                let field = match v {
+                    ValueRef::Null => String::new(),
+                    ValueRef::Integer(i) => i.to_string(),
+                    ValueRef::Real(f) => f.to_string(),
+                    ValueRef::Text(s) => String::from_utf8_lossy(s).into_owned(),
+                    ValueRef::Blob(b) => hex::encode(b),
+                };
+                write_csv_field(&mut self.csv_row, &field)
+                    .map_err(|e| QueryResultBuilderError::Internal(e.into()))?;
+            }
+            TableExportFormat::Jsonl => {
+                let value = match v {
+                    ValueRef::Null => serde_json::Value::Null,
+                    ValueRef::Integer(i) => i.into(),
+                    ValueRef::Real(f) => f.into(),
+                    ValueRef::Text(s) => String::from_utf8_lossy(s).into(),
+                    ValueRef::Blob(b) => BASE64_STANDARD.encode(b).into(),
+                };
+                let column = self.columns.get(self.value_count).cloned();
+                self.json_row.insert(column.unwrap_or_default(), value);
+            }
+        }
+        self.value_count += 1;
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        let row = match self.format {
+            TableExportFormat::Csv => {
+                self.csv_row.extend_from_slice(b"\r\n");
+                take(&mut self.csv_row)
+            }
+            TableExportFormat::Jsonl => {
+                let mut row = serde_json::to_vec(&take(&mut self.json_row))
+                    .map_err(|e| QueryResultBuilderError::Internal(e.into()))?;
+                row.push(b'\n');
+                row
+            }
+        };
+
+        let byte_count = self.byte_count + row.len() as u64;
+        if self.max_bytes.is_some_and(|max| byte_count > max) {
+            return self.stop();
+        }
+        self.byte_count = byte_count;
+        self.row_count += 1;
+        self.buffer
+            .write_all(&row)
+            .map_err(|e| QueryResultBuilderError::Internal(e.into()))?;
+        if self.buffer.len() >= EXPORT_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        if !self.buffer.is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn into_ret(self) -> Self::Ret {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn export(
+        format: TableExportFormat,
+        max_rows: Option<u64>,
+        max_bytes: Option<u64>,
+    ) -> (String, bool) {
+        let (chunks_tx, mut chunks_rx) = mpsc::channel(128);
+        let mut builder = ExportResultBuilder::new(format, chunks_tx, max_rows, max_bytes);
+        let rows: [[ValueRef; 3]; 3] = [
+            [
+                ValueRef::Integer(1),
+                ValueRef::Text(b"plain"),
+                ValueRef::Blob(b"hello"),
+            ],
+            [
+                ValueRef::Real(1.5),
+                ValueRef::Text(b"with, \"quotes\"\nand newline"),
+                ValueRef::Null,
+            ],
+            [ValueRef::Integer(3), ValueRef::Null, ValueRef::Null],
+        ];
+
+        let run = |builder: &mut ExportResultBuilder| {
+            builder.init(&QueryBuilderConfig::default())?;
+            builder.begin_step()?;
+            builder.cols_description([("id", None), ("name", None), ("data", None)])?;
+            builder.begin_rows()?;
+            for row in rows {
+                builder.begin_row()?;
+                for value in row {
+                    builder.add_row_value(value)?;
+                }
+                builder.finish_row()?;
+            }
+            builder.finish_rows()?;
+            builder.finish_step(0, None)?;
+            builder.finish()
+        };
+        let stopped = run(&mut builder).is_err();
+        drop(builder);
+
+        let mut out = Vec::new();
+        while let Ok(chunk) = chunks_rx.try_recv() {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        (String::from_utf8(out).unwrap(), stopped)
+    }
+
+    #[test]
+    fn export_csv() {
+        assert_eq!(
+            export(TableExportFormat::Csv, None, None),
+            (
+                "id,name,data\r\n1,plain,68656c6c6f\r\n1.5,\"with, \"\"quotes\"\"\nand newline\",\r\n3,,\r\n"
+                    .to_string(),
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn export_jsonl() {
+        assert_eq!(
+            export(TableExportFormat::Jsonl, None, None),
+            (
+                "{\"id\":1,\"name\":\"plain\",\"data\":\"aGVsbG8=\"}\n\
+                 {\"id\":1.5,\"name\":\"with, \\\"quotes\\\"\\nand newline\",\"data\":null}\n\
+                 {\"id\":3,\"name\":null,\"data\":null}\n"
+                    .to_string(),
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn export_limits() {
+        assert_eq!(
+            export(TableExportFormat::Csv, Some(1), None),
+            ("id,name,data\r\n1,plain,68656c6c6f\r\n".to_string(), true)
+        );
+        // the header is not counted, and the export stops before the row exceeding the limit
+        assert_eq!(
+            export(TableExportFormat::Csv, None, Some(20)),
+            ("id,name,data\r\n1,plain,68656c6c6f\r\n".to_string(), true)
+        );
+        assert_eq!(
+            export(TableExportFormat::Csv, Some(3), None),
+            (
+                "id,name,data\r\n1,plain,68656c6c6f\r\n1.5,\"with, \"\"quotes\"\"\nand newline\",\r\n3,,\r\n"
+                    .to_string(),
+                false
+            )
+        );
+    }
+}
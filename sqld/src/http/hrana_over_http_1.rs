@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::future::Future;
@@ -27,7 +28,7 @@ pub async fn handle_index() -> hyper::Response<hyper::Body> {
 pub(crate) async fn handle_execute<D: Connection>(
     MakeConnectionExtractor(factory): MakeConnectionExtractor<D>,
     auth: Authenticated,
-    req: hyper::Request<hyper::Body>,
+    body: Bytes,
 ) -> crate::Result<hyper::Response<hyper::Body>> {
     #[derive(Debug, Deserialize)]
     struct ReqBody {
@@ -39,7 +40,7 @@ pub(crate) async fn handle_execute<D: Connection>(
         result: hrana::proto::StmtResult,
     }
 
-    let res = handle_request(factory, req, |db, req_body: ReqBody| async move {
+    let res = handle_request(factory, body, |db, req_body: ReqBody| async move {
         let query = hrana::stmt::proto_stmt_to_query(
             &req_body.stmt,
             &HashMap::new(),
@@ -60,7 +61,7 @@ pub(crate) async fn handle_execute<D: Connection>(
 pub(crate) async fn handle_batch<D: Connection>(
     MakeConnectionExtractor(factory): MakeConnectionExtractor<D>,
     auth: Authenticated,
-    req: hyper::Request<hyper::Body>,
+    body: Bytes,
 ) -> crate::Result<hyper::Response<hyper::Body>> {
     #[derive(Debug, Deserialize)]
     struct ReqBody {
@@ -72,7 +73,7 @@ pub(crate) async fn handle_batch<D: Connection>(
         result: hrana::proto::BatchResult,
     }
 
-    let res = handle_request(factory, req, |db, req_body: ReqBody| async move {
+    let res = handle_request(factory, body, |db, req_body: ReqBody| async move {
         let pgm = hrana::batch::proto_batch_to_program(
             &req_body.batch,
             &HashMap::new(),
@@ -91,7 +92,7 @@ pub(crate) async fn handle_batch<D: Connection>(
 
 async fn handle_request<ReqBody, RespBody, F, Fut, FT>(
     db_factory: Arc<FT>,
-    req: hyper::Request<hyper::Body>,
+    req_body: Bytes,
     f: F,
 ) -> Result<hyper::Response<hyper::Body>>
 where
@@ -102,7 +103,6 @@ where
     FT: MakeConnection + ?Sized,
 {
     let res: Result<_> = async move {
-        let req_body = hyper::body::to_bytes(req.into_body()).await?;
         let req_body = serde_json::from_slice(&req_body)
             .map_err(|e| hrana::ProtocolError::JsonDeserialize { source: e })?;
 
@@ -140,15 +140,24 @@ fn response_error_response(err: ResponseError) -> hyper::Response<hyper::Body> {
             | StmtError::SqlNoStmt
             | StmtError::SqlManyStmts
             | StmtError::ArgsInvalid { .. }
+            | StmtError::ArgsTooMany { .. }
+            | StmtError::UnknownParameter { .. }
+            | StmtError::ArgsKindMismatch { .. }
             | StmtError::SqlInputError { .. }
             | StmtError::Proxy(_)
             | StmtError::ResponseTooLarge
+            | StmtError::MemoryLimitExceeded { .. }
+            | StmtError::RowLimitExceeded { .. }
             | StmtError::Blocked { .. } => hyper::StatusCode::BAD_REQUEST,
             StmtError::ArgsBothPositionalAndNamed => hyper::StatusCode::NOT_IMPLEMENTED,
             StmtError::TransactionTimeout | StmtError::TransactionBusy => {
                 hyper::StatusCode::SERVICE_UNAVAILABLE
             }
             StmtError::SqliteError { .. } => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            StmtError::DatabaseFull { .. } => hyper::StatusCode::INSUFFICIENT_STORAGE,
+            StmtError::AttachNotAllowed { .. }
+            | StmtError::AttachDisabled
+            | StmtError::PragmaNotAllowed { .. } => hyper::StatusCode::FORBIDDEN,
         },
     };
 
@@ -195,3 +204,44 @@ impl ResponseError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use crate::http::test::test_router;
+
+    #[tokio::test]
+    async fn reject_too_large_request() {
+        let (router, _state, _tmp) = test_router();
+        let post = |path: &str, body: String| {
+            Request::post(path)
+                .header("host", "foo.sqld")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let req = post("/v1/execute", r#"{"stmt": {"sql": "SELECT 1"}}"#.into());
+        let resp = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the test router accepts requests of at most 1MiB
+        let sql = format!("SELECT '{}'", "x".repeat(2 * 1024 * 1024));
+        let body = serde_json::json!({ "stmt": { "sql": sql } }).to_string();
+        let resp = router
+            .clone()
+            .oneshot(post("/v1/execute", body))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body = serde_json::json!({ "batch": { "steps": [{ "stmt": { "sql": sql } }] } });
+        let resp = router
+            .oneshot(post("/v1/batch", body.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -1,15 +1,21 @@
+mod batch;
+mod cors;
+mod cursor;
 pub mod db_factory;
-mod dump;
+pub mod dump;
+mod export;
 mod hrana_over_http_1;
+pub mod response_headers;
 mod result_builder;
 pub mod stats;
 mod types;
 
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
-use axum::extract::{FromRef, FromRequest, FromRequestParts, State as AxumState};
+use axum::extract::{DefaultBodyLimit, FromRef, FromRequest, FromRequestParts, State as AxumState};
 use axum::http::request::Parts;
 use axum::http::HeaderValue;
 use axum::response::{Html, IntoResponse};
@@ -25,11 +31,12 @@ use serde_json::Number;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinSet;
 use tonic::transport::Server;
+use tower_http::compression::CompressionLayer;
 use tower_http::trace::DefaultOnResponse;
-use tower_http::{compression::CompressionLayer, cors};
 use tracing::{Level, Span};
 
 use crate::auth::{Auth, Authenticated};
+use crate::connection::schema::Schema;
 use crate::connection::Connection;
 use crate::database::Database;
 use crate::error::Error;
@@ -46,7 +53,9 @@ use crate::rpc::ReplicationLogServer;
 use crate::stats::Stats;
 use crate::utils::services::idle_shutdown::IdleShutdownKicker;
 use crate::version;
+use crate::DEFAULT_NAMESPACE_NAME;
 
+use self::cursor::CursorStore;
 use self::db_factory::MakeConnectionExtractor;
 use self::result_builder::JsonHttpPayloadBuilder;
 use self::types::QueryObject;
@@ -94,6 +103,8 @@ fn parse_queries(queries: Vec<QueryObject>) -> crate::Result<Vec<Query>> {
             stmt,
             params: query.params.0,
             want_rows: true,
+            max_memory_bytes: query.max_memory_bytes,
+            max_rows: None,
         };
 
         out.push(query);
@@ -132,18 +143,96 @@ async fn handle_query<C: Connection>(
     Ok(res.into_response())
 }
 
+/// Serves the console to the clients with the same credentials as the data APIs, so that
+/// enabling it on a public server doesn't let anyone query the database.
 async fn show_console<F: MakeNamespace>(
-    AxumState(AppState { enable_console, .. }): AxumState<AppState<F>>,
+    AxumState(AppState {
+        enable_console,
+        auth,
+        ..
+    }): AxumState<AppState<F>>,
+    headers: hyper::HeaderMap,
 ) -> impl IntoResponse {
-    if enable_console {
-        Html(std::include_str!("console.html")).into_response()
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+    if !enable_console {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match auth.authenticate_http(headers.get(header::AUTHORIZATION)) {
+        Ok(Authenticated::Authorized(_)) => Html(std::include_str!("console.html")).into_response(),
+        Ok(Authenticated::Anonymous) | Err(_) if auth.http_basic.is_some() => {
+            // browsers prompt for the basic credentials, and send them with the queries of the
+            // console too
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"sqld\"")],
+            )
+                .into_response()
+        }
+        Ok(Authenticated::Anonymous) | Err(_) => StatusCode::UNAUTHORIZED.into_response(),
     }
 }
 
-async fn handle_health() -> Response<Body> {
-    // return empty OK
+/// Name of the header of the health response holding the state of the connection of a replica to
+/// its primary.
+const PRIMARY_CONNECTION_STATE_HEADER: &str = "x-sqld-primary-connection-state";
+
+async fn handle_health<F: MakeNamespace>(
+    AxumState(AppState { stats, .. }): AxumState<AppState<F>>,
+) -> Response<Body> {
+    // return empty OK, with the state of the connection to the primary on replicas
+    let mut resp = Response::new(Body::empty());
+    if let Some(state) = stats.primary_connection_state().as_str() {
+        resp.headers_mut().insert(
+            PRIMARY_CONNECTION_STATE_HEADER,
+            HeaderValue::from_static(state),
+        );
+    }
+    resp
+}
+
+/// How long `/ready` waits for a connection to the default namespace. The connection is made
+/// like any other, so a server saturated with requests reports itself as not ready instead of
+/// leaving the probe waiting in the queue.
+const READY_CONNECTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Returns `HTTP 200 (OK)` if the server can serve queries: a connection to the default namespace
+/// can be opened within [READY_CONNECTION_TIMEOUT] and, on a replica, the replica is not lagging
+/// too far behind its primary. Otherwise, returns `HTTP 503 (Service Unavailable)` with the reason.
+async fn handle_ready<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+) -> Response<Body> {
+    let not_ready = |reason: String| {
+        let mut resp = Response::new(Body::from(reason));
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        resp
+    };
+
+    if let Some(lag) = state.stats.replication_lag() {
+        if lag > state.ready_max_replication_lag {
+            return not_ready(format!(
+                "replica has been disconnected from its primary for {}s",
+                lag.as_secs()
+            ));
+        }
+    }
+
+    if !state.disable_default_namespace {
+        let maker = match state
+            .namespaces
+            .with(DEFAULT_NAMESPACE_NAME.into(), |ns| ns.db.connection_maker())
+            .await
+        {
+            Ok(maker) => maker,
+            Err(e) => return not_ready(format!("default namespace is not available: {e}")),
+        };
+        match tokio::time::timeout(READY_CONNECTION_TIMEOUT, maker.create()).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                return not_ready(format!("failed to connect to the default namespace: {e}"))
+            }
+            Err(_) => return not_ready("timed out connecting to the default namespace".into()),
+        }
+    }
+
     Response::new(Body::empty())
 }
 
@@ -173,6 +262,27 @@ async fn handle_upgrade<F: MakeNamespace>(
     }
 }
 
+/// Header holding the schema version of the database described in a response.
+const SCHEMA_VERSION_HEADER: &str = "x-sqld-schema-version";
+
+impl IntoResponse for Schema {
+    fn into_response(self) -> axum::response::Response {
+        (
+            [(SCHEMA_VERSION_HEADER, self.version.to_string())],
+            axum::Json(self),
+        )
+            .into_response()
+    }
+}
+
+async fn handle_schema<C: Connection>(
+    auth: Authenticated,
+    MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<C>,
+) -> Result<Schema, Error> {
+    let db = connection_maker.create().await?;
+    db.schema(auth).await
+}
+
 async fn handle_version() -> Response<Body> {
     let version = version::version();
     Response::new(Body::from(version))
@@ -194,6 +304,8 @@ pub(crate) struct AppState<F: MakeNamespace> {
     disable_default_namespace: bool,
     disable_namespaces: bool,
     path: Arc<Path>,
+    cursors: Arc<CursorStore>,
+    ready_max_replication_lag: Duration,
 }
 
 impl<F: MakeNamespace> Clone for AppState<F> {
@@ -208,6 +320,8 @@ impl<F: MakeNamespace> Clone for AppState<F> {
             disable_default_namespace: self.disable_default_namespace,
             disable_namespaces: self.disable_namespaces,
             path: self.path.clone(),
+            cursors: self.cursors.clone(),
+            ready_max_replication_lag: self.ready_max_replication_lag,
         }
     }
 }
@@ -216,6 +330,7 @@ pub struct UserApi<M: MakeNamespace, A, P, S> {
     pub auth: Arc<Auth>,
     pub http_acceptor: Option<A>,
     pub hrana_ws_acceptor: Option<A>,
+    pub hrana_uds_acceptor: Option<A>,
     pub namespaces: NamespaceStore<M>,
     pub idle_shutdown_kicker: Option<IdleShutdownKicker>,
     pub stats: Stats,
@@ -226,6 +341,16 @@ pub struct UserApi<M: MakeNamespace, A, P, S> {
     pub max_response_size: u64,
     pub enable_console: bool,
     pub self_url: Option<String>,
+    pub hrana_stream_idle_timeout: Option<Duration>,
+    pub hrana_max_streams_per_session: usize,
+    pub hrana_fetch_cursor_size_divisor: u64,
+    pub hrana_max_sql_count: usize,
+    pub hrana_max_sql_bytes: u64,
+    pub hrana_ws_ping_interval: Option<Duration>,
+    pub hrana_ws_max_missed_pongs: u32,
+    pub max_request_size: u64,
+    pub cursor_idle_timeout: Duration,
+    pub ready_max_replication_lag: Duration,
     pub path: Arc<Path>,
 }
 
@@ -239,7 +364,11 @@ where
     pub fn configure(self, join_set: &mut JoinSet<anyhow::Result<()>>) {
         let (hrana_accept_tx, hrana_accept_rx) = mpsc::channel(8);
         let (hrana_upgrade_tx, hrana_upgrade_rx) = mpsc::channel(8);
-        let hrana_http_srv = Arc::new(hrana::http::Server::new(self.self_url.clone()));
+        let hrana_http_srv = Arc::new(hrana::http::Server::new(
+            self.self_url.clone(),
+            self.max_request_size,
+        ));
+        let cursors = Arc::new(CursorStore::new(self.cursor_idle_timeout));
 
         join_set.spawn({
             let namespaces = self.namespaces.clone();
@@ -251,11 +380,28 @@ where
             let disable_default_namespace = self.disable_default_namespace;
             let disable_namespaces = self.disable_namespaces;
             let max_response_size = self.max_response_size;
+            let max_fetch_cursor_size =
+                self.max_response_size / self.hrana_fetch_cursor_size_divisor;
+            let stream_idle_timeout = self.hrana_stream_idle_timeout;
+            let max_streams_per_session = self.hrana_max_streams_per_session;
+            let max_sql_count = self.hrana_max_sql_count;
+            let max_sql_bytes = self.hrana_max_sql_bytes;
+            let ping_interval = self.hrana_ws_ping_interval;
+            let max_missed_pongs = self.hrana_ws_max_missed_pongs;
+            let max_request_size = self.max_request_size;
             async move {
                 hrana::ws::serve(
                     auth,
                     idle_kicker,
                     max_response_size,
+                    max_fetch_cursor_size,
+                    stream_idle_timeout,
+                    max_streams_per_session,
+                    max_sql_count,
+                    max_sql_bytes,
+                    ping_interval,
+                    max_missed_pongs,
+                    max_request_size,
                     hrana_accept_rx,
                     hrana_upgrade_rx,
                     namespaces,
@@ -275,6 +421,14 @@ where
             }
         });
 
+        join_set.spawn({
+            let cursors = cursors.clone();
+            async move {
+                cursors.run_expire().await;
+                Ok(())
+            }
+        });
+
         if let Some(acceptor) = self.hrana_ws_acceptor {
             join_set.spawn(async move {
                 hrana::ws::listen(acceptor, hrana_accept_tx).await;
@@ -282,7 +436,7 @@ where
             });
         }
 
-        if let Some(acceptor) = self.http_acceptor {
+        if self.http_acceptor.is_some() || self.hrana_uds_acceptor.is_some() {
             let state = AppState {
                 auth: self.auth,
                 upgrade_tx: hrana_upgrade_tx,
@@ -293,112 +447,15 @@ where
                 disable_default_namespace: self.disable_default_namespace,
                 disable_namespaces: self.disable_namespaces,
                 path: self.path,
+                cursors,
+                ready_max_replication_lag: self.ready_max_replication_lag,
             };
 
-            fn trace_request<B>(req: &Request<B>, _span: &Span) {
-                tracing::debug!("got request: {} {}", req.method(), req.uri());
-            }
-
-            macro_rules! handle_hrana {
-                ($endpoint:expr, $version:expr, $encoding:expr,) => {{
-                    async fn handle_hrana<F: MakeNamespace>(
-                        AxumState(state): AxumState<AppState<F>>,
-                        MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<
-                            <F::Database as Database>::Connection,
-                        >,
-                        auth: Authenticated,
-                        req: Request<Body>,
-                    ) -> Result<Response<Body>, Error> {
-                        Ok(state
-                            .hrana_http_srv
-                            .handle_request(
-                                connection_maker,
-                                auth,
-                                req,
-                                $endpoint,
-                                $version,
-                                $encoding,
-                            )
-                            .await?)
-                    }
-                    handle_hrana
-                }};
-            }
-
-            let app = Router::new()
-                .route("/", post(handle_query))
-                .route("/", get(handle_upgrade))
-                .route("/version", get(handle_version))
-                .route("/console", get(show_console))
-                .route("/health", get(handle_health))
-                .route("/dump", get(dump::handle_dump))
-                .route("/v1/stats", get(stats::handle_stats))
-                .route("/v1", get(hrana_over_http_1::handle_index))
-                .route("/v1/execute", post(hrana_over_http_1::handle_execute))
-                .route("/v1/batch", post(hrana_over_http_1::handle_batch))
-                .route("/v2", get(crate::hrana::http::handle_index))
-                .route(
-                    "/v2/pipeline",
-                    post(handle_hrana!(
-                        hrana::http::Endpoint::Pipeline,
-                        hrana::Version::Hrana2,
-                        hrana::Encoding::Json,
-                    )),
-                )
-                .route("/v3", get(crate::hrana::http::handle_index))
-                .route(
-                    "/v3/pipeline",
-                    post(handle_hrana!(
-                        hrana::http::Endpoint::Pipeline,
-                        hrana::Version::Hrana3,
-                        hrana::Encoding::Json,
-                    )),
-                )
-                .route(
-                    "/v3/cursor",
-                    post(handle_hrana!(
-                        hrana::http::Endpoint::Cursor,
-                        hrana::Version::Hrana3,
-                        hrana::Encoding::Json,
-                    )),
-                )
-                .route("/v3-protobuf", get(crate::hrana::http::handle_index))
-                .route(
-                    "/v3-protobuf/pipeline",
-                    post(handle_hrana!(
-                        hrana::http::Endpoint::Pipeline,
-                        hrana::Version::Hrana3,
-                        hrana::Encoding::Protobuf,
-                    )),
-                )
-                .route(
-                    "/v3-protobuf/cursor",
-                    post(handle_hrana!(
-                        hrana::http::Endpoint::Cursor,
-                        hrana::Version::Hrana3,
-                        hrana::Encoding::Protobuf,
-                    )),
-                )
-                .with_state(state);
-
-            let layered_app = app
-                .layer(option_layer(self.idle_shutdown_kicker.clone()))
-                .layer(
-                    tower_http::trace::TraceLayer::new_for_http()
-                        .on_request(trace_request)
-                        .on_response(
-                            DefaultOnResponse::new()
-                                .level(Level::DEBUG)
-                                .latency_unit(tower_http::LatencyUnit::Micros),
-                        ),
-                )
-                .layer(CompressionLayer::new())
-                .layer(
-                    cors::CorsLayer::new()
-                        .allow_methods(cors::AllowMethods::any())
-                        .allow_headers(cors::Any)
-                        .allow_origin(cors::Any),
-                );
+            let layered_app = user_router(
+                state,
+                self.max_request_size,
+                self.idle_shutdown_kicker.clone(),
+            );
 
             // Merge the grpc based axum router into our regular http router
             let replication = ReplicationLogServer::new(self.replication_service);
@@ -410,20 +467,148 @@ where
                 .add_service(tonic_web::enable(write_proxy))
                 .into_router();
 
-            let router = layered_app.merge(grpc_router);
+            // The replication and write proxy services identify replicas by their network address,
+            // so they are only served over TCP; the unix socket only serves the user API.
+            let uds_router = layered_app.clone().fallback(handle_fallback);
+            let router = layered_app.merge(grpc_router).fallback(handle_fallback);
+
+            for (acceptor, router) in [
+                (self.http_acceptor, router),
+                (self.hrana_uds_acceptor, uds_router),
+            ] {
+                let Some(acceptor) = acceptor else { continue };
+                let h2c = crate::h2c::H2cMaker::new(router);
+                join_set.spawn(async move {
+                    hyper::server::Server::builder(acceptor)
+                        .serve(h2c)
+                        .await
+                        .context("http server")?;
+                    Ok(())
+                });
+            }
+        }
+    }
+}
 
-            let router = router.fallback(handle_fallback);
-            let h2c = crate::h2c::H2cMaker::new(router);
+/// Builds the router of the HTTP API, without the gRPC services.
+fn user_router<M: MakeNamespace>(
+    state: AppState<M>,
+    max_request_size: u64,
+    idle_shutdown_kicker: Option<IdleShutdownKicker>,
+) -> Router {
+    fn trace_request<B>(req: &Request<B>, _span: &Span) {
+        tracing::debug!("got request: {} {}", req.method(), req.uri());
+    }
 
-            join_set.spawn(async move {
-                hyper::server::Server::builder(acceptor)
-                    .serve(h2c)
-                    .await
-                    .context("http server")?;
-                Ok(())
-            });
-        }
+    macro_rules! handle_hrana {
+        ($endpoint:expr, $version:expr, $encoding:expr,) => {{
+            async fn handle_hrana<F: MakeNamespace>(
+                AxumState(state): AxumState<AppState<F>>,
+                MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<
+                    <F::Database as Database>::Connection,
+                >,
+                auth: Authenticated,
+                req: Request<Body>,
+            ) -> Result<Response<Body>, Error> {
+                Ok(state
+                    .hrana_http_srv
+                    .handle_request(connection_maker, auth, req, $endpoint, $version, $encoding)
+                    .await?)
+            }
+            handle_hrana
+        }};
     }
+
+    let namespace_cors =
+        axum::middleware::from_fn_with_state(state.clone(), cors::handle_cors::<M>);
+    let namespace_response_headers = axum::middleware::from_fn_with_state(
+        state.clone(),
+        response_headers::handle_response_headers::<M>,
+    );
+
+    let app = Router::new()
+        .route("/", post(handle_query))
+        .route("/", get(handle_upgrade))
+        .route("/batch", post(batch::handle_batch))
+        .route("/version", get(handle_version))
+        .route("/console", get(show_console))
+        .route("/health", get(handle_health))
+        .route("/ready", get(handle_ready))
+        .route("/dump", get(dump::handle_dump))
+        .route("/namespaces/:namespace/export", get(dump::handle_export))
+        .route("/load", post(dump::handle_load))
+        .route("/export", post(export::handle_export_query))
+        .route("/v1/stats", get(stats::handle_stats))
+        .route("/v1/schema", get(handle_schema))
+        .route("/metrics", get(stats::handle_metrics))
+        .route("/v1", get(hrana_over_http_1::handle_index))
+        .route("/v1/execute", post(hrana_over_http_1::handle_execute))
+        .route("/v1/batch", post(hrana_over_http_1::handle_batch))
+        .route("/v1/cursors", post(cursor::handle_open_cursor))
+        .route(
+            "/v1/cursors/:cursor_id/fetch",
+            get(cursor::handle_fetch_cursor),
+        )
+        .route("/v2", get(crate::hrana::http::handle_index))
+        .route(
+            "/v2/pipeline",
+            post(handle_hrana!(
+                hrana::http::Endpoint::Pipeline,
+                hrana::Version::Hrana2,
+                hrana::Encoding::Json,
+            )),
+        )
+        .route("/v3", get(crate::hrana::http::handle_index))
+        .route(
+            "/v3/pipeline",
+            post(handle_hrana!(
+                hrana::http::Endpoint::Pipeline,
+                hrana::Version::Hrana3,
+                hrana::Encoding::Json,
+            )),
+        )
+        .route(
+            "/v3/cursor",
+            post(handle_hrana!(
+                hrana::http::Endpoint::Cursor,
+                hrana::Version::Hrana3,
+                hrana::Encoding::Json,
+            )),
+        )
+        .route("/v3-protobuf", get(crate::hrana::http::handle_index))
+        .route(
+            "/v3-protobuf/pipeline",
+            post(handle_hrana!(
+                hrana::http::Endpoint::Pipeline,
+                hrana::Version::Hrana3,
+                hrana::Encoding::Protobuf,
+            )),
+        )
+        .route(
+            "/v3-protobuf/cursor",
+            post(handle_hrana!(
+                hrana::http::Endpoint::Cursor,
+                hrana::Version::Hrana3,
+                hrana::Encoding::Protobuf,
+            )),
+        )
+        .with_state(state)
+        // the body of `/load` is streamed, so it's not limited
+        .layer(DefaultBodyLimit::max(max_request_size as usize));
+
+    app.layer(option_layer(idle_shutdown_kicker))
+        .layer(
+            tower_http::trace::TraceLayer::new_for_http()
+                .on_request(trace_request)
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::DEBUG)
+                        .latency_unit(tower_http::LatencyUnit::Micros),
+                ),
+        )
+        .layer(CompressionLayer::new())
+        .layer(namespace_cors)
+        .layer(namespace_response_headers)
 }
 
 /// Axum authenticated extractor
@@ -479,3 +664,127 @@ where
             .map(|t| Json(t.0))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use crate::namespace::{PrimaryNamespaceConfig, PrimaryNamespaceMaker};
+    use crate::stats::PrimaryConnectionState;
+
+    use super::*;
+
+    /// Returns the state of the HTTP API of a primary, with authentication disabled, storing its
+    /// namespaces in `path`. Namespaces are created lazily by the requests, as on replicas.
+    pub(super) fn test_state(path: &Path) -> AppState<PrimaryNamespaceMaker> {
+        let stats = Stats::new(path).unwrap();
+        let config = PrimaryNamespaceConfig::new_test(path, stats.clone());
+        let (upgrade_tx, _) = mpsc::channel(1);
+        AppState {
+            auth: Arc::new(Auth {
+                disabled: true,
+                ..Auth::default()
+            }),
+            namespaces: NamespaceStore::new(PrimaryNamespaceMaker::new(config), true),
+            upgrade_tx,
+            hrana_http_srv: Arc::new(hrana::http::Server::new(None, 1024 * 1024)),
+            enable_console: false,
+            stats,
+            disable_default_namespace: true,
+            disable_namespaces: false,
+            path: path.into(),
+            cursors: Arc::new(CursorStore::new(Duration::from_secs(60))),
+            ready_max_replication_lag: Duration::from_secs(10),
+        }
+    }
+
+    /// Returns the router of the HTTP API of a primary, its state, and the directory of its
+    /// namespaces.
+    pub(super) fn test_router() -> (Router, AppState<PrimaryNamespaceMaker>, TempDir) {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = test_state(tmp.path());
+        (user_router(state.clone(), 1024 * 1024, None), state, tmp)
+    }
+
+    async fn ready_status(state: AppState<PrimaryNamespaceMaker>) -> StatusCode {
+        let router = user_router(state, 1024 * 1024, None);
+        let req = Request::get("/ready").body(Body::empty()).unwrap();
+        tower::ServiceExt::oneshot(router, req)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn ready_checks_default_namespace() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut state = test_state(tmp.path());
+        assert_eq!(ready_status(state.clone()).await, StatusCode::OK);
+
+        state.disable_default_namespace = false;
+        assert_eq!(ready_status(state.clone()).await, StatusCode::OK);
+
+        // the default namespace can't be opened if its directory is a file
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("dbs")).unwrap();
+        std::fs::write(tmp.path().join("dbs").join(DEFAULT_NAMESPACE_NAME), b"").unwrap();
+        let mut state = test_state(tmp.path());
+        state.disable_default_namespace = false;
+        assert_eq!(ready_status(state).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn ready_checks_replication_lag() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut state = test_state(tmp.path());
+        state.ready_max_replication_lag = Duration::ZERO;
+
+        state
+            .stats
+            .set_primary_connection_state(PrimaryConnectionState::Connected);
+        assert_eq!(ready_status(state.clone()).await, StatusCode::OK);
+
+        state
+            .stats
+            .set_primary_connection_state(PrimaryConnectionState::Reconnecting);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(
+            ready_status(state.clone()).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        state
+            .stats
+            .set_primary_connection_state(PrimaryConnectionState::Connected);
+        assert_eq!(ready_status(state).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serve_over_unix_socket() {
+        let (router, _state, tmp) = test_router();
+        let path = tmp.path().join("sqld.sock");
+        let acceptor =
+            crate::net::AddrIncoming::new_unix(tokio::net::UnixListener::bind(&path).unwrap());
+        let router = router.fallback(handle_fallback);
+        tokio::spawn(
+            hyper::server::Server::builder(acceptor).serve(crate::h2c::H2cMaker::new(router)),
+        );
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::get("/health").body(Body::empty()).unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // connections over the socket have no network address, and the replication service is
+        // not served on it
+        let req = Request::post("/wal_log.ReplicationLog/Hello")
+            .header("content-type", "application/grpc")
+            .body(Body::empty())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use axum::extract::State as AxumState;
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Request};
+
+use crate::error::Error;
+use crate::namespace::MakeNamespace;
+
+use super::db_factory::NamespaceConfig;
+use super::AppState;
+
+/// Headers which the config of a namespace may add to its responses. Custom headers starting with
+/// `x-` are allowed too, except the ones of [DENIED_CUSTOM_HEADER_PREFIXES].
+const ALLOWED_RESPONSE_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-security-policy",
+    "cross-origin-embedder-policy",
+    "cross-origin-opener-policy",
+    "cross-origin-resource-policy",
+    "permissions-policy",
+    "referrer-policy",
+    "strict-transport-security",
+    "x-content-type-options",
+    "x-frame-options",
+];
+
+/// Prefixes of the custom headers which are set by the server, or interpreted by the proxies in
+/// front of it, such as `X-Accel-Redirect` which makes nginx serve an internal location.
+const DENIED_CUSTOM_HEADER_PREFIXES: &[&str] =
+    &["x-sqld-", "x-accel-", "x-sendfile", "x-forwarded-"];
+
+fn is_allowed(name: &HeaderName) -> bool {
+    let name = name.as_str();
+    ALLOWED_RESPONSE_HEADERS.contains(&name)
+        || (name.starts_with("x-")
+            && !DENIED_CUSTOM_HEADER_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix)))
+}
+
+/// Parses the `response_headers` of the config of a namespace, failing on the first header which
+/// is not allowed, or is not a valid header.
+pub fn parse_response_headers(headers: &HashMap<String, String>) -> Result<HeaderMap, Error> {
+    let mut parsed = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let invalid = || Error::InvalidResponseHeader(name.clone());
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        if !is_allowed(&name) {
+            return Err(invalid());
+        }
+        let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+        parsed.insert(name, value);
+    }
+    Ok(parsed)
+}
+
+/// Middleware adding the `response_headers` of the config of the namespace of the request to its
+/// response. The headers replace the headers of the same name set by the server. Nothing is added
+/// to the responses of a namespace which is not loaded.
+///
+/// This is the outermost namespace middleware: it looks up the [NamespaceConfig] of the request
+/// for the inner ones.
+pub(super) async fn handle_response_headers<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let config = NamespaceConfig::lookup(&state, req.headers()).await;
+    let headers = config
+        .0
+        .as_ref()
+        .map(|config| config.response_headers.clone())
+        .unwrap_or_default();
+    req.extensions_mut().insert(config);
+
+    let mut resp = next.run(req).await;
+    if !headers.is_empty() {
+        match parse_response_headers(&headers) {
+            Ok(headers) => resp.headers_mut().extend(headers),
+            // the config was validated when it was updated, unless it was edited by hand
+            Err(e) => tracing::warn!("not adding the response headers of the namespace: {e}"),
+        }
+    }
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use tower::ServiceExt;
+
+    use crate::connection::config::DatabaseConfig;
+    use crate::http::test::test_router;
+    use crate::namespace::RestoreOption;
+
+    use super::*;
+
+    fn headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn allowed_response_headers() {
+        let parsed = parse_response_headers(&headers(&[
+            ("X-Frame-Options", "DENY"),
+            ("Strict-Transport-Security", "max-age=63072000"),
+            ("X-Custom", "value"),
+        ]))
+        .unwrap();
+        assert_eq!(parsed["x-frame-options"], "DENY");
+        assert_eq!(parsed["strict-transport-security"], "max-age=63072000");
+        assert_eq!(parsed["x-custom"], "value");
+
+        for name in [
+            "Content-Length",
+            "Set-Cookie",
+            "X-Sqld-Schema-Version",
+            "X-Accel-Redirect",
+            "X-Sendfile",
+            "X-Forwarded-For",
+            "bad header",
+        ] {
+            assert!(matches!(
+                parse_response_headers(&headers(&[(name, "1")])),
+                Err(Error::InvalidResponseHeader(_))
+            ));
+        }
+        assert!(parse_response_headers(&headers(&[("X-Custom", "bad\nvalue")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn namespace_response_headers() {
+        let (router, state, tmp) = test_router();
+        state
+            .namespaces
+            .create("foo".into(), RestoreOption::Latest)
+            .await
+            .unwrap();
+        state
+            .namespaces
+            .with("foo".into(), |ns| {
+                ns.config_store.store(DatabaseConfig {
+                    response_headers: headers(&[("X-Frame-Options", "DENY")]),
+                    ..DatabaseConfig::default()
+                })
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let health = |host: &str| {
+            Request::get("/health")
+                .header("host", host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = router.clone().oneshot(health("foo.sqld")).await.unwrap();
+        assert_eq!(resp.headers()["x-frame-options"], "DENY");
+
+        // the middleware doesn't load, nor create, the namespaces of unauthenticated requests
+        let resp = router.oneshot(health("bar.sqld")).await.unwrap();
+        assert!(resp.headers().get("x-frame-options").is_none());
+        assert!(!tmp.path().join("dbs").join("bar").exists());
+    }
+}
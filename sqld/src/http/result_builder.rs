@@ -97,7 +97,7 @@ impl Drop for LimitBuffer {
     }
 }
 
-struct HttpJsonValueSerializer<'a>(&'a ValueRef<'a>);
+pub(super) struct HttpJsonValueSerializer<'a>(pub(super) &'a ValueRef<'a>);
 
 impl JsonHttpPayloadBuilder {
     pub fn new() -> Self {
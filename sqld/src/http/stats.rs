@@ -1,4 +1,6 @@
 use hyper::{Body, Response};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
 use serde::Serialize;
 
 use axum::extract::{FromRef, State as AxumState};
@@ -13,6 +15,10 @@ pub struct StatsResponse {
     pub rows_written_count: u64,
     pub storage_bytes_used: u64,
     pub write_requests_delegated: u64,
+    pub replica_reconnect_attempts: u64,
+    pub retried_writes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary_connection_state: Option<&'static str>,
 }
 
 impl From<&Stats> for StatsResponse {
@@ -22,6 +28,9 @@ impl From<&Stats> for StatsResponse {
             rows_written_count: stats.rows_written(),
             storage_bytes_used: stats.storage_bytes_used(),
             write_requests_delegated: stats.write_requests_delegated(),
+            replica_reconnect_attempts: stats.replica_reconnect_attempts(),
+            retried_writes: stats.retried_writes(),
+            primary_connection_state: stats.primary_connection_state().as_str(),
         }
     }
 }
@@ -47,3 +56,100 @@ pub(crate) async fn handle_stats(AxumState(stats): AxumState<Stats>) -> Response
         .body(Body::from(payload))
         .unwrap()
 }
+
+/// Buckets of the histograms of durations, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+static METRICS_HANDLE: OnceCell<PrometheusHandle> = OnceCell::new();
+
+/// Installs the recorder of the metrics recorded with the `metrics` crate, which are returned by
+/// the metrics endpoint along with the stats.
+pub fn install_metrics_recorder() {
+    METRICS_HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets(DURATION_BUCKETS)
+            .expect("buckets are not empty")
+            .install_recorder()
+            .expect("no other metrics recorder is installed")
+    });
+}
+
+/// Returns the stats in the Prometheus text exposition format.
+pub(crate) async fn handle_metrics(AxumState(stats): AxumState<Stats>) -> Response<Body> {
+    let metrics: [(&str, &str, &str, u64); 6] = [
+        (
+            "rows_read_total",
+            "counter",
+            "Number of rows read.",
+            stats.rows_read(),
+        ),
+        (
+            "rows_written_total",
+            "counter",
+            "Number of rows written.",
+            stats.rows_written(),
+        ),
+        (
+            "storage_bytes_used",
+            "gauge",
+            "Number of bytes used by the database.",
+            stats.storage_bytes_used(),
+        ),
+        (
+            "write_requests_delegated_total",
+            "counter",
+            "Number of write requests delegated from a replica to the primary.",
+            stats.write_requests_delegated(),
+        ),
+        (
+            "replica_reconnect_attempts_total",
+            "counter",
+            "Number of times the replica reconnected to the primary.",
+            stats.replica_reconnect_attempts(),
+        ),
+        (
+            "retried_writes_total",
+            "counter",
+            "Number of retries of write requests delegated to the primary.",
+            stats.retried_writes(),
+        ),
+    ];
+
+    let mut payload = String::new();
+    for (name, ty, help, value) in metrics {
+        payload.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {ty}\n{name} {value}\n"
+        ));
+    }
+
+    payload.push_str(
+        "# HELP statements_executed_total Number of statements executed, by namespace and class.\n\
+         # TYPE statements_executed_total counter\n",
+    );
+    for (namespace, counts) in stats.all_statement_counts() {
+        let namespace = escape_label_value(&namespace);
+        for (class, value) in counts.by_class() {
+            payload.push_str(&format!(
+                "statements_executed_total{{namespace=\"{namespace}\",class=\"{class}\"}} {value}\n"
+            ));
+        }
+    }
+
+    if let Some(handle) = METRICS_HANDLE.get() {
+        payload.push_str(&handle.render());
+    }
+
+    Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(payload))
+        .unwrap()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -16,6 +16,9 @@ pub struct HttpQuery {
 pub struct QueryObject {
     pub q: String,
     pub params: QueryParams,
+    /// Limit of the memory used by the query, see [`query::Query::max_memory_bytes`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory_bytes: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
@@ -187,6 +190,7 @@ impl<'de> Deserialize<'de> for QueryObject {
                 Ok(QueryObject {
                     q: q.to_string(),
                     params: QueryParams(query::Params::empty()),
+                    max_memory_bytes: None,
                 })
             }
 
@@ -196,6 +200,7 @@ impl<'de> Deserialize<'de> for QueryObject {
             {
                 let mut q = None;
                 let mut params = None;
+                let mut max_memory_bytes = None;
                 while let Some(k) = map.next_key::<&str>()? {
                     match k {
                         "q" => {
@@ -212,13 +217,26 @@ impl<'de> Deserialize<'de> for QueryObject {
                                 return Err(A::Error::duplicate_field("params"));
                             }
                         }
-                        _ => return Err(A::Error::unknown_field(k, &["q", "params"])),
+                        "max_memory_bytes" => {
+                            if max_memory_bytes.is_none() {
+                                max_memory_bytes.replace(map.next_value::<u64>()?);
+                            } else {
+                                return Err(A::Error::duplicate_field("max_memory_bytes"));
+                            }
+                        }
+                        _ => {
+                            return Err(A::Error::unknown_field(
+                                k,
+                                &["q", "params", "max_memory_bytes"],
+                            ))
+                        }
                     }
                 }
 
                 Ok(QueryObject {
                     q: q.ok_or_else(|| A::Error::missing_field("q"))?,
                     params: params.unwrap_or_else(|| QueryParams(query::Params::empty())),
+                    max_memory_bytes,
                 })
             }
         }
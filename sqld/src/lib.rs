@@ -9,7 +9,8 @@ use std::time::Duration;
 use anyhow::Context as AnyhowContext;
 use bytes::Bytes;
 use config::{
-    AdminApiConfig, DbConfig, HeartbeatConfig, RpcClientConfig, RpcServerConfig, UserApiConfig,
+    AdminApiConfig, DbConfig, HeartbeatConfig, RpcClientConfig, RpcServerConfig, TrustedExtension,
+    UserApiConfig,
 };
 use futures::never::Never;
 use http::UserApi;
@@ -28,12 +29,13 @@ use rpc::replication_log::rpc::replication_log_server::ReplicationLog;
 use rpc::replication_log::ReplicationLogService;
 use rpc::replication_log_proxy::ReplicationLogProxyService;
 use rpc::run_rpc_server;
+use rpc::tls::{ClientTls, ServerTls, TlsReload};
 use tokio::sync::Notify;
 use tokio::task::JoinSet;
 use utils::services::idle_shutdown::IdleShutdownKicker;
 
-use crate::auth::Auth;
-use crate::connection::config::DatabaseConfigStore;
+use crate::auth::{Auth, RevokedJwts};
+use crate::connection::config::{DatabaseConfigStore, DefaultLimits};
 use crate::connection::libsql::open_db;
 use crate::connection::{Connection, MakeConnection};
 use crate::error::Error;
@@ -45,9 +47,12 @@ pub use sqld_libsql_bindings as libsql;
 
 pub mod config;
 pub mod connection;
+pub mod heap_limit;
 pub mod net;
 pub mod rpc;
 pub mod version;
+pub mod wal_compression;
+pub mod wal_encryption;
 
 mod admin_api;
 mod auth;
@@ -72,6 +77,9 @@ const MAX_CONCURRENT_DBS: usize = 128;
 const DB_CREATE_TIMEOUT: Duration = Duration::from_secs(1);
 const DEFAULT_NAMESPACE_NAME: &str = "default";
 const DEFAULT_AUTO_CHECKPOINT: u32 = 1000;
+/// Maximum number of parameters bound to a statement, SQLite's compiled limit
+/// (`SQLITE_MAX_VARIABLE_NUMBER`).
+pub const DEFAULT_MAX_QUERY_PARAMS: usize = 32766;
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -103,7 +111,9 @@ struct Services<M: MakeNamespace, A, P, S> {
     disable_default_namespace: bool,
     db_config: DbConfig,
     auth: Arc<Auth>,
+    revoked_jwts: Arc<RevokedJwts>,
     path: Arc<Path>,
+    tls_reload: Option<Arc<dyn TlsReload>>,
 }
 
 impl<M, A, P, S> Services<M, A, P, S>
@@ -117,7 +127,8 @@ where
         let user_http = UserApi {
             http_acceptor: self.user_api_config.http_acceptor,
             hrana_ws_acceptor: self.user_api_config.hrana_ws_acceptor,
-            auth: self.auth,
+            hrana_uds_acceptor: self.user_api_config.hrana_uds_acceptor,
+            auth: self.auth.clone(),
             namespaces: self.namespaces.clone(),
             idle_shutdown_kicker: self.idle_shutdown_kicker.clone(),
             stats: self.stats.clone(),
@@ -128,6 +139,16 @@ where
             max_response_size: self.db_config.max_response_size,
             enable_console: self.user_api_config.enable_http_console,
             self_url: self.user_api_config.self_url,
+            hrana_stream_idle_timeout: self.user_api_config.hrana_stream_idle_timeout,
+            hrana_max_streams_per_session: self.user_api_config.hrana_max_streams_per_session,
+            hrana_fetch_cursor_size_divisor: self.user_api_config.hrana_fetch_cursor_size_divisor,
+            hrana_max_sql_count: self.user_api_config.hrana_max_sql_count,
+            hrana_max_sql_bytes: self.user_api_config.hrana_max_sql_bytes,
+            hrana_ws_ping_interval: self.user_api_config.hrana_ws_ping_interval,
+            hrana_ws_max_missed_pongs: self.user_api_config.hrana_ws_max_missed_pongs,
+            max_request_size: self.user_api_config.max_request_size,
+            cursor_idle_timeout: self.user_api_config.cursor_idle_timeout,
+            ready_max_replication_lag: self.user_api_config.ready_max_replication_lag,
             path: self.path.clone(),
         };
 
@@ -136,8 +157,13 @@ where
         if let Some(AdminApiConfig { acceptor }) = self.admin_api_config {
             join_set.spawn(admin_api::run_admin_api(
                 acceptor,
+                self.auth,
                 self.db_config_store,
                 self.namespaces,
+                self.tls_reload,
+                self.db_config.bottomless_replication,
+                self.revoked_jwts,
+                self.stats,
             ));
         }
     }
@@ -282,10 +308,21 @@ where
 {
     /// Setup sqlite global environment
     fn init_sqlite_globals(&self) {
+        // the allocator can only be replaced before SQLite is initialized
+        heap_limit::register();
+
         if self.db_config.bottomless_replication.is_some() {
             bottomless::static_init::register_bottomless_methods();
         }
 
+        if self.db_config.wal_master_key.is_some() {
+            wal_encryption::register();
+        }
+
+        if self.db_config.wal_compression.is_some() {
+            wal_compression::register();
+        }
+
         if let Some(soft_limit_mb) = self.db_config.soft_heap_limit_mb {
             tracing::warn!("Setting soft heap limit to {soft_limit_mb}MiB");
             unsafe {
@@ -350,6 +387,17 @@ where
                 tracing::warn!("No server heartbeat configured")
             }
         }
+
+        if let (Some(interval), Some(soft_limit_mb)) = (
+            self.db_config.connection_idle_check_interval,
+            self.db_config.soft_heap_limit_mb,
+        ) {
+            let threshold = soft_limit_mb as f64
+                * 1024.0
+                * 1024.0
+                * self.db_config.connection_idle_memory_fraction;
+            join_set.spawn(connection::reaper::run(interval, threshold as u64));
+        }
     }
 
     pub async fn start(self) -> anyhow::Result<()> {
@@ -358,22 +406,43 @@ where
         init_version_file(&self.path)?;
         maybe_migrate(&self.path)?;
         let stats = Stats::new(&self.path)?;
+        http::stats::install_metrics_recorder();
         self.spawn_monitoring_tasks(&mut join_set, stats.clone());
         self.init_sqlite_globals();
+        connection::warm::set_max_pooled_connections(self.db_config.max_pooled_connections);
         let db_is_dirty = init_sentinel_file(&self.path)?;
         let idle_shutdown_kicker = self.setup_shutdown();
 
+        let default_limits = DefaultLimits {
+            max_db_size: self.db_config.max_db_size,
+            max_rows: self.db_config.max_rows,
+        };
         let db_config_store = Arc::new(
-            DatabaseConfigStore::load(&self.path).context("Could not load database config")?,
+            DatabaseConfigStore::load(&self.path)
+                .context("Could not load database config")?
+                .with_defaults(default_limits),
         );
         let snapshot_callback = self.make_snapshot_callback();
-        let auth = self.user_api_config.get_auth()?.into();
+        let mut auth = self.user_api_config.get_auth()?;
+        let revoked_jwts = Arc::new(
+            RevokedJwts::load(self.path.join("revocations.json"))
+                .context("Could not load revoked JWTs")?,
+        );
+        auth.revoked_jwts = revoked_jwts.clone();
+        let auth = Arc::new(auth);
         let extensions = self.db_config.validate_extensions()?;
 
         match self.rpc_client_config {
             Some(rpc_config) => {
+                let client_tls = rpc_config
+                    .tls_config
+                    .clone()
+                    .map(ClientTls::load)
+                    .transpose()
+                    .context("Could not load the gRPC client TLS config")?;
                 let replica = Replica {
                     rpc_config,
+                    tls: client_tls.clone(),
                     stats: stats.clone(),
                     db_config_store: db_config_store.clone(),
                     extensions,
@@ -394,14 +463,24 @@ where
                     disable_default_namespace: self.disable_default_namespace,
                     db_config: self.db_config,
                     auth,
+                    revoked_jwts: revoked_jwts.clone(),
                     path: self.path.clone(),
+                    tls_reload: client_tls.map(|tls| tls as Arc<dyn TlsReload>),
                 };
 
                 services.configure(&mut join_set);
             }
             None => {
+                let server_tls = self
+                    .rpc_server_config
+                    .as_ref()
+                    .and_then(|config| config.tls_config.clone())
+                    .map(ServerTls::load)
+                    .transpose()
+                    .context("Could not load the gRPC server TLS config")?;
                 let primary = Primary {
                     rpc_config: self.rpc_server_config,
+                    tls: server_tls.clone(),
                     db_config: self.db_config.clone(),
                     idle_shutdown_kicker: idle_shutdown_kicker.clone(),
                     stats: stats.clone(),
@@ -429,7 +508,9 @@ where
                     disable_default_namespace: self.disable_default_namespace,
                     db_config: self.db_config,
                     auth,
+                    revoked_jwts: revoked_jwts.clone(),
                     path: self.path.clone(),
+                    tls_reload: server_tls.map(|tls| tls as Arc<dyn TlsReload>),
                 };
 
                 services.configure(&mut join_set);
@@ -461,13 +542,14 @@ where
 
 struct Primary<'a, A> {
     rpc_config: Option<RpcServerConfig<A>>,
+    tls: Option<Arc<ServerTls>>,
     db_config: DbConfig,
     idle_shutdown_kicker: Option<IdleShutdownKicker>,
     stats: Stats,
     db_config_store: Arc<DatabaseConfigStore>,
     db_is_dirty: bool,
     snapshot_callback: NamespacedSnapshotCallback,
-    extensions: Arc<[PathBuf]>,
+    extensions: Arc<[TrustedExtension]>,
     base_path: Arc<Path>,
     disable_namespaces: bool,
     auth: Arc<Auth>,
@@ -497,8 +579,14 @@ where
             config_store: self.db_config_store,
             max_response_size: self.db_config.max_response_size,
             max_total_response_size: self.db_config.max_total_response_size,
+            statement_cache_size: self.db_config.statement_cache_size,
+            max_query_params: self.db_config.max_query_params,
+            coalesce_reads: self.db_config.coalesce_reads,
+            connection_pool_size: self.db_config.connection_pool_size,
             checkpoint_interval: self.db_config.checkpoint_interval,
             disable_namespace: self.disable_namespaces,
+            wal_master_key: self.db_config.wal_master_key,
+            wal_compression: self.db_config.wal_compression,
         };
         let factory = PrimaryNamespaceMaker::new(conf);
         let namespaces = NamespaceStore::new(factory, false);
@@ -516,10 +604,11 @@ where
         if let Some(config) = self.rpc_config.take() {
             self.join_set.spawn(run_rpc_server(
                 config.acceptor,
-                config.tls_config,
+                self.tls.clone(),
                 self.idle_shutdown_kicker.clone(),
                 namespaces.clone(),
                 self.disable_namespaces,
+                config.max_message_size,
             ));
         }
 
@@ -539,9 +628,10 @@ where
 
 struct Replica<C> {
     rpc_config: RpcClientConfig<C>,
+    tls: Option<Arc<ClientTls>>,
     stats: Stats,
     db_config_store: Arc<DatabaseConfigStore>,
-    extensions: Arc<[PathBuf]>,
+    extensions: Arc<[TrustedExtension]>,
     db_config: DbConfig,
     base_path: Arc<Path>,
 }
@@ -554,7 +644,9 @@ impl<C: Connector> Replica<C> {
         impl Proxy,
         impl ReplicationLog,
     )> {
-        let (channel, uri) = self.rpc_config.configure().await?;
+        let write_proxy_retry = self.rpc_config.write_proxy_retry;
+        let max_message_size = self.rpc_config.max_message_size;
+        let (channel, uri) = self.rpc_config.configure(self.tls).await?;
 
         let conf = ReplicaNamespaceConfig {
             channel: channel.clone(),
@@ -565,11 +657,19 @@ impl<C: Connector> Replica<C> {
             base_path: self.base_path,
             max_response_size: self.db_config.max_response_size,
             max_total_response_size: self.db_config.max_total_response_size,
+            statement_cache_size: self.db_config.statement_cache_size,
+            max_query_params: self.db_config.max_query_params,
+            write_proxy_retry,
+            max_message_size,
+            wal_master_key: self.db_config.wal_master_key,
+            wal_compression: self.db_config.wal_compression,
+            connection_pool_size: self.db_config.connection_pool_size,
         };
         let factory = ReplicaNamespaceMaker::new(conf);
         let namespaces = NamespaceStore::new(factory, true);
-        let replication_service = ReplicationLogProxyService::new(channel.clone(), uri.clone());
-        let proxy_service = ReplicaProxyService::new(channel, uri);
+        let replication_service =
+            ReplicationLogProxyService::new(channel.clone(), uri.clone(), max_message_size);
+        let proxy_service = ReplicaProxyService::new(channel, uri, max_message_size);
 
         Ok((namespaces, proxy_service, replication_service))
     }
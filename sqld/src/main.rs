@@ -17,12 +17,15 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::Layer;
 
 use sqld::config::{
-    AdminApiConfig, DbConfig, HeartbeatConfig, RpcClientConfig, RpcServerConfig, TlsConfig,
-    UserApiConfig,
+    AdminApiConfig, DbConfig, HeartbeatAuth, HeartbeatConfig, RpcClientConfig, RpcServerConfig,
+    TlsConfig, TlsMaterial, UserApiConfig, WriteProxyRetryConfig,
 };
+use sqld::connection::dump::exporter::{export_dump, DumpOptions};
 use sqld::net::AddrIncoming;
+use sqld::version::Version;
+use sqld::wal_compression::Codec;
+use sqld::wal_encryption::MasterKey;
 use sqld::Server;
-use sqld::{connection::dump::exporter::export_dump, version::Version};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -40,17 +43,77 @@ struct Cli {
     /// If present, the directory is expected to have a trusted.lst file containing the sha256 and name of each extension, one per line. Example:
     ///
     /// 99890762817735984843bf5cf02a4b2ea648018fd05f04df6f9ce7f976841510  math.dylib
+    ///
+    /// Extensions can also be trusted with a file per extension in a trusted.d directory, named after the extension and containing its sha256.
+    ///
+    /// Digests can use another hash algorithm with a `sha512:` or `blake3:` prefix.
     #[clap(long, short)]
     extensions_path: Option<PathBuf>,
 
     #[clap(long, default_value = "127.0.0.1:8080", env = "SQLD_HTTP_LISTEN_ADDR")]
     http_listen_addr: SocketAddr,
+    /// Serve a web console at `/console`. It requires the same credentials as the HTTP API.
     #[clap(long)]
     enable_http_console: bool,
 
     /// Address and port for the legacy, Web-Socket-only Hrana server.
     #[clap(long, short = 'l', env = "SQLD_HRANA_LISTEN_ADDR")]
     hrana_listen_addr: Option<SocketAddr>,
+    /// Path of a Unix domain socket on which the user HTTP API (including Hrana over HTTP and
+    /// WebSockets) is served, in addition to `--http-listen-addr`. This avoids the overhead of TCP
+    /// when the client runs on the same host.
+    #[clap(long, env = "SQLD_HRANA_UDS_PATH")]
+    hrana_uds_path: Option<PathBuf>,
+    /// The duration, in seconds, after which a Hrana WebSocket stream that received no request is
+    /// closed, rolling back its transaction and releasing its database connection. 0 disables the
+    /// timeout.
+    #[clap(long, env = "SQLD_HRANA_STREAM_IDLE_TIMEOUT_S", default_value = "300")]
+    hrana_stream_idle_timeout_s: u64,
+    /// The maximum number of streams that a single Hrana WebSocket connection can have open at
+    /// the same time. Each stream holds a database connection.
+    #[clap(
+        long,
+        env = "SQLD_HRANA_MAX_STREAMS_PER_SESSION",
+        default_value = "100"
+    )]
+    hrana_max_streams_per_session: usize,
+    /// The fraction of the maximum response size, as a divisor, that the entries returned by a
+    /// single Hrana `fetch_cursor` request may take up. Must be at least 1.
+    #[clap(
+        long,
+        env = "SQLD_HRANA_FETCH_CURSOR_SIZE_DIVISOR",
+        default_value = "8"
+    )]
+    hrana_fetch_cursor_size_divisor: u64,
+    /// The maximum number of SQL texts that a single Hrana WebSocket connection can store with
+    /// `store_sql` requests. Must be at least 1.
+    #[clap(
+        long,
+        alias = "hrana-max-stored-sql",
+        env = "SQLD_HRANA_MAX_SQL_COUNT",
+        default_value = "150"
+    )]
+    hrana_max_sql_count: usize,
+    /// The maximum total size of the SQL texts that a single Hrana WebSocket connection can store
+    /// with `store_sql` requests. e.g 512KB, 10MB...
+    #[clap(long, env = "SQLD_HRANA_MAX_SQL_BYTES", default_value = "32MB")]
+    hrana_max_sql_bytes: ByteSize,
+    /// The interval, in seconds, at which the server pings Hrana WebSocket connections, to detect
+    /// the connections whose client is gone without closing them. 0 disables the pings.
+    #[clap(long, env = "SQLD_HRANA_WS_PING_INTERVAL_S", default_value = "30")]
+    hrana_ws_ping_interval_s: u64,
+    /// The number of consecutive pings that a Hrana WebSocket client may leave unanswered before
+    /// its connection is closed, releasing its database connections.
+    #[clap(long, env = "SQLD_HRANA_WS_MAX_MISSED_PONGS", default_value = "3")]
+    hrana_ws_max_missed_pongs: u32,
+    /// The duration, in seconds, after which a cursor of the HTTP API that is not fetched from is
+    /// closed.
+    #[clap(long, env = "SQLD_CURSOR_IDLE_TIMEOUT_S", default_value = "60")]
+    cursor_idle_timeout_s: u64,
+    /// The time, in seconds, that a replica may stay disconnected from its primary before the
+    /// `/ready` route reports it as not ready.
+    #[clap(long, env = "SQLD_READY_MAX_REPLICATION_LAG_S", default_value = "30")]
+    ready_max_replication_lag_s: u64,
 
     /// The address and port for the admin HTTP API.
     #[clap(long, env = "SQLD_ADMIN_LISTEN_ADDR")]
@@ -67,8 +130,14 @@ struct Cli {
     /// where $PARAM is base64-encoded string "$USERNAME:$PASSWORD".
     #[clap(long, env = "SQLD_HTTP_AUTH")]
     http_auth: Option<String>,
+    /// Records every authentication attempt in an audit log, as JSON lines. The argument is
+    /// either the path of a file to which the records are appended, or `tracing` to emit them as
+    /// events of the `sqld::auth_audit` tracing target.
+    #[clap(long, env = "SQLD_AUTH_AUDIT_LOG")]
+    auth_audit_log: Option<String>,
     /// URL that points to the HTTP API of this server. If set, this is used to implement "sticky
-    /// sessions" in Hrana over HTTP.
+    /// sessions" in Hrana over HTTP. If not set, clients send the follow-up requests of a stream to
+    /// the URL they already used, which works as long as it reaches this server.
     #[clap(long, env = "SQLD_HTTP_SELF_URL")]
     http_self_url: Option<String>,
 
@@ -79,12 +148,9 @@ struct Cli {
         env = "SQLD_GRPC_LISTEN_ADDR"
     )]
     grpc_listen_addr: Option<SocketAddr>,
-    #[clap(
-        long,
-        requires = "grpc_cert_file",
-        requires = "grpc_key_file",
-        requires = "grpc_ca_cert_file"
-    )]
+    /// Enables TLS for the gRPC server. The certificate, key and CA certificate are each given
+    /// either as a file or inline in PEM format.
+    #[clap(long)]
     grpc_tls: bool,
     #[clap(long)]
     grpc_cert_file: Option<PathBuf>,
@@ -92,16 +158,41 @@ struct Cli {
     grpc_key_file: Option<PathBuf>,
     #[clap(long)]
     grpc_ca_cert_file: Option<PathBuf>,
+    /// The gRPC server certificate, in PEM format.
+    #[clap(
+        long,
+        env = "SQLD_GRPC_CERT",
+        hide_env_values = true,
+        conflicts_with = "grpc_cert_file"
+    )]
+    grpc_cert: Option<String>,
+    /// The gRPC server private key, in PEM format.
+    #[clap(
+        long,
+        env = "SQLD_GRPC_KEY",
+        hide_env_values = true,
+        conflicts_with = "grpc_key_file"
+    )]
+    grpc_key: Option<String>,
+    /// The CA certificate of the gRPC clients, in PEM format.
+    #[clap(
+        long,
+        env = "SQLD_GRPC_CA_CERT",
+        hide_env_values = true,
+        conflicts_with = "grpc_ca_cert_file"
+    )]
+    grpc_ca_cert: Option<String>,
+    /// The domain name replicas use to reach this node over TLS. If set, the server certificate
+    /// is checked to be valid for it at startup.
+    #[clap(long, requires = "grpc_tls", env = "SQLD_GRPC_TLS_DOMAIN")]
+    grpc_tls_domain: Option<String>,
 
     /// The gRPC URL of the primary node to connect to for writes. Example: `http://localhost:5001`.
     #[clap(long, env = "SQLD_PRIMARY_GRPC_URL")]
     primary_grpc_url: Option<String>,
-    #[clap(
-        long,
-        requires = "primary_grpc_cert_file",
-        requires = "primary_grpc_key_file",
-        requires = "primary_grpc_ca_cert_file"
-    )]
+    /// Enables TLS for the connection to the primary. The certificate, key and CA certificate are
+    /// each given either as a file or inline in PEM format.
+    #[clap(long)]
     primary_grpc_tls: bool,
     #[clap(long)]
     primary_grpc_cert_file: Option<PathBuf>,
@@ -109,6 +200,93 @@ struct Cli {
     primary_grpc_key_file: Option<PathBuf>,
     #[clap(long)]
     primary_grpc_ca_cert_file: Option<PathBuf>,
+    /// The client certificate used to connect to the primary, in PEM format.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_CERT",
+        hide_env_values = true,
+        conflicts_with = "primary_grpc_cert_file"
+    )]
+    primary_grpc_cert: Option<String>,
+    /// The client private key used to connect to the primary, in PEM format.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_KEY",
+        hide_env_values = true,
+        conflicts_with = "primary_grpc_key_file"
+    )]
+    primary_grpc_key: Option<String>,
+    /// The CA certificate of the primary, in PEM format.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_CA_CERT",
+        hide_env_values = true,
+        conflicts_with = "primary_grpc_ca_cert_file"
+    )]
+    primary_grpc_ca_cert: Option<String>,
+    /// The domain name used for SNI and to verify the primary's TLS certificate.
+    #[clap(
+        long,
+        alias = "primary-grpc-domain",
+        env = "SQLD_PRIMARY_GRPC_TLS_DOMAIN",
+        default_value = sqld::config::DEFAULT_TLS_DOMAIN
+    )]
+    primary_grpc_tls_domain: String,
+    /// Timeout, in seconds, for establishing a connection to the primary.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_CONNECT_TIMEOUT_S",
+        default_value = "10"
+    )]
+    primary_grpc_connect_timeout_s: u64,
+    /// Interval, in seconds, between keepalive pings sent to the primary, so that dead
+    /// connections are detected even when idle. Set to 0 to disable keepalive.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_KEEPALIVE_INTERVAL_S",
+        default_value = "30"
+    )]
+    primary_grpc_keepalive_interval_s: u64,
+    /// Time, in seconds, to wait for a keepalive ping to be acknowledged by the primary before
+    /// closing the connection.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_KEEPALIVE_TIMEOUT_S",
+        default_value = "10"
+    )]
+    primary_grpc_keepalive_timeout_s: u64,
+    /// Timeout, in seconds, for the primary to respond to a request, such as a forwarded write.
+    /// It doesn't limit the duration of the replication stream once it's open. Set to 0 to
+    /// disable the timeout.
+    #[clap(
+        long,
+        env = "SQLD_PRIMARY_GRPC_REQUEST_TIMEOUT_S",
+        default_value = "60"
+    )]
+    primary_grpc_request_timeout_s: u64,
+    /// Maximum number of times a write forwarded to the primary is retried when the connection to
+    /// the primary can't be established. Writes that reached the primary are never retried. Set to
+    /// 0 to disable retries.
+    #[clap(long, env = "SQLD_WRITE_PROXY_MAX_RETRIES", default_value = "3")]
+    write_proxy_max_retries: u32,
+    /// Time, in milliseconds, to wait before retrying a write forwarded to the primary. The wait is
+    /// doubled after every retry.
+    #[clap(
+        long,
+        env = "SQLD_WRITE_PROXY_INITIAL_BACKOFF_MS",
+        default_value = "100"
+    )]
+    write_proxy_initial_backoff_ms: u64,
+    /// Maximum size, in MiB, of the messages exchanged between the primary and the replicas over
+    /// gRPC, up to 128. Raise it on both sides when large replication batches or forwarded results
+    /// fail with a message length error.
+    #[clap(
+        long,
+        env = "SQLD_GRPC_MAX_MESSAGE_SIZE_MB",
+        default_value = "4",
+        value_parser = clap::value_parser!(u64).range(1..=128)
+    )]
+    grpc_max_message_size_mb: u64,
 
     /// Don't display welcome message
     #[clap(long)]
@@ -148,15 +326,27 @@ struct Cli {
     /// The HTTP "Authornization" header to include in the a server heartbeat
     /// `POST` request.
     /// By default, the server doesn't send a heartbeat.
-    #[clap(long, env = "SQLD_HEARTBEAT_AUTH")]
+    #[clap(
+        long,
+        env = "SQLD_HEARTBEAT_AUTH",
+        conflicts_with = "heartbeat_auth_file"
+    )]
     heartbeat_auth: Option<String>,
 
+    /// Path to a file containing the HTTP "Authorization" header to include in server heartbeat
+    /// `POST` requests. The file is read again before each heartbeat, so the token can be
+    /// rotated without restarting the server.
+    #[clap(long, env = "SQLD_HEARTBEAT_AUTH_FILE")]
+    heartbeat_auth_file: Option<PathBuf>,
+
     /// The heartbeat time period in seconds.
     /// By default, the the period is 30 seconds.
     #[clap(long, env = "SQLD_HEARTBEAT_PERIOD_S", default_value = "30")]
     heartbeat_period_s: u64,
 
     /// Soft heap size limit in mebibytes - libSQL will try to not go over this limit with memory usage.
+    /// This limit applies to the whole process, namespaces can have their own heap limits in their
+    /// config.
     #[clap(long, env = "SQLD_SOFT_HEAP_LIMIT_MB")]
     soft_heap_limit_mb: Option<usize>,
 
@@ -173,6 +363,70 @@ struct Cli {
     #[clap(long, env = "SQLD_MAX_TOTAL_RESPONSE_SIZE", default_value = "32MB")]
     max_total_response_size: ByteSize,
 
+    /// Set the maximum size for the body of an HTTP request and for a Hrana WebSocket message.
+    /// e.g 5KB, 10MB... Loading a dump with `/load` is not limited.
+    #[clap(long, env = "SQLD_MAX_REQUEST_SIZE", default_value = "64MB")]
+    max_request_size: ByteSize,
+
+    /// Number of prepared statements cached by each connection, evicting the least recently used
+    /// ones. 0 disables the cache.
+    #[clap(long, env = "SQLD_STATEMENT_CACHE_SIZE", default_value = "128")]
+    statement_cache_size: usize,
+
+    /// Maximum number of parameters bound to a single statement. Queries with more parameters
+    /// are rejected before reaching SQLite. Defaults to SQLite's compiled limit.
+    #[clap(long, env = "SQLD_MAX_QUERY_PARAMS", default_value_t = sqld::DEFAULT_MAX_QUERY_PARAMS)]
+    max_query_params: usize,
+
+    /// Default size quota of each database, e.g. `10GB`. Writes to a database exceeding its quota
+    /// fail, while reads continue. The quota of a namespace can be changed through the admin API.
+    /// Unlimited by default.
+    #[clap(long, env = "SQLD_MAX_DB_SIZE")]
+    max_db_size: Option<ByteSize>,
+
+    /// Default maximum number of rows returned by a statement. Statements returning more rows
+    /// fail, rather than returning a truncated result. The limit of a namespace can be changed
+    /// through the admin API, and Hrana clients may ask for a lower one. Cursors are not limited.
+    /// Unlimited by default.
+    #[clap(long, env = "SQLD_MAX_ROWS")]
+    max_rows: Option<u64>,
+
+    /// Coalesce the identical read queries executed concurrently on a primary: a query waits for
+    /// the results of the identical query in flight, if any, instead of executing it again.
+    #[clap(long, env = "SQLD_COALESCE_READS")]
+    coalesce_reads: bool,
+
+    /// Number of connections to each namespace opened ahead of time, so that requests don't wait
+    /// for a connection to be opened. The pool is refilled in the background whenever a pooled
+    /// connection is handed out. Disabled by default.
+    #[clap(long, env = "SQLD_CONNECTION_POOL_SIZE", default_value = "0")]
+    connection_pool_size: usize,
+
+    /// Maximum number of connections opened ahead of time for all the namespaces together, see
+    /// `--connection-pool-size`. Each open connection holds a thread.
+    #[clap(long, env = "SQLD_MAX_POOLED_CONNECTIONS", default_value = "128")]
+    max_pooled_connections: usize,
+
+    /// Interval in seconds between the checks of the memory used by SQLite. When it's over
+    /// `--connection-idle-memory-fraction` of `--soft-heap-limit-mb`, the least recently used idle
+    /// connections are closed. The connections in use are never closed. Only applies with
+    /// `--soft-heap-limit-mb`, and 0 disables the checks.
+    #[clap(
+        long,
+        env = "SQLD_CONNECTION_IDLE_CHECK_INTERVAL_S",
+        default_value = "30"
+    )]
+    connection_idle_check_interval_s: u64,
+
+    /// Fraction of `--soft-heap-limit-mb` over which idle connections are closed, see
+    /// `--connection-idle-check-interval-s`.
+    #[clap(
+        long,
+        env = "SQLD_CONNECTION_IDLE_MEMORY_FRACTION",
+        default_value = "0.8"
+    )]
+    connection_idle_memory_fraction: f64,
+
     /// Set a command to execute when a snapshot file is generated.
     #[clap(long, env = "SQLD_SNAPSHOT_EXEC")]
     snapshot_exec: Option<String>,
@@ -182,6 +436,17 @@ struct Cli {
     #[clap(long, env = "SQLD_CHECKPOINT_INTERVAL_S")]
     checkpoint_interval_s: Option<u64>,
 
+    /// Path to a file with a 256-bit master key, as raw bytes or as 64 hex digits, enabling the
+    /// encryption at rest of the WAL of every namespace. The key of a namespace is derived from
+    /// the master key and the name of the namespace. It can't be combined with bottomless
+    /// replication.
+    #[clap(long, env = "SQLD_WAL_MASTER_KEY_FILE")]
+    wal_master_key_file: Option<PathBuf>,
+
+    /// Compression of the pages written to the WAL of every namespace.
+    #[clap(long, value_enum, default_value = "none", env = "SQLD_WAL_COMPRESSION")]
+    wal_compression: WalCompression,
+
     /// By default, all request for which a namespace can't be determined fallaback to the default
     /// namespace `default`. This flag disables that.
     #[clap(long)]
@@ -193,6 +458,13 @@ struct Cli {
     enable_namespaces: bool,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum WalCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum UtilsSubcommands {
     Dump {
@@ -261,7 +533,7 @@ fn perform_dump(dump_path: Option<&Path>, db_path: &Path) -> anyhow::Result<()>
     };
     let conn = rusqlite::Connection::open(db_path.join("data"))?;
 
-    export_dump(conn, out)?;
+    export_dump(conn, out, &DumpOptions::default())?;
 
     Ok(())
 }
@@ -282,6 +554,35 @@ fn enable_libsql_logging() {
 }
 
 fn make_db_config(config: &Cli) -> anyhow::Result<DbConfig> {
+    let wal_master_key = config
+        .wal_master_key_file
+        .as_deref()
+        .map(MasterKey::from_file)
+        .transpose()?;
+    // bottomless backs up the frames as they are written to the WAL, without the sidecar holding
+    // their nonces and tags, so it would upload frames it can't restore. Encrypting the backups is
+    // left to the storage, e.g. with S3 server-side encryption.
+    if wal_master_key.is_some() && config.enable_bottomless_replication {
+        bail!("--wal-master-key-file can't be used with bottomless replication: encrypt the bottomless bucket instead");
+    }
+    let wal_compression = match config.wal_compression {
+        WalCompression::None => None,
+        WalCompression::Lz4 => Some(Codec::Lz4),
+        WalCompression::Zstd => Some(Codec::Zstd),
+    };
+    // bottomless reads the frames straight from the WAL file
+    if wal_compression.is_some() && config.enable_bottomless_replication {
+        bail!("--wal-compression can't be used with bottomless replication");
+    }
+    // encrypted pages don't compress
+    if wal_compression.is_some() && wal_master_key.is_some() {
+        bail!("--wal-compression can't be used with --wal-master-key-file");
+    }
+    let fraction = config.connection_idle_memory_fraction;
+    if fraction.is_nan() || fraction <= 0.0 {
+        bail!("--connection-idle-memory-fraction must be positive");
+    }
+
     Ok(DbConfig {
         extensions_path: config.extensions_path.clone().map(Into::into),
         bottomless_replication: config
@@ -294,12 +595,34 @@ fn make_db_config(config: &Cli) -> anyhow::Result<DbConfig> {
         hard_heap_limit_mb: config.hard_heap_limit_mb,
         max_response_size: config.max_response_size.as_u64(),
         max_total_response_size: config.max_total_response_size.as_u64(),
+        statement_cache_size: config.statement_cache_size,
+        max_query_params: config.max_query_params,
+        max_db_size: config.max_db_size.map(|size| size.as_u64()),
+        max_rows: config.max_rows,
+        coalesce_reads: config.coalesce_reads,
+        connection_pool_size: config.connection_pool_size,
+        max_pooled_connections: config.max_pooled_connections,
+        connection_idle_check_interval: (config.connection_idle_check_interval_s > 0)
+            .then(|| Duration::from_secs(config.connection_idle_check_interval_s)),
+        connection_idle_memory_fraction: config.connection_idle_memory_fraction,
         snapshot_exec: config.snapshot_exec.clone(),
         checkpoint_interval: config.checkpoint_interval_s.map(Duration::from_secs),
+        wal_master_key,
+        wal_compression,
     })
 }
 
 async fn make_user_api_config(config: &Cli) -> anyhow::Result<UserApiConfig> {
+    if config.hrana_max_sql_count == 0 {
+        bail!("--hrana-max-sql-count must be at least 1");
+    }
+    if config.hrana_fetch_cursor_size_divisor == 0 {
+        bail!("--hrana-fetch-cursor-size-divisor must be at least 1");
+    }
+    if config.hrana_ws_max_missed_pongs == 0 {
+        bail!("--hrana-ws-max-missed-pongs must be at least 1");
+    }
+
     let auth_jwt_key = if let Some(ref file_path) = config.auth_jwt_key_file {
         let data = tokio::fs::read_to_string(file_path)
             .await
@@ -335,16 +658,62 @@ async fn make_user_api_config(config: &Cli) -> anyhow::Result<UserApiConfig> {
         None => None,
     };
 
+    let hrana_uds_acceptor = match config.hrana_uds_path {
+        Some(ref path) => {
+            remove_stale_socket(path)?;
+            let incoming = AddrIncoming::new_unix(tokio::net::UnixListener::bind(path)?);
+
+            tracing::info!(
+                "listening for incomming user HTTP connection on unix socket {}",
+                path.display()
+            );
+
+            Some(incoming)
+        }
+        None => None,
+    };
+
     Ok(UserApiConfig {
         http_acceptor: Some(http_acceptor),
         hrana_ws_acceptor,
+        hrana_uds_acceptor,
         enable_http_console: config.enable_http_console,
         self_url: config.http_self_url.clone(),
         http_auth: config.http_auth.clone(),
         auth_jwt_key,
+        auth_audit_log: config.auth_audit_log.clone(),
+        hrana_stream_idle_timeout: (config.hrana_stream_idle_timeout_s > 0)
+            .then(|| Duration::from_secs(config.hrana_stream_idle_timeout_s)),
+        hrana_max_streams_per_session: config.hrana_max_streams_per_session,
+        hrana_fetch_cursor_size_divisor: config.hrana_fetch_cursor_size_divisor,
+        hrana_max_sql_count: config.hrana_max_sql_count,
+        hrana_max_sql_bytes: config.hrana_max_sql_bytes.as_u64(),
+        hrana_ws_ping_interval: (config.hrana_ws_ping_interval_s > 0)
+            .then(|| Duration::from_secs(config.hrana_ws_ping_interval_s)),
+        hrana_ws_max_missed_pongs: config.hrana_ws_max_missed_pongs,
+        max_request_size: config.max_request_size.as_u64(),
+        cursor_idle_timeout: Duration::from_secs(config.cursor_idle_timeout_s),
+        ready_max_replication_lag: Duration::from_secs(config.ready_max_replication_lag_s),
     })
 }
 
+/// Removes a socket file left over by a previous run, so that the socket can be bound again.
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!(
+            "cannot bind unix socket to {}: file exists and is not a socket",
+            path.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
 async fn make_admin_api_config(config: &Cli) -> anyhow::Result<Option<AdminApiConfig>> {
     match config.admin_listen_addr {
         Some(addr) => {
@@ -358,6 +727,15 @@ async fn make_admin_api_config(config: &Cli) -> anyhow::Result<Option<AdminApiCo
     }
 }
 
+/// TLS material given either as a file or inline. Both can't be given at once.
+fn tls_material(file: &Option<PathBuf>, pem: &Option<String>) -> Option<TlsMaterial> {
+    match (file, pem) {
+        (Some(path), _) => Some(TlsMaterial::Path(path.clone())),
+        (None, Some(pem)) => Some(TlsMaterial::Pem(pem.clone())),
+        (None, None) => None,
+    }
+}
+
 async fn make_rpc_server_config(config: &Cli) -> anyhow::Result<Option<RpcServerConfig>> {
     match config.grpc_listen_addr {
         Some(addr) => {
@@ -366,20 +744,22 @@ async fn make_rpc_server_config(config: &Cli) -> anyhow::Result<Option<RpcServer
             tracing::info!("listening for incomming gRPC connection on {}", addr);
 
             let tls_config = if config.grpc_tls {
-                Some(TlsConfig {
-                    cert: config
-                        .grpc_cert_file
-                        .clone()
-                        .context("server tls is enabled but cert file is missing")?,
-                    key: config
-                        .grpc_key_file
-                        .clone()
-                        .context("server tls is enabled but key file is missing")?,
-                    ca_cert: config
-                        .grpc_ca_cert_file
-                        .clone()
-                        .context("server tls is enabled but ca_cert file is missing")?,
-                })
+                let tls_config = TlsConfig {
+                    cert: tls_material(&config.grpc_cert_file, &config.grpc_cert)
+                        .context("server tls is enabled but cert is missing")?,
+                    key: tls_material(&config.grpc_key_file, &config.grpc_key)
+                        .context("server tls is enabled but key is missing")?,
+                    ca_cert: tls_material(&config.grpc_ca_cert_file, &config.grpc_ca_cert)
+                        .context("server tls is enabled but ca_cert is missing")?,
+                    domain: config.grpc_tls_domain.clone(),
+                };
+                tls_config
+                    .validate()
+                    .context("invalid gRPC server TLS configuration")?;
+                tls_config
+                    .validate_server_domain()
+                    .context("invalid gRPC server TLS configuration")?;
+                Some(tls_config)
             } else {
                 None
             };
@@ -388,6 +768,7 @@ async fn make_rpc_server_config(config: &Cli) -> anyhow::Result<Option<RpcServer
                 acceptor,
                 addr,
                 tls_config,
+                max_message_size: config.grpc_max_message_size_mb as usize * 1024 * 1024,
             }))
         }
         None => Ok(None),
@@ -397,24 +778,31 @@ async fn make_rpc_server_config(config: &Cli) -> anyhow::Result<Option<RpcServer
 async fn make_rpc_client_config(config: &Cli) -> anyhow::Result<Option<RpcClientConfig>> {
     match config.primary_grpc_url {
         Some(ref url) => {
+            let connect_timeout = Duration::from_secs(config.primary_grpc_connect_timeout_s);
+            let keepalive_interval = (config.primary_grpc_keepalive_interval_s != 0)
+                .then(|| Duration::from_secs(config.primary_grpc_keepalive_interval_s));
             let mut connector = HttpConnector::new();
             connector.enforce_http(true);
             connector.set_nodelay(true);
+            connector.set_connect_timeout(Some(connect_timeout));
+            connector.set_keepalive(keepalive_interval);
             let tls_config = if config.primary_grpc_tls {
-                Some(TlsConfig {
-                    cert: config
-                        .primary_grpc_cert_file
-                        .clone()
-                        .context("client tls is enabled but cert file is missing")?,
-                    key: config
-                        .primary_grpc_key_file
-                        .clone()
-                        .context("client tls is enabled but key file is missing")?,
-                    ca_cert: config
-                        .primary_grpc_ca_cert_file
-                        .clone()
-                        .context("client tls is enabled but ca_cert file is missing")?,
-                })
+                let tls_config = TlsConfig {
+                    cert: tls_material(&config.primary_grpc_cert_file, &config.primary_grpc_cert)
+                        .context("client tls is enabled but cert is missing")?,
+                    key: tls_material(&config.primary_grpc_key_file, &config.primary_grpc_key)
+                        .context("client tls is enabled but key is missing")?,
+                    ca_cert: tls_material(
+                        &config.primary_grpc_ca_cert_file,
+                        &config.primary_grpc_ca_cert,
+                    )
+                    .context("client tls is enabled but ca_cert is missing")?,
+                    domain: Some(config.primary_grpc_tls_domain.clone()),
+                };
+                tls_config
+                    .validate()
+                    .context("invalid gRPC client TLS configuration")?;
+                Some(tls_config)
             } else {
                 None
             };
@@ -423,6 +811,18 @@ async fn make_rpc_client_config(config: &Cli) -> anyhow::Result<Option<RpcClient
                 remote_url: url.clone(),
                 connector,
                 tls_config,
+                connect_timeout: Some(connect_timeout),
+                keepalive_interval,
+                keepalive_timeout: Some(Duration::from_secs(
+                    config.primary_grpc_keepalive_timeout_s,
+                )),
+                request_timeout: (config.primary_grpc_request_timeout_s != 0)
+                    .then(|| Duration::from_secs(config.primary_grpc_request_timeout_s)),
+                write_proxy_retry: WriteProxyRetryConfig {
+                    max_retries: config.write_proxy_max_retries,
+                    initial_backoff: Duration::from_millis(config.write_proxy_initial_backoff_ms),
+                },
+                max_message_size: config.grpc_max_message_size_mb as usize * 1024 * 1024,
             }))
         }
         None => Ok(None),
@@ -433,7 +833,11 @@ fn make_hearbeat_config(config: &Cli) -> Option<HeartbeatConfig> {
     Some(HeartbeatConfig {
         heartbeat_url: config.heartbeat_url.clone()?,
         heartbeat_period: Duration::from_secs(config.heartbeat_period_s),
-        heartbeat_auth: config.heartbeat_auth.clone(),
+        heartbeat_auth: match (&config.heartbeat_auth, &config.heartbeat_auth_file) {
+            (Some(auth), _) => Some(HeartbeatAuth::Static(auth.clone())),
+            (None, Some(path)) => Some(HeartbeatAuth::File(path.clone())),
+            (None, None) => None,
+        },
     })
 }
 
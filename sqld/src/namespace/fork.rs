@@ -31,6 +31,8 @@ pub enum ForkError {
     CreateNamespace(Box<crate::error::Error>),
     #[error("cannot fork a replica, try again with the primary.")]
     ForkReplica,
+    #[error("cannot fork an in-memory namespace.")]
+    ForkInMemory,
 }
 
 impl From<tokio::task::JoinError> for ForkError {
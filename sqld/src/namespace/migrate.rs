@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use url::Url;
+
+use crate::connection::config::{DatabaseConfig, DatabaseConfigStore};
+use crate::connection::dump::exporter::{export_dump, DumpOptions};
+use crate::connection::Connection;
+use crate::database::{Database, PrimaryDatabase};
+
+type Result<T> = crate::Result<T, MigrateError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrateError {
+    #[error("internal error: {0}")]
+    Internal(anyhow::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to checkpoint the database: {0}")]
+    Checkpoint(Box<crate::error::Error>),
+    #[error("invalid target primary url: {0}")]
+    InvalidTarget(String),
+    #[error("failed to upload database to the target primary: {0}")]
+    Upload(#[from] hyper::Error),
+    #[error("target primary rejected the database upload with status {0}")]
+    Rejected(hyper::StatusCode),
+    #[error("namespace `{0}` has already been migrated to `{1}`")]
+    AlreadyMigrated(String, Url),
+    #[error("cannot migrate a replica, try again with the primary.")]
+    MigrateReplica,
+    #[error("namespace `{0}` is already being migrated")]
+    InProgress(String),
+    #[error("failed to store the target primary in the namespace config: {0}")]
+    Config(Box<crate::error::Error>),
+}
+
+impl From<tokio::task::JoinError> for MigrateError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+/// Moves a primary namespace to another primary instance.
+///
+/// The local database is first made read-only, so that no write can be committed past the point
+/// where its content is copied to the target. The copy is then uploaded as a SQL dump to the
+/// target's admin API, which creates the namespace from it. Reads are served by the local database
+/// all along.
+///
+/// Once the target has the namespace, its url is stored in the config of the namespace: from then
+/// on, the writes to the namespace fail with [crate::error::Error::NamespaceMigrated], which
+/// redirects the clients to the target.
+pub struct MigrateTask<'a> {
+    pub base_path: PathBuf,
+    pub db: &'a PrimaryDatabase,
+    pub config_store: &'a DatabaseConfigStore,
+    pub name: Bytes,
+    pub target: Url,
+    /// `Authorization` header of the upload to the target.
+    pub target_auth: Option<String>,
+}
+
+impl MigrateTask<'_> {
+    pub async fn migrate(self) -> Result<()> {
+        let name = std::str::from_utf8(&self.name).map_err(|e| MigrateError::Internal(e.into()))?;
+        let upload_url = self
+            .target
+            .join(&format!("v1/namespaces/{name}/load"))
+            .map_err(|e| MigrateError::InvalidTarget(e.to_string()))?;
+        let upload_uri: hyper::Uri = upload_url
+            .as_str()
+            .parse()
+            .map_err(|_| MigrateError::InvalidTarget(upload_url.to_string()))?;
+
+        self.db.logger.set_read_only(true);
+        if let Err(e) = self.try_migrate(upload_uri).await {
+            // the local database is still the source of truth, resume accepting writes.
+            self.db.logger.set_read_only(false);
+            tracing::error!("failed to migrate namespace `{name}`: {e}");
+            return Err(e);
+        }
+
+        // cutover: the target is the source of truth now, the local database stays read-only.
+        self.config_store
+            .store(DatabaseConfig {
+                migrated_to: Some(self.target.clone()),
+                ..(*self.config_store.get()).clone()
+            })
+            .map_err(|e| MigrateError::Config(Box::new(e)))?;
+
+        Ok(())
+    }
+
+    async fn try_migrate(&self, upload_uri: hyper::Uri) -> Result<()> {
+        // bring all the committed frames into the main database file, so that the dump is
+        // exported from a database with no WAL lag.
+        let conn = self
+            .db
+            .connection_maker()
+            .create()
+            .await
+            .map_err(|e| MigrateError::Checkpoint(Box::new(e)))?;
+        conn.checkpoint()
+            .await
+            .map_err(|e| MigrateError::Checkpoint(Box::new(e)))?;
+        drop(conn);
+
+        let db_path = self
+            .base_path
+            .join("dbs")
+            .join(std::str::from_utf8(&self.name).unwrap())
+            .join("data");
+        let connection = tokio::task::spawn_blocking(move || rusqlite::Connection::open(db_path))
+            .await?
+            .map_err(|e| MigrateError::Internal(e.into()))?;
+
+        let (reader, writer) = tokio::io::duplex(8 * 1024);
+        let dump_task = tokio::task::spawn_blocking(move || {
+            let writer = tokio_util::io::SyncIoBridge::new(writer);
+            export_dump(connection, writer, &DumpOptions::default())
+        });
+
+        let body = hyper::Body::wrap_stream(tokio_util::io::ReaderStream::new(reader));
+        let mut request = hyper::Request::post(upload_uri);
+        if let Some(auth) = &self.target_auth {
+            request = request.header(hyper::header::AUTHORIZATION, auth);
+        }
+        let request = request
+            .body(body)
+            .map_err(|e| MigrateError::Internal(e.into()))?;
+        let response = hyper::client::Client::new().request(request).await?;
+        // the export fails, and stops, once the rejected upload is dropped
+        if !response.status().is_success() {
+            return Err(MigrateError::Rejected(response.status()));
+        }
+
+        dump_task.await?.map_err(MigrateError::Internal)?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{bail, Context as _};
 use async_lock::{RwLock, RwLockUpgradableReadGuard};
 use bottomless::replicator::Options;
+use bottomless::status::ReplicatorStatus;
 use bytes::Bytes;
 use chrono::NaiveDateTime;
 use enclose::enclose;
@@ -18,28 +19,41 @@ use tokio::io::AsyncBufReadExt;
 use tokio::task::{block_in_place, JoinSet};
 use tokio_util::io::StreamReader;
 use tonic::transport::Channel;
+use url::Url;
 use uuid::Uuid;
 
-use crate::connection::config::DatabaseConfigStore;
+use crate::config::{TrustedExtension, WriteProxyRetryConfig};
+use crate::connection::coalescing::CoalescingMakeConnection;
+use crate::connection::config::{DatabaseConfig, DatabaseConfigStore};
 use crate::connection::libsql::{open_db, LibSqlDbFactory};
+use crate::connection::warm::WarmMakeConnection;
 use crate::connection::write_proxy::MakeWriteProxyConnection;
 use crate::connection::MakeConnection;
 use crate::database::{Database, PrimaryDatabase, ReplicaDatabase};
 use crate::error::{Error, LoadDumpError};
+use crate::heap_limit;
 use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
 use crate::replication::replica::Replicator;
 use crate::replication::{NamespacedSnapshotCallback, ReplicationLogger};
 use crate::stats::Stats;
+use crate::wal_compression::{self, Codec};
+use crate::wal_encryption::{self, MasterKey};
 use crate::{
-    run_periodic_checkpoint, DB_CREATE_TIMEOUT, DEFAULT_AUTO_CHECKPOINT, DEFAULT_NAMESPACE_NAME,
-    MAX_CONCURRENT_DBS,
+    run_periodic_checkpoint, DB_CREATE_TIMEOUT, DEFAULT_AUTO_CHECKPOINT, DEFAULT_MAX_QUERY_PARAMS,
+    DEFAULT_NAMESPACE_NAME, MAX_CONCURRENT_DBS,
 };
 
 pub use fork::ForkError;
+pub use migrate::MigrateError;
+pub use restore::{RestoreError, Restored};
 
 use self::fork::ForkTask;
+use self::migrate::MigrateTask;
+use self::restore::StagedRestore;
 
 mod fork;
+mod migrate;
+mod restore;
 pub type ResetCb = Box<dyn Fn(ResetOp) -> BoxFuture<'static, crate::Result<()>> + Send + Sync>;
 
 pub enum ResetOp {
@@ -71,6 +85,26 @@ pub trait MakeNamespace: Sync + Send + 'static {
         to: Bytes,
         reset: ResetCb,
     ) -> crate::Result<Namespace<Self::Database>>;
+
+    /// Copy the database of the namespace to the `target` primary, and redirect the writes to the
+    /// namespace to it. `target_auth` is the `Authorization` header of the upload to the target.
+    async fn migrate(
+        &self,
+        db: &Self::Database,
+        config_store: &DatabaseConfigStore,
+        name: Bytes,
+        target: Url,
+        target_auth: Option<String>,
+    ) -> crate::Result<()>;
+
+    /// Restore the database of the namespace from its bottomless backups into a staging
+    /// directory, leaving the current database untouched.
+    async fn stage_restore(
+        &self,
+        name: &Bytes,
+        generation: Option<Uuid>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> crate::Result<StagedRestore>;
 }
 
 /// Creates new primary `Namespace`
@@ -132,6 +166,10 @@ impl MakeNamespace for PrimaryNamespaceMaker {
         to: Bytes,
         reset_cb: ResetCb,
     ) -> crate::Result<Namespace<Self::Database>> {
+        // the data of in-memory databases is not in the replication log
+        if from.config_store.get().in_memory {
+            return Err(ForkError::ForkInMemory.into());
+        }
         let fork_task = ForkTask {
             base_path: self.config.base_path.clone(),
             dest_namespace: to,
@@ -142,6 +180,47 @@ impl MakeNamespace for PrimaryNamespaceMaker {
         let ns = fork_task.fork().await?;
         Ok(ns)
     }
+
+    async fn migrate(
+        &self,
+        db: &Self::Database,
+        config_store: &DatabaseConfigStore,
+        name: Bytes,
+        target: Url,
+        target_auth: Option<String>,
+    ) -> crate::Result<()> {
+        let migrate_task = MigrateTask {
+            base_path: self.config.base_path.to_path_buf(),
+            db,
+            config_store,
+            name,
+            target,
+            target_auth,
+        };
+        migrate_task.migrate().await?;
+        Ok(())
+    }
+
+    async fn stage_restore(
+        &self,
+        name: &Bytes,
+        generation: Option<Uuid>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> crate::Result<StagedRestore> {
+        let name_str = std::str::from_utf8(name).map_err(|_| Error::InvalidNamespace)?;
+        let Some(ref options) = self.config.bottomless_replication else {
+            return Err(Error::BottomlessNotEnabled(name_str.to_owned()));
+        };
+        let staged = StagedRestore::restore(
+            &self.config.base_path,
+            self.config.base_path.join("dbs").join(name_str),
+            make_bottomless_options(options, name),
+            generation,
+            timestamp,
+        )
+        .await?;
+        Ok(staged)
+    }
 }
 
 /// Creates new replica `Namespace`
@@ -193,6 +272,26 @@ impl MakeNamespace for ReplicaNamespaceMaker {
     ) -> crate::Result<Namespace<Self::Database>> {
         return Err(ForkError::ForkReplica.into());
     }
+
+    async fn migrate(
+        &self,
+        _db: &Self::Database,
+        _config_store: &DatabaseConfigStore,
+        _name: Bytes,
+        _target: Url,
+        _target_auth: Option<String>,
+    ) -> crate::Result<()> {
+        return Err(MigrateError::MigrateReplica.into());
+    }
+
+    async fn stage_restore(
+        &self,
+        _name: &Bytes,
+        _generation: Option<Uuid>,
+        _timestamp: Option<NaiveDateTime>,
+    ) -> crate::Result<StagedRestore> {
+        return Err(Error::ReplicaRestoreError);
+    }
 }
 
 /// Stores and manage a set of namespaces.
@@ -213,6 +312,10 @@ struct NamespaceStoreInner<M: MakeNamespace> {
     /// The namespace factory, to create new namespaces.
     make_namespace: M,
     allow_lazy_creation: bool,
+    /// Namespaces being migrated to another primary.
+    migrating: parking_lot::Mutex<HashSet<Bytes>>,
+    /// Namespaces being restored.
+    restoring: parking_lot::Mutex<HashSet<Bytes>>,
 }
 
 impl<M: MakeNamespace> NamespaceStore<M> {
@@ -222,6 +325,8 @@ impl<M: MakeNamespace> NamespaceStore<M> {
                 store: Default::default(),
                 make_namespace,
                 allow_lazy_creation,
+                migrating: Default::default(),
+                restoring: Default::default(),
             }),
         }
     }
@@ -280,6 +385,155 @@ impl<M: MakeNamespace> NamespaceStore<M> {
         Ok(())
     }
 
+    /// Restores the namespace from its bottomless backups, to `generation` or to the point in
+    /// time `timestamp`, or to the latest backup if neither is set.
+    ///
+    /// Reads and writes to the namespace are blocked while the backup is restored in a staging
+    /// directory, which then replaces the database directory of the namespace. The restored
+    /// database starts a new replication log and a new bottomless generation. If anything fails,
+    /// the original database is kept.
+    pub async fn restore(
+        &self,
+        namespace: Bytes,
+        generation: Option<Uuid>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> crate::Result<Restored> {
+        if !self.inner.restoring.lock().insert(namespace.clone()) {
+            return Err(RestoreError::InProgress(
+                String::from_utf8(namespace.to_vec()).unwrap_or_default(),
+            )
+            .into());
+        }
+        let res = self.try_restore(&namespace, generation, timestamp).await;
+        self.inner.restoring.lock().remove(&namespace);
+        res
+    }
+
+    async fn try_restore(
+        &self,
+        namespace: &Bytes,
+        generation: Option<Uuid>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> crate::Result<Restored> {
+        let config_store = self
+            .with(namespace.clone(), |ns| ns.config_store.clone())
+            .await?;
+        let config = config_store.get();
+        config_store.store(DatabaseConfig {
+            block_reads: true,
+            block_writes: true,
+            block_reason: Some("the namespace is being restored".into()),
+            ..(*config).clone()
+        })?;
+
+        let res = self
+            .restore_blocked(namespace, generation, timestamp, &config)
+            .await;
+        if res.is_err() {
+            // the namespace in place was unblocked already, unless it couldn't be opened
+            if let Err(e) = unblock(&config_store, &config) {
+                tracing::error!("failed to unblock namespace after a failed restore: {e}");
+            }
+        }
+        res
+    }
+
+    async fn restore_blocked(
+        &self,
+        namespace: &Bytes,
+        generation: Option<Uuid>,
+        timestamp: Option<NaiveDateTime>,
+        config: &DatabaseConfig,
+    ) -> crate::Result<Restored> {
+        let staged = self
+            .inner
+            .make_namespace
+            .stage_restore(namespace, generation, timestamp)
+            .await?;
+        self.swap_restored(namespace, staged, config).await
+    }
+
+    /// Replaces the database of the namespace with the `staged` one and opens it. If the swap
+    /// fails, or the restored database can't be opened, the original database is put back and
+    /// opened again. The namespace is unblocked with `config` once it's open.
+    async fn swap_restored(
+        &self,
+        namespace: &Bytes,
+        staged: StagedRestore,
+        config: &DatabaseConfig,
+    ) -> crate::Result<Restored> {
+        let mut lock = self.inner.store.write().await;
+        let swapped = async {
+            if let Some(ns) = lock.remove(namespace) {
+                ns.destroy().await?;
+            }
+            // the swap puts the original database back in place when it fails
+            staged.swap().await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = swapped {
+            self.reopen_original(&mut lock, namespace, config).await;
+            return Err(e);
+        }
+
+        let restored = self
+            .inner
+            .make_namespace
+            .create(
+                namespace.clone(),
+                RestoreOption::KeepLocal,
+                false,
+                self.make_reset_cb(),
+            )
+            .await;
+        match restored {
+            Ok(ns) => {
+                unblock(&ns.config_store, config)?;
+                lock.insert(namespace.clone(), ns);
+                tracing::info!(
+                    "restored namespace `{}` from generation {}",
+                    std::str::from_utf8(namespace).unwrap_or_default(),
+                    staged.restored.generation
+                );
+                Ok(staged.restored)
+            }
+            Err(e) => {
+                staged.rollback().await?;
+                self.reopen_original(&mut lock, namespace, config).await;
+                Err(RestoreError::OpenRestored(Box::new(e)).into())
+            }
+        }
+    }
+
+    /// Opens the original database of a namespace after a failed restore, and unblocks it. If it
+    /// can't be opened, it's opened again on its next use, and unblocked by the caller.
+    async fn reopen_original(
+        &self,
+        store: &mut HashMap<Bytes, Namespace<M::Database>>,
+        namespace: &Bytes,
+        config: &DatabaseConfig,
+    ) {
+        let ns = self
+            .inner
+            .make_namespace
+            .create(
+                namespace.clone(),
+                RestoreOption::Latest,
+                false,
+                self.make_reset_cb(),
+            )
+            .await;
+        let res = ns.and_then(|ns| {
+            unblock(&ns.config_store, config)?;
+            store.insert(namespace.clone(), ns);
+            Ok(())
+        });
+        if let Err(e) = res {
+            tracing::error!("failed to reopen namespace after a failed restore: {e}");
+        }
+    }
+
     fn make_reset_cb(&self) -> ResetCb {
         let this = self.clone();
         Box::new(move |op| {
@@ -339,6 +593,72 @@ impl<M: MakeNamespace> NamespaceStore<M> {
         Ok(())
     }
 
+    /// Migrates a namespace to the `target` primary. The namespace keeps serving reads while its
+    /// database is copied, and other namespaces are not locked.
+    pub async fn migrate(
+        &self,
+        namespace: Bytes,
+        target: Url,
+        target_auth: Option<String>,
+    ) -> crate::Result<()> {
+        if !self.inner.migrating.lock().insert(namespace.clone()) {
+            return Err(MigrateError::InProgress(
+                String::from_utf8(namespace.to_vec()).unwrap_or_default(),
+            )
+            .into());
+        }
+        let res = self.try_migrate(&namespace, target, target_auth).await;
+        self.inner.migrating.lock().remove(&namespace);
+        res
+    }
+
+    async fn try_migrate(
+        &self,
+        namespace: &Bytes,
+        target: Url,
+        target_auth: Option<String>,
+    ) -> crate::Result<()> {
+        let (db, config_store) = self
+            .with(namespace.clone(), |ns| {
+                (ns.db.clone(), ns.config_store.clone())
+            })
+            .await?;
+        if let Some(target) = &config_store.get().migrated_to {
+            return Err(MigrateError::AlreadyMigrated(
+                String::from_utf8(namespace.to_vec()).unwrap_or_default(),
+                target.clone(),
+            )
+            .into());
+        }
+
+        self.inner
+            .make_namespace
+            .migrate(
+                &db,
+                &config_store,
+                namespace.clone(),
+                target.clone(),
+                target_auth,
+            )
+            .await?;
+
+        tracing::info!(
+            "migrated namespace `{}` to {target}",
+            std::str::from_utf8(namespace).unwrap_or_default()
+        );
+
+        Ok(())
+    }
+
+    /// Calls `f` with the namespace if it's loaded, without loading or creating it. Returns `None`
+    /// if the namespace is not loaded.
+    pub async fn with_loaded<Fun, R>(&self, namespace: &Bytes, f: Fun) -> Option<R>
+    where
+        Fun: FnOnce(&Namespace<M::Database>) -> R,
+    {
+        self.inner.store.read().await.get(namespace).map(f)
+    }
+
     pub async fn with<Fun, R>(&self, namespace: Bytes, f: Fun) -> crate::Result<R>
     where
         Fun: FnOnce(&Namespace<M::Database>) -> R,
@@ -406,6 +726,12 @@ impl<M: MakeNamespace> NamespaceStore<M> {
 #[derive(Debug)]
 pub struct Namespace<T: Database> {
     pub db: T,
+    /// Config of the namespace, stored in its database directory.
+    pub config_store: Arc<DatabaseConfigStore>,
+    /// Status of the bottomless replication of the namespace, if it is enabled.
+    pub bottomless_status: Option<Arc<std::sync::Mutex<ReplicatorStatus>>>,
+    /// Bottomless replicator of the namespace, if it is enabled.
+    pub bottomless_replicator: Option<Arc<std::sync::Mutex<bottomless::replicator::Replicator>>>,
     /// The set of tasks associated with this namespace
     tasks: JoinSet<anyhow::Result<()>>,
 }
@@ -423,16 +749,28 @@ pub struct ReplicaNamespaceConfig {
     pub base_path: Arc<Path>,
     pub max_response_size: u64,
     pub max_total_response_size: u64,
+    pub statement_cache_size: usize,
+    pub max_query_params: usize,
     /// grpc channel
     pub channel: Channel,
     /// grpc uri
     pub uri: Uri,
     /// Extensions to load for the database connection
-    pub extensions: Arc<[PathBuf]>,
+    pub extensions: Arc<[TrustedExtension]>,
     /// Stats monitor
     pub stats: Stats,
     /// Reference to the config store
     pub config_store: Arc<DatabaseConfigStore>,
+    /// Retry policy of the writes forwarded to the primary
+    pub write_proxy_retry: WriteProxyRetryConfig,
+    /// Maximum size, in bytes, of the messages exchanged with the primary
+    pub max_message_size: usize,
+    /// Key from which the WAL encryption key of the namespace is derived
+    pub wal_master_key: Option<MasterKey>,
+    /// Codec compressing the WAL of the namespace, if any
+    pub wal_compression: Option<Codec>,
+    /// Number of connections opened ahead of time, see [WarmMakeConnection].
+    pub connection_pool_size: usize,
 }
 
 impl Namespace<ReplicaDatabase> {
@@ -452,14 +790,23 @@ impl Namespace<ReplicaDatabase> {
             ));
         }
 
+        let config_store = Arc::new(DatabaseConfigStore::load_with_parent(
+            &db_path,
+            config.config_store.clone(),
+        )?);
+        init_wal_compression(&db_path, config.wal_compression)?;
+        init_wal_encryption(&db_path, &name, config.wal_master_key.as_ref())?;
+
         let mut join_set = JoinSet::new();
         let replicator = Replicator::new(
             db_path.clone(),
             config.channel.clone(),
             config.uri.clone(),
+            config.max_message_size,
             name.clone(),
             &mut join_set,
             reset,
+            config.stats.clone(),
         )
         .await?;
 
@@ -472,24 +819,33 @@ impl Namespace<ReplicaDatabase> {
             config.extensions.clone(),
             config.channel.clone(),
             config.uri.clone(),
+            config.max_message_size,
             config.stats.clone(),
-            config.config_store.clone(),
+            config_store.clone(),
             applied_frame_no_receiver,
             config.max_response_size,
             config.max_total_response_size,
+            config.statement_cache_size,
+            config.max_query_params,
+            config.write_proxy_retry,
             name.clone(),
-        )
-        .throttled(
-            MAX_CONCURRENT_DBS,
-            Some(DB_CREATE_TIMEOUT),
-            config.max_total_response_size,
         );
+        let connection_maker =
+            WarmMakeConnection::new(connection_maker, config.connection_pool_size).throttled(
+                MAX_CONCURRENT_DBS,
+                Some(DB_CREATE_TIMEOUT),
+                config.max_total_response_size,
+                name_str.to_owned(),
+            );
 
         Ok(Self {
             tasks: join_set,
             db: ReplicaDatabase {
                 connection_maker: Arc::new(connection_maker),
             },
+            config_store,
+            bottomless_status: None,
+            bottomless_replicator: None,
         })
     }
 }
@@ -501,18 +857,65 @@ pub struct PrimaryNamespaceConfig {
     pub max_log_duration: Option<Duration>,
     pub snapshot_callback: NamespacedSnapshotCallback,
     pub bottomless_replication: Option<bottomless::replicator::Options>,
-    pub extensions: Arc<[PathBuf]>,
+    pub extensions: Arc<[TrustedExtension]>,
     pub stats: Stats,
     pub config_store: Arc<DatabaseConfigStore>,
     pub max_response_size: u64,
     pub max_total_response_size: u64,
+    pub statement_cache_size: usize,
+    pub max_query_params: usize,
+    /// Whether the identical read programs executed concurrently are coalesced.
+    pub coalesce_reads: bool,
     pub checkpoint_interval: Option<Duration>,
     pub disable_namespace: bool,
+    pub wal_master_key: Option<MasterKey>,
+    pub wal_compression: Option<Codec>,
+    /// Number of connections opened ahead of time, see [WarmMakeConnection].
+    pub connection_pool_size: usize,
+}
+
+#[cfg(test)]
+impl PrimaryNamespaceConfig {
+    /// Config of the primary namespaces stored in `base_path`, with the default limits, and no
+    /// replication to bottomless.
+    pub fn new_test(base_path: &Path, stats: Stats) -> Self {
+        Self {
+            base_path: base_path.into(),
+            max_log_size: 1024 * 1024,
+            db_is_dirty: false,
+            max_log_duration: None,
+            snapshot_callback: Arc::new(|_: &Path, _: &Bytes| Ok(())),
+            bottomless_replication: None,
+            extensions: Vec::new().into(),
+            stats,
+            config_store: Arc::new(DatabaseConfigStore::new_test()),
+            max_response_size: 10_000_000,
+            max_total_response_size: 10_000_000,
+            statement_cache_size: 16,
+            max_query_params: DEFAULT_MAX_QUERY_PARAMS,
+            coalesce_reads: false,
+            checkpoint_interval: None,
+            disable_namespace: false,
+            wal_master_key: None,
+            wal_compression: None,
+            connection_pool_size: 0,
+        }
+    }
 }
 
 pub type DumpStream =
     Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static + Unpin>;
 
+/// Sets the blocks of `config` back on the namespace config in `store`.
+fn unblock(store: &DatabaseConfigStore, config: &DatabaseConfig) -> crate::Result<()> {
+    store.store(DatabaseConfig {
+        block_reads: config.block_reads,
+        block_writes: config.block_writes,
+        block_reason: config.block_reason.clone(),
+        ..(*store.get()).clone()
+    })
+}
+
 fn make_bottomless_options(options: &Options, name: &Bytes) -> Options {
     let mut options = options.clone();
     let namespace = std::str::from_utf8(name).unwrap();
@@ -522,6 +925,32 @@ fn make_bottomless_options(options: &Options, name: &Bytes) -> Options {
     options
 }
 
+/// Sets up the compression of the WAL of a namespace, before any connection to it is opened. It
+/// must be set up before the encryption, which would checkpoint a compressed WAL as plaintext.
+fn init_wal_compression(db_path: &Path, codec: Option<Codec>) -> anyhow::Result<()> {
+    let db_file = db_path.join("data");
+    wal_compression::prepare(&db_file, codec.is_some())?;
+    if let Some(codec) = codec {
+        wal_compression::enable(&db_file, codec)?;
+    }
+    Ok(())
+}
+
+/// Sets up the encryption of the WAL of a namespace, before any connection to it is opened.
+fn init_wal_encryption(
+    db_path: &Path,
+    name: &Bytes,
+    master_key: Option<&MasterKey>,
+) -> anyhow::Result<()> {
+    let db_file = db_path.join("data");
+    wal_encryption::prepare(&db_file, master_key.is_some())?;
+    if let Some(master_key) = master_key {
+        let key = master_key.derive_namespace_key(name);
+        wal_encryption::set_key(&db_file, key)?;
+    }
+    Ok(())
+}
+
 impl Namespace<PrimaryDatabase> {
     async fn new_primary(
         config: &PrimaryNamespaceConfig,
@@ -548,7 +977,27 @@ impl Namespace<PrimaryDatabase> {
 
         tokio::fs::create_dir_all(&db_path).await?;
 
-        let bottomless_replicator = if let Some(options) = &config.bottomless_replication {
+        let config_store = Arc::new(DatabaseConfigStore::load_with_parent(
+            &db_path,
+            config.config_store.clone(),
+        )?);
+        if let RestoreOption::InMemory = restore_option {
+            if !check_fresh_db(&db_path)? {
+                return Err(Error::NamespaceAlreadyExist(name_str.to_owned()));
+            }
+            config_store.store(DatabaseConfig {
+                in_memory: true,
+                ..(*config_store.get()).clone()
+            })?;
+        }
+        // in-memory databases are not backed up
+        let bottomless_replication = config
+            .bottomless_replication
+            .as_ref()
+            .filter(|_| !config_store.get().in_memory);
+
+        let mut bottomless_status = None;
+        let bottomless_replicator = if let Some(options) = bottomless_replication {
             let options = make_bottomless_options(options, &name);
             let (replicator, did_recover) =
                 init_bottomless_replicator(db_path.join("data"), options, &restore_option).await?;
@@ -566,15 +1015,19 @@ impl Namespace<PrimaryDatabase> {
             }
 
             is_dirty |= did_recover;
+            bottomless_status = Some(replicator.status());
             Some(Arc::new(std::sync::Mutex::new(replicator)))
         } else {
             None
         };
 
+        init_wal_compression(&db_path, config.wal_compression)?;
+        init_wal_encryption(&db_path, &name, config.wal_master_key.as_ref())?;
+
         let is_fresh_db = check_fresh_db(&db_path)?;
         // switch frame-count checkpoint to time-based one
         let auto_checkpoint =
-            if config.checkpoint_interval.is_some() && config.bottomless_replication.is_some() {
+            if config.checkpoint_interval.is_some() && bottomless_replication.is_some() {
                 0
             } else {
                 DEFAULT_AUTO_CHECKPOINT
@@ -592,6 +1045,10 @@ impl Namespace<PrimaryDatabase> {
                 move |path: &Path| cb(path, &name)
             }),
         )?);
+        // the writes to a migrated namespace are redirected to its new primary
+        if config_store.get().migrated_to.is_some() {
+            logger.set_read_only(true);
+        }
 
         let ctx_builder = {
             let logger = logger.clone();
@@ -599,22 +1056,35 @@ impl Namespace<PrimaryDatabase> {
             move || ReplicationLoggerHookCtx::new(logger.clone(), bottomless_replicator.clone())
         };
 
-        let connection_maker: Arc<_> = LibSqlDbFactory::new(
+        let factory = LibSqlDbFactory::new(
             db_path.clone(),
             &REPLICATION_METHODS,
             ctx_builder.clone(),
             config.stats.clone(),
-            config.config_store.clone(),
+            config.stats.statement_counts(name_str),
+            config_store.clone(),
             config.extensions.clone(),
             config.max_response_size,
             config.max_total_response_size,
+            config.statement_cache_size,
+            config.max_query_params,
             auto_checkpoint,
+            heap_limit::namespace_heap(name_str),
+        )
+        .await?;
+        let builder_config = factory.builder_config();
+        let connection_maker: Arc<_> = CoalescingMakeConnection::new(
+            WarmMakeConnection::new(factory, config.connection_pool_size),
+            builder_config,
+            config
+                .coalesce_reads
+                .then(|| logger.new_frame_notifier.subscribe()),
         )
-        .await?
         .throttled(
             MAX_CONCURRENT_DBS,
             Some(DB_CREATE_TIMEOUT),
             config.max_total_response_size,
+            name_str.to_owned(),
         )
         .into();
 
@@ -631,7 +1101,7 @@ impl Namespace<PrimaryDatabase> {
 
         join_set.spawn(run_periodic_compactions(logger.clone()));
 
-        if config.bottomless_replication.is_some() {
+        if bottomless_replication.is_some() {
             if let Some(checkpoint_interval) = config.checkpoint_interval {
                 join_set.spawn(run_periodic_checkpoint(
                     connection_maker.clone(),
@@ -646,6 +1116,9 @@ impl Namespace<PrimaryDatabase> {
                 logger,
                 connection_maker,
             },
+            config_store,
+            bottomless_status,
+            bottomless_replicator,
         })
     }
 }
@@ -662,6 +1135,12 @@ pub enum RestoreOption {
     /// Restore database state to a backup version present at a specific point in time.
     /// Granularity depends of how frequently WAL log pages are being snapshotted.
     PointInTime(NaiveDateTime),
+    /// Keep the local database, which was restored beforehand, and back it up in a new
+    /// generation.
+    KeepLocal,
+    /// Create an empty database in memory, which is not backed up, and whose data is lost when
+    /// the namespace is unloaded.
+    InMemory,
 }
 
 const WASM_TABLE_CREATE: &str =
@@ -755,12 +1234,22 @@ pub async fn init_bottomless_replicator(
     let mut replicator = bottomless::replicator::Replicator::with_options(path, options).await?;
 
     let (generation, timestamp) = match restore_option {
-        RestoreOption::Latest | RestoreOption::Dump(_) => (None, None),
+        RestoreOption::Latest
+        | RestoreOption::Dump(_)
+        | RestoreOption::KeepLocal
+        | RestoreOption::InMemory => (None, None),
         RestoreOption::Generation(generation) => (Some(*generation), None),
         RestoreOption::PointInTime(timestamp) => (None, Some(*timestamp)),
     };
 
-    let (action, did_recover) = replicator.restore(generation, timestamp).await?;
+    let (action, did_recover) = match restore_option {
+        // the database is already restored, it only needs to be backed up
+        RestoreOption::KeepLocal => (
+            bottomless::replicator::RestoreAction::SnapshotMainDbFile,
+            true,
+        ),
+        _ => replicator.restore(generation, timestamp).await?,
+    };
     match action {
         bottomless::replicator::RestoreAction::SnapshotMainDbFile => {
             replicator.new_generation();
@@ -799,3 +1288,109 @@ fn check_fresh_db(path: &Path) -> crate::Result<bool> {
     let is_fresh = !path.join("wallog").try_exists()?;
     Ok(is_fresh)
 }
+
+#[cfg(test)]
+mod test {
+    use crate::test::namespaces::{execute, has_row_value, primary_namespaces};
+
+    use super::*;
+
+    /// Creates the namespace `foo` holding the value 1, and blocks it as a restore does. Returns
+    /// the config it had before.
+    async fn setup_restore(
+        namespaces: &NamespaceStore<PrimaryNamespaceMaker>,
+    ) -> Arc<DatabaseConfig> {
+        execute(namespaces, "create table test (x)").await.unwrap();
+        execute(namespaces, "insert into test values (1)")
+            .await
+            .unwrap();
+        let config_store = namespaces
+            .with("foo".into(), |ns| ns.config_store.clone())
+            .await
+            .unwrap();
+        let config = config_store.get();
+        config_store
+            .store(DatabaseConfig {
+                block_reads: true,
+                block_writes: true,
+                ..(*config).clone()
+            })
+            .unwrap();
+        config
+    }
+
+    /// Stages a restore of the namespace `foo`, whose database directory holds a `data` file with
+    /// `contents`, if passed.
+    async fn stage(base_path: &Path, contents: Option<&[u8]>) -> StagedRestore {
+        let staging_dir = tempfile::tempdir_in(base_path).unwrap();
+        if let Some(contents) = contents {
+            let db_path = staging_dir.path().join("db");
+            tokio::fs::create_dir(&db_path).await.unwrap();
+            tokio::fs::write(db_path.join("data"), contents)
+                .await
+                .unwrap();
+        }
+        StagedRestore::new_test(staging_dir, base_path.join("dbs").join("foo"))
+    }
+
+    #[tokio::test]
+    async fn restore_swaps_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let namespaces = primary_namespaces(tmp.path());
+        let config = setup_restore(&namespaces).await;
+
+        let restored_path = tmp.path().join("restored");
+        let restored = rusqlite::Connection::open(&restored_path).unwrap();
+        restored
+            .execute_batch("create table test (x); insert into test values (2);")
+            .unwrap();
+        drop(restored);
+        let contents = tokio::fs::read(&restored_path).await.unwrap();
+        let staged = stage(tmp.path(), Some(&contents)).await;
+
+        namespaces
+            .swap_restored(&"foo".into(), staged, &config)
+            .await
+            .unwrap();
+        let calls = execute(&namespaces, "select * from test").await.unwrap();
+        assert!(has_row_value(&calls, 2));
+        assert!(!has_row_value(&calls, 1));
+    }
+
+    #[tokio::test]
+    async fn failed_swap_reopens_original_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let namespaces = primary_namespaces(tmp.path());
+        let config = setup_restore(&namespaces).await;
+
+        // there is no restored database to move in place of the original one
+        let staged = stage(tmp.path(), None).await;
+        assert!(namespaces
+            .swap_restored(&"foo".into(), staged, &config)
+            .await
+            .is_err());
+
+        // the namespace is open and unblocked
+        let calls = execute(&namespaces, "select * from test").await.unwrap();
+        assert!(has_row_value(&calls, 1));
+    }
+
+    #[tokio::test]
+    async fn failed_open_reopens_original_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let namespaces = primary_namespaces(tmp.path());
+        let config = setup_restore(&namespaces).await;
+
+        let staged = stage(tmp.path(), Some(&[0xff; 4096])).await;
+        let res = namespaces
+            .swap_restored(&"foo".into(), staged, &config)
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::Restore(RestoreError::OpenRestored(_)))
+        ));
+
+        let calls = execute(&namespaces, "select * from test").await.unwrap();
+        assert!(has_row_value(&calls, 1));
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+type Result<T> = crate::Result<T, RestoreError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestoreError {
+    #[error("internal error: {0}")]
+    Internal(anyhow::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to restore the database from bottomless: {0}")]
+    Bottomless(anyhow::Error),
+    #[error("no backup of namespace `{0}` found to restore from")]
+    NoGeneration(String),
+    #[error("namespace `{0}` is already being restored")]
+    InProgress(String),
+    #[error("failed to open the restored database, the original one was kept: {0}")]
+    OpenRestored(Box<crate::error::Error>),
+}
+
+impl From<tokio::task::JoinError> for RestoreError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+/// Outcome of the restore of a namespace.
+#[derive(Debug, Clone, Copy)]
+pub struct Restored {
+    /// Generation the database was restored from.
+    pub generation: Uuid,
+    /// Time of the last transaction applied from the backup, with the precision of the backup of
+    /// the WAL. When only the snapshot of the generation was restored, this is the time at which
+    /// the generation was created.
+    pub last_applied_at: Option<DateTime<Utc>>,
+}
+
+/// A database restored from bottomless into a staging directory, next to the databases of the
+/// namespaces so that it can be moved in place of the database of the namespace by a rename.
+///
+/// The staging directory holds the restored database directory in `db`, and the original
+/// database directory is moved to `original` when they are swapped, until the restore is done.
+pub struct StagedRestore {
+    staging_dir: TempDir,
+    /// Database directory of the namespace.
+    db_path: PathBuf,
+    pub restored: Restored,
+}
+
+impl StagedRestore {
+    /// Restores the database at `db_path` to `generation` or to the point in time `timestamp`, or
+    /// to the latest backup if neither is set, in a staging directory in `base_path`.
+    pub async fn restore(
+        base_path: &Path,
+        db_path: PathBuf,
+        options: bottomless::replicator::Options,
+        generation: Option<Uuid>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<Self> {
+        let base_path = base_path.to_owned();
+        let staging_dir =
+            tokio::task::spawn_blocking(move || tempfile::tempdir_in(base_path)).await??;
+        let staged_db_path = staging_dir.path().join("db");
+        tokio::fs::create_dir(&staged_db_path).await?;
+
+        let staged_db_file = staged_db_path.join("data");
+        let staged_db_file = staged_db_file
+            .to_str()
+            .ok_or_else(|| RestoreError::Internal(anyhow::anyhow!("invalid db path")))?;
+        let mut replicator =
+            bottomless::replicator::Replicator::with_options(staged_db_file, options)
+                .await
+                .map_err(RestoreError::Bottomless)?;
+        let generation = match generation {
+            Some(generation) => generation,
+            None => replicator
+                .latest_generation_before(timestamp.as_ref())
+                .await
+                .ok_or_else(|| {
+                    let name = db_path.file_name().unwrap_or_default().to_string_lossy();
+                    RestoreError::NoGeneration(name.into_owned())
+                })?,
+        };
+        replicator
+            .restore(Some(generation), timestamp)
+            .await
+            .map_err(RestoreError::Bottomless)?;
+
+        let last_applied_at = replicator.restore_progress().last_applied_at().or_else(|| {
+            let timestamp =
+                bottomless::replicator::Replicator::generation_to_timestamp(&generation)?;
+            let (secs, nanos) = timestamp.to_unix();
+            NaiveDateTime::from_timestamp_opt(secs as i64, nanos).map(|t| t.and_utc())
+        });
+
+        Ok(Self {
+            staging_dir,
+            db_path,
+            restored: Restored {
+                generation,
+                last_applied_at,
+            },
+        })
+    }
+
+    /// Moves the restored database in place of the database directory of the namespace, which
+    /// must be closed. The configuration of the namespace is carried over.
+    pub async fn swap(&self) -> Result<()> {
+        let staged_db_path = self.staging_dir.path().join("db");
+        match tokio::fs::copy(
+            self.db_path.join("config.json"),
+            staged_db_path.join("config.json"),
+        )
+        .await
+        {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let original_path = self.original_path();
+        tokio::fs::rename(&self.db_path, &original_path).await?;
+        if let Err(e) = tokio::fs::rename(&staged_db_path, &self.db_path).await {
+            tokio::fs::rename(&original_path, &self.db_path).await?;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Puts the original database directory back in place, after a swap.
+    pub async fn rollback(&self) -> Result<()> {
+        tokio::fs::remove_dir_all(&self.db_path).await?;
+        tokio::fs::rename(self.original_path(), &self.db_path).await?;
+        Ok(())
+    }
+
+    /// A restore staged in `staging_dir`, which holds the restored database directory in `db`, if
+    /// any.
+    #[cfg(test)]
+    pub fn new_test(staging_dir: TempDir, db_path: PathBuf) -> Self {
+        Self {
+            staging_dir,
+            db_path,
+            restored: Restored {
+                generation: Uuid::nil(),
+                last_applied_at: None,
+            },
+        }
+    }
+
+    fn original_path(&self) -> PathBuf {
+        self.staging_dir.path().join("original")
+    }
+}
@@ -1,12 +1,10 @@
 use std::error::Error as StdError;
 use std::io::Error as IoError;
-use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
 use hyper::server::accept::Accept as HyperAccept;
 use hyper::Uri;
-use pin_project_lite::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tonic::transport::server::{Connected, TcpConnectInfo};
 use tower::make::MakeConnection;
@@ -14,7 +12,7 @@ use tower::make::MakeConnection;
 pub trait Connector:
     MakeConnection<Uri, Connection = Self::Conn, Future = Self::Fut, Error = Self::Err> + Send + 'static
 {
-    type Conn: Unpin + Send + 'static;
+    type Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static;
     type Fut: Send + 'static;
     type Err: StdError + Send + Sync;
 }
@@ -22,7 +20,7 @@ pub trait Connector:
 impl<T> Connector for T
 where
     T: MakeConnection<Uri> + Send + 'static,
-    T::Connection: Unpin + Send + 'static,
+    T::Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     T::Future: Send + 'static,
     T::Error: StdError + Send + Sync,
 {
@@ -31,8 +29,10 @@ where
     type Err = Self::Error;
 }
 
+/// A connection accepted by an [`Accept`]. Its connect info is `None` if the connection has no
+/// network address, such as a Unix domain socket connection.
 pub trait Conn:
-    AsyncRead + AsyncWrite + Unpin + Send + 'static + Connected<ConnectInfo = TcpConnectInfo>
+    AsyncRead + AsyncWrite + Unpin + Send + 'static + Connected<ConnectInfo = Option<TcpConnectInfo>>
 {
 }
 
@@ -41,12 +41,27 @@ pub trait Accept: HyperAccept<Conn = Self::Connection, Error = IoError> + Send +
 }
 
 pub struct AddrIncoming {
-    listener: tokio::net::TcpListener,
+    listener: Listener,
+}
+
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
 }
 
 impl AddrIncoming {
     pub fn new(listener: tokio::net::TcpListener) -> Self {
-        Self { listener }
+        Self {
+            listener: Listener::Tcp(listener),
+        }
+    }
+
+    /// Accepts connections on a Unix domain socket. These connections have no network address, so
+    /// they have no [`TcpConnectInfo`].
+    pub fn new_unix(listener: tokio::net::UnixListener) -> Self {
+        Self {
+            listener: Listener::Unix(listener),
+        }
     }
 }
 
@@ -58,44 +73,72 @@ impl HyperAccept for AddrIncoming {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        match ready!(self.listener.poll_accept(cx)) {
-            Ok((stream, remote_addr)) => {
-                // disable naggle algorithm
-                stream.set_nodelay(true)?;
-                let local_addr = stream.local_addr()?;
-                Poll::Ready(Some(Ok(AddrStream {
-                    stream,
-                    local_addr,
-                    remote_addr,
-                })))
-            }
-            Err(e) => Poll::Ready(Some(Err(e))),
-        }
+        let stream = match &self.listener {
+            Listener::Tcp(listener) => match ready!(listener.poll_accept(cx)) {
+                Ok((stream, _)) => {
+                    // disable naggle algorithm
+                    stream.set_nodelay(true)?;
+                    Stream::Tcp(stream)
+                }
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            },
+            Listener::Unix(listener) => match ready!(listener.poll_accept(cx)) {
+                Ok((stream, _)) => Stream::Unix(stream),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            },
+        };
+
+        Poll::Ready(Some(Ok(AddrStream { stream })))
     }
 }
 
-pin_project! {
-    pub struct AddrStream {
-        #[pin]
-        stream: tokio::net::TcpStream,
-        remote_addr: SocketAddr,
-        local_addr: SocketAddr,
+pub struct AddrStream {
+    stream: Stream,
+}
+
+impl AddrStream {
+    #[cfg(test)]
+    pub fn from_unix(stream: tokio::net::UnixStream) -> Self {
+        Self {
+            stream: Stream::Unix(stream),
+        }
     }
 }
 
+enum Stream {
+    Tcp(tokio::net::TcpStream),
+    Unix(tokio::net::UnixStream),
+}
+
 impl Accept for AddrIncoming {
     type Connection = AddrStream;
 }
 
 impl Conn for AddrStream {}
 
+/// Calls a method on the underlying stream of an [`AddrStream`].
+macro_rules! with_stream {
+    ($this:expr, |$stream:ident| $body:expr) => {
+        match &mut $this.get_mut().stream {
+            Stream::Tcp(stream) => {
+                let $stream = Pin::new(stream);
+                $body
+            }
+            Stream::Unix(stream) => {
+                let $stream = Pin::new(stream);
+                $body
+            }
+        }
+    };
+}
+
 impl AsyncRead for AddrStream {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        self.project().stream.poll_read(cx, buf)
+        with_stream!(self, |stream| stream.poll_read(cx, buf))
     }
 }
 
@@ -105,28 +148,31 @@ impl AsyncWrite for AddrStream {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
-        self.project().stream.poll_write(cx, buf)
+        with_stream!(self, |stream| stream.poll_write(cx, buf))
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        self.project().stream.poll_flush(cx)
+        with_stream!(self, |stream| stream.poll_flush(cx))
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        self.project().stream.poll_shutdown(cx)
+        with_stream!(self, |stream| stream.poll_shutdown(cx))
     }
 }
 
 impl Connected for AddrStream {
-    type ConnectInfo = TcpConnectInfo;
+    type ConnectInfo = Option<TcpConnectInfo>;
 
     fn connect_info(&self) -> Self::ConnectInfo {
-        self.stream.connect_info()
+        match &self.stream {
+            Stream::Tcp(stream) => Some(stream.connect_info()),
+            Stream::Unix(_) => None,
+        }
     }
 }
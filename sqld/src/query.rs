@@ -51,6 +51,17 @@ pub struct Query {
     pub stmt: Statement,
     pub params: Params,
     pub want_rows: bool,
+    /// If set, the query is interrupted with [`Error::MemoryLimitExceeded`] once the memory used
+    /// by SQLite grew by more than this number of bytes since the query started. Clients set it
+    /// with the `max_memory_bytes` field of their statements.
+    ///
+    /// [`Error::MemoryLimitExceeded`]: crate::error::Error::MemoryLimitExceeded
+    pub max_memory_bytes: Option<u64>,
+    /// If set, the query is aborted with [`Error::RowLimitExceeded`] when it returns more than
+    /// this number of rows, before the rows over the limit are added to the response.
+    ///
+    /// [`Error::RowLimitExceeded`]: crate::error::Error::RowLimitExceeded
+    pub max_rows: Option<u64>,
 }
 
 impl ToSql for Value {
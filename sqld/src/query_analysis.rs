@@ -1,6 +1,8 @@
 use anyhow::Result;
 use fallible_iterator::FallibleIterator;
-use sqlite3_parser::ast::{Cmd, PragmaBody, QualifiedName, Stmt};
+use sqlite3_parser::ast::{
+    Cmd, Expr, Literal, OneSelect, PragmaBody, QualifiedName, ResultColumn, SelectTable, Stmt,
+};
 use sqlite3_parser::lexer::sql::{Parser, ParserError};
 
 /// A group of statements to be executed together.
@@ -11,6 +13,36 @@ pub struct Statement {
     /// Is the statement an INSERT, UPDATE or DELETE?
     pub is_iud: bool,
     pub is_insert: bool,
+    /// Does the statement change the schema?
+    pub is_ddl: bool,
+    /// Is the statement a DELETE or a DROP, which free space in the database rather than use it?
+    pub frees_space: bool,
+    /// The namespace attached by an `ATTACH` statement.
+    pub attach: Option<Attach>,
+    /// The name of the savepoint of a `SAVEPOINT`, `RELEASE` or `ROLLBACK TO` statement,
+    /// unquoted and lowercased, as SQLite compares them case-insensitively.
+    pub savepoint: Option<String>,
+    /// The pragma of a `PRAGMA` statement.
+    pub pragma: Option<Pragma>,
+}
+
+/// A `PRAGMA` statement, which the connections check against the pragma policy of their
+/// namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pragma {
+    /// Name of the pragma, unquoted and lowercased, without the schema it applies to.
+    pub name: String,
+    /// Whether the statement sets the pragma, with `PRAGMA x = value` or `PRAGMA x(value)`,
+    /// rather than reading it.
+    pub sets_value: bool,
+}
+
+/// An `ATTACH` of the database of another namespace, which is attached read-only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attach {
+    pub namespace: String,
+    /// Schema name of the attached database.
+    pub alias: String,
 }
 
 impl Default for Statement {
@@ -26,8 +58,18 @@ pub enum StmtKind {
     TxnBegin,
     /// The end of a transaction
     TxnEnd,
+    /// A savepoint, which starts a transaction if none is open
+    Savepoint,
+    /// The release of a savepoint, which ends the transaction if the savepoint started it
+    Release,
+    /// A rollback to a savepoint, which keeps the transaction open
+    RollbackTo,
     Read,
     Write,
+    /// Attaches the database of another namespace to the connection
+    Attach,
+    /// Detaches a database from the connection
+    Detach,
     Other,
 }
 
@@ -47,10 +89,16 @@ fn write_if_not_reserved(name: &QualifiedName) -> Option<StmtKind> {
 impl StmtKind {
     fn kind(cmd: &Cmd) -> Option<Self> {
         match cmd {
-            Cmd::Explain(Stmt::Pragma(name, body)) => Self::pragma_kind(name, body.as_ref()),
+            Cmd::Explain(Stmt::Pragma(name, body)) => Some(Self::pragma_kind(name, body.as_ref())),
             Cmd::Explain(_) => Some(Self::Other),
             Cmd::ExplainQueryPlan(_) => Some(Self::Other),
             Cmd::Stmt(Stmt::Begin { .. }) => Some(Self::TxnBegin),
+            Cmd::Stmt(Stmt::Savepoint(_)) => Some(Self::Savepoint),
+            Cmd::Stmt(Stmt::Release(_)) => Some(Self::Release),
+            Cmd::Stmt(Stmt::Rollback {
+                savepoint_name: Some(_),
+                ..
+            }) => Some(Self::RollbackTo),
             Cmd::Stmt(Stmt::Commit { .. } | Stmt::Rollback { .. }) => Some(Self::TxnEnd),
             Cmd::Stmt(
                 Stmt::CreateVirtualTable { tbl_name, .. }
@@ -92,83 +140,56 @@ impl StmtKind {
                 | Stmt::CreateIndex { .. },
             ) => Some(Self::Write),
             Cmd::Stmt(Stmt::Select { .. }) => Some(Self::Read),
-            Cmd::Stmt(Stmt::Pragma(name, body)) => Self::pragma_kind(name, body.as_ref()),
+            Cmd::Stmt(Stmt::Pragma(name, body)) => Some(Self::pragma_kind(name, body.as_ref())),
             // Creating regular views is OK, temporary views are bound to a connection
             // and thus disallowed in sqld.
             Cmd::Stmt(Stmt::CreateView {
                 temporary: false, ..
             }) => Some(Self::Write),
             Cmd::Stmt(Stmt::DropView { .. }) => Some(Self::Write),
+            Cmd::Stmt(Stmt::Attach { .. }) => Some(Self::Attach),
+            Cmd::Stmt(Stmt::Detach(_)) => Some(Self::Detach),
             _ => None,
         }
     }
 
-    fn pragma_kind(name: &QualifiedName, body: Option<&PragmaBody>) -> Option<Self> {
-        let name = name.name.0.as_str();
-        match name {
+    /// Tells where a pragma can be served. The pragmas which are not allowed on a namespace are
+    /// rejected by its connections, so the pragmas which change the state of the connection are
+    /// sent to the primary, like writes.
+    fn pragma_kind(name: &QualifiedName, body: Option<&PragmaBody>) -> Self {
+        let name = pragma_name(name);
+        match name.as_str() {
             // always ok to be served by primary or replicas - pure readonly pragmas
-            "table_list" | "index_list" | "table_info" | "table_xinfo" | "index_xinfo"
-            | "pragma_list" | "compile_options" | "database_list" | "function_list"
-            | "module_list" => Some(Self::Read),
+            "table_list" | "index_list" | "table_info" | "table_xinfo" | "index_info"
+            | "index_xinfo" | "pragma_list" | "compile_options" | "database_list"
+            | "function_list" | "module_list" => Self::Read,
             // special case for `encoding` - it's effectively readonly for connections
             // that already created a database, which is always the case for sqld
-            "encoding" => Some(Self::Read),
-            // always ok to be served by primary
-            "foreign_keys" | "foreign_key_list" | "foreign_key_check" | "collation_list"
-            | "data_version" | "freelist_count" | "integrity_check" | "legacy_file_format"
-            | "page_count" | "quick_check" | "stats" | "user_version" => Some(Self::Write),
-            // ok to be served by primary without args
-            "analysis_limit"
-            | "application_id"
-            | "auto_vacuum"
-            | "automatic_index"
-            | "busy_timeout"
-            | "cache_size"
-            | "cache_spill"
-            | "cell_size_check"
-            | "checkpoint_fullfsync"
-            | "defer_foreign_keys"
-            | "fullfsync"
-            | "hard_heap_limit"
-            | "journal_mode"
-            | "journal_size_limit"
-            | "legacy_alter_table"
-            | "locking_mode"
-            | "max_page_count"
-            | "mmap_size"
-            | "page_size"
-            | "query_only"
-            | "read_uncommitted"
-            | "recursive_triggers"
-            | "reverse_unordered_selects"
-            | "schema_version"
-            | "secure_delete"
-            | "soft_heap_limit"
-            | "synchronous"
-            | "temp_store"
-            | "threads"
-            | "trusted_schema"
-            | "wal_autocheckpoint" => {
-                match body {
-                    Some(_) => None,
-                    None => Some(Self::Write),
-                }
-            }
-            // changes the state of the connection, and can't be allowed rn:
-            "case_sensitive_like" | "ignore_check_constraints" | "incremental_vacuum"
-                // TODO: check if optimize can be safely performed
-                | "optimize"
-                | "parser_trace"
-                | "shrink_memory"
-                | "wal_checkpoint" => None,
-            _ => {
-                tracing::debug!("Unknown pragma: {name}");
-                None
-            },
+            "encoding" if body.is_none() => Self::Read,
+            _ => Self::Write,
         }
     }
 }
 
+/// Pragmas whose argument names a table or an index, or the number of errors to report, rather
+/// than a new value.
+const ARGUMENT_PRAGMAS: &[&str] = &[
+    "foreign_key_check",
+    "foreign_key_list",
+    "index_info",
+    "index_list",
+    "index_xinfo",
+    "integrity_check",
+    "quick_check",
+    "table_info",
+    "table_list",
+    "table_xinfo",
+];
+
+fn pragma_name(name: &QualifiedName) -> String {
+    unquote(&name.name.0).to_lowercase()
+}
+
 /// The state of a transaction for a series of statement
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum State {
@@ -181,11 +202,26 @@ pub enum State {
 }
 
 impl State {
+    /// Steps the state machine with a statement of the given kind. The kind is not enough to know
+    /// whether a `RELEASE` ends the transaction, which is assumed to stay open: [`StateTracker`]
+    /// keeps track of the open savepoints to tell.
     pub fn step(&mut self, kind: StmtKind) {
         *self = match (*self, kind) {
-            (State::Txn, StmtKind::TxnBegin) | (State::Init, StmtKind::TxnEnd) => State::Invalid,
+            (State::Txn, StmtKind::TxnBegin)
+            | (State::Init, StmtKind::TxnEnd | StmtKind::Release | StmtKind::RollbackTo) => {
+                State::Invalid
+            }
             (State::Txn, StmtKind::TxnEnd) => State::Init,
-            (state, StmtKind::Other | StmtKind::Write | StmtKind::Read) => state,
+            (State::Init | State::Txn, StmtKind::Savepoint) => State::Txn,
+            (State::Txn, StmtKind::Release | StmtKind::RollbackTo) => State::Txn,
+            (
+                state,
+                StmtKind::Other
+                | StmtKind::Write
+                | StmtKind::Read
+                | StmtKind::Attach
+                | StmtKind::Detach,
+            ) => state,
             (State::Invalid, _) => State::Invalid,
             (State::Init, StmtKind::TxnBegin) => State::Txn,
         };
@@ -196,6 +232,69 @@ impl State {
     }
 }
 
+/// Tracks the state of the transaction across a series of statements, along with the savepoints
+/// that are open, so that the release of the savepoint that started the transaction ends it.
+#[derive(Debug, Clone)]
+pub struct StateTracker {
+    state: State,
+    /// The names of the open savepoints, innermost last.
+    savepoints: Vec<String>,
+    /// Whether the transaction was started by the outermost savepoint rather than by `BEGIN`.
+    started_by_savepoint: bool,
+}
+
+impl StateTracker {
+    pub fn new(state: State) -> Self {
+        Self {
+            state,
+            savepoints: Vec::new(),
+            started_by_savepoint: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn step(&mut self, stmt: &Statement) {
+        let position = |savepoints: &[String]| {
+            let name = stmt.savepoint.as_deref()?;
+            savepoints.iter().rposition(|s| s == name)
+        };
+        match (self.state, stmt.kind) {
+            (State::Init, StmtKind::Savepoint) => {
+                self.state = State::Txn;
+                self.started_by_savepoint = true;
+                self.savepoints.extend(stmt.savepoint.clone());
+            }
+            (State::Txn, StmtKind::Savepoint) => self.savepoints.extend(stmt.savepoint.clone()),
+            // releasing a savepoint releases all the savepoints opened after it
+            (State::Txn, StmtKind::Release) => match position(&self.savepoints) {
+                Some(i) => {
+                    self.savepoints.truncate(i);
+                    if self.savepoints.is_empty() && self.started_by_savepoint {
+                        self.state = State::Init;
+                        self.started_by_savepoint = false;
+                    }
+                }
+                None => self.state = State::Invalid,
+            },
+            // rolling back to a savepoint keeps it open, but not the savepoints opened after it
+            (State::Txn, StmtKind::RollbackTo) => match position(&self.savepoints) {
+                Some(i) => self.savepoints.truncate(i + 1),
+                None => self.state = State::Invalid,
+            },
+            (_, kind) => {
+                self.state.step(kind);
+                if self.state != State::Txn {
+                    self.savepoints.clear();
+                    self.started_by_savepoint = false;
+                }
+            }
+        }
+    }
+}
+
 impl Statement {
     pub fn empty() -> Self {
         Self {
@@ -204,6 +303,11 @@ impl Statement {
             kind: StmtKind::Read,
             is_iud: false,
             is_insert: false,
+            is_ddl: false,
+            frees_space: false,
+            attach: None,
+            savepoint: None,
+            pragma: None,
         }
     }
 
@@ -225,6 +329,11 @@ impl Statement {
                         kind,
                         is_iud: false,
                         is_insert: false,
+                        is_ddl: true,
+                        frees_space: false,
+                        attach: None,
+                        savepoint: None,
+                        pragma: None,
                     });
                 }
             }
@@ -234,12 +343,86 @@ impl Statement {
                 Cmd::Stmt(Stmt::Insert { .. } | Stmt::Update { .. } | Stmt::Delete { .. })
             );
             let is_insert = matches!(c, Cmd::Stmt(Stmt::Insert { .. }));
+            let is_ddl = matches!(
+                c,
+                Cmd::Stmt(
+                    Stmt::AlterTable(..)
+                        | Stmt::CreateIndex { .. }
+                        | Stmt::CreateTable { .. }
+                        | Stmt::CreateTrigger { .. }
+                        | Stmt::CreateView { .. }
+                        | Stmt::CreateVirtualTable { .. }
+                        | Stmt::DropIndex { .. }
+                        | Stmt::DropTable { .. }
+                        | Stmt::DropTrigger { .. }
+                        | Stmt::DropView { .. }
+                )
+            );
+
+            let frees_space = matches!(
+                c,
+                Cmd::Stmt(
+                    Stmt::Delete { .. }
+                        | Stmt::DropIndex { .. }
+                        | Stmt::DropTable { .. }
+                        | Stmt::DropTrigger { .. }
+                        | Stmt::DropView { .. }
+                )
+            );
+
+            let attach = match &c {
+                Cmd::Stmt(Stmt::Attach {
+                    expr,
+                    db_name,
+                    key: None,
+                }) => Some(Attach {
+                    namespace: attached_name(expr)
+                        .filter(|name| is_namespace_name(name))
+                        .ok_or_else(|| anyhow::anyhow!("only namespaces can be attached"))?,
+                    alias: attached_name(db_name)
+                        .ok_or_else(|| anyhow::anyhow!("invalid schema name for ATTACH"))?,
+                }),
+                Cmd::Stmt(Stmt::Attach { key: Some(_), .. }) => {
+                    anyhow::bail!("ATTACH with a key is not supported")
+                }
+                _ => None,
+            };
+
+            let savepoint = match &c {
+                Cmd::Stmt(
+                    Stmt::Savepoint(name)
+                    | Stmt::Release(name)
+                    | Stmt::Rollback {
+                        savepoint_name: Some(name),
+                        ..
+                    },
+                ) => Some(unquote(&name.0).to_lowercase()),
+                _ => None,
+            };
+
+            let pragma = match &c {
+                Cmd::Stmt(Stmt::Pragma(name, body)) => {
+                    let name = pragma_name(name);
+                    let sets_value = match body {
+                        Some(PragmaBody::Equals(_)) => true,
+                        Some(PragmaBody::Call(_)) => !ARGUMENT_PRAGMAS.contains(&name.as_str()),
+                        None => false,
+                    };
+                    Some(Pragma { name, sets_value })
+                }
+                _ => None,
+            };
 
             Ok(Statement {
                 stmt: c.to_string(),
                 kind,
                 is_iud,
                 is_insert,
+                is_ddl,
+                frees_space,
+                attach,
+                savepoint,
+                pragma,
             })
         }
         // The parser needs to be boxed because it's large, and you don't want it on the stack.
@@ -273,22 +456,328 @@ impl Statement {
         })
     }
 
+    /// Attached databases are read-only, so attaching and detaching them doesn't write.
     pub fn is_read_only(&self) -> bool {
         matches!(
             self.kind,
-            StmtKind::Read | StmtKind::TxnEnd | StmtKind::TxnBegin
+            StmtKind::Read
+                | StmtKind::TxnEnd
+                | StmtKind::TxnBegin
+                | StmtKind::Savepoint
+                | StmtKind::Release
+                | StmtKind::RollbackTo
+                | StmtKind::Attach
+                | StmtKind::Detach
         )
     }
+
+    /// Returns a query counting the rows returned by the statement, if it's a simple SELECT of
+    /// the columns of a single table, without filter, grouping or limit, whose rows SQLite counts
+    /// from the b-tree of the table without reading them.
+    pub fn count_rows_query(&self) -> Option<String> {
+        let mut parser = Box::new(Parser::new(self.stmt.as_bytes()));
+        let Ok(Some(Cmd::Stmt(Stmt::Select(select)))) = parser.next() else {
+            return None
+        };
+        if select.with.is_some() || select.limit.is_some() || select.body.compounds.is_some() {
+            return None;
+        }
+        let OneSelect::Select {
+            distinctness: None,
+            columns,
+            from: Some(from),
+            where_clause: None,
+            group_by: None,
+            ..
+        } = &select.body.select
+        else {
+            return None
+        };
+        if from.joins.as_ref().map_or(false, |joins| !joins.is_empty())
+            || !matches!(from.select.as_deref(), Some(SelectTable::Table(..)))
+        {
+            return None;
+        }
+        // any expression other than a column may be an aggregate, which changes the row count
+        let only_columns = columns.iter().all(|col| {
+            matches!(
+                col,
+                ResultColumn::Star
+                    | ResultColumn::TableStar(_)
+                    | ResultColumn::Expr(
+                        Expr::Id(_)
+                            | Expr::Name(_)
+                            | Expr::Qualified(..)
+                            | Expr::DoublyQualified(..),
+                        _
+                    )
+            )
+        });
+        // SQLite flattens the subquery, and counts the rows of the table
+        only_columns.then(|| format!("SELECT count(*) FROM ({})", self.stmt))
+    }
+
+    /// Returns the parameters of the statement, in order of appearance: `?`, `?NNN`, or named
+    /// parameters with their prefix (`:name`, `@name`, `#name` or `$name`).
+    pub fn parameters(&self) -> Vec<&str> {
+        let sql = self.stmt.as_bytes();
+        let is_ident = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80;
+        let mut params = Vec::new();
+        let mut i = 0;
+        while i < sql.len() {
+            match sql[i] {
+                // string literals and quoted identifiers, where quotes are escaped by doubling them
+                quote @ (b'\'' | b'"' | b'`') => {
+                    i += 1;
+                    while i < sql.len() {
+                        if sql[i] == quote {
+                            if sql.get(i + 1) != Some(&quote) {
+                                break;
+                            }
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                b'[' => {
+                    while i < sql.len() && sql[i] != b']' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                b'-' if sql.get(i + 1) == Some(&b'-') => {
+                    while i < sql.len() && sql[i] != b'\n' {
+                        i += 1;
+                    }
+                }
+                b'/' if sql.get(i + 1) == Some(&b'*') => {
+                    i += 2;
+                    while i < sql.len() && !sql[i..].starts_with(b"*/") {
+                        i += 1;
+                    }
+                    i += 2;
+                }
+                b'?' => {
+                    let start = i;
+                    i += 1;
+                    while i < sql.len() && sql[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                    params.push(&self.stmt[start..i]);
+                }
+                b':' | b'@' | b'#' | b'$' => {
+                    let start = i;
+                    i += 1;
+                    while i < sql.len() && is_ident(sql[i]) {
+                        i += 1;
+                    }
+                    if i > start + 1 {
+                        params.push(&self.stmt[start..i]);
+                    }
+                }
+                // identifiers, keywords and numbers, which may contain a `$`
+                c if is_ident(c) => {
+                    while i < sql.len() && is_ident(sql[i]) {
+                        i += 1;
+                    }
+                }
+                _ => i += 1,
+            }
+        }
+        params
+    }
+}
+
+/// Returns the name given to `ATTACH`, either as a string literal or as an identifier.
+fn attached_name(expr: &Expr) -> Option<String> {
+    let name = match expr {
+        Expr::Literal(Literal::String(s)) => s,
+        Expr::Id(id) => &id.0,
+        _ => return None,
+    };
+    Some(unquote(name))
+}
+
+/// Removes the quotes around an SQL string or identifier, if any.
+fn unquote(s: &str) -> String {
+    for q in ['\'', '"', '`'] {
+        if let Some(inner) = s.strip_prefix(q).and_then(|s| s.strip_suffix(q)) {
+            return inner.replace(&format!("{q}{q}"), &q.to_string());
+        }
+    }
+    match s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        Some(inner) => inner.to_string(),
+        None => s.to_string(),
+    }
+}
+
+/// Whether `name` can be the name of a namespace, whose database is stored in a directory of that
+/// name: it must not point anywhere else on the filesystem.
+fn is_namespace_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
 }
 
 /// Given a an initial state and an array of queries, attempts to predict what the final state will
 /// be
-pub fn predict_final_state<'a>(
-    mut state: State,
-    stmts: impl Iterator<Item = &'a Statement>,
-) -> State {
+pub fn predict_final_state<'a>(state: State, stmts: impl Iterator<Item = &'a Statement>) -> State {
+    let mut tracker = StateTracker::new(state);
     for stmt in stmts {
-        state.step(stmt.kind);
+        tracker.step(stmt);
+    }
+    tracker.state()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parameters(sql: &str) -> Vec<String> {
+        let stmt = Statement::parse(sql).next().unwrap().unwrap();
+        stmt.parameters().into_iter().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn statement_parameters() {
+        assert_eq!(
+            parameters("SELECT ?, ?2, :a, @b, $c FROM t WHERE x = $d"),
+            ["?", "?2", ":a", "@b", "$c", "$d"]
+        );
+        assert_eq!(
+            parameters("SELECT ':a', \"@b\", [$c], `?`, x$y, 'it''s :d' FROM t WHERE x = :e"),
+            [":e"]
+        );
+        assert!(parameters("SELECT 1").is_empty());
+    }
+
+    fn attach(sql: &str) -> Result<Option<Attach>> {
+        Ok(Statement::parse(sql).next().unwrap()?.attach)
+    }
+
+    #[test]
+    fn attach_namespace() {
+        let expected = Some(Attach {
+            namespace: "other".into(),
+            alias: "o".into(),
+        });
+        assert_eq!(attach("ATTACH 'other' AS o").unwrap(), expected);
+        assert_eq!(
+            attach("ATTACH DATABASE \"other\" AS [o]").unwrap(),
+            expected
+        );
+        assert_eq!(attach("ATTACH other AS 'o'").unwrap(), expected);
+
+        let stmt = Statement::parse("ATTACH 'other' AS o")
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(stmt.kind, StmtKind::Attach);
+        assert!(stmt.is_read_only());
+        assert_eq!(
+            Statement::parse("DETACH o").next().unwrap().unwrap().kind,
+            StmtKind::Detach
+        );
+
+        // only namespaces can be attached, not arbitrary files
+        assert!(attach("ATTACH '/etc/passwd' AS o").is_err());
+        assert!(attach("ATTACH '../other/data' AS o").is_err());
+        assert!(attach("ATTACH '..' AS o").is_err());
+        assert!(attach("ATTACH 'other' || '/data' AS o").is_err());
+        assert!(attach("ATTACH 'other' AS o KEY 'secret'").is_err());
+    }
+
+    fn count_rows_query(sql: &str) -> Option<String> {
+        Statement::parse(sql)
+            .next()
+            .unwrap()
+            .unwrap()
+            .count_rows_query()
+    }
+
+    #[test]
+    fn count_rows_of_simple_selects() {
+        assert!(count_rows_query("SELECT * FROM t").is_some());
+        assert!(count_rows_query("SELECT a, t.b, main.t.c FROM main.t ORDER BY a").is_some());
+
+        assert!(count_rows_query("SELECT count(*) FROM t").is_none());
+        assert!(count_rows_query("SELECT a + 1 FROM t").is_none());
+        assert!(count_rows_query("SELECT DISTINCT a FROM t").is_none());
+        assert!(count_rows_query("SELECT * FROM t WHERE a = 1").is_none());
+        assert!(count_rows_query("SELECT a FROM t GROUP BY a").is_none());
+        assert!(count_rows_query("SELECT * FROM t LIMIT 10").is_none());
+        assert!(count_rows_query("SELECT * FROM t, u").is_none());
+        assert!(count_rows_query("SELECT * FROM t JOIN u ON t.a = u.a").is_none());
+        assert!(count_rows_query("SELECT * FROM (SELECT * FROM t)").is_none());
+        assert!(count_rows_query("SELECT a FROM t UNION SELECT a FROM u").is_none());
+        assert!(count_rows_query("SELECT 1").is_none());
+        assert!(count_rows_query("INSERT INTO t VALUES (1)").is_none());
+    }
+
+    fn final_state(sql: &str) -> State {
+        let stmts = Statement::parse(sql).collect::<Result<Vec<_>>>().unwrap();
+        predict_final_state(State::Init, stmts.iter())
+    }
+
+    #[test]
+    fn savepoints() {
+        let stmt = Statement::parse("SAVEPOINT \"Sp\"")
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(stmt.kind, StmtKind::Savepoint);
+        assert_eq!(stmt.savepoint.as_deref(), Some("sp"));
+        let stmt = Statement::parse("ROLLBACK TO sp").next().unwrap().unwrap();
+        assert_eq!(stmt.kind, StmtKind::RollbackTo);
+        let stmt = Statement::parse("ROLLBACK").next().unwrap().unwrap();
+        assert_eq!(stmt.kind, StmtKind::TxnEnd);
+
+        assert_eq!(final_state("SAVEPOINT a"), State::Txn);
+        assert_eq!(final_state("SAVEPOINT a; RELEASE a"), State::Init);
+        assert_eq!(final_state("SAVEPOINT a; ROLLBACK TO a"), State::Txn);
+        assert_eq!(
+            final_state("SAVEPOINT a; ROLLBACK TO a; RELEASE A"),
+            State::Init
+        );
+        assert_eq!(final_state("SAVEPOINT a; ROLLBACK"), State::Init);
+        assert_eq!(final_state("RELEASE a"), State::Invalid);
+        assert_eq!(final_state("SAVEPOINT a; RELEASE b"), State::Invalid);
+        // a savepoint doesn't end a transaction started by BEGIN
+        assert_eq!(final_state("BEGIN; SAVEPOINT a; RELEASE a"), State::Txn);
+        assert_eq!(
+            final_state("BEGIN; SAVEPOINT a; RELEASE a; COMMIT"),
+            State::Init
+        );
+        assert_eq!(final_state("SAVEPOINT a; BEGIN"), State::Invalid);
+    }
+
+    #[test]
+    fn nested_savepoints() {
+        assert_eq!(
+            final_state("SAVEPOINT a; SAVEPOINT b; RELEASE b"),
+            State::Txn
+        );
+        assert_eq!(
+            final_state("SAVEPOINT a; SAVEPOINT b; RELEASE b; RELEASE a"),
+            State::Init
+        );
+        // releasing the outermost savepoint releases the ones nested in it
+        assert_eq!(
+            final_state("SAVEPOINT a; SAVEPOINT b; RELEASE a"),
+            State::Init
+        );
+        // rolling back to a savepoint releases the ones nested in it, but not itself
+        assert_eq!(
+            final_state("SAVEPOINT a; SAVEPOINT b; ROLLBACK TO a; RELEASE b"),
+            State::Invalid
+        );
+        assert_eq!(
+            final_state("SAVEPOINT a; SAVEPOINT b; ROLLBACK TO a; RELEASE a"),
+            State::Init
+        );
+        // savepoints of the same name are released innermost first
+        assert_eq!(
+            final_state("SAVEPOINT a; SAVEPOINT a; RELEASE a"),
+            State::Txn
+        );
     }
-    state
 }
@@ -13,6 +13,8 @@ pub static TOTAL_RESPONSE_SIZE: AtomicUsize = AtomicUsize::new(0);
 #[derive(Debug)]
 pub enum QueryResultBuilderError {
     ResponseTooLarge(u64),
+    /// A step returned more rows than its limit, see [`LimitRows`].
+    RowLimitExceeded(u64),
     Internal(anyhow::Error),
 }
 
@@ -22,6 +24,9 @@ impl fmt::Display for QueryResultBuilderError {
             QueryResultBuilderError::ResponseTooLarge(s) => {
                 write!(f, "query response exceeds the maximum size of {}. Try reducing the number of queried rows.", ByteSize(*s))
             }
+            QueryResultBuilderError::RowLimitExceeded(limit) => {
+                write!(f, "query returned more than {limit} rows")
+            }
             QueryResultBuilderError::Internal(e) => e.fmt(f),
         }
     }
@@ -84,6 +89,13 @@ pub struct QueryBuilderConfig {
     pub max_size: Option<u64>,
     pub max_total_size: Option<u64>,
     pub auto_checkpoint: u32,
+    /// Number of prepared statements cached by the connection, 0 to disable the cache.
+    pub statement_cache_size: usize,
+    /// Maximum number of parameters bound to a statement, if limited.
+    pub max_query_params: Option<usize>,
+    /// Maximum number of rows returned by a step, if limited, on top of the limit of its query.
+    /// Set by the connection from the config of the namespace, and enforced by [`LimitRows`].
+    pub max_rows: Option<u64>,
 }
 
 pub trait QueryResultBuilder: Send + 'static {
@@ -92,8 +104,16 @@ pub trait QueryResultBuilder: Send + 'static {
     /// (Re)initialize the builder. This method can be called multiple times.
     fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError>;
     /// start serializing new step
+    ///
+    /// A step which was skipped, because its condition evaluated to false, is begun and finished
+    /// without any call in between: neither `cols_description` nor `step_error` are called for it.
+    /// A step which was executed always gets one of them, even if it returned no columns, so
+    /// builders can tell a skipped step apart from a step which ran and affected no rows.
     fn begin_step(&mut self) -> Result<(), QueryResultBuilderError>;
     /// finish serializing current step
+    ///
+    /// `last_insert_rowid` is the last inserted rowid of the connection right after this step
+    /// was executed, not at the end of the program. It is `None` for skipped steps.
     fn finish_step(
         &mut self,
         affected_row_count: u64,
@@ -132,6 +152,17 @@ pub trait QueryResultBuilder: Send + 'static {
             inner: self,
         }
     }
+    /// Returns a `QueryResultBuilder` that wraps Self and ignores the first `n` steps
+    fn skip(self, skip: usize) -> Skip<Self>
+    where
+        Self: Sized,
+    {
+        Skip {
+            skip,
+            count: 0,
+            inner: self,
+        }
+    }
 }
 
 pub struct JsonFormatter<F>(pub F);
@@ -318,6 +349,176 @@ impl QueryResultBuilder for StepResultsBuilder {
     }
 }
 
+/// Calls made to a `QueryResultBuilder` by [`StepRecorder`], with owned arguments.
+pub enum RecordedCall {
+    BeginStep,
+    FinishStep {
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    },
+    StepError(crate::error::Error),
+    ColsDescription(Vec<(String, Option<String>)>),
+    BeginRows,
+    BeginRow,
+    AddRowValue(rusqlite::types::Value),
+    FinishRow,
+    FinishRows,
+}
+
+impl RecordedCall {
+    /// Clones the call, unless it is a [`RecordedCall::StepError`], whose error can't be cloned.
+    pub fn try_clone(&self) -> Option<Self> {
+        let call = match self {
+            Self::BeginStep => Self::BeginStep,
+            Self::FinishStep {
+                affected_row_count,
+                last_insert_rowid,
+            } => Self::FinishStep {
+                affected_row_count: *affected_row_count,
+                last_insert_rowid: *last_insert_rowid,
+            },
+            Self::StepError(_) => return None,
+            Self::ColsDescription(cols) => Self::ColsDescription(cols.clone()),
+            Self::BeginRows => Self::BeginRows,
+            Self::BeginRow => Self::BeginRow,
+            Self::AddRowValue(v) => Self::AddRowValue(v.clone()),
+            Self::FinishRow => Self::FinishRow,
+            Self::FinishRows => Self::FinishRows,
+        };
+        Some(call)
+    }
+}
+
+/// A `QueryResultBuilder` that records the calls made for steps executed on another connection,
+/// to replay them in the builder of the program with [`StepRecorder::replay`].
+///
+/// The size of the recorded values is limited to the `max_size` of the builder config, so that
+/// results too large for the response are rejected before they are held in memory.
+#[derive(Default)]
+pub struct StepRecorder {
+    calls: Vec<RecordedCall>,
+    size: u64,
+    max_size: Option<u64>,
+}
+
+impl StepRecorder {
+    pub fn replay<B: QueryResultBuilder>(
+        calls: Vec<RecordedCall>,
+        builder: &mut B,
+    ) -> Result<(), QueryResultBuilderError> {
+        for call in calls {
+            match call {
+                RecordedCall::BeginStep => builder.begin_step()?,
+                RecordedCall::FinishStep {
+                    affected_row_count,
+                    last_insert_rowid,
+                } => builder.finish_step(affected_row_count, last_insert_rowid)?,
+                RecordedCall::StepError(e) => builder.step_error(e)?,
+                RecordedCall::ColsDescription(cols) => builder.cols_description(
+                    cols.iter()
+                        .map(|(name, decl_ty)| (name.as_str(), decl_ty.as_deref())),
+                )?,
+                RecordedCall::BeginRows => builder.begin_rows()?,
+                RecordedCall::BeginRow => builder.begin_row()?,
+                RecordedCall::AddRowValue(v) => builder.add_row_value(ValueRef::from(&v))?,
+                RecordedCall::FinishRow => builder.finish_row()?,
+                RecordedCall::FinishRows => builder.finish_rows()?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl QueryResultBuilder for StepRecorder {
+    type Ret = Vec<RecordedCall>;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        *self = Self {
+            max_size: config.max_size,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::BeginStep);
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::FinishStep {
+            affected_row_count,
+            last_insert_rowid,
+        });
+        Ok(())
+    }
+
+    fn step_error(&mut self, error: crate::error::Error) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::StepError(error));
+        Ok(())
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        let cols = cols
+            .into_iter()
+            .map(Into::into)
+            .map(|col| (col.name.to_owned(), col.decl_ty.map(str::to_owned)))
+            .collect();
+        self.calls.push(RecordedCall::ColsDescription(cols));
+        Ok(())
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::BeginRows);
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::BeginRow);
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.size += match v {
+            ValueRef::Text(data) | ValueRef::Blob(data) => data.len() as u64,
+            _ => std::mem::size_of::<rusqlite::types::Value>() as u64,
+        };
+        if let Some(max_size) = self.max_size {
+            if self.size > max_size {
+                return Err(QueryResultBuilderError::ResponseTooLarge(max_size));
+            }
+        }
+        self.calls.push(RecordedCall::AddRowValue(v.into()));
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::FinishRow);
+        Ok(())
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.calls.push(RecordedCall::FinishRows);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.calls
+    }
+}
+
 pub struct IgnoreResult;
 
 impl QueryResultBuilder for IgnoreResult {
@@ -377,6 +578,117 @@ impl QueryResultBuilder for IgnoreResult {
     fn into_ret(self) -> Self::Ret {}
 }
 
+// A builder that wraps another builder, but skips the first `n` steps
+pub struct Skip<B> {
+    skip: usize,
+    count: usize,
+    inner: B,
+}
+
+impl<B> Skip<B> {
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: QueryResultBuilder> QueryResultBuilder for Skip<B> {
+    type Ret = B::Ret;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        self.count = 0;
+        self.inner.init(config)
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.begin_step()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner
+                .finish_step(affected_row_count, last_insert_rowid)
+        } else {
+            self.count += 1;
+            Ok(())
+        }
+    }
+
+    fn step_error(&mut self, error: crate::error::Error) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.step_error(error)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.cols_description(cols)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.begin_rows()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.begin_row()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.add_row_value(v)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.finish_row()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.count >= self.skip {
+            self.inner.finish_rows()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish()
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.inner.into_ret()
+    }
+}
+
 // A builder that wraps another builder, but takes at most `n` steps
 pub struct Take<B> {
     limit: usize,
@@ -488,6 +800,192 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
     }
 }
 
+/// A builder that wraps another builder, and counts the rows passed to it
+pub struct CountRows<B> {
+    rows: u64,
+    inner: B,
+}
+
+impl<B> CountRows<B> {
+    pub fn new(inner: B) -> Self {
+        Self { rows: 0, inner }
+    }
+
+    /// number of rows passed to the builder since it was last initialized
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: QueryResultBuilder> QueryResultBuilder for CountRows<B> {
+    type Ret = B::Ret;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        self.rows = 0;
+        self.inner.init(config)
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_step()
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.inner
+            .finish_step(affected_row_count, last_insert_rowid)
+    }
+
+    fn step_error(&mut self, error: crate::error::Error) -> Result<(), QueryResultBuilderError> {
+        self.inner.step_error(error)
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.inner.cols_description(cols)
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_rows()
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.rows += 1;
+        self.inner.begin_row()
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.inner.add_row_value(v)
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_row()
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_rows()
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish()
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.inner.into_ret()
+    }
+}
+
+/// A builder that wraps another builder, and fails a step once it returns more rows than its
+/// limit, before the row reaches the inner builder. The limit of a step is the lowest of the limit
+/// of its query and of the `max_rows` of the builder config.
+pub struct LimitRows<B> {
+    /// Limits of the queries of the steps, in order.
+    step_limits: Vec<Option<u64>>,
+    max_rows: Option<u64>,
+    /// Number of steps begun since the builder was last initialized.
+    steps: usize,
+    /// Limit of the current step.
+    limit: Option<u64>,
+    /// Number of rows of the current step.
+    rows: u64,
+    inner: B,
+}
+
+impl<B> LimitRows<B> {
+    pub fn new(inner: B, step_limits: Vec<Option<u64>>) -> Self {
+        Self {
+            step_limits,
+            max_rows: None,
+            steps: 0,
+            limit: None,
+            rows: 0,
+            inner,
+        }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: QueryResultBuilder> QueryResultBuilder for LimitRows<B> {
+    type Ret = B::Ret;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        self.max_rows = config.max_rows;
+        self.steps = 0;
+        self.inner.init(config)
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        let step_limit = self.step_limits.get(self.steps).copied().flatten();
+        self.limit = step_limit.into_iter().chain(self.max_rows).min();
+        self.steps += 1;
+        self.rows = 0;
+        self.inner.begin_step()
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.inner
+            .finish_step(affected_row_count, last_insert_rowid)
+    }
+
+    fn step_error(&mut self, error: crate::error::Error) -> Result<(), QueryResultBuilderError> {
+        self.inner.step_error(error)
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.inner.cols_description(cols)
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_rows()
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.rows += 1;
+        if let Some(limit) = self.limit.filter(|limit| self.rows > *limit) {
+            return Err(QueryResultBuilderError::RowLimitExceeded(limit));
+        }
+        self.inner.begin_row()
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.inner.add_row_value(v)
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_row()
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_rows()
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish()
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.inner.into_ret()
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use std::fmt;
@@ -7,6 +7,10 @@ use crc::Crc;
 pub use primary::logger::{LogReadError, ReplicationLogger, ReplicationLoggerHook};
 pub use snapshot::{NamespacedSnapshotCallback, SnapshotCallback};
 
+/// Page size of every database. The frames of the replication log and its snapshots hold a single
+/// page each and have a fixed size, replicas inject them into a WAL with this page size, and the
+/// primary refuses to log frames of another size. So this is the only page size that the config
+/// of a namespace may set, unless the log format records the page size of each database.
 pub const WAL_PAGE_SIZE: i32 = 4096;
 pub const WAL_MAGIC: u64 = u64::from_le_bytes(*b"SQLDWAL\0");
 const CRC_64_GO_ISO: Crc<u64> = Crc::<u64>::new(&crc::CRC_64_GO_ISO);
@@ -4,13 +4,14 @@ use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::prelude::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::{bail, ensure};
 use bytemuck::{bytes_of, pod_read_unaligned, Pod, Zeroable};
 use bytes::{Bytes, BytesMut};
 use parking_lot::RwLock;
-use rusqlite::ffi::SQLITE_BUSY;
+use rusqlite::ffi::{SQLITE_BUSY, SQLITE_READONLY};
 use sqld_libsql_bindings::init_static_wal_method;
 use tokio::sync::watch;
 use tokio::time::{Duration, Instant};
@@ -79,6 +80,10 @@ unsafe impl WalHook for ReplicationLoggerHook {
         let last_valid_frame = wal.hdr.mxFrame;
         let ctx = Self::wal_extract_ctx(wal);
 
+        if ctx.logger.is_read_only() {
+            return SQLITE_READONLY;
+        }
+
         let mut frame_count = 0;
         for (page_no, data) in PageHdrIter::new(page_headers, page_size as _) {
             ctx.write_frame(page_no, data);
@@ -723,6 +728,8 @@ pub struct ReplicationLogger {
     pub new_frame_notifier: watch::Sender<FrameNo>,
     pub closed_signal: watch::Sender<bool>,
     pub auto_checkpoint: u32,
+    /// When set, any attempt to append new frames to the WAL is rejected.
+    read_only: AtomicBool,
 }
 
 impl ReplicationLogger {
@@ -803,6 +810,7 @@ impl ReplicationLogger {
             closed_signal,
             new_frame_notifier,
             auto_checkpoint,
+            read_only: AtomicBool::new(false),
         })
     }
 
@@ -846,6 +854,16 @@ impl ReplicationLogger {
         Self::from_log_file(data_path, log_file, callback, auto_checkpoint)
     }
 
+    /// When `read_only` is true, rejects all subsequent writes to the database. Transactions that
+    /// didn't commit yet will fail with `SQLITE_READONLY`.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Release);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
     pub fn database_id(&self) -> anyhow::Result<Uuid> {
         Ok(Uuid::from_u128((self.log_file.read()).header().db_id))
     }
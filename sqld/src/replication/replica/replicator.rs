@@ -6,8 +6,10 @@ use std::time::Duration;
 use bytemuck::bytes_of;
 use bytes::Bytes;
 use futures::StreamExt;
+use rand::Rng;
 use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use tokio::task::JoinSet;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::metadata::BinaryMetadataValue;
 use tonic::transport::Channel;
 use tonic::{Code, Request};
@@ -17,20 +19,48 @@ use crate::replication::frame::Frame;
 use crate::replication::replica::error::ReplicationError;
 use crate::replication::replica::snapshot::TempSnapshot;
 use crate::replication::FrameNo;
+use crate::rpc::replication_log::rpc::replica_message::Message;
 use crate::rpc::replication_log::rpc::{
-    replication_log_client::ReplicationLogClient, HelloRequest, LogOffset,
+    replication_log_client::ReplicationLogClient, Ack, HelloRequest, LogOffset, ReplicaMessage,
 };
 use crate::rpc::replication_log::NEED_SNAPSHOT_ERROR_MSG;
-use crate::rpc::{NAMESPACE_DOESNT_EXIST, NAMESPACE_METADATA_KEY};
+use crate::rpc::{explain_message_size_error, NAMESPACE_DOESNT_EXIST, NAMESPACE_METADATA_KEY};
+use crate::stats::{PrimaryConnectionState, Stats};
 
 use super::hook::{Frames, InjectorHookCtx};
 use super::injector::FrameInjector;
 use super::meta::WalIndexMeta;
 
 const HANDSHAKE_MAX_RETRIES: usize = 100;
+/// Delay before resubscribing to the primary after the log stream dropped. It is doubled after
+/// each consecutive failure, up to [`MAX_RESUBSCRIBE_BACKOFF`].
+const INITIAL_RESUBSCRIBE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
+/// Number of messages to the primary buffered before acknowledgements are dropped.
+const ACK_CHANNEL_LEN: usize = 16;
 
 type Client = ReplicationLogClient<Channel>;
 
+/// Failure of a handshake with the primary.
+enum HandshakeError {
+    /// The primary couldn't be reached, or failed to respond: the handshake can be retried.
+    Unreachable(tonic::Status),
+    /// The primary responded, but replication can't proceed.
+    Fatal(crate::error::Error),
+}
+
+impl From<crate::error::Error> for HandshakeError {
+    fn from(e: crate::error::Error) -> Self {
+        Self::Fatal(e)
+    }
+}
+
+impl From<anyhow::Error> for HandshakeError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Fatal(e.into())
+    }
+}
+
 /// The `Replicator` duty is to download frames from the primary, and pass them to the injector at
 /// transaction boundaries.
 pub struct Replicator {
@@ -43,6 +73,7 @@ pub struct Replicator {
     frames_sender: mpsc::Sender<Frames>,
     /// hard reset channel: send the namespace there, to reset it
     reset: ResetCb,
+    stats: Stats,
 }
 
 impl Replicator {
@@ -50,11 +81,15 @@ impl Replicator {
         db_path: PathBuf,
         channel: Channel,
         uri: tonic::transport::Uri,
+        max_message_size: usize,
         namespace: Bytes,
         join_set: &mut JoinSet<anyhow::Result<()>>,
         reset: ResetCb,
+        stats: Stats,
     ) -> anyhow::Result<Self> {
-        let client = Client::with_origin(channel, uri);
+        let client = Client::with_origin(channel, uri)
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
         let (applied_frame_notifier, current_frame_no_notifier) = watch::channel(FrameNo::MAX);
         let (frames_sender, receiver) = tokio::sync::mpsc::channel(1);
 
@@ -66,6 +101,7 @@ impl Replicator {
             meta: Arc::new(Mutex::new(None)),
             frames_sender,
             reset,
+            stats,
         };
 
         this.try_perform_handshake().await?;
@@ -137,15 +173,51 @@ impl Replicator {
     }
 
     pub async fn run(mut self) -> anyhow::Result<()> {
+        // the handshake was performed when the replicator was created
+        let mut failures = 0;
         loop {
-            self.try_perform_handshake().await?;
+            self.stats
+                .set_primary_connection_state(PrimaryConnectionState::Connected);
 
-            if let Err(e) = self.replicate().await {
+            let mut made_progress = false;
+            if let Err(e) = self.replicate(&mut made_progress).await {
                 // Replication encountered an error. We log the error, and then shut down the
                 // injector and propagate a potential panic from there.
                 tracing::warn!("replication error: {e}");
             }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            if made_progress {
+                failures = 0;
+            }
+
+            self.stats
+                .set_primary_connection_state(PrimaryConnectionState::Reconnecting);
+            self.reconnect(&mut failures).await?;
+        }
+    }
+
+    /// Performs the handshake with the primary again after the log stream dropped, retrying with
+    /// an exponential backoff for as long as the primary can't be reached. `failures` is the
+    /// number of consecutive failures so far, which is updated with the failed attempts.
+    async fn reconnect(&mut self, failures: &mut u32) -> crate::Result<()> {
+        loop {
+            *failures += 1;
+            let backoff = resubscribe_backoff(*failures);
+            tracing::info!(
+                "replication stream to primary dropped, reconnecting in {backoff:?} (attempt {failures})"
+            );
+            self.stats.inc_replica_reconnect_attempts();
+            tokio::time::sleep(backoff).await;
+
+            match self.perform_handshake().await {
+                Ok(()) => {
+                    tracing::info!("reconnected to primary");
+                    return Ok(());
+                }
+                Err(HandshakeError::Unreachable(e)) => {
+                    tracing::warn!("failed to reconnect to primary: {e}");
+                }
+                Err(HandshakeError::Fatal(e)) => return Err(e),
+            }
         }
     }
 
@@ -176,48 +248,14 @@ impl Replicator {
         let mut error_printed = false;
         for _ in 0..HANDSHAKE_MAX_RETRIES {
             tracing::info!("Attempting to perform handshake with primary.");
-            let req = self.make_request(HelloRequest {});
-            match self.client.hello(req).await {
-                Ok(resp) => {
-                    let hello = resp.into_inner();
-
-                    let mut lock = self.meta.lock().await;
-                    let meta = match *lock {
-                        Some(meta) => match meta.merge_from_hello(hello) {
-                            Ok(meta) => meta,
-                            Err(e) => return Err(self.handle_replication_error(e).await),
-                        },
-                        None => match WalIndexMeta::read_from_path(&self.db_path)? {
-                            Some(meta) => match meta.merge_from_hello(hello) {
-                                Ok(meta) => meta,
-                                Err(e) => return Err(self.handle_replication_error(e).await),
-                            },
-                            None => WalIndexMeta::new_from_hello(hello)?,
-                        },
-                    };
-
-                    *lock = Some(meta);
-
-                    return Ok(());
-                }
-                Err(e)
-                    if e.code() == Code::FailedPrecondition
-                        && e.message() == NAMESPACE_DOESNT_EXIST =>
-                {
-                    tracing::info!(
-                        "namespace `{}` doesn't exist, cleaning...",
-                        std::str::from_utf8(&self.namespace).unwrap_or_default()
-                    );
-                    (self.reset)(ResetOp::Destroy(self.namespace.clone())).await?;
-                    return Err(crate::error::Error::NamespaceDoesntExist(
-                        String::from_utf8(self.namespace.to_vec()).unwrap_or_default(),
-                    ));
-                }
-                Err(e) if !error_printed => {
+            match self.perform_handshake().await {
+                Ok(()) => return Ok(()),
+                Err(HandshakeError::Fatal(e)) => return Err(e),
+                Err(HandshakeError::Unreachable(e)) if !error_printed => {
                     tracing::error!("error connecting to primary. retrying. error: {e}");
                     error_printed = true;
                 }
-                _ => (),
+                Err(HandshakeError::Unreachable(_)) => (),
             }
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
@@ -225,21 +263,102 @@ impl Replicator {
         Err(crate::error::Error::PrimaryConnectionTimeout)
     }
 
-    async fn replicate(&mut self) -> anyhow::Result<()> {
+    /// Says hello to the primary, and merges its response in the replication metadata.
+    async fn perform_handshake(&mut self) -> Result<(), HandshakeError> {
+        let req = self.make_request(HelloRequest {});
+        match self.client.hello(req).await {
+            Ok(resp) => {
+                let hello = resp.into_inner();
+
+                let mut lock = self.meta.lock().await;
+                let meta = match *lock {
+                    Some(meta) => match meta.merge_from_hello(hello) {
+                        Ok(meta) => meta,
+                        Err(e) => return Err(self.handle_replication_error(e).await.into()),
+                    },
+                    None => match WalIndexMeta::read_from_path(&self.db_path)? {
+                        Some(meta) => match meta.merge_from_hello(hello) {
+                            Ok(meta) => meta,
+                            Err(e) => return Err(self.handle_replication_error(e).await.into()),
+                        },
+                        None => WalIndexMeta::new_from_hello(hello)?,
+                    },
+                };
+
+                *lock = Some(meta);
+
+                Ok(())
+            }
+            Err(e)
+                if e.code() == Code::FailedPrecondition
+                    && e.message() == NAMESPACE_DOESNT_EXIST =>
+            {
+                tracing::info!(
+                    "namespace `{}` doesn't exist, cleaning...",
+                    std::str::from_utf8(&self.namespace).unwrap_or_default()
+                );
+                (self.reset)(ResetOp::Destroy(self.namespace.clone())).await?;
+                Err(crate::error::Error::NamespaceDoesntExist(
+                    String::from_utf8(self.namespace.to_vec()).unwrap_or_default(),
+                )
+                .into())
+            }
+            // retrying won't help until the replica is configured with valid credentials
+            Err(e) if matches!(e.code(), Code::Unauthenticated | Code::PermissionDenied) => {
+                Err(crate::error::Error::NotAuthorized(format!(
+                    "primary rejected the handshake: {}",
+                    e.message()
+                ))
+                .into())
+            }
+            Err(e) => Err(HandshakeError::Unreachable(e)),
+        }
+    }
+
+    /// Streams frames from the primary until the stream ends or fails, acknowledging the frames
+    /// as they are applied. `made_progress` is set as soon as the primary sends anything.
+    async fn replicate(&mut self, made_progress: &mut bool) -> anyhow::Result<()> {
         const MAX_REPLICA_REPLICATION_BUFFER_LEN: usize = 10_000_000 / 4096; // ~10MB
         let offset = LogOffset {
             // if current == FrameNo::Max then it means that we're starting fresh
             next_offset: self.next_offset(),
         };
 
-        let req = self.make_request(offset);
-
-        let mut stream = self.client.log_entries(req).await?.into_inner();
+        let (acks, messages) = mpsc::channel(ACK_CHANNEL_LEN);
+        acks.try_send(ReplicaMessage {
+            message: Some(Message::Start(offset.clone())),
+        })?;
+        let req = self.make_request(ReceiverStream::new(messages));
+        let mut stream = match self.client.stream_log_entries(req).await {
+            Ok(resp) => resp.into_inner(),
+            // primaries that predate the bidirectional stream only push frames to the replica
+            Err(e) if e.code() == Code::Unimplemented => {
+                let req = self.make_request(offset);
+                self.client.log_entries(req).await?.into_inner()
+            }
+            Err(e) => return Err(e.into()),
+        };
 
+        let mut applied_frame_no = self.current_frame_no_notifier.clone();
         let mut buffer = Vec::new();
         loop {
-            match stream.next().await {
+            let frame = tokio::select! {
+                frame = stream.next() => frame,
+                Ok(()) = applied_frame_no.changed() => {
+                    let frame_no = *applied_frame_no.borrow_and_update();
+                    if frame_no != FrameNo::MAX {
+                        // acks are cumulative, so the ack can be dropped if the channel is full
+                        let _ = acks.try_send(ReplicaMessage {
+                            message: Some(Message::Ack(Ack { frame_no })),
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            match frame {
                 Some(Ok(frame)) => {
+                    *made_progress = true;
                     let frame = Frame::try_from_bytes(frame.data)?;
                     buffer.push(frame.clone());
                     if frame.header().size_after != 0
@@ -261,7 +380,7 @@ impl Replicator {
                     buffer.clear();
                     self.load_snapshot().await?;
                 }
-                Some(Err(e)) => return Err(e.into()),
+                Some(Err(e)) => return Err(explain_message_size_error(e).into()),
                 None => return Ok(()),
             }
         }
@@ -276,7 +395,7 @@ impl Replicator {
 
         let stream = frames.map(|data| match data {
             Ok(frame) => Frame::try_from_bytes(frame.data),
-            Err(e) => anyhow::bail!(e),
+            Err(e) => anyhow::bail!(explain_message_size_error(e)),
         });
         let snap = TempSnapshot::from_stream(&self.db_path, stream).await?;
 
@@ -294,3 +413,298 @@ impl Replicator {
         (current != FrameNo::MAX).then_some(current)
     }
 }
+
+/// Exponential backoff with full jitter for the `attempt`-th consecutive resubscription.
+fn resubscribe_backoff(attempt: u32) -> Duration {
+    let max = INITIAL_RESUBSCRIBE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_RESUBSCRIBE_BACKOFF);
+    max.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::stream::BoxStream;
+    use tonic::{Response, Status, Streaming};
+
+    use super::*;
+    use crate::rpc::replication_log::rpc::replication_log_server::{
+        ReplicationLog, ReplicationLogServer,
+    };
+    use crate::rpc::replication_log::rpc::{Frame, Frames, HelloResponse};
+    use crate::rpc::DEFAULT_MAX_MESSAGE_SIZE;
+
+    /// A primary without any frame, whose log streams stay open until it shuts down.
+    struct FakePrimary {
+        shutdown: watch::Receiver<bool>,
+        /// If set, the primary rejects every handshake with this code.
+        reject: Option<Code>,
+        /// If set, the primary predates `stream_log_entries`, and only serves `log_entries`.
+        legacy: bool,
+        /// Number of log streams opened by the replicas.
+        streams: Arc<AtomicUsize>,
+    }
+
+    impl FakePrimary {
+        fn open_stream(&self) -> BoxStream<'static, Result<Frame, Status>> {
+            self.streams.fetch_add(1, Ordering::Relaxed);
+            let mut shutdown = self.shutdown.clone();
+            futures::stream::once(async move {
+                while !*shutdown.borrow_and_update() {
+                    if shutdown.changed().await.is_err() {
+                        break;
+                    }
+                }
+                Err(Status::unavailable("primary is shutting down"))
+            })
+            .boxed()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl ReplicationLog for FakePrimary {
+        type LogEntriesStream = BoxStream<'static, Result<Frame, Status>>;
+        type SnapshotStream = BoxStream<'static, Result<Frame, Status>>;
+        type StreamLogEntriesStream = BoxStream<'static, Result<Frame, Status>>;
+
+        async fn hello(
+            &self,
+            _req: Request<HelloRequest>,
+        ) -> Result<Response<HelloResponse>, Status> {
+            if let Some(code) = self.reject {
+                return Err(Status::new(code, "invalid credentials"));
+            }
+            Ok(Response::new(HelloResponse {
+                generation_id: "c2d2b2a8-95a2-4a39-9a56-4fb6f8b6a3a1".into(),
+                generation_start_index: 0,
+                database_id: "0b1c8a1e-8a34-4b4e-8f0c-9d3f0ed4c7a5".into(),
+            }))
+        }
+
+        async fn log_entries(
+            &self,
+            _req: Request<LogOffset>,
+        ) -> Result<Response<Self::LogEntriesStream>, Status> {
+            if !self.legacy {
+                return Err(Status::unimplemented("log_entries"));
+            }
+            Ok(Response::new(self.open_stream()))
+        }
+
+        async fn batch_log_entries(
+            &self,
+            _req: Request<LogOffset>,
+        ) -> Result<Response<Frames>, Status> {
+            Err(Status::unimplemented("batch_log_entries"))
+        }
+
+        async fn snapshot(
+            &self,
+            _req: Request<LogOffset>,
+        ) -> Result<Response<Self::SnapshotStream>, Status> {
+            Err(Status::unimplemented("snapshot"))
+        }
+
+        async fn stream_log_entries(
+            &self,
+            _req: Request<Streaming<ReplicaMessage>>,
+        ) -> Result<Response<Self::StreamLogEntriesStream>, Status> {
+            if self.legacy {
+                return Err(Status::unimplemented("stream_log_entries"));
+            }
+            Ok(Response::new(self.open_stream()))
+        }
+    }
+
+    struct RunningPrimary {
+        shutdown: watch::Sender<bool>,
+        streams: Arc<AtomicUsize>,
+        server: tokio::task::JoinHandle<Result<(), tonic::transport::Error>>,
+    }
+
+    impl RunningPrimary {
+        fn start(addr: SocketAddr) -> Self {
+            Self::start_with(addr, None, false)
+        }
+
+        fn start_with(addr: SocketAddr, reject: Option<Code>, legacy: bool) -> Self {
+            let (shutdown, shutdown_receiver) = watch::channel(false);
+            let streams = Arc::new(AtomicUsize::new(0));
+            let primary = FakePrimary {
+                shutdown: shutdown_receiver.clone(),
+                reject,
+                legacy,
+                streams: streams.clone(),
+            };
+            let mut signal = shutdown_receiver;
+            let server = tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(ReplicationLogServer::new(primary))
+                    .serve_with_shutdown(addr, async move {
+                        while !*signal.borrow_and_update() {
+                            if signal.changed().await.is_err() {
+                                break;
+                            }
+                        }
+                    }),
+            );
+            Self {
+                shutdown,
+                streams,
+                server,
+            }
+        }
+
+        async fn stop(self) {
+            self.shutdown.send(true).unwrap();
+            self.server.await.unwrap().unwrap();
+        }
+    }
+
+    async fn wait_until(what: &str, mut cond: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !cond() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting until {what}"));
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_primary_comes_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let uri: tonic::transport::Uri = format!("http://{addr}").parse().unwrap();
+        let channel = Channel::builder(uri.clone()).connect_lazy();
+        let stats = Stats::default();
+
+        let primary = RunningPrimary::start(addr);
+        let mut join_set = JoinSet::new();
+        let replicator = Replicator::new(
+            tmp.path().to_owned(),
+            channel,
+            uri,
+            DEFAULT_MAX_MESSAGE_SIZE,
+            Bytes::from_static(b"default"),
+            &mut join_set,
+            Box::new(|_| Box::pin(async { Ok(()) })),
+            stats.clone(),
+        )
+        .await
+        .unwrap();
+        let replication = tokio::spawn(replicator.run());
+
+        wait_until("the replica streams from the primary", || {
+            primary.streams.load(Ordering::Relaxed) == 1
+                && stats.primary_connection_state() == PrimaryConnectionState::Connected
+        })
+        .await;
+
+        primary.stop().await;
+        wait_until("the replica tried to reconnect", || {
+            stats.primary_connection_state() == PrimaryConnectionState::Reconnecting
+                && stats.replica_reconnect_attempts() >= 2
+        })
+        .await;
+
+        // the replica keeps retrying until the primary is back
+        let primary = RunningPrimary::start(addr);
+        wait_until("the replica streams from the restarted primary", || {
+            primary.streams.load(Ordering::Relaxed) == 1
+                && stats.primary_connection_state() == PrimaryConnectionState::Connected
+        })
+        .await;
+        assert!(!replication.is_finished());
+
+        replication.abort();
+        primary.stop().await;
+    }
+
+    #[tokio::test]
+    async fn rejected_handshake_is_fatal() {
+        for code in [Code::Unauthenticated, Code::PermissionDenied] {
+            let tmp = tempfile::tempdir().unwrap();
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let uri: tonic::transport::Uri = format!("http://{addr}").parse().unwrap();
+            let channel = Channel::builder(uri.clone()).connect_lazy();
+
+            let primary = RunningPrimary::start_with(addr, Some(code), false);
+            let mut join_set = JoinSet::new();
+            // the replicator gives up instead of retrying the handshake forever
+            let res = tokio::time::timeout(
+                Duration::from_secs(30),
+                Replicator::new(
+                    tmp.path().to_owned(),
+                    channel,
+                    uri,
+                    DEFAULT_MAX_MESSAGE_SIZE,
+                    Bytes::from_static(b"default"),
+                    &mut join_set,
+                    Box::new(|_| Box::pin(async { Ok(()) })),
+                    Stats::default(),
+                ),
+            )
+            .await
+            .expect("the handshake was retried");
+            let err = res.err().expect("the handshake should fail");
+            assert!(
+                matches!(
+                    err.downcast_ref::<crate::error::Error>(),
+                    Some(crate::error::Error::NotAuthorized(_))
+                ),
+                "unexpected error: {err}"
+            );
+
+            primary.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_from_legacy_primary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let uri: tonic::transport::Uri = format!("http://{addr}").parse().unwrap();
+        let channel = Channel::builder(uri.clone()).connect_lazy();
+        let stats = Stats::default();
+
+        let primary = RunningPrimary::start_with(addr, None, true);
+        let mut join_set = JoinSet::new();
+        let replicator = Replicator::new(
+            tmp.path().to_owned(),
+            channel,
+            uri,
+            DEFAULT_MAX_MESSAGE_SIZE,
+            Bytes::from_static(b"default"),
+            &mut join_set,
+            Box::new(|_| Box::pin(async { Ok(()) })),
+            stats.clone(),
+        )
+        .await
+        .unwrap();
+        let replication = tokio::spawn(replicator.run());
+
+        // the replica falls back to `log_entries` instead of retrying `stream_log_entries`
+        wait_until("the replica streams from the primary", || {
+            primary.streams.load(Ordering::Relaxed) == 1
+                && stats.primary_connection_state() == PrimaryConnectionState::Connected
+        })
+        .await;
+        assert_eq!(stats.replica_reconnect_attempts(), 0);
+
+        replication.abort();
+        primary.stop().await;
+    }
+}
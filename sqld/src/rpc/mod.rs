@@ -1,14 +1,17 @@
+use std::sync::Arc;
+
 use anyhow::Context;
 use bytes::Bytes;
-use tonic::Status;
+use tonic::{Code, Status};
 use tower::util::option_layer;
+use url::Url;
 
-use crate::config::TlsConfig;
 use crate::namespace::{NamespaceStore, PrimaryNamespaceMaker};
 use crate::rpc::proxy::rpc::proxy_server::ProxyServer;
 use crate::rpc::proxy::ProxyService;
 pub use crate::rpc::replication_log::rpc::replication_log_server::ReplicationLogServer;
 use crate::rpc::replication_log::ReplicationLogService;
+use crate::rpc::tls::{ServerTls, TlsAcceptor};
 use crate::utils::services::idle_shutdown::IdleShutdownKicker;
 use crate::DEFAULT_NAMESPACE_NAME;
 
@@ -16,18 +19,65 @@ pub mod proxy;
 pub mod replica_proxy;
 pub mod replication_log;
 pub mod replication_log_proxy;
+pub mod tls;
 
 /// A tonic error code to signify that a namespace doesn't exist.
 pub const NAMESPACE_DOESNT_EXIST: &str = "NAMESPACE_DOESNT_EXIST";
 pub(crate) const NAMESPACE_METADATA_KEY: &str = "x-namespace-bin";
+/// Metadata of the errors of the writes to a migrated namespace, with the url of its new primary.
+pub(crate) const MIGRATED_TO_METADATA_KEY: &str = "x-sqld-migrated-to";
+/// Default maximum size of the messages exchanged over gRPC, which is the default of tonic.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Hints at `--grpc-max-message-size-mb` in the error of a message over the maximum size, which
+/// tonic reports without saying how to raise it.
+pub fn explain_message_size_error(status: Status) -> Status {
+    let too_large = matches!(status.code(), Code::OutOfRange | Code::ResourceExhausted)
+        && status.message().contains("message length too large");
+    if !too_large {
+        return status;
+    }
+    Status::new(
+        status.code(),
+        format!(
+            "{}. The limit can be raised with --grpc-max-message-size-mb, on both the primary and the replicas",
+            status.message()
+        ),
+    )
+}
+
+/// Converts the error of a write to a namespace that was migrated to another primary, so that
+/// replicas can redirect their clients to it with [migrated_to].
+pub fn namespace_migrated_status(namespace: &str, target: &Url) -> Status {
+    let mut status = Status::failed_precondition(format!(
+        "namespace `{namespace}` was migrated to `{target}`"
+    ));
+    if let Ok(target) = target.as_str().parse() {
+        status
+            .metadata_mut()
+            .insert(MIGRATED_TO_METADATA_KEY, target);
+    }
+    status
+}
+
+/// Returns the primary to which the namespace of a write was migrated, if the write failed with a
+/// [namespace_migrated_status].
+pub fn migrated_to(status: &Status) -> Option<Url> {
+    if status.code() != Code::FailedPrecondition {
+        return None;
+    }
+    let target = status.metadata().get(MIGRATED_TO_METADATA_KEY)?;
+    Url::parse(target.to_str().ok()?).ok()
+}
 
 #[allow(clippy::too_many_arguments)]
 pub async fn run_rpc_server<A: crate::net::Accept>(
     acceptor: A,
-    maybe_tls: Option<TlsConfig>,
+    maybe_tls: Option<Arc<ServerTls>>,
     idle_shutdown_layer: Option<IdleShutdownKicker>,
     namespaces: NamespaceStore<PrimaryNamespaceMaker>,
     disable_namespaces: bool,
+    max_message_size: usize,
 ) -> anyhow::Result<()> {
     let proxy_service = ProxyService::new(namespaces.clone(), None, disable_namespaces);
     let logger_service = ReplicationLogService::new(
@@ -39,33 +89,30 @@ pub async fn run_rpc_server<A: crate::net::Accept>(
 
     // tracing::info!("serving write proxy server at {addr}");
 
-    let mut builder = tonic::transport::Server::builder();
-    if let Some(tls_config) = maybe_tls {
-        let cert_pem = std::fs::read_to_string(&tls_config.cert)?;
-        let key_pem = std::fs::read_to_string(&tls_config.key)?;
-        let identity = tonic::transport::Identity::from_pem(cert_pem, key_pem);
-
-        let ca_cert_pem = std::fs::read_to_string(&tls_config.ca_cert)?;
-        let ca_cert = tonic::transport::Certificate::from_pem(ca_cert_pem);
-
-        let tls_config = tonic::transport::ServerTlsConfig::new()
-            .identity(identity)
-            .client_ca_root(ca_cert);
-        builder = builder
-            .tls_config(tls_config)
-            .context("Failed to read the TSL config of RPC server")?;
-    }
-    let router = builder
+    let router = tonic::transport::Server::builder()
         .layer(&option_layer(idle_shutdown_layer))
-        .add_service(ProxyServer::new(proxy_service))
-        .add_service(ReplicationLogServer::new(logger_service))
+        .add_service(
+            ProxyServer::new(proxy_service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+        )
+        .add_service(
+            ReplicationLogServer::new(logger_service)
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(max_message_size),
+        )
         .into_router();
 
     let h2c = crate::h2c::H2cMaker::new(router);
-    hyper::server::Server::builder(acceptor)
-        .serve(h2c)
-        .await
-        .context("http server")?;
+    match maybe_tls {
+        Some(tls) => {
+            hyper::server::Server::builder(TlsAcceptor::new(acceptor, tls))
+                .serve(h2c)
+                .await
+        }
+        None => hyper::server::Server::builder(acceptor).serve(h2c).await,
+    }
+    .context("http server")?;
     Ok(())
 }
 
@@ -85,3 +132,20 @@ fn extract_namespace<T>(
         Err(Status::invalid_argument("Missing x-namespace-bin metadata"))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_size_errors_are_explained() {
+        let status = explain_message_size_error(Status::out_of_range(
+            "Error, message length too large: found 5000000 bytes, the limit is: 4194304 bytes",
+        ));
+        assert_eq!(status.code(), Code::OutOfRange);
+        assert!(status.message().contains("--grpc-max-message-size-mb"));
+
+        let status = explain_message_size_error(Status::out_of_range("frame_no is too large"));
+        assert_eq!(status.message(), "frame_no is too large");
+    }
+}
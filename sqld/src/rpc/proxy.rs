@@ -6,6 +6,7 @@ use async_lock::{RwLock, RwLockUpgradableReadGuard};
 use uuid::Uuid;
 
 use crate::auth::{Auth, Authenticated};
+use crate::connection::coalescing::CoalescingConnection;
 use crate::connection::libsql::LibSqlConnection;
 use crate::connection::{Connection, TrackedConnection};
 use crate::database::Database;
@@ -16,7 +17,9 @@ use crate::query_result_builder::{
 
 use self::rpc::proxy_server::Proxy;
 use self::rpc::query_result::RowResult;
-use self::rpc::{Ack, DisconnectMessage, ExecuteResults, QueryResult, ResultRows, Row};
+use self::rpc::{
+    Ack, DisconnectMessage, ExecuteResults, QueryResult, ResultRows, Row, VacuumReq, VacuumResp,
+};
 use super::NAMESPACE_DOESNT_EXIST;
 
 pub mod rpc {
@@ -135,7 +138,12 @@ pub mod rpc {
                 .map(TryInto::try_into)
                 .collect::<anyhow::Result<_>>()?;
 
-            Ok(Self::new(steps))
+            Ok(Self {
+                steps: Arc::new(steps),
+                dry_run: pgm.dry_run,
+                parallel: pgm.parallel,
+                incremental: pgm.incremental,
+            })
         }
     }
 
@@ -195,6 +203,8 @@ pub mod rpc {
                     .context("missing params in query")?
                     .try_into()?,
                 want_rows: !query.skip_rows,
+                max_memory_bytes: query.max_memory_bytes,
+                max_rows: query.max_rows,
             })
         }
     }
@@ -209,6 +219,9 @@ pub mod rpc {
 
             Self {
                 steps: steps.into_iter().map(|s| s.into()).collect(),
+                dry_run: pgm.dry_run,
+                parallel: pgm.parallel,
+                incremental: pgm.incremental,
             }
         }
     }
@@ -219,6 +232,8 @@ pub mod rpc {
                 stmt: query.stmt.stmt,
                 params: Some(query.params.try_into().unwrap()),
                 skip_rows: !query.want_rows,
+                max_memory_bytes: query.max_memory_bytes,
+                max_rows: query.max_rows,
             }
         }
     }
@@ -261,7 +276,7 @@ pub mod rpc {
 }
 
 pub struct ProxyService {
-    clients: RwLock<HashMap<Uuid, Arc<TrackedConnection<LibSqlConnection>>>>,
+    clients: RwLock<HashMap<Uuid, Arc<TrackedConnection<CoalescingConnection<LibSqlConnection>>>>>,
     namespaces: NamespaceStore<PrimaryNamespaceMaker>,
     auth: Option<Arc<Auth>>,
     disable_namespaces: bool,
@@ -292,6 +307,20 @@ struct ExecuteResultBuilder {
     max_size: u64,
     current_size: u64,
     current_step_size: u64,
+    /// Whether the current step was skipped, i.e. it got neither columns nor an error.
+    current_skipped: bool,
+    /// Whether skipped steps are reported as such, or as empty rows, for the replicas that don't
+    /// know about [`rpc::Skipped`].
+    report_skipped: bool,
+}
+
+impl ExecuteResultBuilder {
+    fn new(report_skipped: bool) -> Self {
+        Self {
+            report_skipped,
+            ..Default::default()
+        }
+    }
 }
 
 impl QueryResultBuilder for ExecuteResultBuilder {
@@ -300,6 +329,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
     fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
         *self = Self {
             max_size: config.max_size.unwrap_or(u64::MAX),
+            report_skipped: self.report_skipped,
             ..Default::default()
         };
         Ok(())
@@ -309,6 +339,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
         assert!(self.current_err.is_none());
         assert!(self.current_rows.is_empty());
         self.current_step_size = 0;
+        self.current_skipped = true;
         Ok(())
     }
 
@@ -327,6 +358,9 @@ impl QueryResultBuilder for ExecuteResultBuilder {
                     row_result: Some(RowResult::Error(err.into())),
                 })
             }
+            None if self.current_skipped && self.report_skipped => self.results.push(QueryResult {
+                row_result: Some(RowResult::Skipped(rpc::Skipped {})),
+            }),
             None => {
                 let result_rows = ResultRows {
                     column_descriptions: std::mem::take(&mut self.current_col_description),
@@ -351,6 +385,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
             return Err(QueryResultBuilderError::ResponseTooLarge(self.max_size));
         }
         self.current_step_size = error_size;
+        self.current_skipped = false;
 
         self.current_err = Some(error);
 
@@ -362,6 +397,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
         cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
     ) -> Result<(), QueryResultBuilderError> {
         assert!(self.current_col_description.is_empty());
+        self.current_skipped = false;
         for col in cols {
             let col = col.into();
             let col_len =
@@ -489,12 +525,17 @@ impl Proxy for ProxyService {
 
         tracing::debug!("executing request for {client_id}");
 
-        let builder = ExecuteResultBuilder::default();
-        let (results, state) = db
-            .execute_program(pgm, auth, builder)
-            .await
-            // TODO: this is no necessarily a permission denied error!
-            .map_err(|e| tonic::Status::new(tonic::Code::PermissionDenied, e.to_string()))?;
+        let builder = ExecuteResultBuilder::new(req.report_skipped);
+        let (results, state) =
+            db.execute_program(pgm, auth, builder)
+                .await
+                .map_err(|e| match e {
+                    crate::error::Error::NamespaceMigrated(namespace, target) => {
+                        super::namespace_migrated_status(&namespace, &target)
+                    }
+                    // TODO: this is no necessarily a permission denied error!
+                    e => tonic::Status::new(tonic::Code::PermissionDenied, e.to_string()),
+                })?;
 
         let current_frame_no = *new_frame_notifier.borrow();
         Ok(tonic::Response::new(ExecuteResults {
@@ -518,4 +559,90 @@ impl Proxy for ProxyService {
 
         Ok(tonic::Response::new(Ack {}))
     }
+
+    async fn vacuum(
+        &self,
+        req: tonic::Request<VacuumReq>,
+    ) -> Result<tonic::Response<VacuumResp>, tonic::Status> {
+        let auth = if let Some(auth) = &self.auth {
+            auth.authenticate_grpc(&req)?
+        } else {
+            Authenticated::from_proxy_grpc_request(&req)?
+        };
+        let namespace = super::extract_namespace(self.disable_namespaces, &req)?;
+
+        let (connection_maker, new_frame_notifier) = self
+            .namespaces
+            .with(namespace, |ns| {
+                let connection_maker = ns.db.connection_maker();
+                let notifier = ns.db.logger.new_frame_notifier.subscribe();
+                (connection_maker, notifier)
+            })
+            .await
+            .map_err(|e| {
+                if let crate::error::Error::NamespaceDoesntExist(_) = e {
+                    tonic::Status::failed_precondition(NAMESPACE_DOESNT_EXIST)
+                } else {
+                    tonic::Status::internal(e.to_string())
+                }
+            })?;
+
+        let db = connection_maker
+            .create()
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        db.vacuum(auth).await.map_err(|e| match e {
+            crate::error::Error::NotAuthorized(_) => {
+                tonic::Status::permission_denied(e.to_string())
+            }
+            crate::error::Error::VacuumConflict => tonic::Status::aborted(e.to_string()),
+            e => tonic::Status::internal(e.to_string()),
+        })?;
+
+        let current_frame_no = *new_frame_notifier.borrow();
+        Ok(tonic::Response::new(VacuumResp { current_frame_no }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the results of a statement which returns no columns and changes no rows, followed
+    /// by a step whose condition evaluated to false.
+    fn execute_with_skipped_step(report_skipped: bool) -> Vec<QueryResult> {
+        let mut builder = ExecuteResultBuilder::new(report_skipped);
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+        builder.begin_step().unwrap();
+        builder
+            .cols_description(std::iter::empty::<Column>())
+            .unwrap();
+        builder.finish_step(0, None).unwrap();
+        builder.begin_step().unwrap();
+        builder.finish_step(0, None).unwrap();
+        builder.finish().unwrap();
+        builder.into_ret()
+    }
+
+    #[test]
+    fn skipped_steps_are_reported_as_skipped() {
+        let results = execute_with_skipped_step(true);
+        assert!(matches!(
+            results[0].row_result,
+            Some(RowResult::Row(ResultRows {
+                affected_row_count: 0,
+                ..
+            }))
+        ));
+        assert!(matches!(results[1].row_result, Some(RowResult::Skipped(_))));
+    }
+
+    #[test]
+    fn skipped_steps_are_empty_rows_for_older_replicas() {
+        let results = execute_with_skipped_step(false);
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result.row_result, Some(RowResult::Row(_)))));
+    }
 }
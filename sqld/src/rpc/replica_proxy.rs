@@ -3,6 +3,7 @@ use tonic::transport::Channel;
 
 use super::proxy::rpc::{
     self, proxy_client::ProxyClient, proxy_server::Proxy, Ack, DisconnectMessage, ExecuteResults,
+    VacuumReq, VacuumResp,
 };
 
 pub struct ReplicaProxyService {
@@ -10,8 +11,10 @@ pub struct ReplicaProxyService {
 }
 
 impl ReplicaProxyService {
-    pub fn new(channel: Channel, uri: Uri) -> Self {
-        let client = ProxyClient::with_origin(channel, uri);
+    pub fn new(channel: Channel, uri: Uri, max_message_size: usize) -> Self {
+        let client = ProxyClient::with_origin(channel, uri)
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
         Self { client }
     }
 }
@@ -34,4 +37,12 @@ impl Proxy for ReplicaProxyService {
         let mut client = self.client.clone();
         client.disconnect(msg).await
     }
+
+    async fn vacuum(
+        &self,
+        req: tonic::Request<VacuumReq>,
+    ) -> Result<tonic::Response<VacuumResp>, tonic::Status> {
+        let mut client = self.client.clone();
+        client.vacuum(req).await
+    }
 }
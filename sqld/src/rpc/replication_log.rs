@@ -3,32 +3,40 @@ pub mod rpc {
     tonic::include_proto!("wal_log");
 }
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
 use bytes::Bytes;
 use futures::stream::BoxStream;
+use metrics::gauge;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
+use tonic::codec::Streaming;
 use tonic::Status;
 
 use crate::auth::Auth;
 use crate::namespace::{NamespaceStore, PrimaryNamespaceMaker};
 use crate::replication::primary::frame_stream::FrameStream;
-use crate::replication::LogReadError;
+use crate::replication::{FrameNo, LogReadError, ReplicationLogger};
 use crate::utils::services::idle_shutdown::IdleShutdownKicker;
 
+use self::rpc::replica_message::Message;
 use self::rpc::replication_log_server::ReplicationLog;
-use self::rpc::{Frame, Frames, HelloRequest, HelloResponse, LogOffset};
+use self::rpc::{Ack, Frame, Frames, HelloRequest, HelloResponse, LogOffset, ReplicaMessage};
 
 use super::NAMESPACE_DOESNT_EXIST;
 
+/// Last frame acknowledged by each replica streaming the log of a namespace.
+type ReplicaAcks = Arc<RwLock<HashMap<(SocketAddr, Bytes), Arc<AtomicU64>>>>;
+
 pub struct ReplicationLogService {
     namespaces: NamespaceStore<PrimaryNamespaceMaker>,
     replicas_with_hello: RwLock<HashSet<(SocketAddr, Bytes)>>,
+    replica_acks: ReplicaAcks,
     idle_shutdown_layer: Option<IdleShutdownKicker>,
     auth: Option<Arc<Auth>>,
     disable_namespaces: bool,
@@ -49,6 +57,7 @@ impl ReplicationLogService {
         Self {
             namespaces,
             replicas_with_hello: Default::default(),
+            replica_acks: Default::default(),
             idle_shutdown_layer,
             auth,
             disable_namespaces,
@@ -62,6 +71,38 @@ impl ReplicationLogService {
 
         Ok(())
     }
+
+    fn check_hello(&self, replica_addr: SocketAddr, namespace: &Bytes) -> Result<(), Status> {
+        let guard = self.replicas_with_hello.read().unwrap();
+        if !guard.contains(&(replica_addr, namespace.clone())) {
+            return Err(Status::failed_precondition(NO_HELLO_ERROR_MSG));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the last frame that the replica at `replica_addr` acknowledged on its stream of
+    /// the log of `namespace`, if it is streaming the log and acknowledged a frame.
+    pub fn acked_frame_no(&self, replica_addr: SocketAddr, namespace: &Bytes) -> Option<FrameNo> {
+        let acks = self.replica_acks.read().unwrap();
+        let frame_no = acks
+            .get(&(replica_addr, namespace.clone()))?
+            .load(Ordering::Relaxed);
+        (frame_no != FrameNo::MAX).then_some(frame_no)
+    }
+
+    async fn logger(&self, namespace: Bytes) -> Result<Arc<ReplicationLogger>, Status> {
+        self.namespaces
+            .with(namespace, |ns| ns.db.logger.clone())
+            .await
+            .map_err(|e| {
+                if let crate::error::Error::NamespaceDoesntExist(_) = e {
+                    Status::failed_precondition(NAMESPACE_DOESNT_EXIST)
+                } else {
+                    Status::internal(e.to_string())
+                }
+            })
+    }
 }
 
 fn map_frame_stream_output(
@@ -84,6 +125,49 @@ fn map_frame_stream_output(
     }
 }
 
+/// Tracks the acknowledgements of a replica for as long as it streams the log of a namespace.
+struct ReplicaAckGuard {
+    acks: ReplicaAcks,
+    key: (SocketAddr, Bytes),
+    frame_no: Arc<AtomicU64>,
+}
+
+impl ReplicaAckGuard {
+    fn new(acks: ReplicaAcks, replica_addr: SocketAddr, namespace: Bytes) -> Self {
+        let key = (replica_addr, namespace);
+        let frame_no = Arc::new(AtomicU64::new(FrameNo::MAX));
+        acks.write().unwrap().insert(key.clone(), frame_no.clone());
+        Self {
+            acks,
+            key,
+            frame_no,
+        }
+    }
+
+    /// Records that the replica applied the log up to `frame_no`, and reports how far behind the
+    /// `logger` it is.
+    fn ack(&self, frame_no: FrameNo, logger: &ReplicationLogger) {
+        self.frame_no.store(frame_no, Ordering::Relaxed);
+        let lag = logger.new_frame_notifier.borrow().saturating_sub(frame_no);
+        gauge!(
+            "replica_lag_frames",
+            lag as f64,
+            "namespace" => String::from_utf8_lossy(&self.key.1).into_owned(),
+            "replica" => self.key.0.to_string()
+        );
+    }
+}
+
+impl Drop for ReplicaAckGuard {
+    fn drop(&mut self) {
+        let mut acks = self.acks.write().unwrap();
+        // the replica may already have opened a new stream from the same address
+        if matches!(acks.get(&self.key), Some(frame_no) if Arc::ptr_eq(frame_no, &self.frame_no)) {
+            acks.remove(&self.key);
+        }
+    }
+}
+
 pub struct StreamGuard<S> {
     s: S,
     idle_shutdown_layer: Option<IdleShutdownKicker>,
@@ -124,6 +208,7 @@ impl<S: futures::stream::Stream + Unpin> futures::stream::Stream for StreamGuard
 impl ReplicationLog for ReplicationLogService {
     type LogEntriesStream = BoxStream<'static, Result<Frame, Status>>;
     type SnapshotStream = BoxStream<'static, Result<Frame, Status>>;
+    type StreamLogEntriesStream = BoxStream<'static, Result<Frame, Status>>;
 
     async fn log_entries(
         &self,
@@ -136,24 +221,9 @@ impl ReplicationLog for ReplicationLogService {
             .remote_addr()
             .ok_or(Status::internal("No remote RPC address"))?;
         let req = req.into_inner();
-        {
-            let guard = self.replicas_with_hello.read().unwrap();
-            if !guard.contains(&(replica_addr, namespace.clone())) {
-                return Err(Status::failed_precondition(NO_HELLO_ERROR_MSG));
-            }
-        }
+        self.check_hello(replica_addr, &namespace)?;
 
-        let logger = self
-            .namespaces
-            .with(namespace, |ns| ns.db.logger.clone())
-            .await
-            .map_err(|e| {
-                if let crate::error::Error::NamespaceDoesntExist(_) = e {
-                    Status::failed_precondition(NAMESPACE_DOESNT_EXIST)
-                } else {
-                    Status::internal(e.to_string())
-                }
-            })?;
+        let logger = self.logger(namespace).await?;
 
         let stream = StreamGuard::new(
             FrameStream::new(logger, req.next_offset, true, None)
@@ -176,24 +246,9 @@ impl ReplicationLog for ReplicationLogService {
             .remote_addr()
             .ok_or(Status::internal("No remote RPC address"))?;
         let req = req.into_inner();
-        {
-            let guard = self.replicas_with_hello.read().unwrap();
-            if !guard.contains(&(replica_addr, namespace.clone())) {
-                return Err(Status::failed_precondition(NO_HELLO_ERROR_MSG));
-            }
-        }
+        self.check_hello(replica_addr, &namespace)?;
 
-        let logger = self
-            .namespaces
-            .with(namespace, |ns| ns.db.logger.clone())
-            .await
-            .map_err(|e| {
-                if let crate::error::Error::NamespaceDoesntExist(_) = e {
-                    Status::failed_precondition(NAMESPACE_DOESNT_EXIST)
-                } else {
-                    Status::internal(e.to_string())
-                }
-            })?;
+        let logger = self.logger(namespace).await?;
 
         let frames = StreamGuard::new(
             FrameStream::new(logger, req.next_offset, false, Some(MAX_FRAMES_PER_BATCH))
@@ -214,9 +269,6 @@ impl ReplicationLog for ReplicationLogService {
         self.authenticate(&req)?;
         let namespace = super::extract_namespace(self.disable_namespaces, &req)?;
 
-        use tonic::transport::server::TcpConnectInfo;
-
-        req.extensions().get::<TcpConnectInfo>().unwrap();
         let replica_addr = req
             .remote_addr()
             .ok_or(Status::internal("No remote RPC address"))?;
@@ -226,17 +278,7 @@ impl ReplicationLog for ReplicationLogService {
             guard.insert((replica_addr, namespace.clone()));
         }
 
-        let logger = self
-            .namespaces
-            .with(namespace, |ns| ns.db.logger.clone())
-            .await
-            .map_err(|e| {
-                if let crate::error::Error::NamespaceDoesntExist(_) = e {
-                    Status::failed_precondition(NAMESPACE_DOESNT_EXIST)
-                } else {
-                    Status::internal(e.to_string())
-                }
-            })?;
+        let logger = self.logger(namespace).await?;
 
         let response = HelloResponse {
             database_id: logger.database_id().unwrap().to_string(),
@@ -296,4 +338,190 @@ impl ReplicationLog for ReplicationLogService {
             Ok(Err(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
         }
     }
+
+    async fn stream_log_entries(
+        &self,
+        req: tonic::Request<Streaming<ReplicaMessage>>,
+    ) -> Result<tonic::Response<Self::StreamLogEntriesStream>, Status> {
+        self.authenticate(&req)?;
+        let namespace = super::extract_namespace(self.disable_namespaces, &req)?;
+
+        let replica_addr = req
+            .remote_addr()
+            .ok_or(Status::internal("No remote RPC address"))?;
+        self.check_hello(replica_addr, &namespace)?;
+
+        let mut messages = req.into_inner();
+        let next_offset = match messages.message().await? {
+            Some(ReplicaMessage {
+                message: Some(Message::Start(LogOffset { next_offset })),
+            }) => next_offset,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "replication stream must start with a log offset",
+                ))
+            }
+        };
+
+        let logger = self.logger(namespace.clone()).await?;
+
+        // the frames are streamed until the replica closes its side of the stream, or it fails
+        let ack_guard = ReplicaAckGuard::new(self.replica_acks.clone(), replica_addr, namespace);
+        let replica_closed = {
+            let logger = logger.clone();
+            async move {
+                loop {
+                    match messages.message().await {
+                        Ok(Some(ReplicaMessage {
+                            message: Some(Message::Ack(Ack { frame_no })),
+                        })) => {
+                            tracing::trace!("replica {replica_addr} applied frame {frame_no}");
+                            ack_guard.ack(frame_no, &logger);
+                        }
+                        Ok(Some(_)) => {
+                            tracing::debug!(
+                                "unexpected message in replication stream from {replica_addr}"
+                            );
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::debug!("replication stream from {replica_addr} failed: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+        };
+
+        let stream = StreamGuard::new(
+            FrameStream::new(logger, next_offset, true, None)
+                .map_err(|e| Status::internal(e.to_string()))?,
+            self.idle_shutdown_layer.clone(),
+        )
+        .map(map_frame_stream_output);
+        let stream = futures::StreamExt::take_until(stream, replica_closed);
+
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::task::JoinSet;
+    use tonic::transport::Channel;
+
+    use crate::namespace::PrimaryNamespaceConfig;
+    use crate::replication::replica::Replicator;
+    use crate::rpc::DEFAULT_MAX_MESSAGE_SIZE;
+    use crate::stats::Stats;
+    use crate::test::namespaces::{execute, primary_namespaces};
+
+    use super::rpc::replication_log_server::ReplicationLogServer;
+    use super::*;
+
+    #[tokio::test]
+    async fn hello_without_remote_address() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let config = PrimaryNamespaceConfig::new_test(tmp.path(), stats);
+        let namespaces = NamespaceStore::new(PrimaryNamespaceMaker::new(config), false);
+        let service = ReplicationLogService::new(namespaces, None, None, true);
+
+        // requests received over a unix socket carry no connect info
+        let status = service
+            .hello(tonic::Request::new(HelloRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+
+        let status = service
+            .batch_log_entries(tonic::Request::new(LogOffset { next_offset: 0 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+    }
+
+    #[tokio::test]
+    async fn replica_acks_streamed_frames() {
+        let tmp = tempfile::tempdir().unwrap();
+        let namespaces = primary_namespaces(tmp.path());
+        execute(&namespaces, "CREATE TABLE t (x)").await.unwrap();
+        execute(&namespaces, "INSERT INTO t VALUES (42)")
+            .await
+            .unwrap();
+        let primary_frame_no = namespaces
+            .with("foo".into(), |ns| *ns.db.logger.new_frame_notifier.borrow())
+            .await
+            .unwrap();
+
+        let service = Arc::new(ReplicationLogService::new(namespaces, None, None, false));
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ReplicationLogServer::from_arc(service.clone()))
+                .serve(addr),
+        );
+
+        let replica_dir = tempfile::tempdir().unwrap();
+        let uri: tonic::transport::Uri = format!("http://{addr}").parse().unwrap();
+        let channel = Channel::builder(uri.clone()).connect_lazy();
+        let mut join_set = JoinSet::new();
+        let replicator = Replicator::new(
+            replica_dir.path().to_owned(),
+            channel,
+            uri,
+            DEFAULT_MAX_MESSAGE_SIZE,
+            Bytes::from_static(b"foo"),
+            &mut join_set,
+            Box::new(|_| Box::pin(async { Ok(()) })),
+            Stats::default(),
+        )
+        .await
+        .unwrap();
+        let mut applied_frame_no = replicator.current_frame_no_notifier.clone();
+        let replication = tokio::spawn(replicator.run());
+
+        // the replica applies the frames it streamed, and acknowledges them to the primary
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while *applied_frame_no.borrow_and_update() != primary_frame_no {
+                applied_frame_no.changed().await.unwrap();
+            }
+            loop {
+                let replicas: Vec<_> = service
+                    .replica_acks
+                    .read()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect();
+                if replicas
+                    .iter()
+                    .any(|(addr, ns)| service.acked_frame_no(*addr, ns) == Some(primary_frame_no))
+                {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the replica didn't acknowledge the frames of the primary");
+
+        // the acknowledgements are forgotten once the replica stops streaming
+        replication.abort();
+        let _ = replication.await;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !service.replica_acks.read().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the stream of the replica wasn't closed");
+
+        server.abort();
+    }
 }
@@ -1,9 +1,13 @@
 use hyper::Uri;
+use tokio_stream::StreamExt;
+use tonic::codec::Streaming;
 use tonic::{transport::Channel, Status};
 
 use super::replication_log::rpc::replication_log_client::ReplicationLogClient;
 use super::replication_log::rpc::replication_log_server::ReplicationLog;
-use super::replication_log::rpc::{Frame, Frames, HelloRequest, HelloResponse, LogOffset};
+use super::replication_log::rpc::{
+    Frame, Frames, HelloRequest, HelloResponse, LogOffset, ReplicaMessage,
+};
 
 /// A replication log service that proxies request to the primary.
 pub struct ReplicationLogProxyService {
@@ -11,8 +15,10 @@ pub struct ReplicationLogProxyService {
 }
 
 impl ReplicationLogProxyService {
-    pub fn new(channel: Channel, uri: Uri) -> Self {
-        let client = ReplicationLogClient::with_origin(channel, uri);
+    pub fn new(channel: Channel, uri: Uri, max_message_size: usize) -> Self {
+        let client = ReplicationLogClient::with_origin(channel, uri)
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
         Self { client }
     }
 }
@@ -21,6 +27,7 @@ impl ReplicationLogProxyService {
 impl ReplicationLog for ReplicationLogProxyService {
     type LogEntriesStream = tonic::codec::Streaming<Frame>;
     type SnapshotStream = tonic::codec::Streaming<Frame>;
+    type StreamLogEntriesStream = tonic::codec::Streaming<Frame>;
 
     async fn log_entries(
         &self,
@@ -53,4 +60,14 @@ impl ReplicationLog for ReplicationLogProxyService {
         let mut client = self.client.clone();
         client.snapshot(req).await
     }
+
+    async fn stream_log_entries(
+        &self,
+        req: tonic::Request<Streaming<ReplicaMessage>>,
+    ) -> Result<tonic::Response<Self::StreamLogEntriesStream>, Status> {
+        let mut client = self.client.clone();
+        // the stream to the primary is closed as soon as the one from the replica fails
+        let req = req.map(|messages| messages.map_while(Result::ok));
+        client.stream_log_entries(req).await
+    }
 }
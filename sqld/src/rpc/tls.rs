@@ -0,0 +1,464 @@
+//! TLS for the inter-node gRPC connections.
+//!
+//! The TLS material is loaded into rustls configurations that can be swapped at runtime with
+//! [`ReloadableTls::reload`]: connections established after a reload use the new certificates,
+//! while existing connections keep going with the ones they were established with.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use hyper::server::accept::Accept as HyperAccept;
+use hyper::Uri;
+use parking_lot::RwLock;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tonic::transport::server::Connected;
+use tower::make::MakeConnection;
+use tower::Service;
+
+use crate::config::{TlsConfig, TlsMaterial, DEFAULT_TLS_DOMAIN};
+use crate::net::{Accept, Conn};
+
+/// Time allowed to a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Something that holds TLS material that can be reloaded from disk.
+pub trait TlsReload: Send + Sync + 'static {
+    fn reload(&self) -> anyhow::Result<()>;
+}
+
+/// A rustls configuration that can be reloaded from the files it was loaded from. Inline PEM
+/// material doesn't change, so reloading it only parses it again.
+pub struct ReloadableTls<T> {
+    config: TlsConfig,
+    current: RwLock<Arc<T>>,
+    load: fn(&TlsConfig) -> anyhow::Result<T>,
+}
+
+pub type ServerTls = ReloadableTls<rustls::ServerConfig>;
+pub type ClientTls = ReloadableTls<rustls::ClientConfig>;
+
+impl<T> ReloadableTls<T> {
+    fn new(config: TlsConfig, load: fn(&TlsConfig) -> anyhow::Result<T>) -> anyhow::Result<Self> {
+        let current = load(&config)?;
+        Ok(Self {
+            config,
+            current: RwLock::new(Arc::new(current)),
+            load,
+        })
+    }
+
+    fn current(&self) -> Arc<T> {
+        self.current.read().clone()
+    }
+}
+
+impl<T: Send + Sync + 'static> TlsReload for ReloadableTls<T> {
+    /// Loads the TLS material again. If it fails to load, the current configuration is kept.
+    fn reload(&self) -> anyhow::Result<()> {
+        let new = (self.load)(&self.config)?;
+        *self.current.write() = Arc::new(new);
+        Ok(())
+    }
+}
+
+impl ServerTls {
+    pub fn load(config: TlsConfig) -> anyhow::Result<Arc<Self>> {
+        Self::new(config, load_server_config).map(Arc::new)
+    }
+}
+
+impl ClientTls {
+    pub fn load(config: TlsConfig) -> anyhow::Result<Arc<Self>> {
+        Self::new(config, load_client_config).map(Arc::new)
+    }
+}
+
+fn load_server_config(config: &TlsConfig) -> anyhow::Result<rustls::ServerConfig> {
+    config.validate()?;
+    config.validate_server_domain()?;
+
+    let certs = load_certs(&config.cert)?;
+    let key = load_private_key(&config.key)?;
+    let roots = load_roots(&config.ca_cert)?;
+
+    let client_verifier = rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed();
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certs, key)
+        .with_context(|| {
+            format!(
+                "invalid certificate or key in {} and {}",
+                config.cert, config.key
+            )
+        })?;
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(server_config)
+}
+
+fn load_client_config(config: &TlsConfig) -> anyhow::Result<rustls::ClientConfig> {
+    config.validate()?;
+
+    let certs = load_certs(&config.cert)?;
+    let key = load_private_key(&config.key)?;
+    let roots = load_roots(&config.ca_cert)?;
+
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)
+        .with_context(|| {
+            format!(
+                "invalid certificate or key in {} and {}",
+                config.cert, config.key
+            )
+        })?;
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(client_config)
+}
+
+fn load_certs(material: &TlsMaterial) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let pem = material
+        .read()
+        .with_context(|| format!("failed to read certificate {material}"))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .with_context(|| format!("failed to parse certificate {material}"))?;
+
+    for cert in certs.iter() {
+        match x509_parser::parse_x509_certificate(cert) {
+            Ok((_, cert)) => tracing::info!(
+                "loaded certificate `{}` from {material}, valid until {}",
+                cert.subject(),
+                cert.validity().not_after
+            ),
+            Err(e) => anyhow::bail!("invalid certificate in {material}: {e}"),
+        }
+    }
+
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_private_key(material: &TlsMaterial) -> anyhow::Result<rustls::PrivateKey> {
+    let pem = material
+        .read()
+        .with_context(|| format!("failed to read key {material}"))?;
+    let items = rustls_pemfile::read_all(&mut pem.as_slice())
+        .with_context(|| format!("failed to parse key {material}"))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in {material}"))
+}
+
+fn load_roots(material: &TlsMaterial) -> anyhow::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(material)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("invalid CA certificate in {material}"))?;
+    }
+
+    Ok(roots)
+}
+
+type Handshake<C> = Pin<Box<dyn Future<Output = io::Result<TlsConn<C>>> + Send>>;
+
+/// An acceptor that performs the TLS handshake on the connections of the inner acceptor, with
+/// the server configuration that is current at the time the connection is accepted.
+pub struct TlsAcceptor<A: Accept> {
+    inner: Pin<Box<A>>,
+    tls: Arc<ServerTls>,
+    handshakes: FuturesUnordered<Handshake<A::Connection>>,
+    inner_done: bool,
+}
+
+impl<A: Accept> TlsAcceptor<A> {
+    pub fn new(inner: A, tls: Arc<ServerTls>) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            tls,
+            handshakes: FuturesUnordered::new(),
+            inner_done: false,
+        }
+    }
+}
+
+impl<A: Accept> HyperAccept for TlsAcceptor<A> {
+    type Conn = TlsConn<A::Connection>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        while !this.inner_done {
+            match this.inner.as_mut().poll_accept(cx) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    let acceptor = tokio_rustls::TlsAcceptor::from(this.tls.current());
+                    this.handshakes.push(Box::pin(async move {
+                        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn))
+                            .await
+                            .map_err(|_| {
+                                io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+                            })??;
+                        Ok(TlsConn { stream })
+                    }));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => this.inner_done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match ready!(this.handshakes.poll_next_unpin(cx)) {
+                Some(Ok(conn)) => return Poll::Ready(Some(Ok(conn))),
+                Some(Err(e)) => tracing::warn!("failed to accept gRPC TLS connection: {e}"),
+                None if this.inner_done => return Poll::Ready(None),
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<A: Accept> Accept for TlsAcceptor<A> {
+    type Connection = TlsConn<A::Connection>;
+}
+
+pin_project! {
+    /// A TLS connection, that exposes the connection info of the underlying connection.
+    pub struct TlsConn<C> {
+        #[pin]
+        stream: tokio_rustls::server::TlsStream<C>,
+    }
+}
+
+impl<C: Conn> Conn for TlsConn<C> {}
+
+impl<C: Connected> Connected for TlsConn<C> {
+    type ConnectInfo = C::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.stream.get_ref().0.connect_info()
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsConn<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsConn<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+/// A connector that establishes TLS over the connections of the inner connector, with the client
+/// configuration that is current at the time the connection is made.
+pub struct TlsConnector<C> {
+    inner: C,
+    tls: Arc<ClientTls>,
+    domain: rustls::ServerName,
+}
+
+impl<C> TlsConnector<C> {
+    pub fn new(inner: C, tls: Arc<ClientTls>) -> anyhow::Result<Self> {
+        let domain = tls.config.domain.as_deref().unwrap_or(DEFAULT_TLS_DOMAIN);
+        let domain = rustls::ServerName::try_from(domain)
+            .map_err(|_| anyhow::anyhow!("invalid TLS domain name `{domain}`"))?;
+        Ok(Self { inner, tls, domain })
+    }
+}
+
+impl<C> Service<Uri> for TlsConnector<C>
+where
+    C: MakeConnection<Uri>,
+    C::Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = tokio_rustls::client::TlsStream<C::Connection>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connect = self.inner.make_connection(uri);
+        let connector = tokio_rustls::TlsConnector::from(self.tls.current());
+        let domain = self.domain.clone();
+        Box::pin(async move {
+            let conn = connect.await.map_err(Into::into)?;
+            let stream = connector.connect(domain, conn).await?;
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    fn new_ca() -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    /// Writes a certificate for `name` signed by `ca`, its key and the CA certificate in `dir`,
+    /// and returns their configuration.
+    fn write_tls(dir: &Path, ca: &rcgen::Certificate, name: &str) -> TlsConfig {
+        let cert =
+            rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![name.to_string()]))
+                .unwrap();
+        let path = |file: &str| dir.join(format!("{name}-{file}.pem"));
+        std::fs::write(path("cert"), cert.serialize_pem_with_signer(ca).unwrap()).unwrap();
+        std::fs::write(path("key"), cert.serialize_private_key_pem()).unwrap();
+        std::fs::write(path("ca"), ca.serialize_pem().unwrap()).unwrap();
+        TlsConfig {
+            cert: TlsMaterial::Path(path("cert")),
+            key: TlsMaterial::Path(path("key")),
+            ca_cert: TlsMaterial::Path(path("ca")),
+            domain: None,
+        }
+    }
+
+    /// Performs a TLS handshake between the current configurations of `server` and `client`.
+    async fn handshake(server: &ServerTls, client: &ClientTls) -> io::Result<()> {
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let acceptor = tokio_rustls::TlsAcceptor::from(server.current());
+        let connector = tokio_rustls::TlsConnector::from(client.current());
+        let domain = rustls::ServerName::try_from(DEFAULT_TLS_DOMAIN).unwrap();
+        let (accepted, connected) = tokio::join!(
+            acceptor.accept(server_io),
+            connector.connect(domain, client_io)
+        );
+        accepted?;
+        connected?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_tls_from_files_and_inline_pem() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ca = new_ca();
+        let server_config = write_tls(tmp.path(), &ca, DEFAULT_TLS_DOMAIN);
+        let client_config = write_tls(tmp.path(), &ca, "replica");
+
+        let server = ServerTls::load(server_config.clone()).unwrap();
+        let client = ClientTls::load(client_config.clone()).unwrap();
+        handshake(&server, &client).await.unwrap();
+
+        let inline = |material: &TlsMaterial| {
+            TlsMaterial::Pem(String::from_utf8(material.read().unwrap()).unwrap())
+        };
+        let client = ClientTls::load(TlsConfig {
+            cert: inline(&client_config.cert),
+            key: inline(&client_config.key),
+            ca_cert: inline(&client_config.ca_cert),
+            domain: None,
+        })
+        .unwrap();
+        handshake(&server, &client).await.unwrap();
+    }
+
+    #[test]
+    fn load_rejects_invalid_tls() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ca = new_ca();
+        let config = write_tls(tmp.path(), &ca, DEFAULT_TLS_DOMAIN);
+
+        // the server certificate must be valid for the domain the clients use
+        assert!(ServerTls::load(TlsConfig {
+            domain: Some("primary.example.com".into()),
+            ..config.clone()
+        })
+        .is_err());
+
+        // the key must match the certificate
+        let other = write_tls(tmp.path(), &ca, "other");
+        assert!(ServerTls::load(TlsConfig {
+            key: other.key,
+            ..config.clone()
+        })
+        .is_err());
+
+        assert!(ClientTls::load(TlsConfig {
+            cert: TlsMaterial::Path(tmp.path().join("missing.pem")),
+            ..config
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_tls() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ca = new_ca();
+        let server_config = write_tls(tmp.path(), &ca, DEFAULT_TLS_DOMAIN);
+        let client_config = write_tls(tmp.path(), &ca, "replica");
+        let server = ServerTls::load(server_config.clone()).unwrap();
+        let client = ClientTls::load(client_config).unwrap();
+        handshake(&server, &client).await.unwrap();
+
+        // a failed reload keeps the current configuration
+        let TlsMaterial::Path(cert_path) = &server_config.cert else {
+            unreachable!()
+        };
+        std::fs::write(cert_path, "not a certificate").unwrap();
+        let current = server.current();
+        assert!(server.reload().is_err());
+        assert!(Arc::ptr_eq(&current, &server.current()));
+        handshake(&server, &client).await.unwrap();
+
+        // the server is moved to another CA, which the client doesn't trust yet
+        let new_ca = new_ca();
+        write_tls(tmp.path(), &new_ca, DEFAULT_TLS_DOMAIN);
+        server.reload().unwrap();
+        assert!(!Arc::ptr_eq(&current, &server.current()));
+        assert!(handshake(&server, &client).await.is_err());
+
+        write_tls(tmp.path(), &new_ca, "replica");
+        client.reload().unwrap();
+        handshake(&server, &client).await.unwrap();
+    }
+}
@@ -1,11 +1,16 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{File, OpenOptions};
 use std::io::Seek;
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use serde::{Deserialize, Serialize};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::broadcast;
+
+use crate::query_analysis::{Statement, StmtKind};
 
 #[derive(Clone, Default)]
 pub struct Stats {
@@ -19,6 +24,168 @@ struct StatsInner {
     storage_bytes_used: AtomicU64,
     // number of write requests delegated from a replica to primary
     write_requests_delegated: AtomicU64,
+    // number of times a replica had to reconnect to the primary
+    #[serde(default)]
+    replica_reconnect_attempts: AtomicU64,
+    // number of times a write delegated to the primary was retried
+    #[serde(default)]
+    retried_writes: AtomicU64,
+    // state of the replica channel to the primary, see [`PrimaryConnectionState`]
+    #[serde(skip)]
+    primary_connection_state: AtomicU8,
+    // time at which the replica lost its connection to the primary, if it is reconnecting
+    #[serde(skip)]
+    primary_disconnected_at: Mutex<Option<Instant>>,
+    // number of statements executed in each namespace, see [`StatementCounts`]. Only the time of
+    // the last write of the namespaces is persisted.
+    #[serde(
+        default,
+        serialize_with = "serialize_statement_counts",
+        deserialize_with = "deserialize_statement_counts"
+    )]
+    statement_counts: RwLock<BTreeMap<String, Arc<StatementCounts>>>,
+    // last slow queries of each namespace, see [`SlowQueries`]
+    #[serde(skip)]
+    slow_queries: RwLock<BTreeMap<String, Arc<SlowQueries>>>,
+    // schema changes of each namespace, see [`SchemaEvent`]
+    #[serde(skip)]
+    schema_events: RwLock<BTreeMap<String, broadcast::Sender<SchemaEvent>>>,
+}
+
+/// Number of statements executed in a namespace, by class, and time of its last write.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StatementCounts {
+    #[serde(skip)]
+    reads: AtomicU64,
+    #[serde(skip)]
+    writes: AtomicU64,
+    #[serde(skip)]
+    ddl: AtomicU64,
+    #[serde(skip)]
+    transaction_control: AtomicU64,
+    // unix timestamp of the last write, in milliseconds, or 0 if the namespace was never written
+    #[serde(default)]
+    last_write_ms: AtomicU64,
+}
+
+impl StatementCounts {
+    /// increments the count of the class of the statement, as determined when it was parsed
+    pub fn record(&self, stmt: &Statement) {
+        let counter = match stmt.kind {
+            StmtKind::TxnBegin
+            | StmtKind::TxnEnd
+            | StmtKind::Savepoint
+            | StmtKind::Release
+            | StmtKind::RollbackTo => &self.transaction_control,
+            _ if stmt.is_ddl => &self.ddl,
+            StmtKind::Write => &self.writes,
+            StmtKind::Read | StmtKind::Attach | StmtKind::Detach | StmtKind::Other => &self.reads,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// records that a write statement was executed now. The time of the last write never goes
+    /// backwards, even if the clock does.
+    pub fn record_write(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.last_write_ms.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// returns the unix timestamp of the last write, in milliseconds
+    pub fn last_write_ms(&self) -> Option<u64> {
+        match self.last_write_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    /// returns the counts labelled by class
+    pub fn by_class(&self) -> [(&'static str, u64); 4] {
+        [
+            ("read", self.reads.load(Ordering::Relaxed)),
+            ("write", self.writes.load(Ordering::Relaxed)),
+            ("ddl", self.ddl.load(Ordering::Relaxed)),
+            (
+                "transaction_control",
+                self.transaction_control.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+/// Number of slow queries kept for each namespace.
+pub const MAX_SLOW_QUERIES: usize = 100;
+
+/// A program whose execution took longer than the slow query threshold of its namespace.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowQuery {
+    /// unix timestamp of the end of the execution, in milliseconds
+    pub timestamp_ms: u64,
+    /// SQL text of the statements of the program, truncated
+    pub sql: String,
+    pub duration_us: u64,
+    /// number of rows returned by the program
+    pub rows: u64,
+}
+
+/// The last [`MAX_SLOW_QUERIES`] slow queries of a namespace, oldest first.
+#[derive(Debug, Default)]
+pub struct SlowQueries {
+    queries: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl SlowQueries {
+    /// records a slow query, evicting the oldest one if the buffer is full
+    pub fn record(&self, query: SlowQuery) {
+        let mut queries = self.queries.lock();
+        if queries.len() >= MAX_SLOW_QUERIES {
+            queries.pop_front();
+        }
+        queries.push_back(query);
+    }
+
+    /// returns the recorded slow queries, oldest first
+    pub fn list(&self) -> Vec<SlowQuery> {
+        self.queries.lock().iter().cloned().collect()
+    }
+}
+
+/// Number of schema events buffered for each subscriber, past which a slow subscriber misses
+/// events.
+const SCHEMA_EVENTS_CAPACITY: usize = 256;
+
+/// An event about the schema of a namespace, sent to the subscribers of its schema events.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SchemaEvent {
+    /// A DDL statement completed successfully.
+    SchemaChange {
+        /// schema cookie of the database once the statement was committed
+        schema_version: i64,
+        statement: String,
+    },
+}
+
+/// State of the connection from a replica to its primary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PrimaryConnectionState {
+    /// This instance doesn't replicate from a primary.
+    None = 0,
+    Connected = 1,
+    Reconnecting = 2,
+}
+
+impl PrimaryConnectionState {
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Connected => Some("connected"),
+            Self::Reconnecting => Some("reconnecting"),
+        }
+    }
 }
 
 impl Stats {
@@ -78,6 +245,150 @@ impl Stats {
     pub fn write_requests_delegated(&self) -> u64 {
         self.inner.write_requests_delegated.load(Ordering::Relaxed)
     }
+
+    /// increments the number of reconnections of a replica to its primary
+    pub fn inc_replica_reconnect_attempts(&self) {
+        self.inner
+            .replica_reconnect_attempts
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn replica_reconnect_attempts(&self) -> u64 {
+        self.inner
+            .replica_reconnect_attempts
+            .load(Ordering::Relaxed)
+    }
+
+    /// increments the number of retries of writes delegated to the primary
+    pub fn inc_retried_writes(&self) {
+        self.inner.retried_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn retried_writes(&self) -> u64 {
+        self.inner.retried_writes.load(Ordering::Relaxed)
+    }
+
+    pub fn set_primary_connection_state(&self, state: PrimaryConnectionState) {
+        let mut disconnected_at = self.inner.primary_disconnected_at.lock();
+        match state {
+            PrimaryConnectionState::Reconnecting => {
+                disconnected_at.get_or_insert_with(Instant::now);
+            }
+            PrimaryConnectionState::Connected | PrimaryConnectionState::None => {
+                *disconnected_at = None
+            }
+        }
+        self.inner
+            .primary_connection_state
+            .store(state as u8, Ordering::Relaxed);
+    }
+
+    /// returns how far behind its primary a replica may be: the time since it lost its
+    /// connection to the primary, or zero while it is connected, as the primary streams its
+    /// writes as they happen. Returns `None` if this instance is not a replica.
+    pub fn replication_lag(&self) -> Option<Duration> {
+        match self.primary_connection_state() {
+            PrimaryConnectionState::None => None,
+            PrimaryConnectionState::Connected => Some(Duration::ZERO),
+            PrimaryConnectionState::Reconnecting => Some(
+                self.inner
+                    .primary_disconnected_at
+                    .lock()
+                    .map_or(Duration::ZERO, |at| at.elapsed()),
+            ),
+        }
+    }
+
+    /// returns the statement counts of a namespace, which the connections to it update
+    pub fn statement_counts(&self, namespace: &str) -> Arc<StatementCounts> {
+        if let Some(counts) = self.inner.statement_counts.read().get(namespace) {
+            return counts.clone();
+        }
+        self.inner
+            .statement_counts
+            .write()
+            .entry(namespace.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// returns the slow queries of a namespace, which the connections to it record
+    pub fn slow_queries(&self, namespace: &str) -> Arc<SlowQueries> {
+        if let Some(queries) = self.inner.slow_queries.read().get(namespace) {
+            return queries.clone();
+        }
+        self.inner
+            .slow_queries
+            .write()
+            .entry(namespace.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// returns the channel of the schema events of a namespace, which the connections to it send
+    /// to
+    pub fn schema_events(&self, namespace: &str) -> broadcast::Sender<SchemaEvent> {
+        if let Some(events) = self.inner.schema_events.read().get(namespace) {
+            return events.clone();
+        }
+        self.inner
+            .schema_events
+            .write()
+            .entry(namespace.to_string())
+            .or_insert_with(|| broadcast::channel(SCHEMA_EVENTS_CAPACITY).0)
+            .clone()
+    }
+
+    /// returns the statement counts of all the namespaces, ordered by namespace
+    pub fn all_statement_counts(&self) -> Vec<(String, Arc<StatementCounts>)> {
+        self.inner
+            .statement_counts
+            .read()
+            .iter()
+            .map(|(namespace, counts)| (namespace.clone(), counts.clone()))
+            .collect()
+    }
+
+    /// returns the unix timestamp of the last write to a namespace, in milliseconds
+    pub fn namespace_last_write_ms(&self, namespace: &str) -> Option<u64> {
+        self.inner
+            .statement_counts
+            .read()
+            .get(namespace)
+            .and_then(|counts| counts.last_write_ms())
+    }
+
+    /// returns the unix timestamps of the last write to the namespaces which were written, in
+    /// milliseconds
+    pub fn all_last_writes_ms(&self) -> BTreeMap<String, u64> {
+        self.inner
+            .statement_counts
+            .read()
+            .iter()
+            .filter_map(|(namespace, counts)| Some((namespace.clone(), counts.last_write_ms()?)))
+            .collect()
+    }
+
+    pub fn primary_connection_state(&self) -> PrimaryConnectionState {
+        match self.inner.primary_connection_state.load(Ordering::Relaxed) {
+            1 => PrimaryConnectionState::Connected,
+            2 => PrimaryConnectionState::Reconnecting,
+            _ => PrimaryConnectionState::None,
+        }
+    }
+}
+
+fn serialize_statement_counts<S: Serializer>(
+    counts: &RwLock<BTreeMap<String, Arc<StatementCounts>>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    counts.read().serialize(serializer)
+}
+
+fn deserialize_statement_counts<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<RwLock<BTreeMap<String, Arc<StatementCounts>>>, D::Error> {
+    BTreeMap::deserialize(deserializer).map(RwLock::new)
 }
 
 fn spawn_stats_persist_thread(stats: Arc<StatsInner>, mut file: File) {
@@ -89,3 +400,30 @@ fn spawn_stats_persist_thread(stats: Arc<StatsInner>, mut file: File) {
         std::thread::sleep(Duration::from_secs(5));
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replication_lag() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        // not a replica
+        assert_eq!(stats.replication_lag(), None);
+
+        stats.set_primary_connection_state(PrimaryConnectionState::Connected);
+        assert_eq!(stats.replication_lag(), Some(Duration::ZERO));
+
+        stats.set_primary_connection_state(PrimaryConnectionState::Reconnecting);
+        std::thread::sleep(Duration::from_millis(10));
+        let lag = stats.replication_lag().unwrap();
+        assert!(lag >= Duration::from_millis(10));
+        // retries keep the time of the disconnection
+        stats.set_primary_connection_state(PrimaryConnectionState::Reconnecting);
+        assert!(stats.replication_lag().unwrap() >= lag);
+
+        stats.set_primary_connection_state(PrimaryConnectionState::Connected);
+        assert_eq!(stats.replication_lag(), Some(Duration::ZERO));
+    }
+}
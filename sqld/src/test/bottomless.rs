@@ -40,26 +40,35 @@ async fn configure_server(
     let http_acceptor = AddrIncoming::new(tokio::net::TcpListener::bind(addr).await.unwrap());
     Server {
         db_config: DbConfig {
-            extensions_path: None,
             bottomless_replication: Some(options.clone()),
             max_log_size: 200 * 4046,
-            max_log_duration: None,
-            soft_heap_limit_mb: None,
-            hard_heap_limit_mb: None,
             max_response_size: 10000000 * 4096,
             max_total_response_size: 10000000 * 4096,
-            snapshot_exec: None,
-            checkpoint_interval: None,
+            statement_cache_size: 128,
+            max_query_params: crate::DEFAULT_MAX_QUERY_PARAMS,
+            ..DbConfig::new_test()
         },
         admin_api_config: None,
         disable_namespaces: true,
         user_api_config: UserApiConfig {
             hrana_ws_acceptor: None,
             http_acceptor: Some(http_acceptor),
+            hrana_uds_acceptor: None,
             enable_http_console: false,
             self_url: None,
             http_auth: None,
             auth_jwt_key: None,
+            auth_audit_log: None,
+            hrana_stream_idle_timeout: None,
+            hrana_max_streams_per_session: 100,
+            hrana_fetch_cursor_size_divisor: 8,
+            hrana_max_sql_count: 150,
+            hrana_max_sql_bytes: 32 * 1024 * 1024,
+            hrana_ws_ping_interval: None,
+            hrana_ws_max_missed_pongs: 3,
+            max_request_size: 64 * 1024 * 1024,
+            cursor_idle_timeout: Duration::from_secs(60),
+            ready_max_replication_lag: Duration::from_secs(30),
         },
         path: path.into().into(),
         disable_default_namespace: false,
@@ -316,6 +325,46 @@ async fn rollback_restore() {
     }
 }
 
+#[tokio::test]
+async fn dump_to_s3() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const BUCKET: &str = "testdumptos3";
+    const KEY: &str = "dumps/test.sql";
+
+    let _ = S3BucketCleaner::new(BUCKET).await;
+    let client = s3_client().await.unwrap();
+    let _ = client.create_bucket().bucket(BUCKET).send().await;
+
+    let options = bottomless::replicator::Options {
+        bucket_name: BUCKET.to_string(),
+        ..bottomless::replicator::Options::from_env().unwrap()
+    };
+    let tmp = tempfile::tempdir().unwrap();
+    let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+    // the blob is dumped as hex, so the dump spans several parts
+    conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (zeroblob(6 * 1024 * 1024));")
+        .unwrap();
+
+    tokio::task::spawn_blocking(move || {
+        crate::connection::dump::s3::export_dump_to_s3(conn, &options, KEY)
+    })
+    .await
+    .unwrap()
+    .unwrap();
+
+    let object = client
+        .get_object()
+        .bucket(BUCKET)
+        .key(KEY)
+        .send()
+        .await
+        .unwrap();
+    let dump = object.body.collect().await.unwrap().into_bytes();
+    assert!(dump.len() > 12 * 1024 * 1024);
+    assert!(dump.starts_with(b"PRAGMA foreign_keys=OFF;\nBEGIN TRANSACTION;\nCREATE TABLE"));
+    assert!(dump.ends_with(b"COMMIT;\n"));
+}
+
 async fn perform_updates(connection_addr: &Url, row_count: usize, ops_count: usize, update: &str) {
     let stmts: Vec<_> = (0..ops_count)
         .map(|i| {
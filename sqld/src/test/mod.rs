@@ -1 +1,2 @@
 mod bottomless;
+pub mod namespaces;
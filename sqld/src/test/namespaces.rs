@@ -0,0 +1,44 @@
+//! Namespaces of a primary, and helpers to query them.
+
+use std::path::Path;
+
+use crate::auth::{Authenticated, Authorized};
+use crate::connection::program::Program;
+use crate::connection::{Connection, MakeConnection};
+use crate::namespace::{NamespaceStore, PrimaryNamespaceConfig, PrimaryNamespaceMaker};
+use crate::query_result_builder::{QueryResultBuilder, RecordedCall, StepRecorder};
+use crate::stats::Stats;
+
+/// Returns a store of primary namespaces, stored in `path`.
+pub fn primary_namespaces(path: &Path) -> NamespaceStore<PrimaryNamespaceMaker> {
+    let stats = Stats::new(path).unwrap();
+    let config = PrimaryNamespaceConfig::new_test(path, stats);
+    NamespaceStore::new(PrimaryNamespaceMaker::new(config), false)
+}
+
+/// Executes `sql` on the namespace `foo`, and returns the calls made to the result builder.
+pub async fn execute(
+    namespaces: &NamespaceStore<PrimaryNamespaceMaker>,
+    sql: &str,
+) -> crate::Result<Vec<RecordedCall>> {
+    let conn = namespaces
+        .with("foo".into(), |ns| ns.db.connection_maker())
+        .await?
+        .create()
+        .await?;
+    let (recorder, _) = conn
+        .execute_program(
+            Program::seq(&[sql]),
+            Authenticated::Authorized(Authorized::FullAccess),
+            StepRecorder::default(),
+        )
+        .await?;
+    Ok(recorder.into_ret())
+}
+
+/// Returns whether the calls made to a result builder added the integer `value` to a row.
+pub fn has_row_value(calls: &[RecordedCall], value: i64) -> bool {
+    calls.iter().any(|call| {
+        matches!(call, RecordedCall::AddRowValue(rusqlite::types::Value::Integer(v)) if *v == value)
+    })
+}
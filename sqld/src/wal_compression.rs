@@ -0,0 +1,787 @@
+//! Compression of the SQLite WAL of namespaces.
+//!
+//! Like the encryption of the WAL, compression is implemented by a VFS shim, registered as the
+//! default VFS, which wraps the original default VFS. When a WAL file is opened for a database
+//! which was registered with [enable], connections see the usual layout of a WAL, with frames at
+//! fixed offsets, but the file on disk is a log of the writes made to the WAL: every write is
+//! appended as a record holding its offset in the WAL and its content, compressed with LZ4 or
+//! zstd. Reads look up the records covering the requested range, and decompress them.
+//!
+//! The file starts with a header holding [FILE_MAGIC], the codec of the records and a random
+//! generation. The log is reset, with a new generation, whenever SQLite writes the header of the
+//! WAL, which happens when the WAL is restarted after a checkpoint, so it doesn't grow forever.
+//! Every connection keeps an index of the records, which it brings up to date before accessing
+//! the WAL, from the generation and the size of the file.
+//!
+//! The headers of the records are checksummed, so that a record torn by a crash ends the log.
+//! Its content is checked by SQLite, from the checksums of the frames, like a torn frame of a
+//! plain WAL.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Once;
+
+use anyhow::bail;
+use crc::Crc;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use sqld_libsql_bindings::ffi::{
+    sqlite3_file, sqlite3_int64, sqlite3_io_methods, sqlite3_vfs, sqlite3_vfs_find,
+    sqlite3_vfs_register, SQLITE_CANTOPEN, SQLITE_FCNTL_SIZE_HINT, SQLITE_IOERR_READ,
+    SQLITE_IOERR_SHORT_READ, SQLITE_OK, SQLITE_OPEN_WAL,
+};
+
+use crate::wal_encryption::{self, canonical_db_path, wal_path};
+
+/// Header of the compressed WAL file.
+const FILE_MAGIC: &[u8; 8] = b"SQLDWALZ";
+const FILE_HEADER_SIZE: u64 = 24;
+/// A record header holds the offset of the write in the WAL, its length, the length of the
+/// stored content and a checksum of these fields. A record of length 0 truncates the WAL.
+const RECORD_HEADER_SIZE: u64 = 20;
+/// Writes shorter than this, such as the headers of the WAL and of its frames, are stored as is.
+const MIN_COMPRESSED_SIZE: usize = 64;
+/// Pages are compressed on the write path, so we favor speed over ratio.
+const ZSTD_LEVEL: i32 = 1;
+
+const RECORD_CRC: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+const VFS_NAME: &[u8] = b"sqld-wal-compression\0";
+
+/// Codecs of the databases whose WAL is compressed, by the canonical path of their main file.
+static CODECS: Lazy<RwLock<HashMap<PathBuf, Codec>>> = Lazy::new(Default::default);
+
+/// Algorithm compressing the pages of the WAL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Codec::Lz4 => Some(lz4_flex::block::compress(data)),
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+        }
+    }
+
+    /// Decompresses `data` into `out`, and returns whether it filled `out` exactly.
+    fn decompress(self, data: &[u8], out: &mut [u8]) -> bool {
+        let len = out.len();
+        match self {
+            Codec::Lz4 => lz4_flex::block::decompress_into(data, out).is_ok_and(|n| n == len),
+            Codec::Zstd => zstd::bulk::decompress_to_buffer(data, out).is_ok_and(|n| n == len),
+        }
+    }
+}
+
+/// Registers the compressing VFS as the default VFS. It's a no-op for databases which weren't
+/// registered with [enable].
+pub fn register() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| unsafe {
+        let underlying = sqlite3_vfs_find(std::ptr::null());
+        assert!(!underlying.is_null(), "no default VFS registered");
+        // the methods we don't override are called with our VFS, so it carries the app data of
+        // the underlying VFS
+        let mut vfs = std::ptr::read(underlying);
+        vfs.szOsFile = (std::mem::size_of::<CompressedFile>() as c_int) + (*underlying).szOsFile;
+        vfs.pNext = std::ptr::null_mut();
+        vfs.zName = VFS_NAME.as_ptr() as *const c_char;
+        vfs.xOpen = Some(x_open);
+        UNDERLYING.store(underlying, Ordering::Release);
+        let rc = sqlite3_vfs_register(Box::into_raw(Box::new(vfs)), 1);
+        assert_eq!(rc, SQLITE_OK, "failed to register the WAL compression VFS");
+    });
+}
+
+/// Compresses the WAL of the database stored in `db_file` with `codec`. It must be enabled before
+/// any connection to the database is opened.
+pub fn enable(db_file: &Path, codec: Codec) -> anyhow::Result<()> {
+    CODECS.write().insert(canonical_db_path(db_file)?, codec);
+    Ok(())
+}
+
+/// Prepares the WAL of the database stored in `db_file` to be opened with or without compression.
+///
+/// A plain WAL left from before compression was enabled is checkpointed, so that the WAL is
+/// compressed from scratch. On the other hand, a compressed WAL can't be opened without
+/// compression.
+pub fn prepare(db_file: &Path, compressed: bool) -> anyhow::Result<()> {
+    let wal_path = wal_path(db_file);
+    let mut magic = Vec::with_capacity(FILE_MAGIC.len());
+    match File::open(&wal_path) {
+        Ok(file) => {
+            file.take(FILE_MAGIC.len() as u64).read_to_end(&mut magic)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    let wal_is_empty = magic.is_empty();
+    let wal_is_compressed = magic == FILE_MAGIC;
+
+    match (compressed, wal_is_compressed) {
+        (true, false) if !wal_is_empty => {
+            // the frames of an encrypted WAL can't be read without its key
+            if wal_encryption::is_encrypted(db_file)? {
+                bail!(
+                    "the WAL of {} is encrypted, but no WAL master key was provided",
+                    db_file.display()
+                );
+            }
+            tracing::info!("checkpointing uncompressed WAL of {}", db_file.display());
+            let conn = rusqlite::Connection::open(db_file)?;
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))?;
+            drop(conn);
+            if std::fs::metadata(&wal_path).is_ok_and(|meta| meta.len() > 0) {
+                bail!(
+                    "failed to checkpoint the uncompressed WAL of {}",
+                    db_file.display()
+                );
+            }
+        }
+        (false, true) => bail!(
+            "the WAL of {} is compressed, but WAL compression is disabled",
+            db_file.display()
+        ),
+        _ => (),
+    }
+    Ok(())
+}
+
+/// The VFS wrapped by the compressing VFS.
+static UNDERLYING: AtomicPtr<sqlite3_vfs> = AtomicPtr::new(std::ptr::null_mut());
+
+#[repr(C)]
+struct CompressedFile {
+    base: sqlite3_file,
+    /// File opened by the underlying VFS, allocated right after this struct.
+    inner: *mut sqlite3_file,
+    state: *mut WalState,
+}
+
+/// Location of the content of a write in the file.
+#[derive(Clone, Copy)]
+struct Extent {
+    /// Length of the write in the WAL.
+    len: u32,
+    /// Offset of the stored content in the file.
+    position: u64,
+    /// Length of the stored content, which is equal to `len` if it's not compressed.
+    stored: u32,
+}
+
+impl Extent {
+    fn end(&self, start: u64) -> u64 {
+        start + self.len as u64
+    }
+}
+
+struct WalState {
+    /// Codec of the records written after the next reset of the log.
+    codec: Codec,
+    /// Generation and codec of the log, or `None` if the file is empty.
+    log: Option<(u64, Codec)>,
+    /// Records of the log which were read so far, by their offset in the WAL. They don't overlap:
+    /// a record replaces the records it overlaps.
+    extents: BTreeMap<u64, Extent>,
+    /// Size of the part of the file which was read.
+    scanned: u64,
+    /// Size of the WAL seen by SQLite.
+    wal_size: u64,
+}
+
+fn encode_record_header(offset: u64, len: u32, stored: u32) -> [u8; RECORD_HEADER_SIZE as usize] {
+    let mut header = [0; RECORD_HEADER_SIZE as usize];
+    header[0..8].copy_from_slice(&offset.to_be_bytes());
+    header[8..12].copy_from_slice(&len.to_be_bytes());
+    header[12..16].copy_from_slice(&stored.to_be_bytes());
+    let crc = RECORD_CRC.checksum(&header[..16]);
+    header[16..20].copy_from_slice(&crc.to_be_bytes());
+    header
+}
+
+/// Returns the offset, the length and the stored length of a record, or `None` if the header is
+/// torn.
+fn decode_record_header(header: &[u8; RECORD_HEADER_SIZE as usize]) -> Option<(u64, u32, u32)> {
+    let crc = u32::from_be_bytes(header[16..20].try_into().unwrap());
+    if RECORD_CRC.checksum(&header[..16]) != crc {
+        return None;
+    }
+    Some((
+        u64::from_be_bytes(header[0..8].try_into().unwrap()),
+        u32::from_be_bytes(header[8..12].try_into().unwrap()),
+        u32::from_be_bytes(header[12..16].try_into().unwrap()),
+    ))
+}
+
+impl WalState {
+    fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            log: None,
+            extents: BTreeMap::new(),
+            scanned: 0,
+            wal_size: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.log = None;
+        self.extents.clear();
+        self.scanned = 0;
+        self.wal_size = 0;
+    }
+
+    /// Returns the extents overlapping `offset..end`, from the last one.
+    fn overlapping(&self, offset: u64, end: u64) -> impl Iterator<Item = (u64, Extent)> + '_ {
+        self.extents
+            .range(..end)
+            .rev()
+            .take_while(move |(&start, extent)| extent.end(start) > offset)
+            .map(|(&start, extent)| (start, *extent))
+    }
+
+    fn apply_write(&mut self, offset: u64, extent: Extent) {
+        let end = extent.end(offset);
+        let replaced: Vec<u64> = self
+            .overlapping(offset, end)
+            .map(|(start, _)| start)
+            .collect();
+        for start in replaced {
+            self.extents.remove(&start);
+        }
+        self.extents.insert(offset, extent);
+        self.wal_size = self.wal_size.max(end);
+    }
+
+    fn apply_truncate(&mut self, size: u64) {
+        self.extents.split_off(&size);
+        self.wal_size = size;
+    }
+
+    /// Reads the records appended to the file since the last call, e.g. by another connection.
+    unsafe fn refresh(&mut self, inner: *mut sqlite3_file) -> Result<(), c_int> {
+        let size = file_size(inner)?;
+        if size < FILE_HEADER_SIZE {
+            // the file is empty, or its header was torn right after a reset
+            self.clear();
+            return Ok(());
+        }
+        let mut header = [0; FILE_HEADER_SIZE as usize];
+        read_exact(inner, &mut header, 0)?;
+        let codec = Codec::from_id(header[8]);
+        let (Some(codec), true) = (codec, header[..FILE_MAGIC.len()] == FILE_MAGIC[..]) else {
+            tracing::error!("invalid header in a compressed WAL");
+            return Err(SQLITE_IOERR_READ);
+        };
+        let generation = u64::from_be_bytes(header[16..24].try_into().unwrap());
+        if self.log != Some((generation, codec)) || size < self.scanned {
+            self.clear();
+            self.log = Some((generation, codec));
+            self.scanned = FILE_HEADER_SIZE;
+        }
+
+        while self.scanned + RECORD_HEADER_SIZE <= size {
+            let mut header = [0; RECORD_HEADER_SIZE as usize];
+            read_exact(inner, &mut header, self.scanned)?;
+            let Some((offset, len, stored)) = decode_record_header(&header) else {
+                break;
+            };
+            let position = self.scanned + RECORD_HEADER_SIZE;
+            let record_end = if len == 0 {
+                position
+            } else {
+                position + stored as u64
+            };
+            if record_end > size {
+                break;
+            }
+            if len == 0 {
+                self.apply_truncate(offset);
+            } else {
+                self.apply_write(
+                    offset,
+                    Extent {
+                        len,
+                        position,
+                        stored,
+                    },
+                );
+            }
+            self.scanned = record_end;
+        }
+        Ok(())
+    }
+
+    /// Prepares the file for a record to be appended: drops what follows the last valid record,
+    /// which was torn by a crash, and writes the header of the file if it's empty.
+    unsafe fn prepare_append(&mut self, inner: *mut sqlite3_file) -> Result<(), c_int> {
+        self.refresh(inner)?;
+        if file_size(inner)? > self.scanned {
+            truncate(inner, self.scanned)?;
+        }
+        if self.log.is_none() {
+            let generation = rand::random::<u64>();
+            let mut header = [0; FILE_HEADER_SIZE as usize];
+            header[..FILE_MAGIC.len()].copy_from_slice(FILE_MAGIC);
+            header[8] = self.codec.id();
+            header[16..24].copy_from_slice(&generation.to_be_bytes());
+            write_all(inner, &header, 0)?;
+            self.log = Some((generation, self.codec));
+            self.scanned = FILE_HEADER_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes at `offset` of the WAL, and returns whether they were all within
+    /// the WAL. The bytes past the end of the WAL are zeroed.
+    unsafe fn read(
+        &self,
+        inner: *mut sqlite3_file,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> Result<bool, c_int> {
+        buf.fill(0);
+        let end = offset + buf.len() as u64;
+        let codec = match self.log {
+            Some((_, codec)) => codec,
+            None => return Ok(end <= self.wal_size),
+        };
+        for (start, extent) in self.overlapping(offset, end) {
+            let Some(content) = self.load(inner, codec, &extent)? else {
+                if start > offset {
+                    // the read covers the header of the frame, e.g. during recovery: a frame
+                    // whose record was torn is treated as invalid, like a torn frame
+                    continue;
+                }
+                tracing::error!("failed to decompress a record of a compressed WAL at {start}");
+                return Err(SQLITE_IOERR_READ);
+            };
+            let lo = start.max(offset);
+            let hi = extent.end(start).min(end).min(self.wal_size);
+            if lo < hi {
+                buf[(lo - offset) as usize..(hi - offset) as usize]
+                    .copy_from_slice(&content[(lo - start) as usize..(hi - start) as usize]);
+            }
+        }
+        Ok(end <= self.wal_size)
+    }
+
+    /// Loads the content of a record, or returns `None` if it can't be decompressed.
+    unsafe fn load(
+        &self,
+        inner: *mut sqlite3_file,
+        codec: Codec,
+        extent: &Extent,
+    ) -> Result<Option<Vec<u8>>, c_int> {
+        let mut stored = vec![0; extent.stored as usize];
+        read_exact(inner, &mut stored, extent.position)?;
+        if extent.stored == extent.len {
+            return Ok(Some(stored));
+        }
+        let mut content = vec![0; extent.len as usize];
+        Ok(codec.decompress(&stored, &mut content).then_some(content))
+    }
+
+    /// Writes `buf` at `offset` of the WAL.
+    unsafe fn write(
+        &mut self,
+        inner: *mut sqlite3_file,
+        buf: &[u8],
+        offset: u64,
+    ) -> Result<(), c_int> {
+        if offset == 0 {
+            // SQLite writes the header of the WAL when it restarts it, so the log is reset
+            truncate(inner, 0)?;
+            self.clear();
+        }
+        self.prepare_append(inner)?;
+
+        // a write which overlaps records only partially is merged with them, so that the
+        // records never overlap
+        let end = offset + buf.len() as u64;
+        let overlapping: Vec<(u64, Extent)> = self.overlapping(offset, end).collect();
+        let (start, content) = match overlapping[..] {
+            [] => (offset, Cow::Borrowed(buf)),
+            [(start, extent)] if start == offset && extent.len as usize == buf.len() => {
+                (offset, Cow::Borrowed(buf))
+            }
+            _ => {
+                let (last_start, last) = overlapping[0];
+                let (first_start, _) = overlapping[overlapping.len() - 1];
+                let start = first_start.min(offset);
+                let merged_end = last.end(last_start).max(end);
+                let mut merged = vec![0; (merged_end - start) as usize];
+                self.read(inner, &mut merged, start)?;
+                merged[(offset - start) as usize..][..buf.len()].copy_from_slice(buf);
+                (start, Cow::Owned(merged))
+            }
+        };
+
+        let (_, codec) = self.log.expect("the log was prepared");
+        let compressed = if content.len() >= MIN_COMPRESSED_SIZE {
+            codec.compress(&content)
+        } else {
+            None
+        };
+        let stored = match &compressed {
+            Some(compressed) if compressed.len() < content.len() => compressed.as_slice(),
+            _ => &content[..],
+        };
+        let header = encode_record_header(start, content.len() as u32, stored.len() as u32);
+        let mut record = Vec::with_capacity(header.len() + stored.len());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(stored);
+        write_all(inner, &record, self.scanned)?;
+
+        self.apply_write(
+            start,
+            Extent {
+                len: content.len() as u32,
+                position: self.scanned + RECORD_HEADER_SIZE,
+                stored: stored.len() as u32,
+            },
+        );
+        self.scanned += record.len() as u64;
+        Ok(())
+    }
+
+    /// Truncates the WAL to `size` bytes.
+    unsafe fn truncate(&mut self, inner: *mut sqlite3_file, size: u64) -> Result<(), c_int> {
+        if size == 0 {
+            truncate(inner, 0)?;
+            self.clear();
+            return Ok(());
+        }
+        self.prepare_append(inner)?;
+        let header = encode_record_header(size, 0, 0);
+        write_all(inner, &header, self.scanned)?;
+        self.apply_truncate(size);
+        self.scanned += header.len() as u64;
+        Ok(())
+    }
+}
+
+unsafe fn inner_methods(
+    file: *mut sqlite3_file,
+) -> (*mut sqlite3_file, &'static sqlite3_io_methods) {
+    let inner = (*(file as *mut CompressedFile)).inner;
+    (inner, &*(*inner).pMethods)
+}
+
+unsafe fn state<'a>(file: *mut sqlite3_file) -> &'a mut WalState {
+    &mut *(*(file as *mut CompressedFile)).state
+}
+
+unsafe fn file_size(inner: *mut sqlite3_file) -> Result<u64, c_int> {
+    let mut size = 0;
+    match (*(*inner).pMethods).xFileSize.unwrap()(inner, &mut size) {
+        SQLITE_OK => Ok(size as u64),
+        rc => Err(rc),
+    }
+}
+
+unsafe fn read_exact(inner: *mut sqlite3_file, buf: &mut [u8], offset: u64) -> Result<(), c_int> {
+    match (*(*inner).pMethods).xRead.unwrap()(
+        inner,
+        buf.as_mut_ptr() as *mut c_void,
+        buf.len() as c_int,
+        offset as _,
+    ) {
+        SQLITE_OK => Ok(()),
+        // the records are read within the size of the file
+        SQLITE_IOERR_SHORT_READ => Err(SQLITE_IOERR_READ),
+        rc => Err(rc),
+    }
+}
+
+unsafe fn write_all(inner: *mut sqlite3_file, buf: &[u8], offset: u64) -> Result<(), c_int> {
+    match (*(*inner).pMethods).xWrite.unwrap()(
+        inner,
+        buf.as_ptr() as *const c_void,
+        buf.len() as c_int,
+        offset as _,
+    ) {
+        SQLITE_OK => Ok(()),
+        rc => Err(rc),
+    }
+}
+
+unsafe fn truncate(inner: *mut sqlite3_file, size: u64) -> Result<(), c_int> {
+    match (*(*inner).pMethods).xTruncate.unwrap()(inner, size as _) {
+        SQLITE_OK => Ok(()),
+        rc => Err(rc),
+    }
+}
+
+unsafe extern "C" fn x_open(
+    _vfs: *mut sqlite3_vfs,
+    name: *const c_char,
+    file: *mut sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    let underlying = UNDERLYING.load(Ordering::Acquire);
+    let open = (*underlying).xOpen.unwrap();
+    if name.is_null() || flags & SQLITE_OPEN_WAL == 0 {
+        return open(underlying, name, file, flags, out_flags);
+    }
+    let wal_path = PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned());
+    let codec = wal_path
+        .to_str()
+        .and_then(|path| path.strip_suffix("-wal"))
+        .and_then(|db_path| CODECS.read().get(Path::new(db_path)).copied());
+    let Some(codec) = codec else {
+        return open(underlying, name, file, flags, out_flags);
+    };
+
+    let compressed = file as *mut CompressedFile;
+    let inner = (file as *mut u8).add(std::mem::size_of::<CompressedFile>()) as *mut sqlite3_file;
+    (*compressed).base.pMethods = std::ptr::null();
+    let rc = open(underlying, name, inner, flags, out_flags);
+    if rc != SQLITE_OK {
+        return rc;
+    }
+
+    let mut state = WalState::new(codec);
+    if let Err(rc) = state.refresh(inner) {
+        tracing::error!(
+            "failed to open compressed WAL {}: error code {rc}",
+            wal_path.display()
+        );
+        (*(*inner).pMethods).xClose.unwrap()(inner);
+        return SQLITE_CANTOPEN;
+    }
+    (*compressed).inner = inner;
+    (*compressed).state = Box::into_raw(Box::new(state));
+    (*compressed).base.pMethods = &COMPRESSED_IO_METHODS;
+    SQLITE_OK
+}
+
+static COMPRESSED_IO_METHODS: sqlite3_io_methods = sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(x_close),
+    xRead: Some(x_read),
+    xWrite: Some(x_write),
+    xTruncate: Some(x_truncate),
+    xSync: Some(x_sync),
+    xFileSize: Some(x_file_size),
+    xLock: Some(x_lock),
+    xUnlock: Some(x_unlock),
+    xCheckReservedLock: Some(x_check_reserved_lock),
+    xFileControl: Some(x_file_control),
+    xSectorSize: Some(x_sector_size),
+    xDeviceCharacteristics: Some(x_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe extern "C" fn x_close(file: *mut sqlite3_file) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    let rc = methods.xClose.unwrap()(inner);
+    drop(Box::from_raw((*(file as *mut CompressedFile)).state));
+    rc
+}
+
+unsafe extern "C" fn x_read(
+    file: *mut sqlite3_file,
+    buf: *mut c_void,
+    amount: c_int,
+    offset: sqlite3_int64,
+) -> c_int {
+    let (inner, _) = inner_methods(file);
+    let state = state(file);
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, amount as usize);
+    let result = state
+        .refresh(inner)
+        .and_then(|()| state.read(inner, buf, offset as u64));
+    match result {
+        Ok(true) => SQLITE_OK,
+        Ok(false) => SQLITE_IOERR_SHORT_READ,
+        Err(rc) => rc,
+    }
+}
+
+unsafe extern "C" fn x_write(
+    file: *mut sqlite3_file,
+    buf: *const c_void,
+    amount: c_int,
+    offset: sqlite3_int64,
+) -> c_int {
+    let (inner, _) = inner_methods(file);
+    if amount == 0 {
+        return SQLITE_OK;
+    }
+    let buf = std::slice::from_raw_parts(buf as *const u8, amount as usize);
+    match state(file).write(inner, buf, offset as u64) {
+        Ok(()) => SQLITE_OK,
+        Err(rc) => rc,
+    }
+}
+
+unsafe extern "C" fn x_truncate(file: *mut sqlite3_file, size: sqlite3_int64) -> c_int {
+    let (inner, _) = inner_methods(file);
+    match state(file).truncate(inner, size as u64) {
+        Ok(()) => SQLITE_OK,
+        Err(rc) => rc,
+    }
+}
+
+unsafe extern "C" fn x_sync(file: *mut sqlite3_file, flags: c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xSync.unwrap()(inner, flags)
+}
+
+unsafe extern "C" fn x_file_size(file: *mut sqlite3_file, size: *mut sqlite3_int64) -> c_int {
+    let (inner, _) = inner_methods(file);
+    let state = state(file);
+    match state.refresh(inner) {
+        Ok(()) => {
+            *size = state.wal_size as sqlite3_int64;
+            SQLITE_OK
+        }
+        Err(rc) => rc,
+    }
+}
+
+unsafe extern "C" fn x_lock(file: *mut sqlite3_file, lock: c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xLock.unwrap()(inner, lock)
+}
+
+unsafe extern "C" fn x_unlock(file: *mut sqlite3_file, lock: c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xUnlock.unwrap()(inner, lock)
+}
+
+unsafe extern "C" fn x_check_reserved_lock(file: *mut sqlite3_file, out: *mut c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xCheckReservedLock.unwrap()(inner, out)
+}
+
+unsafe extern "C" fn x_file_control(file: *mut sqlite3_file, op: c_int, arg: *mut c_void) -> c_int {
+    // the hint is about the size of the WAL, which isn't the size of the file
+    if op == SQLITE_FCNTL_SIZE_HINT {
+        return SQLITE_OK;
+    }
+    let (inner, methods) = inner_methods(file);
+    methods.xFileControl.unwrap()(inner, op, arg)
+}
+
+unsafe extern "C" fn x_sector_size(file: *mut sqlite3_file) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xSectorSize.unwrap()(inner)
+}
+
+unsafe extern "C" fn x_device_characteristics(file: *mut sqlite3_file) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xDeviceCharacteristics.unwrap()(inner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(db_file: &Path) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open(db_file).unwrap();
+        conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        conn.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        conn
+    }
+
+    fn count(conn: &rusqlite::Connection) -> u64 {
+        conn.query_row("select count(*) from test", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn wal_is_compressed_on_disk() {
+        register();
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let tmp = tempfile::tempdir().unwrap();
+            let db_file = tmp.path().join("data");
+            prepare(&db_file, true).unwrap();
+            enable(&db_file, codec).unwrap();
+
+            let conn = open(&db_file);
+            conn.execute("create table test (x)", ()).unwrap();
+            let value = "x".repeat(1000);
+            for _ in 0..100 {
+                conn.execute("insert into test values (?)", [&value])
+                    .unwrap();
+            }
+
+            // recovery of the WAL by a new connection, which then sees the frames written by
+            // the first one
+            let conn2 = open(&db_file);
+            assert_eq!(count(&conn2), 100);
+            conn.execute("insert into test values (?)", [&value])
+                .unwrap();
+            assert_eq!(count(&conn2), 101);
+            drop(conn2);
+
+            let frames: u64 = conn
+                .query_row("PRAGMA wal_checkpoint(PASSIVE)", (), |row| row.get(1))
+                .unwrap();
+            let wal_size = std::fs::metadata(wal_path(&db_file)).unwrap().len();
+            assert!(frames > 0);
+            assert!(
+                wal_size < frames * (24 + 4096) / 4,
+                "{codec:?}: {wal_size} bytes"
+            );
+
+            // the WAL is restarted after the checkpoint
+            conn.execute("insert into test values (?)", [&value])
+                .unwrap();
+            assert!(std::fs::metadata(wal_path(&db_file)).unwrap().len() < wal_size);
+            let conn2 = open(&db_file);
+            assert_eq!(count(&conn2), 102);
+            drop(conn2);
+
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))
+                .unwrap();
+            assert_eq!(std::fs::metadata(wal_path(&db_file)).unwrap().len(), 0);
+            assert_eq!(count(&conn), 102);
+        }
+    }
+
+    #[test]
+    fn compressed_wal_requires_compression() {
+        register();
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("data");
+        enable(&db_file, Codec::Lz4).unwrap();
+        let conn = open(&db_file);
+        conn.execute("create table test (x)", ()).unwrap();
+
+        assert!(prepare(&db_file, false).is_err());
+        prepare(&db_file, true).unwrap();
+        assert_eq!(count(&conn), 0);
+    }
+}
@@ -0,0 +1,735 @@
+//! Encryption at rest of the SQLite WAL of namespaces.
+//!
+//! Encryption is implemented by a VFS shim, registered as the default VFS, which wraps the
+//! original default VFS. When a WAL file is opened for a database whose key was registered with
+//! [set_key], every page written to the WAL is encrypted with AES-256-GCM, and decrypted when it's
+//! read back, so that connections never see the difference. The headers of the WAL and of its
+//! frames are left untouched, so that SQLite can still recover the WAL.
+//!
+//! A page is encrypted in place, so the nonce and the authentication tag of every frame are stored
+//! in a sidecar file next to the WAL, `<wal>-crypt`. The sidecar starts with a header holding
+//! [SIDECAR_MAGIC] and a fingerprint of the key, so that opening the WAL with a wrong key fails
+//! instead of silently dropping the frames.
+//!
+//! A WAL which has a sidecar is never opened without its key, so that it can't be read, or appended
+//! to, as plaintext.
+//!
+//! The key of a namespace is derived from the master key each time the namespace is opened, and is
+//! never stored: the namespace config is stored in plaintext next to the database, so a key in it
+//! wouldn't protect the WAL.
+//!
+//! Only the WAL is encrypted: the main database file, the replication log and its snapshots are
+//! stored in plaintext. Bottomless replication is not supported: it reads the frames straight from
+//! the WAL file, so it would back up pages that can't be restored without the sidecar.
+
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Once;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, bail, Context};
+use hkdf::Hkdf;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqld_libsql_bindings::ffi::{
+    sqlite3_file, sqlite3_int64, sqlite3_io_methods, sqlite3_vfs, sqlite3_vfs_find,
+    sqlite3_vfs_register, SQLITE_CANTOPEN, SQLITE_IOERR_FSYNC, SQLITE_IOERR_READ,
+    SQLITE_IOERR_SHORT_READ, SQLITE_IOERR_TRUNCATE, SQLITE_IOERR_WRITE, SQLITE_OK, SQLITE_OPEN_WAL,
+};
+
+/// Header of the sidecar file.
+const SIDECAR_MAGIC: &[u8; 8] = b"SQLDWAL1";
+const SIDECAR_HEADER_SIZE: u64 = 32;
+const KEY_CHECK_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Size of the entry of a frame in the sidecar file.
+const ENTRY_SIZE: u64 = (NONCE_SIZE + TAG_SIZE) as u64;
+
+const VFS_NAME: &[u8] = b"sqld-wal-encryption\0";
+
+const WAL_HEADER_SIZE: u64 = 32;
+const WAL_FRAME_HEADER_SIZE: u64 = 24;
+
+/// Keys of the databases whose WAL is encrypted, by the canonical path of their main file.
+static KEYS: Lazy<RwLock<HashMap<PathBuf, [u8; 32]>>> = Lazy::new(Default::default);
+
+/// Server-wide key from which the WAL key of every namespace is derived.
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MasterKey(<redacted>)")
+    }
+}
+
+impl MasterKey {
+    /// Reads a key from a file, which contains either the raw 32 bytes of the key, or its hex
+    /// representation.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("failed to read WAL master key file {}", path.display()))?;
+        if let Ok(key) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return Ok(Self(key));
+        }
+        let hex = std::str::from_utf8(&bytes)
+            .with_context(|| format!("invalid WAL master key file {}", path.display()))?;
+        let bytes = hex::decode(hex.trim()).context("WAL master key is not a valid hex string")?;
+        let key = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            anyhow!("WAL master key must be 32 bytes long, got {}", bytes.len())
+        })?;
+        Ok(Self(key))
+    }
+
+    /// Derives the key encrypting the WAL of a namespace.
+    pub fn derive_namespace_key(&self, namespace: &[u8]) -> [u8; 32] {
+        let mut info = b"sqld-wal-encryption:".to_vec();
+        info.extend_from_slice(namespace);
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid length for HKDF-SHA256");
+        key
+    }
+}
+
+/// Registers the encrypting VFS as the default VFS. It's a no-op for databases without a key.
+pub fn register() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| unsafe {
+        let underlying = sqlite3_vfs_find(std::ptr::null());
+        assert!(!underlying.is_null(), "no default VFS registered");
+        // the methods we don't override are called with our VFS, so it carries the app data of
+        // the underlying VFS
+        let mut vfs = std::ptr::read(underlying);
+        vfs.szOsFile = (std::mem::size_of::<EncryptedFile>() as c_int) + (*underlying).szOsFile;
+        vfs.pNext = std::ptr::null_mut();
+        vfs.zName = VFS_NAME.as_ptr() as *const c_char;
+        vfs.xOpen = Some(x_open);
+        vfs.xDelete = Some(x_delete);
+        UNDERLYING.store(underlying, Ordering::Release);
+        let rc = sqlite3_vfs_register(Box::into_raw(Box::new(vfs)), 1);
+        assert_eq!(rc, SQLITE_OK, "failed to register the WAL encryption VFS");
+    });
+}
+
+/// Sets the key encrypting the WAL of the database stored in `db_file`. It must be set before
+/// any connection to the database is opened.
+pub fn set_key(db_file: &Path, key: [u8; 32]) -> anyhow::Result<()> {
+    KEYS.write().insert(canonical_db_path(db_file)?, key);
+    Ok(())
+}
+
+/// Returns the key of the database stored in `db_file`, whether or not its path is canonical.
+fn find_key(db_file: &Path) -> Option<[u8; 32]> {
+    let keys = KEYS.read();
+    keys.get(db_file).copied().or_else(|| {
+        let path = canonical_db_path(db_file).ok()?;
+        keys.get(&path).copied()
+    })
+}
+
+/// Prepares the WAL of the database stored in `db_file` to be opened with or without encryption.
+///
+/// A plaintext WAL left from before encryption was enabled is checkpointed, so that the WAL is
+/// encrypted from scratch. On the other hand, an encrypted WAL can't be opened without its key.
+pub fn prepare(db_file: &Path, encrypted: bool) -> anyhow::Result<()> {
+    let wal_path = wal_path(db_file);
+    let sidecar_path = sidecar_path(&wal_path);
+    let wal_is_empty = match std::fs::metadata(&wal_path) {
+        Ok(meta) => meta.len() == 0,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e.into()),
+    };
+    let has_sidecar = sidecar_path.try_exists()?;
+
+    match (encrypted, has_sidecar) {
+        (true, false) if !wal_is_empty => {
+            tracing::info!("checkpointing plaintext WAL of {}", db_file.display());
+            let conn = rusqlite::Connection::open(db_file)?;
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))?;
+            drop(conn);
+            if std::fs::metadata(&wal_path).is_ok_and(|meta| meta.len() > 0) {
+                bail!(
+                    "failed to checkpoint the plaintext WAL of {}",
+                    db_file.display()
+                );
+            }
+        }
+        (false, true) if !wal_is_empty => bail!(
+            "the WAL of {} is encrypted, but no WAL master key was provided",
+            db_file.display()
+        ),
+        (false, true) => std::fs::remove_file(&sidecar_path)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Returns whether the WAL of the database stored in `db_file` was encrypted.
+pub(crate) fn is_encrypted(db_file: &Path) -> std::io::Result<bool> {
+    sidecar_path(&wal_path(db_file)).try_exists()
+}
+
+pub(crate) fn canonical_db_path(db_file: &Path) -> anyhow::Result<PathBuf> {
+    let file_name = db_file
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid database path {}", db_file.display()))?;
+    let dir = match db_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(dir.canonicalize()?.join(file_name))
+}
+
+pub(crate) fn wal_path(db_file: &Path) -> PathBuf {
+    let mut path = db_file.as_os_str().to_owned();
+    path.push("-wal");
+    path.into()
+}
+
+fn sidecar_path(wal_path: &Path) -> PathBuf {
+    let mut path = wal_path.as_os_str().to_owned();
+    path.push("-crypt");
+    path.into()
+}
+
+fn key_check(key: &[u8; 32]) -> [u8; KEY_CHECK_SIZE] {
+    let digest = Sha256::new()
+        .chain_update(b"sqld-wal-key-check")
+        .chain_update(key)
+        .finalize();
+    let mut check = [0; KEY_CHECK_SIZE];
+    check.copy_from_slice(&digest[..KEY_CHECK_SIZE]);
+    check
+}
+
+/// The VFS wrapped by the encrypting VFS.
+static UNDERLYING: AtomicPtr<sqlite3_vfs> = AtomicPtr::new(std::ptr::null_mut());
+
+#[repr(C)]
+struct EncryptedFile {
+    base: sqlite3_file,
+    /// File opened by the underlying VFS, allocated right after this struct.
+    inner: *mut sqlite3_file,
+    state: *mut WalState,
+}
+
+struct WalState {
+    cipher: Aes256Gcm,
+    sidecar: File,
+    /// Page size of the WAL, or 0 if the WAL header wasn't read or written yet.
+    page_size: u64,
+}
+
+/// Location of the content of a page within a read or written buffer.
+struct PageRegion {
+    frame: u64,
+    offset: u64,
+    start: usize,
+    end: usize,
+}
+
+impl WalState {
+    fn open(wal_path: &Path, key: &[u8; 32], wal_size: u64) -> anyhow::Result<Self> {
+        let sidecar_path = sidecar_path(wal_path);
+        let sidecar = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&sidecar_path)?;
+        let mut header = [0; SIDECAR_HEADER_SIZE as usize];
+        header[..SIDECAR_MAGIC.len()].copy_from_slice(SIDECAR_MAGIC);
+        header[SIDECAR_MAGIC.len()..][..KEY_CHECK_SIZE].copy_from_slice(&key_check(key));
+
+        if sidecar.metadata()?.len() < SIDECAR_HEADER_SIZE {
+            if wal_size > 0 {
+                bail!("{} is not encrypted", wal_path.display());
+            }
+            sidecar.write_all_at(&header, 0)?;
+        } else {
+            let mut current = [0; SIDECAR_HEADER_SIZE as usize];
+            sidecar.read_exact_at(&mut current, 0)?;
+            if current[..SIDECAR_MAGIC.len()] != SIDECAR_MAGIC[..] {
+                bail!("invalid header in {}", sidecar_path.display());
+            }
+            if current != header {
+                bail!("{} is encrypted with another key", wal_path.display());
+            }
+        }
+
+        Ok(Self {
+            cipher: Aes256Gcm::new(GenericArray::from_slice(key)),
+            sidecar,
+            page_size: 0,
+        })
+    }
+
+    fn learn_page_size(&mut self, buf: &[u8], offset: u64) {
+        if offset == 0 && buf.len() >= WAL_HEADER_SIZE as usize {
+            self.page_size = u32::from_be_bytes(buf[8..12].try_into().unwrap()) as u64;
+        }
+    }
+
+    /// Returns the pages contained in the buffer at `offset` of the WAL, or `None` if the buffer
+    /// contains only a part of a page.
+    fn page_regions(&self, offset: u64, len: usize) -> Option<Vec<PageRegion>> {
+        let end = offset + len as u64;
+        let mut regions = Vec::new();
+        if self.page_size == 0 || end <= WAL_HEADER_SIZE + WAL_FRAME_HEADER_SIZE {
+            return Some(regions);
+        }
+        let frame_size = WAL_FRAME_HEADER_SIZE + self.page_size;
+        let mut frame = offset.saturating_sub(WAL_HEADER_SIZE + WAL_FRAME_HEADER_SIZE) / frame_size;
+        loop {
+            let page_start = WAL_HEADER_SIZE + frame * frame_size + WAL_FRAME_HEADER_SIZE;
+            let page_end = page_start + self.page_size;
+            if page_start >= end {
+                break;
+            }
+            if page_end > offset {
+                if page_start < offset || page_end > end {
+                    return None;
+                }
+                regions.push(PageRegion {
+                    frame,
+                    offset: page_start,
+                    start: (page_start - offset) as usize,
+                    end: (page_end - offset) as usize,
+                });
+            }
+            frame += 1;
+        }
+        Some(regions)
+    }
+
+    fn encrypt(&self, region: &PageRegion, page: &mut [u8]) -> anyhow::Result<()> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                &region.offset.to_be_bytes(),
+                page,
+            )
+            .map_err(|_| anyhow!("failed to encrypt WAL frame {}", region.frame))?;
+        let mut entry = [0; ENTRY_SIZE as usize];
+        entry[..NONCE_SIZE].copy_from_slice(&nonce);
+        entry[NONCE_SIZE..].copy_from_slice(&tag);
+        self.sidecar
+            .write_all_at(&entry, SIDECAR_HEADER_SIZE + region.frame * ENTRY_SIZE)?;
+        Ok(())
+    }
+
+    fn decrypt(&self, region: &PageRegion, page: &mut [u8]) -> anyhow::Result<()> {
+        let mut entry = [0; ENTRY_SIZE as usize];
+        self.sidecar
+            .read_exact_at(&mut entry, SIDECAR_HEADER_SIZE + region.frame * ENTRY_SIZE)?;
+        self.cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&entry[..NONCE_SIZE]),
+                &region.offset.to_be_bytes(),
+                page,
+                GenericArray::from_slice(&entry[NONCE_SIZE..]),
+            )
+            .map_err(|_| anyhow!("failed to decrypt WAL frame {}", region.frame))
+    }
+}
+
+unsafe fn inner_methods(
+    file: *mut sqlite3_file,
+) -> (*mut sqlite3_file, &'static sqlite3_io_methods) {
+    let inner = (*(file as *mut EncryptedFile)).inner;
+    (inner, &*(*inner).pMethods)
+}
+
+unsafe fn state<'a>(file: *mut sqlite3_file) -> &'a mut WalState {
+    &mut *(*(file as *mut EncryptedFile)).state
+}
+
+/// Reads the page size from the WAL header before a frame is accessed, if it's not known yet,
+/// e.g. when a connection reads frames which were written by another connection.
+unsafe fn load_page_size(file: *mut sqlite3_file, offset: u64) -> c_int {
+    let state = state(file);
+    if state.page_size != 0 || offset < WAL_HEADER_SIZE {
+        return SQLITE_OK;
+    }
+    let (inner, methods) = inner_methods(file);
+    let mut header = [0u8; WAL_HEADER_SIZE as usize];
+    let rc = methods.xRead.unwrap()(
+        inner,
+        header.as_mut_ptr() as *mut c_void,
+        header.len() as c_int,
+        0,
+    );
+    if rc == SQLITE_OK {
+        state.learn_page_size(&header, 0);
+    }
+    rc
+}
+
+unsafe extern "C" fn x_open(
+    _vfs: *mut sqlite3_vfs,
+    name: *const c_char,
+    file: *mut sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    let underlying = UNDERLYING.load(Ordering::Acquire);
+    let open = (*underlying).xOpen.unwrap();
+    if name.is_null() || flags & SQLITE_OPEN_WAL == 0 {
+        return open(underlying, name, file, flags, out_flags);
+    }
+    let wal_path = PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned());
+    let key = wal_path
+        .to_str()
+        .and_then(|path| path.strip_suffix("-wal"))
+        .and_then(|db_path| find_key(Path::new(db_path)));
+    let Some(key) = key else {
+        // an encrypted WAL must never be read, or appended to, as plaintext
+        if sidecar_path(&wal_path).exists() {
+            tracing::error!("no key to open encrypted WAL {}", wal_path.display());
+            return SQLITE_CANTOPEN;
+        }
+        return open(underlying, name, file, flags, out_flags);
+    };
+
+    let encrypted = file as *mut EncryptedFile;
+    let inner = (file as *mut u8).add(std::mem::size_of::<EncryptedFile>()) as *mut sqlite3_file;
+    (*encrypted).base.pMethods = std::ptr::null();
+    let rc = open(underlying, name, inner, flags, out_flags);
+    if rc != SQLITE_OK {
+        return rc;
+    }
+
+    let inner_methods = &*(*inner).pMethods;
+    let mut wal_size = 0;
+    let rc = inner_methods.xFileSize.unwrap()(inner, &mut wal_size);
+    let state = if rc == SQLITE_OK {
+        WalState::open(&wal_path, &key, wal_size as u64)
+    } else {
+        Err(anyhow!("failed to get the size of {}", wal_path.display()))
+    };
+    match state {
+        Ok(state) => {
+            (*encrypted).inner = inner;
+            (*encrypted).state = Box::into_raw(Box::new(state));
+            (*encrypted).base.pMethods = &ENCRYPTED_IO_METHODS;
+            SQLITE_OK
+        }
+        Err(e) => {
+            tracing::error!("failed to open encrypted WAL: {e}");
+            inner_methods.xClose.unwrap()(inner);
+            SQLITE_CANTOPEN
+        }
+    }
+}
+
+unsafe extern "C" fn x_delete(
+    _vfs: *mut sqlite3_vfs,
+    name: *const c_char,
+    sync_dir: c_int,
+) -> c_int {
+    let underlying = UNDERLYING.load(Ordering::Acquire);
+    let rc = (*underlying).xDelete.unwrap()(underlying, name, sync_dir);
+    let name = CStr::from_ptr(name).to_string_lossy();
+    if rc == SQLITE_OK && name.ends_with("-wal") {
+        let _ = std::fs::remove_file(sidecar_path(Path::new(name.as_ref())));
+    }
+    rc
+}
+
+static ENCRYPTED_IO_METHODS: sqlite3_io_methods = sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(x_close),
+    xRead: Some(x_read),
+    xWrite: Some(x_write),
+    xTruncate: Some(x_truncate),
+    xSync: Some(x_sync),
+    xFileSize: Some(x_file_size),
+    xLock: Some(x_lock),
+    xUnlock: Some(x_unlock),
+    xCheckReservedLock: Some(x_check_reserved_lock),
+    xFileControl: Some(x_file_control),
+    xSectorSize: Some(x_sector_size),
+    xDeviceCharacteristics: Some(x_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe extern "C" fn x_close(file: *mut sqlite3_file) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    let rc = methods.xClose.unwrap()(inner);
+    drop(Box::from_raw((*(file as *mut EncryptedFile)).state));
+    rc
+}
+
+unsafe extern "C" fn x_read(
+    file: *mut sqlite3_file,
+    buf: *mut c_void,
+    amount: c_int,
+    offset: sqlite3_int64,
+) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    let rc = load_page_size(file, offset as u64);
+    if rc != SQLITE_OK {
+        return rc;
+    }
+    let rc = methods.xRead.unwrap()(inner, buf, amount, offset);
+    if rc != SQLITE_OK && rc != SQLITE_IOERR_SHORT_READ {
+        return rc;
+    }
+    let state = state(file);
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, amount as usize);
+    let offset = offset as u64;
+    if rc == SQLITE_OK {
+        state.learn_page_size(buf, offset);
+    }
+    let Some(regions) = state.page_regions(offset, buf.len()) else {
+        tracing::error!("unaligned read of {amount} bytes at {offset} from an encrypted WAL");
+        return SQLITE_IOERR_READ;
+    };
+    for region in regions {
+        let page = &mut buf[region.start..region.end];
+        if rc == SQLITE_IOERR_SHORT_READ {
+            // we can't tell which pages were read entirely
+            page.fill(0);
+            continue;
+        }
+        if let Err(e) = state.decrypt(&region, page) {
+            if region.offset - offset >= WAL_FRAME_HEADER_SIZE {
+                // the whole frame was read, e.g. during recovery: a frame whose write was torn
+                // between the WAL and the sidecar is treated as invalid, like a torn frame
+                page.fill(0);
+            } else {
+                tracing::error!("{e}");
+                return SQLITE_IOERR_READ;
+            }
+        }
+    }
+    rc
+}
+
+unsafe extern "C" fn x_write(
+    file: *mut sqlite3_file,
+    buf: *const c_void,
+    amount: c_int,
+    offset: sqlite3_int64,
+) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    let state = state(file);
+    let buf = std::slice::from_raw_parts(buf as *const u8, amount as usize);
+    let offset = offset as u64;
+    state.learn_page_size(buf, offset);
+    let rc = load_page_size(file, offset);
+    if rc != SQLITE_OK {
+        return rc;
+    }
+    let Some(regions) = state.page_regions(offset, buf.len()) else {
+        tracing::error!("unaligned write of {amount} bytes at {offset} to an encrypted WAL");
+        return SQLITE_IOERR_WRITE;
+    };
+    if regions.is_empty() {
+        return methods.xWrite.unwrap()(inner, buf.as_ptr() as *const c_void, amount, offset as _);
+    }
+
+    let mut encrypted = buf.to_vec();
+    for region in regions {
+        if let Err(e) = state.encrypt(&region, &mut encrypted[region.start..region.end]) {
+            tracing::error!("{e}");
+            return SQLITE_IOERR_WRITE;
+        }
+    }
+    methods.xWrite.unwrap()(
+        inner,
+        encrypted.as_ptr() as *const c_void,
+        amount,
+        offset as _,
+    )
+}
+
+unsafe extern "C" fn x_truncate(file: *mut sqlite3_file, size: sqlite3_int64) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    let rc = methods.xTruncate.unwrap()(inner, size);
+    if rc != SQLITE_OK {
+        return rc;
+    }
+    let state = state(file);
+    let size = size as u64;
+    let frames = if state.page_size == 0 || size <= WAL_HEADER_SIZE {
+        0
+    } else {
+        let frame_size = WAL_FRAME_HEADER_SIZE + state.page_size;
+        (size - WAL_HEADER_SIZE - 1) / frame_size + 1
+    };
+    match state
+        .sidecar
+        .set_len(SIDECAR_HEADER_SIZE + frames * ENTRY_SIZE)
+    {
+        Ok(()) => SQLITE_OK,
+        Err(e) => {
+            tracing::error!("failed to truncate WAL encryption sidecar: {e}");
+            SQLITE_IOERR_TRUNCATE
+        }
+    }
+}
+
+unsafe extern "C" fn x_sync(file: *mut sqlite3_file, flags: c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    // the nonces must be durable before the frames referring to them are
+    if let Err(e) = state(file).sidecar.sync_data() {
+        tracing::error!("failed to sync WAL encryption sidecar: {e}");
+        return SQLITE_IOERR_FSYNC;
+    }
+    methods.xSync.unwrap()(inner, flags)
+}
+
+unsafe extern "C" fn x_file_size(file: *mut sqlite3_file, size: *mut sqlite3_int64) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xFileSize.unwrap()(inner, size)
+}
+
+unsafe extern "C" fn x_lock(file: *mut sqlite3_file, lock: c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xLock.unwrap()(inner, lock)
+}
+
+unsafe extern "C" fn x_unlock(file: *mut sqlite3_file, lock: c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xUnlock.unwrap()(inner, lock)
+}
+
+unsafe extern "C" fn x_check_reserved_lock(file: *mut sqlite3_file, out: *mut c_int) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xCheckReservedLock.unwrap()(inner, out)
+}
+
+unsafe extern "C" fn x_file_control(file: *mut sqlite3_file, op: c_int, arg: *mut c_void) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xFileControl.unwrap()(inner, op, arg)
+}
+
+unsafe extern "C" fn x_sector_size(file: *mut sqlite3_file) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xSectorSize.unwrap()(inner)
+}
+
+unsafe extern "C" fn x_device_characteristics(file: *mut sqlite3_file) -> c_int {
+    let (inner, methods) = inner_methods(file);
+    methods.xDeviceCharacteristics.unwrap()(inner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MARKER: &str = "plaintext-marker-b2f1c8";
+
+    fn open(db_file: &Path) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open(db_file).unwrap();
+        conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        conn.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        conn
+    }
+
+    #[test]
+    fn wal_is_encrypted_at_rest() {
+        register();
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("data");
+        let key = MasterKey([7; 32]).derive_namespace_key(b"ns");
+        prepare(&db_file, true).unwrap();
+        set_key(&db_file, key).unwrap();
+
+        let conn = open(&db_file);
+        conn.execute("create table test (x)", ()).unwrap();
+        for _ in 0..100 {
+            conn.execute("insert into test values (?)", [MARKER])
+                .unwrap();
+        }
+        let wal = std::fs::read(wal_path(&db_file)).unwrap();
+        assert!(!wal.is_empty());
+        assert!(!wal.windows(MARKER.len()).any(|w| w == MARKER.as_bytes()));
+
+        // recovery of the WAL by a new connection
+        let conn2 = open(&db_file);
+        let count: u64 = conn2
+            .query_row("select count(*) from test where x = ?", [MARKER], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 100);
+        drop(conn2);
+        drop(conn);
+
+        // a WAL left on disk can't be read with another key
+        let conn = open(&db_file);
+        conn.execute("insert into test values (?)", [MARKER])
+            .unwrap();
+        let other = tmp.path().join("copy");
+        std::fs::copy(&db_file, &other).unwrap();
+        std::fs::copy(wal_path(&db_file), wal_path(&other)).unwrap();
+        std::fs::copy(
+            sidecar_path(&wal_path(&db_file)),
+            sidecar_path(&wal_path(&other)),
+        )
+        .unwrap();
+        set_key(&other, [8; 32]).unwrap();
+        assert!(rusqlite::Connection::open(&other)
+            .and_then(|conn| conn.query_row("select count(*) from test", (), |_| Ok(())))
+            .is_err());
+
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))
+            .unwrap();
+        let count: u64 = conn
+            .query_row("select count(*) from test", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 101);
+    }
+
+    #[test]
+    fn encrypted_wal_is_not_opened_without_key() {
+        register();
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("data");
+        prepare(&db_file, true).unwrap();
+        set_key(&db_file, MasterKey([7; 32]).derive_namespace_key(b"ns")).unwrap();
+
+        // the key is found through a path that isn't canonical
+        let conn = open(&tmp.path().join(".").join("data"));
+        conn.execute("create table test (x)", ()).unwrap();
+        conn.execute("insert into test values (?)", [MARKER])
+            .unwrap();
+        let wal = std::fs::read(wal_path(&db_file)).unwrap();
+        assert!(!wal.windows(MARKER.len()).any(|w| w == MARKER.as_bytes()));
+
+        let other = tmp.path().join("copy");
+        std::fs::copy(&db_file, &other).unwrap();
+        std::fs::copy(wal_path(&db_file), wal_path(&other)).unwrap();
+        std::fs::copy(
+            sidecar_path(&wal_path(&db_file)),
+            sidecar_path(&wal_path(&other)),
+        )
+        .unwrap();
+        assert!(rusqlite::Connection::open(&other)
+            .and_then(|conn| conn.execute("insert into test values (?)", [MARKER]))
+            .is_err());
+        let copied = std::fs::read(wal_path(&other)).unwrap();
+        assert_eq!(copied, wal);
+    }
+}
@@ -0,0 +1,43 @@
+//! The allocator enforcing the heap limits of namespaces can only be registered before SQLite is
+//! initialized, so this test runs in its own process, and must stay the only test in it.
+
+use rusqlite::ErrorCode;
+use sqld::heap_limit;
+
+#[test]
+fn allocations_over_the_hard_limit_fail_with_nomem() {
+    heap_limit::register();
+    let heap = heap_limit::namespace_heap("heap-limit-nomem");
+    let guard = heap_limit::enter(heap.clone());
+
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute("create table test (x)", ()).unwrap();
+    assert!(heap.used() > 0);
+
+    heap.set_limits(None, Some(1));
+    let err = conn
+        .execute("insert into test values (randomblob(2 * 1024 * 1024))", ())
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::OutOfMemory));
+    // the limit only applies to the namespace
+    std::thread::spawn(|| {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.query_row("select length(randomblob(2 * 1024 * 1024))", (), |row| {
+            row.get::<_, i64>(0)
+        })
+        .unwrap();
+    })
+    .join()
+    .unwrap();
+
+    heap.set_limits(None, None);
+    conn.execute("insert into test values (randomblob(2 * 1024 * 1024))", ())
+        .unwrap();
+    assert!(heap.used() > 2 * 1024 * 1024);
+
+    // the memory charged to the heap is released with the connection
+    conn.execute("delete from test", ()).unwrap();
+    drop(conn);
+    drop(guard);
+    assert!(heap.used() < 1024 * 1024);
+}